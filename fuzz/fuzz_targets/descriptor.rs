@@ -0,0 +1,17 @@
+//! Descriptors of any content must be read or refused within the default
+//! limits, without panicking. Run with `cargo fuzz run descriptor`.
+
+#![no_main]
+
+use flame::file::{FlameSource, ParseLimits};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    // Referenced files are refused, so that the fuzzer only exercises the
+    // parser and not whatever files it can name.
+    let limits = ParseLimits { max_referenced_bytes: 0, ..ParseLimits::default() };
+    if let Ok(source) = FlameSource::from_reader_with_limits(data, "", limits) {
+        let _ = source.check_finite();
+        let _ = source.to_flame();
+    }
+});
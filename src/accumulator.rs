@@ -0,0 +1,713 @@
+//! A file format for accumulated histograms, so that a render can be saved
+//! and picked up again, or rendered in pieces and merged.
+//!
+//! Every number is stored little-endian, whatever the machine writing it.
+//! A file is a fixed header followed by the buckets, row by row, each as
+//! its alpha, red, green and blue channels:
+//!
+//! | bytes | contents                                          |
+//! |-------|---------------------------------------------------|
+//! | 8     | `FLAMEACC`                                        |
+//! | 2     | format version                                    |
+//! | 4     | `0x01020304`, to recognize byte-swapped files     |
+//! | 1     | channel type: 1 for u32, 2 for u64, 3 for f32     |
+//! | 1     | flags: 1 if a seed is stored, 2 if a shard is     |
+//! | 8     | width                                             |
+//! | 8     | height                                            |
+//! | 8     | iterations accumulated                            |
+//! | 8     | `Flame::content_hash` of the flame accumulated    |
+//! | 8     | seed, or zero                                     |
+//! | 4     | shard index, or zero                              |
+//! | 4     | shard count, or zero                              |
+
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use super::core::*;
+
+/// Bytes every accumulator file starts with.
+pub const MAGIC: [u8; 8] = *b"FLAMEACC";
+
+/// Version of the format written. Files of later versions are rejected
+/// rather than guessed at. Version 1 hashed flames as their descriptors,
+/// which `Flame::content_hash` replaced in version 2.
+pub const VERSION: u16 = 2;
+
+/// Oldest version whose flame hashes are `Flame::content_hash`.
+const CONTENT_HASH_SINCE: u16 = 2;
+
+const BYTE_ORDER_MARK: u32 = 0x0102_0304;
+const HAS_SEED: u8 = 1;
+const HAS_SHARD: u8 = 2;
+
+/// Added to the name of an image which could not be saved to name the
+/// file its histogram is rescued to.
+pub const RESCUE_EXTENSION: &str = "accum.partial";
+
+/// Size in bytes of the header of the current version.
+pub const HEADER_LEN: usize = 8 + 2 + 4 + 1 + 1 + 8 * 5 + 4 * 2;
+
+/// Number type of each channel of the stored buckets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelType {
+    U32,
+    U64,
+    F32,
+}
+
+impl ChannelType {
+    fn code(self) -> u8 {
+        match self {
+            ChannelType::U32 => 1,
+            ChannelType::U64 => 2,
+            ChannelType::F32 => 3,
+        }
+    }
+
+    fn from_code(code: u8) -> Option<Self> {
+        match code {
+            1 => Some(ChannelType::U32),
+            2 => Some(ChannelType::U64),
+            3 => Some(ChannelType::F32),
+            _ => None,
+        }
+    }
+
+    /// Size in bytes of one channel.
+    pub fn size(self) -> usize {
+        match self {
+            ChannelType::U32 | ChannelType::F32 => 4,
+            ChannelType::U64 => 8,
+        }
+    }
+}
+
+impl std::fmt::Display for ChannelType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChannelType::U32 => write!(f, "u32"),
+            ChannelType::U64 => write!(f, "u64"),
+            ChannelType::F32 => write!(f, "f32"),
+        }
+    }
+}
+
+/// A channel type which can be stored in an accumulator file.
+pub trait Channel: Copy {
+    const TYPE: ChannelType;
+
+    fn write_le(self, out: &mut Vec<u8>);
+
+    /// Read a channel from exactly `TYPE.size()` bytes.
+    fn read_le(bytes: &[u8]) -> Self;
+
+    /// The buffer held by `buckets`, if it is of this type.
+    fn from_loaded(buckets: Buckets) -> Option<Buffer<Self>>;
+}
+
+impl Channel for u32 {
+    const TYPE: ChannelType = ChannelType::U32;
+
+    fn write_le(self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.to_le_bytes());
+    }
+
+    fn read_le(bytes: &[u8]) -> Self {
+        u32::from_le_bytes(bytes.try_into().expect("four bytes"))
+    }
+
+    fn from_loaded(buckets: Buckets) -> Option<Buffer<Self>> {
+        match buckets {
+            Buckets::U32(buffer) => Some(buffer),
+            _ => None,
+        }
+    }
+}
+
+impl Channel for u64 {
+    const TYPE: ChannelType = ChannelType::U64;
+
+    fn write_le(self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.to_le_bytes());
+    }
+
+    fn read_le(bytes: &[u8]) -> Self {
+        u64::from_le_bytes(bytes.try_into().expect("eight bytes"))
+    }
+
+    fn from_loaded(buckets: Buckets) -> Option<Buffer<Self>> {
+        match buckets {
+            Buckets::U64(buffer) => Some(buffer),
+            _ => None,
+        }
+    }
+}
+
+impl Channel for f32 {
+    const TYPE: ChannelType = ChannelType::F32;
+
+    fn write_le(self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.to_le_bytes());
+    }
+
+    fn read_le(bytes: &[u8]) -> Self {
+        f32::from_le_bytes(bytes.try_into().expect("four bytes"))
+    }
+
+    fn from_loaded(buckets: Buckets) -> Option<Buffer<Self>> {
+        match buckets {
+            Buckets::F32(buffer) => Some(buffer),
+            _ => None,
+        }
+    }
+}
+
+/// Which of several renders of the same flame, to be merged, a file holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Shard {
+    pub index: u32,
+    pub count: u32,
+}
+
+/// Everything stored about an accumulator besides its buckets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub version: u16,
+    pub width: usize,
+    pub height: usize,
+    pub channel: ChannelType,
+    pub iterations: u64,
+    /// `Flame::content_hash` of the flame accumulated, to refuse resuming
+    /// or merging with a different one.
+    pub flame_hash: u64,
+    pub seed: Option<u64>,
+    pub shard: Option<Shard>,
+}
+
+impl Header {
+    /// Check that the buckets were accumulated from the flame with hash
+    /// `flame_hash`. Files too old to hold a comparable hash pass.
+    pub fn check_flame(&self, flame_hash: u64) -> Result<(), AccumError> {
+        if self.version >= CONTENT_HASH_SINCE && self.flame_hash != flame_hash {
+            return Err(AccumError::FlameMismatch { expected: flame_hash, found: self.flame_hash });
+        }
+        Ok(())
+    }
+
+    /// Size in bytes of the buckets following the header, if it can be
+    /// addressed at all.
+    pub fn payload_len(&self) -> Option<usize> {
+        self.width.checked_mul(self.height)?.checked_mul(4)?.checked_mul(self.channel.size())
+    }
+}
+
+impl std::fmt::Display for Header {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "version     {}", self.version)?;
+        writeln!(f, "size        {}x{}", self.width, self.height)?;
+        writeln!(f, "channels    {}", self.channel)?;
+        writeln!(f, "iterations  {}", self.iterations)?;
+        writeln!(f, "flame hash  {:016x}", self.flame_hash)?;
+        match self.seed {
+            Some(seed) => writeln!(f, "seed        {}", seed)?,
+            None => writeln!(f, "seed        none")?,
+        }
+        match self.shard {
+            Some(shard) => write!(f, "shard       {} of {}", shard.index + 1, shard.count),
+            None => write!(f, "shard       none"),
+        }
+    }
+}
+
+/// What is written alongside the buckets, which the buffer cannot tell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Provenance {
+    pub iterations: u64,
+    pub flame_hash: u64,
+    pub seed: Option<u64>,
+    pub shard: Option<Shard>,
+}
+
+/// The buckets of an accumulator file, in the type they were stored as.
+#[derive(Debug, Clone)]
+pub enum Buckets {
+    U32(Buffer<u32>),
+    U64(Buffer<u64>),
+    F32(Buffer<f32>),
+}
+
+/// An accumulator read from a file.
+#[derive(Debug, Clone)]
+pub struct LoadedAccumulator {
+    pub header: Header,
+    pub buckets: Buckets,
+}
+
+impl LoadedAccumulator {
+    /// The buckets, if they were stored as `T`. Channels are not converted,
+    /// as counts saved in a narrower type may already have been clamped.
+    pub fn into_buffer<T: Channel>(self) -> Result<Buffer<T>, AccumError> {
+        let found = self.header.channel;
+        T::from_loaded(self.buckets).ok_or(AccumError::ChannelMismatch { expected: T::TYPE, found })
+    }
+
+    /// The buckets as an `Accumulator` of `flame`, which they must have
+    /// been accumulated from, so that rendering can go on where it stopped.
+    /// Only counts stored as u32 can be resumed.
+    pub fn into_accumulator(self, flame: &Flame) -> Result<Accumulator, AccumError> {
+        let header = self.header;
+        let hash = flame.content_hash()?;
+        header.check_flame(hash)?;
+        let buffer = self.into_buffer::<u32>()?;
+        // Files too old for a comparable hash are taken to be of the flame.
+        Ok(flame.resume(buffer, hash, header.iterations).expect("the hash is the flame's own"))
+    }
+}
+
+impl Provenance {
+    /// What an accumulator holds, without a seed or shard.
+    pub fn of(acc: &Accumulator) -> Self {
+        Provenance { iterations: acc.iters_done(), flame_hash: acc.flame_hash(), seed: None, shard: None }
+    }
+}
+
+#[derive(Debug)]
+pub enum AccumError {
+    Io(io::Error),
+    /// The file does not start with `MAGIC`.
+    NotAccumulator,
+    /// The file is of a later version of the format.
+    UnsupportedVersion(u16),
+    /// The byte order mark is not `0x01020304` read little-endian.
+    ByteOrder([u8; 4]),
+    UnknownChannel(u8),
+    /// The header describes something no file can hold.
+    InvalidHeader(String),
+    /// The file ends before all of the buckets.
+    Truncated { expected: usize, found: usize },
+    /// The buckets are not of the type asked for.
+    ChannelMismatch { expected: ChannelType, found: ChannelType },
+    /// The buckets were accumulated from a different flame.
+    FlameMismatch { expected: u64, found: u64 },
+    /// The flame to compare the buckets with cannot be hashed.
+    Unhashable(ContentHashError),
+    Buffer(BufferError),
+}
+
+impl std::fmt::Display for AccumError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AccumError::Io(e) => write!(f, "{}", e),
+            AccumError::NotAccumulator => write!(f, "not an accumulator file"),
+            AccumError::UnsupportedVersion(v) => write!(
+                f, "accumulator file is version {}, but only up to version {} is supported", v, VERSION,
+            ),
+            AccumError::ByteOrder(bytes) => write!(f, "accumulator file has unknown byte order mark {:02x?}", bytes),
+            AccumError::UnknownChannel(code) => write!(f, "accumulator file has unknown channel type {}", code),
+            AccumError::InvalidHeader(msg) => write!(f, "invalid accumulator header: {}", msg),
+            AccumError::Truncated { expected, found } => write!(
+                f, "accumulator file is truncated: expected {} bytes of buckets, found {}", expected, found,
+            ),
+            AccumError::ChannelMismatch { expected, found } => write!(
+                f, "accumulator holds {} channels, not {}", found, expected,
+            ),
+            AccumError::FlameMismatch { expected, found } => write!(
+                f, "accumulator holds a flame with hash {:016x}, not {:016x}", found, expected,
+            ),
+            AccumError::Unhashable(e) => write!(f, "{}", e),
+            AccumError::Buffer(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for AccumError {}
+
+impl From<io::Error> for AccumError {
+    fn from(e: io::Error) -> Self { AccumError::Io(e) }
+}
+
+impl From<ContentHashError> for AccumError {
+    fn from(e: ContentHashError) -> Self { AccumError::Unhashable(e) }
+}
+
+impl From<BufferError> for AccumError {
+    fn from(e: BufferError) -> Self { AccumError::Buffer(e) }
+}
+
+/// Write a buffer and what produced it.
+pub fn write<T: Channel>(mut w: impl Write, buffer: &Buffer<T>, provenance: &Provenance) -> Result<(), AccumError> {
+    let mut flags = 0;
+    if provenance.seed.is_some() {
+        flags |= HAS_SEED;
+    }
+    if provenance.shard.is_some() {
+        flags |= HAS_SHARD;
+    }
+    let shard = provenance.shard.unwrap_or(Shard { index: 0, count: 0 });
+
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    header.extend_from_slice(&MAGIC);
+    header.extend_from_slice(&VERSION.to_le_bytes());
+    header.extend_from_slice(&BYTE_ORDER_MARK.to_le_bytes());
+    header.push(T::TYPE.code());
+    header.push(flags);
+    header.extend_from_slice(&(buffer.width() as u64).to_le_bytes());
+    header.extend_from_slice(&(buffer.height() as u64).to_le_bytes());
+    header.extend_from_slice(&provenance.iterations.to_le_bytes());
+    header.extend_from_slice(&provenance.flame_hash.to_le_bytes());
+    header.extend_from_slice(&provenance.seed.unwrap_or(0).to_le_bytes());
+    header.extend_from_slice(&shard.index.to_le_bytes());
+    header.extend_from_slice(&shard.count.to_le_bytes());
+    debug_assert_eq!(header.len(), HEADER_LEN);
+    w.write_all(&header)?;
+
+    // Buckets are encoded a row at a time, so the whole payload is never
+    // held twice.
+    let row_len = buffer.width() * 4 * T::TYPE.size();
+    let mut row = Vec::with_capacity(row_len);
+    for bucket in buffer.row_major_buckets() {
+        for channel in [bucket.alpha, bucket.red, bucket.green, bucket.blue] {
+            channel.write_le(&mut row);
+        }
+        if row.len() >= row_len {
+            w.write_all(&row)?;
+            row.clear();
+        }
+    }
+    w.write_all(&row)?;
+    Ok(())
+}
+
+/// Save the histogram of an image which could not be written to `output`,
+/// so that the render need not be run again: beside it as
+/// `<output>.accum.partial`, or in the system's temporary directory if that
+/// cannot be written either. Returns the path saved to, or the error from
+/// the last place tried.
+pub fn rescue<T: Channel>(output: &Path, buffer: &Buffer<T>, provenance: &Provenance) -> Result<PathBuf, AccumError> {
+    let name = output.file_name().map_or("flame".into(), |n| n.to_string_lossy());
+    let name = format!("{}.{}", name, RESCUE_EXTENSION);
+    let mut last = None;
+    for path in [output.with_file_name(&name), std::env::temp_dir().join(&name)] {
+        match write_file(&path, buffer, provenance) {
+            Ok(()) => return Ok(path),
+            Err(e) => last = Some(e),
+        }
+    }
+    Err(last.expect("two places are tried"))
+}
+
+/// Write a buffer to a new file at `path`, removing what was written if
+/// it cannot be finished.
+fn write_file<T: Channel>(path: &Path, buffer: &Buffer<T>, provenance: &Provenance) -> Result<(), AccumError> {
+    let mut file = BufWriter::new(File::create(path)?);
+    let written = write(&mut file, buffer, provenance).and_then(|()| {
+        // Some file systems only report a full disk once data is synced.
+        let file = file.into_inner().map_err(|e| e.into_error())?;
+        Ok(file.sync_all()?)
+    });
+    if written.is_err() {
+        let _ = std::fs::remove_file(path);
+    }
+    written
+}
+
+/// Read the header of an accumulator file, leaving the reader at the
+/// start of the buckets.
+pub fn read_header(mut r: impl Read) -> Result<Header, AccumError> {
+    let mut magic = [0; 8];
+    read_header_bytes(&mut r, &mut magic)?;
+    if magic != MAGIC {
+        return Err(AccumError::NotAccumulator);
+    }
+    let mut version = [0; 2];
+    read_header_bytes(&mut r, &mut version)?;
+    let version = u16::from_le_bytes(version);
+    if version == 0 || version > VERSION {
+        return Err(AccumError::UnsupportedVersion(version));
+    }
+
+    let mut rest = [0; HEADER_LEN - 10];
+    read_header_bytes(&mut r, &mut rest)?;
+    let mut fields = Fields(&rest);
+    let mark = fields.take::<4>();
+    if u32::from_le_bytes(mark) != BYTE_ORDER_MARK {
+        return Err(AccumError::ByteOrder(mark));
+    }
+    let [code] = fields.take::<1>();
+    let channel = ChannelType::from_code(code).ok_or(AccumError::UnknownChannel(code))?;
+    let [flags] = fields.take::<1>();
+    if flags & !(HAS_SEED | HAS_SHARD) != 0 {
+        return Err(AccumError::InvalidHeader(format!("unknown flags {:#04x}", flags)));
+    }
+    let dimension = |value: u64| {
+        usize::try_from(value).map_err(|_| AccumError::InvalidHeader(format!("dimension {} is too large", value)))
+    };
+    let width = dimension(u64::from_le_bytes(fields.take()))?;
+    let height = dimension(u64::from_le_bytes(fields.take()))?;
+    let iterations = u64::from_le_bytes(fields.take());
+    let flame_hash = u64::from_le_bytes(fields.take());
+    let seed = u64::from_le_bytes(fields.take());
+    let index = u32::from_le_bytes(fields.take());
+    let count = u32::from_le_bytes(fields.take());
+
+    let shard = if flags & HAS_SHARD != 0 {
+        if index >= count {
+            return Err(AccumError::InvalidHeader(format!("shard {} of {} does not exist", index, count)));
+        }
+        Some(Shard { index, count })
+    } else {
+        None
+    };
+
+    let header = Header {
+        version,
+        width,
+        height,
+        channel,
+        iterations,
+        flame_hash,
+        seed: (flags & HAS_SEED != 0).then_some(seed),
+        shard,
+    };
+    if header.payload_len().is_none() {
+        return Err(AccumError::InvalidHeader(format!("{}x{} buckets cannot be addressed", width, height)));
+    }
+    Ok(header)
+}
+
+/// Read an accumulator file written by `write`.
+pub fn read(mut r: impl Read) -> Result<LoadedAccumulator, AccumError> {
+    let header = read_header(&mut r)?;
+    let expected = header.payload_len().expect("checked by read_header");
+
+    // The payload grows as it is read, rather than being allocated up
+    // front, so a corrupt size fails at the end of the file instead of
+    // exhausting memory.
+    let mut payload = Vec::new();
+    r.take(expected as u64).read_to_end(&mut payload)?;
+    if payload.len() < expected {
+        return Err(AccumError::Truncated { expected, found: payload.len() });
+    }
+
+    let buckets = match header.channel {
+        ChannelType::U32 => Buckets::U32(decode(&header, &payload)?),
+        ChannelType::U64 => Buckets::U64(decode(&header, &payload)?),
+        ChannelType::F32 => Buckets::F32(decode(&header, &payload)?),
+    };
+    Ok(LoadedAccumulator { header, buckets })
+}
+
+fn decode<T: Channel>(header: &Header, payload: &[u8]) -> Result<Buffer<T>, AccumError> {
+    let channels = payload.chunks_exact(T::TYPE.size()).map(T::read_le).collect();
+    Ok(Buffer::from_flat_vec(header.width, header.height, channels)?)
+}
+
+/// Fill `buf` from the header, reporting a short read as a truncated file.
+fn read_header_bytes(r: &mut impl Read, buf: &mut [u8]) -> Result<(), AccumError> {
+    r.read_exact(buf).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => AccumError::InvalidHeader("file ends within the header".to_string()),
+        _ => AccumError::Io(e),
+    })
+}
+
+/// Fixed-size fields taken in turn from the front of the header.
+struct Fields<'a>(&'a [u8]);
+
+impl Fields<'_> {
+    fn take<const N: usize>(&mut self) -> [u8; N] {
+        let (field, rest) = self.0.split_at(N);
+        self.0 = rest;
+        field.try_into().expect("split at N")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::presets;
+
+    fn provenance() -> Provenance {
+        Provenance {
+            iterations: 123_456,
+            flame_hash: 0x0123_4567_89ab_cdef,
+            seed: Some(42),
+            shard: Some(Shard { index: 1, count: 3 }),
+        }
+    }
+
+    /// A buffer whose every channel differs, holding `f(i)` in channel `i`.
+    fn buffer<T>(width: usize, height: usize, f: impl Fn(usize) -> T) -> Buffer<T> {
+        Buffer::from_flat_vec(width, height, (0 .. width * height * 4).map(f).collect()).unwrap()
+    }
+
+    fn bytes<T: Channel>(buffer: &Buffer<T>, provenance: &Provenance) -> Vec<u8> {
+        let mut out = Vec::new();
+        write(&mut out, buffer, provenance).unwrap();
+        out
+    }
+
+    #[test]
+    fn every_channel_type_round_trips() {
+        let u32s = buffer(5, 3, |i| i as u32 * 7919);
+        let u64s = buffer(5, 3, |i| u64::MAX - i as u64);
+        let f32s = buffer(5, 3, |i| i as f32 * -0.25);
+        for (bytes, channel) in [
+            (bytes(&u32s, &provenance()), ChannelType::U32),
+            (bytes(&u64s, &provenance()), ChannelType::U64),
+            (bytes(&f32s, &provenance()), ChannelType::F32),
+        ] {
+            assert_eq!(bytes.len(), HEADER_LEN + 5 * 3 * 4 * channel.size());
+            let loaded = read(bytes.as_slice()).unwrap();
+            assert_eq!(loaded.header, Header {
+                version: VERSION,
+                width: 5,
+                height: 3,
+                channel,
+                iterations: 123_456,
+                flame_hash: 0x0123_4567_89ab_cdef,
+                seed: Some(42),
+                shard: Some(Shard { index: 1, count: 3 }),
+            });
+            match loaded.buckets {
+                Buckets::U32(b) => assert_eq!(b.as_flat_slice(), u32s.as_flat_slice()),
+                Buckets::U64(b) => assert_eq!(b.as_flat_slice(), u64s.as_flat_slice()),
+                Buckets::F32(b) => assert_eq!(b.as_flat_slice(), f32s.as_flat_slice()),
+            }
+        }
+    }
+
+    #[test]
+    fn headers_are_little_endian_and_optional_fields_stay_empty() {
+        let out = bytes(&buffer(2, 1, |i| i as u32), &Provenance { iterations: 9, ..Provenance::default() });
+        assert_eq!(&out[.. 8], b"FLAMEACC");
+        assert_eq!(&out[8 .. 10], &VERSION.to_le_bytes());
+        assert_eq!(&out[10 .. 14], &[4, 3, 2, 1]);
+        // The first channel of the payload.
+        assert_eq!(&out[HEADER_LEN + 4 .. HEADER_LEN + 8], &[1, 0, 0, 0]);
+        let header = read_header(out.as_slice()).unwrap();
+        assert_eq!((header.seed, header.shard, header.iterations), (None, None, 9));
+    }
+
+    #[test]
+    fn morton_buffers_are_written_row_by_row() {
+        let mut morton = Buffer::<u32>::new_with_layout(3, 2, Layout::Morton);
+        let row_major = buffer(3, 2, |i| if i % 4 == 0 { i as u32 } else { 0 });
+        for y in 0 .. 2 {
+            for x in 0 .. 3 {
+                morton.bucket_mut(x, y).alpha = ((x + y * 3) * 4) as u32;
+            }
+        }
+        assert_eq!(bytes(&morton, &provenance()), bytes(&row_major, &provenance()));
+    }
+
+    /// The error reading `bytes` after `corrupt` has changed them.
+    fn corrupted(corrupt: impl FnOnce(&mut Vec<u8>)) -> AccumError {
+        let mut out = bytes(&buffer(2, 2, |i| i as u32), &provenance());
+        corrupt(&mut out);
+        read(out.as_slice()).unwrap_err()
+    }
+
+    #[test]
+    fn corrupted_headers_are_rejected() {
+        assert!(matches!(corrupted(|b| b[0] = b'X'), AccumError::NotAccumulator));
+        assert!(matches!(corrupted(|b| b[8 .. 10].copy_from_slice(&0u16.to_le_bytes())), AccumError::UnsupportedVersion(0)));
+        let later = VERSION + 1;
+        assert!(matches!(
+            corrupted(|b| b[8 .. 10].copy_from_slice(&later.to_le_bytes())),
+            AccumError::UnsupportedVersion(v) if v == later
+        ));
+        assert!(matches!(corrupted(|b| b[10 .. 14].reverse()), AccumError::ByteOrder([1, 2, 3, 4])));
+        assert!(matches!(corrupted(|b| b[14] = 9), AccumError::UnknownChannel(9)));
+        assert!(matches!(corrupted(|b| b[15] = 4), AccumError::InvalidHeader(_)));
+        // Shard 1 of 1.
+        assert!(matches!(corrupted(|b| b[60 .. 64].copy_from_slice(&1u32.to_le_bytes())), AccumError::InvalidHeader(_)));
+        // Width and height whose product overflows.
+        assert!(matches!(
+            corrupted(|b| {
+                b[16 .. 24].copy_from_slice(&u64::MAX.to_le_bytes());
+                b[24 .. 32].copy_from_slice(&u64::MAX.to_le_bytes());
+            }),
+            AccumError::InvalidHeader(_)
+        ));
+        assert!(matches!(corrupted(|b| b.truncate(20)), AccumError::InvalidHeader(_)));
+        assert!(matches!(corrupted(Vec::clear), AccumError::InvalidHeader(_)));
+    }
+
+    #[test]
+    fn truncated_payloads_are_rejected() {
+        let expected = 2 * 2 * 4 * 4;
+        match corrupted(|b| b.truncate(HEADER_LEN + expected - 1)) {
+            AccumError::Truncated { expected: e, found } => assert_eq!((e, found), (expected, expected - 1)),
+            e => panic!("expected a truncated file, found {}", e),
+        }
+        assert!(matches!(corrupted(|b| b.truncate(HEADER_LEN)), AccumError::Truncated { found: 0, .. }));
+        // A header claiming far more buckets than follow fails at the end
+        // of the file rather than allocating them.
+        assert!(matches!(
+            corrupted(|b| b[16 .. 24].copy_from_slice(&(1u64 << 40).to_le_bytes())),
+            AccumError::Truncated { .. }
+        ));
+    }
+
+    #[test]
+    fn other_channel_types_are_not_converted() {
+        let out = bytes(&buffer(2, 2, |i| i as u64), &provenance());
+        let e = read(out.as_slice()).unwrap().into_buffer::<u32>().unwrap_err();
+        assert!(matches!(e, AccumError::ChannelMismatch { expected: ChannelType::U32, found: ChannelType::U64 }));
+        let out = bytes(&buffer(2, 2, |i| i as f32), &provenance());
+        let e = read(out.as_slice()).unwrap().into_buffer::<u64>().unwrap_err();
+        assert!(matches!(e, AccumError::ChannelMismatch { expected: ChannelType::U64, found: ChannelType::F32 }));
+    }
+
+    #[test]
+    fn accumulators_resume_only_for_their_flame() {
+        let gasket = presets::gasket();
+        let acc = gasket.accumulator(4, 4).unwrap();
+        let out = bytes(acc.buffer(), &Provenance { iterations: 77, ..Provenance::of(&acc) });
+
+        let resumed = read(out.as_slice()).unwrap().into_accumulator(&gasket).unwrap();
+        assert_eq!((resumed.width(), resumed.iters_done()), (4, 77));
+        let e = read(out.as_slice()).unwrap().into_accumulator(&presets::fern()).unwrap_err();
+        assert!(matches!(e, AccumError::FlameMismatch { .. }));
+
+        // Version 1 hashed flames differently, so its hashes are not compared.
+        let mut old = out.clone();
+        old[8 .. 10].copy_from_slice(&1u16.to_le_bytes());
+        assert!(read(old.as_slice()).unwrap().into_accumulator(&presets::fern()).is_ok());
+    }
+
+    #[test]
+    fn rescued_histograms_are_saved_beside_the_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let histogram = buffer(3, 3, |i| i as u32);
+        let path = rescue(&dir.path().join("out.png"), &histogram, &provenance()).unwrap();
+        assert_eq!(path, dir.path().join(format!("out.png.{}", RESCUE_EXTENSION)));
+        let loaded = read(File::open(path).unwrap()).unwrap().into_buffer::<u32>().unwrap();
+        assert_eq!(loaded.as_flat_slice(), histogram.as_flat_slice());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn histograms_are_rescued_to_the_temporary_directory_when_the_output_directory_is_read_only() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::set_permissions(dir.path(), std::fs::Permissions::from_mode(0o555)).unwrap();
+        // Permissions are not enforced for every user, root among them.
+        if File::create(dir.path().join("probe")).is_ok() {
+            return;
+        }
+
+        // Named after the directory, so that tests run at once do not share
+        // a file in the temporary directory.
+        let name = format!("{}.png", dir.path().file_name().unwrap().to_string_lossy());
+        let histogram = buffer(3, 3, |i| i as u32);
+        let path = rescue(&dir.path().join(&name), &histogram, &provenance()).unwrap();
+        assert_eq!(path, std::env::temp_dir().join(format!("{}.{}", name, RESCUE_EXTENSION)));
+        let loaded = read(File::open(&path).unwrap()).unwrap().into_buffer::<u32>();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.unwrap().as_flat_slice(), histogram.as_flat_slice());
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+}
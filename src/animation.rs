@@ -0,0 +1,675 @@
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use serde_json::Value;
+
+use super::core::{Flame, LerpError, LerpOptions};
+use super::file::{DescriptorError, FlameSource};
+use super::template::{self, ParamMap, TemplateError};
+
+#[derive(Debug)]
+pub enum AnimationError {
+    Expr(String),
+    Path(String),
+    Descriptor(DescriptorError),
+    Lerp(LerpError),
+    Template(TemplateError),
+}
+
+impl std::fmt::Display for AnimationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AnimationError::Expr(e) => write!(f, "invalid expression: {}", e),
+            AnimationError::Path(e) => write!(f, "invalid parameter path: {}", e),
+            AnimationError::Descriptor(e) => write!(f, "{}", e),
+            AnimationError::Lerp(e) => write!(f, "{}", e),
+            AnimationError::Template(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for AnimationError {}
+
+impl From<DescriptorError> for AnimationError {
+    fn from(e: DescriptorError) -> Self { AnimationError::Descriptor(e) }
+}
+
+impl From<LerpError> for AnimationError {
+    fn from(e: LerpError) -> Self { AnimationError::Lerp(e) }
+}
+
+impl From<TemplateError> for AnimationError {
+    fn from(e: TemplateError) -> Self { AnimationError::Template(e) }
+}
+
+impl From<serde_json::Error> for AnimationError {
+    fn from(e: serde_json::Error) -> Self { AnimationError::Descriptor(DescriptorError::Json(e)) }
+}
+
+/// Variables available to modulation expressions.
+#[derive(Debug, Clone, Copy)]
+pub struct Vars {
+    /// Phase of the frame in the sequence, in [0, 1).
+    pub t: f64,
+    /// Index of the frame.
+    pub n: f64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Func { Sin, Cos, Tan, Abs, Sqrt, Exp, Ln, Floor, Min, Max, Pow }
+
+impl Func {
+    fn from_name(name: &str) -> Option<(Func, usize)> {
+        Some(match name {
+            "sin" => (Func::Sin, 1),
+            "cos" => (Func::Cos, 1),
+            "tan" => (Func::Tan, 1),
+            "abs" => (Func::Abs, 1),
+            "sqrt" => (Func::Sqrt, 1),
+            "exp" => (Func::Exp, 1),
+            "ln" => (Func::Ln, 1),
+            "floor" => (Func::Floor, 1),
+            "min" => (Func::Min, 2),
+            "max" => (Func::Max, 2),
+            "pow" => (Func::Pow, 2),
+            _ => return None,
+        })
+    }
+
+    fn apply(self, args: &[f64]) -> f64 {
+        match self {
+            Func::Sin => args[0].sin(),
+            Func::Cos => args[0].cos(),
+            Func::Tan => args[0].tan(),
+            Func::Abs => args[0].abs(),
+            Func::Sqrt => args[0].sqrt(),
+            Func::Exp => args[0].exp(),
+            Func::Ln => args[0].ln(),
+            Func::Floor => args[0].floor(),
+            Func::Min => args[0].min(args[1]),
+            Func::Max => args[0].max(args[1]),
+            Func::Pow => args[0].powf(args[1]),
+        }
+    }
+}
+
+/// An arithmetic expression in the frame variables `t` and `n`, and in
+/// template parameters written `$name`.
+///
+/// Supports `+ - * / ^`, parentheses, the constants `pi`, `tau` and `e`, and
+/// the functions `sin cos tan abs sqrt exp ln floor min max pow`.
+#[derive(Debug, Clone)]
+pub enum Expr {
+    Num(f64),
+    T,
+    N,
+    Param(String),
+    Neg(Box<Expr>),
+    Add(Box<Expr>, Box<Expr>),
+    Sub(Box<Expr>, Box<Expr>),
+    Mul(Box<Expr>, Box<Expr>),
+    Div(Box<Expr>, Box<Expr>),
+    Pow(Box<Expr>, Box<Expr>),
+    Call(Func, Vec<Expr>),
+}
+
+impl Expr {
+    /// The value of the expression, with every parameter NaN.
+    pub fn eval(&self, vars: Vars) -> f64 {
+        self.eval_with(vars, &ParamMap::new())
+    }
+
+    /// The value of the expression, taking parameters from `params`, and
+    /// NaN for those missing.
+    pub fn eval_with(&self, vars: Vars, params: &ParamMap) -> f64 {
+        let eval = |e: &Expr| e.eval_with(vars, params);
+        match self {
+            Expr::Num(x) => *x,
+            Expr::T => vars.t,
+            Expr::N => vars.n,
+            Expr::Param(name) => params.get(name).copied().unwrap_or(f64::NAN),
+            Expr::Neg(a) => -eval(a),
+            Expr::Add(a, b) => eval(a) + eval(b),
+            Expr::Sub(a, b) => eval(a) - eval(b),
+            Expr::Mul(a, b) => eval(a) * eval(b),
+            Expr::Div(a, b) => eval(a) / eval(b),
+            Expr::Pow(a, b) => eval(a).powf(eval(b)),
+            Expr::Call(f, args) => {
+                let args: Vec<f64> = args.iter().map(eval).collect();
+                f.apply(&args)
+            }
+        }
+    }
+
+    /// The parameters the expression refers to, in order of appearance.
+    pub fn params(&self) -> Vec<&str> {
+        let mut names = Vec::new();
+        self.visit(&mut |e| if let Expr::Param(name) = e {
+            if !names.contains(&name.as_str()) {
+                names.push(name.as_str());
+            }
+        });
+        names
+    }
+
+    /// Whether the expression refers to `t` or `n`.
+    pub fn uses_frame(&self) -> bool {
+        let mut uses = false;
+        self.visit(&mut |e| uses |= matches!(e, Expr::T | Expr::N));
+        uses
+    }
+
+    fn visit<'a>(&'a self, f: &mut impl FnMut(&'a Expr)) {
+        f(self);
+        match self {
+            Expr::Num(_) | Expr::T | Expr::N | Expr::Param(_) => {}
+            Expr::Neg(a) => a.visit(f),
+            Expr::Add(a, b) | Expr::Sub(a, b) | Expr::Mul(a, b) | Expr::Div(a, b) | Expr::Pow(a, b) => {
+                a.visit(f);
+                b.visit(f);
+            }
+            Expr::Call(_, args) => args.iter().for_each(|a| a.visit(f)),
+        }
+    }
+}
+
+impl FromStr for Expr {
+    type Err = AnimationError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser { src: s.as_bytes(), pos: 0 };
+        let expr = parser.expr()?;
+        parser.skip_space();
+        if parser.pos < s.len() {
+            return Err(parser.error("unexpected input"));
+        }
+        Ok(expr)
+    }
+}
+
+struct Parser<'a> {
+    src: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, msg: &str) -> AnimationError {
+        AnimationError::Expr(format!("{} at position {}", msg, self.pos))
+    }
+
+    fn skip_space(&mut self) {
+        while self.src.get(self.pos).is_some_and(u8::is_ascii_whitespace) {
+            self.pos += 1;
+        }
+    }
+
+    fn eat(&mut self, c: u8) -> bool {
+        self.skip_space();
+        if self.src.get(self.pos) == Some(&c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expr(&mut self) -> Result<Expr, AnimationError> {
+        let mut lhs = self.term()?;
+        loop {
+            if self.eat(b'+') {
+                lhs = Expr::Add(Box::new(lhs), Box::new(self.term()?));
+            } else if self.eat(b'-') {
+                lhs = Expr::Sub(Box::new(lhs), Box::new(self.term()?));
+            } else {
+                return Ok(lhs);
+            }
+        }
+    }
+
+    fn term(&mut self) -> Result<Expr, AnimationError> {
+        let mut lhs = self.unary()?;
+        loop {
+            if self.eat(b'*') {
+                lhs = Expr::Mul(Box::new(lhs), Box::new(self.unary()?));
+            } else if self.eat(b'/') {
+                lhs = Expr::Div(Box::new(lhs), Box::new(self.unary()?));
+            } else {
+                return Ok(lhs);
+            }
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, AnimationError> {
+        if self.eat(b'-') {
+            return Ok(Expr::Neg(Box::new(self.unary()?)));
+        }
+        let base = self.atom()?;
+        if self.eat(b'^') {
+            return Ok(Expr::Pow(Box::new(base), Box::new(self.unary()?)));
+        }
+        Ok(base)
+    }
+
+    fn atom(&mut self) -> Result<Expr, AnimationError> {
+        self.skip_space();
+        if self.eat(b'(') {
+            let e = self.expr()?;
+            if !self.eat(b')') {
+                return Err(self.error("expected ')'"));
+            }
+            return Ok(e);
+        }
+
+        let start = self.pos;
+        let Some(&c) = self.src.get(self.pos) else {
+            return Err(self.error("unexpected end of expression"));
+        };
+
+        if c.is_ascii_digit() || c == b'.' {
+            while self.src.get(self.pos).is_some_and(|c| c.is_ascii_digit() || *c == b'.') {
+                self.pos += 1;
+            }
+            if self.src.get(self.pos).is_some_and(|c| *c == b'e' || *c == b'E') {
+                self.pos += 1;
+                if self.src.get(self.pos).is_some_and(|c| *c == b'-' || *c == b'+') {
+                    self.pos += 1;
+                }
+                while self.src.get(self.pos).is_some_and(u8::is_ascii_digit) {
+                    self.pos += 1;
+                }
+            }
+            let text = std::str::from_utf8(&self.src[start .. self.pos]).unwrap();
+            return text.parse().map(Expr::Num).map_err(|_| self.error("invalid number"));
+        }
+
+        if c == b'$' {
+            self.pos += 1;
+            let start = self.pos;
+            while self.src.get(self.pos).is_some_and(|c| c.is_ascii_alphanumeric() || *c == b'_') {
+                self.pos += 1;
+            }
+            if start == self.pos || self.src[start].is_ascii_digit() {
+                return Err(self.error("expected a parameter name after '$'"));
+            }
+            let name = std::str::from_utf8(&self.src[start .. self.pos]).unwrap();
+            return Ok(Expr::Param(name.to_string()));
+        }
+
+        if c.is_ascii_alphabetic() {
+            while self.src.get(self.pos).is_some_and(|c| c.is_ascii_alphanumeric() || *c == b'_') {
+                self.pos += 1;
+            }
+            let name = std::str::from_utf8(&self.src[start .. self.pos]).unwrap();
+            return match name {
+                "t" => Ok(Expr::T),
+                "n" => Ok(Expr::N),
+                "pi" => Ok(Expr::Num(std::f64::consts::PI)),
+                "tau" => Ok(Expr::Num(std::f64::consts::TAU)),
+                "e" => Ok(Expr::Num(std::f64::consts::E)),
+                _ => {
+                    let (func, arity) = Func::from_name(name)
+                        .ok_or_else(|| self.error(&format!("unknown name '{}'", name)))?;
+                    if !self.eat(b'(') {
+                        return Err(self.error("expected '('"));
+                    }
+                    let mut args = vec![self.expr()?];
+                    while self.eat(b',') {
+                        args.push(self.expr()?);
+                    }
+                    if !self.eat(b')') {
+                        return Err(self.error("expected ')'"));
+                    }
+                    if args.len() != arity {
+                        return Err(self.error(&format!("'{}' takes {} argument(s)", name, arity)));
+                    }
+                    Ok(Expr::Call(func, args))
+                }
+            };
+        }
+
+        Err(self.error("unexpected character"))
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Segment {
+    Key(String),
+    Index(usize),
+}
+
+/// A path to a number inside a descriptor, such as `functions[0][2][4]` or
+/// `bounds[1]`.
+#[derive(Debug, Clone)]
+pub struct ValuePath(Vec<Segment>);
+
+impl FromStr for ValuePath {
+    type Err = AnimationError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || AnimationError::Path(s.to_string());
+        let mut segments = Vec::new();
+        let mut rest = s.trim();
+
+        while !rest.is_empty() {
+            if let Some(r) = rest.strip_prefix('[') {
+                let end = r.find(']').ok_or_else(err)?;
+                segments.push(Segment::Index(r[.. end].trim().parse().map_err(|_| err())?));
+                rest = &r[end + 1 ..];
+            } else {
+                let r = if segments.is_empty() { rest } else { rest.strip_prefix('.').ok_or_else(err)? };
+                let end = r.find(['.', '[']).unwrap_or(r.len());
+                if end == 0 {
+                    return Err(err());
+                }
+                segments.push(Segment::Key(r[.. end].to_string()));
+                rest = &r[end ..];
+            }
+        }
+
+        if segments.is_empty() { Err(err()) } else { Ok(ValuePath(segments)) }
+    }
+}
+
+impl std::fmt::Display for ValuePath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, seg) in self.0.iter().enumerate() {
+            match seg {
+                Segment::Key(k) if i == 0 => write!(f, "{}", k)?,
+                Segment::Key(k) => write!(f, ".{}", k)?,
+                Segment::Index(n) => write!(f, "[{}]", n)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ValuePath {
+    /// The number this path refers to, if there is one.
+    pub fn get_mut<'a>(&self, value: &'a mut Value) -> Option<&'a mut Value> {
+        let mut v = value;
+        for seg in &self.0 {
+            v = match seg {
+                Segment::Key(k) => v.get_mut(k.as_str())?,
+                Segment::Index(n) => v.get_mut(*n)?,
+            };
+        }
+        v.is_number().then_some(v)
+    }
+
+    pub fn set(&self, value: &mut Value, x: f64) -> Result<(), AnimationError> {
+        let target = self.get_mut(value)
+            .ok_or_else(|| AnimationError::Path(format!("'{}' does not refer to a number", self)))?;
+        if !x.is_finite() {
+            return Err(DescriptorError::NonFinite { path: self.to_string(), value: x }.into());
+        }
+        // Whole numbers are written as integers, for fields which are, such
+        // as the channels of palette colors.
+        *target = if x.fract() == 0.0 && x.abs() < (1u64 << 53) as f64 {
+            Value::from(x as i64)
+        } else {
+            serde_json::Number::from_f64(x).map(Value::Number).expect("values are finite")
+        };
+        Ok(())
+    }
+}
+
+/// An assignment of an expression to a descriptor parameter, written
+/// `path = expression`.
+#[derive(Debug, Clone)]
+pub struct Modulation {
+    pub path: ValuePath,
+    pub expr: Expr,
+}
+
+impl FromStr for Modulation {
+    type Err = AnimationError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (path, expr) = s.split_once('=')
+            .ok_or_else(|| AnimationError::Expr(format!("expected 'path = expression', got '{}'", s)))?;
+        let expr: Expr = expr.parse()?;
+        if let Some(name) = expr.params().first() {
+            let msg = format!("'${}' cannot be used in a modulation; modulate 'params.{}' instead", name, name);
+            return Err(AnimationError::Expr(msg));
+        }
+        Ok(Modulation { path: path.parse()?, expr })
+    }
+}
+
+/// A sequence of flames produced by applying modulations to a base
+/// descriptor once per frame.
+///
+/// The base may be a template, whose parameters are modulated by paths
+/// such as `params.spin` and which is instantiated after the modulations
+/// are applied, so that one parameter can drive many numbers.
+pub struct ModulatedSequence {
+    base: Value,
+    mods: Vec<Modulation>,
+    frames: usize,
+    next: usize,
+    dir: PathBuf,
+}
+
+impl ModulatedSequence {
+    /// Checks every modulation against the base descriptor, so that invalid
+    /// paths are reported before any frame is produced.
+    pub fn new(base: Value, mods: Vec<Modulation>, frames: usize) -> Result<Self, AnimationError> {
+        let seq = ModulatedSequence { base, mods, frames, next: 0, dir: PathBuf::new() };
+        if frames > 0 {
+            seq.descriptor(0)?;
+        }
+        Ok(seq)
+    }
+
+    /// Resolve relative paths in the descriptor against `dir`.
+    pub fn with_base_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.dir = dir.into();
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames == 0
+    }
+
+    /// The descriptor for a frame, with every modulation applied and, if
+    /// the base is a template, instantiated.
+    pub fn descriptor(&self, frame: usize) -> Result<Value, AnimationError> {
+        let vars = Vars { t: frame as f64 / self.frames as f64, n: frame as f64 };
+        let mut value = self.base.clone();
+        for m in &self.mods {
+            m.path.set(&mut value, m.expr.eval(vars))?;
+        }
+        if template::is_template(&value) {
+            value = template::instantiate(value, &ParamMap::new())?;
+        }
+        Ok(value)
+    }
+
+    pub fn flame(&self, frame: usize) -> Result<Flame, AnimationError> {
+        let source = FlameSource::from_value(self.descriptor(frame)?, &self.dir)?;
+        Ok(source.to_flame()?)
+    }
+}
+
+impl Iterator for ModulatedSequence {
+    type Item = Result<Flame, AnimationError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next >= self.frames {
+            return None;
+        }
+        self.next += 1;
+        Some(self.flame(self.next - 1))
+    }
+}
+
+/// A sequence of flames morphing from one flame to another, including both.
+pub struct MorphSequence {
+    from: Flame,
+    to: Flame,
+    opts: LerpOptions,
+    frames: usize,
+    next: usize,
+}
+
+impl MorphSequence {
+    /// Checks that the flames can be interpolated, so that a mismatch is
+    /// reported before any frame is produced.
+    pub fn new(from: Flame, to: Flame, frames: usize, opts: LerpOptions) -> Result<Self, AnimationError> {
+        from.lerp(&to, 0.0, opts)?;
+        Ok(MorphSequence { from, to, opts, frames, next: 0 })
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames == 0
+    }
+
+    pub fn flame(&self, frame: usize) -> Result<Flame, AnimationError> {
+        let t = frame as f32 / self.frames.saturating_sub(1).max(1) as f32;
+        Ok(self.from.lerp(&self.to, t, self.opts)?)
+    }
+}
+
+impl Iterator for MorphSequence {
+    type Item = Result<Flame, AnimationError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next >= self.frames {
+            return None;
+        }
+        self.next += 1;
+        Some(self.flame(self.next - 1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::presets;
+
+    fn eval(s: &str, t: f64, n: f64) -> f64 {
+        s.parse::<Expr>().unwrap_or_else(|e| panic!("'{}': {}", s, e)).eval(Vars { t, n })
+    }
+
+    fn base() -> Value {
+        serde_json::to_value(FlameSource::from_flame(&presets::gasket())).unwrap()
+    }
+
+    fn mods(mods: &[&str]) -> Vec<Modulation> {
+        mods.iter().map(|m| m.parse().unwrap_or_else(|e| panic!("'{}': {}", m, e))).collect()
+    }
+
+    #[test]
+    fn expressions_follow_the_usual_precedence() {
+        for (s, expected) in [
+            ("1 + 2 * 3", 7.0),
+            ("(1 + 2) * 3", 9.0),
+            ("2 ^ 3 ^ 2", 512.0),
+            ("-2 ^ 2", -4.0),
+            ("8 / 4 / 2", 1.0),
+            ("1 - 2 - 3", -4.0),
+            ("2 * -t", -1.0),
+            ("1.5e2 + .5", 150.5),
+            ("min(n, 3) + max(1, 2) + pow(2, 3)", 13.0),
+            ("floor(2.7) + abs(-1) + sqrt(9) + ln(e) + exp(0)", 8.0),
+        ] {
+            assert_eq!(eval(s, 0.5, 4.0), expected, "{}", s);
+        }
+        assert!((eval("0.5*sin(t*tau)", 0.25, 0.0) - 0.5).abs() < 1e-12);
+        assert!((eval("cos(pi) + tan(0)", 0.0, 0.0) + 1.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn malformed_expressions_are_refused() {
+        for s in ["", "1 +", "(1", "2 3", "sin 1", "sin(1, 2)", "min(1)", "frame", "1 # 2", "$", "$1x"] {
+            assert!(matches!(s.parse::<Expr>(), Err(AnimationError::Expr(_))), "'{}'", s);
+        }
+        // Template parameters are not modulated directly.
+        assert!("bounds[0] = $spin".parse::<Modulation>().is_err());
+        assert!("bounds[0] 1".parse::<Modulation>().is_err());
+    }
+
+    #[test]
+    fn paths_are_parsed_and_written_back() {
+        for s in ["functions[0][2][4]", "bounds[1]", "render.gamma", "a.b[2].c"] {
+            assert_eq!(s.parse::<ValuePath>().unwrap().to_string(), s);
+        }
+        for s in ["", "[x]", "a..b", "a[1", ".a", "a[-1]"] {
+            assert!(s.parse::<ValuePath>().is_err(), "'{}'", s);
+        }
+        let mut doc = serde_json::json!({"a": [1, {"b": 2}], "c": "text"});
+        "a[1].b".parse::<ValuePath>().unwrap().set(&mut doc, 5.5).unwrap();
+        assert_eq!(doc["a"][1]["b"], 5.5);
+        // Only numbers which are there can be set.
+        for path in ["a[2]", "c", "a", "d"] {
+            assert!(path.parse::<ValuePath>().unwrap().set(&mut doc, 1.0).is_err(), "{}", path);
+        }
+        assert!("a[0]".parse::<ValuePath>().unwrap().set(&mut doc, f64::NAN).is_err());
+    }
+
+    #[test]
+    fn modulations_set_numbers_in_each_frame() {
+        let seq = ModulatedSequence::new(base(), mods(&[
+            "functions[0][2][4] = 0.5*sin(t*tau)",
+            "functions[1][0] = 0.2 + t",
+            "functions[2][3] = n / 8",
+            "bounds[1] = 1 + t",
+            "palette[0][0] = 100 + 2*n",
+        ]), 8).unwrap();
+        assert_eq!(seq.len(), 8);
+
+        let first = seq.flame(0).unwrap();
+        assert_eq!(first.functions[0].trans.matrix()[(0, 2)], 0.0);
+        assert_eq!(first.functions[1].weight, 0.2);
+        assert_eq!(first.functions[2].color, 0);
+        assert_eq!(first.bounds.to_array()[1], 1.0);
+        assert_eq!(first.palette.keys()[0].red, 100);
+
+        // Halfway through, t is 0.5 and n is 4.
+        let middle = seq.flame(4).unwrap();
+        assert!(middle.functions[0].trans.matrix()[(0, 2)].abs() < 1e-6);
+        assert_eq!(middle.functions[1].weight, 0.7);
+        assert_eq!(middle.functions[2].color, 127);
+        assert_eq!(middle.bounds.to_array()[1], 1.5);
+        assert_eq!(middle.palette.keys()[0].red, 108);
+        let quarter = seq.flame(2).unwrap();
+        assert!((quarter.functions[0].trans.matrix()[(0, 2)] - 0.5).abs() < 1e-6);
+
+        // Everything else is the base.
+        assert_eq!(middle.functions[0].weight, presets::gasket().functions[0].weight);
+        assert_eq!(seq.count(), 8);
+    }
+
+    #[test]
+    fn invalid_paths_fail_before_the_first_frame() {
+        for m in ["functions[9][0] = t", "bounds = t", "render.gamma = t", "palette[0][0] = 1 / 0"] {
+            assert!(ModulatedSequence::new(base(), mods(&[m]), 4).is_err(), "{}", m);
+        }
+        // A value which is only infinite in a later frame fails in that frame.
+        let seq = ModulatedSequence::new(base(), mods(&["bounds[1] = 1 / (t - 0.75)"]), 4).unwrap();
+        assert!(seq.flame(0).is_ok());
+        assert!(seq.flame(3).is_err());
+    }
+
+    #[test]
+    fn template_parameters_drive_many_numbers() {
+        let template = serde_json::json!({
+            "params": {"spin": 0.0},
+            "bounds": [-1, 1, -1, 1],
+            "functions": [[0.5, "Id", ["$spin", 0, 0, "$spin", 0, 0], 0], [0.5, "Id", [0.5, 0, 0, 0.5, "$spin", 0], 1]],
+            "palette": [[0, 0, 0], [255, 255, 255]]
+        });
+        let seq = ModulatedSequence::new(template, mods(&["params.spin = 0.25 + t"]), 2).unwrap();
+        let flame = seq.flame(1).unwrap();
+        assert_eq!(flame.functions[0].trans.matrix()[(0, 0)], 0.75);
+        assert_eq!(flame.functions[1].trans.matrix()[(0, 2)], 0.75);
+    }
+}
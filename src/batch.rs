@@ -0,0 +1,545 @@
+//! Rendering many flames in one process.
+//!
+//! Jobs are pulled lazily from an iterator, so descriptors can be generated
+//! as they are needed, and at most a fixed number are rendered at once. A
+//! job that fails is reported in its result without affecting the others.
+//!
+//! A batch can keep a `Journal` of the items it has finished, so that an
+//! interrupted batch can be resumed without redoing them.
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::sync_channel;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use super::core::*;
+use super::error::FlameError;
+use super::file::FlameSource;
+use super::meta::fnv1a;
+use super::naming::{write_atomically, UniquePathAllocator};
+use super::output::*;
+
+/// Position of a job in the sequence passed to `render_all`.
+pub type JobId = usize;
+
+/// The flame a job renders.
+pub enum JobSource {
+    Flame(Box<Flame>),
+    /// The text of a JSON descriptor, with the directory relative paths in
+    /// it are resolved against.
+    Descriptor { text: String, base: PathBuf },
+}
+
+pub struct RenderJob {
+    pub source: JobSource,
+    pub run: RunConfig,
+    pub tone: ToneMapping,
+    pub encoding: OutputEncoding,
+    pub sink: Box<dyn ImageSink + Send>,
+}
+
+impl RenderJob {
+    pub fn new(
+        flame: Flame,
+        run: RunConfig,
+        tone: ToneMapping,
+        encoding: OutputEncoding,
+        sink: impl ImageSink + Send + 'static,
+    ) -> Self {
+        RenderJob { source: JobSource::Flame(Box::new(flame)), run, tone, encoding, sink: Box::new(sink) }
+    }
+
+    /// A job writing `stem.EXTENSION` in the allocator's directory, in the
+    /// format the extension names. The file is claimed now, so no other job
+    /// or process can choose it, and the image is written over it
+    /// atomically. Returns the job and the path claimed.
+    pub fn allocated(
+        flame: Flame,
+        run: RunConfig,
+        tone: ToneMapping,
+        encoding: OutputEncoding,
+        allocator: &UniquePathAllocator,
+        stem: &str,
+        extension: &str,
+    ) -> Result<(Self, PathBuf), FlameError> {
+        let format: OutputFormat = extension.parse()
+            .map_err(|_| SinkError::UnknownFormat(PathBuf::from(format!("{}.{}", stem, extension))))?;
+        let (_, path) = allocator.allocate(stem, extension)?;
+        let sink = FileSink::new_with_format(&path, format).atomic();
+        Ok((RenderJob::new(flame, run, tone, encoding, sink), path))
+    }
+}
+
+/// Notification of a change in a job's state.
+#[derive(Debug, Clone, Copy)]
+pub enum JobEvent {
+    Started,
+    Finished(SessionStats),
+    Failed,
+}
+
+pub struct JobResult {
+    pub id: JobId,
+    /// Time from the start of the job to its completion or failure.
+    pub elapsed: Duration,
+    /// Statistics of the render, if it got that far.
+    pub stats: Option<SessionStats>,
+    pub result: Result<(), FlameError>,
+}
+
+impl JobSource {
+    fn into_flame(self) -> Result<Flame, FlameError> {
+        match self {
+            JobSource::Flame(flame) => Ok(*flame),
+            JobSource::Descriptor { text, base } => {
+                let value = serde_json::from_str(&text)?;
+                Ok(FlameSource::from_value(value, base)?.to_flame()?)
+            }
+        }
+    }
+}
+
+fn run_job(job: RenderJob, stats: &mut Option<SessionStats>) -> Result<(), FlameError> {
+    let RenderJob { source, run, tone, encoding, mut sink } = job;
+    let flame = source.into_flame()?;
+    if let Some(finding) = flame.validate().into_iter().find(|f| f.severity == Severity::Error) {
+        return Err(FlameError::Validation(finding.message));
+    }
+
+    let mut session = RenderSession::new(flame, run)?;
+    session.run();
+    *stats = Some(session.stats());
+    let toned = session.into_buffer().tone_map(&tone);
+    let image = encode_for(&toned, encoding, sink.format())?;
+    write_image(&image, sink.as_mut())?;
+    Ok(())
+}
+
+/// Render every job, with at most `parallel_flames` in progress at once.
+///
+/// Each job's own `RunConfig` controls how many threads it uses, so the
+/// total is up to `parallel_flames` times that. Results are returned in the
+/// order the jobs were given, and `progress` is called from the worker
+/// threads as jobs start and end.
+pub fn render_all<P>(
+    jobs: impl IntoIterator<Item = RenderJob>,
+    parallel_flames: usize,
+    progress: P,
+) -> Vec<JobResult>
+where
+    P: Fn(JobId, JobEvent) + Sync,
+{
+    let (sender, receiver) = sync_channel::<(JobId, RenderJob)>(0);
+    let receiver = Mutex::new(receiver);
+    let results = Mutex::new(Vec::new());
+
+    std::thread::scope(|s| {
+        for _ in 0 .. parallel_flames.max(1) {
+            s.spawn(|| loop {
+                // The lock is released as soon as a job is received.
+                let next = receiver.lock().unwrap().recv();
+                let Ok((id, job)) = next else { break };
+
+                progress(id, JobEvent::Started);
+                let start = Instant::now();
+                let mut stats = None;
+                let result = run_job(job, &mut stats);
+                progress(id, match (&result, stats) {
+                    (Ok(()), Some(stats)) => JobEvent::Finished(stats),
+                    _ => JobEvent::Failed,
+                });
+
+                results.lock().unwrap().push(JobResult { id, elapsed: start.elapsed(), stats, result });
+            });
+        }
+
+        for (id, job) in jobs.into_iter().enumerate() {
+            if sender.send((id, job)).is_err() {
+                break;
+            }
+        }
+        drop(sender);
+    });
+
+    let mut results = results.into_inner().unwrap();
+    results.sort_by_key(|r| r.id);
+    results
+}
+
+/// Name of the journal a batch keeps in its output directory.
+pub const JOURNAL_FILE: &str = "journal.jsonl";
+
+/// A line of a journal after the first, which describes the batch.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+pub enum JournalEntry {
+    /// The item was given a name, and files under it may exist.
+    Claimed { item: usize, stem: String },
+    /// The item's outputs were written in full.
+    Completed { item: usize, stem: String, outputs: Vec<JournalOutput> },
+}
+
+/// An output file of a completed item, relative to the journal's directory.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct JournalOutput {
+    pub path: PathBuf,
+    /// FNV-1a hash of the file's contents, in hex.
+    pub hash: String,
+}
+
+#[derive(Debug)]
+pub enum JournalError {
+    Io(io::Error),
+    /// A line other than the last could not be read.
+    Malformed { line: usize, error: serde_json::Error },
+    /// The journal belongs to a batch run with other settings.
+    Mismatch { key: String, recorded: serde_json::Value, given: serde_json::Value },
+}
+
+impl std::fmt::Display for JournalError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JournalError::Io(e) => write!(f, "could not read journal: {}", e),
+            JournalError::Malformed { line, error } => write!(f, "malformed journal line {}: {}", line, error),
+            JournalError::Mismatch { key, recorded, given } => write!(
+                f,
+                "cannot resume a batch run with different settings: '{}' was {} and is now {}",
+                key, recorded, given
+            ),
+        }
+    }
+}
+
+impl std::error::Error for JournalError {}
+
+impl From<io::Error> for JournalError {
+    fn from(e: io::Error) -> Self { JournalError::Io(e) }
+}
+
+/// A record of a batch's progress, written a line at a time as items are
+/// named and finished, each line on disk before the call recording it
+/// returns.
+///
+/// The first line holds the settings of the batch, which a resumed run must
+/// match, and each line after it a `JournalEntry`.
+pub struct Journal {
+    dir: PathBuf,
+    file: File,
+    batch: serde_json::Value,
+    entries: Vec<JournalEntry>,
+}
+
+impl Journal {
+    /// Start a journal for a batch writing to `dir`, replacing any journal
+    /// already there.
+    pub fn create(dir: impl AsRef<Path>, batch: serde_json::Value) -> io::Result<Journal> {
+        let dir = dir.as_ref().to_path_buf();
+        let mut file = File::create(dir.join(JOURNAL_FILE))?;
+        writeln!(file, "{}", batch)?;
+        file.sync_all()?;
+        Ok(Journal { dir, file, batch, entries: Vec::new() })
+    }
+
+    /// Open the journal in `dir` to continue its batch.
+    ///
+    /// Completed items whose outputs are missing or have changed since are
+    /// kept only as claimed, and a last line cut short by an interruption
+    /// is dropped. The journal is rewritten without them.
+    pub fn open(dir: impl AsRef<Path>) -> Result<Journal, JournalError> {
+        let dir = dir.as_ref().to_path_buf();
+        let path = dir.join(JOURNAL_FILE);
+        let lines: Vec<String> = BufReader::new(File::open(&path)?).lines().collect::<io::Result<_>>()?;
+        let parse_error = |line: usize, error| JournalError::Malformed { line, error };
+
+        let batch = match lines.first() {
+            Some(first) => serde_json::from_str(first).map_err(|e| parse_error(1, e))?,
+            None => return Err(parse_error(1, serde::de::Error::custom("journal is empty"))),
+        };
+        let mut entries = Vec::new();
+        for (i, line) in lines.iter().enumerate().skip(1) {
+            match serde_json::from_str::<JournalEntry>(line) {
+                Ok(JournalEntry::Completed { item, stem, outputs }) if !outputs_intact(&dir, &outputs) => {
+                    entries.push(JournalEntry::Claimed { item, stem });
+                }
+                Ok(entry) => entries.push(entry),
+                Err(_) if i + 1 == lines.len() => {}
+                Err(e) => return Err(parse_error(i + 1, e)),
+            }
+        }
+
+        let mut text = format!("{}\n", batch);
+        for entry in &entries {
+            text += &format!("{}\n", serde_json::to_string(entry).expect("entries serialize"));
+        }
+        write_atomically(&path, text.as_bytes())?;
+        let file = File::options().append(true).open(&path)?;
+        Ok(Journal { dir, file, batch, entries })
+    }
+
+    /// The settings the batch was started with.
+    pub fn batch(&self) -> &serde_json::Value {
+        &self.batch
+    }
+
+    /// Check that `batch` is the settings the journal was started with.
+    pub fn check_batch(&self, batch: &serde_json::Value) -> Result<(), JournalError> {
+        let (Some(recorded), Some(given)) = (self.batch.as_object(), batch.as_object()) else {
+            return match self.batch == *batch {
+                true => Ok(()),
+                false => Err(JournalError::Mismatch { key: String::new(), recorded: self.batch.clone(), given: batch.clone() }),
+            };
+        };
+        let keys = recorded.keys().chain(given.keys());
+        match keys.into_iter().find(|k| recorded.get(*k) != given.get(*k)) {
+            Some(key) => Err(JournalError::Mismatch {
+                key: key.clone(),
+                recorded: recorded.get(key).cloned().unwrap_or_default(),
+                given: given.get(key).cloned().unwrap_or_default(),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// The name `item` was given, if it has been named.
+    pub fn stem(&self, item: usize) -> Option<&str> {
+        self.entries.iter().rev().find_map(|e| match e {
+            JournalEntry::Claimed { item: i, stem } | JournalEntry::Completed { item: i, stem, .. } if *i == item => {
+                Some(stem.as_str())
+            }
+            _ => None,
+        })
+    }
+
+    /// Whether `item`'s outputs were written in full and are unchanged.
+    pub fn is_completed(&self, item: usize) -> bool {
+        self.entries.iter().any(|e| matches!(e, JournalEntry::Completed { item: i, .. } if *i == item))
+    }
+
+    /// Record that `item` was named `stem`.
+    pub fn claim(&mut self, item: usize, stem: &str) -> io::Result<()> {
+        self.append(JournalEntry::Claimed { item, stem: stem.to_string() })
+    }
+
+    /// Record that `item` is finished, with the hashes of its outputs.
+    pub fn complete(&mut self, item: usize, stem: &str, outputs: &[PathBuf]) -> io::Result<()> {
+        let outputs = outputs.iter()
+            .map(|path| {
+                let bytes = std::fs::read(self.dir.join(path))?;
+                Ok(JournalOutput { path: path.clone(), hash: format!("{:016x}", fnv1a(&bytes)) })
+            })
+            .collect::<io::Result<_>>()?;
+        self.append(JournalEntry::Completed { item, stem: stem.to_string(), outputs })
+    }
+
+    fn append(&mut self, entry: JournalEntry) -> io::Result<()> {
+        writeln!(self.file, "{}", serde_json::to_string(&entry).expect("entries serialize"))?;
+        self.file.sync_data()?;
+        self.entries.push(entry);
+        Ok(())
+    }
+}
+
+/// Whether every output exists with the contents it was recorded with.
+fn outputs_intact(dir: &Path, outputs: &[JournalOutput]) -> bool {
+    outputs.iter().all(|o| {
+        std::fs::read(dir.join(&o.path)).is_ok_and(|bytes| format!("{:016x}", fnv1a(&bytes)) == o.hash)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bench::baseline_config;
+    use crate::naming::CollisionPolicy;
+    use crate::presets;
+
+    #[test]
+    fn allocated_jobs_never_share_a_file() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("out.png"), b"keep").unwrap();
+        let allocator = UniquePathAllocator::new(dir.path(), CollisionPolicy::Suffix);
+        let run = RunConfig { width: 16, height: 16, iters: 1000, ..baseline_config(1) };
+
+        let (jobs, paths): (Vec<_>, Vec<_>) = [presets::gasket(), presets::fern(), presets::swirl()].into_iter()
+            .map(|flame| RenderJob::allocated(flame, run, ToneMapping::default(), OutputEncoding::default(), &allocator, "out", "png").unwrap())
+            .unzip();
+        assert_eq!(paths, ["out-2.png", "out-3.png", "out-4.png"].map(|name| dir.path().join(name)));
+
+        let results = render_all(jobs, 3, |_, _| {});
+        assert!(results.iter().all(|r| r.result.is_ok()));
+        assert_eq!(std::fs::read(dir.path().join("out.png")).unwrap(), b"keep");
+        for path in paths {
+            assert_eq!(image::open(path).unwrap().width(), 16);
+        }
+    }
+
+    #[test]
+    fn allocated_jobs_need_a_known_format() {
+        let dir = tempfile::tempdir().unwrap();
+        let allocator = UniquePathAllocator::new(dir.path(), CollisionPolicy::Suffix);
+        let job = RenderJob::allocated(presets::gasket(), baseline_config(1), ToneMapping::default(), OutputEncoding::default(), &allocator, "out", "tiff");
+        assert!(matches!(job, Err(FlameError::Output(SinkError::UnknownFormat(_)))));
+        // Nothing was claimed.
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    /// A sink keeping its images where the test can see them.
+    struct SharedSink(std::sync::Arc<Mutex<Vec<Vec<u8>>>>);
+
+    impl ImageSink for SharedSink {
+        fn write(&mut self, _format: OutputFormat, bytes: &[u8]) -> Result<(), SinkError> {
+            self.0.lock().unwrap().push(bytes.to_vec());
+            Ok(())
+        }
+    }
+
+    struct FullSink;
+
+    impl ImageSink for FullSink {
+        fn write(&mut self, _format: OutputFormat, _bytes: &[u8]) -> Result<(), SinkError> {
+            Err(SinkError::Io(io::Error::new(io::ErrorKind::StorageFull, "sink is full")))
+        }
+    }
+
+    fn small_run() -> RunConfig {
+        RunConfig { width: 16, height: 16, iters: 2_000, seed: Some(1), ..baseline_config(1) }
+    }
+
+    #[test]
+    fn failing_jobs_do_not_affect_the_others() {
+        let images = std::sync::Arc::new(Mutex::new(Vec::new()));
+        let sink = || SharedSink(images.clone());
+        let descriptor = |text: &str| RenderJob {
+            source: JobSource::Descriptor { text: text.to_string(), base: PathBuf::from(".") },
+            run: small_run(),
+            tone: ToneMapping::default(),
+            encoding: OutputEncoding::default(),
+            sink: Box::new(sink()),
+        };
+        let empty = Flame { functions: Vec::new(), ..presets::gasket() };
+        let gasket = serde_json::to_string(&FlameSource::from_flame(&presets::gasket())).unwrap();
+        let jobs = vec![
+            RenderJob::new(presets::gasket(), small_run(), ToneMapping::default(), OutputEncoding::default(), sink()),
+            descriptor("{ not a descriptor"),
+            RenderJob::new(empty, small_run(), ToneMapping::default(), OutputEncoding::default(), sink()),
+            RenderJob::new(presets::fern(), small_run(), ToneMapping::default(), OutputEncoding::default(), FullSink),
+            descriptor(&gasket),
+            RenderJob::new(presets::fern(), RunConfig { width: 1 << 30, height: 1 << 30, ..small_run() }, ToneMapping::default(), OutputEncoding::default(), sink()),
+            RenderJob::new(presets::swirl(), small_run(), ToneMapping::default(), OutputEncoding::default(), sink()),
+        ];
+
+        let results = render_all(jobs, 3, |_, _| {});
+        assert_eq!(results.iter().map(|r| r.id).collect::<Vec<_>>(), (0 .. 7).collect::<Vec<_>>());
+        assert!(matches!(results[1].result, Err(FlameError::Json(_))));
+        assert!(matches!(&results[2].result, Err(FlameError::Validation(m)) if m.contains("no functions")));
+        assert!(matches!(results[3].result, Err(FlameError::Output(SinkError::Io(_)))));
+        assert!(matches!(results[5].result, Err(FlameError::Buffer(BufferError::TooLarge { .. }))));
+        for i in [0, 4, 6] {
+            assert!(results[i].result.is_ok(), "job {} failed", i);
+            assert_eq!(results[i].stats.unwrap().iters, 2_000);
+        }
+        // The sink failed after the render, whose statistics are kept.
+        assert!(results[3].stats.is_some());
+        assert!(results[1].stats.is_none() && results[2].stats.is_none() && results[5].stats.is_none());
+        assert_eq!(images.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn at_most_parallel_flames_jobs_run_at_once() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        for parallel in [1, 2, 3] {
+            let (active, most) = (AtomicUsize::new(0), AtomicUsize::new(0));
+            let events = Mutex::new(Vec::new());
+            let run = RunConfig { iters: 50_000, ..small_run() };
+            let jobs = (0 .. 8).map(|_| RenderJob::new(presets::gasket(), run, ToneMapping::default(), OutputEncoding::default(), FullSink));
+            let results = render_all(jobs, parallel, |id, event| {
+                match event {
+                    JobEvent::Started => {
+                        let now = active.fetch_add(1, Ordering::SeqCst) + 1;
+                        most.fetch_max(now, Ordering::SeqCst);
+                    }
+                    JobEvent::Finished(_) | JobEvent::Failed => {
+                        active.fetch_sub(1, Ordering::SeqCst);
+                    }
+                }
+                events.lock().unwrap().push((id, matches!(event, JobEvent::Started)));
+            });
+            assert_eq!(results.len(), 8);
+            let most = most.load(Ordering::SeqCst);
+            assert!((1 ..= parallel).contains(&most), "{} jobs ran at once with {} allowed", most, parallel);
+            let events = events.into_inner().unwrap();
+            assert_eq!(events.len(), 16);
+            if parallel == 1 {
+                // One at a time, in the order given.
+                let expected: Vec<_> = (0 .. 8).flat_map(|id| [(id, true), (id, false)]).collect();
+                assert_eq!(events, expected);
+            }
+        }
+    }
+
+    /// A journal in `dir` with item 0 finished as `a` and item 1 named `b`.
+    fn journal(dir: &Path) -> Journal {
+        let mut journal = Journal::create(dir, serde_json::json!({"seed": 3, "count": 4})).unwrap();
+        journal.claim(0, "a").unwrap();
+        std::fs::write(dir.join("a.png"), b"image").unwrap();
+        journal.complete(0, "a", &[PathBuf::from("a.png")]).unwrap();
+        journal.claim(1, "b").unwrap();
+        journal
+    }
+
+    #[test]
+    fn journals_reopen_as_they_were_written() {
+        let dir = tempfile::tempdir().unwrap();
+        drop(journal(dir.path()));
+        let journal = Journal::open(dir.path()).unwrap();
+        assert_eq!(journal.batch(), &serde_json::json!({"seed": 3, "count": 4}));
+        assert_eq!((journal.stem(0), journal.stem(1), journal.stem(2)), (Some("a"), Some("b"), None));
+        assert!(journal.is_completed(0) && !journal.is_completed(1));
+    }
+
+    #[test]
+    fn changed_outputs_and_cut_lines_are_not_trusted() {
+        let dir = tempfile::tempdir().unwrap();
+        drop(journal(dir.path()));
+        std::fs::write(dir.path().join("a.png"), b"other").unwrap();
+        let path = dir.path().join(JOURNAL_FILE);
+        let mut text = std::fs::read_to_string(&path).unwrap();
+        text += "{\"event\":\"completed\",\"item\":1,";
+        std::fs::write(&path, text).unwrap();
+
+        let journal = Journal::open(dir.path()).unwrap();
+        assert_eq!(journal.stem(0), Some("a"));
+        assert!(!journal.is_completed(0) && !journal.is_completed(1));
+        // The journal is rewritten without the cut line, so that more can be
+        // appended.
+        drop(journal);
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 4);
+        assert!(Journal::open(dir.path()).is_ok());
+    }
+
+    #[test]
+    fn malformed_lines_before_the_last_are_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        drop(journal(dir.path()));
+        let path = dir.path().join(JOURNAL_FILE);
+        let text = std::fs::read_to_string(&path).unwrap().replacen("claimed", "guessed", 1);
+        std::fs::write(&path, text).unwrap();
+        assert!(matches!(Journal::open(dir.path()), Err(JournalError::Malformed { line: 2, .. })));
+    }
+
+    #[test]
+    fn resumed_batches_must_have_the_same_settings() {
+        let dir = tempfile::tempdir().unwrap();
+        let journal = journal(dir.path());
+        assert!(journal.check_batch(&serde_json::json!({"count": 4, "seed": 3})).is_ok());
+        let Err(JournalError::Mismatch { key, recorded, given }) = journal.check_batch(&serde_json::json!({"seed": 4, "count": 4})) else {
+            panic!("a different seed was accepted");
+        };
+        assert_eq!((key.as_str(), recorded, given), ("seed", serde_json::json!(3), serde_json::json!(4)));
+        assert!(journal.check_batch(&serde_json::json!({"seed": 3})).is_err());
+    }
+}
@@ -0,0 +1,382 @@
+//! Measuring how fast flames are iterated, so that machines and options
+//! can be compared.
+//!
+//! Each measurement first finds a chunk of iterations long enough to time
+//! reliably, then warms the workload up until its rate settles, and then
+//! times several equal runs, reporting the rate of each so that their
+//! spread shows how far the figures can be trusted.
+
+use std::time::Duration;
+
+use super::core::*;
+
+/// Shortest time a chunk of iterations should take to be timed reliably.
+const MIN_CHUNK_TIME: Duration = Duration::from_millis(10);
+
+/// Iterations in the first chunk, which is doubled until it takes
+/// `MIN_CHUNK_TIME`.
+const FIRST_CHUNK: u64 = 1000;
+
+/// Number of windows each repeat's time is divided into while warming up.
+const WARMUP_WINDOWS_PER_REPEAT: u32 = 4;
+
+/// Width and height of the histogram benchmarks plot into.
+pub const BENCH_SIZE: usize = 1024;
+
+/// Seed of every benchmark run, so that each plots the same points.
+pub const BENCH_SEED: u64 = 0;
+
+/// Work which can be run a chunk of iterations at a time.
+pub trait Workload {
+    /// Run about `iters` iterations, returning how many were run and how
+    /// many points were plotted.
+    fn step(&mut self, iters: u64) -> Step;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Step {
+    pub iters: u64,
+    pub plotted: u64,
+}
+
+impl Workload for RenderSession {
+    fn step(&mut self, iters: u64) -> Step {
+        let before = self.iters();
+        let plotted = self.advance(iters).plotted;
+        Step { iters: self.iters() - before, plotted }
+    }
+}
+
+/// How long to measure for and when the warmup is over.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BenchConfig {
+    /// Time spent measuring, split evenly between the repeats.
+    pub duration: Duration,
+    pub repeats: u32,
+    /// Time after which the warmup ends with the window in progress,
+    /// whether or not the rate has settled.
+    pub max_warmup: Duration,
+    /// The warmup ends once the rates of two windows in a row differ by at
+    /// most this fraction.
+    pub steady_tolerance: f64,
+}
+
+impl BenchConfig {
+    /// Three repeats sharing `duration`, after a warmup of up to a quarter
+    /// of it which ends once rates are within 5% of each other.
+    pub fn new(duration: Duration) -> Self {
+        BenchConfig { duration, repeats: 3, max_warmup: duration / 4, steady_tolerance: 0.05 }
+    }
+}
+
+/// Rates of each timed run of a workload.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Measurement {
+    /// Iterations a second in each repeat.
+    pub iters_per_sec: Vec<f64>,
+    /// Points plotted a second in each repeat.
+    pub plots_per_sec: Vec<f64>,
+    /// Iterations run between readings of the clock.
+    pub chunk_iters: u64,
+    /// Time spent finding the chunk size and warming up.
+    pub warmup: Duration,
+    /// Whether the rate settled before `max_warmup`.
+    pub steady: bool,
+}
+
+impl Measurement {
+    pub fn mean_iters_per_sec(&self) -> f64 {
+        mean(&self.iters_per_sec)
+    }
+
+    pub fn mean_plots_per_sec(&self) -> f64 {
+        mean(&self.plots_per_sec)
+    }
+
+    /// Sample standard deviation of the iteration rates of the repeats,
+    /// relative to their mean. Zero with fewer than two repeats.
+    pub fn rel_spread(&self) -> f64 {
+        let rates = &self.iters_per_sec;
+        if rates.len() < 2 {
+            return 0.0;
+        }
+        let m = mean(rates);
+        let variance = rates.iter().map(|r| (r - m) * (r - m)).sum::<f64>() / (rates.len() - 1) as f64;
+        if m > 0.0 { variance.sqrt() / m } else { 0.0 }
+    }
+}
+
+fn mean(values: &[f64]) -> f64 {
+    if values.is_empty() { 0.0 } else { values.iter().sum::<f64>() / values.len() as f64 }
+}
+
+/// Measure how fast `workload` runs, reading time from `clock`.
+pub fn measure<W: Workload, C: Clock>(workload: &mut W, cfg: BenchConfig, clock: &C) -> Measurement {
+    let repeats = cfg.repeats.max(1);
+    let repeat_time = cfg.duration / repeats;
+    let start = clock.now();
+    let warming = |clock: &C| clock.now() - start < cfg.max_warmup;
+
+    let mut chunk = FIRST_CHUNK;
+    loop {
+        let (_, elapsed) = run_for(workload, clock, chunk, Duration::ZERO);
+        if elapsed >= MIN_CHUNK_TIME || !warming(clock) {
+            break;
+        }
+        chunk = chunk.saturating_mul(2);
+    }
+
+    let window = (repeat_time / WARMUP_WINDOWS_PER_REPEAT).max(MIN_CHUNK_TIME);
+    let mut previous = None;
+    let mut steady = false;
+    while warming(clock) {
+        let (step, elapsed) = run_for(workload, clock, chunk, window);
+        let rate = per_sec(step.iters, elapsed);
+        if previous.is_some_and(|p: f64| (rate - p).abs() <= cfg.steady_tolerance * p) {
+            steady = true;
+            break;
+        }
+        previous = Some(rate);
+    }
+    let warmup = clock.now() - start;
+
+    let (mut iters_per_sec, mut plots_per_sec) = (Vec::new(), Vec::new());
+    for _ in 0 .. repeats {
+        let (step, elapsed) = run_for(workload, clock, chunk, repeat_time);
+        iters_per_sec.push(per_sec(step.iters, elapsed));
+        plots_per_sec.push(per_sec(step.plotted, elapsed));
+    }
+    Measurement { iters_per_sec, plots_per_sec, chunk_iters: chunk, warmup, steady }
+}
+
+/// Run chunks until `duration` has passed, and at least one, returning
+/// their totals and the time they took. Stops early if the workload
+/// runs out of iterations.
+fn run_for<W: Workload, C: Clock>(workload: &mut W, clock: &C, chunk: u64, duration: Duration) -> (Step, Duration) {
+    let start = clock.now();
+    let mut total = Step::default();
+    loop {
+        let step = workload.step(chunk);
+        total.iters += step.iters;
+        total.plotted += step.plotted;
+        let elapsed = clock.now() - start;
+        if elapsed >= duration || step.iters == 0 {
+            return (total, elapsed);
+        }
+    }
+}
+
+fn per_sec(count: u64, elapsed: Duration) -> f64 {
+    if elapsed.is_zero() { 0.0 } else { count as f64 / elapsed.as_secs_f64() }
+}
+
+/// An option whose effect on speed is measured, as a change from the
+/// baseline configuration.
+#[derive(Clone, Copy)]
+pub struct BenchVariant {
+    pub name: &'static str,
+    pub description: &'static str,
+    apply: fn(RunConfig) -> RunConfig,
+}
+
+impl BenchVariant {
+    pub fn apply(&self, cfg: RunConfig) -> RunConfig {
+        (self.apply)(cfg)
+    }
+}
+
+/// The baseline and the options swept by `flame bench --matrix`, each
+/// changed on its own, so that each has a speedup of its own relative to
+/// the baseline.
+pub const VARIANTS: [BenchVariant; 8] = [
+    BenchVariant { name: "baseline", description: "single precision, platform math, row-major points", apply: |cfg| cfg },
+    BenchVariant {
+        name: "f64",
+        description: "orbits in double precision",
+        apply: |cfg| RunConfig { precision: Precision::F64, ..cfg },
+    },
+    BenchVariant {
+        name: "portable-math",
+        description: "math giving the same results on every platform",
+        apply: |cfg| RunConfig { deterministic_math: true, ..cfg },
+    },
+    BenchVariant {
+        name: "morton",
+        description: "histograms in Z-order blocks",
+        apply: |cfg| RunConfig { layout: Layout::Morton, ..cfg },
+    },
+    BenchVariant {
+        name: "strokes",
+        description: "points joined by strokes of 8",
+        apply: |cfg| RunConfig { plot_mode: PlotMode::Strokes { length: 8, attenuation: 0.1 }, ..cfg },
+    },
+    BenchVariant {
+        name: "stable-chunks",
+        description: "iterations in seeded chunks of 1M",
+        apply: |cfg| RunConfig { stable_chunk_iters: Some(1_000_000), ..cfg },
+    },
+    BenchVariant {
+        name: "pinned",
+        description: "each thread pinned to a core of its own",
+        apply: |cfg| RunConfig { pin_threads: true, ..cfg },
+    },
+    BenchVariant {
+        name: "function-stats",
+        description: "counting each function's points, which the baseline skips",
+        apply: |cfg| RunConfig { per_function_stats: true, ..cfg },
+    },
+];
+
+/// The configuration benchmarks start from: a `BENCH_SIZE` square
+/// histogram, seeded, with no stopping condition, so that a session can be
+/// advanced for as long as a measurement takes.
+pub fn baseline_config(threads: usize) -> RunConfig {
+    RunConfig {
+        width: BENCH_SIZE,
+        height: BENCH_SIZE,
+        iters: usize::MAX,
+        threads: threads.max(1),
+        seed: Some(BENCH_SEED),
+        precision: Precision::F32,
+        ..RunConfig::default()
+    }
+}
+
+/// Thread counts `flame bench --scaling` compares, up to `max`: each power
+/// of two below it, and `max` itself.
+pub fn scaling_threads(max: usize) -> Vec<usize> {
+    let max = max.max(1);
+    let mut threads: Vec<usize> = std::iter::successors(Some(1usize), |&n| n.checked_mul(2)).take_while(|&n| n < max).collect();
+    threads.push(max);
+    threads
+}
+
+/// Measure a flame under a variant of the baseline configuration.
+pub fn bench_flame(flame: &Flame, variant: &BenchVariant, threads: usize, cfg: BenchConfig) -> Measurement {
+    let mut session = RenderSession::new(flame.clone(), variant.apply(baseline_config(threads)))
+        .expect("the benchmark histogram is a fixed, allowed size");
+    measure(&mut session, cfg, &MonotonicClock::new())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    /// A clock which only moves when the test moves it.
+    #[derive(Clone, Default)]
+    struct FakeClock(Rc<Cell<Duration>>);
+
+    impl Clock for FakeClock {
+        fn now(&self) -> Duration {
+            self.0.get()
+        }
+    }
+
+    /// A workload whose iterations take as long as `per_iter` says at the
+    /// time they start, and plot a point every other iteration.
+    struct FakeWorkload {
+        clock: FakeClock,
+        per_iter: fn(Duration) -> Duration,
+        chunks: Vec<u64>,
+    }
+
+    impl FakeWorkload {
+        fn new(per_iter: fn(Duration) -> Duration) -> Self {
+            FakeWorkload { clock: FakeClock::default(), per_iter, chunks: Vec::new() }
+        }
+
+        fn measure(&mut self, cfg: BenchConfig) -> Measurement {
+            let clock = self.clock.clone();
+            measure(self, cfg, &clock)
+        }
+    }
+
+    impl Workload for FakeWorkload {
+        fn step(&mut self, iters: u64) -> Step {
+            let now = self.clock.now();
+            self.clock.0.set(now + (self.per_iter)(now) * iters as u32);
+            self.chunks.push(iters);
+            Step { iters, plotted: iters / 2 }
+        }
+    }
+
+    #[test]
+    fn chunks_double_until_they_take_long_enough_to_time() {
+        let mut workload = FakeWorkload::new(|_| Duration::from_micros(1));
+        let m = workload.measure(BenchConfig::new(Duration::from_secs(1)));
+        // 16k iterations are the first to take at least 10ms.
+        assert_eq!(workload.chunks[.. 5], [1000, 2000, 4000, 8000, 16_000]);
+        assert_eq!(m.chunk_iters, 16_000);
+        assert!(workload.chunks[5 ..].iter().all(|&c| c == 16_000));
+
+        // Without time to warm up, the first chunk is kept however short.
+        let mut workload = FakeWorkload::new(|_| Duration::from_nanos(1));
+        let cfg = BenchConfig { max_warmup: Duration::ZERO, ..BenchConfig::new(Duration::from_secs(1)) };
+        let m = workload.measure(cfg);
+        assert_eq!((m.chunk_iters, m.steady, m.warmup), (1000, false, Duration::from_micros(1)));
+    }
+
+    #[test]
+    fn the_warmup_ends_once_two_windows_agree() {
+        // Three times slower for the first 150ms.
+        let mut workload = FakeWorkload::new(|t| Duration::from_micros(if t < Duration::from_millis(150) { 3 } else { 1 }));
+        let cfg = BenchConfig { max_warmup: Duration::from_secs(1), ..BenchConfig::new(Duration::from_secs(1)) };
+        let m = workload.measure(cfg);
+        assert!(m.steady);
+        assert_eq!(m.chunk_iters, 4000);
+        // Windows of a quarter of a repeat, about 84ms each: one slow, one
+        // mixed and two fast, after the 21ms the chunk search took.
+        assert_eq!(m.warmup, Duration::from_millis(357));
+        assert_eq!(m.iters_per_sec.len(), 3);
+        for (&iters, &plots) in m.iters_per_sec.iter().zip(&m.plots_per_sec) {
+            assert!((iters - 1e6).abs() < 1e-3 && (plots - 5e5).abs() < 1e-3, "{} and {}", iters, plots);
+        }
+        assert!(m.rel_spread() < 1e-9);
+    }
+
+    #[test]
+    fn warmups_of_rates_which_never_settle_are_cut_short() {
+        // Twice as slow every 100ms.
+        let mut workload = FakeWorkload::new(|t| Duration::from_secs_f64(1e-6 * 2f64.powf(t.as_secs_f64() / 0.1)));
+        let m = workload.measure(BenchConfig::new(Duration::from_secs(1)));
+        assert!(!m.steady);
+        assert!(m.warmup >= Duration::from_millis(250) && m.warmup < Duration::from_millis(500), "{:?}", m.warmup);
+        // Each repeat is slower than the last.
+        assert!(m.iters_per_sec.windows(2).all(|w| w[1] < w[0]), "{:?}", m.iters_per_sec);
+        assert!(m.rel_spread() > 0.5, "{}", m.rel_spread());
+    }
+
+    #[test]
+    fn runs_last_at_least_their_duration_and_one_chunk() {
+        let mut workload = FakeWorkload::new(|_| Duration::from_millis(1));
+        let clock = workload.clock.clone();
+        assert_eq!(run_for(&mut workload, &clock, 3, Duration::from_millis(10)), (Step { iters: 12, plotted: 4 }, Duration::from_millis(12)));
+        assert_eq!(run_for(&mut workload, &clock, 3, Duration::ZERO), (Step { iters: 3, plotted: 1 }, Duration::from_millis(3)));
+    }
+
+    #[test]
+    fn spread_is_the_relative_standard_deviation_of_the_repeats() {
+        let measurement = |iters_per_sec: Vec<f64>| Measurement {
+            plots_per_sec: iters_per_sec.clone(),
+            iters_per_sec,
+            chunk_iters: 1000,
+            warmup: Duration::ZERO,
+            steady: true,
+        };
+        let m = measurement(vec![9.0, 10.0, 11.0]);
+        assert_eq!((m.mean_iters_per_sec(), m.rel_spread()), (10.0, 0.1));
+        assert_eq!(measurement(vec![10.0]).rel_spread(), 0.0);
+        assert_eq!(measurement(vec![0.0, 0.0]).rel_spread(), 0.0);
+        assert_eq!(measurement(Vec::new()).mean_iters_per_sec(), 0.0);
+    }
+
+    #[test]
+    fn scaling_doubles_the_threads_up_to_the_most() {
+        assert_eq!(scaling_threads(0), [1]);
+        assert_eq!(scaling_threads(1), [1]);
+        assert_eq!(scaling_threads(8), [1, 2, 4, 8]);
+        assert_eq!(scaling_threads(12), [1, 2, 4, 8, 12]);
+    }
+}
@@ -0,0 +1,372 @@
+//! Human friendly numeric types for command line arguments.
+//!
+//! Each type parses from and displays as a number with a unit suffix, such
+//! as `5M` iterations, `512MiB` of memory or `1h30m`, so values can be echoed
+//! back in the form they were written. They implement `FromStr`, so clap
+//! can use them directly as argument types.
+
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// A count written with an optional SI suffix: `k`, `M`, `G` or `T`.
+///
+/// Fractional mantissas are accepted only when the result is a whole
+/// number, so `2.5k` is 2500 but `2.5` is rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SiCount(pub u64);
+
+/// A number of bytes, with decimal (`K`, `M`, `G`, `T`) or binary (`Ki`,
+/// `Mi`, `Gi`, `Ti`) suffixes and an optional trailing `B`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ByteSize(pub u64);
+
+/// A length of time written as one or more numbers with units, such as
+/// `30s`, `1.5h` or `1h30m`. Units are `ns`, `us`, `ms`, `s`, `m`, `h` and `d`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct HumanDuration(pub Duration);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UnitErrorKind {
+    Empty,
+    Negative,
+    Malformed,
+    MissingUnit,
+    UnknownSuffix(String),
+    AmbiguousSuffix(String, &'static str),
+    Fractional,
+    Overflow,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnitParseError {
+    input: String,
+    expected: &'static str,
+    kind: UnitErrorKind,
+}
+
+impl UnitParseError {
+    pub fn kind(&self) -> &UnitErrorKind {
+        &self.kind
+    }
+}
+
+impl fmt::Display for UnitParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid {} '{}': ", self.expected, self.input)?;
+        match &self.kind {
+            UnitErrorKind::Empty => write!(f, "no value given"),
+            UnitErrorKind::Negative => write!(f, "must not be negative"),
+            UnitErrorKind::Malformed => write!(f, "not a number"),
+            UnitErrorKind::MissingUnit => write!(f, "missing a unit"),
+            UnitErrorKind::UnknownSuffix(s) => write!(f, "unknown suffix '{}'", s),
+            UnitErrorKind::AmbiguousSuffix(s, hint) => write!(f, "suffix '{}' is ambiguous ({})", s, hint),
+            UnitErrorKind::Fractional => write!(f, "must be a whole number"),
+            UnitErrorKind::Overflow => write!(f, "too large"),
+        }
+    }
+}
+
+impl std::error::Error for UnitParseError {}
+
+/// Multiply a non-negative decimal such as `2.5` by `unit` exactly,
+/// failing if the result is not a whole number or does not fit in a `u64`.
+fn scale_decimal(mantissa: &str, unit: u64) -> Result<u64, UnitErrorKind> {
+    if mantissa.starts_with('-') {
+        return Err(UnitErrorKind::Negative);
+    }
+    let (whole, frac) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+    let is_digits = |s: &str| s.bytes().all(|b| b.is_ascii_digit());
+    if whole.len() + frac.len() == 0 || !is_digits(whole) || !is_digits(frac) {
+        return Err(UnitErrorKind::Malformed);
+    }
+
+    let parse = |s: &str| if s.is_empty() { Ok(0) } else { s.parse::<u128>().map_err(|_| UnitErrorKind::Overflow) };
+    let denom = 10u128.checked_pow(frac.len() as u32).ok_or(UnitErrorKind::Fractional)?;
+    let frac_scaled = parse(frac)?.checked_mul(unit as u128).ok_or(UnitErrorKind::Overflow)?;
+    if frac_scaled % denom != 0 {
+        return Err(UnitErrorKind::Fractional);
+    }
+
+    parse(whole)?
+        .checked_mul(unit as u128)
+        .and_then(|x| x.checked_add(frac_scaled / denom))
+        .and_then(|x| u64::try_from(x).ok())
+        .ok_or(UnitErrorKind::Overflow)
+}
+
+/// Split a value into its leading number and trailing suffix.
+fn split_suffix(s: &str) -> (&str, &str) {
+    let end = s.find(|c: char| !(c.is_ascii_digit() || c == '.' || c == '-')).unwrap_or(s.len());
+    s.split_at(end)
+}
+
+/// Write `n` using the largest unit which divides it exactly.
+fn fmt_exact(f: &mut fmt::Formatter<'_>, n: u64, units: &[(&str, u64)], plain: &str) -> fmt::Result {
+    for &(suffix, unit) in units {
+        if n != 0 && n.is_multiple_of(unit) {
+            return write!(f, "{}{}", n / unit, suffix);
+        }
+    }
+    write!(f, "{}{}", n, plain)
+}
+
+const SI_UNITS: [(&str, u64); 4] = [
+    ("T", 1_000_000_000_000),
+    ("G", 1_000_000_000),
+    ("M", 1_000_000),
+    ("k", 1_000),
+];
+
+impl FromStr for SiCount {
+    type Err = UnitParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = |kind| UnitParseError { input: s.to_string(), expected: "count", kind };
+        let s = s.trim();
+        if s.is_empty() {
+            return Err(err(UnitErrorKind::Empty));
+        }
+        let (mantissa, suffix) = split_suffix(s);
+        if mantissa.is_empty() {
+            return Err(err(UnitErrorKind::Malformed));
+        }
+        let unit = match suffix {
+            "" => 1,
+            "k" | "K" => 1_000,
+            "M" => 1_000_000,
+            "G" => 1_000_000_000,
+            "T" => 1_000_000_000_000,
+            "m" => return Err(err(UnitErrorKind::AmbiguousSuffix(suffix.to_string(), "use 'M' for millions"))),
+            _ => return Err(err(UnitErrorKind::UnknownSuffix(suffix.to_string()))),
+        };
+        scale_decimal(mantissa, unit).map(SiCount).map_err(err)
+    }
+}
+
+impl fmt::Display for SiCount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt_exact(f, self.0, &SI_UNITS, "")
+    }
+}
+
+const BYTE_UNITS: [(&str, u64); 8] = [
+    ("TiB", 1 << 40),
+    ("TB", 1_000_000_000_000),
+    ("GiB", 1 << 30),
+    ("GB", 1_000_000_000),
+    ("MiB", 1 << 20),
+    ("MB", 1_000_000),
+    ("KiB", 1 << 10),
+    ("KB", 1_000),
+];
+
+impl FromStr for ByteSize {
+    type Err = UnitParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = |kind| UnitParseError { input: s.to_string(), expected: "size", kind };
+        let s = s.trim();
+        if s.is_empty() {
+            return Err(err(UnitErrorKind::Empty));
+        }
+        let (mantissa, suffix) = split_suffix(s);
+        if mantissa.is_empty() {
+            return Err(err(UnitErrorKind::Malformed));
+        }
+        let prefix = suffix.strip_suffix('B').unwrap_or(suffix);
+        let unit = match prefix {
+            "" => 1,
+            "k" | "K" => 1_000,
+            "M" => 1_000_000,
+            "G" => 1_000_000_000,
+            "T" => 1_000_000_000_000,
+            "Ki" => 1 << 10,
+            "Mi" => 1 << 20,
+            "Gi" => 1 << 30,
+            "Ti" => 1 << 40,
+            _ if suffix.ends_with('b') => {
+                return Err(err(UnitErrorKind::AmbiguousSuffix(suffix.to_string(), "use 'B' for bytes")));
+            }
+            "m" => return Err(err(UnitErrorKind::AmbiguousSuffix(suffix.to_string(), "use 'M' or 'Mi'"))),
+            _ => return Err(err(UnitErrorKind::UnknownSuffix(suffix.to_string()))),
+        };
+        scale_decimal(mantissa, unit).map(ByteSize).map_err(err)
+    }
+}
+
+impl fmt::Display for ByteSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt_exact(f, self.0, &BYTE_UNITS, "B")
+    }
+}
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+impl FromStr for HumanDuration {
+    type Err = UnitParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = |kind| UnitParseError { input: s.to_string(), expected: "duration", kind };
+        let mut rest = s.trim();
+        if rest.is_empty() {
+            return Err(err(UnitErrorKind::Empty));
+        }
+
+        let mut nanos: u128 = 0;
+        while !rest.is_empty() {
+            let (mantissa, tail) = split_suffix(rest);
+            let end = tail.find(|c: char| !c.is_ascii_alphabetic()).unwrap_or(tail.len());
+            let (suffix, tail) = tail.split_at(end);
+            let unit = match suffix {
+                "ns" => 1,
+                "us" => 1_000,
+                "ms" => 1_000_000,
+                "s" => NANOS_PER_SEC,
+                "m" => 60 * NANOS_PER_SEC,
+                "h" => 3600 * NANOS_PER_SEC,
+                "d" => 86400 * NANOS_PER_SEC,
+                "" => return Err(err(if mantissa.is_empty() { UnitErrorKind::Malformed } else { UnitErrorKind::MissingUnit })),
+                "M" => return Err(err(UnitErrorKind::AmbiguousSuffix(suffix.to_string(), "use 'm' for minutes"))),
+                _ => return Err(err(UnitErrorKind::UnknownSuffix(suffix.to_string()))),
+            };
+            nanos += scale_decimal(mantissa, unit).map_err(err)? as u128;
+            rest = tail;
+        }
+
+        let secs = u64::try_from(nanos / NANOS_PER_SEC as u128).map_err(|_| err(UnitErrorKind::Overflow))?;
+        Ok(HumanDuration(Duration::new(secs, (nanos % NANOS_PER_SEC as u128) as u32)))
+    }
+}
+
+impl fmt::Display for HumanDuration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let secs = self.0.as_secs();
+        let nanos = self.0.subsec_nanos();
+        if secs == 0 && nanos == 0 {
+            return write!(f, "0s");
+        }
+        let parts = [
+            (secs / 3600, "h"),
+            (secs / 60 % 60, "m"),
+            (secs % 60, "s"),
+            (nanos as u64 / 1_000_000, "ms"),
+            (nanos as u64 / 1_000 % 1_000, "us"),
+            (nanos as u64 % 1_000, "ns"),
+        ];
+        for (value, unit) in parts {
+            if value != 0 {
+                write!(f, "{}{}", value, unit)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kind<T: FromStr<Err = UnitParseError> + fmt::Debug>(s: &str) -> UnitErrorKind {
+        s.parse::<T>().unwrap_err().kind().clone()
+    }
+
+    /// Parse `s`, check it displays as `shown`, and that `shown` parses back
+    /// to the same value.
+    fn round_trip<T>(s: &str, shown: &str) -> T
+    where
+        T: FromStr<Err = UnitParseError> + fmt::Display + fmt::Debug + PartialEq,
+    {
+        let value: T = s.parse().unwrap();
+        assert_eq!(value.to_string(), shown, "{} was displayed differently", s);
+        assert_eq!(shown.parse::<T>().unwrap(), value, "{} did not parse back", shown);
+        value
+    }
+
+    #[test]
+    fn counts_round_trip_through_their_display() {
+        assert_eq!(round_trip::<SiCount>("0", "0"), SiCount(0));
+        assert_eq!(round_trip::<SiCount>("999", "999"), SiCount(999));
+        assert_eq!(round_trip::<SiCount>("2.5k", "2500"), SiCount(2_500));
+        assert_eq!(round_trip::<SiCount>("5M", "5M"), SiCount(5_000_000));
+        assert_eq!(round_trip::<SiCount>("1500k", "1500k"), SiCount(1_500_000));
+        assert_eq!(round_trip::<SiCount>(" 3G ", "3G"), SiCount(3_000_000_000));
+        assert_eq!(round_trip::<SiCount>("0.001T", "1G"), SiCount(1_000_000_000));
+        assert_eq!(round_trip::<SiCount>("18446744073709551615", "18446744073709551615"), SiCount(u64::MAX));
+    }
+
+    #[test]
+    fn sizes_round_trip_through_their_display() {
+        assert_eq!(round_trip::<ByteSize>("17", "17B"), ByteSize(17));
+        assert_eq!(round_trip::<ByteSize>("512MiB", "512MiB"), ByteSize(512 << 20));
+        assert_eq!(round_trip::<ByteSize>("512Mi", "512MiB"), ByteSize(512 << 20));
+        assert_eq!(round_trip::<ByteSize>("2k", "2KB"), ByteSize(2_000));
+        assert_eq!(round_trip::<ByteSize>("1.5GiB", "1536MiB"), ByteSize(3 << 29));
+        assert_eq!(round_trip::<ByteSize>("4TB", "4TB"), ByteSize(4_000_000_000_000));
+        assert_eq!(round_trip::<ByteSize>("1024KiB", "1MiB"), ByteSize(1 << 20));
+    }
+
+    #[test]
+    fn durations_round_trip_through_their_display() {
+        let secs = |s| HumanDuration(Duration::from_secs(s));
+        assert_eq!(round_trip::<HumanDuration>("30s", "30s"), secs(30));
+        assert_eq!(round_trip::<HumanDuration>("5m", "5m"), secs(300));
+        assert_eq!(round_trip::<HumanDuration>("1h30m", "1h30m"), secs(5_400));
+        assert_eq!(round_trip::<HumanDuration>("1.5h", "1h30m"), secs(5_400));
+        assert_eq!(round_trip::<HumanDuration>("2d", "48h"), secs(172_800));
+        assert_eq!(round_trip::<HumanDuration>("0s", "0s"), secs(0));
+        assert_eq!(
+            round_trip::<HumanDuration>("1s250ms3ns", "1s250ms3ns"),
+            HumanDuration(Duration::new(1, 250_000_003)),
+        );
+        assert_eq!(round_trip::<HumanDuration>("0.5ms", "500us"), HumanDuration(Duration::from_micros(500)));
+    }
+
+    #[test]
+    fn fractional_counts_are_rejected() {
+        assert_eq!(kind::<SiCount>("2.5"), UnitErrorKind::Fractional);
+        assert_eq!(kind::<SiCount>("1.0001k"), UnitErrorKind::Fractional);
+        assert_eq!(kind::<ByteSize>("0.5"), UnitErrorKind::Fractional);
+        assert_eq!(kind::<HumanDuration>("0.5ns"), UnitErrorKind::Fractional);
+    }
+
+    #[test]
+    fn ambiguous_suffixes_are_rejected_with_a_hint() {
+        assert!(matches!(kind::<SiCount>("5m"), UnitErrorKind::AmbiguousSuffix(s, _) if s == "m"));
+        assert!(matches!(kind::<ByteSize>("1b"), UnitErrorKind::AmbiguousSuffix(s, _) if s == "b"));
+        assert!(matches!(kind::<ByteSize>("1Mb"), UnitErrorKind::AmbiguousSuffix(s, _) if s == "Mb"));
+        assert!(matches!(kind::<ByteSize>("3m"), UnitErrorKind::AmbiguousSuffix(..)));
+        assert!(matches!(kind::<HumanDuration>("5M"), UnitErrorKind::AmbiguousSuffix(..)));
+        let message = "5m".parse::<SiCount>().unwrap_err().to_string();
+        assert_eq!(message, "invalid count '5m': suffix 'm' is ambiguous (use 'M' for millions)");
+    }
+
+    #[test]
+    fn negative_values_are_rejected() {
+        assert_eq!(kind::<SiCount>("-1"), UnitErrorKind::Negative);
+        assert_eq!(kind::<SiCount>("-2k"), UnitErrorKind::Negative);
+        assert_eq!(kind::<ByteSize>("-1KiB"), UnitErrorKind::Negative);
+        assert_eq!(kind::<HumanDuration>("-5s"), UnitErrorKind::Negative);
+    }
+
+    #[test]
+    fn values_beyond_u64_overflow() {
+        assert_eq!(kind::<SiCount>("18446744073709551616"), UnitErrorKind::Overflow);
+        assert_eq!(kind::<SiCount>("18446744073709552k"), UnitErrorKind::Overflow);
+        assert_eq!(kind::<SiCount>("99999999999999999999999999999999999999999"), UnitErrorKind::Overflow);
+        assert_eq!(kind::<ByteSize>("16777216TiB"), UnitErrorKind::Overflow);
+        assert_eq!(kind::<HumanDuration>("999999999999999999999d"), UnitErrorKind::Overflow);
+    }
+
+    #[test]
+    fn other_malformed_values_are_rejected() {
+        assert_eq!(kind::<SiCount>(""), UnitErrorKind::Empty);
+        assert_eq!(kind::<SiCount>("k"), UnitErrorKind::Malformed);
+        assert_eq!(kind::<SiCount>("1.2.3"), UnitErrorKind::Malformed);
+        assert_eq!(kind::<SiCount>("5x"), UnitErrorKind::UnknownSuffix("x".to_string()));
+        assert_eq!(kind::<ByteSize>("5XiB"), UnitErrorKind::UnknownSuffix("XiB".to_string()));
+        assert_eq!(kind::<HumanDuration>("30"), UnitErrorKind::MissingUnit);
+        assert_eq!(kind::<HumanDuration>("1h30"), UnitErrorKind::MissingUnit);
+        assert_eq!(kind::<HumanDuration>("5w"), UnitErrorKind::UnknownSuffix("w".to_string()));
+    }
+}
@@ -0,0 +1,280 @@
+//! Histograms accumulated a piece at a time, which carry what they were
+//! accumulated from so that later pieces cannot be plotted into the wrong
+//! one.
+
+use rand::Rng;
+
+use super::*;
+
+/// A histogram accumulated over any number of calls to `Flame::advance`,
+/// with the bounds and content hash of the flame it belongs to and the
+/// number of iterations it holds.
+#[derive(Debug, Clone)]
+pub struct Accumulator {
+    buffer: Buffer<u32>,
+    bounds: Bounds,
+    flame_hash: u64,
+    iters_done: u64,
+}
+
+/// Why a histogram could not be accumulated into.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AccumulateError {
+    /// The flame is not the one the accumulator was made for.
+    FlameMismatch { expected: u64, found: u64 },
+    /// The histograms have different dimensions.
+    SizeMismatch { expected: (usize, usize), found: (usize, usize) },
+    /// The flame holds a number which cannot be hashed.
+    Unhashable(ContentHashError),
+    /// The histogram's dimensions cannot be rendered into.
+    Size(BufferError),
+}
+
+impl std::fmt::Display for AccumulateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AccumulateError::FlameMismatch { expected, found } => write!(
+                f, "histogram was accumulated from flame {:016x}, not {:016x}", expected, found,
+            ),
+            AccumulateError::SizeMismatch { expected, found } => write!(
+                f, "histogram is {}x{} pixels, not {}x{}", found.0, found.1, expected.0, expected.1,
+            ),
+            AccumulateError::Unhashable(e) => write!(f, "{}", e),
+            AccumulateError::Size(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for AccumulateError {}
+
+impl From<ContentHashError> for AccumulateError {
+    fn from(e: ContentHashError) -> Self {
+        AccumulateError::Unhashable(e)
+    }
+}
+
+impl From<BufferError> for AccumulateError {
+    fn from(e: BufferError) -> Self {
+        AccumulateError::Size(e)
+    }
+}
+
+impl Accumulator {
+    pub fn buffer(&self) -> &Buffer<u32> {
+        &self.buffer
+    }
+
+    pub fn into_buffer(self) -> Buffer<u32> {
+        self.buffer
+    }
+
+    pub fn bounds(&self) -> Bounds {
+        self.bounds
+    }
+
+    /// `Flame::content_hash` of the flame accumulated.
+    pub fn flame_hash(&self) -> u64 {
+        self.flame_hash
+    }
+
+    pub fn iters_done(&self) -> u64 {
+        self.iters_done
+    }
+
+    pub fn width(&self) -> usize {
+        self.buffer.width()
+    }
+
+    pub fn height(&self) -> usize {
+        self.buffer.height()
+    }
+
+    /// Check that the histogram has the given dimensions, as before it is
+    /// rendered at them.
+    pub fn check_size(&self, width: usize, height: usize) -> Result<(), AccumulateError> {
+        let found = (self.width(), self.height());
+        if found != (width, height) {
+            return Err(AccumulateError::SizeMismatch { expected: (width, height), found });
+        }
+        Ok(())
+    }
+
+    /// Add another histogram of the same flame and dimensions, such as one
+    /// accumulated elsewhere from other random numbers.
+    pub fn merge(&mut self, other: Accumulator) -> Result<(), AccumulateError> {
+        if other.flame_hash != self.flame_hash {
+            return Err(AccumulateError::FlameMismatch { expected: self.flame_hash, found: other.flame_hash });
+        }
+        other.check_size(self.width(), self.height())?;
+        self.add(other.buffer, other.iters_done);
+        Ok(())
+    }
+
+    fn add(&mut self, buffer: Buffer<u32>, iters: u64) {
+        let own = std::mem::replace(&mut self.buffer, Buffer::new(0, 0));
+        self.buffer = Buffer::combine([own, buffer]);
+        self.iters_done += iters;
+    }
+}
+
+impl Flame {
+    /// An empty histogram of the given dimensions for this flame.
+    pub fn accumulator(&self, width: usize, height: usize) -> Result<Accumulator, AccumulateError> {
+        Ok(Accumulator {
+            buffer: Buffer::new(width, height),
+            bounds: self.bounds,
+            flame_hash: self.content_hash()?,
+            iters_done: 0,
+        })
+    }
+
+    /// A histogram accumulated earlier, such as one read from a file,
+    /// checked to belong to this flame.
+    pub fn resume(&self, buffer: Buffer<u32>, flame_hash: u64, iters_done: u64) -> Result<Accumulator, AccumulateError> {
+        let found = self.content_hash()?;
+        if flame_hash != found {
+            return Err(AccumulateError::FlameMismatch { expected: flame_hash, found });
+        }
+        Ok(Accumulator { buffer: buffer.to_row_major(), bounds: self.bounds, flame_hash, iters_done })
+    }
+
+    /// Run `iters` more iterations on one thread, seeded from `rng`, and
+    /// add their hits to the histogram, which must have been made for this
+    /// flame.
+    pub fn advance(&self, acc: &mut Accumulator, iters: u64, rng: &mut impl Rng) -> Result<(), AccumulateError> {
+        let found = self.content_hash()?;
+        if found != acc.flame_hash {
+            return Err(AccumulateError::FlameMismatch { expected: acc.flame_hash, found });
+        }
+        let cfg = RunConfig {
+            width: acc.width(),
+            height: acc.height(),
+            iters: usize::try_from(iters).unwrap_or(usize::MAX),
+            seed: Some(rng.gen()),
+            ..RunConfig::default()
+        };
+        acc.add(self.run(cfg)?, iters);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::presets;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn hits(buffer: &Buffer<u32>) -> u64 {
+        buffer.buckets().iter().map(|b| b.alpha as u64).sum()
+    }
+
+    #[test]
+    fn advancing_another_flame_is_rejected() {
+        let gasket = presets::gasket();
+        let mut acc = gasket.accumulator(32, 32).unwrap();
+        let mut rng = StdRng::seed_from_u64(1);
+        let err = presets::fern().advance(&mut acc, 100, &mut rng).unwrap_err();
+        assert_eq!(err, AccumulateError::FlameMismatch {
+            expected: gasket.content_hash().unwrap(),
+            found: presets::fern().content_hash().unwrap(),
+        });
+        assert_eq!(acc.iters_done(), 0);
+        assert_eq!(hits(acc.buffer()), 0);
+    }
+
+    #[test]
+    fn moving_the_bounds_is_a_different_flame() {
+        let gasket = presets::gasket();
+        let mut acc = gasket.accumulator(32, 32).unwrap();
+        let moved = Flame { bounds: Bounds::new(-1.0, 1.0, -1.0, 1.0), ..gasket };
+        let err = moved.advance(&mut acc, 100, &mut StdRng::seed_from_u64(1)).unwrap_err();
+        assert!(matches!(err, AccumulateError::FlameMismatch { .. }));
+    }
+
+    #[test]
+    fn merging_other_sizes_or_flames_is_rejected() {
+        let gasket = presets::gasket();
+        let mut acc = gasket.accumulator(32, 32).unwrap();
+        let err = acc.merge(gasket.accumulator(64, 32).unwrap()).unwrap_err();
+        assert_eq!(err, AccumulateError::SizeMismatch { expected: (32, 32), found: (64, 32) });
+        let err = acc.merge(presets::fern().accumulator(32, 32).unwrap()).unwrap_err();
+        assert!(matches!(err, AccumulateError::FlameMismatch { .. }));
+        assert_eq!(acc.check_size(32, 16), Err(AccumulateError::SizeMismatch {
+            expected: (32, 16),
+            found: (32, 32),
+        }));
+    }
+
+    #[test]
+    fn resuming_checks_the_flame() {
+        let gasket = presets::gasket();
+        let hash = gasket.content_hash().unwrap();
+        let acc = gasket.resume(Buffer::new(8, 8), hash, 10).unwrap();
+        assert_eq!((acc.width(), acc.height(), acc.iters_done()), (8, 8, 10));
+        let err = presets::fern().resume(Buffer::new(8, 8), hash, 10).unwrap_err();
+        assert!(matches!(err, AccumulateError::FlameMismatch { .. }));
+    }
+
+    #[test]
+    fn unhashable_flames_have_no_accumulator() {
+        let mut flame = presets::gasket();
+        flame.functions[0].weight = f32::NAN;
+        assert!(matches!(flame.accumulator(8, 8), Err(AccumulateError::Unhashable(_))));
+    }
+
+    #[test]
+    fn hits_accumulate_over_many_advances() {
+        let gasket = presets::gasket();
+        let mut acc = gasket.accumulator(48, 48).unwrap();
+        let mut rng = StdRng::seed_from_u64(3);
+        let mut expected = Buffer::<u32>::new(48, 48);
+        let mut last = 0;
+        for _ in 0 .. 25 {
+            let before = acc.clone();
+            gasket.advance(&mut acc, 2_000, &mut rng).unwrap();
+            assert!(hits(acc.buffer()) > last);
+            last = hits(acc.buffer());
+            // Every bucket only ever grows.
+            let grew = before.buffer().buckets().iter().zip(acc.buffer().buckets()).all(|(a, b)| b.alpha >= a.alpha);
+            assert!(grew);
+            expected = acc.buffer().clone();
+        }
+        assert_eq!(acc.iters_done(), 50_000);
+        // The gasket lies within its bounds, so nearly every iteration is
+        // plotted, short of those each advance spends settling.
+        assert!(last <= 50_000 && last > 45_000, "{} hits", last);
+
+        // The same advances merged from separate accumulators give the same
+        // histogram.
+        let mut rng = StdRng::seed_from_u64(3);
+        let mut merged = gasket.accumulator(48, 48).unwrap();
+        for _ in 0 .. 25 {
+            let mut piece = gasket.accumulator(48, 48).unwrap();
+            gasket.advance(&mut piece, 2_000, &mut rng).unwrap();
+            merged.merge(piece).unwrap();
+        }
+        assert_eq!(merged.iters_done(), 50_000);
+        let channels = |b: &Buffer<u32>| b.as_flat_slice().to_vec();
+        assert_eq!(channels(merged.buffer()), channels(&expected));
+    }
+
+    #[test]
+    fn sessions_hand_over_checked_accumulators() {
+        let gasket = presets::gasket();
+        let cfg = RunConfig { width: 24, height: 16, iters: 20_000, seed: Some(5), threads: 2, ..RunConfig::default() };
+        let mut session = RenderSession::new(gasket.clone(), cfg).unwrap();
+        session.run();
+        let mut acc = session.accumulator().unwrap();
+        assert_eq!((acc.width(), acc.height(), acc.iters_done()), (24, 16, 20_000));
+        assert_eq!(acc.flame_hash(), gasket.content_hash().unwrap());
+        assert_eq!(acc.buffer().as_flat_slice(), session.buffer().as_flat_slice());
+        assert_eq!(session.into_accumulator().unwrap().buffer().as_flat_slice(), acc.buffer().as_flat_slice());
+
+        gasket.advance(&mut acc, 1_000, &mut StdRng::seed_from_u64(1)).unwrap();
+        assert_eq!(acc.iters_done(), 21_000);
+        let mut other = RenderSession::new(presets::fern(), cfg).unwrap();
+        other.advance(100);
+        assert!(matches!(acc.merge(other.into_accumulator().unwrap()), Err(AccumulateError::FlameMismatch { .. })));
+    }
+}
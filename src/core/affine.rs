@@ -0,0 +1,183 @@
+use nalgebra::{Affine2, Matrix2, Matrix3, Rotation2, Transform, Vector2};
+
+/// An affine transform described as a shear, then a scale, then a rotation
+/// and finally a translation.
+///
+/// A reflection is represented by a negative vertical scale.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AffineDecomposition {
+    pub translation: Vector2<f32>,
+    /// Counterclockwise rotation in radians, between -pi and pi.
+    pub rotation: f32,
+    pub scale: Vector2<f32>,
+    /// Horizontal shear factor, applied before scaling: `x' = x + skew * y`.
+    pub skew: f32,
+}
+
+impl AffineDecomposition {
+    pub fn identity() -> Self {
+        AffineDecomposition {
+            translation: Vector2::zeros(),
+            rotation: 0.0,
+            scale: Vector2::new(1.0, 1.0),
+            skew: 0.0,
+        }
+    }
+
+    /// Split the linear part of `affine` into rotation, scale and shear by a
+    /// QR decomposition. If the first column is zero, the rotation is chosen
+    /// to align the second column instead and the horizontal scale is zero.
+    pub fn decompose(affine: &Affine2<f32>) -> Self {
+        let m = affine.matrix();
+        let (a, c, b, d) = (m[(0, 0)], m[(1, 0)], m[(0, 1)], m[(1, 1)]);
+        let translation = Vector2::new(m[(0, 2)], m[(1, 2)]);
+
+        let sx = a.hypot(c);
+        if sx <= f32::EPSILON * (b.abs() + d.abs()).max(1.0) {
+            return AffineDecomposition {
+                translation,
+                rotation: (-b).atan2(d) + 0.0,
+                scale: Vector2::new(0.0, b.hypot(d)),
+                skew: 0.0,
+            };
+        }
+
+        // Adding zero turns a rotation of -0 into 0.
+        let rotation = c.atan2(a) + 0.0;
+        let (sin, cos) = rotation.sin_cos();
+        let shear = cos * b + sin * d;
+        let sy = (a * d - b * c) / sx;
+
+        AffineDecomposition {
+            translation,
+            rotation,
+            scale: Vector2::new(sx, sy),
+            skew: shear / sx,
+        }
+    }
+
+    pub fn compose(&self) -> Affine2<f32> {
+        let r = Rotation2::new(self.rotation);
+        let (sx, sy) = (self.scale[0], self.scale[1]);
+        let upper = Matrix2::new(
+            sx, sx * self.skew,
+            0.0, sy,
+        );
+        let l = r.matrix() * upper;
+        Transform::from_matrix_unchecked(Matrix3::new(
+            l[(0, 0)], l[(0, 1)], self.translation[0],
+            l[(1, 0)], l[(1, 1)], self.translation[1],
+            0.0, 0.0, 1.0,
+        ))
+    }
+
+    pub fn with_rotation(self, rotation: f32) -> Self {
+        AffineDecomposition { rotation, ..self }
+    }
+
+    pub fn with_translation(self, translation: Vector2<f32>) -> Self {
+        AffineDecomposition { translation, ..self }
+    }
+
+    /// Scale uniformly about the origin of the function's input, leaving the
+    /// translation unchanged.
+    pub fn scaled_by(self, factor: f32) -> Self {
+        AffineDecomposition { scale: self.scale * factor, ..self }
+    }
+
+    pub fn rotated_by(self, angle: f32) -> Self {
+        let rotation = (self.rotation + angle + std::f32::consts::PI)
+            .rem_euclid(std::f32::consts::TAU) - std::f32::consts::PI;
+        AffineDecomposition { rotation, ..self }
+    }
+}
+
+impl Default for AffineDecomposition {
+    fn default() -> Self {
+        AffineDecomposition::identity()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+    use std::f32::consts::{FRAC_PI_2, PI};
+
+    fn affine(c: [f32; 6]) -> Affine2<f32> {
+        Transform::from_matrix_unchecked(Matrix3::new(
+            c[0], c[1], c[4],
+            c[2], c[3], c[5],
+            0.0, 0.0, 1.0,
+        ))
+    }
+
+    fn assert_same(a: &Affine2<f32>, b: &Affine2<f32>) {
+        let error = (a.matrix() - b.matrix()).abs().max();
+        assert!(error < 1e-5, "{} differs from {} by {}", a.matrix(), b.matrix(), error);
+    }
+
+    #[test]
+    fn composing_a_decomposition_gives_the_transform_back() {
+        let mut rng = StdRng::seed_from_u64(4);
+        for _ in 0 .. 1000 {
+            let c: [f32; 6] = std::array::from_fn(|_| rng.gen_range(-2.0 .. 2.0));
+            if (c[0] * c[3] - c[1] * c[2]).abs() < 1e-3 {
+                continue;
+            }
+            let t = affine(c);
+            let d = AffineDecomposition::decompose(&t);
+            assert!(d.rotation > -PI && d.rotation <= PI, "rotation {}", d.rotation);
+            assert!(d.scale[0] > 0.0);
+            assert_same(&d.compose(), &t);
+        }
+    }
+
+    #[test]
+    fn simple_transforms_decompose_into_their_parts() {
+        let d = AffineDecomposition::decompose(&affine([0.0, -0.5, 0.5, 0.0, 1.0, -2.0]));
+        assert!((d.rotation - FRAC_PI_2).abs() < 1e-6);
+        assert!((d.scale - Vector2::new(0.5, 0.5)).norm() < 1e-6);
+        assert!(d.skew.abs() < 1e-6);
+        assert_eq!(d.translation, Vector2::new(1.0, -2.0));
+
+        assert_eq!(AffineDecomposition::decompose(&affine([1.0, 0.0, 0.0, 1.0, 0.0, 0.0])), AffineDecomposition::identity());
+        // A reflection is a negative vertical scale, not a turned rotation.
+        let mirror = AffineDecomposition::decompose(&affine([1.0, 0.0, 0.0, -1.0, 0.0, 0.0]));
+        assert_eq!((mirror.rotation, mirror.scale), (0.0, Vector2::new(1.0, -1.0)));
+        let sheared = AffineDecomposition::decompose(&affine([2.0, 1.0, 0.0, 3.0, 0.0, 0.0]));
+        assert_eq!((sheared.scale, sheared.skew), (Vector2::new(2.0, 3.0), 0.5));
+        // A half turn is pi, not -pi.
+        let turned = AffineDecomposition::decompose(&affine([-1.0, 0.0, 0.0, -1.0, 0.0, 0.0]));
+        assert_eq!(turned.rotation, PI);
+    }
+
+    #[test]
+    fn degenerate_transforms_still_round_trip() {
+        for c in [
+            [0.0, 0.0, 0.0, 0.0, 0.3, 0.4],
+            [0.0, 0.5, 0.0, 0.5, 0.0, 0.0],
+            [1.0, 2.0, 0.5, 1.0, 0.0, 1.0],
+            [0.0, 0.0, 0.0, 0.16, 0.0, 0.0],
+        ] {
+            let d = AffineDecomposition::decompose(&affine(c));
+            assert!(d.rotation.is_finite() && d.skew.is_finite() && d.scale.iter().all(|s| s.is_finite()), "{:?}", d);
+            assert_same(&d.compose(), &affine(c));
+        }
+        let stem = AffineDecomposition::decompose(&affine([0.0, 0.0, 0.0, 0.16, 0.0, 0.0]));
+        assert_eq!((stem.rotation, stem.scale), (0.0, Vector2::new(0.0, 0.16)));
+    }
+
+    #[test]
+    fn edits_change_one_part() {
+        let d = AffineDecomposition::decompose(&affine([0.5, 0.0, 0.0, 0.5, 0.25, 0.0]));
+        assert_same(&d.with_rotation(FRAC_PI_2).compose(), &affine([0.0, -0.5, 0.5, 0.0, 0.25, 0.0]));
+        assert_same(&d.scaled_by(2.0).compose(), &affine([1.0, 0.0, 0.0, 1.0, 0.25, 0.0]));
+        assert_same(&d.with_translation(Vector2::new(0.0, 1.0)).compose(), &affine([0.5, 0.0, 0.0, 0.5, 0.0, 1.0]));
+        // Rotations wrap around into (-pi, pi].
+        let turned = d.with_rotation(3.0).rotated_by(1.0);
+        assert!((turned.rotation - (4.0 - 2.0 * PI)).abs() < 1e-6);
+        assert_same(&turned.compose(), &d.with_rotation(4.0).compose());
+    }
+}
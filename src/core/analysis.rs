@@ -0,0 +1,716 @@
+use nalgebra::{Matrix2, Point2, Vector2};
+use std::time::{Duration, Instant};
+
+use super::*;
+
+/// Number of points on the attractor at which each function's stretch is
+/// measured, and the number of iterations discarded before collecting them.
+const SAMPLES: usize = 512;
+const SAMPLE_WARMUP: usize = 32;
+/// Number of points sampled to fit bounds to the attractor, the fraction
+/// of the most outlying discarded on each side, and the margin added on
+/// each side relative to the extent of the rest.
+const FIT_SAMPLES: usize = 16384;
+const FIT_OUTLIERS: f32 = 0.005;
+const FIT_MARGIN: f32 = 0.05;
+/// Smallest width or height of fitted bounds.
+const FIT_MIN_SIZE: f32 = 1e-3;
+/// Step used for the finite difference estimate of a variation's Jacobian.
+const STEP: f32 = 1e-3;
+/// Longest side of the histogram an iteration estimate is calibrated on,
+/// small enough that a few seconds plot many points into most pixels, and
+/// the number of iterations run between checks of the time taken.
+const CALIBRATION_SIDE: usize = 512;
+const CALIBRATION_CHUNK: u64 = 100_000;
+/// Distance from the attractor, relative to where an orbit starts, an
+/// automatic fuse runs until, and the shortest and longest it may be.
+const AUTO_FUSE_TARGET: f32 = 1e-3;
+const AUTO_FUSE_RANGE: std::ops::RangeInclusive<u32> = 10 ..= 500;
+/// Number of groups, by density, the hit pixels are split into when
+/// estimating iterations.
+pub const DENSITY_GROUPS: usize = 10;
+
+/// The average displacement of points on a regular grid over a flame's
+/// bounds, under a step of the chaos game.
+#[derive(Debug, Clone)]
+pub struct VectorField {
+    width: usize,
+    height: usize,
+    bounds: Bounds,
+    /// Row by row, from the top.
+    vectors: Vec<Vector2<f32>>,
+}
+
+impl VectorField {
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// The center of the cell in column `x` and row `y`, counting rows from
+    /// the top of the image, as in a render.
+    pub fn origin(&self, x: usize, y: usize) -> Point2<f32> {
+        let [x_min, _, _, y_max] = self.bounds.to_array();
+        Point2::new(
+            x_min + (x as f32 + 0.5) * self.bounds.width() / self.width as f32,
+            y_max - (y as f32 + 0.5) * self.bounds.height() / self.height as f32,
+        )
+    }
+
+    pub fn vector(&self, x: usize, y: usize) -> Vector2<f32> {
+        self.vectors[x + y * self.width]
+    }
+
+    /// Every sample point with its displacement, row by row.
+    pub fn iter(&self) -> impl Iterator<Item = (Point2<f32>, Vector2<f32>)> + '_ {
+        (0 .. self.height)
+            .flat_map(move |y| (0 .. self.width).map(move |x| (x, y)))
+            .map(|(x, y)| (self.origin(x, y), self.vector(x, y)))
+    }
+
+    /// Length of the longest displacement.
+    pub fn max_magnitude(&self) -> f32 {
+        self.vectors.iter().map(|v| v.norm()).fold(0.0, f32::max)
+    }
+
+    /// Draw the field one pixel per sample on a color wheel: the hue gives
+    /// the direction, with red pointing right and the hues turning
+    /// counterclockwise, and the saturation the length relative to the
+    /// longest. A field which is zero everywhere is white.
+    pub fn to_buffer(&self) -> Buffer<u8> {
+        let max = self.max_magnitude();
+        let buckets = self.vectors.iter().map(|v| {
+            let saturation = if max > 0.0 { v.norm() / max } else { 0.0 };
+            let hue = v[1].atan2(v[0]).to_degrees().rem_euclid(360.0);
+            let [red, green, blue] = hsv_to_rgb(hue, saturation, 1.0).map(|c| (c * 255.0).round() as u8);
+            Bucket { alpha: 255, red, green, blue }
+        }).collect();
+        Buffer::from_buckets(self.width, self.height, buckets).expect("one vector per cell")
+    }
+
+    /// The field as comma separated values, with a header line and a line
+    /// per sample point.
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("x,y,dx,dy\n");
+        for (p, v) in self.iter() {
+            csv.push_str(&format!("{},{},{},{}\n", p[0], p[1], v[0], v[1]));
+        }
+        csv
+    }
+}
+
+/// Convert a color from hue in degrees, saturation and value to RGB, all but
+/// the hue in [0, 1].
+pub(crate) fn hsv_to_rgb(hue: f32, saturation: f32, value: f32) -> [f32; 3] {
+    let f = |n: f32| {
+        let k = (n + hue / 60.0) % 6.0;
+        value - value * saturation * k.min(4.0 - k).clamp(0.0, 1.0)
+    };
+    [f(5.0), f(3.0), f(1.0)]
+}
+
+/// How strongly a single function expands or contracts the plane.
+#[derive(Debug, Clone)]
+pub struct FunctionContractivity {
+    /// Singular values of the linear part of the affine transform, largest first.
+    pub singular_values: [f32; 2],
+    /// Largest local stretch of the variation seen at any sample point.
+    pub variation_lipschitz: f32,
+    /// Median over the sample points of the log of the function's local
+    /// stretch. Negative values mean the function typically contracts.
+    pub log_contraction: f32,
+}
+
+impl FunctionContractivity {
+    pub fn is_expansive(&self) -> bool {
+        self.log_contraction >= 0.0
+    }
+}
+
+/// Estimate of whether the chaos game for a flame settles onto an attractor.
+#[derive(Debug, Clone)]
+pub struct ContractivityReport {
+    pub functions: Vec<FunctionContractivity>,
+    /// Weighted average of the functions' log contraction. Negative values
+    /// mean the flame is likely to converge.
+    pub mean_log_contraction: f32,
+}
+
+impl ContractivityReport {
+    pub fn is_likely_convergent(&self) -> bool {
+        self.mean_log_contraction < 0.0
+    }
+
+    /// Iterations an orbit takes to come within `AUTO_FUSE_TARGET` of the
+    /// attractor from where it started, if distances shrink by the mean
+    /// log contraction each iteration, clamped to `AUTO_FUSE_RANGE`. `None`
+    /// if the flame does not contract on average, or could not be measured.
+    pub fn auto_fuse(&self) -> Option<u32> {
+        if !self.is_likely_convergent() {
+            return None;
+        }
+        let (min, max) = (*AUTO_FUSE_RANGE.start(), *AUTO_FUSE_RANGE.end());
+        Some(((AUTO_FUSE_TARGET.ln() / self.mean_log_contraction).ceil().min(max as f32) as u32).max(min))
+    }
+
+    /// Indices of the functions which expand the plane on average.
+    pub fn expansive_functions(&self) -> Vec<usize> {
+        self.functions.iter().enumerate()
+            .filter(|(_, f)| f.is_expansive())
+            .map(|(i, _)| i)
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+impl std::fmt::Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Severity::Warning => write!(f, "warning"),
+            Severity::Error => write!(f, "error"),
+        }
+    }
+}
+
+impl Severity {
+    /// Names of the severities, as accepted by `from_str`.
+    pub const NAMES: [&'static str; 2] = ["warning", "error"];
+}
+
+impl std::str::FromStr for Severity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "warning" => Ok(Severity::Warning),
+            "error" => Ok(Severity::Error),
+            _ => Err(format!("unknown severity '{}' (expected warning or error)", s)),
+        }
+    }
+}
+
+/// A problem found by `Flame::validate`.
+#[derive(Debug, Clone)]
+pub struct Finding {
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl std::fmt::Display for Finding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.severity, self.message)
+    }
+}
+
+/// Largest singular value of a 2x2 matrix.
+fn spectral_norm(m: Matrix2<f32>) -> f32 {
+    m.singular_values().max()
+}
+
+/// Finite difference estimate of the Jacobian of a variation at `p`.
+fn jacobian(var: Variation, p: Point2<f32>) -> Matrix2<f32> {
+    let dx = (var.eval(p + Vector2::new(STEP, 0.)) - var.eval(p - Vector2::new(STEP, 0.))) / (2. * STEP);
+    let dy = (var.eval(p + Vector2::new(0., STEP)) - var.eval(p - Vector2::new(0., STEP))) / (2. * STEP);
+    Matrix2::from_columns(&[dx, dy])
+}
+
+impl Function {
+    fn contractivity(&self, samples: &[Point2<f32>]) -> FunctionContractivity {
+        let linear: Matrix2<f32> = self.trans.matrix().fixed_view::<2, 2>(0, 0).into();
+        let sv = linear.singular_values();
+        let singular_values = [sv.max(), sv.min()];
+
+        let mut lipschitz: f32 = 0.0;
+        let mut logs = Vec::with_capacity(samples.len());
+        // Each axis moves from the variation's input towards its output by
+        // its blend, so the Jacobian is blended with the identity likewise.
+        let blend = Matrix2::from_diagonal(&Vector2::from(self.axis_blend));
+        let rest = Matrix2::identity() - blend;
+        for &p in samples {
+            let j = blend * jacobian(self.var, self.trans * p) + rest;
+            let (var_norm, norm) = (spectral_norm(j), spectral_norm(j * linear));
+            if var_norm.is_finite() && norm.is_finite() {
+                lipschitz = lipschitz.max(var_norm);
+                logs.push(norm.max(f32::MIN_POSITIVE).ln());
+            }
+        }
+        logs.sort_by(f32::total_cmp);
+
+        FunctionContractivity {
+            singular_values,
+            variation_lipschitz: if logs.is_empty() { f32::INFINITY } else { lipschitz },
+            log_contraction: logs.get(logs.len() / 2).copied().unwrap_or(f32::INFINITY),
+        }
+    }
+}
+
+impl Flame {
+    /// Estimate how strongly each function stretches the plane, sampling
+    /// points from a short, fixed-seed run of the chaos game.
+    pub fn contractivity_report(&self) -> ContractivityReport {
+        let samples = self.attractor_samples(SAMPLES);
+        let functions: Vec<_> = self.functions.iter().map(|f| f.contractivity(&samples)).collect();
+        let total: f32 = self.functions.iter().map(|f| f.weight).sum();
+        let mean_log_contraction = self.functions.iter().zip(&functions)
+            .map(|(f, c)| f.weight * c.log_contraction)
+            .sum::<f32>() / total;
+
+        ContractivityReport { functions, mean_log_contraction }
+    }
+
+    /// Points of a fixed-seed run of the chaos game of `iterations` steps,
+    /// after `SAMPLE_WARMUP` steps to reach the attractor. Steps which leave
+    /// the finite plane restart the orbit and are not kept.
+    pub(super) fn attractor_samples(&self, iterations: usize) -> Vec<Point2<f32>> {
+        let Some(selector) = FunctionSelector::new(&self.functions) else { return Vec::new() };
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut samples = Vec::with_capacity(iterations);
+        let mut point = Point2::origin();
+        for i in 0 .. iterations + SAMPLE_WARMUP {
+            point = self.functions[selector.sample(&mut rng)].eval(point);
+            if !(point[0].is_finite() && point[1].is_finite()) {
+                point = Point2::new(rng.gen_range(-1.0 .. 1.0), rng.gen_range(-1.0 .. 1.0));
+            } else if i >= SAMPLE_WARMUP {
+                samples.push(point);
+            }
+        }
+        samples
+    }
+
+    /// Bounds with the given ratio of width to height framing the flame's
+    /// attractor, estimated from a short run of the chaos game. The most
+    /// outlying points are left out, and a margin added around the rest.
+    /// `None` if the orbit never stays finite.
+    pub fn fit_bounds(&self, aspect: f32) -> Option<Bounds> {
+        let samples = self.attractor_samples(FIT_SAMPLES);
+        if samples.is_empty() || !(aspect.is_finite() && aspect > 0.0) {
+            return None;
+        }
+        let range = |axis: usize| {
+            let mut values: Vec<f32> = samples.iter().map(|p| p[axis]).collect();
+            values.sort_by(f32::total_cmp);
+            let trim = (values.len() as f32 * FIT_OUTLIERS) as usize;
+            (values[trim], values[values.len() - 1 - trim])
+        };
+        let ((x_min, x_max), (y_min, y_max)) = (range(0), range(1));
+
+        // A point attractor still gets a window around it.
+        let mut width = ((x_max - x_min) * (1.0 + 2.0 * FIT_MARGIN)).max(FIT_MIN_SIZE);
+        let mut height = ((y_max - y_min) * (1.0 + 2.0 * FIT_MARGIN)).max(FIT_MIN_SIZE);
+        if width / height < aspect {
+            width = height * aspect;
+        } else {
+            height = width / aspect;
+        }
+        let [cx, cy] = [(x_min + x_max) / 2.0, (y_min + y_max) / 2.0];
+        Some(Bounds::new(cx - width / 2.0, cx + width / 2.0, cy - height / 2.0, cy + height / 2.0))
+    }
+
+    /// Sample the average displacement of a step of the chaos game over a
+    /// grid of `grid_w` by `grid_h` points covering the bounds: at each, the
+    /// weighted mean of `f(p) - p` over the functions `f`. Functions whose
+    /// image of a point is not finite are left out of its mean, and points
+    /// where every function's is have no displacement.
+    pub fn vector_field(&self, grid_w: usize, grid_h: usize) -> VectorField {
+        let mut field = VectorField {
+            width: grid_w,
+            height: grid_h,
+            bounds: self.bounds,
+            vectors: Vec::with_capacity(grid_w * grid_h),
+        };
+        for y in 0 .. grid_h {
+            for x in 0 .. grid_w {
+                let p = field.origin(x, y);
+                let (mut sum, mut total) = (Vector2::zeros(), 0.0);
+                for f in &self.functions {
+                    let d = f.eval(p) - p;
+                    if d[0].is_finite() && d[1].is_finite() {
+                        sum += d * f.weight;
+                        total += f.weight;
+                    }
+                }
+                field.vectors.push(if total > 0.0 { sum / total } else { Vector2::zeros() });
+            }
+        }
+        field
+    }
+
+    /// Check a flame for problems, most severe first. Errors mean the flame
+    /// cannot be rendered meaningfully; warnings that the result may be poor.
+    pub fn validate(&self) -> Vec<Finding> {
+        let mut findings = Vec::new();
+        let mut error = |message: String| findings.push(Finding { severity: Severity::Error, message });
+
+        if self.functions.is_empty() {
+            error("flame has no functions".to_string());
+        }
+        let [x_min, x_max, y_min, y_max] = self.bounds.to_array();
+        if ![x_min, x_max, y_min, y_max].iter().all(|x| x.is_finite()) || x_min >= x_max || y_min >= y_max {
+            error("bounds are empty or not finite".to_string());
+        }
+        for (i, f) in self.functions.iter().enumerate() {
+            // Named by their paths in a descriptor written from the flame.
+            let m = f.trans.matrix();
+            let coefficients = [m[(0, 0)], m[(0, 1)], m[(1, 0)], m[(1, 1)], m[(0, 2)], m[(1, 2)]];
+            let non_finite = std::iter::once((format!("functions[{}][0]", i), f.weight))
+                .chain(f.var.params().into_iter().enumerate()
+                    .map(|(j, p)| (format!("functions[{}][1].{:?}[{}]", i, f.var.discriminant(), j), p)))
+                .chain(coefficients.into_iter().enumerate()
+                    .map(|(j, x)| (format!("functions[{}][2][{}]", i, j), x)))
+                .chain(f.axis_blend.into_iter().enumerate()
+                    .map(|(j, x)| (format!("functions[{}][4][{}]", i, j), x)))
+                .find(|(_, x)| !x.is_finite());
+            if let Some((path, x)) = non_finite {
+                error(format!("function {} has parameters which are not finite ('{}' is {})", i, path, x));
+            } else if f.axis_blend.iter().any(|b| !(0.0 ..= 1.0).contains(b)) {
+                error(format!("function {} has an axis blend outside [0, 1] ({:?})", i, f.axis_blend));
+            }
+        }
+        if self.palette.alphas().iter().all(|&a| a == 0) {
+            error("every color of the palette is transparent, so nothing would be plotted".to_string());
+        }
+
+        if findings.is_empty() {
+            let report = self.contractivity_report();
+            if !report.is_likely_convergent() {
+                let expansive = report.expansive_functions().iter()
+                    .map(|i| i.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                findings.push(Finding {
+                    severity: Severity::Warning,
+                    message: format!(
+                        "flame may not converge and could render as noise (mean log contraction {:.3}, expansive functions: {})",
+                        report.mean_log_contraction,
+                        if expansive.is_empty() { "none" } else { &expansive },
+                    ),
+                });
+            }
+        }
+
+        findings
+    }
+}
+
+/// How many iterations a render needs for its mean relative error to reach
+/// a target, extrapolated from a short calibration run.
+#[derive(Debug, Clone)]
+pub struct IterationEstimate {
+    /// Iterations needed over all the pixels hit.
+    pub iters: u64,
+    /// Iterations needed by each group of `DENSITY_GROUPS` of the hit pixels
+    /// for the group's own mean relative error to reach the target, from
+    /// the sparsest pixels to the densest.
+    pub density_iters: Vec<u64>,
+    /// Fraction of the calibration's iterations which plotted a point.
+    pub hit_rate: f64,
+    /// Fraction of the calibration histogram's pixels which were hit.
+    pub coverage: f64,
+    pub calibration_iters: u64,
+    /// Pixels across and down the calibration histogram.
+    pub calibration_dims: (usize, usize),
+    /// Expected time to run `iters` iterations, at the calibration's speed.
+    pub time: Duration,
+    /// Rough peak memory of the render in bytes, as `preflight` reports it.
+    pub memory_bytes: u64,
+}
+
+impl Flame {
+    /// Estimate the iterations needed for the mean relative error over the
+    /// hit pixels of a render with `cfg` to reach `target`, by running the
+    /// chaos game for `calibration` into a histogram at most
+    /// `CALIBRATION_SIDE` pixels on a side. `None` if nothing was plotted,
+    /// or if the calibration histogram is larger than `cfg` allows.
+    ///
+    /// Each pixel's hits are taken to be Poisson distributed, so its
+    /// relative error is one over the square root of its expected count,
+    /// and the flame's density to be even within each calibration pixel.
+    /// Pixels not hit during calibration are left out, which makes the
+    /// estimate low for flames with large, faint regions.
+    pub fn estimate_iterations(&self, cfg: RunConfig, target: f64, calibration: Duration) -> Option<IterationEstimate> {
+        let scale = cfg.width.max(cfg.height).div_ceil(CALIBRATION_SIDE).max(1);
+        let (width, height) = ((cfg.width / scale).max(2), (cfg.height / scale).max(2));
+        let calibration_cfg = RunConfig {
+            width,
+            height,
+            iters: usize::MAX,
+            stop_when: None,
+            track_variance: false,
+            quality: None,
+            per_function_stats: false,
+            stable_chunk_iters: None,
+            split_bands: None,
+            attribution: false,
+            ..cfg
+        };
+
+        let mut session = RenderSession::new(self.clone(), calibration_cfg).ok()?;
+        let started = Instant::now();
+        while started.elapsed() < calibration {
+            session.advance(CALIBRATION_CHUNK);
+        }
+        let elapsed = started.elapsed();
+        let stats = session.stats();
+
+        let counts: Vec<f64> = session.buffer().buckets().iter()
+            .filter(|b| b.alpha > 0)
+            .map(|b| b.alpha as f64)
+            .collect();
+        if counts.is_empty() {
+            return None;
+        }
+        let coverage = counts.len() as f64 / (width * height) as f64;
+        let ratio = (cfg.width * cfg.height) as f64 / (width * height) as f64;
+        let (iters, density_iters) = extrapolate_iterations(counts, stats.iters, ratio, target);
+
+        let per_iter = elapsed.as_secs_f64() / stats.iters.max(1) as f64;
+        Some(IterationEstimate {
+            iters,
+            density_iters,
+            hit_rate: stats.hit_rate,
+            coverage,
+            calibration_iters: stats.iters,
+            calibration_dims: (width, height),
+            time: Duration::try_from_secs_f64(per_iter * iters as f64).unwrap_or(Duration::MAX),
+            memory_bytes: estimate_memory(cfg, self),
+        })
+    }
+}
+
+/// The iterations needed for the mean relative error over the pixels hit by
+/// a calibration run of `calibration_iters` to reach `target`, given the
+/// nonzero counts of its pixels and the number of render pixels in each,
+/// and the same for each of `DENSITY_GROUPS` groups of the pixels from the
+/// sparsest to the densest.
+fn extrapolate_iterations(mut counts: Vec<f64>, calibration_iters: u64, ratio: f64, target: f64) -> (u64, Vec<u64>) {
+    counts.sort_by(f64::total_cmp);
+    // A render pixel within a calibration pixel with count c expects
+    // c * n / (iters * ratio) hits after n iterations, so the mean of
+    // 1 / sqrt(c) over a group of pixels gives the iterations that group
+    // needs.
+    let needed = |counts: &[f64]| {
+        let mean = counts.iter().map(|c| c.sqrt().recip()).sum::<f64>() / counts.len() as f64;
+        (ratio * calibration_iters as f64 * (mean / target).powi(2)).ceil() as u64
+    };
+    let density_iters = (0 .. DENSITY_GROUPS)
+        .map(|g| &counts[g * counts.len() / DENSITY_GROUPS .. (g + 1) * counts.len() / DENSITY_GROUPS])
+        .filter(|group| !group.is_empty())
+        .map(needed)
+        .collect();
+    (needed(&counts), density_iters)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::presets;
+    use nalgebra::{Matrix3, Transform};
+
+    /// The gasket with every function replaced by `var` after scaling by `scale`.
+    fn scaled(var: Variation, scale: f32) -> Flame {
+        let mut flame = presets::gasket();
+        for f in &mut flame.functions {
+            let t = *f.trans.matrix();
+            f.var = var;
+            f.trans = Transform::from_matrix_unchecked(Matrix3::new(
+                scale, 0.0, t[(0, 2)],
+                0.0, scale, t[(1, 2)],
+                0.0, 0.0, 1.0,
+            ));
+        }
+        flame
+    }
+
+    #[test]
+    fn the_fern_contracts() {
+        let fern = presets::fern();
+        let report = fern.contractivity_report();
+        assert!(report.is_likely_convergent(), "mean log contraction {}", report.mean_log_contraction);
+        assert!(report.mean_log_contraction < -0.1);
+        // The stem squashes the plane onto a line.
+        assert!(report.functions[0].singular_values[1] < 1e-6);
+        assert!(fern.validate().is_empty());
+    }
+
+    #[test]
+    fn expanding_exponential_flames_are_flagged() {
+        let flame = scaled(Variation::Exponential, 2.0);
+        let report = flame.contractivity_report();
+        assert!(!report.is_likely_convergent(), "mean log contraction {}", report.mean_log_contraction);
+        assert_eq!(report.expansive_functions(), vec![0, 1, 2]);
+        assert!(report.functions.iter().all(|f| f.singular_values == [2.0, 2.0]));
+        assert_eq!(report.auto_fuse(), None);
+
+        // Only a warning: the flame can still be rendered.
+        let findings = flame.validate();
+        assert_eq!(findings.len(), 1);
+        assert_eq!(findings[0].severity, Severity::Warning);
+        assert!(findings[0].message.contains("expansive functions: 0, 1, 2"), "{}", findings[0]);
+    }
+
+    #[test]
+    fn linear_functions_contract_by_their_singular_values() {
+        let report = scaled(Variation::Id, 0.5).contractivity_report();
+        for f in &report.functions {
+            assert_eq!(f.singular_values, [0.5, 0.5]);
+            assert!((f.variation_lipschitz - 1.0).abs() < 1e-2);
+            assert!((f.log_contraction - 0.5f32.ln()).abs() < 1e-2);
+        }
+        assert!((report.mean_log_contraction - 0.5f32.ln()).abs() < 1e-2);
+        assert!(report.auto_fuse().is_some_and(|n| (10 ..= 20).contains(&n)));
+    }
+
+    #[test]
+    fn the_fuse_follows_how_strongly_the_flame_contracts() {
+        // Distances shrink twentyfold each iteration, so two iterations
+        // would do, but the fuse is never shorter than the shortest.
+        assert_eq!(FuseMode::Auto.resolve(&scaled(Variation::Id, 0.05)), FuseMode::Fixed(*AUTO_FUSE_RANGE.start()));
+        // One percent each iteration would take almost seven hundred.
+        assert_eq!(FuseMode::Auto.resolve(&scaled(Variation::Id, 0.99)), FuseMode::Fixed(*AUTO_FUSE_RANGE.end()));
+        // Halving distances takes ten iterations to come within a thousandth.
+        assert_eq!(FuseMode::Auto.resolve(&scaled(Variation::Id, 0.5)), FuseMode::Fixed(10));
+        assert_eq!(FuseMode::Auto.resolve(&scaled(Variation::Id, 0.8)), FuseMode::Fixed(31));
+        // Flames which do not contract keep the default, and fixed fuses
+        // are left as they are.
+        assert_eq!(FuseMode::Auto.resolve(&scaled(Variation::Exponential, 2.0)), FuseMode::Fixed(DEFAULT_FUSE));
+        assert_eq!(FuseMode::Fixed(5).resolve(&scaled(Variation::Id, 0.99)), FuseMode::Fixed(5));
+    }
+
+    /// A flame whose attractor is the horizontal segment from (-0.5, -0.53)
+    /// to (0.5, -0.53), which orbits approach only slowly, shrinking their
+    /// distance from it by a fifth each iteration.
+    fn slow_segment() -> Flame {
+        let towards = |x: f32| Function {
+            weight: 1.0,
+            trans: Transform::from_matrix_unchecked(Matrix3::new(
+                0.8, 0.0, 0.2 * x,
+                0.0, 0.8, 0.2 * -0.53,
+                0.0, 0.0, 1.0,
+            )),
+            ..presets::gasket().functions[0]
+        };
+        Flame { functions: vec![towards(-0.5), towards(0.5)], bounds: Bounds::new(-1.0, 1.0, -1.0, 1.0), ..presets::gasket() }
+    }
+
+    #[test]
+    fn automatic_fuses_leave_no_transients() {
+        let flame = slow_segment();
+        // The rows of 32 are a sixteenth high, so the segment lies well
+        // inside one of them and every other hit is an orbit yet to reach it.
+        let off_the_segment = |fuse| {
+            let cfg = RunConfig { width: 32, height: 32, iters: 200_000, seed: Some(3), fuse, ..crate::bench::baseline_config(2) };
+            let histogram = flame.run(cfg).unwrap();
+            let rows: Vec<u32> = histogram.buckets().chunks(32).map(|row| row.iter().map(|b| b.alpha).sum()).collect();
+            rows.iter().sum::<u32>() - rows.iter().max().unwrap()
+        };
+        let transients = off_the_segment(FuseMode::Fixed(5));
+        assert!(transients >= 5, "only {} points were plotted off the segment", transients);
+        assert_eq!(off_the_segment(FuseMode::Auto), 0);
+    }
+
+    /// A flame of one function applying `matrix`, over a window centered
+    /// on the origin.
+    fn linear(matrix: Matrix3<f32>) -> Flame {
+        let function = Function { weight: 1.0, trans: Transform::from_matrix_unchecked(matrix), ..presets::gasket().functions[0] };
+        Flame { functions: vec![function], bounds: Bounds::new(-1.0, 1.0, -1.0, 1.0), ..presets::gasket() }
+    }
+
+    #[test]
+    fn rotations_turn_the_field_around_the_center() {
+        // A quarter turn counterclockwise.
+        let flame = linear(Matrix3::new(0.0, -1.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0));
+        let field = flame.vector_field(8, 6);
+        assert_eq!((field.width(), field.height(), field.iter().count()), (8, 6, 48));
+        for (p, v) in field.iter() {
+            let expected = Vector2::new(-p[1] - p[0], p[0] - p[1]);
+            assert!((v - expected).norm() < 1e-6, "{:?} at {:?}", v, p);
+            // Every vector turns counterclockwise about the center.
+            assert!(p[0] * v[1] - p[1] * v[0] > 0.0);
+        }
+        // The top left cell, rows counting from the top.
+        assert_eq!(field.origin(0, 0), Point2::new(-0.875, 1.0 - 1.0 / 6.0));
+        // Vectors at opposite corners point opposite ways, and so are drawn
+        // in opposite hues at full saturation.
+        let image = field.to_buffer().to_rgb8().unwrap();
+        let (right, left) = (field.vector(7, 3), field.vector(0, 2));
+        assert!(right.dot(&left) < 0.0);
+        assert_ne!(image.get_pixel(7, 3), image.get_pixel(0, 2));
+        let weighted = |f: f32| Function { weight: f, ..flame.functions[0] };
+        // Weights only matter relative to each other.
+        let reweighted = Flame { functions: vec![weighted(3.0)], ..flame.clone() }.vector_field(8, 6);
+        assert!(reweighted.iter().zip(field.iter()).all(|((_, a), (_, b))| (a - b).norm() < 1e-6));
+    }
+
+    #[test]
+    fn the_identity_has_no_displacement() {
+        let field = linear(Matrix3::identity()).vector_field(5, 4);
+        assert!(field.iter().all(|(_, v)| v == Vector2::zeros()));
+        assert_eq!(field.max_magnitude(), 0.0);
+        let image = field.to_buffer().to_rgb8().unwrap();
+        assert!(image.pixels().all(|p| p.0 == [255, 255, 255]));
+
+        let csv = field.to_csv();
+        let lines: Vec<_> = csv.lines().collect();
+        assert_eq!((lines.len(), lines[0]), (21, "x,y,dx,dy"));
+        assert!(lines[1 ..].iter().all(|l| l.ends_with(",0,0")), "{}", csv);
+    }
+
+    #[test]
+    fn iterations_grow_with_pixels_and_precision() {
+        // A hundred hits in each pixel give a relative error of a tenth.
+        let (iters, density_iters) = extrapolate_iterations(vec![100.0; 50], 1000, 1.0, 0.01);
+        assert_eq!(iters, 100_000);
+        assert_eq!(density_iters, vec![100_000; DENSITY_GROUPS]);
+        // Four render pixels to each calibration pixel spread the hits four
+        // ways, and halving the error takes four times the iterations.
+        assert_eq!(extrapolate_iterations(vec![100.0; 50], 1000, 4.0, 0.01).0, 400_000);
+        assert_eq!(extrapolate_iterations(vec![100.0; 50], 1000, 1.0, 0.005).0, 400_000);
+    }
+
+    #[test]
+    fn sparse_pixels_need_the_most_iterations() {
+        // Half the pixels hit once and half four times, in any order.
+        let counts = (0 .. 100).map(|i| if i % 2 == 0 { 4.0 } else { 1.0 }).collect();
+        let (iters, density_iters) = extrapolate_iterations(counts, 1000, 1.0, 0.25);
+        // Errors of 1 and a half average to three quarters.
+        assert_eq!(iters, 9000);
+        assert_eq!(density_iters[.. 5], [16_000; 5]);
+        assert_eq!(density_iters[5 ..], [4000; 5]);
+
+        // Too few pixels to fill every group leave the empty ones out.
+        assert_eq!(extrapolate_iterations(vec![4.0, 1.0], 10, 1.0, 0.5).1, vec![40, 10]);
+    }
+
+    #[test]
+    fn estimates_are_within_three_times_the_iterations_needed() {
+        let flame = presets::gasket();
+        let cfg = RunConfig { width: 32, height: 32, iters: 0, ..crate::bench::baseline_config(1) };
+        let target = 0.2;
+        let estimate = flame.estimate_iterations(cfg, target, Duration::from_millis(100)).unwrap();
+        assert_eq!(estimate.calibration_dims, (32, 32));
+        assert_eq!(estimate.density_iters.len(), DENSITY_GROUPS);
+
+        let density = |iters: u64, seed: u64| -> Vec<f64> {
+            let histogram = flame.run(RunConfig { iters: iters as usize, seed: Some(seed), ..cfg }).unwrap();
+            histogram.buckets().iter().map(|b| b.alpha as f64 / iters as f64).collect()
+        };
+        let reference = density(40 * estimate.iters, 1);
+        let error = |iters: u64| {
+            let errors: Vec<f64> = density(iters, 2).iter().zip(&reference)
+                .filter(|(_, &r)| r > 0.0)
+                .map(|(d, r)| (d - r).abs() / r)
+                .collect();
+            errors.iter().sum::<f64>() / errors.len() as f64
+        };
+        let (under, over) = (error(estimate.iters / 3), error(estimate.iters * 3));
+        assert!(under > target && over < target, "{} iterations: {} at a third, {} at three times", estimate.iters, under, over);
+    }
+}
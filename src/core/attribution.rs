@@ -0,0 +1,143 @@
+use image::{Rgb, RgbImage};
+
+use super::*;
+
+/// Most functions whose hits can be kept apart in an attribution.
+pub const MAX_ATTRIBUTED_FUNCTIONS: usize = 16;
+
+/// Number of hits each function contributed to each bucket, which shows
+/// where each function's points land.
+///
+/// A hit is credited to the function applied in the iteration which
+/// plotted it, so the counts of a bucket add up to its hit count.
+#[derive(Debug, Clone)]
+pub struct Attribution {
+    width: usize,
+    height: usize,
+    functions: usize,
+    /// Counts of each bucket, `functions` at a time, in row-major order.
+    counts: Vec<u32>,
+}
+
+impl Attribution {
+    pub fn new(width: usize, height: usize, functions: usize) -> Self {
+        Attribution { width, height, functions, counts: vec![0; width * height * functions] }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Credit a hit of the bucket at `pixel` to `function`.
+    #[inline]
+    pub(crate) fn hit(&mut self, pixel: Point2<f32>, function: usize) {
+        let i = (pixel[0] as usize + pixel[1] as usize * self.width) * self.functions + function;
+        self.counts[i] = self.counts[i].saturating_add(1);
+    }
+
+    /// Add the counts of another attribution of the same size.
+    pub fn merge(&mut self, other: &Attribution) {
+        for (a, b) in self.counts.iter_mut().zip(&other.counts) {
+            *a = a.saturating_add(*b);
+        }
+    }
+
+    /// Hits of each function in the bucket at (`x`, `y`).
+    pub fn counts(&self, x: usize, y: usize) -> &[u32] {
+        let i = (x + y * self.width) * self.functions;
+        &self.counts[i .. i + self.functions]
+    }
+
+    /// The function with the most hits in the bucket at (`x`, `y`), the
+    /// first of them if several tie, or `None` if it was never hit.
+    pub fn dominant(&self, x: usize, y: usize) -> Option<usize> {
+        let counts = self.counts(x, y);
+        let max = *counts.iter().max()?;
+        (max > 0).then(|| counts.iter().position(|&c| c == max).expect("the maximum is a count"))
+    }
+
+    /// Color identifying `function` of `functions`, their hues evenly
+    /// spaced around the color wheel starting from red.
+    pub fn function_color(function: usize, functions: usize) -> Rgb<u8> {
+        shaded_color(function, functions, 1.0)
+    }
+
+    /// An image coloring each pixel by its dominant function, as given by
+    /// `function_color`, and brightening it with the logarithm of its hits
+    /// relative to the densest pixel. Pixels never hit are black.
+    pub fn to_map(&self) -> RgbImage {
+        let total = |x: usize, y: usize| self.counts(x, y).iter().map(|&c| c as f64).sum::<f64>();
+        let densest = (0 .. self.height)
+            .flat_map(|y| (0 .. self.width).map(move |x| (x, y)))
+            .map(|(x, y)| total(x, y))
+            .fold(0.0, f64::max);
+        RgbImage::from_fn(self.width as u32, self.height as u32, |x, y| {
+            let (x, y) = (x as usize, y as usize);
+            let Some(function) = self.dominant(x, y) else { return Rgb([0, 0, 0]) };
+            shaded_color(function, self.functions, (total(x, y).ln_1p() / densest.ln_1p()) as f32)
+        })
+    }
+}
+
+fn shaded_color(function: usize, functions: usize, value: f32) -> Rgb<u8> {
+    let hue = 360.0 * function as f32 / functions.max(1) as f32;
+    Rgb(hsv_to_rgb(hue, 1.0, value).map(|c| (c * 255.0).round() as u8))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bench::baseline_config;
+    use crate::presets;
+    use nalgebra::{Affine2, Matrix3};
+
+    /// Two functions shrinking the plane towards opposite corners of the
+    /// unit square, so that each lands its points in its own corner, on a
+    /// dust of points along the diagonal.
+    fn corners() -> Flame {
+        let mut flame = presets::gasket();
+        flame.functions.truncate(2);
+        for (f, offset) in flame.functions.iter_mut().zip([0.0, 0.6]) {
+            f.weight = 0.5;
+            f.trans = Affine2::from_matrix_unchecked(Matrix3::new(
+                0.4, 0.0, offset,
+                0.0, 0.4, offset,
+                0.0, 0.0, 1.0,
+            ));
+        }
+        flame.bounds = Bounds::new(-0.05, 1.05, -0.05, 1.05);
+        flame
+    }
+
+    #[test]
+    fn functions_with_separate_outputs_are_told_apart() {
+        let cfg = RunConfig { width: 44, height: 44, iters: 100_000, seed: Some(2), attribution: true, ..baseline_config(2) };
+        let mut session = RenderSession::new(corners(), cfg).unwrap();
+        session.run();
+        let (attribution, histogram) = (session.attribution().unwrap(), session.buffer());
+        let map = attribution.to_map();
+        let mut seen = [0; 2];
+        for (i, bucket) in histogram.buckets().iter().enumerate() {
+            let (x, y) = (i % 44, i / 44);
+            let counts = attribution.counts(x, y);
+            assert_eq!(counts.iter().sum::<u32>(), bucket.alpha, "pixel ({}, {})", x, y);
+            let Some(function) = attribution.dominant(x, y) else {
+                assert_eq!(map.get_pixel(x as u32, y as u32), &Rgb([0, 0, 0]));
+                continue;
+            };
+            // Only one function reaches each pixel, the first on the left
+            // half and the second on the right.
+            assert_eq!(counts[1 - function], 0, "pixel ({}, {})", x, y);
+            assert_eq!(function, usize::from(x >= 22), "pixel ({}, {})", x, y);
+            seen[function] += 1;
+            // Red for the first and cyan for the second, shaded by density.
+            let Rgb([r, g, b]) = *map.get_pixel(x as u32, y as u32);
+            assert!(if function == 0 { g == 0 && b == 0 && r > 0 } else { r == 0 && g == b && g > 0 }, "pixel ({}, {})", x, y);
+        }
+        assert!(seen[0] > 10 && seen[1] > 10, "{:?}", seen);
+    }
+}
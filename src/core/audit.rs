@@ -0,0 +1,163 @@
+use std::collections::VecDeque;
+
+/// Number of incidents each thread keeps, the most recent replacing the
+/// oldest once it is full.
+pub const INCIDENT_CAPACITY: usize = 256;
+
+/// An invariant of the chaos game found broken by a paranoid run.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IncidentKind {
+    /// A function took the orbit to an infinite or NaN point, from which it
+    /// was restarted.
+    NonFinite,
+    /// A point inside the bounds landed at this screen position, outside
+    /// the buffer, and was not plotted.
+    OffScreen { screen: [f32; 2] },
+    /// The position along the palette a point was colored from was outside
+    /// [0, 1]. Orbit colors are bytes and cannot leave their range, so this
+    /// is the position used by temporal coloring.
+    ColorOutOfRange { position: f32 },
+    /// Adding a hit to the bucket at this pixel would have overflowed it,
+    /// so the bucket was left full instead of wrapping around.
+    BucketOverflow { pixel: [usize; 2] },
+}
+
+impl std::fmt::Display for IncidentKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IncidentKind::NonFinite => write!(f, "orbit left the finite plane"),
+            IncidentKind::OffScreen { screen } => {
+                write!(f, "point in bounds mapped off screen to ({}, {})", screen[0], screen[1])
+            }
+            IncidentKind::ColorOutOfRange { position } => {
+                write!(f, "palette position {} is outside [0, 1]", position)
+            }
+            IncidentKind::BucketOverflow { pixel } => {
+                write!(f, "bucket at ({}, {}) is full", pixel[0], pixel[1])
+            }
+        }
+    }
+}
+
+/// A broken invariant, with where in the run it happened.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Incident {
+    pub kind: IncidentKind,
+    /// Number of iterations the thread had run, counting this one.
+    pub iteration: u64,
+    pub thread: usize,
+    /// The orbit's point before the function was applied.
+    pub point: [f64; 2],
+    /// Index of the function applied in this iteration.
+    pub function: usize,
+}
+
+impl std::fmt::Display for Incident {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f, "thread {} iteration {}: function {} at ({}, {}): {}",
+            self.thread, self.iteration, self.function, self.point[0], self.point[1], self.kind,
+        )
+    }
+}
+
+/// The most recent incidents of a run, and a count of all of them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IncidentLog {
+    capacity: usize,
+    entries: VecDeque<Incident>,
+    total: u64,
+}
+
+impl IncidentLog {
+    pub fn new(capacity: usize) -> Self {
+        IncidentLog { capacity, entries: VecDeque::new(), total: 0 }
+    }
+
+    /// Record an incident, dropping the oldest kept if the log is full.
+    pub fn record(&mut self, incident: Incident) {
+        self.total += 1;
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(incident);
+    }
+
+    /// Every log's incidents in one, in the order the logs are given.
+    pub fn combine<'a>(logs: impl IntoIterator<Item = &'a IncidentLog>) -> Self {
+        let mut combined = IncidentLog::new(0);
+        for log in logs {
+            combined.capacity += log.capacity;
+            combined.total += log.total;
+            combined.entries.extend(log.entries.iter().copied());
+        }
+        combined
+    }
+
+    /// Number of incidents recorded, including those no longer kept.
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// Number of incidents dropped to make room for later ones.
+    pub fn dropped(&self) -> u64 {
+        self.total - self.entries.len() as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.total == 0
+    }
+
+    /// The incidents kept, oldest first.
+    pub fn iter(&self) -> impl Iterator<Item = &Incident> {
+        self.entries.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn incident(iteration: u64, thread: usize) -> Incident {
+        Incident { kind: IncidentKind::NonFinite, iteration, thread, point: [0.0, 0.0], function: 2 }
+    }
+
+    #[test]
+    fn full_logs_keep_the_most_recent_incidents() {
+        let mut log = IncidentLog::new(3);
+        assert!(log.is_empty());
+        for i in 1 ..= 5 {
+            log.record(incident(i, 0));
+        }
+        assert_eq!((log.total(), log.dropped()), (5, 2));
+        assert_eq!(log.iter().map(|i| i.iteration).collect::<Vec<_>>(), [3, 4, 5]);
+
+        let mut counting = IncidentLog::new(0);
+        counting.record(incident(1, 0));
+        assert_eq!((counting.total(), counting.dropped(), counting.iter().count()), (1, 1, 0));
+        assert!(!counting.is_empty());
+    }
+
+    #[test]
+    fn combined_logs_keep_every_thread_in_order() {
+        let mut first = IncidentLog::new(2);
+        let mut second = IncidentLog::new(2);
+        for i in 0 .. 3 {
+            first.record(incident(i, 0));
+        }
+        second.record(incident(7, 1));
+        let combined = IncidentLog::combine([&first, &second]);
+        assert_eq!((combined.total(), combined.dropped()), (4, 1));
+        let kept: Vec<_> = combined.iter().map(|i| (i.thread, i.iteration)).collect();
+        assert_eq!(kept, [(0, 1), (0, 2), (1, 7)]);
+    }
+
+    #[test]
+    fn incidents_describe_where_they_happened() {
+        let overflow = Incident { kind: IncidentKind::BucketOverflow { pixel: [4, 9] }, point: [0.5, -1.0], ..incident(12, 3) };
+        assert_eq!(overflow.to_string(), "thread 3 iteration 12: function 2 at (0.5, -1): bucket at (4, 9) is full");
+    }
+}
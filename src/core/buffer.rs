@@ -2,9 +2,18 @@ use std::ops::{MulAssign, AddAssign};
 
 use nalgebra::Point2;
 use num_traits::{NumAssign, Float, Zero, Bounded, Num, NumCast, ToPrimitive};
-use image::{RgbImage, GrayImage, ImageBuffer};
+use image::{RgbImage, GrayImage, ImageBuffer, Rgb32FImage};
 
+use super::{Color, DitherMode};
+use super::math::{Math, PlatformMath};
+
+/// The channels of a single pixel.
+///
+/// The layout is stable: the channels are stored in the order alpha, red,
+/// green, blue with no padding, so a slice of buckets is a flat slice of
+/// four times as many channels.
 #[derive(Debug, Clone)]
+#[repr(C)]
 pub struct Bucket<T> {
     pub alpha: T,
     pub red: T,
@@ -12,6 +21,13 @@ pub struct Bucket<T> {
     pub blue: T
 }
 
+const _: () = {
+    use std::mem::{align_of, size_of};
+    assert!(size_of::<Bucket<u8>>() == 4 * size_of::<u8>() && align_of::<Bucket<u8>>() == align_of::<u8>());
+    assert!(size_of::<Bucket<u32>>() == 4 * size_of::<u32>() && align_of::<Bucket<u32>>() == align_of::<u32>());
+    assert!(size_of::<Bucket<f64>>() == 4 * size_of::<f64>() && align_of::<Bucket<f64>>() == align_of::<f64>());
+};
+
 impl<T: Zero> Bucket<T> {
     fn new() -> Self {
         Bucket {
@@ -52,39 +68,356 @@ impl<T: Float> Bucket<T> {
     }
 }
 
+/// How color channels brighter than can be displayed are handled.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum HighlightMode {
+    /// Limit each channel on its own, which turns bright colors white.
+    #[default]
+    Clip,
+    /// Scale all channels down by the brightest, keeping the hue at the cost
+    /// of brightness.
+    PreserveHue,
+    /// Keep the hue, then blend towards white as the brightest channel goes
+    /// further over range, reaching halfway at about `0.7 * knee` over.
+    DesaturateToWhite { knee: f64 },
+}
+
+impl HighlightMode {
+    /// Names of the modes, as accepted by `from_str`.
+    pub const NAMES: [&'static str; 3] = ["clip", "preserve-hue", "desaturate"];
+}
+
+impl std::str::FromStr for HighlightMode {
+    type Err = String;
+
+    /// Parses `clip`, `preserve-hue` or `desaturate`, optionally followed by
+    /// the knee as in `desaturate:0.5`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || format!("invalid highlight mode '{}' (expected clip, preserve-hue or desaturate[:KNEE])", s);
+        let (name, knee) = s.split_once(':').map_or((s, None), |(n, k)| (n, Some(k)));
+        match (name.to_ascii_lowercase().as_str(), knee) {
+            ("clip", None) => Ok(HighlightMode::Clip),
+            ("preserve-hue", None) => Ok(HighlightMode::PreserveHue),
+            ("desaturate", knee) => {
+                let knee: f64 = knee.map_or(Ok(0.5), str::parse).map_err(|_| err())?;
+                if knee > 0.0 { Ok(HighlightMode::DesaturateToWhite { knee }) } else { Err(err()) }
+            }
+            _ => Err(err()),
+        }
+    }
+}
+
+impl std::fmt::Display for HighlightMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HighlightMode::Clip => write!(f, "clip"),
+            HighlightMode::PreserveHue => write!(f, "preserve-hue"),
+            HighlightMode::DesaturateToWhite { knee } => write!(f, "desaturate:{}", knee),
+        }
+    }
+}
+
+/// Side of the square blocks a Morton ordered buffer is divided into. The
+/// blocks are stored row by row, each in Z-order.
+const MORTON_BLOCK: usize = 256;
+
+/// The bits of a byte spread out to the even bits of 16, so that two
+/// coordinates can be interleaved by table lookups.
+const SPREAD: [u16; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut bit = 0;
+        while bit < 8 {
+            table[i] |= (((i >> bit) & 1) as u16) << (2 * bit);
+            bit += 1;
+        }
+        i += 1;
+    }
+    table
+};
+
+/// Order in which the buckets of a buffer are kept in memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Layout {
+    /// Row by row, from the top.
+    #[default]
+    RowMajor,
+    /// Along a Z-order curve within square blocks, so that pixels close on
+    /// screen are mostly close in memory. The buffer is padded to a whole
+    /// number of blocks.
+    ///
+    /// Only plotting and combining are done in this layout. Buffers handed
+    /// out by a `RenderSession` are converted to row-major order first.
+    Morton,
+}
+
+impl Layout {
+    /// Names of the layouts, as accepted by `from_str`.
+    pub const NAMES: [&'static str; 2] = ["row-major", "morton"];
+
+    /// Number of buckets a buffer of the given size holds in this layout.
+    pub fn len(self, width: usize, height: usize) -> usize {
+        match self {
+            Layout::RowMajor => width * height,
+            Layout::Morton => width.div_ceil(MORTON_BLOCK) * height.div_ceil(MORTON_BLOCK) * MORTON_BLOCK * MORTON_BLOCK,
+        }
+    }
+}
+
+impl std::str::FromStr for Layout {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "row-major" => Ok(Layout::RowMajor),
+            "morton" => Ok(Layout::Morton),
+            _ => Err(format!("unknown layout '{}' (expected row-major or morton)", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for Layout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Layout::RowMajor => write!(f, "row-major"),
+            Layout::Morton => write!(f, "morton"),
+        }
+    }
+}
+
+/// Lifting of sparse regions relative to their surroundings, so that thin
+/// filaments stay visible next to dense cores.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FilamentBoost {
+    /// Gain of the sparsest regions, less one. Regions as dense as the
+    /// densest are not boosted.
+    pub strength: f64,
+    /// Standard deviation in pixels of the blur giving each pixel's
+    /// neighborhood density.
+    pub scale: usize,
+}
+
+impl std::str::FromStr for FilamentBoost {
+    type Err = String;
+
+    /// Parses the strength, optionally followed by the scale as in `0.5:16`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || format!("invalid filament boost '{}' (expected STRENGTH[:SCALE])", s);
+        let (strength, scale) = s.split_once(':').map_or((s, None), |(a, b)| (a, Some(b)));
+        let strength: f64 = strength.parse().map_err(|_| err())?;
+        let scale = scale.map_or(Ok(16), str::parse).map_err(|_| err())?;
+        if !(strength.is_finite() && strength >= 0.0) || scale == 0 {
+            return Err(err());
+        }
+        Ok(FilamentBoost { strength, scale })
+    }
+}
+
+/// Fractions of an image's pixels which had color channels over range.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ClipStats {
+    pub any_channel: f64,
+    pub all_channels: f64,
+}
+
 #[derive(Debug, Clone)]
 pub struct Buffer<T> {
     width: usize,
     height: usize,
+    layout: Layout,
     buckets: Vec<Bucket<T>>
 }
 
-impl<T: ToPrimitive> Buffer<T> {
-    pub fn convert<S: NumCast>(self) -> Buffer<S> {
-        Buffer {
-            width: self.width, height: self.height,
-            buckets: self.buckets.into_iter().map(|b| {
-                    Bucket {
-                        alpha: S::from(b.alpha).unwrap(),
-                        red: S::from(b.red).unwrap(),
-                        green: S::from(b.green).unwrap(),
-                        blue: S::from(b.blue).unwrap()
-                    }
-                }).collect()
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BufferError {
+    /// The image is less than two pixels wide or high, so its edges cannot
+    /// be mapped to the flame's bounds.
+    TooSmall { width: usize, height: usize },
+    /// The image has more pixels than allowed, or than an image can address.
+    TooLarge { width: usize, height: usize, max_pixels: usize },
+    /// The number of buckets does not match the dimensions.
+    SizeMismatch { width: usize, height: usize, buckets: usize },
+    /// The number of flat channels is not four for every pixel.
+    FlatLength { width: usize, height: usize, len: usize },
+}
+
+impl std::fmt::Display for BufferError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BufferError::TooSmall { width, height } => write!(
+                f, "image of {}x{} pixels is too small (it must be at least 2 pixels wide and high)", width, height,
+            ),
+            BufferError::TooLarge { width, height, max_pixels } => write!(
+                f, "image of {}x{} pixels is too large (at most {} pixels allowed)",
+                width, height, max_pixels,
+            ),
+            BufferError::SizeMismatch { width, height, buckets } => write!(
+                f, "buffer of {}x{} pixels holds {} buckets", width, height, buckets,
+            ),
+            BufferError::FlatLength { width, height, len } => write!(
+                f, "{} channels cannot fill a buffer of {}x{} pixels with four channels each", len, width, height,
+            ),
+        }
+    }
+}
+
+impl std::error::Error for BufferError {}
+
+impl<T> Buffer<T> {
+    /// A buffer holding the given buckets, row by row.
+    pub fn from_buckets(width: usize, height: usize, buckets: Vec<Bucket<T>>) -> Result<Self, BufferError> {
+        if width.checked_mul(height) != Some(buckets.len()) {
+            return Err(BufferError::SizeMismatch { width, height, buckets: buckets.len() });
+        }
+        Ok(Buffer { width, height, layout: Layout::RowMajor, buckets })
+    }
+
+    /// A buffer holding channels laid out as in `as_flat_slice`.
+    pub fn from_flat_vec(width: usize, height: usize, data: Vec<T>) -> Result<Self, BufferError> {
+        if width.checked_mul(height).and_then(|n| n.checked_mul(4)) != Some(data.len()) {
+            return Err(BufferError::FlatLength { width, height, len: data.len() });
+        }
+        let mut channels = data.into_iter();
+        let buckets = std::iter::from_fn(|| Some(Bucket {
+            alpha: channels.next()?,
+            red: channels.next()?,
+            green: channels.next()?,
+            blue: channels.next()?,
+        })).collect();
+        Ok(Buffer::from_parts(width, height, buckets))
+    }
+
+    /// Construct a buffer whose size is known to be right.
+    fn from_parts(width: usize, height: usize, buckets: Vec<Bucket<T>>) -> Self {
+        debug_assert_eq!(buckets.len(), width * height);
+        Buffer { width, height, layout: Layout::RowMajor, buckets }
+    }
+
+    /// Check that the buffer can be turned into an image with `channels`
+    /// bytes per pixel.
+    fn check_image_size(&self, channels: usize) -> Result<(), BufferError> {
+        let (width, height) = (self.width, self.height);
+        if width * height != self.buckets.len() {
+            return Err(BufferError::SizeMismatch { width, height, buckets: self.buckets.len() });
+        }
+        let fits = u32::try_from(width).is_ok()
+            && u32::try_from(height).is_ok()
+            && (width * height).checked_mul(channels).is_some_and(|n| n <= isize::MAX as usize);
+        if !fits {
+            return Err(BufferError::TooLarge { width, height, max_pixels: isize::MAX as usize / channels });
         }
+        Ok(())
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn layout(&self) -> Layout {
+        self.layout
+    }
+
+    /// Position in `buckets` of the pixel at `(x, y)`.
+    #[inline]
+    fn index(&self, x: usize, y: usize) -> usize {
+        match self.layout {
+            Layout::RowMajor => x + y * self.width,
+            Layout::Morton => {
+                // Coordinates past the edges would land in the padding, or in
+                // another block, rather than out of the buffer.
+                assert!(x < self.width && y < self.height, "({}, {}) is outside a {}x{} buffer", x, y, self.width, self.height);
+                let block = x / MORTON_BLOCK + y / MORTON_BLOCK * self.width.div_ceil(MORTON_BLOCK);
+                let (x, y) = (x % MORTON_BLOCK, y % MORTON_BLOCK);
+                block * MORTON_BLOCK * MORTON_BLOCK + (SPREAD[x] as usize | (SPREAD[y] as usize) << 1)
+            }
+        }
+    }
+
+    pub(crate) fn bucket_mut(&mut self, x: usize, y: usize) -> &mut Bucket<T> {
+        let i = self.index(x, y);
+        &mut self.buckets[i]
+    }
+
+    pub(crate) fn buckets(&self) -> &[Bucket<T>] {
+        assert_eq!(self.layout, Layout::RowMajor);
+        &self.buckets
+    }
+
+    /// Every bucket, row by row, whatever the layout.
+    pub(crate) fn row_major_buckets(&self) -> impl Iterator<Item = &Bucket<T>> {
+        (0 .. self.height)
+            .flat_map(move |y| (0 .. self.width).map(move |x| (x, y)))
+            .map(|(x, y)| &self.buckets[self.index(x, y)])
+    }
+
+    /// The rows of the buffer, from top to bottom.
+    pub fn rows(&self) -> impl Iterator<Item = &[Bucket<T>]> {
+        assert_eq!(self.layout, Layout::RowMajor);
+        self.buckets.chunks(self.width.max(1))
+    }
+
+    /// Every channel of every pixel, row by row, in the order alpha, red,
+    /// green, blue within each pixel.
+    pub fn as_flat_slice(&self) -> &[T] {
+        assert_eq!(self.layout, Layout::RowMajor);
+        // Buckets are `repr(C)` with four fields of type T, so they have the
+        // size and alignment of `[T; 4]`.
+        unsafe { std::slice::from_raw_parts(self.buckets.as_ptr().cast(), self.buckets.len() * 4) }
+    }
+
+    /// Mutable access to the channels, laid out as in `as_flat_slice`.
+    pub fn as_flat_slice_mut(&mut self) -> &mut [T] {
+        assert_eq!(self.layout, Layout::RowMajor);
+        // See `as_flat_slice`.
+        unsafe { std::slice::from_raw_parts_mut(self.buckets.as_mut_ptr().cast(), self.buckets.len() * 4) }
+    }
+
+    /// A buffer of new channels computed from each of this one's.
+    pub fn map_channels<S>(&self, mut f: impl FnMut(&T) -> S) -> Buffer<S> {
+        let buckets = self.buckets.iter().map(|b| Bucket {
+            alpha: f(&b.alpha),
+            red: f(&b.red),
+            green: f(&b.green),
+            blue: f(&b.blue),
+        }).collect();
+        Buffer { width: self.width, height: self.height, layout: self.layout, buckets }
+    }
+}
+
+impl<T: ToPrimitive + Copy> Buffer<T> {
+    pub fn convert<S: NumCast>(self) -> Buffer<S> {
+        self.map_channels(|&c| S::from(c).unwrap())
     }
 }
 
 impl<T: NumAssign + Copy> Buffer<T> {
     pub fn new(width: usize, height: usize) -> Self {
-        Buffer {
-            width, height,
-            buckets: vec![Bucket::new(); width * height]
-        }
+        Buffer::new_with_layout(width, height, Layout::RowMajor)
+    }
+
+    /// A buffer kept in the given layout. Only histograms being plotted are
+    /// kept in Morton order, so such buffers never leave the crate, and
+    /// row by row access to one is a bug.
+    pub(crate) fn new_with_layout(width: usize, height: usize, layout: Layout) -> Self {
+        Buffer { width, height, layout, buckets: vec![Bucket::new(); layout.len(width, height)] }
     }
 
     pub fn at_mut(&mut self, p: Point2<f32>) -> &mut Bucket<T> {
-        &mut self.buckets[p[0] as usize + p[1] as usize * self.width]
+        self.bucket_mut(p[0] as usize, p[1] as usize)
+    }
+
+    /// The same buffer with its buckets in row-major order.
+    pub fn to_row_major(self) -> Self {
+        if self.layout == Layout::RowMajor {
+            return self;
+        }
+        let buckets = self.row_major_buckets().cloned().collect();
+        Buffer::from_parts(self.width, self.height, buckets)
     }
 
     pub fn combine(buffers: impl IntoIterator<Item=Self>) -> Self {
@@ -95,7 +428,8 @@ impl<T: NumAssign + Copy> Buffer<T> {
         for buffer in buffers_iter {
             assert_eq!(combined.width, buffer.width);
             assert_eq!(combined.height, buffer.height);
-            let pairs = combined.buckets.iter_mut().zip(buffer.buckets.into_iter());
+            assert_eq!(combined.layout, buffer.layout);
+            let pairs = combined.buckets.iter_mut().zip(buffer.buckets);
             for (comb_bucket, new_bucket) in pairs {
                 *comb_bucket += new_bucket;
             }
@@ -105,79 +439,973 @@ impl<T: NumAssign + Copy> Buffer<T> {
     }
 }
 
+impl<T: Copy> Buffer<T> {
+    /// Sum each `factor` by `factor` block of pixels in the type `S`, with
+    /// the blocks at the right and bottom edges covering whatever pixels
+    /// remain, in either layout. Returns the sums and the number of pixels in each block.
+    fn block_sums<S: NumAssign + Copy + From<T>>(&self, factor: usize) -> (Buffer<S>, Vec<usize>) {
+        let factor = factor.max(1);
+        let (width, height) = (self.width.div_ceil(factor).max(1), self.height.div_ceil(factor).max(1));
+        let mut sums = Buffer::new(width, height);
+        let mut counts = vec![0; width * height];
+        for (j, bucket) in self.row_major_buckets().enumerate() {
+            let (x, y) = (j % self.width, j / self.width);
+            let i = x / factor + y / factor * width;
+            sums.buckets[i] += Bucket {
+                alpha: S::from(bucket.alpha),
+                red: S::from(bucket.red),
+                green: S::from(bucket.green),
+                blue: S::from(bucket.blue),
+            };
+            counts[i] += 1;
+        }
+        (sums, counts)
+    }
+}
+
 impl<T: Float + NumAssign + Copy + std::fmt::Debug> Buffer<T> {
+    /// A buffer smaller by `factor` in each dimension, each pixel the mean
+    /// of a block of this one's. Blocks cut short by the right and bottom
+    /// edges are averaged over the pixels they have, and the result is at
+    /// least one pixel in each dimension.
+    pub fn downsample(&self, factor: usize) -> Buffer<T> {
+        let (mut sums, counts) = self.block_sums::<T>(factor);
+        for (bucket, n) in sums.buckets.iter_mut().zip(counts) {
+            if n > 0 {
+                *bucket *= T::from(n).unwrap().recip();
+            }
+        }
+        sums
+    }
+
     pub fn log_density(&mut self) {
+        self.log_density_with::<PlatformMath>()
+    }
+
+    pub(crate) fn log_density_with<M: Math>(&mut self) {
         for bucket in self.buckets.iter_mut() {
             if bucket.alpha.is_normal() {
-                let s = bucket.alpha.ln() / bucket.alpha;
+                let s = M::ln(bucket.alpha) / bucket.alpha;
                 *bucket *= s;
             }
         }
     }
 
     pub fn gamma(&mut self, gamma: T, vibrancy: T) {
+        self.gamma_with::<PlatformMath>(gamma, vibrancy)
+    }
+
+    pub(crate) fn gamma_with<M: Math>(&mut self, gamma: T, vibrancy: T) {
         for bucket in self.buckets.iter_mut() {
             let g = gamma.recip() - T::one();
             let iv = T::one() - vibrancy;
-            let alpha_s = bucket.alpha.powf(g * vibrancy);
-            bucket.alpha = bucket.alpha.powf(gamma.recip());
-            bucket.red *= bucket.red.powf(g * iv) * alpha_s;
-            bucket.green *= bucket.green.powf(g * iv) * alpha_s;
-            bucket.blue *=bucket.blue.powf(g * iv) * alpha_s;
+            let alpha_s = M::powf(bucket.alpha, g * vibrancy);
+            bucket.alpha = M::powf(bucket.alpha, gamma.recip());
+            // Empty channels stay empty instead of becoming 0 * inf.
+            let correct = |c: T| if c > T::zero() { c * M::powf(c, g * iv) * alpha_s } else { c };
+            bucket.red = correct(bucket.red);
+            bucket.green = correct(bucket.green);
+            bucket.blue = correct(bucket.blue);
         }
     } 
 
+    /// Scale the channels so that the largest of each is one. Does nothing
+    /// to a buffer with no pixels.
     pub fn normalize(&mut self, preserve_color: bool) {
-        let max = self.buckets.iter().cloned().reduce(Bucket::max).unwrap();
-        if preserve_color {
-            let max_rgb = T::max(max.red, T::max(max.green, max.blue));
-            for bucket in self.buckets.iter_mut() {
-                bucket.alpha /= max.alpha;
-                bucket.red /= max_rgb;
-                bucket.green /= max_rgb;
-                bucket.blue /= max_rgb;
-            }
+        let Some(max) = self.buckets.iter().cloned().reduce(Bucket::max) else {
+            return;
+        };
+        // An empty channel is left at zero rather than divided by zero, and
+        // an infinite one is not divided by infinity, which would give NaN.
+        let nonzero = |m: T| if m > T::zero() && m.is_finite() { m } else { T::one() };
+        let max_alpha = nonzero(max.alpha);
+        let (max_red, max_green, max_blue) = if preserve_color {
+            let max_rgb = nonzero(T::max(max.red, T::max(max.green, max.blue)));
+            (max_rgb, max_rgb, max_rgb)
         } else {
-            for bucket in self.buckets.iter_mut() {
-                bucket.alpha /= max.alpha;
-                bucket.red /= max.red;
-                bucket.green /= max.green;
-                bucket.blue /= max.blue;
+            (nonzero(max.red), nonzero(max.green), nonzero(max.blue))
+        };
+        for bucket in self.buckets.iter_mut() {
+            bucket.alpha /= max_alpha;
+            bucket.red /= max_red;
+            bucket.green /= max_green;
+            bucket.blue /= max_blue;
+        }
+    }
+
+    /// Multiply every channel of each pixel by a gain which is larger the
+    /// sparser its neighborhood, so that hues are kept.
+    ///
+    /// The gain is `1 + strength * (1 - local / max)`, where `local` is the
+    /// alpha channel blurred over `scale` pixels and `max` its largest value.
+    pub fn filament_boost(&mut self, boost: FilamentBoost) {
+        self.filament_boost_with::<PlatformMath>(boost)
+    }
+
+    pub(crate) fn filament_boost_with<M: Math>(&mut self, boost: FilamentBoost) {
+        let alpha: Vec<T> = self.buckets.iter().map(|b| b.alpha).collect();
+        let local = gaussian_blur::<T, M>(&alpha, self.width, self.height, boost.scale as f64);
+        let max = local.iter().cloned().fold(T::zero(), T::max);
+        if max <= T::zero() {
+            return;
+        }
+        let strength = T::from(boost.strength).unwrap();
+        for (bucket, &l) in self.buckets.iter_mut().zip(&local) {
+            *bucket *= T::one() + strength * (T::one() - l / max);
+        }
+    }
+
+    /// Composite the buffer over a solid background.
+    ///
+    /// The color channels are already scaled by density, so they are treated
+    /// as premultiplied by the alpha channel. The background's alpha channel
+    /// holds its luminance, which the alpha channel is composited over for
+    /// grayscale output. All channels should be normalized to [0, 1].
+    ///
+    /// The color channels may exceed one where the background shows through
+    /// bright pixels; `highlights` brings them back into range.
+    pub fn composite(&mut self, bg: &Bucket<T>) {
+        for bucket in self.buckets.iter_mut() {
+            let t = T::one() - bucket.alpha.max(T::zero()).min(T::one());
+            bucket.alpha = (bucket.alpha + bg.alpha * t).min(T::one());
+            bucket.red += bg.red * t;
+            bucket.green += bg.green * t;
+            bucket.blue += bg.blue * t;
+        }
+    }
+
+    /// Bring color channels above one back into range, returning how many
+    /// pixels were over range beforehand. Pixels within range are unchanged.
+    pub fn highlights(&mut self, mode: HighlightMode) -> ClipStats {
+        self.highlights_with::<PlatformMath>(mode)
+    }
+
+    pub(crate) fn highlights_with<M: Math>(&mut self, mode: HighlightMode) -> ClipStats {
+        let (mut any, mut all) = (0, 0);
+        for bucket in self.buckets.iter_mut() {
+            let channels = [bucket.red, bucket.green, bucket.blue];
+            let over = channels.iter().filter(|&&c| c > T::one()).count();
+            if over == 0 {
+                continue;
+            }
+            any += 1;
+            if over == 3 {
+                all += 1;
             }
+
+            let max = channels.into_iter().fold(T::zero(), T::max);
+            let [red, green, blue] = match mode {
+                HighlightMode::Clip => channels.map(|c| c.min(T::one())),
+                HighlightMode::PreserveHue => channels.map(|c| c / max),
+                HighlightMode::DesaturateToWhite { knee } => {
+                    // Keep the hue at full brightness, then fade towards white
+                    // as the excess grows, reaching it asymptotically.
+                    let knee = T::from(knee.max(f64::MIN_POSITIVE)).unwrap();
+                    let w = T::one() - M::exp(-(max - T::one()) / knee);
+                    channels.map(|c| c / max + (T::one() - c / max) * w)
+                }
+            };
+            bucket.red = red;
+            bucket.green = green;
+            bucket.blue = blue;
         }
+
+        let n = self.buckets.len().max(1) as f64;
+        ClipStats { any_channel: any as f64 / n, all_channels: all as f64 / n }
     }
 
-    pub fn scale_convert<S: Bounded + Num + NumCast>(&self) -> Buffer<S> {
-        let new = self.buckets.iter().map(|b| {
+    /// Scale channels in [0, 1] to the full range of an integer type,
+    /// adding the dither threshold of each pixel before truncating.
+    ///
+    /// Channels outside [0, 1] are clamped to it, and NaN becomes zero.
+    pub fn scale_convert<S: Bounded + Num + NumCast>(&self, dither: DitherMode) -> Buffer<S> {
+        let buckets = self.buckets.iter().enumerate().map(|(i, b)| {
+            let offset = T::from(dither.threshold(i % self.width, i / self.width)).unwrap();
             Bucket {
-                alpha: scale(b.alpha),
-                red: scale(b.red),
-                blue: scale(b.blue),
-                green: scale(b.green),
+                alpha: scale(b.alpha, offset),
+                red: scale(b.red, offset),
+                green: scale(b.green, offset),
+                blue: scale(b.blue, offset),
             }
         }).collect();
+        Buffer::from_parts(self.width, self.height, buckets)
+    }
+}
 
-        Buffer {
-            width: self.width, height: self.height,
-            buckets: new
+/// Blur a `width` by `height` grid of values with a Gaussian of standard
+/// deviation `sigma`, as a horizontal pass and then a vertical one. Values
+/// beyond the edges are taken to be zero.
+fn gaussian_blur<T: Float, M: Math>(values: &[T], width: usize, height: usize, sigma: f64) -> Vec<T> {
+    let radius = (3.0 * sigma).ceil() as isize;
+    let kernel: Vec<T> = (-radius ..= radius)
+        .map(|i| T::from(M::exp(-(i * i) as f64 / (2.0 * sigma * sigma))).unwrap())
+        .collect();
+    let total = kernel.iter().fold(T::zero(), |a, &k| a + k);
+
+    let pass = |src: &[T], step: usize, len: usize, lines: usize, stride: usize| {
+        let mut dst = vec![T::zero(); src.len()];
+        for line in 0 .. lines {
+            for i in 0 .. len as isize {
+                let mut sum = T::zero();
+                for (k, &w) in kernel.iter().enumerate() {
+                    let j = i + k as isize - radius;
+                    if (0 .. len as isize).contains(&j) {
+                        sum = sum + w * src[line * stride + j as usize * step];
+                    }
+                }
+                dst[line * stride + i as usize * step] = sum / total;
+            }
         }
+        dst
+    };
+
+    let rows = pass(values, 1, width, height, width);
+    pass(&rows, width, height, width, 1)
+}
+
+fn scale<T: Float, S: Bounded + Num + NumCast>(val: T, offset: T) -> S {
+    let max = T::from(S::max_value()).unwrap();
+    let val = if val.is_nan() { T::zero() } else { val.max(T::zero()).min(T::one()) };
+    S::from((max * val + offset).min(max)).unwrap_or_else(S::zero)
+}
+
+impl Buffer<u32> {
+    /// A histogram smaller by `factor` in each dimension, each bucket the
+    /// sum of a block of this one's, so that the total count is unchanged.
+    /// Blocks cut short by the right and bottom edges sum the buckets they
+    /// have, and the result is at least one pixel in each dimension. Sums
+    /// are 64-bit, as a block can hold more hits than a bucket.
+    pub fn downsample_sum(&self, factor: usize) -> Buffer<u64> {
+        self.block_sums(factor).0
     }
 }
 
-fn scale<T: Float, S: Bounded + Num + NumCast>(val: T) -> S {
-    S::from(T::from(S::max_value()).unwrap() * T::max(T::zero(), val)).unwrap()
+impl Buffer<f64> {
+    /// The channels as they are, without quantizing them, as a floating
+    /// point image. Grayscale images repeat the alpha channel in each.
+    pub fn to_rgb32f(&self, grayscale: bool) -> Result<Rgb32FImage, BufferError> {
+        self.check_image_size(3)?;
+        let raw = self.buckets.iter()
+            .flat_map(|b| if grayscale { [b.alpha; 3] } else { [b.red, b.green, b.blue] })
+            .map(|c| c as f32)
+            .collect();
+        Ok(ImageBuffer::from_raw(self.width as u32, self.height as u32, raw).expect("size already checked"))
+    }
 }
 
 impl Buffer<u8> {
+    pub fn write_gray8(&self, raw: &mut Vec<u8>) {
+        raw.clear();
+        raw.extend(self.buckets.iter().map(|b| b.alpha));
+    }
+
+    pub fn write_rgb8(&self, raw: &mut Vec<u8>) {
+        raw.clear();
+        raw.extend(self.buckets.iter().flat_map(|b| [b.red, b.green, b.blue]));
+    }
+
+    pub fn to_gray8(&self) -> Result<GrayImage, BufferError> {
+        self.check_image_size(1)?;
+        let mut raw = Vec::new();
+        self.write_gray8(&mut raw);
+        Ok(ImageBuffer::from_raw(self.width as u32, self.height as u32, raw).expect("size already checked"))
+    }
+
+    pub fn to_rgb8(&self) -> Result<RgbImage, BufferError> {
+        self.check_image_size(3)?;
+        let mut raw = Vec::new();
+        self.write_rgb8(&mut raw);
+        Ok(ImageBuffer::from_raw(self.width as u32, self.height as u32, raw).expect("size already checked"))
+    }
+
+    /// `to_gray8`, under its old name.
+    ///
+    /// # Panics
+    ///
+    /// If the buffer is too large for an image.
+    #[deprecated(note = "use `to_gray8`, which returns an error for buffers too large for an image")]
+    #[allow(clippy::wrong_self_convention)]
     pub fn into_gray8(&self) -> GrayImage {
-        let raw = self.buckets.iter().map(|b| b.alpha).collect();
-        ImageBuffer::from_raw(self.width as u32, self.height as u32, raw).unwrap()
+        self.to_gray8().expect("buffer fits in an image")
     }
 
+    /// `to_rgb8`, under its old name.
+    ///
+    /// # Panics
+    ///
+    /// If the buffer is too large for an image.
+    #[deprecated(note = "use `to_rgb8`, which returns an error for buffers too large for an image")]
+    #[allow(clippy::wrong_self_convention)]
     pub fn into_rgb8(&self) -> RgbImage {
-        let raw = self.buckets.iter()
-            .map(|b| [b.red, b.green, b.blue].into_iter())
-            .flatten().collect();
-        ImageBuffer::from_raw(self.width as u32, self.height as u32, raw).unwrap()
+        self.to_rgb8().expect("buffer fits in an image")
+    }
+
+    /// The gray level of each pixel, as in grayscale output, as a plain
+    /// (P2) portable graymap.
+    pub fn to_pgm_ascii(&self) -> String {
+        let mut raw = Vec::new();
+        self.write_gray8(&mut raw);
+        pnm_ascii("P2", self.width, self.height, 1, &raw)
     }
-}
\ No newline at end of file
+
+    /// The color of each pixel as a plain (P3) portable pixmap.
+    pub fn to_ppm_ascii(&self) -> String {
+        let mut raw = Vec::new();
+        self.write_rgb8(&mut raw);
+        pnm_ascii("P3", self.width, self.height, 3, &raw)
+    }
+
+    /// Read a plain portable graymap with a maximum value of 255, as
+    /// written by `to_pgm_ascii`, into every channel of a buffer.
+    pub fn from_pgm_ascii(text: &str) -> Result<Self, PnmParseError> {
+        let (width, height, samples) = parse_pnm_ascii(text, "P2", 1)?;
+        let buckets = samples.into_iter().map(|v| Bucket { alpha: v, red: v, green: v, blue: v }).collect();
+        Ok(Buffer::from_parts(width, height, buckets))
+    }
+
+    /// Read a plain portable pixmap with a maximum value of 255, as written
+    /// by `to_ppm_ascii`. The alpha channel holds each color's luminance.
+    pub fn from_ppm_ascii(text: &str) -> Result<Self, PnmParseError> {
+        let (width, height, samples) = parse_pnm_ascii(text, "P3", 3)?;
+        let buckets = samples.chunks_exact(3).map(|c| Bucket {
+            alpha: Color::rgb(c[0], c[1], c[2]).to_gray(),
+            red: c[0],
+            green: c[1],
+            blue: c[2],
+        }).collect();
+        Ok(Buffer::from_parts(width, height, buckets))
+    }
+}
+
+/// Longest line of a plain portable anymap, as its specification asks.
+const PNM_LINE: usize = 70;
+
+/// Format 8-bit samples, `channels` to a pixel, as a plain portable
+/// anymap. Each row of the image starts a new line, and rows longer than
+/// `PNM_LINE` are wrapped between samples, so that the output changes
+/// line by line with the image.
+pub(crate) fn pnm_ascii(magic: &str, width: usize, height: usize, channels: usize, samples: &[u8]) -> String {
+    let mut out = format!("{}\n{} {}\n255\n", magic, width, height);
+    if width == 0 || channels == 0 {
+        return out;
+    }
+    for row in samples.chunks(width * channels).take(height) {
+        let mut line_len = 0;
+        for &v in row {
+            let digits = if v >= 100 { 3 } else if v >= 10 { 2 } else { 1 };
+            if line_len > 0 && line_len + 1 + digits > PNM_LINE {
+                out.push('\n');
+                line_len = 0;
+            } else if line_len > 0 {
+                out.push(' ');
+                line_len += 1;
+            }
+            out.push_str(&v.to_string());
+            line_len += digits;
+        }
+        out.push('\n');
+    }
+    out
+}
+
+/// The dimensions and samples of a plain portable anymap with the given
+/// magic number and number of samples to a pixel.
+fn parse_pnm_ascii(text: &str, magic: &str, channels: usize) -> Result<(usize, usize, Vec<u8>), PnmParseError> {
+    let err = |msg: String| PnmParseError(msg);
+    let mut tokens = text.lines()
+        .map(|line| line.split_once('#').map_or(line, |(before, _)| before))
+        .flat_map(str::split_ascii_whitespace);
+    let found = tokens.next().unwrap_or("");
+    if found != magic {
+        return Err(err(format!("expected {} but found '{}'", magic, found)));
+    }
+    let mut number = |what: &str| {
+        let token = tokens.next().ok_or_else(|| err(format!("missing {}", what)))?;
+        token.parse::<usize>().map_err(|_| err(format!("invalid {} '{}'", what, token)))
+    };
+
+    let width = number("width")?;
+    let height = number("height")?;
+    let max = number("maximum value")?;
+    if max != 255 {
+        return Err(err(format!("maximum value {} is not 255", max)));
+    }
+    let len = width.checked_mul(height).and_then(|n| n.checked_mul(channels))
+        .ok_or_else(|| err(format!("{}x{} pixels cannot be addressed", width, height)))?;
+    let samples = (0 .. len)
+        .map(|_| {
+            let v = number("sample")?;
+            u8::try_from(v).map_err(|_| err(format!("sample {} is above 255", v)))
+        })
+        .collect::<Result<Vec<u8>, _>>()?;
+    if tokens.next().is_some() {
+        return Err(err("more samples than pixels".to_string()));
+    }
+    Ok((width, height, samples))
+}
+
+/// A plain portable anymap which could not be read.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PnmParseError(String);
+
+impl std::fmt::Display for PnmParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid portable anymap: {}", self.0)
+    }
+}
+
+impl std::error::Error for PnmParseError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bench::baseline_config;
+    use crate::core::{Flame, RunConfig};
+    use crate::presets;
+    use rand::prelude::*;
+
+    /// A buffer whose pixels each count their own position, written in the
+    /// given layout.
+    fn numbered(width: usize, height: usize, layout: Layout) -> Buffer<u32> {
+        let mut buffer = Buffer::new_with_layout(width, height, layout);
+        for y in 0 .. height {
+            for x in 0 .. width {
+                buffer.bucket_mut(x, y).alpha = (x + y * width) as u32;
+            }
+        }
+        buffer
+    }
+
+    #[test]
+    fn morton_pixels_have_their_own_buckets() {
+        // Wider than a block and not a whole number of them.
+        let (width, height) = (MORTON_BLOCK + 44, 3);
+        let buffer = Buffer::<u32>::new_with_layout(width, height, Layout::Morton);
+        let mut seen = vec![false; buffer.buckets.len()];
+        for y in 0 .. height {
+            for x in 0 .. width {
+                let i = buffer.index(x, y);
+                assert!(!seen[i], "({}, {}) shares bucket {}", x, y, i);
+                seen[i] = true;
+            }
+        }
+    }
+
+    #[test]
+    fn layouts_convert_to_the_same_rows() {
+        let (width, height) = (MORTON_BLOCK + 5, 7);
+        let row_major = numbered(width, height, Layout::RowMajor);
+        let morton = numbered(width, height, Layout::Morton).to_row_major();
+        assert_eq!(morton.layout(), Layout::RowMajor);
+        assert_eq!(morton.as_flat_slice(), row_major.as_flat_slice());
+        assert!(morton.rows().all(|row| row.len() == width));
+    }
+
+    #[test]
+    #[should_panic(expected = "outside")]
+    fn morton_buffers_check_x() {
+        // Past the right edge but within the padding of the block.
+        let mut buffer = Buffer::<u32>::new_with_layout(10, 10, Layout::Morton);
+        buffer.bucket_mut(10, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "outside")]
+    fn morton_buffers_check_y() {
+        let mut buffer = Buffer::<u32>::new_with_layout(10, 10, Layout::Morton);
+        buffer.bucket_mut(0, 10);
+    }
+
+    #[test]
+    #[should_panic]
+    fn morton_buffers_have_no_flat_slice() {
+        Buffer::<u32>::new_with_layout(4, 4, Layout::Morton).as_flat_slice();
+    }
+
+    #[test]
+    #[should_panic]
+    fn morton_buffers_have_no_rows() {
+        let _ = Buffer::<u32>::new_with_layout(4, 4, Layout::Morton).rows().count();
+    }
+
+    /// A buffer of pseudo-random bytes, each channel of each pixel different.
+    fn noise(width: usize, height: usize) -> Buffer<u8> {
+        let mut state = 0x2545_f491_u32;
+        let data = (0 .. width * height * 4).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        }).collect();
+        Buffer::from_flat_vec(width, height, data).unwrap()
+    }
+
+    #[test]
+    fn plain_pnm_round_trips() {
+        // Wide enough that rows wrap.
+        let buffer = noise(41, 5);
+        let gray = Buffer::from_pgm_ascii(&buffer.to_pgm_ascii()).unwrap();
+        let color = Buffer::from_ppm_ascii(&buffer.to_ppm_ascii()).unwrap();
+        assert_eq!((gray.width(), gray.height()), (41, 5));
+        for ((original, gray), color) in buffer.buckets().iter().zip(gray.buckets()).zip(color.buckets()) {
+            assert_eq!([gray.alpha, gray.red, gray.green, gray.blue], [original.alpha; 4]);
+            assert_eq!([color.red, color.green, color.blue], [original.red, original.green, original.blue]);
+            assert_eq!(color.alpha, Color::rgb(original.red, original.green, original.blue).to_gray());
+        }
+    }
+
+    #[test]
+    fn plain_pnm_lines_are_short_and_untrimmed() {
+        let text = noise(41, 5).to_ppm_ascii();
+        assert!(text.starts_with("P3\n41 5\n255\n"));
+        assert!(text.ends_with('\n'));
+        for line in text.lines() {
+            assert!(line.len() <= PNM_LINE, "{:?} is too long", line);
+            assert!(!line.starts_with(' ') && !line.ends_with(' '), "{:?} has stray spaces", line);
+        }
+        // Formatting the same image twice gives the same text.
+        assert_eq!(text, noise(41, 5).to_ppm_ascii());
+    }
+
+    #[test]
+    fn the_image_crate_opens_plain_pnm() {
+        let buffer = noise(13, 4);
+        let gray = image::load_from_memory(buffer.to_pgm_ascii().as_bytes()).unwrap().into_luma8();
+        assert_eq!(gray.as_raw(), buffer.to_gray8().unwrap().as_raw());
+        let color = image::load_from_memory(buffer.to_ppm_ascii().as_bytes()).unwrap().into_rgb8();
+        assert_eq!(color.as_raw(), buffer.to_rgb8().unwrap().as_raw());
+    }
+
+    #[test]
+    fn empty_images_have_only_a_header() {
+        for (width, height) in [(0, 3), (3, 0), (0, 0)] {
+            let buffer = Buffer::<u8>::new(width, height);
+            let text = buffer.to_pgm_ascii();
+            assert_eq!(text, format!("P2\n{} {}\n255\n", width, height));
+            let read = Buffer::from_pgm_ascii(&text).unwrap();
+            assert_eq!((read.width(), read.height()), (width, height));
+            assert_eq!(buffer.to_ppm_ascii(), format!("P3\n{} {}\n255\n", width, height));
+        }
+    }
+
+    #[test]
+    fn malformed_plain_pnm_is_rejected() {
+        let valid = "P2\n2 1\n255\n0 255\n";
+        assert!(Buffer::from_pgm_ascii(valid).is_ok());
+        assert!(Buffer::from_pgm_ascii("P2 # a comment\n2 1 255 0 255").is_ok());
+        for text in [
+            "P3\n2 1\n255\n0 255\n",
+            "P2\n2 1\n15\n0 15\n",
+            "P2\n2 1\n255\n0\n",
+            "P2\n2 1\n255\n0 255 7\n",
+            "P2\n2 1\n255\n0 256\n",
+            "P2\n2 x\n255\n",
+            "P2\n18446744073709551615 2\n255\n",
+            "",
+        ] {
+            assert!(Buffer::from_pgm_ascii(text).is_err(), "{:?} was read", text);
+        }
+    }
+
+    fn seeded_run(flame: &Flame, threads: usize, layout: Layout) -> Buffer<u32> {
+        // Large enough to span several blocks, with partial ones at the
+        // right and bottom edges.
+        flame.run(RunConfig {
+            width: MORTON_BLOCK * 2 + 17,
+            height: MORTON_BLOCK + 3,
+            iters: 200_000,
+            layout,
+            ..baseline_config(threads)
+        }).unwrap()
+    }
+
+    #[test]
+    fn both_layouts_give_the_same_histogram_and_image() {
+        for flame in [presets::gasket(), presets::swirl()] {
+            for threads in [1, 3] {
+                let row_major = seeded_run(&flame, threads, Layout::RowMajor);
+                let morton = seeded_run(&flame, threads, Layout::Morton);
+                assert_eq!(morton.layout(), Layout::RowMajor);
+                assert_eq!(morton.as_flat_slice(), row_major.as_flat_slice());
+                let image = |b: Buffer<u32>| b.convert::<f64>().scale_convert::<u8>(DitherMode::None).to_rgb8().unwrap();
+                assert_eq!(image(morton), image(row_major));
+            }
+        }
+    }
+
+    #[test]
+    fn buckets_must_match_the_dimensions() {
+        let buckets = || vec![Bucket::<u8>::new(); 6];
+        assert!(Buffer::from_buckets(3, 2, buckets()).is_ok());
+        assert_eq!(
+            Buffer::from_buckets(4, 2, buckets()).err(),
+            Some(BufferError::SizeMismatch { width: 4, height: 2, buckets: 6 }),
+        );
+        // Dimensions whose product overflows match no number of buckets.
+        assert!(matches!(Buffer::from_buckets(usize::MAX, 2, buckets()), Err(BufferError::SizeMismatch { .. })));
+        assert_eq!(
+            Buffer::<u8>::from_flat_vec(2, 2, vec![0; 15]).err(),
+            Some(BufferError::FlatLength { width: 2, height: 2, len: 15 }),
+        );
+    }
+
+    #[test]
+    fn images_report_their_size_errors() {
+        // Too wide for an image, though it holds no pixels at all.
+        let wide = Buffer::<u8>::from_buckets(1 << 32, 0, Vec::new()).unwrap();
+        assert!(matches!(wide.to_rgb8(), Err(BufferError::TooLarge { width, height: 0, .. }) if width == 1 << 32));
+        assert!(matches!(wide.to_gray8(), Err(BufferError::TooLarge { .. })));
+        assert!(matches!(wide.to_image(false), Err(BufferError::TooLarge { .. })));
+
+        let mut short = noise(3, 2);
+        short.buckets.pop();
+        let mismatch = BufferError::SizeMismatch { width: 3, height: 2, buckets: 5 };
+        assert_eq!(short.to_rgb8().err(), Some(mismatch.clone()));
+        assert_eq!(short.to_gray8().err(), Some(mismatch.clone()));
+        assert_eq!(mismatch.to_string(), "buffer of 3x2 pixels holds 5 buckets");
+    }
+
+    #[test]
+    fn normal_sizes_make_images() {
+        let buffer = noise(5, 3);
+        let rgb = buffer.to_rgb8().unwrap();
+        assert_eq!(rgb.dimensions(), (5, 3));
+        let mut raw = Vec::new();
+        buffer.write_rgb8(&mut raw);
+        assert_eq!(rgb.into_raw(), raw);
+
+        let gray = buffer.to_gray8().unwrap();
+        assert_eq!(gray.dimensions(), (5, 3));
+        assert_eq!(gray.get_pixel(4, 2).0[0], buffer.buckets[14].alpha);
+        assert!(matches!(buffer.to_image(true), Ok(image::DynamicImage::ImageLuma8(_))));
+    }
+
+    /// A pixel within range, one with a single channel over and one with
+    /// every channel over.
+    fn bright() -> Buffer<f64> {
+        let bucket = |red, green, blue| Bucket { alpha: 1.0, red, green, blue };
+        Buffer::from_buckets(3, 1, vec![bucket(0.2, 0.5, 0.9), bucket(2.0, 0.5, 0.25), bucket(1.5, 1.2, 3.0)]).unwrap()
+    }
+
+    fn colors(buffer: &Buffer<f64>) -> Vec<[f64; 3]> {
+        buffer.buckets.iter().map(|b| [b.red, b.green, b.blue]).collect()
+    }
+
+    fn assert_colors_close(buffer: &Buffer<f64>, expected: [[f64; 3]; 3]) {
+        for (got, want) in colors(buffer).iter().zip(expected) {
+            assert!(got.iter().zip(want).all(|(g, w)| (g - w).abs() < 1e-12), "{:?} is not {:?}", got, want);
+        }
+    }
+
+    #[test]
+    fn highlight_modes_bring_channels_into_range() {
+        let modes = [HighlightMode::Clip, HighlightMode::PreserveHue, HighlightMode::DesaturateToWhite { knee: 0.5 }];
+        for mode in modes {
+            let mut buffer = bright();
+            let stats = buffer.highlights(mode);
+            assert_eq!((stats.any_channel, stats.all_channels), (2. / 3., 1. / 3.), "{:?}", mode);
+            // Pixels within range are left exactly as they were.
+            assert_eq!(colors(&buffer)[0], [0.2, 0.5, 0.9], "{:?}", mode);
+            assert!(colors(&buffer).iter().flatten().all(|&c| (0.0 ..= 1.0).contains(&c)), "{:?}", mode);
+        }
+
+        let mut clipped = bright();
+        clipped.highlights(HighlightMode::Clip);
+        assert_colors_close(&clipped, [[0.2, 0.5, 0.9], [1.0, 0.5, 0.25], [1.0, 1.0, 1.0]]);
+
+        let mut hue = bright();
+        hue.highlights(HighlightMode::PreserveHue);
+        assert_colors_close(&hue, [[0.2, 0.5, 0.9], [1.0, 0.25, 0.125], [0.5, 0.4, 1.0]]);
+
+        // One over the knee of 0.5 is two knees, so the hue is blended
+        // 1 - e^-2 of the way to white; half a knee over blends 1 - e^-1.
+        let mut desaturated = bright();
+        desaturated.highlights(HighlightMode::DesaturateToWhite { knee: 0.5 });
+        let blend = |c: f64, w: f64| c + (1.0 - c) * w;
+        let (w1, w2) = (1.0 - (-2.0f64).exp(), 1.0 - (-4.0f64).exp());
+        assert_colors_close(&desaturated, [
+            [0.2, 0.5, 0.9],
+            [1.0, blend(0.25, w1), blend(0.125, w1)],
+            [blend(0.5, w2), blend(0.4, w2), 1.0],
+        ]);
+    }
+
+    #[test]
+    fn images_within_range_report_no_clipping() {
+        let mut buffer = Buffer::from_buckets(1, 1, vec![bright().buckets[0].clone()]).unwrap();
+        let stats = buffer.highlights(HighlightMode::PreserveHue);
+        assert_eq!((stats.any_channel, stats.all_channels), (0.0, 0.0));
+        // Exactly one is within range.
+        let mut white = Buffer::from_buckets(1, 1, vec![Bucket { alpha: 1.0, red: 1.0, green: 1.0, blue: 1.0 }]).unwrap();
+        assert_eq!(white.highlights(HighlightMode::DesaturateToWhite { knee: 0.1 }).any_channel, 0.0);
+    }
+
+    #[test]
+    fn highlight_modes_parse_by_name() {
+        assert_eq!("clip".parse(), Ok(HighlightMode::Clip));
+        assert_eq!("Preserve-Hue".parse(), Ok(HighlightMode::PreserveHue));
+        assert_eq!("desaturate".parse(), Ok(HighlightMode::DesaturateToWhite { knee: 0.5 }));
+        assert_eq!("desaturate:0.25".parse(), Ok(HighlightMode::DesaturateToWhite { knee: 0.25 }));
+        for bad in ["clamp", "clip:1", "desaturate:0", "desaturate:-1", "desaturate:x"] {
+            assert!(bad.parse::<HighlightMode>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn flat_slices_hold_each_pixel_alpha_first() {
+        let (width, height) = (5, 3);
+        let mut buffer = Buffer::<u32>::new(width, height);
+        for y in 0 .. height {
+            for x in 0 .. width {
+                let n = (x + y * width) as u32;
+                *buffer.bucket_mut(x, y) = Bucket { alpha: n, red: 100 + n, green: 200 + n, blue: 300 + n };
+            }
+        }
+
+        // Reinterpreted as an array of [alpha, red, green, blue] per pixel,
+        // row by row, as an image or GPU upload would read it.
+        let flat = buffer.as_flat_slice();
+        assert_eq!(flat.len(), width * height * 4);
+        for (n, pixel) in flat.chunks_exact(4).enumerate() {
+            let n = n as u32;
+            assert_eq!(pixel, [n, 100 + n, 200 + n, 300 + n]);
+        }
+        let rows: Vec<_> = buffer.rows().collect();
+        assert_eq!(rows.len(), height);
+        assert!(rows.iter().all(|row| row.len() == width));
+        assert_eq!(rows[2][1].red, 100 + 11);
+
+        let copy = Buffer::from_flat_vec(width, height, flat.to_vec()).unwrap();
+        assert_eq!(copy.as_flat_slice(), flat);
+    }
+
+    #[test]
+    fn flat_slices_write_through_to_the_buckets() {
+        let mut buffer = Buffer::<u8>::new(2, 2);
+        for (i, c) in buffer.as_flat_slice_mut().iter_mut().enumerate() {
+            *c = i as u8;
+        }
+        let b = &buffer.buckets[3];
+        assert_eq!([b.alpha, b.red, b.green, b.blue], [12, 13, 14, 15]);
+
+        let doubled = buffer.map_channels(|&c| c as u32 * 2);
+        assert_eq!(doubled.as_flat_slice(), (0 .. 16).map(|c| c * 2).collect::<Vec<u32>>());
+        assert_eq!((doubled.width(), doubled.height()), (2, 2));
+    }
+
+    /// A slow ramp from black, rising by a twentieth of a level per pixel
+    /// along each row.
+    fn ramp(width: usize, height: usize) -> Buffer<f64> {
+        let buckets = (0 .. width * height).map(|i| {
+            let v = (i % width) as f64 / (20.0 * 255.0);
+            Bucket { alpha: 1.0, red: v, green: v, blue: v }
+        }).collect();
+        Buffer::from_buckets(width, height, buckets).unwrap()
+    }
+
+    /// Number of runs of equal values along the rows.
+    fn runs(buffer: &Buffer<u8>) -> usize {
+        buffer.rows().map(|row| 1 + row.windows(2).filter(|w| w[0].red != w[1].red).count()).sum()
+    }
+
+    #[test]
+    fn dithering_breaks_up_contours() {
+        let ramp = ramp(400, 16);
+        let plain = runs(&ramp.scale_convert(DitherMode::None));
+        // Twenty levels, each a single run of twenty pixels.
+        assert_eq!(plain, 20 * 16);
+        for mode in [DitherMode::Ordered8x8, DitherMode::BlueNoise] {
+            let dithered = ramp.scale_convert::<u8>(mode);
+            assert!(runs(&dithered) > 5 * plain, "{:?} has {} runs", mode, runs(&dithered));
+            // Averaged over a tile, the dithered levels follow the ramp.
+            let mean = |x0: usize| {
+                let sum: u32 = dithered.rows().flat_map(|row| &row[x0 .. x0 + 16]).map(|b| b.red as u32).sum();
+                sum as f64 / (16.0 * 16.0)
+            };
+            for x0 in [0, 96, 192, 304] {
+                let exact = (x0 as f64 + 7.5) / 20.0;
+                assert!((mean(x0) - exact).abs() < 0.2, "{:?} at {} is {} not {}", mode, x0, mean(x0), exact);
+            }
+            assert_eq!(ramp.scale_convert::<u8>(mode).as_flat_slice(), dithered.as_flat_slice());
+        }
+    }
+
+    #[test]
+    fn undithered_conversion_truncates() {
+        let values = [0.0, 1e-9, 0.5, 0.999, 1.0, 1.5, -0.25];
+        let buckets = values.iter().map(|&v| Bucket { alpha: v, red: v, green: v, blue: v }).collect();
+        let buffer = Buffer::from_buckets(values.len(), 1, buckets).unwrap().scale_convert::<u8>(DitherMode::None);
+        let reds: Vec<u8> = buffer.buckets.iter().map(|b| b.red).collect();
+        assert_eq!(reds, [0, 0, 127, 254, 255, 255, 0]);
+    }
+
+    #[test]
+    fn conversion_is_total() {
+        let values = [f64::NAN, f64::INFINITY, f64::NEG_INFINITY, -0.5, 2.0, -0.0, f64::MAX, f64::MIN_POSITIVE];
+        let buckets = values.iter().map(|&v| Bucket { alpha: v, red: v, green: v, blue: v }).collect();
+        let buffer = Buffer::from_buckets(values.len(), 1, buckets).unwrap();
+        for dither in [DitherMode::None, DitherMode::BlueNoise] {
+            let reds: Vec<u8> = buffer.scale_convert::<u8>(dither).buckets.iter().map(|b| b.red).collect();
+            assert_eq!(reds[.. 7], [0, 255, 0, 0, 255, 0, 255], "{:?}", dither);
+            assert!(reds[7] <= 1);
+            let wide: Vec<u32> = buffer.scale_convert::<u32>(dither).buckets.iter().map(|b| b.red).collect();
+            assert_eq!(wide[.. 5], [0, u32::MAX, 0, 0, u32::MAX]);
+        }
+    }
+
+    #[test]
+    fn normalizing_tolerates_empty_and_infinite_channels() {
+        let mut empty = Buffer::<f64>::new(0, 0);
+        empty.normalize(false);
+        assert_eq!(empty.as_flat_slice(), &[] as &[f64]);
+
+        let mut zero = Buffer::<f64>::new(3, 2);
+        zero.normalize(true);
+        assert!(zero.as_flat_slice().iter().all(|&c| c == 0.0));
+
+        // An infinite channel is left as it is rather than made NaN.
+        let bucket = |red| Bucket { alpha: 2.0, red, green: 4.0, blue: 0.0 };
+        let mut infinite = Buffer::from_buckets(2, 1, vec![bucket(f64::INFINITY), bucket(1.0)]).unwrap();
+        infinite.normalize(false);
+        let b = &infinite.buckets[1];
+        assert_eq!([b.alpha, b.red, b.green, b.blue], [1.0, 1.0, 1.0, 0.0]);
+        assert_eq!(infinite.buckets[0].red, f64::INFINITY);
+        assert!(infinite.as_flat_slice().iter().all(|c| !c.is_nan()));
+    }
+
+    /// A dim image with a bright 5x5 blob about (16, 16) and a faint
+    /// vertical line at x = 80.
+    fn blob_and_line() -> Buffer<f64> {
+        let (width, height) = (96, 64);
+        let mut buffer = Buffer::<f64>::new(width, height);
+        for y in 0 .. height {
+            for x in 0 .. width {
+                let level = if (14 ..= 18).contains(&x) && (14 ..= 18).contains(&y) {
+                    1.0
+                } else if x == 80 {
+                    0.05
+                } else {
+                    continue;
+                };
+                buffer.buckets[y * width + x] = Bucket { alpha: level, red: level, green: level / 2.0, blue: 0.0 };
+            }
+        }
+        buffer
+    }
+
+    #[test]
+    fn filament_boost_lifts_faint_lines_and_spares_dense_blobs() {
+        let baseline = blob_and_line();
+        let mut boosted = baseline.clone();
+        boosted.filament_boost(FilamentBoost { strength: 0.5, scale: 16 });
+
+        let at = |buffer: &Buffer<f64>, x: usize, y: usize| buffer.buckets[y * buffer.width + x].clone();
+        for y in 14 ..= 18 {
+            for x in 14 ..= 18 {
+                let (before, after) = (at(&baseline, x, y), at(&boosted, x, y));
+                assert!(after.alpha >= before.alpha && after.alpha <= before.alpha * 1.01, "blob at ({}, {})", x, y);
+            }
+        }
+        for y in [0, 32, 63] {
+            let (before, after) = (at(&baseline, 80, y), at(&boosted, 80, y));
+            assert!(after.alpha > before.alpha * 1.4, "line at y = {}", y);
+            // Every channel gets the same gain, so the hue is kept.
+            assert!((after.red / after.green - before.red / before.green).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn filament_boost_leaves_empty_images_alone() {
+        let mut empty = Buffer::<f64>::new(8, 8);
+        empty.filament_boost(FilamentBoost { strength: 2.0, scale: 4 });
+        assert!(empty.as_flat_slice().iter().all(|&c| c == 0.0));
+    }
+
+    #[test]
+    fn filament_boosts_parse_with_a_default_scale() {
+        assert_eq!("0.5".parse(), Ok(FilamentBoost { strength: 0.5, scale: 16 }));
+        assert_eq!("1:4".parse(), Ok(FilamentBoost { strength: 1.0, scale: 4 }));
+        for bad in ["", "-1", "nan", "0.5:0", "0.5:x", "inf"] {
+            assert!(bad.parse::<FilamentBoost>().is_err(), "{}", bad);
+        }
+    }
+
+    /// A buffer of the given rows of alphas, each color channel a fixed
+    /// multiple of the alpha.
+    fn alphas<T: Copy + std::ops::Mul<Output = T> + From<u8>>(rows: &[&[T]]) -> Buffer<T> {
+        let buckets = rows.iter().flat_map(|row| row.iter().map(|&a| Bucket {
+            alpha: a,
+            red: a * T::from(2),
+            green: a * T::from(3),
+            blue: a * T::from(4),
+        })).collect();
+        Buffer::from_buckets(rows[0].len(), rows.len(), buckets).unwrap()
+    }
+
+    #[test]
+    fn downsampling_averages_blocks_and_partial_edges() {
+        let buffer = alphas::<f64>(&[
+            &[1.0, 2.0, 3.0, 4.0, 5.0],
+            &[6.0, 7.0, 8.0, 9.0, 10.0],
+            &[11.0, 12.0, 13.0, 14.0, 15.0],
+        ]);
+        let small = buffer.downsample(2);
+        assert_eq!((small.width(), small.height()), (3, 2));
+        // Whole blocks, blocks cut short on the right, at the bottom, and
+        // the corner of a single pixel.
+        let expected = [4.0, 6.0, 7.5, 11.5, 13.5, 15.0];
+        for (bucket, mean) in small.buckets().iter().zip(expected) {
+            assert_eq!([bucket.alpha, bucket.red, bucket.green, bucket.blue], [mean, 2.0 * mean, 3.0 * mean, 4.0 * mean]);
+        }
+
+        let whole = buffer.downsample(10);
+        assert_eq!((whole.width(), whole.height()), (1, 1));
+        assert_eq!(whole.buckets()[0].alpha, 8.0);
+        // A factor of one, or zero, copies the buffer.
+        for factor in [0, 1] {
+            assert_eq!(buffer.downsample(factor).as_flat_slice(), buffer.as_flat_slice());
+        }
+    }
+
+    #[test]
+    fn downsampling_sums_blocks_and_partial_edges() {
+        let buffer = alphas::<u32>(&[
+            &[1, 2, 3, 4, 5, 6, 7],
+            &[8, 9, 10, 11, 12, 13, 14],
+            &[15, 16, 17, 18, 19, 20, 21],
+            &[22, 23, 24, 25, 26, 27, 28],
+        ]);
+        let small = buffer.downsample_sum(3);
+        assert_eq!((small.width(), small.height()), (3, 2));
+        let expected = [81, 108, 42, 69, 78, 28];
+        for (bucket, sum) in small.buckets().iter().zip(expected) {
+            assert_eq!([bucket.alpha, bucket.red, bucket.green, bucket.blue], [sum, 2 * sum, 3 * sum, 4 * sum]);
+        }
+        // Sums past a bucket's range are kept.
+        let full = alphas::<u32>(&[&[u32::MAX / 4, u32::MAX / 4], &[u32::MAX / 4, u32::MAX / 4]]).downsample_sum(2);
+        assert_eq!(full.buckets()[0].alpha, 4 * (u32::MAX / 4) as u64);
+        // Nothing is ever zero sized.
+        let empty = Buffer::<u32>::new(0, 0).downsample_sum(4);
+        assert_eq!((empty.width(), empty.height()), (1, 1));
+    }
+
+    #[test]
+    fn downsampling_sums_preserve_the_total_alpha() {
+        let mut rng = StdRng::seed_from_u64(9);
+        for _ in 0 .. 200 {
+            let (width, height) = (rng.gen_range(1 ..= 40), rng.gen_range(1 ..= 40));
+            let layout = if rng.gen() { Layout::RowMajor } else { Layout::Morton };
+            let mut buffer = Buffer::<u32>::new_with_layout(width, height, layout);
+            for y in 0 .. height {
+                for x in 0 .. width {
+                    buffer.bucket_mut(x, y).alpha = rng.gen_range(0 .. 1 << 20);
+                }
+            }
+            let total: u64 = buffer.row_major_buckets().map(|b| b.alpha as u64).sum();
+            let factor = rng.gen_range(1 ..= 50);
+            let summed = buffer.downsample_sum(factor);
+            assert_eq!((summed.width(), summed.height()), (width.div_ceil(factor), height.div_ceil(factor)));
+            assert_eq!(summed.buckets().iter().map(|b| b.alpha).sum::<u64>(), total, "{}x{} by {}", width, height, factor);
+        }
+    }
+}
@@ -1,4 +1,4 @@
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Color {
     pub red: u8,
     pub green: u8,
@@ -9,16 +9,402 @@ impl Color {
     pub fn rgb(red: u8, green: u8, blue: u8) -> Self {
         Color { red, green, blue }
     }
+
+    /// The color's channels in linear (gamma expanded) sRGB, between 0 and 1.
+    pub fn to_linear(self) -> [f32; 3] {
+        [self.red, self.green, self.blue].map(|c| srgb_to_linear(c as f32 / 255.))
+    }
+
+    pub fn from_linear(rgb: [f32; 3]) -> Self {
+        let [red, green, blue] = rgb.map(|c| (linear_to_srgb(c.clamp(0., 1.)) * 255.).round() as u8);
+        Color { red, green, blue }
+    }
+
+    pub fn to_oklab(self) -> Oklab {
+        Oklab::from_linear(self.to_linear())
+    }
+
+    pub fn from_oklab(lab: Oklab) -> Self {
+        Color::from_linear(lab.to_linear())
+    }
+
+    pub fn to_oklch(self) -> Oklch {
+        self.to_oklab().to_oklch()
+    }
+
+    /// The displayable color nearest `lch` in lightness and hue, found by
+    /// reducing its chroma until it is within the sRGB gamut.
+    pub fn from_oklch(lch: Oklch) -> Self {
+        let in_gamut = |c: f32| Oklch { c, ..lch }.to_oklab().to_linear().iter().all(|x| (-1e-4 ..= 1.0 + 1e-4).contains(x));
+        if in_gamut(lch.c) {
+            return Color::from_oklab(lch.to_oklab());
+        }
+        let (mut lo, mut hi) = (0.0, lch.c.max(0.0));
+        for _ in 0 .. 24 {
+            let mid = (lo + hi) / 2.0;
+            if in_gamut(mid) { lo = mid } else { hi = mid }
+        }
+        Color::from_oklab(Oklch { c: lo, ..lch }.to_oklab())
+    }
+
+    /// The sRGB encoded relative luminance of the color.
+    pub fn to_gray(self) -> u8 {
+        let [r, g, b] = self.to_linear();
+        let y = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+        (linear_to_srgb(y.clamp(0., 1.)) * 255.).round() as u8
+    }
+
+    /// Simulate how the color appears to a viewer with the given color vision deficiency.
+    pub fn simulate(self, cvd: Deficiency) -> Self {
+        Color::from_linear(mat_mul(cvd.matrix(), self.to_linear()))
+    }
+}
+
+/// Parses colors written as `#rrggbb`, `#rgb` or one of a handful of names.
+impl std::str::FromStr for Color {
+    type Err = ColorParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || ColorParseError(s.to_string());
+        let named = match s.to_ascii_lowercase().as_str() {
+            "black" => Some(Color::rgb(0, 0, 0)),
+            "white" => Some(Color::rgb(255, 255, 255)),
+            "gray" | "grey" => Some(Color::rgb(128, 128, 128)),
+            "red" => Some(Color::rgb(255, 0, 0)),
+            "green" => Some(Color::rgb(0, 128, 0)),
+            "blue" => Some(Color::rgb(0, 0, 255)),
+            "yellow" => Some(Color::rgb(255, 255, 0)),
+            "cyan" => Some(Color::rgb(0, 255, 255)),
+            "magenta" => Some(Color::rgb(255, 0, 255)),
+            _ => None,
+        };
+        if let Some(c) = named {
+            return Ok(c);
+        }
+
+        let hex = s.strip_prefix('#').ok_or_else(err)?;
+        if !hex.is_ascii() {
+            return Err(err());
+        }
+        let channel = |h: &str| u8::from_str_radix(h, 16).map_err(|_| err());
+        match hex.len() {
+            6 => Ok(Color::rgb(channel(&hex[0..2])?, channel(&hex[2..4])?, channel(&hex[4..6])?)),
+            3 => Ok(Color::rgb(
+                channel(&hex[0..1])? * 17,
+                channel(&hex[1..2])? * 17,
+                channel(&hex[2..3])? * 17,
+            )),
+            _ => Err(err()),
+        }
+    }
 }
 
-#[derive(Clone)]
+/// Writes the color as `#rrggbb`, which it can be parsed from again.
+impl std::fmt::Display for Color {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "#{:02x}{:02x}{:02x}", self.red, self.green, self.blue)
+    }
+}
+
+/// A color with an opacity, from 0 for transparent to 255 for opaque.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ColorA {
+    pub color: Color,
+    pub alpha: u8,
+}
+
+impl ColorA {
+    pub fn opaque(color: Color) -> Self {
+        ColorA { color, alpha: 255 }
+    }
+
+    pub fn is_opaque(self) -> bool {
+        self.alpha == 255
+    }
+}
+
+impl From<Color> for ColorA {
+    fn from(color: Color) -> Self {
+        ColorA::opaque(color)
+    }
+}
+
+/// Parses colors written as `#rrggbbaa` or `#rgba`, or as anything a
+/// `Color` is parsed from, which is opaque.
+impl std::str::FromStr for ColorA {
+    type Err = ColorParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || ColorParseError(s.to_string());
+        let hex = match s.strip_prefix('#') {
+            Some(hex) if hex.is_ascii() && (hex.len() == 8 || hex.len() == 4) => hex,
+            _ => return s.parse().map(ColorA::opaque).map_err(|_| err()),
+        };
+        let (rgb, alpha) = hex.split_at(hex.len() / 4 * 3);
+        let color = format!("#{}", rgb).parse().map_err(|_| err())?;
+        let scale = if alpha.len() == 1 { 17 } else { 1 };
+        let alpha = u8::from_str_radix(alpha, 16).map_err(|_| err())? * scale;
+        Ok(ColorA { color, alpha })
+    }
+}
+
+/// Writes the color as `#rrggbb` if it is opaque, and `#rrggbbaa` if not,
+/// which it can be parsed from again.
+impl std::fmt::Display for ColorA {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.color)?;
+        if !self.is_opaque() {
+            write!(f, "{:02x}", self.alpha)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct ColorParseError(String);
+
+impl std::fmt::Display for ColorParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid color '{}' (expected #rrggbb, #rgb or a color name)", self.0)
+    }
+}
+
+impl std::error::Error for ColorParseError {}
+
+fn srgb_to_linear(c: f32) -> f32 {
+    if c <= 0.04045 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) }
+}
+
+fn linear_to_srgb(c: f32) -> f32 {
+    if c <= 0.0031308 { c * 12.92 } else { 1.055 * c.powf(1. / 2.4) - 0.055 }
+}
+
+fn mat_mul(m: [[f32; 3]; 3], v: [f32; 3]) -> [f32; 3] {
+    m.map(|row| row[0] * v[0] + row[1] * v[1] + row[2] * v[2])
+}
+
+// The matrices of the Oklab transform, between linear sRGB and cone
+// responses and between the cube roots of those and Oklab.
+#[allow(clippy::excessive_precision)]
+pub(crate) const LINEAR_TO_LMS: [[f32; 3]; 3] = [
+    [0.4122214708, 0.5363325363, 0.0514459929],
+    [0.2119034982, 0.6806995451, 0.1073969566],
+    [0.0883024619, 0.2817188376, 0.6299787005],
+];
+#[allow(clippy::excessive_precision)]
+pub(crate) const LMS_TO_OKLAB: [[f32; 3]; 3] = [
+    [0.2104542553, 0.7936177850, -0.0040720468],
+    [1.9779984951, -2.4285922050, 0.4505937099],
+    [0.0259040371, 0.7827717662, -0.8086757660],
+];
+#[allow(clippy::excessive_precision)]
+pub(crate) const OKLAB_TO_LMS: [[f32; 3]; 3] = [
+    [1.0, 0.3963377774, 0.2158037573],
+    [1.0, -0.1055613458, -0.0638541728],
+    [1.0, -0.0894841775, -1.2914855480],
+];
+#[allow(clippy::excessive_precision)]
+pub(crate) const LMS_TO_LINEAR: [[f32; 3]; 3] = [
+    [4.0767416621, -3.3077115913, 0.2309699292],
+    [-1.2684380046, 2.6097574011, -0.3413193965],
+    [-0.0041960863, -0.7034186147, 1.7076147010],
+];
+
+/// A color in the Oklab perceptual color space.
+#[derive(Debug, Clone, Copy)]
+pub struct Oklab {
+    pub l: f32,
+    pub a: f32,
+    pub b: f32,
+}
+
+impl Oklab {
+    pub fn from_linear(rgb: [f32; 3]) -> Self {
+        let lms = mat_mul(LINEAR_TO_LMS, rgb).map(f32::cbrt);
+        let [l, a, b] = mat_mul(LMS_TO_OKLAB, lms);
+        Oklab { l, a, b }
+    }
+
+    pub fn to_linear(self) -> [f32; 3] {
+        let lms = mat_mul(OKLAB_TO_LMS, [self.l, self.a, self.b]).map(|c| c.powi(3));
+        mat_mul(LMS_TO_LINEAR, lms)
+    }
+
+    pub fn chroma(&self) -> f32 {
+        self.a.hypot(self.b)
+    }
+
+    /// Euclidean distance between two colors, which approximates perceived difference.
+    pub fn distance(&self, other: &Oklab) -> f32 {
+        ((self.l - other.l).powi(2) + (self.a - other.a).powi(2) + (self.b - other.b).powi(2)).sqrt()
+    }
+
+    pub fn to_oklch(self) -> Oklch {
+        Oklch { l: self.l, c: self.chroma(), h: self.b.atan2(self.a).to_degrees().rem_euclid(360.0) }
+    }
+}
+
+/// Oklab in polar form: lightness, chroma and hue in degrees.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Oklch {
+    pub l: f32,
+    pub c: f32,
+    pub h: f32,
+}
+
+impl Oklch {
+    pub fn to_oklab(self) -> Oklab {
+        let (sin, cos) = self.h.to_radians().sin_cos();
+        Oklab { l: self.l, a: self.c * cos, b: self.c * sin }
+    }
+}
+
+/// Types of dichromatic color vision deficiency.
+#[derive(Debug, Clone, Copy)]
+pub enum Deficiency {
+    Protanopia,
+    Deuteranopia,
+    Tritanopia,
+}
+
+impl Deficiency {
+    /// Simulation matrices for full severity deficiencies in linear RGB, from
+    /// Machado, Oliveira and Fernandes (2009).
+    fn matrix(self) -> [[f32; 3]; 3] {
+        match self {
+            Deficiency::Protanopia => [
+                [0.152286, 1.052583, -0.204868],
+                [0.114503, 0.786281, 0.099216],
+                [-0.003882, -0.048116, 1.051998],
+            ],
+            Deficiency::Deuteranopia => [
+                [0.367322, 0.860646, -0.227968],
+                [0.280085, 0.672501, 0.047413],
+                [-0.011820, 0.042940, 0.968881],
+            ],
+            Deficiency::Tritanopia => [
+                [1.255528, -0.076749, -0.178779],
+                [-0.078411, 0.930809, 0.147602],
+                [0.004733, 0.691367, 0.303900],
+            ],
+        }
+    }
+}
+
+/// Oklch hue and chroma of the solid inks and two-color overprints of coated
+/// offset print, sorted by hue.
+///
+/// These are the ISO 12647-2 (paper types 1 and 2) characterization values for
+/// red, yellow, green, cyan, blue and magenta, converted from CIELAB under D50.
+/// They sit on the outer edge of the print gamut, so the most saturated color
+/// of any other hue lies roughly between its two neighbours.
+const PRINT_GAMUT_CUSPS: [(f32, f32); 6] = [
+    (26.62, 0.2153),
+    (102.25, 0.1958),
+    (158.92, 0.1891),
+    (222.09, 0.2091),
+    (280.40, 0.1318),
+    (357.35, 0.2316),
+];
+
+/// Chroma a color may exceed the print gamut by and still count as printable,
+/// which absorbs the difference between the inks of one press and another.
+const PRINT_CHROMA_TOLERANCE: f32 = 0.02;
+
+/// The largest Oklch chroma print can reproduce at `hue`, interpolated between
+/// the neighbouring entries of [`PRINT_GAMUT_CUSPS`].
+///
+/// Lightness is ignored: printing maps the lightness range onto the one between
+/// the paper and the darkest ink anyway, so it is chroma that limits the gamut.
+fn print_chroma_limit(hue: f32) -> f32 {
+    let n = PRINT_GAMUT_CUSPS.len();
+    (0 .. n)
+        .map(|i| (PRINT_GAMUT_CUSPS[i], PRINT_GAMUT_CUSPS[(i + 1) % n]))
+        .find_map(|((h0, c0), (h1, c1))| {
+            let span = (h1 - h0).rem_euclid(360.0);
+            let offset = (hue - h0).rem_euclid(360.0);
+            (offset <= span).then(|| c0 + (c1 - c0) * offset / span)
+        })
+        .expect("the cusps go all the way around the hue circle")
+}
+
+/// Whether `color` is within the approximate gamut of coated offset print.
+fn in_print_gamut(color: Color) -> bool {
+    let lch = color.to_oklch();
+    lch.c <= print_chroma_limit(lch.h) + PRINT_CHROMA_TOLERANCE
+}
+
+#[derive(Clone, PartialEq)]
 pub struct Palette {
-    colors: [Color; 256]
+    colors: [Color; 256],
+    keys: Vec<Color>,
+    /// Opacity of each entry, and of each key if the keys are known.
+    alpha: [u8; 256],
+    key_alpha: Vec<u8>,
 }
 
 impl Palette {
     pub fn new(colors: [Color; 256]) -> Palette {
-        Palette { colors }
+        Palette { colors, keys: Vec::new(), alpha: [255; 256], key_alpha: Vec::new() }
+    }
+
+    /// A palette of 256 entries which may be transparent.
+    pub fn new_with_alpha(colors: [ColorA; 256]) -> Palette {
+        Palette { colors: colors.map(|c| c.color), keys: Vec::new(), alpha: colors.map(|c| c.alpha), key_alpha: Vec::new() }
+    }
+
+    /// A gradient between two colors, for tinted monochrome renders.
+    pub fn monochrome(from: Color, to: Color) -> Palette {
+        Palette::from_keys(vec![from, to]).expect("two keys are a valid palette")
+    }
+
+    /// Construct a palette by interpolating linearly between evenly spaced control colors.
+    pub fn from_keys(keys: Vec<Color>) -> Result<Palette, PaletteError> {
+        Palette::from_keys_with_alpha(keys.into_iter().map(ColorA::opaque).collect())
+    }
+
+    /// Construct a palette from evenly spaced control colors which may be
+    /// transparent, interpolating their opacity along with their colors.
+    pub fn from_keys_with_alpha(keys: Vec<ColorA>) -> Result<Palette, PaletteError> {
+        if keys.is_empty() { return Err(PaletteError::Empty); }
+        if keys.len() > 256 { return Err(PaletteError::TooManyColors(keys.len())); }
+        let key_alpha: Vec<u8> = keys.iter().map(|k| k.alpha).collect();
+        if keys.len() == 1 {
+            let keys = vec![keys[0].color];
+            return Ok(Palette { colors: [keys[0]; 256], keys, alpha: [key_alpha[0]; 256], key_alpha });
+        }
+
+        let spacing = 256 / (keys.len() - 1);
+        let leftover = 256 % (keys.len() - 1);
+
+        let mut p_colors = [Color::rgb(0, 0, 0); 256];
+        let mut p_alpha = [255; 256];
+
+        let mut colors = keys.iter().copied();
+        let mut start_color = colors.next().unwrap();
+        let mut offset = 0;
+
+        for (i, end_color) in colors.enumerate() {
+            let span = if i < leftover { spacing + 1 } else { spacing };
+            for j in 0 .. span {
+                let t = if span > 1 { j as f32 / (span - 1) as f32 } else { 0. };
+                let (start, end) = (start_color.color, end_color.color);
+                let c = Color::rgb(
+                    lerp(start.red, end.red, t),
+                    lerp(start.green, end.green, t),
+                    lerp(start.blue, end.blue, t)
+                );
+                p_colors[offset + j] = c;
+                // Rounded, unlike the colors, so that opaque keys give
+                // opaque entries.
+                let (a, b) = (start_color.alpha as f32, end_color.alpha as f32);
+                p_alpha[offset + j] = (a + (b - a) * t).round() as u8;
+            }
+            offset += span;
+            start_color = end_color;
+        }
+
+        Ok(Palette { colors: p_colors, keys: keys.iter().map(|k| k.color).collect(), alpha: p_alpha, key_alpha })
     }
 
     // pub fn sample(&self, i: f32) -> Color {
@@ -29,7 +415,548 @@ impl Palette {
     //     }
     // }
 
-    pub fn sample(&self, i: u8) -> Color {
-        self.colors[i as usize]
+    pub fn sample(&self, i: u8) -> ColorA {
+        ColorA { color: self.colors[i as usize], alpha: self.alpha[i as usize] }
+    }
+
+    /// The color at position `t` along the gradient, between 0 and 1.
+    pub fn sample_at(&self, t: f32) -> ColorA {
+        self.sample((t.clamp(0., 1.) * 255.).round() as u8)
+    }
+
+    /// The control colors of the palette, or every entry if they are not known.
+    pub fn keys(&self) -> &[Color] {
+        if self.keys.is_empty() { &self.colors } else { &self.keys }
+    }
+
+    /// The opacity of each of `keys`.
+    pub fn alphas(&self) -> &[u8] {
+        if self.keys.is_empty() { &self.alpha } else { &self.key_alpha }
+    }
+
+    /// The control colors with their opacities.
+    pub fn keys_with_alpha(&self) -> Vec<ColorA> {
+        self.keys().iter().zip(self.alphas()).map(|(&color, &alpha)| ColorA { color, alpha }).collect()
+    }
+
+    /// Whether every entry of the palette is opaque.
+    pub fn is_opaque(&self) -> bool {
+        self.alpha.iter().all(|&a| a == 255)
+    }
+
+    /// Number of control colors, or of entries if they are not known.
+    pub fn len(&self) -> usize {
+        self.keys().len()
+    }
+
+    /// Always false: every palette has at least one color.
+    pub fn is_empty(&self) -> bool {
+        false
+    }
+
+    /// The control colors with their positions along the gradient, from 0 to 1.
+    ///
+    /// With more than 129 keys some segments are a single entry, and the
+    /// final key may not appear in the table at all; it is still reported at 1.
+    pub fn iter_stops(&self) -> impl Iterator<Item = (f32, ColorA)> + '_ {
+        let keys = self.keys_with_alpha();
+        let segments = keys.len().saturating_sub(1).max(1);
+        let (spacing, leftover) = (256 / segments, 256 % segments);
+        keys.into_iter().enumerate().map(move |(k, c)| {
+            // Segment i covers `spacing + 1` entries if i < leftover. Each key
+            // after the first ends the segment before it, unless that segment
+            // is a single entry, which holds only its starting key.
+            let end = k * spacing + k.min(leftover);
+            let index = if k > 0 && spacing + usize::from(k - 1 < leftover) > 1 { end - 1 } else { end };
+            (index.min(255) as f32 / 255., c)
+        })
+    }
+
+    /// The palette with each of `ops` applied in turn to its control colors.
+    pub fn adjusted(&self, ops: &[PaletteOp]) -> Palette {
+        if ops.is_empty() {
+            return self.clone();
+        }
+        let keys = self.keys_with_alpha().into_iter()
+            .map(|c| ColorA { color: ops.iter().fold(c.color, |c, op| op.apply(c)), ..c })
+            .collect();
+        Palette::from_keys_with_alpha(keys).expect("adjusting keeps the number of keys")
+    }
+
+    pub fn audit(&self) -> PaletteAudit {
+        let min_distance = |cvd: Option<Deficiency>| {
+            let labs: Vec<Oklab> = self.keys().iter()
+                .map(|c| cvd.map_or(*c, |d| c.simulate(d)).to_oklab())
+                .collect();
+            labs.windows(2)
+                .map(|w| w[0].distance(&w[1]))
+                .fold(f32::INFINITY, f32::min)
+        };
+
+        let out_of_gamut = self.colors.iter()
+            .filter(|c| !in_print_gamut(**c))
+            .count();
+
+        PaletteAudit {
+            min_distance: min_distance(None),
+            protanopia: min_distance(Some(Deficiency::Protanopia)),
+            deuteranopia: min_distance(Some(Deficiency::Deuteranopia)),
+            tritanopia: min_distance(Some(Deficiency::Tritanopia)),
+            out_of_gamut: out_of_gamut as f32 / self.colors.len() as f32,
+        }
+    }
+}
+
+/// Linear value which contrast adjustments pivot around, about middle gray.
+const CONTRAST_PIVOT: f32 = 0.18;
+
+/// An adjustment to the colors of a palette, written `name=value`.
+///
+/// Hue and saturation are changed in Oklch, so that lightness is kept;
+/// brightness and contrast in linear RGB, as light would be.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PaletteOp {
+    /// Scale the light of each color.
+    Brightness(f32),
+    /// Scale chroma, reduced again where the result is outside the gamut.
+    Saturation(f32),
+    /// Turn hue by a number of degrees.
+    HueRotate(f32),
+    /// Raise light relative to middle gray to a power, which keeps black
+    /// and middle gray where they are.
+    Contrast(f32),
+    /// Round each channel to one of this many evenly spaced levels.
+    Posterize(u8),
+}
+
+impl PaletteOp {
+    /// Names of the operations, as accepted by `from_str`.
+    pub const NAMES: [&'static str; 5] = ["brightness", "saturation", "hue-rotate", "contrast", "posterize"];
+
+    pub fn apply(self, c: Color) -> Color {
+        match self {
+            PaletteOp::Brightness(k) => Color::from_linear(c.to_linear().map(|x| x * k)),
+            PaletteOp::Saturation(k) => {
+                let lch = c.to_oklch();
+                Color::from_oklch(Oklch { c: lch.c * k, ..lch })
+            }
+            PaletteOp::HueRotate(deg) => {
+                let lch = c.to_oklch();
+                Color::from_oklch(Oklch { h: (lch.h + deg).rem_euclid(360.0), ..lch })
+            }
+            PaletteOp::Contrast(k) => {
+                Color::from_linear(c.to_linear().map(|x| CONTRAST_PIVOT * (x / CONTRAST_PIVOT).powf(k)))
+            }
+            PaletteOp::Posterize(levels) => {
+                let steps = levels.max(2) as f32 - 1.0;
+                let q = |x: u8| ((x as f32 / 255.0 * steps).round() / steps * 255.0).round() as u8;
+                Color::rgb(q(c.red), q(c.green), q(c.blue))
+            }
+        }
+    }
+}
+
+impl std::str::FromStr for PaletteOp {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, value) = s.split_once('=')
+            .ok_or_else(|| format!("palette operation '{}' must be written name=value", s))?;
+        let factor = || match value.trim().parse::<f32>() {
+            Ok(x) if x.is_finite() && x >= 0.0 => Ok(x),
+            _ => Err(format!("invalid {} '{}' (expected a non-negative number)", name, value)),
+        };
+        match name.trim().to_ascii_lowercase().as_str() {
+            "brightness" => Ok(PaletteOp::Brightness(factor()?)),
+            "saturation" => Ok(PaletteOp::Saturation(factor()?)),
+            "contrast" => Ok(PaletteOp::Contrast(factor()?)),
+            "hue-rotate" => match value.trim().parse::<f32>() {
+                Ok(deg) if deg.is_finite() => Ok(PaletteOp::HueRotate(deg)),
+                _ => Err(format!("invalid hue rotation '{}' (expected degrees)", value)),
+            },
+            "posterize" => match value.trim().parse::<u8>() {
+                Ok(n) if n >= 2 => Ok(PaletteOp::Posterize(n)),
+                _ => Err(format!("invalid posterize levels '{}' (expected 2 to 255)", value)),
+            },
+            _ => Err(format!(
+                "unknown palette operation '{}' (expected brightness, saturation, hue-rotate, contrast or posterize)",
+                name
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for PaletteOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PaletteOp::Brightness(k) => write!(f, "brightness={}", k),
+            PaletteOp::Saturation(k) => write!(f, "saturation={}", k),
+            PaletteOp::HueRotate(deg) => write!(f, "hue-rotate={}", deg),
+            PaletteOp::Contrast(k) => write!(f, "contrast={}", k),
+            PaletteOp::Posterize(n) => write!(f, "posterize={}", n),
+        }
+    }
+}
+
+fn lerp(a: u8, b: u8, t: f32) -> u8 {
+    (a as f32 * (1. - t) + b as f32 * t) as u8
+}
+
+#[derive(Debug)]
+pub enum PaletteError {
+    Empty,
+    TooManyColors(usize),
+    EmptyImage,
+    /// A GIMP gradient could not be read.
+    InvalidGradient { line: usize, message: String },
+}
+
+impl std::fmt::Display for PaletteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PaletteError::Empty => write!(f, "palette must contain at least one color"),
+            PaletteError::TooManyColors(n) => write!(f, "too many colors in palette ({}, at most 256 allowed)", n),
+            PaletteError::EmptyImage => write!(f, "cannot extract a palette from an empty image"),
+            PaletteError::InvalidGradient { line, message } => write!(f, "invalid GIMP gradient at line {}: {}", line, message),
+        }
+    }
+}
+
+impl std::error::Error for PaletteError {}
+
+/// Summary of how distinguishable and printable a palette is.
+///
+/// Distances are the smallest Oklab distance between adjacent control colors,
+/// both for normal vision and under each simulated deficiency. They are
+/// infinite for palettes with a single control color.
+#[derive(Debug, Clone, Copy)]
+pub struct PaletteAudit {
+    pub min_distance: f32,
+    pub protanopia: f32,
+    pub deuteranopia: f32,
+    pub tritanopia: f32,
+    /// Fraction of the palette's entries outside the approximate print gamut.
+    pub out_of_gamut: f32,
+}
+
+impl PaletteAudit {
+    /// The smallest adjacent distance under any of the simulated deficiencies.
+    pub fn min_cvd_distance(&self) -> f32 {
+        self.protanopia.min(self.deuteranopia).min(self.tritanopia)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+
+    fn assert_near(found: Color, expected: [u8; 3]) {
+        let found = [found.red, found.green, found.blue];
+        let close = found.iter().zip(expected).all(|(&a, b)| a.abs_diff(b) <= 1);
+        assert!(close, "{:?} is not {:?}", found, expected);
+    }
+
+    #[test]
+    fn deficiencies_match_the_published_simulation() {
+        // The Machado et al. matrices applied to the sRGB primaries, clamped
+        // into the gamut.
+        let cases = [
+            (Deficiency::Protanopia, [[109, 95, 0], [255, 229, 0], [0, 89, 255]]),
+            (Deficiency::Deuteranopia, [[163, 144, 0], [239, 214, 58], [0, 61, 251]]),
+            (Deficiency::Tritanopia, [[255, 0, 15], [0, 247, 217], [0, 107, 150]]),
+        ];
+        let primaries = [Color::rgb(255, 0, 0), Color::rgb(0, 255, 0), Color::rgb(0, 0, 255)];
+        for (cvd, expected) in cases {
+            for (primary, expected) in primaries.iter().zip(expected) {
+                assert_near(primary.simulate(cvd), expected);
+            }
+            // Every row sums to one, so grays are seen as they are.
+            for gray in [0, 128, 255] {
+                assert_near(Color::rgb(gray, gray, gray).simulate(cvd), [gray; 3]);
+            }
+        }
+    }
+
+    #[test]
+    fn red_and_green_collapse_without_red_or_green_cones() {
+        let audit = Palette::from_keys(vec![Color::rgb(200, 40, 30), Color::rgb(60, 150, 40)]).unwrap().audit();
+        assert!(audit.min_distance > 0.2, "{:?}", audit);
+        // Red darkens without red cones, which keeps the two apart by
+        // lightness.
+        assert!(audit.protanopia < audit.min_distance * 0.75, "{:?}", audit);
+        assert!(audit.deuteranopia < audit.min_distance / 4.0, "{:?}", audit);
+        assert!(audit.tritanopia > audit.protanopia && audit.tritanopia > audit.deuteranopia, "{:?}", audit);
+        assert_eq!(audit.min_cvd_distance(), audit.deuteranopia);
+    }
+
+    #[test]
+    fn audit_distances_are_between_adjacent_keys() {
+        let black = Color::rgb(0, 0, 0);
+        let white = Color::rgb(255, 255, 255);
+        let audit = Palette::from_keys(vec![black, white, black]).unwrap().audit();
+        // Black to white is the whole range of Oklab lightness, whatever the
+        // deficiency.
+        for d in [audit.min_distance, audit.protanopia, audit.deuteranopia, audit.tritanopia] {
+            assert!((d - 1.0).abs() < 1e-3, "{:?}", audit);
+        }
+        assert!(Palette::from_keys(vec![white]).unwrap().audit().min_distance.is_infinite());
+    }
+
+    #[test]
+    fn saturated_colors_are_outside_the_print_gamut() {
+        let audit = |keys: Vec<Color>| Palette::from_keys(keys).unwrap().audit().out_of_gamut;
+        assert_eq!(audit(vec![Color::rgb(0, 0, 0), Color::rgb(255, 255, 255)]), 0.0);
+        assert_eq!(audit(vec![Color::rgb(0, 0, 255)]), 1.0);
+        assert_eq!(audit(vec![Color::rgb(190, 170, 150)]), 0.0);
+        // The ISO 12647-2 solids and overprints, as the nearest sRGB colors,
+        // are printable by definition.
+        let solids = [
+            Color::rgb(0, 151, 218),
+            Color::rgb(216, 11, 123),
+            Color::rgb(247, 224, 0),
+            Color::rgb(215, 29, 36),
+            Color::rgb(0, 143, 68),
+            Color::rgb(52, 47, 128),
+        ];
+        for c in solids {
+            assert!(in_print_gamut(c), "{:?}", c);
+        }
+        // Screen yellow is as saturated as yellow ink, but the screen's blues,
+        // greens and purples are far beyond anything ink can reach.
+        assert!(in_print_gamut(Color::rgb(255, 255, 0)));
+        for c in [
+            Color::rgb(0, 0, 255),
+            Color::rgb(0, 0, 128),
+            Color::rgb(0, 255, 0),
+            Color::rgb(255, 0, 255),
+            Color::rgb(128, 0, 255),
+        ] {
+            assert!(!in_print_gamut(c), "{:?}", c);
+        }
+        // A gradient from gray to pure blue leaves the gamut part way along.
+        let part = audit(vec![Color::rgb(128, 128, 128), Color::rgb(0, 0, 255)]);
+        assert!(part > 0.2 && part < 0.8, "{}", part);
+    }
+
+    fn random_palette(rng: &mut impl Rng, keys: usize) -> Palette {
+        let keys = (0 .. keys)
+            .map(|_| ColorA { color: Color::rgb(rng.gen(), rng.gen(), rng.gen()), alpha: rng.gen() })
+            .collect();
+        Palette::from_keys_with_alpha(keys).unwrap()
+    }
+
+    /// Key counts which fill the table evenly, unevenly, or with segments
+    /// of a single entry.
+    const KEY_COUNTS: [usize; 11] = [1, 2, 3, 4, 17, 128, 129, 130, 200, 255, 256];
+
+    #[test]
+    fn stops_sample_to_their_keys() {
+        let mut rng = StdRng::seed_from_u64(9);
+        let random: Vec<usize> = (0 .. 20).map(|_| rng.gen_range(1 ..= 256)).collect();
+        for &n in KEY_COUNTS.iter().chain(&random) {
+            let palette = random_palette(&mut rng, n);
+            let stops: Vec<(f32, ColorA)> = palette.iter_stops().collect();
+            assert_eq!((palette.len(), stops.len()), (n, n));
+            assert_eq!(stops[0].0, 0.0);
+            assert_eq!(stops[n - 1].0, if n == 1 { 0.0 } else { 1.0 });
+            assert!(stops.windows(2).all(|w| w[0].0 < w[1].0 || (n > 129 && w[1].0 == 1.0)), "{} keys", n);
+            for (k, &(position, key)) in stops.iter().enumerate() {
+                // The last of more than 129 keys has no entry of its own.
+                if n > 129 && k == n - 1 {
+                    continue;
+                }
+                assert_eq!(palette.sample_at(position), key, "key {} of {} at {}", k, n, position);
+            }
+        }
+    }
+
+    #[test]
+    fn entries_between_stops_lie_between_their_keys() {
+        let mut rng = StdRng::seed_from_u64(10);
+        for _ in 0 .. 50 {
+            let n = rng.gen_range(2 ..= 129);
+            let palette = random_palette(&mut rng, n);
+            let stops: Vec<(usize, ColorA)> = palette.iter_stops()
+                .map(|(p, c)| ((p * 255.).round() as usize, c))
+                .collect();
+            for i in 0 ..= 255 {
+                // The bracketing stops, found by a linear scan and by a
+                // binary search over the positions.
+                let linear = stops.iter().rposition(|&(p, _)| p <= i).unwrap();
+                let binary = stops.partition_point(|&(p, _)| p <= i) - 1;
+                assert_eq!(linear, binary);
+                let (lo, hi) = (stops[linear].1, stops[(linear + 1).min(n - 1)].1);
+                let entry = palette.sample(i as u8);
+                let channels = |c: ColorA| [c.color.red, c.color.green, c.color.blue, c.alpha];
+                // Colors are truncated, so may fall one below the lower key.
+                for ((e, a), b) in channels(entry).into_iter().zip(channels(lo)).zip(channels(hi)) {
+                    assert!(a.min(b).saturating_sub(1) <= e && e <= a.max(b), "entry {} of {} keys: {} outside {}..{}", i, n, e, a, b);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn repeated_keys_make_hard_stops() {
+        let (a, b) = (Color::rgb(200, 10, 10), Color::rgb(10, 10, 200));
+        let palette = Palette::from_keys(vec![a, a, b, b]).unwrap();
+        let stops: Vec<f32> = palette.iter_stops().map(|(p, _)| p).collect();
+        let near = |c: Color, key: Color| {
+            [(c.red, key.red), (c.green, key.green), (c.blue, key.blue)].iter().all(|&(x, k)| x == k || x + 1 == k)
+        };
+        for i in 0 ..= 255u8 {
+            let t = i as f32 / 255.;
+            let color = palette.sample(i).color;
+            // Truncation may leave a channel one short of the key.
+            if t <= stops[1] {
+                assert!(near(color, a), "entry {} is {:?}", i, color);
+            } else if t >= stops[2] {
+                assert!(near(color, b), "entry {} is {:?}", i, color);
+            }
+        }
+        // At the stop between two equal keys the color is the key exactly.
+        assert_eq!(palette.sample_at(stops[1]).color, a);
+        assert_eq!(palette.sample_at(stops[2]).color, b);
+    }
+
+    #[test]
+    fn positions_at_the_limits_of_precision_sample_the_nearest_entry() {
+        let palette = random_palette(&mut StdRng::seed_from_u64(11), 256);
+        let first = palette.sample(0);
+        let last = palette.sample(255);
+        for t in [-0.0, f32::MIN_POSITIVE, 1e-30, -1.0, f32::NEG_INFINITY, f32::NAN] {
+            assert_eq!(palette.sample_at(t), first, "{}", t);
+        }
+        for t in [1.0, 1.0 - f32::EPSILON / 2., 1.0 + f32::EPSILON, 2.0, f32::INFINITY, f32::MAX] {
+            assert_eq!(palette.sample_at(t), last, "{}", t);
+        }
+        // Halfway between two entries rounds up, just below rounds down.
+        let half = 127.5 / 255.;
+        assert_eq!(palette.sample_at(half), palette.sample(128));
+        assert_eq!(palette.sample_at(half - 4. * f32::EPSILON), palette.sample(127));
+    }
+
+    #[test]
+    fn monochrome_palettes_run_between_their_colors() {
+        let palette = Palette::monochrome(Color::rgb(0, 40, 80), Color::rgb(200, 240, 255));
+        assert_eq!(palette.sample(0).color, Color::rgb(0, 40, 80));
+        assert_eq!(palette.sample(255).color, Color::rgb(200, 240, 255));
+        assert_near(palette.sample(128).color, [100, 140, 168]);
+        assert!((0 ..= 255).all(|i| palette.sample(i).alpha == 255));
+    }
+
+    #[test]
+    fn oklch_matches_reference_values() {
+        // Published Oklab values of the sRGB primaries, in polar form.
+        let cases = [
+            (Color::rgb(255, 0, 0), [0.62796, 0.25768, 29.234]),
+            (Color::rgb(0, 255, 0), [0.86644, 0.29483, 142.495]),
+            (Color::rgb(0, 0, 255), [0.45201, 0.31321, 264.052]),
+        ];
+        for (color, [l, c, h]) in cases {
+            let lch = color.to_oklch();
+            assert!((lch.l - l).abs() < 1e-3 && (lch.c - c).abs() < 1e-3, "{:?} is {:?}", color, lch);
+            assert!((lch.h - h).abs() < 0.1, "{:?} is {:?}", color, lch);
+            assert_near(Color::from_oklch(lch), [color.red, color.green, color.blue]);
+        }
+        let white = Color::rgb(255, 255, 255).to_oklch();
+        assert!((white.l - 1.0).abs() < 1e-3 && white.c < 1e-3);
+    }
+
+    #[test]
+    fn colors_outside_the_gamut_keep_their_lightness_and_hue() {
+        let lch = Oklch { l: 0.7, c: 0.4, h: 200.0 };
+        let found = Color::from_oklch(lch).to_oklch();
+        assert!(found.c < lch.c);
+        assert!((found.l - lch.l).abs() < 0.01, "{:?}", found);
+        assert!((found.h - lch.h).abs() < 2.0, "{:?}", found);
+    }
+
+    #[test]
+    fn brightness_scales_light() {
+        assert_near(PaletteOp::Brightness(0.5).apply(Color::rgb(255, 255, 255)), [188, 188, 188]);
+        assert_near(PaletteOp::Brightness(2.0).apply(Color::rgb(255, 128, 0)), [255, 177, 0]);
+        assert_eq!(PaletteOp::Brightness(0.0).apply(Color::rgb(90, 20, 200)), Color::rgb(0, 0, 0));
+    }
+
+    #[test]
+    fn contrast_pivots_around_middle_gray() {
+        let op = PaletteOp::Contrast(2.0);
+        assert_eq!(op.apply(Color::rgb(0, 0, 0)), Color::rgb(0, 0, 0));
+        assert_near(op.apply(Color::rgb(118, 118, 118)), [118, 118, 118]);
+        assert_near(op.apply(Color::rgb(60, 60, 60)), [28, 28, 28]);
+        assert_near(op.apply(Color::rgb(160, 160, 160)), [216, 216, 216]);
+    }
+
+    #[test]
+    fn saturation_scales_chroma_at_the_same_lightness() {
+        let orange = Color::rgb(230, 120, 30);
+        let gray = PaletteOp::Saturation(0.0).apply(orange);
+        assert!(gray.red.abs_diff(gray.green) <= 1 && gray.green.abs_diff(gray.blue) <= 1, "{:?}", gray);
+        assert!((gray.to_oklch().l - orange.to_oklch().l).abs() < 0.01);
+        let duller = PaletteOp::Saturation(0.5).apply(orange).to_oklch();
+        assert!((duller.c - orange.to_oklch().c / 2.0).abs() < 0.005, "{:?}", duller);
+        assert!((duller.h - orange.to_oklch().h).abs() < 1.0);
+    }
+
+    #[test]
+    fn hue_rotation_turns_the_hue_alone() {
+        let teal = Color::rgb(40, 140, 150);
+        let lch = teal.to_oklch();
+        let turned = PaletteOp::HueRotate(-90.0).apply(teal).to_oklch();
+        assert!(((turned.h - lch.h).rem_euclid(360.0) - 270.0).abs() < 1.0, "{:?}", turned);
+        assert!((turned.l - lch.l).abs() < 0.01 && (turned.c - lch.c).abs() < 0.01, "{:?}", turned);
+        assert_near(PaletteOp::HueRotate(360.0).apply(teal), [40, 140, 150]);
+    }
+
+    #[test]
+    fn posterize_rounds_to_even_levels() {
+        assert_eq!(PaletteOp::Posterize(2).apply(Color::rgb(100, 128, 200)), Color::rgb(0, 255, 255));
+        assert_eq!(PaletteOp::Posterize(3).apply(Color::rgb(40, 100, 200)), Color::rgb(0, 128, 255));
+        assert_eq!(PaletteOp::Posterize(255).apply(Color::rgb(40, 100, 200)), Color::rgb(40, 100, 200));
+    }
+
+    #[test]
+    fn no_adjustments_leave_the_palette_as_it_was() {
+        let palette = random_palette(&mut StdRng::seed_from_u64(4), 6);
+        assert!(palette.adjusted(&[]) == palette);
+        // Neutral operations only round.
+        let neutral = [PaletteOp::Brightness(1.0), PaletteOp::Saturation(1.0), PaletteOp::HueRotate(0.0), PaletteOp::Contrast(1.0)];
+        let adjusted = palette.adjusted(&neutral);
+        for (&found, key) in adjusted.keys().iter().zip(palette.keys()) {
+            assert_near(found, [key.red, key.green, key.blue]);
+        }
+    }
+
+    #[test]
+    fn operations_parse_as_they_are_written() {
+        for op in ["brightness=1.2", "saturation=0.8", "hue-rotate=-20", "contrast=1.5", "posterize=4"] {
+            assert_eq!(op.parse::<PaletteOp>().unwrap().to_string(), op);
+        }
+        assert_eq!(" Saturation = 0.8".parse(), Ok(PaletteOp::Saturation(0.8)));
+        for bad in ["brightness", "brightness=-1", "contrast=inf", "posterize=1", "posterize=300", "hue-rotate=x", "blur=2"] {
+            assert!(bad.parse::<PaletteOp>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn colors_with_alpha_parse_as_they_are_written() {
+        let parse = |s: &str| s.parse::<ColorA>().unwrap();
+        assert_eq!(parse("#ff800080"), ColorA { color: Color::rgb(255, 128, 0), alpha: 128 });
+        assert_eq!(parse("#FF800000"), ColorA { color: Color::rgb(255, 128, 0), alpha: 0 });
+        assert_eq!(parse("#f808"), ColorA { color: Color::rgb(255, 136, 0), alpha: 136 });
+        // Colors without an alpha, or with a full one, are opaque.
+        for opaque in ["#ff8000ff", "#ff8000", "#ff8000FF"] {
+            assert_eq!(parse(opaque), ColorA::opaque(Color::rgb(255, 128, 0)));
+        }
+        assert_eq!(parse("white"), ColorA::opaque(Color::rgb(255, 255, 255)));
+        for bad in ["#ff80008", "#ff8000800", "#ff8000g0", "ff800080", "#ff8000\u{e9}", "#12345"] {
+            assert!(bad.parse::<ColorA>().is_err(), "{:?} was accepted", bad);
+        }
+        for written in ["#ff800080", "#00000000", "#ff8000"] {
+            assert_eq!(parse(written).to_string(), written);
+        }
     }
 }
@@ -0,0 +1,326 @@
+//! Hashes of what a flame draws, which stay the same however it is written.
+//!
+//! A flame is hashed through a canonical binary encoding of its structure,
+//! so that key order, number formatting and metadata in a descriptor make
+//! no difference. Version 1 of the encoding is, all numbers little-endian:
+//!
+//! - `FLAMEHASH` and the version, one byte
+//! - the number of functions as a u32, and each function's weight as an
+//!   f32, color as a u8, variation, affine and axis blend
+//! - the bounds, `x_min, x_max, y_min, y_max` as f32
+//! - the 256 palette entries, each red, green, blue and alpha as u8
+//! - the color model, one byte: 0 native, 1 flam3
+//! - the mask
+//!
+//! A variation is its name as in a descriptor, as a u32 length and UTF-8
+//! bytes, then its number of parameters as a u32 and each as an f32. An
+//! affine is the six f32 coefficients `a, b, c, d, e, f` of the matrix
+//! `[a b e; c d f]`, and an axis blend its two f32. The mask is one byte,
+//! 0 for none, 1 for an image followed by its width and height as u32 and
+//! its pixels row by row as u8, 2 for a circle followed by its center and
+//! radius, 3 for a rectangle followed by its two corners, and 4 for a
+//! polygon followed by its number of vertices as a u32 and each vertex, all
+//! as f32.
+//!
+//! Every f32 is written as its bits, with `-0.0` written as `0.0`. NaN and
+//! infinities have no encoding, and flames holding them have no hash. The
+//! palette is hashed as its entries rather than its control colors, since
+//! that is all a render sees of it.
+//!
+//! Hashes only change between releases when `CONTENT_HASH_VERSION` does.
+
+use super::*;
+
+/// Version of the encoding hashed, which is part of it.
+pub const CONTENT_HASH_VERSION: u8 = 1;
+
+const TAG: &[u8] = b"FLAMEHASH";
+
+/// A flame holds a number which cannot be hashed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ContentHashError {
+    /// Where the number is in the flame, as in a descriptor written from it.
+    pub path: String,
+    pub value: f32,
+}
+
+impl std::fmt::Display for ContentHashError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "'{}' is {}, so the flame cannot be hashed", self.path, self.value)
+    }
+}
+
+impl std::error::Error for ContentHashError {}
+
+impl Flame {
+    /// The canonical encoding of the flame described in the module
+    /// documentation, which two flames share exactly when they draw the
+    /// same thing in the same way.
+    pub fn canonical_encoding(&self) -> Result<Vec<u8>, ContentHashError> {
+        let mut e = Encoder { bytes: TAG.to_vec() };
+        e.bytes.push(CONTENT_HASH_VERSION);
+
+        e.len(self.functions.len());
+        for (i, f) in self.functions.iter().enumerate() {
+            e.f32(f.weight, || format!("functions[{}][0]", i))?;
+            e.bytes.push(f.color);
+            let name = format!("{:?}", f.var.discriminant());
+            e.len(name.len());
+            e.bytes.extend_from_slice(name.as_bytes());
+            let params = f.var.params();
+            e.len(params.len());
+            for (j, &p) in params.iter().enumerate() {
+                e.f32(p, || format!("functions[{}][1].{}[{}]", i, name, j))?;
+            }
+            let m = f.trans.matrix();
+            let coefficients = [m[(0, 0)], m[(0, 1)], m[(1, 0)], m[(1, 1)], m[(0, 2)], m[(1, 2)]];
+            for (j, &x) in coefficients.iter().enumerate() {
+                e.f32(x, || format!("functions[{}][2][{}]", i, j))?;
+            }
+            for (j, &x) in f.axis_blend.iter().enumerate() {
+                e.f32(x, || format!("functions[{}][4][{}]", i, j))?;
+            }
+        }
+
+        for (j, &x) in self.bounds.to_array().iter().enumerate() {
+            e.f32(x, || format!("bounds[{}]", j))?;
+        }
+
+        for i in 0 ..= 255 {
+            let ColorA { color, alpha } = self.palette.sample(i);
+            e.bytes.extend_from_slice(&[color.red, color.green, color.blue, alpha]);
+        }
+
+        e.bytes.push(match self.color_model {
+            ColorModel::Native => 0,
+            ColorModel::Flam3 => 1,
+        });
+
+        match &self.mask {
+            None => e.bytes.push(0),
+            Some(MaskShape::Image(img)) => {
+                e.bytes.push(1);
+                e.bytes.extend_from_slice(&img.width().to_le_bytes());
+                e.bytes.extend_from_slice(&img.height().to_le_bytes());
+                e.bytes.extend_from_slice(img.as_raw());
+            }
+            Some(MaskShape::Circle { center, radius }) => {
+                e.bytes.push(2);
+                e.points(&[*center], "mask.circle.center")?;
+                e.f32(*radius, || "mask.circle.radius".to_string())?;
+            }
+            Some(MaskShape::Rect { min, max }) => {
+                e.bytes.push(3);
+                e.points(&[*min, *max], "mask.rect")?;
+            }
+            Some(MaskShape::Polygon(vertices)) => {
+                e.bytes.push(4);
+                e.len(vertices.len());
+                e.points(vertices, "mask.polygon")?;
+            }
+        }
+        Ok(e.bytes)
+    }
+
+    /// A 64-bit hash of the flame's structure, for caches and for telling
+    /// whether two files hold the same flame: the first eight bytes of
+    /// `content_hash_256`, read little-endian.
+    ///
+    /// Metadata, names and thumbnails play no part, nor does how the flame
+    /// was written, so a flame read back from a descriptor written from it
+    /// hashes the same. Any change to what it draws changes the hash.
+    pub fn content_hash(&self) -> Result<u64, ContentHashError> {
+        let hash = self.content_hash_256()?;
+        Ok(u64::from_le_bytes(hash[.. 8].try_into().expect("a SHA-256 hash is longer than 8 bytes")))
+    }
+
+    /// The SHA-256 hash of the flame's canonical encoding, for file headers
+    /// which must not mistake one flame for another.
+    pub fn content_hash_256(&self) -> Result<[u8; 32], ContentHashError> {
+        Ok(sha256(&self.canonical_encoding()?))
+    }
+}
+
+struct Encoder {
+    bytes: Vec<u8>,
+}
+
+impl Encoder {
+    fn len(&mut self, n: usize) {
+        let n = u32::try_from(n).expect("flames hold fewer than 2^32 of anything");
+        self.bytes.extend_from_slice(&n.to_le_bytes());
+    }
+
+    fn f32(&mut self, x: f32, path: impl FnOnce() -> String) -> Result<(), ContentHashError> {
+        if !x.is_finite() {
+            return Err(ContentHashError { path: path(), value: x });
+        }
+        // Adding zero turns -0.0 into 0.0 and leaves everything else alone.
+        self.bytes.extend_from_slice(&(x + 0.0).to_bits().to_le_bytes());
+        Ok(())
+    }
+
+    fn points(&mut self, points: &[Point2<f32>], path: &str) -> Result<(), ContentHashError> {
+        for (i, p) in points.iter().enumerate() {
+            for j in 0 .. 2 {
+                self.f32(p[j], || format!("{}[{}][{}]", path, i, j))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Round constants of SHA-256.
+const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// The SHA-256 hash of `data`.
+pub(crate) fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut h: [u32; 8] = [
+        0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+    ];
+    // The message is padded with a one bit, zeros, and its length in bits
+    // to a multiple of 64 bytes.
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+
+    for block in message.chunks_exact(64) {
+        let mut w = [0u32; 64];
+        for (i, word) in block.chunks_exact(4).enumerate() {
+            w[i] = u32::from_be_bytes(word.try_into().unwrap());
+        }
+        for i in 16 .. 64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7]).wrapping_add(s1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut hh] = h;
+        for i in 0 .. 64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = hh.wrapping_add(s1).wrapping_add(ch).wrapping_add(K[i]).wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            (hh, g, f, e, d, c, b, a) = (g, f, e, d.wrapping_add(t1), c, b, a, t1.wrapping_add(t2));
+        }
+        for (x, y) in h.iter_mut().zip([a, b, c, d, e, f, g, hh]) {
+            *x = x.wrapping_add(y);
+        }
+    }
+
+    let mut out = [0; 32];
+    for (chunk, x) in out.chunks_exact_mut(4).zip(h) {
+        chunk.copy_from_slice(&x.to_be_bytes());
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file::FlameSource;
+    use crate::meta::{GenerationMethod, Meta};
+    use crate::presets;
+
+    fn hash(flame: &Flame) -> u64 {
+        flame.content_hash().unwrap()
+    }
+
+    #[test]
+    fn sha256_matches_the_standard_vectors() {
+        let hex = |h: [u8; 32]| h.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+        assert_eq!(hex(sha256(b"")), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+        assert_eq!(hex(sha256(b"abc")), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        // Two blocks of padding.
+        let long = b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq";
+        assert_eq!(hex(sha256(long)), "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1");
+    }
+
+    #[test]
+    fn the_short_hash_starts_the_long_one() {
+        let flame = presets::swirl();
+        let long = flame.content_hash_256().unwrap();
+        assert_eq!(hash(&flame).to_le_bytes(), long[.. 8]);
+    }
+
+    #[test]
+    fn descriptors_hash_as_their_flame_however_written() {
+        let flame = presets::swirl();
+        let source = FlameSource::from_flame(&flame).with_meta(Meta::new(GenerationMethod::Manual));
+        let mut written = Vec::new();
+        source.to_writer(&mut written).unwrap();
+
+        // The same descriptor with its keys in another order, its numbers
+        // written differently and other metadata.
+        let mut value: serde_json::Value = serde_json::from_slice(&written).unwrap();
+        value["meta"] = serde_json::json!({ "name": "renamed", "version": "0.0.1", "method": "breed", "created": 1 });
+        let text = value.to_string().replace("0.5,", "0.50,").replace("0.0,", "-0.0,");
+        let fields: serde_json::Map<String, serde_json::Value> = serde_json::from_str(&text).unwrap();
+        let reordered: serde_json::Map<String, serde_json::Value> = fields.into_iter().rev().collect();
+        let rewritten = serde_json::Value::Object(reordered);
+        assert_ne!(rewritten.to_string().into_bytes(), written);
+
+        for bytes in [written, rewritten.to_string().into_bytes()] {
+            let read = FlameSource::from_value(serde_json::from_slice(&bytes).unwrap(), ".").unwrap();
+            assert_eq!(hash(&read.to_flame().unwrap()), hash(&flame));
+        }
+    }
+
+    #[test]
+    fn every_structural_change_changes_the_hash() {
+        let base = presets::gasket();
+        let mut changes: Vec<Flame> = Vec::new();
+        let mut change = |f: &dyn Fn(&mut Flame)| {
+            let mut flame = base.clone();
+            f(&mut flame);
+            changes.push(flame);
+        };
+        change(&|f| f.functions[0].weight += 1e-6);
+        change(&|f| f.functions[1].color ^= 1);
+        change(&|f| f.functions[2].var = Variation::Swirl);
+        change(&|f| f.functions[2].var = Variation::Blob(0.1, 0.2, 0.3));
+        change(&|f| f.functions[2].var = Variation::Blob(0.1, 0.2, 0.30001));
+        change(&|f| f.functions[0].trans = Affine2::from_matrix_unchecked(f.functions[0].trans.matrix() * 1.0001));
+        change(&|f| f.functions[0].axis_blend = [1.0, 0.5]);
+        change(&|f| f.functions.swap(0, 1));
+        change(&|f| {
+            f.functions.pop();
+        });
+        change(&|f| f.bounds = Bounds::new(-0.05, 1.05, -0.1, 1.01));
+        change(&|f| f.palette = presets::fern().palette);
+        change(&|f| f.color_model = ColorModel::Flam3);
+        change(&|f| f.mask = Some(MaskShape::Circle { center: Point2::new(0.5, 0.5), radius: 0.5 }));
+        change(&|f| f.mask = Some(MaskShape::Circle { center: Point2::new(0.5, 0.5), radius: 0.25 }));
+
+        let mut seen = std::collections::HashSet::from([hash(&base)]);
+        for (i, flame) in changes.iter().enumerate() {
+            assert!(seen.insert(hash(flame)), "change {} did not change the hash", i);
+        }
+    }
+
+    #[test]
+    fn negative_zero_is_zero_and_nan_has_no_hash() {
+        let mut flame = presets::gasket();
+        let before = hash(&flame);
+        flame.functions[0].trans = Affine2::from_matrix_unchecked(flame.functions[0].trans.matrix().map(|v| if v == 0.0 { -0.0 } else { v }));
+        assert_eq!(hash(&flame), before);
+
+        flame.functions[1].weight = f32::NAN;
+        let e = flame.content_hash().unwrap_err();
+        assert_eq!(e.path, "functions[1][0]");
+        assert!(e.value.is_nan());
+    }
+}
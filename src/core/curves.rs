@@ -0,0 +1,231 @@
+use super::*;
+use super::math::{Math, PlatformMath};
+
+/// Number of entries in the lookup table a curve is evaluated through.
+const LUT_SIZE: usize = 4096;
+
+/// A lift, gamma and gain adjustment of one channel, mapping `x` in [0, 1]
+/// to `(gain * (x + lift * (1 - x)))^(1 / gamma)`, clamped to [0, 1].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Curve {
+    /// Raises the blacks, leaving white where it is.
+    pub lift: f64,
+    /// Brightens the midtones when above one.
+    pub gamma: f64,
+    /// Scales the whole range.
+    pub gain: f64,
+}
+
+impl Curve {
+    pub const IDENTITY: Curve = Curve { lift: 0.0, gamma: 1.0, gain: 1.0 };
+
+    pub fn eval(&self, x: f64) -> f64 {
+        self.eval_with::<PlatformMath>(x)
+    }
+
+    fn eval_with<M: Math>(&self, x: f64) -> f64 {
+        let y = (self.gain * (x + self.lift * (1.0 - x))).clamp(0.0, 1.0);
+        M::powf(y, self.gamma.recip())
+    }
+
+    /// Apply a setting written `key=value`.
+    fn set(&mut self, setting: &str) -> Result<(), String> {
+        let (key, value) = setting.split_once('=')
+            .ok_or_else(|| format!("expected KEY=VALUE, got '{}'", setting))?;
+        let value: f64 = value.trim().parse()
+            .map_err(|_| format!("invalid number '{}' in '{}'", value.trim(), setting))?;
+        if !value.is_finite() {
+            return Err(format!("'{}' is not finite", setting));
+        }
+        match key.trim().to_ascii_lowercase().as_str() {
+            "lift" => self.lift = value,
+            "gamma" if value > 0.0 => self.gamma = value,
+            "gamma" => return Err(format!("gamma must be positive in '{}'", setting)),
+            "gain" => self.gain = value,
+            k => return Err(format!("unknown curve setting '{}' (expected lift, gamma or gain)", k)),
+        }
+        Ok(())
+    }
+}
+
+impl Default for Curve {
+    fn default() -> Self {
+        Curve::IDENTITY
+    }
+}
+
+impl std::fmt::Display for Curve {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "lift={},gamma={},gain={}", self.lift, self.gamma, self.gain)
+    }
+}
+
+/// A channel which a curve can be given for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CurveChannel {
+    /// Every channel, before its own curve.
+    Master,
+    Red,
+    Green,
+    Blue,
+}
+
+/// Tone curves applied to the tonemapped image just before it is quantized.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct ChannelCurves {
+    pub master: Curve,
+    pub red: Curve,
+    pub green: Curve,
+    pub blue: Curve,
+}
+
+impl ChannelCurves {
+    pub fn get_mut(&mut self, channel: CurveChannel) -> &mut Curve {
+        match channel {
+            CurveChannel::Master => &mut self.master,
+            CurveChannel::Red => &mut self.red,
+            CurveChannel::Green => &mut self.green,
+            CurveChannel::Blue => &mut self.blue,
+        }
+    }
+
+    pub fn is_identity(&self) -> bool {
+        [self.master, self.red, self.green, self.blue].iter().all(|c| *c == Curve::IDENTITY)
+    }
+
+    /// Apply a curve written `CHANNEL:KEY=VALUE[,KEY=VALUE...]`, such as
+    /// `r:lift=0.02,gain=1.1`. Channels are `master`, `red`, `green` and
+    /// `blue`, or their initials.
+    pub fn set(&mut self, spec: &str) -> Result<(), String> {
+        let (channel, settings) = spec.split_once(':')
+            .ok_or_else(|| format!("invalid curve '{}' (expected CHANNEL:KEY=VALUE,...)", spec))?;
+        let channel = match channel.trim().to_ascii_lowercase().as_str() {
+            "m" | "master" => CurveChannel::Master,
+            "r" | "red" => CurveChannel::Red,
+            "g" | "green" => CurveChannel::Green,
+            "b" | "blue" => CurveChannel::Blue,
+            c => return Err(format!("unknown curve channel '{}' (expected master, red, green or blue)", c)),
+        };
+        let mut curve = *self.get_mut(channel);
+        for setting in settings.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            curve.set(setting).map_err(|e| format!("invalid curve '{}': {}", spec, e))?;
+        }
+        *self.get_mut(channel) = curve;
+        Ok(())
+    }
+
+    /// Lookup tables for the alpha, red, green and blue channels, with the
+    /// master curve applied first. Alpha, which grayscale images are made
+    /// from, only has the master curve.
+    fn luts<M: Math>(&self) -> [Vec<f64>; 4] {
+        let lut = |c: Option<Curve>| -> Vec<f64> {
+            (0 .. LUT_SIZE).map(|i| {
+                let x = self.master.eval_with::<M>(i as f64 / (LUT_SIZE - 1) as f64);
+                c.map_or(x, |c| c.eval_with::<M>(x))
+            }).collect()
+        };
+        [lut(None), lut(Some(self.red)), lut(Some(self.green)), lut(Some(self.blue))]
+    }
+}
+
+/// Evaluate a lookup table over [0, 1] with linear interpolation.
+fn lookup(lut: &[f64], x: f64) -> f64 {
+    let pos = x.clamp(0.0, 1.0) * (lut.len() - 1) as f64;
+    let i = (pos as usize).min(lut.len() - 2);
+    let t = pos - i as f64;
+    lut[i] + (lut[i + 1] - lut[i]) * t
+}
+
+impl Buffer<f64> {
+    /// Apply tone curves to every channel, which should be in [0, 1]. The
+    /// curves are sampled into lookup tables, so this is cheap however
+    /// large the buffer.
+    pub fn curves(&mut self, curves: &ChannelCurves) {
+        self.curves_with::<PlatformMath>(curves)
+    }
+
+    pub(crate) fn curves_with<M: Math>(&mut self, curves: &ChannelCurves) {
+        // Identity curves leave the buffer exactly as it was, rather than
+        // off by the error of the lookup tables.
+        if curves.is_identity() {
+            return;
+        }
+        let luts = curves.luts::<M>();
+        for pixel in self.as_flat_slice_mut().chunks_exact_mut(4) {
+            for (c, lut) in pixel.iter_mut().zip(&luts) {
+                *c = lookup(lut, *c);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parsed(specs: &[&str]) -> Result<ChannelCurves, String> {
+        let mut curves = ChannelCurves::default();
+        for spec in specs {
+            curves.set(spec)?;
+        }
+        Ok(curves)
+    }
+
+    #[test]
+    fn curves_parse_by_channel() {
+        let curves = parsed(&["r:lift=0.02,gain=1.1", "master:gamma=0.95", " B : Gain = 0.5 , ", "red:gamma=2"]).unwrap();
+        assert_eq!(curves.red, Curve { lift: 0.02, gamma: 2.0, gain: 1.1 });
+        assert_eq!(curves.master, Curve { gamma: 0.95, ..Curve::IDENTITY });
+        assert_eq!(curves.blue, Curve { gain: 0.5, ..Curve::IDENTITY });
+        assert_eq!(curves.green, Curve::IDENTITY);
+        assert!(parsed(&["g:"]).unwrap().is_identity());
+    }
+
+    #[test]
+    fn curve_errors_name_the_bad_token() {
+        let cases = [
+            ("rgain=1", "'rgain=1'"),
+            ("x:gain=1", "'x'"),
+            ("r:gain", "'gain'"),
+            ("r:gain=abc", "'abc'"),
+            ("r:bias=1", "'bias'"),
+            ("r:gamma=0", "gamma must be positive"),
+            ("r:lift=inf", "'lift=inf' is not finite"),
+        ];
+        for (spec, token) in cases {
+            let e = parsed(&[spec]).unwrap_err();
+            assert!(e.contains(token), "{}: {}", spec, e);
+        }
+        // A bad setting leaves the channel as it was.
+        let mut curves = parsed(&["r:gain=2"]).unwrap();
+        assert!(curves.set("r:lift=0.5,gamma=-1").is_err());
+        assert_eq!(curves.red, Curve { gain: 2.0, ..Curve::IDENTITY });
+    }
+
+    #[test]
+    fn lookup_tables_follow_the_curves() {
+        let curves = parsed(&["m:gamma=1.4", "r:lift=0.1,gain=1.2", "g:gamma=0.6", "b:gain=0.5"]).unwrap();
+        let values: Vec<f64> = (0 ..= 100).map(|i| i as f64 / 100.0).collect();
+        let buckets = values.iter().map(|&v| Bucket { alpha: v, red: v, green: v, blue: v }).collect();
+        let mut buffer = Buffer::from_buckets(values.len(), 1, buckets).unwrap();
+        buffer.curves(&curves);
+
+        for (&v, b) in values.iter().zip(buffer.buckets()) {
+            let m = curves.master.eval(v);
+            let expected = [m, curves.red.eval(m), curves.green.eval(m), curves.blue.eval(m)];
+            for (found, expected) in [b.alpha, b.red, b.green, b.blue].into_iter().zip(expected) {
+                assert!((found - expected).abs() < 1e-3, "{} gave {} for {}", v, found, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn identity_curves_change_nothing() {
+        let values = [0.0, 1e-9, 0.123_456_789, 0.5, 0.999_999, 1.0];
+        let buckets = values.iter().map(|&v| Bucket { alpha: v, red: v / 2.0, green: v, blue: 1.0 - v }).collect();
+        let original = Buffer::from_buckets(values.len(), 1, buckets).unwrap();
+        let mut curved = original.clone();
+        curved.curves(&ChannelCurves::default());
+        assert_eq!(curved.as_flat_slice(), original.as_flat_slice());
+    }
+}
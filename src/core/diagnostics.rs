@@ -0,0 +1,138 @@
+use super::*;
+
+/// A value which was adjusted, or supplied in place of one which was left
+/// out, on its way from the user to the render.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// Where the value came from, as a descriptor path such as
+    /// `functions[0][3]` or an option such as `--mutation-rate`.
+    pub path: String,
+    pub message: String,
+    /// The value as given, if there was one.
+    pub value_before: Option<String>,
+    /// The value used instead.
+    pub value_after: Option<String>,
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: '{}' {}", self.severity, self.path, self.message)?;
+        match (&self.value_before, &self.value_after) {
+            (Some(before), Some(after)) => write!(f, " ({} became {})", before, after),
+            (None, Some(after)) => write!(f, " (used {})", after),
+            (Some(before), None) => write!(f, " (was {})", before),
+            (None, None) => Ok(()),
+        }
+    }
+}
+
+/// Diagnostics collected while a flame and its configuration are read and
+/// set up. Nothing is allocated until the first is added.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Diagnostics {
+    entries: Vec<Diagnostic>,
+}
+
+impl Diagnostics {
+    pub fn new() -> Self {
+        Diagnostics::default()
+    }
+
+    pub fn push(&mut self, diagnostic: Diagnostic) {
+        self.entries.push(diagnostic);
+    }
+
+    /// Record a warning that the value at `path` was changed from `before`
+    /// to `after`.
+    pub fn adjusted(&mut self, path: impl Into<String>, message: impl Into<String>, before: impl ToString, after: impl ToString) {
+        self.push(Diagnostic {
+            severity: Severity::Warning,
+            path: path.into(),
+            message: message.into(),
+            value_before: Some(before.to_string()),
+            value_after: Some(after.to_string()),
+        });
+    }
+
+    /// `value` brought into `[min, max]`, recording a warning that the
+    /// value at `path` was clamped if that changed it.
+    pub fn clamped<T: PartialOrd + Copy + std::fmt::Display>(&mut self, path: &str, value: T, min: T, max: T) -> T {
+        let clamped = if value < min { min } else if value > max { max } else { value };
+        if clamped != value {
+            self.adjusted(path, format!("is outside [{}, {}] and was clamped", min, max), value, clamped);
+        }
+        clamped
+    }
+
+    pub fn extend(&mut self, other: Diagnostics) {
+        self.entries.extend(other.entries);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, Diagnostic> {
+        self.entries.iter()
+    }
+
+    /// The most severe diagnostic, if there are any.
+    pub fn worst(&self) -> Option<Severity> {
+        self.entries.iter().map(|d| d.severity).max()
+    }
+}
+
+impl<'a> IntoIterator for &'a Diagnostics {
+    type Item = &'a Diagnostic;
+    type IntoIter = std::slice::Iter<'a, Diagnostic>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.entries.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn options_outside_their_range_are_clamped_once() {
+        let mut diagnostics = Diagnostics::new();
+        assert_eq!(diagnostics.clamped("--palette-samples", 1000usize, 2, 256), 256);
+        assert_eq!(diagnostics.clamped("--lerp-probability", -0.5, 0.0, 1.0), 0.0);
+        assert_eq!(diagnostics.clamped("--mutation-rate", 0.25, 0.0, 1.0), 0.25);
+        assert_eq!(diagnostics.clamped("--palette-samples", 2usize, 2, 256), 2);
+
+        let entries: Vec<_> = diagnostics.iter().cloned().collect();
+        assert_eq!(entries, [
+            Diagnostic {
+                severity: Severity::Warning,
+                path: "--palette-samples".to_string(),
+                message: "is outside [2, 256] and was clamped".to_string(),
+                value_before: Some("1000".to_string()),
+                value_after: Some("256".to_string()),
+            },
+            Diagnostic {
+                severity: Severity::Warning,
+                path: "--lerp-probability".to_string(),
+                message: "is outside [0, 1] and was clamped".to_string(),
+                value_before: Some("-0.5".to_string()),
+                value_after: Some("0".to_string()),
+            },
+        ]);
+        assert_eq!(entries[0].to_string(), "warning: '--palette-samples' is outside [2, 256] and was clamped (1000 became 256)");
+        assert_eq!(diagnostics.worst(), Some(Severity::Warning));
+    }
+
+    #[test]
+    fn an_empty_collector_allocates_nothing() {
+        let diagnostics = Diagnostics::new();
+        assert_eq!(diagnostics.entries.capacity(), 0);
+        assert!(diagnostics.is_empty() && diagnostics.worst().is_none());
+    }
+}
@@ -0,0 +1,124 @@
+/// Thresholds added to channels before they are truncated to integers, to
+/// break up the contours quantization leaves in smooth gradients.
+///
+/// The threshold depends only on the pixel's position, so dithered renders
+/// are as reproducible as undithered ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DitherMode {
+    /// Truncate without dithering.
+    #[default]
+    None,
+    /// An 8x8 Bayer matrix, which is regular and can show as a fine
+    /// crosshatch.
+    Ordered8x8,
+    /// A tile of blue noise, which has no visible structure.
+    BlueNoise,
+}
+
+impl DitherMode {
+    /// Names of the modes, as accepted by `from_str`.
+    pub const NAMES: [&'static str; 3] = ["none", "ordered", "blue-noise"];
+
+    /// Threshold for the pixel at `(x, y)`, in [0, 1).
+    pub fn threshold(self, x: usize, y: usize) -> f64 {
+        match self {
+            DitherMode::None => 0.0,
+            DitherMode::Ordered8x8 => (BAYER_8X8[y % 8][x % 8] as f64 + 0.5) / 64.0,
+            DitherMode::BlueNoise => (BLUE_NOISE_16X16[y % 16][x % 16] as f64 + 0.5) / 256.0,
+        }
+    }
+}
+
+impl std::str::FromStr for DitherMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" => Ok(DitherMode::None),
+            "ordered" => Ok(DitherMode::Ordered8x8),
+            "blue-noise" => Ok(DitherMode::BlueNoise),
+            _ => Err(format!("unknown dither mode '{}' (expected none, ordered or blue-noise)", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for DitherMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DitherMode::None => write!(f, "none"),
+            DitherMode::Ordered8x8 => write!(f, "ordered"),
+            DitherMode::BlueNoise => write!(f, "blue-noise"),
+        }
+    }
+}
+
+const BAYER_8X8: [[u8; 8]; 8] = [
+    [ 0, 32,  8, 40,  2, 34, 10, 42],
+    [48, 16, 56, 24, 50, 18, 58, 26],
+    [12, 44,  4, 36, 14, 46,  6, 38],
+    [60, 28, 52, 20, 62, 30, 54, 22],
+    [ 3, 35, 11, 43,  1, 33,  9, 41],
+    [51, 19, 59, 27, 49, 17, 57, 25],
+    [15, 47,  7, 39, 13, 45,  5, 37],
+    [63, 31, 55, 23, 61, 29, 53, 21],
+];
+
+/// Ranks of a toroidal 16x16 blue noise pattern, made with the
+/// void-and-cluster method using a Gaussian filter of standard deviation 1.5.
+const BLUE_NOISE_16X16: [[u8; 16]; 16] = [
+    [120,  61, 134, 223,  84,  33, 168,  12, 113, 225,  63, 246, 185, 233,  88, 169],
+    [ 23, 206, 181,  17, 109, 214,  58, 140, 201,  24, 161,  93,  34, 133,  14, 221],
+    [144,  73, 250,  49, 158, 187,  81, 251, 100,  51, 142, 210, 172,  57, 191, 106],
+    [ 42, 167, 101, 126, 220,   3, 121,  40, 170, 231,  82,   8, 114, 255,  80, 232],
+    [212,  11, 195,  31,  72, 239, 152, 196,  16, 127, 188, 222,  45, 157,  26, 128],
+    [154,  87, 235, 143, 179,  94,  54, 108, 237,  65,  29, 105, 139, 207, 184,  66],
+    [248,  47, 115,  62, 209,  20, 164, 217,  79, 146, 178, 243,  69,  90,   1, 118],
+    [ 30, 190, 173,   6, 131, 254,  41, 136,  10, 204,  43, 159,  22, 229, 162, 218],
+    [ 77, 148,  99, 226,  74, 182, 117, 192,  86, 247, 119,  97, 197, 130,  53, 103],
+    [242,  19, 198,  44, 155,  96,  59, 230,  28, 165,  60,   5, 240,  39, 175, 202],
+    [137,  64, 122, 238,  25, 211,   0, 149, 104, 224, 135, 183, 151,  71, 112,   9],
+    [ 91, 213, 166,  85, 186, 111, 249, 174,  48,  75, 208,  32,  89, 205, 236, 160],
+    [ 37, 252,  18,  55, 138,  38,  78, 123, 194,  13, 107, 253, 124,  15,  56, 189],
+    [ 76, 145, 110, 228, 203, 163, 219,  21, 241, 141, 171,  50, 156, 227, 102, 129],
+    [  2, 199, 176,  68,   7,  98,  52, 150,  92,  36, 215,  83, 200,  27, 177, 216],
+    [244,  95,  35, 153, 245, 125, 193, 234,  70, 180, 132,   4, 116,  67, 147,  46],
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn each_tile_holds_every_threshold_once() {
+        for (mode, size) in [(DitherMode::Ordered8x8, 8), (DitherMode::BlueNoise, 16)] {
+            let mut thresholds: Vec<f64> = (0 .. size * size).map(|i| mode.threshold(i % size, i / size)).collect();
+            thresholds.sort_by(f64::total_cmp);
+            let n = (size * size) as f64;
+            for (rank, t) in thresholds.iter().enumerate() {
+                assert_eq!(*t, (rank as f64 + 0.5) / n, "{:?}", mode);
+            }
+        }
+    }
+
+    #[test]
+    fn thresholds_depend_only_on_position() {
+        for mode in [DitherMode::Ordered8x8, DitherMode::BlueNoise] {
+            for (x, y) in [(0, 0), (3, 5), (15, 2), (7, 15)] {
+                let t = mode.threshold(x, y);
+                assert!((0.0 .. 1.0).contains(&t));
+                assert_eq!(mode.threshold(x + 16, y + 48), t, "{:?} does not tile", mode);
+            }
+        }
+        assert!((0 .. 40).all(|i| DitherMode::None.threshold(i, 2 * i) == 0.0));
+    }
+
+    #[test]
+    fn modes_round_trip_through_their_names() {
+        for name in DitherMode::NAMES {
+            let mode: DitherMode = name.parse().unwrap();
+            assert_eq!(mode.to_string(), name);
+        }
+        assert_eq!("Ordered".parse(), Ok(DitherMode::Ordered8x8));
+        assert_eq!("bayer".parse::<DitherMode>(), Err("unknown dither mode 'bayer' (expected none, ordered or blue-noise)".to_string()));
+    }
+}
@@ -0,0 +1,183 @@
+use image::DynamicImage;
+use rand::prelude::*;
+use rand::rngs::StdRng;
+
+use super::*;
+
+/// Maximum number of pixels considered when clustering. Larger images are
+/// sampled at a regular stride.
+const MAX_SAMPLES: usize = 16384;
+
+/// Number of rounds of Lloyd's algorithm run after initialization.
+const KMEANS_ROUNDS: usize = 24;
+
+impl Palette {
+    /// Build a palette from the `k` dominant colors of an image.
+    ///
+    /// The pixels are clustered with k-means in Oklab space, initialized by
+    /// k-means++ from `seed`, and the cluster centers are ordered darkest first
+    /// along a nearest-neighbor path so that the gradient doesn't zigzag.
+    pub fn from_image_kmeans(img: &DynamicImage, k: usize, seed: u64) -> Result<Palette, PaletteError> {
+        if k == 0 { return Err(PaletteError::Empty); }
+        if k > 256 { return Err(PaletteError::TooManyColors(k)); }
+
+        let rgb = img.to_rgb8();
+        let n = rgb.pixels().len();
+        if n == 0 { return Err(PaletteError::EmptyImage); }
+
+        let stride = n.div_ceil(MAX_SAMPLES);
+        let samples: Vec<Oklab> = rgb.pixels()
+            .step_by(stride)
+            .map(|p| Color::rgb(p[0], p[1], p[2]).to_oklab())
+            .collect();
+
+        let mut rng = StdRng::seed_from_u64(seed);
+        let centers = kmeans(&samples, k, &mut rng);
+
+        let keys = nearest_neighbor_order(centers).into_iter()
+            .map(Color::from_oklab)
+            .collect();
+
+        Palette::from_keys(keys)
+    }
+}
+
+fn dist2(a: &Oklab, b: &Oklab) -> f32 {
+    (a.l - b.l).powi(2) + (a.a - b.a).powi(2) + (a.b - b.b).powi(2)
+}
+
+fn nearest(centers: &[Oklab], p: &Oklab) -> usize {
+    (0 .. centers.len())
+        .min_by(|&i, &j| dist2(&centers[i], p).total_cmp(&dist2(&centers[j], p)))
+        .unwrap()
+}
+
+fn kmeans(samples: &[Oklab], k: usize, rng: &mut impl Rng) -> Vec<Oklab> {
+    // k-means++ initialization: each new center is chosen with probability
+    // proportional to its squared distance from the nearest existing one.
+    let mut centers = vec![samples[rng.gen_range(0 .. samples.len())]];
+    let mut d2: Vec<f32> = samples.iter().map(|p| dist2(&centers[0], p)).collect();
+
+    while centers.len() < k {
+        let total: f32 = d2.iter().sum();
+        let next = if total > 0.0 {
+            let mut r = rng.gen::<f32>() * total;
+            d2.iter().position(|&d| { r -= d; r <= 0.0 }).unwrap_or(samples.len() - 1)
+        } else {
+            // Fewer distinct colors than clusters.
+            rng.gen_range(0 .. samples.len())
+        };
+        centers.push(samples[next]);
+        for (d, p) in d2.iter_mut().zip(samples) {
+            *d = d.min(dist2(&samples[next], p));
+        }
+    }
+
+    for _ in 0 .. KMEANS_ROUNDS {
+        let mut sums = vec![(0.0, 0.0, 0.0, 0usize); k];
+        for p in samples {
+            let s = &mut sums[nearest(&centers, p)];
+            s.0 += p.l;
+            s.1 += p.a;
+            s.2 += p.b;
+            s.3 += 1;
+        }
+
+        let mut moved = false;
+        for (c, (l, a, b, count)) in centers.iter_mut().zip(sums) {
+            if count == 0 { continue; }
+            let new = Oklab { l: l / count as f32, a: a / count as f32, b: b / count as f32 };
+            moved |= dist2(c, &new) > 1e-10;
+            *c = new;
+        }
+        if !moved { break; }
+    }
+
+    centers
+}
+
+fn nearest_neighbor_order(mut centers: Vec<Oklab>) -> Vec<Oklab> {
+    let first = (0 .. centers.len())
+        .min_by(|&i, &j| centers[i].l.total_cmp(&centers[j].l))
+        .unwrap();
+    let mut ordered = vec![centers.swap_remove(first)];
+
+    while !centers.is_empty() {
+        let next = nearest(&centers, ordered.last().unwrap());
+        ordered.push(centers.swap_remove(next));
+    }
+
+    ordered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage};
+
+    /// An image of vertical stripes of the given colors and widths.
+    fn stripes(colors: &[([u8; 3], u32)]) -> DynamicImage {
+        let width = colors.iter().map(|&(_, w)| w).sum();
+        let mut img = RgbImage::new(width, 20);
+        let mut x0 = 0;
+        for &(color, w) in colors {
+            for x in x0 .. x0 + w {
+                for y in 0 .. 20 {
+                    img.put_pixel(x, y, Rgb(color));
+                }
+            }
+            x0 += w;
+        }
+        DynamicImage::ImageRgb8(img)
+    }
+
+    fn close(a: Color, b: [u8; 3]) -> bool {
+        [a.red, a.green, a.blue].iter().zip(b).all(|(&x, y)| x.abs_diff(y) <= 1)
+    }
+
+    #[test]
+    fn dominant_colors_are_found() {
+        let (navy, orange, cream) = ([20, 30, 90], [230, 120, 30], [250, 240, 210]);
+        let img = stripes(&[(orange, 30), (navy, 50), (cream, 20), (navy, 10)]);
+        let palette = Palette::from_image_kmeans(&img, 3, 1).unwrap();
+        let keys = palette.keys();
+        assert_eq!(keys.len(), 3);
+        // Darkest first, then along the shortest path.
+        assert!(close(keys[0], navy), "{:?}", keys);
+        assert!(close(keys[1], orange), "{:?}", keys);
+        assert!(close(keys[2], cream), "{:?}", keys);
+    }
+
+    #[test]
+    fn clusters_take_the_mean_of_their_colors() {
+        // Two shades of red, far from the blue, make one cluster between them.
+        let img = stripes(&[([200, 0, 0], 10), ([220, 0, 0], 10), ([0, 0, 200], 20)]);
+        let keys = Palette::from_image_kmeans(&img, 2, 5).unwrap().keys().to_vec();
+        assert!(close(keys[1], [211, 0, 0]) || close(keys[0], [211, 0, 0]), "{:?}", keys);
+    }
+
+    #[test]
+    fn extraction_is_repeatable_from_its_seed() {
+        let img = DynamicImage::ImageRgb8(RgbImage::from_fn(64, 64, |x, y| Rgb([(x * 4) as u8, (y * 4) as u8, 128])));
+        let a = Palette::from_image_kmeans(&img, 6, 9).unwrap();
+        assert!(a == Palette::from_image_kmeans(&img, 6, 9).unwrap());
+        assert_eq!(a.keys().len(), 6);
+    }
+
+    #[test]
+    fn more_clusters_than_colors_repeat_colors() {
+        let img = stripes(&[([10, 10, 10], 5), ([240, 240, 240], 5)]);
+        let keys = Palette::from_image_kmeans(&img, 4, 2).unwrap().keys().to_vec();
+        assert_eq!(keys.len(), 4);
+        assert!(keys.iter().all(|&k| close(k, [10, 10, 10]) || close(k, [240, 240, 240])), "{:?}", keys);
+    }
+
+    #[test]
+    fn impossible_extractions_are_refused() {
+        let img = stripes(&[([1, 2, 3], 4)]);
+        assert!(matches!(Palette::from_image_kmeans(&img, 0, 0), Err(PaletteError::Empty)));
+        assert!(matches!(Palette::from_image_kmeans(&img, 257, 0), Err(PaletteError::TooManyColors(257))));
+        let empty = DynamicImage::ImageRgb8(RgbImage::new(0, 0));
+        assert!(matches!(Palette::from_image_kmeans(&empty, 3, 0), Err(PaletteError::EmptyImage)));
+    }
+}
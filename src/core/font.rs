@@ -0,0 +1,226 @@
+use super::*;
+
+/// Width of a glyph in pixels.
+pub const GLYPH_WIDTH: usize = 5;
+/// Height of a glyph in pixels.
+pub const GLYPH_HEIGHT: usize = 7;
+/// Horizontal distance between the starts of consecutive characters.
+const ADVANCE: usize = GLYPH_WIDTH + 1;
+/// Vertical distance between the tops of consecutive lines.
+const LINE_HEIGHT: usize = GLYPH_HEIGHT + 2;
+
+/// Rows of a 5x7 glyph, top to bottom, with the leftmost pixel in bit 4.
+/// Lowercase letters are drawn as uppercase and unknown characters as `?`.
+fn glyph(c: char) -> [u8; GLYPH_HEIGHT] {
+    match c.to_ascii_uppercase() {
+        '0' => [0x0E, 0x11, 0x13, 0x15, 0x19, 0x11, 0x0E],
+        '1' => [0x04, 0x0C, 0x04, 0x04, 0x04, 0x04, 0x0E],
+        '2' => [0x0E, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1F],
+        '3' => [0x1F, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0E],
+        '4' => [0x02, 0x06, 0x0A, 0x12, 0x1F, 0x02, 0x02],
+        '5' => [0x1F, 0x10, 0x1E, 0x01, 0x01, 0x11, 0x0E],
+        '6' => [0x06, 0x08, 0x10, 0x1E, 0x11, 0x11, 0x0E],
+        '7' => [0x1F, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08],
+        '8' => [0x0E, 0x11, 0x11, 0x0E, 0x11, 0x11, 0x0E],
+        '9' => [0x0E, 0x11, 0x11, 0x0F, 0x01, 0x02, 0x0C],
+        'A' => [0x0E, 0x11, 0x11, 0x11, 0x1F, 0x11, 0x11],
+        'B' => [0x1E, 0x11, 0x11, 0x1E, 0x11, 0x11, 0x1E],
+        'C' => [0x0E, 0x11, 0x10, 0x10, 0x10, 0x11, 0x0E],
+        'D' => [0x1C, 0x12, 0x11, 0x11, 0x11, 0x12, 0x1C],
+        'E' => [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x1F],
+        'F' => [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x10],
+        'G' => [0x0E, 0x11, 0x10, 0x17, 0x11, 0x11, 0x0F],
+        'H' => [0x11, 0x11, 0x11, 0x1F, 0x11, 0x11, 0x11],
+        'I' => [0x0E, 0x04, 0x04, 0x04, 0x04, 0x04, 0x0E],
+        'J' => [0x07, 0x02, 0x02, 0x02, 0x02, 0x12, 0x0C],
+        'K' => [0x11, 0x12, 0x14, 0x18, 0x14, 0x12, 0x11],
+        'L' => [0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 0x1F],
+        'M' => [0x11, 0x1B, 0x15, 0x15, 0x11, 0x11, 0x11],
+        'N' => [0x11, 0x11, 0x19, 0x15, 0x13, 0x11, 0x11],
+        'O' => [0x0E, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E],
+        'P' => [0x1E, 0x11, 0x11, 0x1E, 0x10, 0x10, 0x10],
+        'Q' => [0x0E, 0x11, 0x11, 0x11, 0x15, 0x12, 0x0D],
+        'R' => [0x1E, 0x11, 0x11, 0x1E, 0x14, 0x12, 0x11],
+        'S' => [0x0F, 0x10, 0x10, 0x0E, 0x01, 0x01, 0x1E],
+        'T' => [0x1F, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04],
+        'U' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E],
+        'V' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x0A, 0x04],
+        'W' => [0x11, 0x11, 0x11, 0x15, 0x15, 0x15, 0x0A],
+        'X' => [0x11, 0x11, 0x0A, 0x04, 0x0A, 0x11, 0x11],
+        'Y' => [0x11, 0x11, 0x11, 0x0A, 0x04, 0x04, 0x04],
+        'Z' => [0x1F, 0x01, 0x02, 0x04, 0x08, 0x10, 0x1F],
+        ' ' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+        '.' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x0C, 0x0C],
+        ',' => [0x00, 0x00, 0x00, 0x00, 0x0C, 0x04, 0x08],
+        ':' => [0x00, 0x0C, 0x0C, 0x00, 0x0C, 0x0C, 0x00],
+        '%' => [0x18, 0x19, 0x02, 0x04, 0x08, 0x13, 0x03],
+        '/' => [0x00, 0x01, 0x02, 0x04, 0x08, 0x10, 0x00],
+        '-' => [0x00, 0x00, 0x00, 0x1F, 0x00, 0x00, 0x00],
+        '+' => [0x00, 0x04, 0x04, 0x1F, 0x04, 0x04, 0x00],
+        '=' => [0x00, 0x00, 0x1F, 0x00, 0x1F, 0x00, 0x00],
+        '(' => [0x02, 0x04, 0x08, 0x08, 0x08, 0x04, 0x02],
+        ')' => [0x08, 0x04, 0x02, 0x02, 0x02, 0x04, 0x08],
+        _ => [0x0E, 0x11, 0x01, 0x02, 0x04, 0x00, 0x04],
+    }
+}
+
+/// Size in pixels of the box `draw_text` would fill with `text`.
+pub fn text_size(text: &str) -> (usize, usize) {
+    let lines = text.lines().count().max(1);
+    let longest = text.lines().map(|l| l.chars().count()).max().unwrap_or(0);
+    (
+        (longest * ADVANCE).saturating_sub(1),
+        lines * LINE_HEIGHT - (LINE_HEIGHT - GLYPH_HEIGHT),
+    )
+}
+
+impl Buffer<u8> {
+    fn set_pixel(&mut self, x: usize, y: usize, color: Color, opacity: f32) {
+        if x >= self.width() || y >= self.height() {
+            return;
+        }
+        let blend = |a: u8, b: u8| (a as f32 * (1.0 - opacity) + b as f32 * opacity).round() as u8;
+        let bucket = self.bucket_mut(x, y);
+        bucket.red = blend(bucket.red, color.red);
+        bucket.green = blend(bucket.green, color.green);
+        bucket.blue = blend(bucket.blue, color.blue);
+        bucket.alpha = blend(bucket.alpha, color.to_gray());
+    }
+
+    /// Blend a rectangle of solid color over the buffer, clipped to its edges.
+    pub fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, color: Color, opacity: f32) {
+        let x_end = x.saturating_add(width).min(self.width());
+        let y_end = y.saturating_add(height).min(self.height());
+        for py in y .. y_end {
+            for px in x .. x_end {
+                self.set_pixel(px, py, color, opacity);
+            }
+        }
+    }
+
+    /// Draw text with its top left corner at `(x, y)` using a built in 5x7
+    /// pixel font. Text running off the edges of the buffer is clipped.
+    pub fn draw_text(&mut self, x: usize, y: usize, text: &str, color: Color) {
+        for (row, line) in text.lines().enumerate() {
+            let top = y.saturating_add(row * LINE_HEIGHT);
+            for (col, c) in line.chars().enumerate() {
+                let left = x.saturating_add(col * ADVANCE);
+                if left >= self.width() || top >= self.height() {
+                    break;
+                }
+                for (gy, bits) in glyph(c).iter().enumerate() {
+                    for gx in 0 .. GLYPH_WIDTH {
+                        if bits & (0x10 >> gx) != 0 {
+                            self.set_pixel(left + gx, top + gy, color, 1.0);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Draw text over a translucent backing box, so it stays legible over
+    /// any part of the image.
+    pub fn draw_label(&mut self, x: usize, y: usize, text: &str, color: Color, backing: Color) {
+        let (w, h) = text_size(text);
+        self.fill_rect(x, y, w + 4, h + 4, backing, 0.6);
+        self.draw_text(x + 2, y + 2, text, color);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bench::baseline_config;
+    use crate::presets;
+
+    const WHITE: Color = Color { red: 255, green: 255, blue: 255 };
+
+    /// The pixels of the buffer which are lit, as `(x, y)`.
+    fn lit(buffer: &Buffer<u8>) -> Vec<(usize, usize)> {
+        buffer.row_major_buckets().enumerate()
+            .filter(|(_, b)| b.red > 0)
+            .map(|(i, _)| (i % buffer.width(), i / buffer.width()))
+            .collect()
+    }
+
+    #[test]
+    fn glyphs_are_drawn_from_the_font() {
+        let mut buffer = Buffer::new(8, 9);
+        buffer.draw_text(1, 1, "1", WHITE);
+        let pixels = lit(&buffer);
+        // The top of the stem, the serif and the foot of the 1.
+        for p in [(3, 1), (2, 2), (3, 2), (2, 7), (3, 7), (4, 7)] {
+            assert!(pixels.contains(&p), "{:?} is not lit", p);
+        }
+        assert!(!pixels.contains(&(1, 1)) && !pixels.contains(&(5, 1)));
+        assert_eq!(pixels.len(), 1 + 2 + 4 + 3);
+        assert!(pixels.iter().all(|&(x, y)| (1 .. 6).contains(&x) && (1 .. 8).contains(&y)));
+    }
+
+    #[test]
+    fn characters_and_lines_are_spaced_by_the_advance() {
+        let mut one = Buffer::new(40, 20);
+        one.draw_text(0, 0, "4", WHITE);
+        let mut many = Buffer::new(40, 20);
+        many.draw_text(0, 0, "a4\n 4", WHITE);
+        let shifted = |dx: usize, dy: usize| lit(&one).into_iter().map(move |(x, y)| (x + dx, y + dy));
+        let pixels = lit(&many);
+        assert!(shifted(ADVANCE, 0).chain(shifted(ADVANCE, LINE_HEIGHT)).all(|p| pixels.contains(&p)));
+        // Lowercase letters are drawn as uppercase.
+        let mut lower = Buffer::new(40, 20);
+        lower.draw_text(0, 0, "a", WHITE);
+        let mut upper = Buffer::new(40, 20);
+        upper.draw_text(0, 0, "A", WHITE);
+        assert_eq!(lit(&lower), lit(&upper));
+        assert_eq!(text_size("a4\n 4"), (2 * ADVANCE - 1, LINE_HEIGHT + GLYPH_HEIGHT));
+    }
+
+    #[test]
+    fn text_past_the_edges_is_clipped() {
+        let mut buffer = Buffer::new(10, 5);
+        buffer.draw_text(0, 0, "0123456789 ITERATIONS\nSECOND LINE", WHITE);
+        buffer.draw_text(8, 3, "WIDE", WHITE);
+        buffer.draw_text(usize::MAX - 3, usize::MAX, "OFF", WHITE);
+        buffer.draw_label(6, 2, "LABEL\nTWO", WHITE, Color::rgb(0, 0, 0));
+        buffer.fill_rect(usize::MAX, 0, usize::MAX, 3, WHITE, 0.5);
+        let pixels = lit(&buffer);
+        assert!(!pixels.is_empty());
+        assert!(pixels.iter().all(|&(x, y)| x < 10 && y < 5));
+    }
+
+    #[test]
+    fn labels_sit_on_a_translucent_box() {
+        let mut buffer = Buffer::new(20, 12);
+        buffer.fill_rect(0, 0, 20, 12, Color::rgb(100, 100, 100), 1.0);
+        buffer.draw_label(1, 1, "0", WHITE, Color::rgb(0, 0, 0));
+        let at = |x: usize, y: usize| buffer.row_major_buckets().nth(x + y * 20).unwrap().red;
+        assert_eq!(at(0, 0), 100);
+        assert_eq!(at(1, 1), 40);
+        assert_eq!(at(4, 3), 255);
+        assert_eq!(at(19, 11), 100);
+    }
+
+    #[test]
+    fn statistics_are_drawn_only_on_previews() {
+        let tone = ToneMapping::default();
+        let run = RunConfig { width: 64, height: 48, iters: 20_000, seed: Some(5), ..baseline_config(1) };
+        let mut session = RenderSession::new(presets::gasket(), run).unwrap();
+        session.run();
+        let plain: Vec<_> = session.snapshot_8bit(&tone, DitherMode::None).row_major_buckets().map(|b| b.red).collect();
+        let preview = |overlay| -> Vec<u8> {
+            session.preview_8bit(&tone, DitherMode::None, overlay).row_major_buckets().map(|b| b.red).collect()
+        };
+        assert_eq!(preview(false), plain);
+        let overlaid = preview(true);
+        assert_ne!(overlaid, plain);
+        // The overlay stays in the top left corner.
+        let (w, h) = text_size(&session.stats().to_string());
+        for (i, (&a, &b)) in overlaid.iter().zip(&plain).enumerate() {
+            if a != b {
+                assert!(i % 64 < w + 6 && i / 64 < h + 6, "pixel {} changed", i);
+            }
+        }
+        assert_eq!(session.into_buffer().render_8bit(&tone, DitherMode::None).row_major_buckets().map(|b| b.red).collect::<Vec<_>>(), plain);
+    }
+}
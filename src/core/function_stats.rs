@@ -0,0 +1,120 @@
+/// How often a function was applied during a run, and where the points it
+/// produced were plotted.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct FunctionStats {
+    /// Number of iterations which applied the function.
+    pub selected: u64,
+    /// Number of those, after the orbit warmed up, whose result was inside
+    /// the bounds.
+    pub in_bounds: u64,
+    /// Sums of the screen coordinates of the points inside the bounds, and
+    /// of their squares, from which their spread is found.
+    sum: [f64; 2],
+    sum_sq: [f64; 2],
+}
+
+impl FunctionStats {
+    /// Count a point of the function's inside the bounds, at the given
+    /// screen position.
+    pub fn record_in_bounds(&mut self, screen: [f32; 2]) {
+        self.in_bounds += 1;
+        for (axis, v) in screen.into_iter().enumerate() {
+            let v = v as f64;
+            self.sum[axis] += v;
+            self.sum_sq[axis] += v * v;
+        }
+    }
+
+    /// Mean screen position of the points inside the bounds, in pixels.
+    pub fn centroid(&self) -> Option<[f64; 2]> {
+        (self.in_bounds > 0).then(|| self.sum.map(|s| s / self.in_bounds as f64))
+    }
+
+    /// Standard deviation of the screen positions of the points inside the
+    /// bounds along each axis, in pixels.
+    pub fn spread(&self) -> Option<[f64; 2]> {
+        let [cx, cy] = self.centroid()?;
+        let n = self.in_bounds as f64;
+        Some([(self.sum_sq[0] / n - cx * cx).max(0.0).sqrt(), (self.sum_sq[1] / n - cy * cy).max(0.0).sqrt()])
+    }
+
+    /// Add the counts of another run of the same function.
+    pub fn merge(&mut self, other: &FunctionStats) {
+        self.selected += other.selected;
+        self.in_bounds += other.in_bounds;
+        for axis in 0 .. 2 {
+            self.sum[axis] += other.sum[axis];
+            self.sum_sq[axis] += other.sum_sq[axis];
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bench::baseline_config;
+    use crate::core::{Flame, RenderSession, RunConfig};
+    use crate::presets;
+    use nalgebra::{Affine2, Matrix3};
+
+    fn config(threads: usize, iters: usize) -> RunConfig {
+        RunConfig { width: 32, height: 32, iters, seed: Some(3), per_function_stats: true, ..baseline_config(threads) }
+    }
+
+    /// Two functions of the gasket, applied nine times in ten and once in
+    /// ten. The second is moved far to the right of the bounds.
+    fn lopsided() -> Flame {
+        let mut flame = presets::gasket();
+        flame.functions.truncate(2);
+        (flame.functions[0].weight, flame.functions[1].weight) = (0.9, 0.1);
+        flame.functions[1].trans = Affine2::from_matrix_unchecked(Matrix3::new(
+            0.5, 0.0, 100.0,
+            0.0, 0.5, 0.0,
+            0.0, 0.0, 1.0,
+        ));
+        flame
+    }
+
+    #[test]
+    fn selections_follow_the_weights() {
+        for threads in [1, 3] {
+            let mut session = RenderSession::new(lopsided(), config(threads, 200_000)).unwrap();
+            session.run();
+            let stats = session.function_stats().unwrap();
+            assert_eq!(stats.iter().map(|s| s.selected).sum::<u64>(), 200_000);
+            let share = stats[0].selected as f64 / 200_000.0;
+            assert!((share - 0.9).abs() < 0.005, "{} threads: {}", threads, share);
+        }
+    }
+
+    #[test]
+    fn functions_whose_points_escape_the_bounds_have_no_hits() {
+        let mut session = RenderSession::new(lopsided(), config(2, 100_000)).unwrap();
+        session.run();
+        let stats = session.function_stats().unwrap();
+        assert!(stats[1].selected > 0);
+        assert_eq!((stats[1].in_bounds, stats[1].centroid(), stats[1].spread()), (0, None, None));
+        assert!(stats[0].in_bounds > 0 && stats[0].in_bounds < stats[0].selected);
+    }
+
+    #[test]
+    fn sessions_without_the_option_keep_no_stats() {
+        let mut session = RenderSession::new(lopsided(), RunConfig { per_function_stats: false, ..config(1, 1_000) }).unwrap();
+        session.run();
+        assert!(session.function_stats().is_none());
+    }
+
+    #[test]
+    fn centroids_and_spreads_merge_exactly() {
+        let points = [[1.0, 2.0], [3.0, 2.0], [5.0, 8.0], [7.0, 8.0]];
+        let (mut a, mut b, mut all) = (FunctionStats::default(), FunctionStats::default(), FunctionStats::default());
+        for (i, &p) in points.iter().enumerate() {
+            if i < 2 { a.record_in_bounds(p) } else { b.record_in_bounds(p) }
+            all.record_in_bounds(p);
+        }
+        a.merge(&b);
+        assert_eq!(a, all);
+        assert_eq!(a.centroid(), Some([4.0, 5.0]));
+        assert_eq!(a.spread(), Some([5.0f64.sqrt(), 3.0]));
+    }
+}
@@ -0,0 +1,474 @@
+use std::fmt::Write;
+
+use super::*;
+
+/// Number of entries in an exported Apophysis gradient.
+const UGR_STEPS: usize = 400;
+
+impl Palette {
+    /// The palette as an Apophysis gradient file, sampled at 400 evenly
+    /// spaced positions. Apophysis gradients are opaque.
+    ///
+    /// There is no reader for Apophysis gradients, so these cannot be
+    /// imported again; use [`Palette::to_ggr`] for a gradient which can.
+    pub fn to_ugr(&self, name: &str) -> String {
+        let mut out = String::new();
+        writeln!(out, "{} {{", name).unwrap();
+        writeln!(out, "gradient:").unwrap();
+        writeln!(out, " title=\"{}\" smooth=no", name).unwrap();
+        for i in 0 .. UGR_STEPS {
+            let c = self.sample_at(i as f32 / (UGR_STEPS - 1) as f32).color;
+            // Apophysis packs colors with red in the lowest byte.
+            let packed = c.red as u32 | (c.green as u32) << 8 | (c.blue as u32) << 16;
+            writeln!(out, " index={} color={}", i, packed).unwrap();
+        }
+        writeln!(out, "}}").unwrap();
+        out
+    }
+
+    /// The palette as a GIMP gradient, with a linear segment between each
+    /// pair of adjacent control colors, placed where the palette puts them.
+    ///
+    /// Reading the gradient back with [`Palette::from_ggr`] gives every
+    /// entry to within 1/255 per channel, opacity included: the palette
+    /// rounds interpolated colors down where reading rounds them to the
+    /// nearest value, and the file keeps six decimal places.
+    pub fn to_ggr(&self, name: &str) -> String {
+        let knots = self.knots();
+
+        let mut out = String::new();
+        writeln!(out, "GIMP Gradient").unwrap();
+        writeln!(out, "Name: {}", name).unwrap();
+        writeln!(out, "{}", knots.len() - 1).unwrap();
+        for w in knots.windows(2) {
+            let ((left, a), (right, b)) = (w[0], w[1]);
+            let (left, right) = (left as f64 / 255., right as f64 / 255.);
+            let channels = |ColorA { color: c, alpha }: ColorA| {
+                format!("{:.6} {:.6} {:.6} {:.6}",
+                    c.red as f32 / 255., c.green as f32 / 255., c.blue as f32 / 255., alpha as f32 / 255.)
+            };
+            // Linear blending in RGB, with fixed endpoint colors.
+            writeln!(out, "{:.6} {:.6} {:.6} {} {} 0 0 0 0",
+                left, (left + right) / 2., right, channels(a), channels(b)).unwrap();
+        }
+        out
+    }
+
+    /// The entries between which the palette is linear, with their colors.
+    ///
+    /// Each pair of adjacent control colors spans a run of entries from the
+    /// first to the second, and the next run starts again at the second, so
+    /// that it fills two entries. A run a single entry long holds only its
+    /// first color, and the palette jumps to the next from there.
+    fn knots(&self) -> Vec<(usize, ColorA)> {
+        let keys = self.keys_with_alpha();
+        if keys.len() == 1 {
+            return vec![(0, keys[0]), (255, keys[0])];
+        }
+        let segments = keys.len() - 1;
+        let (spacing, leftover) = (256 / segments, 256 % segments);
+        let mut knots = Vec::with_capacity(2 * segments);
+        let mut offset = 0;
+        for (i, w) in keys.windows(2).enumerate() {
+            let span = if i < leftover { spacing + 1 } else { spacing };
+            knots.push((offset, w[0]));
+            if span > 1 {
+                knots.push((offset + span - 1, w[1]));
+            }
+            offset += span;
+        }
+        knots
+    }
+
+    /// The palette as a CSS `linear-gradient` running from left to right.
+    pub fn to_css(&self) -> String {
+        let stops: Vec<String> = self.iter_stops()
+            .map(|(pos, c)| {
+                let percent = (pos * 10000.).round() / 100.;
+                format!("{} {}%", c, percent)
+            })
+            .collect();
+        format!("linear-gradient(90deg, {})", stops.join(", "))
+    }
+}
+
+/// Number of positions a GIMP gradient is sampled at to make a palette.
+const GGR_SAMPLES: usize = 256;
+
+/// How color moves across a segment of a GIMP gradient, from its left end
+/// at 0 to its right end at 1.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum GgrBlend {
+    Linear,
+    Curved,
+    Sine,
+    SphereIncreasing,
+    SphereDecreasing,
+    Step,
+}
+
+/// The space a GIMP gradient segment interpolates color in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum GgrColoring {
+    Rgb,
+    /// HSV, with the hue turning counterclockwise, or increasing.
+    HsvCcw,
+    /// HSV, with the hue turning clockwise, or decreasing.
+    HsvCw,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct GgrSegment {
+    left: f64,
+    middle: f64,
+    right: f64,
+    left_color: [f64; 3],
+    right_color: [f64; 3],
+    /// Opacity at the left and right ends.
+    alpha: [f64; 2],
+    blend: GgrBlend,
+    coloring: GgrColoring,
+}
+
+impl GgrSegment {
+    /// The color and opacity at `pos`, which lies in the segment.
+    /// Opacity is interpolated in step with the color, as in GIMP.
+    fn color_at(&self, pos: f64) -> ([f64; 3], f64) {
+        let width = self.right - self.left;
+        let (middle, pos) = if width < f64::EPSILON {
+            (0.5, 0.5)
+        } else {
+            ((self.middle - self.left) / width, (pos - self.left) / width)
+        };
+        // The fraction of the way from the left color to the right, with
+        // the middle point at one half.
+        let linear = || {
+            if pos <= middle {
+                if middle < f64::EPSILON { 0.0 } else { 0.5 * pos / middle }
+            } else if middle > 1.0 - f64::EPSILON {
+                1.0
+            } else {
+                0.5 + 0.5 * (pos - middle) / (1.0 - middle)
+            }
+        };
+        let t = match self.blend {
+            GgrBlend::Linear => linear(),
+            GgrBlend::Curved => pos.powf(0.5f64.ln() / middle.max(f64::EPSILON).ln()),
+            GgrBlend::Sine => ((std::f64::consts::PI * linear() - std::f64::consts::FRAC_PI_2).sin() + 1.0) / 2.0,
+            GgrBlend::SphereIncreasing => (1.0 - (linear() - 1.0).powi(2)).sqrt(),
+            GgrBlend::SphereDecreasing => 1.0 - (1.0 - linear().powi(2)).max(0.0).sqrt(),
+            GgrBlend::Step => if pos >= middle { 1.0 } else { 0.0 },
+        };
+
+        let alpha = self.alpha[0] + (self.alpha[1] - self.alpha[0]) * t;
+        let (a, b) = (self.left_color, self.right_color);
+        let ([h0, s0, v0], [h1, s1, v1]) = (rgb_to_hsv(a), rgb_to_hsv(b));
+        let hue = match self.coloring {
+            GgrColoring::Rgb => return ([0, 1, 2].map(|i| a[i] + (b[i] - a[i]) * t), alpha),
+            GgrColoring::HsvCcw if h0 < h1 => h0 + (h1 - h0) * t,
+            GgrColoring::HsvCcw => (h0 + (1.0 - (h0 - h1)) * t).rem_euclid(1.0),
+            GgrColoring::HsvCw if h1 < h0 => h0 - (h0 - h1) * t,
+            GgrColoring::HsvCw => (h0 - (1.0 - (h1 - h0)) * t).rem_euclid(1.0),
+        };
+        let rgb = hsv_to_rgb(hue as f32 * 360.0, (s0 + (s1 - s0) * t) as f32, (v0 + (v1 - v0) * t) as f32);
+        (rgb.map(f64::from), alpha)
+    }
+}
+
+/// Hue, as a fraction of a turn, saturation and value of an RGB color.
+pub(super) fn rgb_to_hsv([r, g, b]: [f64; 3]) -> [f64; 3] {
+    let max = r.max(g).max(b);
+    let range = max - r.min(g).min(b);
+    let hue = if range <= 0.0 {
+        0.0
+    } else if max == r {
+        ((g - b) / range).rem_euclid(6.0)
+    } else if max == g {
+        (b - r) / range + 2.0
+    } else {
+        (r - g) / range + 4.0
+    };
+    [hue / 6.0, if max > 0.0 { range / max } else { 0.0 }, max]
+}
+
+impl Palette {
+    /// Read a GIMP gradient, sampling it at each of the 256 entries of the
+    /// palette so that curved, sine, spherical and step blending, and
+    /// blending in HSV, are kept as they are in GIMP.
+    ///
+    /// Colors GIMP would take from the foreground or background are the
+    /// ones saved in the file.
+    pub fn from_ggr(src: &str) -> Result<Palette, PaletteError> {
+        let mut lines = src.lines().enumerate().map(|(i, l)| (i + 1, l.trim())).filter(|(_, l)| !l.is_empty());
+        let invalid = |line: usize, message: &str| PaletteError::InvalidGradient { line, message: message.to_string() };
+
+        match lines.next() {
+            Some((_, "GIMP Gradient")) => {}
+            _ => return Err(invalid(1, "expected 'GIMP Gradient'")),
+        }
+        let (mut line, mut text) = lines.next().ok_or_else(|| invalid(2, "expected the number of segments"))?;
+        if text.starts_with("Name:") {
+            (line, text) = lines.next().ok_or_else(|| invalid(line + 1, "expected the number of segments"))?;
+        }
+        let count: usize = text.parse().map_err(|_| invalid(line, "expected the number of segments"))?;
+        if count == 0 {
+            return Err(PaletteError::Empty);
+        }
+
+        // The count is not trusted to size the list, as the file may not
+        // hold that many segments.
+        let mut segments = Vec::with_capacity(count.min(256));
+        for _ in 0 .. count {
+            (line, text) = lines.next().ok_or_else(|| invalid(line + 1, "expected another segment"))?;
+            let fields = text.split_whitespace().map(str::parse::<f64>).collect::<Result<Vec<_>, _>>()
+                .map_err(|_| invalid(line, "segment fields must be numbers"))?;
+            if fields.len() < 11 {
+                return Err(invalid(line, "a segment needs at least positions and colors"));
+            }
+            let (left, middle, right) = (fields[0], fields[1], fields[2]);
+            if !(0.0 <= left && left <= middle && middle <= right && right <= 1.0) {
+                return Err(invalid(line, "segment positions must increase within [0, 1]"));
+            }
+            // The fields after the colors were added to the format over
+            // time, and default to linear blending in RGB.
+            let blend = match fields.get(11).copied().unwrap_or(0.0) as i64 {
+                0 => GgrBlend::Linear,
+                1 => GgrBlend::Curved,
+                2 => GgrBlend::Sine,
+                3 => GgrBlend::SphereIncreasing,
+                4 => GgrBlend::SphereDecreasing,
+                5 => GgrBlend::Step,
+                _ => return Err(invalid(line, "unknown blending function")),
+            };
+            let coloring = match fields.get(12).copied().unwrap_or(0.0) as i64 {
+                0 => GgrColoring::Rgb,
+                1 => GgrColoring::HsvCcw,
+                2 => GgrColoring::HsvCw,
+                _ => return Err(invalid(line, "unknown coloring type")),
+            };
+            segments.push(GgrSegment {
+                left,
+                middle,
+                right,
+                left_color: [fields[3], fields[4], fields[5]].map(|c| c.clamp(0.0, 1.0)),
+                right_color: [fields[7], fields[8], fields[9]].map(|c| c.clamp(0.0, 1.0)),
+                alpha: [fields[6], fields[10]].map(|a| a.clamp(0.0, 1.0)),
+                blend,
+                coloring,
+            });
+        }
+
+        let mut entries = [ColorA::opaque(Color::rgb(0, 0, 0)); GGR_SAMPLES];
+        for (i, entry) in entries.iter_mut().enumerate() {
+            let pos = i as f64 / (GGR_SAMPLES - 1) as f64;
+            // Positions between segments, which a malformed file may leave,
+            // take the color at the start of the next one.
+            let segment = segments.iter().find(|s| pos <= s.right).unwrap_or(&segments[segments.len() - 1]);
+            let ([red, green, blue], alpha) = segment.color_at(pos.clamp(segment.left, segment.right));
+            let [red, green, blue, alpha] = [red, green, blue, alpha].map(|c| (c.clamp(0.0, 1.0) * 255.0).round() as u8);
+            *entry = ColorA { color: Color::rgb(red, green, blue), alpha };
+        }
+        // The samples are the entries themselves: taking them as control
+        // colors would spread 256 of them over 255 gaps, shifting them.
+        Ok(Palette::new_with_alpha(entries))
+    }
+}
+
+/// File format a palette can be exported to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GradientFormat {
+    /// Apophysis gradient.
+    Ugr,
+    /// GIMP gradient.
+    Ggr,
+    /// CSS `linear-gradient`.
+    Css,
+}
+
+impl GradientFormat {
+    /// Names of the formats, as accepted by `from_str`.
+    pub const NAMES: [&'static str; 3] = ["ugr", "ggr", "css"];
+}
+
+impl std::str::FromStr for GradientFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "ugr" => Ok(GradientFormat::Ugr),
+            "ggr" => Ok(GradientFormat::Ggr),
+            "css" => Ok(GradientFormat::Css),
+            _ => Err(format!("unknown gradient format '{}' (expected ugr, ggr or css)", s)),
+        }
+    }
+}
+
+impl Palette {
+    /// The palette in the given format. The name is ignored by CSS.
+    pub fn export(&self, format: GradientFormat, name: &str) -> String {
+        match format {
+            GradientFormat::Ugr => self.to_ugr(name),
+            GradientFormat::Ggr => self.to_ggr(name),
+            GradientFormat::Css => self.to_css() + "\n",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+
+    fn random_palette(rng: &mut StdRng, keys: usize) -> Palette {
+        let keys = (0 .. keys)
+            .map(|_| ColorA { color: Color::rgb(rng.gen(), rng.gen(), rng.gen()), alpha: rng.gen() })
+            .collect();
+        Palette::from_keys_with_alpha(keys).unwrap()
+    }
+
+    #[test]
+    fn gimp_gradients_read_back_within_rounding() {
+        let mut rng = StdRng::seed_from_u64(5);
+        for keys in [1, 2, 3, 5, 17, 100, 129, 130, 200, 256] {
+            let palette = random_palette(&mut rng, keys);
+            let read = Palette::from_ggr(&palette.to_ggr("round trip")).unwrap();
+            for i in 0 ..= 255 {
+                let (a, b) = (palette.sample(i), read.sample(i));
+                let channels = |c: ColorA| [c.color.red, c.color.green, c.color.blue, c.alpha];
+                let off = channels(a).iter().zip(channels(b)).map(|(&x, y)| x.abs_diff(y)).max().unwrap();
+                assert!(off <= 1, "{} keys, entry {}: {:?} and {:?}", keys, i, a, b);
+            }
+        }
+    }
+
+    /// Black to orange, linear in RGB with the middle a third of the way
+    /// across, then red to blue in a sine, through green.
+    const LINEAR_AND_SINE: &str = "GIMP Gradient
+Name: Linear and sine
+2
+0.000000 0.200000 0.600000 0.000000 0.000000 0.000000 1.000000 0.800000 0.400000 0.000000 0.600000 0 0
+0.600000 0.800000 1.000000 1.000000 0.000000 0.000000 1.000000 0.000000 0.000000 1.000000 1.000000 2 1
+";
+
+    /// Yellow stepping to clear cyan, then black to light gray on a curve
+    /// whose middle is a quarter of the way across.
+    const STEP_AND_CURVED: &str = "GIMP Gradient
+Name: Step and curved
+2
+0.000000 0.100000 0.200000 1.000000 1.000000 0.000000 1.000000 0.000000 1.000000 1.000000 0.000000 5 0
+0.200000 0.400000 1.000000 0.000000 0.000000 0.000000 1.000000 0.800000 0.800000 0.800000 1.000000 1 0
+";
+
+    /// Red to blue through magenta, rising like a sphere, then white to
+    /// clear black, falling like one. Older files stop after the colors.
+    const SPHERES: &str = "GIMP Gradient
+2
+0.000000 0.200000 0.400000 1.000000 0.000000 0.000000 1.000000 0.000000 0.000000 1.000000 1.000000 3 2
+0.400000 0.600000 1.000000 1.000000 1.000000 1.000000 1.000000 0.000000 0.000000 0.000000 0.000000 4
+";
+
+    /// Palette entries and the color and opacity expected there.
+    type Samples = &'static [(u8, [u8; 4])];
+
+    #[test]
+    fn gimp_gradients_match_reference_values() {
+        // Entries 51, 102, 153 and 204 are at 0.2, 0.4, 0.6 and 0.8, and
+        // each boundary belongs to the segment on its left.
+        let references: [(&str, Samples); 3] = [
+            (LINEAR_AND_SINE, &[
+                (0, [0, 0, 0, 255]),
+                // Halfway to (204, 102, 0) and an opacity of 153.
+                (51, [102, 51, 0, 204]),
+                (153, [204, 102, 0, 153]),
+                // Just into the sine, still barely moved from red.
+                (154, [255, 0, 0, 255]),
+                // Halfway round the hue, counterclockwise from red.
+                (204, [0, 255, 0, 255]),
+                (255, [0, 0, 255, 255]),
+            ]),
+            (STEP_AND_CURVED, &[
+                (0, [255, 255, 0, 255]),
+                // Either side of the middle, at 25.5.
+                (25, [255, 255, 0, 255]),
+                (26, [0, 255, 255, 0]),
+                (51, [0, 255, 255, 0]),
+                (102, [102, 102, 102, 255]),
+                // Halfway across, the curve has gone 0.5^0.5 of the way,
+                // where a line would have gone two thirds.
+                (153, [144, 144, 144, 255]),
+                (255, [204, 204, 204, 255]),
+            ]),
+            (SPHERES, &[
+                (0, [255, 0, 0, 255]),
+                // At the middle, the sphere has risen 0.75^0.5 of the way,
+                // turning the hue clockwise from 0 to 256.1 degrees.
+                (51, [68, 0, 255, 255]),
+                (102, [0, 0, 255, 255]),
+                // Falling, it has gone 1 - 0.75^0.5 of the way.
+                (153, [221, 221, 221, 221]),
+                (255, [0, 0, 0, 0]),
+            ]),
+        ];
+        for (ggr, samples) in references {
+            let palette = Palette::from_ggr(ggr).unwrap();
+            for &(i, [red, green, blue, alpha]) in samples {
+                let expected = ColorA { color: Color::rgb(red, green, blue), alpha };
+                assert_eq!(palette.sample(i), expected, "entry {} of\n{}", i, ggr);
+            }
+        }
+    }
+
+    #[test]
+    fn gimp_gradients_keep_hard_stops() {
+        let (a, b) = (Color::rgb(200, 10, 10), Color::rgb(10, 10, 200));
+        let palette = Palette::from_keys(vec![a, a, b, b]).unwrap();
+        let ggr = palette.to_ggr("stops");
+        let read = Palette::from_ggr(&ggr).unwrap();
+        for (pos, key) in palette.iter_stops() {
+            assert_eq!(read.sample_at(pos).color, key.color, "at {}", pos);
+        }
+        assert!(ggr.starts_with("GIMP Gradient\nName: stops\n"), "{}", ggr);
+    }
+
+    #[test]
+    fn apophysis_gradients_sample_the_palette() {
+        let palette = random_palette(&mut StdRng::seed_from_u64(8), 6);
+        let ugr = palette.to_ugr("sampled");
+        let entries: Vec<(usize, u32)> = ugr.lines()
+            .filter_map(|l| l.trim().strip_prefix("index="))
+            .map(|l| {
+                let (index, color) = l.split_once(" color=").unwrap();
+                (index.parse().unwrap(), color.parse().unwrap())
+            })
+            .collect();
+        assert_eq!(entries.len(), UGR_STEPS);
+        for (i, &(index, packed)) in entries.iter().enumerate() {
+            let c = palette.sample_at(i as f32 / (UGR_STEPS - 1) as f32).color;
+            assert_eq!(index, i);
+            assert_eq!(packed, u32::from_le_bytes([c.red, c.green, c.blue, 0]), "entry {}", i);
+        }
+        assert!(ugr.starts_with("sampled {\ngradient:\n title=\"sampled\" smooth=no\n"), "{}", ugr);
+        assert!(ugr.ends_with("}\n"));
+    }
+
+    #[test]
+    fn css_gradients_list_each_stop() {
+        let palette = Palette::from_keys_with_alpha(vec![
+            ColorA::opaque(Color::rgb(255, 0, 0)),
+            ColorA { color: Color::rgb(0, 255, 0), alpha: 128 },
+            ColorA::opaque(Color::rgb(0, 0, 255)),
+        ]).unwrap();
+        assert_eq!(palette.to_css(), "linear-gradient(90deg, #ff0000 0%, #00ff0080 49.8%, #0000ff 100%)");
+        assert_eq!(palette.export(GradientFormat::Css, "ignored"), palette.to_css() + "\n");
+    }
+
+    #[test]
+    fn formats_are_parsed_by_name() {
+        for name in GradientFormat::NAMES {
+            let format: GradientFormat = name.parse().unwrap();
+            assert_eq!(format, name.to_uppercase().parse().unwrap());
+        }
+        assert!("svg".parse::<GradientFormat>().unwrap_err().contains("'svg'"));
+    }
+}
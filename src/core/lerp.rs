@@ -0,0 +1,304 @@
+use nalgebra::Transform;
+
+use super::*;
+
+/// Space in which colors are interpolated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ColorSpace {
+    /// Gamma encoded sRGB, as the channels are stored.
+    Srgb,
+    /// Linear sRGB, which mixes light physically.
+    Linear,
+    /// The Oklab perceptual space, which avoids the dark, muddy midpoints of
+    /// mixing distant hues in sRGB.
+    #[default]
+    Oklab,
+}
+
+impl ColorSpace {
+    /// Names of the color spaces, as accepted by `from_str`.
+    pub const NAMES: [&'static str; 3] = ["srgb", "linear", "oklab"];
+}
+
+impl std::str::FromStr for ColorSpace {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "srgb" => Ok(ColorSpace::Srgb),
+            "linear" => Ok(ColorSpace::Linear),
+            "oklab" => Ok(ColorSpace::Oklab),
+            _ => Err(format!("unknown color space '{}' (expected srgb, linear or oklab)", s)),
+        }
+    }
+}
+
+/// How palettes are interpolated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PaletteLerpMode {
+    /// Interpolate corresponding control colors, failing if the palettes
+    /// have different numbers of them.
+    Strict,
+    /// Sample both gradients at this many evenly spaced positions (at most
+    /// 256) and interpolate the samples, so any two palettes can be mixed.
+    Resample(usize),
+}
+
+impl Default for PaletteLerpMode {
+    fn default() -> Self {
+        PaletteLerpMode::Resample(256)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LerpOptions {
+    pub palette: PaletteLerpMode,
+    pub space: ColorSpace,
+}
+
+#[derive(Debug)]
+pub enum LerpError {
+    /// The flames have different numbers of functions.
+    FunctionCount(usize, usize),
+    /// The functions at this index use different variations.
+    Variation(usize),
+    /// The palettes have different numbers of control colors, in strict mode.
+    PaletteKeys(usize, usize),
+}
+
+impl std::fmt::Display for LerpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LerpError::FunctionCount(a, b) => {
+                write!(f, "cannot interpolate flames with {} and {} functions", a, b)
+            }
+            LerpError::Variation(i) => {
+                write!(f, "cannot interpolate function {}: the variations differ", i)
+            }
+            LerpError::PaletteKeys(a, b) => {
+                write!(f, "cannot strictly interpolate palettes with {} and {} colors", a, b)
+            }
+        }
+    }
+}
+
+impl std::error::Error for LerpError {}
+
+/// Linear interpolation, written so that equal endpoints are returned exactly.
+fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a + (b - a) * t
+}
+
+impl Color {
+    /// The color a fraction `t` of the way from `self` to `other`.
+    pub fn lerp(self, other: Color, t: f32, space: ColorSpace) -> Color {
+        match space {
+            ColorSpace::Srgb => {
+                let c = |x: u8, y: u8| lerp(x as f32, y as f32, t).round() as u8;
+                Color::rgb(c(self.red, other.red), c(self.green, other.green), c(self.blue, other.blue))
+            }
+            ColorSpace::Linear => {
+                let (a, b) = (self.to_linear(), other.to_linear());
+                Color::from_linear([0, 1, 2].map(|i| lerp(a[i], b[i], t)))
+            }
+            ColorSpace::Oklab => {
+                let (a, b) = (self.to_oklab(), other.to_oklab());
+                Color::from_oklab(Oklab {
+                    l: lerp(a.l, b.l, t),
+                    a: lerp(a.a, b.a, t),
+                    b: lerp(a.b, b.b, t),
+                })
+            }
+        }
+    }
+}
+
+impl ColorA {
+    /// The color a fraction `t` of the way from `self` to `other`, with
+    /// opacity interpolated linearly whatever the space.
+    pub fn lerp(self, other: ColorA, t: f32, space: ColorSpace) -> ColorA {
+        ColorA {
+            color: self.color.lerp(other.color, t, space),
+            alpha: lerp(self.alpha as f32, other.alpha as f32, t).round() as u8,
+        }
+    }
+}
+
+impl Palette {
+    /// The palette a fraction `t` of the way from `self` to `other`.
+    pub fn lerp(&self, other: &Palette, t: f32, opts: LerpOptions) -> Result<Palette, LerpError> {
+        let keys: Vec<ColorA> = match opts.palette {
+            PaletteLerpMode::Strict => {
+                let (ka, kb) = (self.keys_with_alpha(), other.keys_with_alpha());
+                if ka.len() != kb.len() {
+                    return Err(LerpError::PaletteKeys(ka.len(), kb.len()));
+                }
+                ka.into_iter().zip(kb).map(|(a, b)| a.lerp(b, t, opts.space)).collect()
+            }
+            PaletteLerpMode::Resample(n) => {
+                let n = n.clamp(2, 256);
+                (0 .. n).map(|i| {
+                    let pos = i as f32 / (n - 1) as f32;
+                    self.sample_at(pos).lerp(other.sample_at(pos), t, opts.space)
+                }).collect()
+            }
+        };
+
+        // A full table is kept as is, since rebuilding it from 256 keys
+        // would shift every entry after the first.
+        match <[ColorA; 256]>::try_from(keys) {
+            Ok(colors) => Ok(Palette::new_with_alpha(colors)),
+            Err(keys) => Ok(Palette::from_keys_with_alpha(keys).expect("between 1 and 256 keys")),
+        }
+    }
+}
+
+impl Function {
+    /// The function a fraction `t` of the way from `self` to `other`, which
+    /// should use the same variation.
+    pub fn lerp(&self, other: &Function, t: f32) -> Function {
+        let params: Vec<f32> = self.var.params().iter().zip(other.var.params())
+            .map(|(&x, y)| lerp(x, y, t))
+            .collect();
+        let matrix = self.trans.matrix().zip_map(other.trans.matrix(), |x, y| lerp(x, y, t));
+
+        Function {
+            weight: lerp(self.weight, other.weight, t),
+            color: lerp(self.color as f32, other.color as f32, t).round() as u8,
+            var: self.var.with_params(&params),
+            trans: Transform::from_matrix_unchecked(matrix),
+            axis_blend: [0, 1].map(|i| lerp(self.axis_blend[i], other.axis_blend[i], t)),
+        }
+    }
+}
+
+impl Bounds {
+    pub fn lerp(&self, other: &Bounds, t: f32) -> Bounds {
+        Bounds::new(
+            lerp(self.x_min, other.x_min, t),
+            lerp(self.x_max, other.x_max, t),
+            lerp(self.y_min, other.y_min, t),
+            lerp(self.y_max, other.y_max, t),
+        )
+    }
+}
+
+impl Flame {
+    /// The flame a fraction `t` of the way from `self` to `other`.
+    ///
+    /// The flames must have the same number of functions, with the same
+    /// variation in each position. Palettes are mixed according to `opts`.
+    pub fn lerp(&self, other: &Flame, t: f32, opts: LerpOptions) -> Result<Flame, LerpError> {
+        if self.functions.len() != other.functions.len() {
+            return Err(LerpError::FunctionCount(self.functions.len(), other.functions.len()));
+        }
+        let functions = self.functions.iter().zip(&other.functions).enumerate()
+            .map(|(i, (a, b))| {
+                if a.var.same_kind(&b.var) { Ok(a.lerp(b, t)) } else { Err(LerpError::Variation(i)) }
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Flame {
+            functions,
+            palette: self.palette.lerp(&other.palette, t, opts)?,
+            bounds: self.bounds.lerp(&other.bounds, t),
+            // Masks are not interpolated, so the first flame's is kept.
+            mask: self.mask.clone(),
+            color_model: self.color_model,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::presets;
+
+    const RED: Color = Color { red: 255, green: 0, blue: 0 };
+    const BLUE: Color = Color { red: 0, green: 0, blue: 255 };
+
+    fn palette(keys: &[Color]) -> Palette {
+        Palette::from_keys(keys.to_vec()).unwrap()
+    }
+
+    fn resampled(space: ColorSpace) -> LerpOptions {
+        LerpOptions { palette: PaletteLerpMode::Resample(256), space }
+    }
+
+    fn assert_close(a: ColorA, b: ColorA, what: &str) {
+        let channels = |c: ColorA| [c.color.red, c.color.green, c.color.blue, c.alpha];
+        let off = channels(a).iter().zip(channels(b)).map(|(&x, y)| x.abs_diff(y)).max().unwrap();
+        assert!(off <= 1, "{}: {:?} and {:?}", what, a, b);
+    }
+
+    #[test]
+    fn endpoints_reproduce_their_palettes() {
+        let a = palette(&[RED, Color::rgb(240, 220, 40)]);
+        let b = palette(&[BLUE, Color::rgb(10, 200, 90), Color::rgb(250, 250, 250), RED, Color::rgb(30, 30, 30)]);
+        for space in [ColorSpace::Srgb, ColorSpace::Linear, ColorSpace::Oklab] {
+            let start = a.lerp(&b, 0.0, resampled(space)).unwrap();
+            let end = a.lerp(&b, 1.0, resampled(space)).unwrap();
+            for i in 0 ..= 255 {
+                assert_close(start.sample(i), a.sample(i), &format!("{:?} start at {}", space, i));
+                assert_close(end.sample(i), b.sample(i), &format!("{:?} end at {}", space, i));
+            }
+        }
+    }
+
+    #[test]
+    fn opposite_gradients_morph_without_turning_gray() {
+        let (a, b) = (palette(&[RED, BLUE]), palette(&[BLUE, RED]));
+        let mut previous: Option<Palette> = None;
+        for step in 0 ..= 20 {
+            let t = step as f32 / 20.;
+            let p = a.lerp(&b, t, resampled(ColorSpace::Oklab)).unwrap();
+            for i in 0 ..= 255 {
+                let chroma = p.sample(i).color.to_oklab().chroma();
+                assert!(chroma > 0.1, "entry {} at t = {} has chroma {}", i, t, chroma);
+            }
+            // Each step moves every entry only a little.
+            if let Some(previous) = &previous {
+                for i in 0 ..= 255 {
+                    let distance = p.sample(i).color.to_oklab().distance(&previous.sample(i).color.to_oklab());
+                    assert!(distance < 0.06, "entry {} jumped {} at t = {}", i, distance, t);
+                }
+            }
+            previous = Some(p);
+        }
+
+        // Halfway, the ends are a brighter purple than mixing in sRGB gives.
+        let oklab = a.lerp(&b, 0.5, resampled(ColorSpace::Oklab)).unwrap().sample(0).color.to_oklab();
+        let srgb = a.lerp(&b, 0.5, resampled(ColorSpace::Srgb)).unwrap().sample(0).color.to_oklab();
+        assert!(oklab.l > srgb.l + 0.05, "{} and {}", oklab.l, srgb.l);
+    }
+
+    #[test]
+    fn strict_palette_interpolation_needs_matching_keys() {
+        let strict = LerpOptions { palette: PaletteLerpMode::Strict, space: ColorSpace::Srgb };
+        let (two, three) = (palette(&[RED, BLUE]), palette(&[RED, BLUE, RED]));
+        assert!(matches!(two.lerp(&three, 0.5, strict), Err(LerpError::PaletteKeys(2, 3))));
+        let mixed = two.lerp(&palette(&[BLUE, RED]), 0.5, strict).unwrap();
+        assert_eq!(mixed.keys(), &[Color::rgb(128, 0, 128); 2]);
+        assert!(two.lerp(&three, 0.5, resampled(ColorSpace::Srgb)).is_ok());
+    }
+
+    #[test]
+    fn flames_interpolate_function_by_function() {
+        let (a, mut b) = (presets::gasket(), presets::gasket());
+        b.functions[0].weight = 0.5;
+        b.bounds = Bounds::new(-1.05, 0.05, -1.1, 0.0);
+        let opts = LerpOptions::default();
+        assert!(a.lerp(&b, 0.0, opts).unwrap().functions == a.functions);
+        assert!(a.lerp(&b, 1.0, opts).unwrap().functions == b.functions);
+        let half = a.lerp(&b, 0.5, opts).unwrap();
+        assert!((half.functions[0].weight - (0.5 + 1. / 3.) / 2.).abs() < 1e-6);
+        for (got, want) in half.bounds.to_array().iter().zip([-0.55, 0.55, -0.6, 0.5]) {
+            assert!((got - want).abs() < 1e-6, "{:?}", half.bounds);
+        }
+
+        b.functions[1].var = Variation::Swirl;
+        assert!(matches!(a.lerp(&b, 0.5, opts), Err(LerpError::Variation(1))));
+        b.functions.pop();
+        assert!(matches!(a.lerp(&b, 0.5, opts), Err(LerpError::FunctionCount(3, 2))));
+    }
+}
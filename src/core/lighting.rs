@@ -0,0 +1,192 @@
+use super::*;
+use super::math::{Math, PlatformMath};
+
+/// Height of the relief the density is shaded as, as a fraction of the
+/// image's shorter side per unit of density. Being relative to the image,
+/// the shading looks the same at any resolution.
+const RELIEF: f64 = 0.01;
+
+/// Shading of the density as if it were a relief lit from one direction,
+/// so that dense ridges catch the light on one side and fall into shadow
+/// on the other.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Lighting {
+    /// Direction the light comes from, in degrees counterclockwise from
+    /// the right of the image, so that 135 is the upper left.
+    pub azimuth: f64,
+    /// Height of the light above the image, in degrees. Lower lights give
+    /// deeper shadows.
+    pub elevation: f64,
+    /// How much of the shading is applied, from none at 0 to all at 1.
+    pub strength: f64,
+}
+
+impl std::str::FromStr for Lighting {
+    type Err = String;
+
+    /// Parses `AZIMUTH,ELEVATION,STRENGTH`, such as `135,45,0.4`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || format!("invalid light '{}' (expected AZIMUTH,ELEVATION,STRENGTH)", s);
+        let values = s.split(',').map(|v| v.trim().parse::<f64>()).collect::<Result<Vec<_>, _>>().map_err(|_| err())?;
+        let [azimuth, elevation, strength] = values[..] else { return Err(err()) };
+        if !(azimuth.is_finite() && elevation > 0.0 && elevation <= 90.0 && strength.is_finite() && strength >= 0.0) {
+            return Err(format!("invalid light '{}': elevation must be in (0, 90] and strength at least 0", s));
+        }
+        Ok(Lighting { azimuth, elevation, strength })
+    }
+}
+
+impl std::fmt::Display for Lighting {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{},{},{}", self.azimuth, self.elevation, self.strength)
+    }
+}
+
+impl Buffer<f64> {
+    /// Shade a tone mapped buffer as a relief whose height is its alpha
+    /// channel, lit from `azimuth_deg` and `elevation_deg` as described by
+    /// `Lighting`.
+    ///
+    /// Each pixel's Oklab lightness is scaled by how much more or less
+    /// light its slope receives than flat ground would, blended in by
+    /// `strength`, so flat regions are untouched. The alpha channel is
+    /// shaded as a gray, so grayscale output is lit too. Slopes at the
+    /// edges are found as if the border pixels went on beyond the image,
+    /// so the edges are not shaded as cliffs.
+    pub fn emboss_light(&mut self, azimuth_deg: f64, elevation_deg: f64, strength: f64) {
+        self.emboss_light_with::<PlatformMath>(Lighting { azimuth: azimuth_deg, elevation: elevation_deg, strength })
+    }
+
+    pub(crate) fn emboss_light_with<M: Math>(&mut self, light: Lighting) {
+        let (width, height) = (self.width(), self.height());
+        if light.strength == 0.0 || width == 0 || height == 0 {
+            return;
+        }
+        let (sin_az, cos_az) = (M::sin(light.azimuth.to_radians()), M::cos(light.azimuth.to_radians()));
+        let (sin_el, cos_el) = (M::sin(light.elevation.to_radians()), M::cos(light.elevation.to_radians()));
+        // Rows run down the image, so up is towards negative y.
+        let dir = [cos_el * cos_az, -cos_el * sin_az, sin_el];
+        let relief = RELIEF * width.min(height) as f64;
+
+        let alpha: Vec<f64> = self.buckets().iter().map(|b| b.alpha).collect();
+        let at = |x: isize, y: isize| {
+            let x = x.clamp(0, width as isize - 1) as usize;
+            let y = y.clamp(0, height as isize - 1) as usize;
+            alpha[x + y * width]
+        };
+
+        for (i, pixel) in self.as_flat_slice_mut().chunks_exact_mut(4).enumerate() {
+            let (x, y) = ((i % width) as isize, (i / width) as isize);
+            // Sobel kernels, scaled to the change in height per pixel.
+            let gx = (at(x + 1, y - 1) + 2.0 * at(x + 1, y) + at(x + 1, y + 1)
+                - at(x - 1, y - 1) - 2.0 * at(x - 1, y) - at(x - 1, y + 1)) / 8.0;
+            let gy = (at(x - 1, y + 1) + 2.0 * at(x, y + 1) + at(x + 1, y + 1)
+                - at(x - 1, y - 1) - 2.0 * at(x, y - 1) - at(x + 1, y - 1)) / 8.0;
+            if gx == 0.0 && gy == 0.0 {
+                continue;
+            }
+            let normal = [-gx * relief, -gy * relief, 1.0];
+            let lambert = (normal[0] * dir[0] + normal[1] * dir[1] + normal[2] * dir[2]).max(0.0)
+                / (normal[0] * normal[0] + normal[1] * normal[1] + 1.0).sqrt();
+            let scale = (1.0 + light.strength * (lambert / sin_el - 1.0)).max(0.0);
+
+            let [a, r, g, b] = [pixel[0], pixel[1], pixel[2], pixel[3]];
+            let [r, g, b] = scale_lightness::<M>([r, g, b], scale);
+            let [a, _, _] = scale_lightness::<M>([a, a, a], scale);
+            pixel.copy_from_slice(&[a, r, g, b]);
+        }
+    }
+}
+
+/// Scale the Oklab lightness of a display color, keeping its chroma.
+fn scale_lightness<M: Math>(rgb: [f64; 3], scale: f64) -> [f64; 3] {
+    let mul = |m: [[f32; 3]; 3], v: [f64; 3]| m.map(|row| row[0] as f64 * v[0] + row[1] as f64 * v[1] + row[2] as f64 * v[2]);
+    let cbrt = |x: f64| if x > 0.0 { M::powf(x, 1.0 / 3.0) } else { -M::powf(-x, 1.0 / 3.0) };
+    let linear = rgb.map(|c| srgb_to_linear::<M>(c.max(0.0)));
+    let [l, a, b] = mul(LMS_TO_OKLAB, mul(LINEAR_TO_LMS, linear).map(cbrt));
+    let lms = mul(OKLAB_TO_LMS, [l * scale, a, b]).map(|c| c * c * c);
+    mul(LMS_TO_LINEAR, lms).map(|c| linear_to_srgb::<M>(c.max(0.0)))
+}
+
+fn srgb_to_linear<M: Math>(c: f64) -> f64 {
+    if c <= 0.04045 { c / 12.92 } else { M::powf((c + 0.055) / 1.055, 2.4) }
+}
+
+fn linear_to_srgb<M: Math>(c: f64) -> f64 {
+    if c <= 0.0031308 { c * 12.92 } else { 1.055 * M::powf(c, 1. / 2.4) - 0.055 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZE: usize = 64;
+    /// Width of the blob, in pixels.
+    const SIGMA: f64 = 12.0;
+
+    /// A gray Gaussian blob in the middle of the image.
+    fn blob() -> Buffer<f64> {
+        let c = (SIZE / 2) as f64;
+        let buckets = (0 .. SIZE * SIZE).map(|i| {
+            let (x, y) = ((i % SIZE) as f64 - c, (i / SIZE) as f64 - c);
+            let v = 0.9 * (-(x * x + y * y) / (SIGMA * SIGMA)).exp();
+            Bucket { alpha: v, red: v, green: v, blue: v }
+        }).collect();
+        Buffer::from_buckets(SIZE, SIZE, buckets).unwrap()
+    }
+
+    fn channels(buffer: &Buffer<f64>) -> Vec<[u64; 4]> {
+        buffer.buckets().iter().map(|b| [b.alpha, b.red, b.green, b.blue].map(f64::to_bits)).collect()
+    }
+
+    /// Oklab lightness of a gray level.
+    fn lightness(gray: f64) -> f64 {
+        srgb_to_linear::<PlatformMath>(gray).cbrt()
+    }
+
+    #[test]
+    fn the_side_facing_the_light_is_brighter_by_the_lambert_ratio() {
+        let (elevation, strength) = (45.0_f64, 1.0);
+        let mut lit = blob();
+        // From the right of the image.
+        lit.emboss_light(0.0, elevation, strength);
+        let original = blob();
+        let c = SIZE / 2;
+        for d in [4, 8, 12] {
+            let scale = |x: usize| lightness(lit.buckets()[x + c * SIZE].red) / lightness(original.buckets()[x + c * SIZE].red);
+            let (bright, dark) = (scale(c + d), scale(c - d));
+
+            // The slope of the blob, d pixels from the middle, in relief
+            // heights, and the light each side gets relative to flat ground.
+            let d = d as f64;
+            let slope = 0.9 * 2.0 * d / (SIGMA * SIGMA) * (-(d * d) / (SIGMA * SIGMA)).exp() * RELIEF * SIZE as f64;
+            let (sin, cos) = (elevation.to_radians().sin(), elevation.to_radians().cos());
+            let light = |facing: f64| (facing * slope * cos + sin) / (slope * slope + 1.0).sqrt() / sin;
+            let expected = (1.0 + strength * (light(1.0) - 1.0)) / (1.0 + strength * (light(-1.0) - 1.0));
+            assert!(bright > 1.0 && dark < 1.0, "{} pixels out: {} and {}", d, bright, dark);
+            assert!((bright / dark / expected - 1.0).abs() < 0.02, "{} pixels out: {} is not {}", d, bright / dark, expected);
+        }
+    }
+
+    #[test]
+    fn no_strength_and_flat_regions_are_untouched() {
+        let mut unlit = blob();
+        unlit.emboss_light(135.0, 45.0, 0.0);
+        assert_eq!(channels(&unlit), channels(&blob()));
+
+        // Flat all the way to the edges, which are not shaded as cliffs.
+        let flat = || Buffer::from_buckets(8, 6, vec![Bucket { alpha: 0.5, red: 0.7, green: 0.4, blue: 0.2 }; 48]).unwrap();
+        let mut lit = flat();
+        lit.emboss_light(135.0, 30.0, 1.0);
+        assert_eq!(channels(&lit), channels(&flat()));
+    }
+
+    #[test]
+    fn lights_parse_as_azimuth_elevation_and_strength() {
+        assert_eq!("135, 45, 0.4".parse(), Ok(Lighting { azimuth: 135.0, elevation: 45.0, strength: 0.4 }));
+        assert_eq!("-90,90,1".parse::<Lighting>().unwrap().to_string(), "-90,90,1");
+        for bad in ["135,45", "135,45,0.4,1", "135,0,0.4", "135,95,0.4", "135,45,-1", "a,45,1"] {
+            assert!(bad.parse::<Lighting>().is_err(), "{}", bad);
+        }
+    }
+}
@@ -0,0 +1,196 @@
+use image::{imageops, GrayImage};
+use nalgebra::{Affine2, Point2};
+
+/// Number of vertices of the polygon standing in for a circle which the
+/// screen transform stretches into an ellipse.
+const ELLIPSE_VERTICES: usize = 64;
+
+/// A region of the plane which a flame's points are plotted in, given in
+/// the flame's own coordinates.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MaskShape {
+    /// A grayscale stencil stretched over the flame's bounds. Points are
+    /// plotted with a weight of the stencil's brightness where they land.
+    Image(GrayImage),
+    Circle { center: Point2<f32>, radius: f32 },
+    Rect { min: Point2<f32>, max: Point2<f32> },
+    /// A polygon filled by the even-odd rule.
+    Polygon(Vec<Point2<f32>>),
+}
+
+impl MaskShape {
+    /// The mask in the screen space of a render, where `trans` maps the
+    /// flame's coordinates to pixels of a `width` by `height` image.
+    pub fn to_mask(&self, trans: &Affine2<f32>, width: usize, height: usize) -> Mask {
+        match self {
+            MaskShape::Image(img) => {
+                let resized = imageops::resize(img, width as u32, height as u32, imageops::FilterType::Triangle);
+                let luma = resized.pixels().map(|p| p[0] as f32 / 255.).collect();
+                Mask::Image { width, height, luma }
+            }
+            MaskShape::Circle { center, radius } => {
+                let m = trans.matrix();
+                let (sx, sy) = (m[(0, 0)].abs(), m[(1, 1)].abs());
+                if (sx - sy).abs() <= 1e-4 * sx.max(sy) {
+                    Mask::Circle { center: trans * center, radius: radius * sx }
+                } else {
+                    let vertices = (0 .. ELLIPSE_VERTICES).map(|i| {
+                        let a = i as f32 / ELLIPSE_VERTICES as f32 * std::f32::consts::TAU;
+                        trans * Point2::new(center.x + radius * a.cos(), center.y + radius * a.sin())
+                    });
+                    Mask::Polygon(vertices.collect())
+                }
+            }
+            MaskShape::Rect { min, max } => {
+                let (a, b) = (trans * min, trans * max);
+                Mask::Rect {
+                    min: Point2::new(a.x.min(b.x), a.y.min(b.y)),
+                    max: Point2::new(a.x.max(b.x), a.y.max(b.y)),
+                }
+            }
+            MaskShape::Polygon(vertices) => Mask::Polygon(vertices.iter().map(|v| trans * v).collect()),
+        }
+    }
+}
+
+/// A mask in the screen space of a render, in pixels.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Mask {
+    /// A weight between 0 and 1 for each pixel, row by row.
+    Image { width: usize, height: usize, luma: Vec<f32> },
+    Circle { center: Point2<f32>, radius: f32 },
+    Rect { min: Point2<f32>, max: Point2<f32> },
+    Polygon(Vec<Point2<f32>>),
+}
+
+impl Mask {
+    /// Weight of points plotted in pixel `(x, y)`, between 0 and 1. Shapes
+    /// are tested at the center of the pixel, so they have hard edges.
+    pub fn weight_at(&self, x: usize, y: usize) -> f32 {
+        let p = Point2::new(x as f32 + 0.5, y as f32 + 0.5);
+        let inside = match self {
+            Mask::Image { width, height, luma } => {
+                return if x < *width && y < *height { luma[x + y * width] } else { 0.0 };
+            }
+            Mask::Circle { center, radius } => (p - center).norm_squared() <= radius * radius,
+            Mask::Rect { min, max } => p.x >= min.x && p.x < max.x && p.y >= min.y && p.y < max.y,
+            Mask::Polygon(vertices) => polygon_contains(vertices, p),
+        };
+        if inside { 1.0 } else { 0.0 }
+    }
+
+    /// The weight of every pixel of a `width` by `height` image, row by row.
+    pub fn rasterize(&self, width: usize, height: usize) -> Vec<f32> {
+        (0 .. height).flat_map(|y| (0 .. width).map(move |x| self.weight_at(x, y))).collect()
+    }
+}
+
+/// Whether `p` is inside a polygon by the even-odd rule, counting crossings
+/// of a ray running from it in the positive x direction.
+fn polygon_contains(vertices: &[Point2<f32>], p: Point2<f32>) -> bool {
+    let mut inside = false;
+    for (i, a) in vertices.iter().enumerate() {
+        let b = vertices[(i + 1) % vertices.len()];
+        if (a.y > p.y) != (b.y > p.y) {
+            let x = a.x + (p.y - a.y) / (b.y - a.y) * (b.x - a.x);
+            if p.x < x {
+                inside = !inside;
+            }
+        }
+    }
+    inside
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra::Matrix3;
+
+    fn screen(sx: f32, sy: f32, dx: f32, dy: f32) -> Affine2<f32> {
+        Affine2::from_matrix_unchecked(Matrix3::new(sx, 0.0, dx, 0.0, sy, dy, 0.0, 0.0, 1.0))
+    }
+
+    /// The pixels of a small image the mask covers, drawn as text.
+    fn drawn(mask: &Mask, width: usize, height: usize) -> Vec<String> {
+        (0 .. height).map(|y| (0 .. width).map(|x| if mask.weight_at(x, y) > 0.5 { '#' } else { '.' }).collect()).collect()
+    }
+
+    #[test]
+    fn circles_cover_pixels_whose_centers_they_contain() {
+        let mask = MaskShape::Circle { center: Point2::new(0.0, 0.0), radius: 0.375 }.to_mask(&screen(10.0, 10.0, 4.0, 4.0), 8, 8);
+        assert_eq!(mask, Mask::Circle { center: Point2::new(4.0, 4.0), radius: 3.75 });
+        assert_eq!(drawn(&mask, 8, 8), [
+            "...##...",
+            ".######.",
+            ".######.",
+            "########",
+            "########",
+            ".######.",
+            ".######.",
+            "...##...",
+        ]);
+    }
+
+    #[test]
+    fn stretched_circles_become_ellipses() {
+        let mask = MaskShape::Circle { center: Point2::new(0.0, 0.0), radius: 1.0 }.to_mask(&screen(4.0, 2.0, 5.0, 3.0), 10, 6);
+        let Mask::Polygon(vertices) = &mask else { panic!("{:?} is not a polygon", mask) };
+        assert_eq!(vertices.len(), ELLIPSE_VERTICES);
+        for v in vertices {
+            let (x, y) = ((v.x - 5.0) / 4.0, (v.y - 3.0) / 2.0);
+            assert!((x * x + y * y - 1.0).abs() < 1e-5, "{:?}", v);
+        }
+        assert_eq!(mask.weight_at(1, 2), 1.0);
+        assert_eq!(mask.weight_at(5, 0), 0.0);
+    }
+
+    #[test]
+    fn rectangles_are_half_open() {
+        // Given with its corners the wrong way round, as a flipped y axis
+        // leaves them.
+        let mask = MaskShape::Rect { min: Point2::new(0.1, 0.4), max: Point2::new(0.5, 0.1) }.to_mask(&screen(10.0, 10.0, 0.0, 0.0), 6, 6);
+        assert_eq!(mask, Mask::Rect { min: Point2::new(1.0, 1.0), max: Point2::new(5.0, 4.0) });
+        assert_eq!(drawn(&mask, 6, 5), ["......", ".####.", ".####.", ".####.", "......"]);
+    }
+
+    #[test]
+    fn polygons_are_filled_by_the_even_odd_rule() {
+        let square = [(1.0, 1.0), (7.0, 1.0), (7.0, 7.0), (1.0, 7.0)];
+        let notched = [(1.0, 1.0), (7.0, 1.0), (7.0, 7.0), (4.0, 3.0), (1.0, 7.0)];
+        let polygon = |points: &[(f32, f32)]| MaskShape::Polygon(points.iter().map(|&(x, y)| Point2::new(x, y)).collect())
+            .to_mask(&screen(1.0, 1.0, 0.0, 0.0), 8, 8);
+        assert_eq!(drawn(&polygon(&square), 8, 4), ["........", ".######.", ".######.", ".######."]);
+        // The notch cuts up from the bottom edge.
+        let notched = drawn(&polygon(&notched), 8, 8);
+        assert_eq!((notched[2].as_str(), notched[4].as_str()), (".######.", ".##..##."));
+
+        // The middle of a star, inside two of its overlapping arms, is out.
+        let star: Vec<_> = (0 .. 5).map(|i| {
+            let a = (i * 2) as f32 / 5.0 * std::f32::consts::TAU;
+            Point2::new(50.0 + 40.0 * a.sin(), 50.0 - 40.0 * a.cos())
+        }).collect();
+        let mask = Mask::Polygon(star);
+        assert_eq!(mask.weight_at(50, 50), 0.0);
+        assert_eq!(mask.weight_at(50, 20), 1.0);
+        assert_eq!(mask.weight_at(2, 2), 0.0);
+    }
+
+    #[test]
+    fn images_are_resampled_to_the_render() {
+        let stencil = GrayImage::from_fn(4, 2, |x, _| image::Luma([if x < 2 { 0 } else { 255 }]));
+        let mask = MaskShape::Image(stencil).to_mask(&screen(1.0, 1.0, 0.0, 0.0), 16, 8);
+        let weights = mask.rasterize(16, 8);
+        assert_eq!(weights.len(), 16 * 8);
+        for y in 0 .. 8 {
+            let row = &weights[y * 16 .. (y + 1) * 16];
+            assert!(row[.. 6].iter().all(|&w| w == 0.0) && row[10 ..].iter().all(|&w| w == 1.0), "{:?}", row);
+            // The edge is blended, rising from left to right.
+            assert!(row.windows(2).all(|w| w[0] <= w[1]), "{:?}", row);
+        }
+        // Outside the image nothing is plotted.
+        assert_eq!(mask.weight_at(16, 0), 0.0);
+
+        let white = MaskShape::Image(GrayImage::from_pixel(3, 5, image::Luma([255]))).to_mask(&screen(1.0, 1.0, 0.0, 0.0), 7, 4);
+        assert!(white.rasterize(7, 4).iter().all(|&w| w == 1.0));
+    }
+}
@@ -0,0 +1,88 @@
+use num_traits::Float;
+
+/// The transcendental functions used by variations and tonemapping, so
+/// that they can be computed either by the platform or portably.
+pub(crate) trait Math {
+    fn sin<T: Float>(x: T) -> T;
+    fn cos<T: Float>(x: T) -> T;
+    fn tan<T: Float>(x: T) -> T;
+    fn atan<T: Float>(x: T) -> T;
+    fn atan2<T: Float>(y: T, x: T) -> T;
+    fn sinh<T: Float>(x: T) -> T;
+    fn cosh<T: Float>(x: T) -> T;
+    fn exp<T: Float>(x: T) -> T;
+    fn ln<T: Float>(x: T) -> T;
+    fn powf<T: Float>(x: T, y: T) -> T;
+}
+
+/// The standard library's functions, which are fastest but may round
+/// differently from one platform to another.
+pub(crate) struct PlatformMath;
+
+impl Math for PlatformMath {
+    fn sin<T: Float>(x: T) -> T { x.sin() }
+    fn cos<T: Float>(x: T) -> T { x.cos() }
+    fn tan<T: Float>(x: T) -> T { x.tan() }
+    fn atan<T: Float>(x: T) -> T { x.atan() }
+    fn atan2<T: Float>(y: T, x: T) -> T { y.atan2(x) }
+    fn sinh<T: Float>(x: T) -> T { x.sinh() }
+    fn cosh<T: Float>(x: T) -> T { x.cosh() }
+    fn exp<T: Float>(x: T) -> T { x.exp() }
+    fn ln<T: Float>(x: T) -> T { x.ln() }
+    fn powf<T: Float>(x: T, y: T) -> T { x.powf(y) }
+}
+
+/// Pure Rust implementations from the `libm` crate, evaluated in double
+/// precision, which give the same results on every platform.
+pub(crate) struct PortableMath;
+
+fn via<T: Float>(x: T, f: fn(f64) -> f64) -> T {
+    T::from(f(x.to_f64().unwrap())).unwrap()
+}
+
+impl Math for PortableMath {
+    fn sin<T: Float>(x: T) -> T { via(x, libm::sin) }
+    fn cos<T: Float>(x: T) -> T { via(x, libm::cos) }
+    fn tan<T: Float>(x: T) -> T { via(x, libm::tan) }
+    fn atan<T: Float>(x: T) -> T { via(x, libm::atan) }
+    fn atan2<T: Float>(y: T, x: T) -> T {
+        T::from(libm::atan2(y.to_f64().unwrap(), x.to_f64().unwrap())).unwrap()
+    }
+    fn sinh<T: Float>(x: T) -> T { via(x, libm::sinh) }
+    fn cosh<T: Float>(x: T) -> T { via(x, libm::cosh) }
+    fn exp<T: Float>(x: T) -> T { via(x, libm::exp) }
+    fn ln<T: Float>(x: T) -> T { via(x, libm::log) }
+    fn powf<T: Float>(x: T, y: T) -> T {
+        T::from(libm::pow(x.to_f64().unwrap(), y.to_f64().unwrap())).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn portable_functions_agree_with_the_platform() {
+        let xs = [-3.7, -1.0, -0.25, 0.0, 0.5, 1.0, 2.0, 10.0];
+        let close = |a: f64, b: f64| a == b || (a - b).abs() <= 1e-12 * a.abs().max(1.0);
+        for &x in &xs {
+            assert!(close(PortableMath::sin(x), PlatformMath::sin(x)), "sin {}", x);
+            assert!(close(PortableMath::cos(x), PlatformMath::cos(x)), "cos {}", x);
+            assert!(close(PortableMath::atan(x), PlatformMath::atan(x)), "atan {}", x);
+            assert!(close(PortableMath::sinh(x), PlatformMath::sinh(x)), "sinh {}", x);
+            assert!(close(PortableMath::exp(x), PlatformMath::exp(x)), "exp {}", x);
+            assert!(close(PortableMath::atan2(x, 0.75), PlatformMath::atan2(x, 0.75)), "atan2 {}", x);
+            assert!(close(PortableMath::powf(x.abs(), 0.45), PlatformMath::powf(x.abs(), 0.45)), "powf {}", x);
+            if x > 0.0 {
+                assert!(close(PortableMath::ln(x), PlatformMath::ln(x)), "ln {}", x);
+            }
+        }
+    }
+
+    #[test]
+    fn portable_functions_keep_single_precision() {
+        let x = 0.3f32;
+        assert_eq!(PortableMath::sin(x), libm::sin(0.3f32 as f64) as f32);
+        assert!(PortableMath::ln(-1.0f32).is_nan());
+    }
+}
@@ -1,19 +1,96 @@
 use image::DynamicImage;
-use nalgebra::{Affine2, Point2, Transform, Matrix3 };
+use nalgebra::{Affine2, Point2, RealField, Scalar, Transform, Matrix3 };
+use num_traits::Float;
 use rand::distributions::Uniform;
+use rand::rngs::mock::StepRng;
 use rand::prelude::*;
-use std::thread;
 
 mod variation;
 pub use variation::*;
 
 mod buffer;
-use buffer::*;
+pub use buffer::*;
 
 mod color;
 pub use color::*;
 
-#[derive(Clone, Copy)]
+mod session;
+pub use session::*;
+
+mod accumulation;
+pub use accumulation::*;
+
+mod extract;
+
+mod noise;
+pub use noise::*;
+
+mod attribution;
+pub use attribution::*;
+
+mod font;
+pub use font::*;
+
+mod analysis;
+pub use analysis::*;
+
+mod affine;
+pub use affine::*;
+
+mod pacing;
+pub use pacing::*;
+
+mod lerp;
+pub use lerp::*;
+
+mod gradient;
+pub use gradient::*;
+
+mod preflight;
+pub use preflight::*;
+
+mod render_preset;
+pub use render_preset::*;
+
+mod dither;
+pub use dither::*;
+
+mod mask;
+pub use mask::*;
+
+mod projection;
+pub use projection::*;
+
+mod diagnostics;
+pub use diagnostics::*;
+
+mod curves;
+pub use curves::*;
+
+mod lighting;
+pub use lighting::*;
+
+mod audit;
+pub use audit::*;
+
+mod function_stats;
+pub use function_stats::*;
+
+mod morphology;
+pub use morphology::*;
+
+mod content_hash;
+pub use content_hash::*;
+
+mod watchdog;
+pub use watchdog::*;
+
+mod summary;
+pub use summary::*;
+
+pub(crate) mod math;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Bounds {
     x_min: f32,
     x_max: f32,
@@ -26,10 +103,43 @@ impl Bounds {
         Bounds { x_min, x_max, y_min, y_max }
     }
 
-    pub fn contains(&self, p: &Point2<f32>) -> bool {
+    /// A window of width `2 / scale` centered on `center`, with the given
+    /// ratio of width to height.
+    pub fn from_center_scale(center: [f32; 2], scale: f32, aspect: f32) -> Self {
+        let half_width = 1.0 / scale;
+        let half_height = half_width / aspect;
+        Bounds {
+            x_min: center[0] - half_width,
+            x_max: center[0] + half_width,
+            y_min: center[1] - half_height,
+            y_max: center[1] + half_height,
+        }
+    }
+
+    pub fn center(&self) -> [f32; 2] {
+        [(self.x_min + self.x_max) / 2.0, (self.y_min + self.y_max) / 2.0]
+    }
+
+    /// The inverse of half the window's width.
+    pub fn scale(&self) -> f32 {
+        2.0 / self.width()
+    }
+
+    /// Ratio of the window's width to its height.
+    pub fn aspect(&self) -> f32 {
+        self.width() / self.height()
+    }
+
+    /// The edges of the window, as `[x_min, x_max, y_min, y_max]`.
+    pub fn to_array(&self) -> [f32; 4] {
+        [self.x_min, self.x_max, self.y_min, self.y_max]
+    }
+
+    pub fn contains<T: Float + Scalar>(&self, p: &Point2<T>) -> bool {
         let x = p[0];
         let y = p[1];
-        x > self.x_min && x < self.x_max && y > self.y_min && y < self.y_max
+        let [x_min, x_max, y_min, y_max] = self.to_array().map(|e| T::from(e).unwrap());
+        x > x_min && x < x_max && y > y_min && y < y_max
     }
 
     pub fn width(&self) -> f32 {
@@ -46,111 +156,1359 @@ pub struct Flame {
     pub functions: Vec<Function>,
     pub palette: Palette,
     pub bounds: Bounds,
+    /// Region outside of which points are not plotted.
+    pub mask: Option<MaskShape>,
+    pub color_model: ColorModel,
+}
+
+/// How an orbit's position along the palette follows the functions applied.
+///
+/// Both models move the position halfway to the color of each function
+/// applied. They differ in the details, which are enough to color flames
+/// made for flam3 noticeably differently.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ColorModel {
+    /// The position is a palette index, rounded down after every step, and
+    /// is updated even when the function sends the orbit off to infinity.
+    #[default]
+    Native,
+    /// As flam3 does: the position is continuous, and is blended with the
+    /// function's color before the point is transformed, the blend being
+    /// kept only if the transformed point is finite. Orbits restarted after
+    /// leaving the finite plane keep the color they had.
+    Flam3,
+}
+
+impl ColorModel {
+    /// Names of the models, as accepted by `from_str`.
+    pub const NAMES: [&'static str; 2] = ["native", "flam3"];
 }
 
+impl std::str::FromStr for ColorModel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "native" => Ok(ColorModel::Native),
+            "flam3" => Ok(ColorModel::Flam3),
+            _ => Err(format!("unknown color model '{}' (expected native or flam3)", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for ColorModel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ColorModel::Native => write!(f, "native"),
+            ColorModel::Flam3 => write!(f, "flam3"),
+        }
+    }
+}
+
+/// Parameters controlling how the chaos game is run.
 #[derive(Clone, Copy)]
-pub struct RenderConfig {
+pub struct RunConfig {
     pub width: usize,
     pub height: usize,
     pub iters: usize,
     pub threads: usize,
-    pub grayscale: bool,
-    pub gamma: f64,
-    pub preserve_color: bool,
-    pub vibrancy: f64,
+    /// Seed for the random number generators. Runs with the same seed and
+    /// number of threads produce identical histograms, as do runs with the
+    /// same seed and any number of threads if `stable_chunk_iters` is set.
+    pub seed: Option<u64>,
+    /// Stop before `iters` once the image stops changing.
+    pub stop_when: Option<StopCondition>,
+    /// Estimate the noise in each bucket's hit count as the run progresses.
+    /// This doubles the memory used by the histogram.
+    pub track_variance: bool,
+    /// Stop before `iters` once the image is estimated to be accurate enough.
+    /// Takes precedence over `stop_when`.
+    pub quality: Option<QualityTarget>,
+    /// Pin each thread to its own core, and allocate its histogram from
+    /// there, so that on machines with several memory nodes every thread
+    /// writes to local memory. Ignored where threads cannot be pinned, and
+    /// when there is only one thread.
+    pub pin_threads: bool,
+    /// How each iteration is drawn into the histogram.
+    pub plot_mode: PlotMode,
+    /// Largest number of pixels the image may have, to catch mistaken
+    /// dimensions before the histograms are allocated.
+    pub max_pixels: usize,
+    /// Also color points by how far through the run they were plotted.
+    pub temporal_color: Option<TemporalColor>,
+    /// Only count hits, leaving the color channels of the histogram empty.
+    /// This is all a grayscale render needs, and is faster.
+    pub monochrome: bool,
+    /// Compute variations with portable implementations of sin, exp and the
+    /// like, so that seeded runs give the same histogram on every platform.
+    /// This makes iterating somewhat slower.
+    pub deterministic_math: bool,
+    /// Precision of the orbits' coordinates.
+    pub precision: Precision,
+    /// Number of independent random starts each thread divides its share
+    /// of `iters` between, at least one. More starts reach every part of an
+    /// attractor whose parts a single orbit rarely moves between, and make
+    /// short previews less streaky.
+    pub restarts_per_thread: u32,
+    /// Memory layout of the threads' histograms while they are plotted.
+    /// The Morton layout keeps nearby pixels together, which may make large
+    /// renders faster.
+    pub layout: Layout,
+    /// Check every iteration for broken invariants, such as points off the
+    /// buffer or buckets overflowing, recording each instead of panicking
+    /// or going on silently. This is much slower, and meant for developing
+    /// variations and descriptors.
+    pub paranoid: bool,
+    /// Run the threads at the lowest priority, where the platform allows,
+    /// so that they only use processor time nothing else wants.
+    pub nice: bool,
+    /// Fraction of the machine's processor time, between 0 and 1, the
+    /// threads may spend iterating between them. Threads pause between
+    /// chunks of iterations to keep to it, which leaves seeded histograms
+    /// unchanged.
+    pub cpu_limit: Option<f64>,
+    /// Count how often each function is applied and where its points land.
+    /// This costs little, and is kept by each thread separately.
+    pub per_function_stats: bool,
+    /// Split the run into chunks of this many iterations, each a fresh
+    /// orbit with a random stream of its own derived from the seed and the
+    /// chunk's index, which threads take in turn. The histogram is then the
+    /// same whatever the number of threads.
+    ///
+    /// Every chunk warms up again from a random point, discarding a few
+    /// more iterations than a run with a single orbit per thread would,
+    /// which is negligible unless chunks are very short. `restarts_per_thread`
+    /// has no effect, as chunks already start afresh.
+    pub stable_chunk_iters: Option<u64>,
+    /// Also keep a histogram for each of this many equal bands of palette
+    /// position, holding only the points colored from that band, which
+    /// together add up to the full histogram. Each band costs as much
+    /// memory as the full histogram.
+    pub split_bands: Option<usize>,
+    /// Count the hits each function contributes to each bucket, for an
+    /// attribution map. Each thread keeps four bytes per function per
+    /// pixel, and flames with more than `MAX_ATTRIBUTED_FUNCTIONS`
+    /// functions are not attributed.
+    pub attribution: bool,
+    /// How the flame's plane is mapped into the image. Masks apply to the
+    /// projected image, and strokes cannot be projected.
+    pub projection: Projection,
+    /// Iterations each orbit runs from a random start before it plots.
+    pub fuse: FuseMode,
+    /// Watch the threads for any which stop making progress, warning of
+    /// them and cancelling the run if one stops for too long. Watched runs
+    /// always iterate on threads of their own.
+    pub watchdog: Option<Watchdog>,
 }
 
-impl Flame {
-    pub fn run(&self, cfg: RenderConfig) -> Buffer<u32> {
-        thread::scope(|s| {
-            let mut handles = Vec::new();
-        
-            for _ in 0 .. cfg.threads {
-                handles.push(s.spawn(|| self.run_single(cfg)));
-            }
-        
-            Buffer::combine(handles.into_iter().map(|h| h.join().unwrap()))
-        })
+/// Default limit on the number of pixels in an image.
+pub const DEFAULT_MAX_PIXELS: usize = 1 << 31;
+
+impl Default for RunConfig {
+    /// An empty run on one unseeded thread, plotting points with every
+    /// option off. Callers set the size and number of iterations.
+    fn default() -> Self {
+        RunConfig {
+            width: 0,
+            height: 0,
+            iters: 0,
+            threads: 1,
+            seed: None,
+            stop_when: None,
+            track_variance: false,
+            quality: None,
+            pin_threads: false,
+            plot_mode: PlotMode::Points,
+            max_pixels: DEFAULT_MAX_PIXELS,
+            temporal_color: None,
+            monochrome: false,
+            deterministic_math: false,
+            precision: Precision::Auto,
+            restarts_per_thread: 1,
+            layout: Layout::RowMajor,
+            paranoid: false,
+            nice: false,
+            cpu_limit: None,
+            per_function_stats: false,
+            stable_chunk_iters: None,
+            split_bands: None,
+            attribution: false,
+            projection: Projection::None,
+            fuse: FuseMode::default(),
+            watchdog: None,
+        }
     }
+}
 
-    fn run_single(&self, cfg: RenderConfig) -> Buffer<u32> {
-        let mut buffer: Buffer<u32> = Buffer::new(cfg.width, cfg.height);
-        let trans = self.screen_transform(cfg);
+impl RunConfig {
+    /// Number of chunks the run is split into, if it is split into any.
+    pub fn stable_chunks(&self) -> Option<u64> {
+        self.stable_chunk_iters.map(|size| (self.iters as u64).div_ceil(size.max(1)))
+    }
 
-        let mut rng = thread_rng();
+    /// Check that the image is at least 2 pixels wide and high, and within
+    /// `max_pixels`.
+    pub fn check_size(&self) -> Result<(), BufferError> {
+        if self.width < 2 || self.height < 2 {
+            return Err(BufferError::TooSmall { width: self.width, height: self.height });
+        }
+        match self.width.checked_mul(self.height) {
+            Some(pixels) if pixels <= self.max_pixels => Ok(()),
+            _ => Err(BufferError::TooLarge {
+                width: self.width,
+                height: self.height,
+                max_pixels: self.max_pixels,
+            }),
+        }
+    }
+}
 
-        let mut point = Point2::new(rng.gen(), rng.gen());
-        let mut c: u8 = rng.gen();
+/// How the points of an orbit are drawn into the histogram.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum PlotMode {
+    /// Plot each point on its own.
+    #[default]
+    Points,
+    /// Join consecutive points with line segments, to show how the orbit
+    /// moves. This is a diagnostic and not meant for final renders.
+    Strokes {
+        /// Number of consecutive points joined before a stroke is restarted.
+        length: u8,
+        /// Weight of each segment per pixel of length, between 0 and 1.
+        attenuation: f32,
+    },
+}
 
-        for i in 0 .. (cfg.iters / cfg.threads) {
-            let f = self.rand_func(&mut rng);
+impl PlotMode {
+    /// Names of the modes, as accepted by `from_str`.
+    pub const NAMES: [&'static str; 2] = ["points", "strokes"];
+}
 
-            point = f.eval(point);
-            c = (c + f.color) / 2;
+impl std::str::FromStr for PlotMode {
+    type Err = String;
 
-            if i > 20 && self.bounds.contains(&point) {
-                let screen_point = trans * point;
-                let bucket = buffer.at_mut(screen_point);
-                let color = self.palette.sample(c);
-                bucket.alpha += 1;
-                bucket.red += color.red as u32;
-                bucket.green += color.green as u32;
-                bucket.blue += color.blue as u32;
+    /// Parses `points` or `strokes`, optionally followed by the stroke
+    /// length and attenuation as in `strokes:8:0.1`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(':');
+        let err = || format!("invalid plot mode '{}' (expected points or strokes[:LENGTH[:ATTENUATION]])", s);
+        match parts.next().map(str::to_ascii_lowercase).as_deref() {
+            Some("points") if parts.next().is_none() => Ok(PlotMode::Points),
+            Some("strokes") => {
+                let length = parts.next().map_or(Ok(8), str::parse).map_err(|_| err())?;
+                let attenuation = parts.next().map_or(Ok(0.1), str::parse).map_err(|_| err())?;
+                if parts.next().is_some() || length < 2 || !(0.0 ..= 1.0).contains(&attenuation) {
+                    return Err(err());
+                }
+                Ok(PlotMode::Strokes { length, attenuation })
             }
+            _ => Err(err()),
         }
+    }
+}
 
-        buffer
+/// Floating point precision of the coordinates of orbits. The histogram is
+/// the same either way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Precision {
+    /// Single precision, unless the window is so small that neighboring
+    /// pixels would be too few single precision values apart.
+    #[default]
+    Auto,
+    F32,
+    /// Double precision, for deep zooms. Iterating is somewhat slower.
+    F64,
+}
+
+/// Fewest distinct single precision coordinates a pixel may span before
+/// `Precision::Auto` chooses double precision.
+const MIN_F32_STEPS_PER_PIXEL: f32 = 64.0;
+
+impl Precision {
+    /// Names of the precisions, as accepted by `from_str`.
+    pub const NAMES: [&'static str; 3] = ["auto", "f32", "f64"];
+
+    /// The precision to render `bounds` in at `width` by `height` pixels,
+    /// which is never `Auto`.
+    pub fn resolve(self, bounds: &Bounds, width: usize, height: usize) -> Precision {
+        match self {
+            Precision::Auto => {
+                let pixel = f32::min(
+                    bounds.width() / width.saturating_sub(1).max(1) as f32,
+                    bounds.height() / height.saturating_sub(1).max(1) as f32,
+                );
+                // Spacing of single precision values at the largest coordinate
+                // in the window, where it is widest.
+                let magnitude = bounds.to_array().into_iter().fold(0.0, |m: f32, e| m.max(e.abs()));
+                let step = magnitude * f32::EPSILON;
+                if pixel < step * MIN_F32_STEPS_PER_PIXEL { Precision::F64 } else { Precision::F32 }
+            }
+            p => p,
+        }
     }
+}
 
-    pub fn render(&self, cfg: RenderConfig) -> DynamicImage {
-        let mut buffer: Buffer<f64> = self.run(cfg).convert();
-        buffer.log_density();
-        buffer.normalize(cfg.preserve_color);
-        buffer.gamma(cfg.gamma, cfg.vibrancy);
-        buffer.normalize(cfg.preserve_color);
-        let image_buf = buffer.scale_convert();
+impl std::str::FromStr for Precision {
+    type Err = String;
 
-        if cfg.grayscale {
-            DynamicImage::ImageLuma8(image_buf.into_gray8())
-        } else {
-            DynamicImage::ImageRgb8(image_buf.into_rgb8())
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "auto" => Ok(Precision::Auto),
+            "f32" => Ok(Precision::F32),
+            "f64" => Ok(Precision::F64),
+            _ => Err(format!("unknown precision '{}' (expected auto, f32 or f64)", s)),
         }
     }
+}
 
-    fn rand_func(&self, rng: &mut impl Rng) -> &Function {
-        let r = Uniform::new(0.0, 1.0).sample(rng);
-        let mut x = 0.0;
-        for f in &self.functions {
-            x += f.weight;
-            if r < x {
-                return f;
+impl std::fmt::Display for Precision {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Precision::Auto => write!(f, "auto"),
+            Precision::F32 => write!(f, "f32"),
+            Precision::F64 => write!(f, "f64"),
+        }
+    }
+}
+
+/// Number of iterations each orbit runs from a random start before it
+/// plots, unless told otherwise.
+pub const DEFAULT_FUSE: u32 = 21;
+
+/// How many iterations each orbit runs from a random start before it
+/// plots, so that its first points are already close to the attractor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FuseMode {
+    Fixed(u32),
+    /// As long as the flame's measured contraction takes to bring a start
+    /// close enough to the attractor, by `ContractivityReport::auto_fuse`.
+    /// Flames which do not contract on average get `DEFAULT_FUSE`.
+    Auto,
+}
+
+impl Default for FuseMode {
+    fn default() -> Self {
+        FuseMode::Fixed(DEFAULT_FUSE)
+    }
+}
+
+impl FuseMode {
+    /// The fuse to render `flame` with, which is never `Auto`.
+    pub fn resolve(self, flame: &Flame) -> FuseMode {
+        match self {
+            FuseMode::Auto => {
+                // Flames which cannot be rendered are not measured.
+                let valid = flame.validate().iter().all(|f| f.severity < Severity::Error);
+                let fuse = valid.then(|| flame.contractivity_report().auto_fuse()).flatten();
+                FuseMode::Fixed(fuse.unwrap_or(DEFAULT_FUSE))
             }
+            fixed => fixed,
+        }
+    }
+
+    /// Number of iterations before plotting, `DEFAULT_FUSE` if not resolved.
+    pub fn iters(self) -> u64 {
+        match self {
+            FuseMode::Fixed(n) => n as u64,
+            FuseMode::Auto => DEFAULT_FUSE as u64,
+        }
+    }
+}
+
+impl std::str::FromStr for FuseMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("auto") {
+            return Ok(FuseMode::Auto);
+        }
+        s.parse().map(FuseMode::Fixed)
+            .map_err(|_| format!("invalid fuse '{}' (expected a number of iterations or auto)", s))
+    }
+}
+
+impl std::fmt::Display for FuseMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FuseMode::Fixed(n) => write!(f, "{}", n),
+            FuseMode::Auto => write!(f, "auto"),
+        }
+    }
+}
+
+/// Coloring of points by when they were plotted as well as by the orbit's
+/// color coordinate, so that early and late iterations are laid down in
+/// different hues like a long exposure.
+///
+/// Progress is measured per thread, as the fraction of the thread's share
+/// of `RunConfig::iters` it has run. Runs which stop early never reach the
+/// end of the palette, and neither does an open-ended session.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TemporalColor {
+    /// Weight of the palette color at the current progress against the
+    /// color the point would otherwise have, between 0 and 1.
+    pub blend: f32,
+}
+
+/// Criterion for ending a run once the estimated Monte Carlo error of the
+/// histogram is small enough.
+#[derive(Clone, Copy)]
+pub struct QualityTarget {
+    /// Mean relative standard error of the hit counts of the buckets which
+    /// have been hit, below which the run stops.
+    pub mean_rel_err: f64,
+    /// Number of iterations between estimates, at least one.
+    pub check_interval_iters: u64,
+}
+
+/// Criterion for ending a run early once extra iterations no longer change
+/// the image.
+#[derive(Clone, Copy)]
+pub struct StopCondition {
+    /// Relative change in a low resolution proxy of the image between
+    /// checks, below which the run stops.
+    pub rel_change_below: f64,
+    /// Number of iterations between checks, at least one.
+    pub check_interval_iters: u64,
+    /// Number of iterations to run before the run may stop.
+    pub min_iters: u64,
+}
+
+/// Parameters controlling how the histogram is turned into an image.
+///
+/// This is a `ToneMapping` and an `OutputEncoding` together, as most
+/// renders need. Renders which write one histogram in several encodings
+/// should split it with `tone_mapping` and `output_encoding` instead, so
+/// that the histogram is only tone mapped once.
+#[deprecated(note = "use `ToneMapping` and `OutputEncoding`, which `tone_mapping` and `output_encoding` convert this into")]
+#[derive(Clone, Copy)]
+pub struct RenderConfig {
+    pub grayscale: bool,
+    pub gamma: f64,
+    pub preserve_color: bool,
+    pub vibrancy: f64,
+    /// Color shown where nothing was plotted.
+    pub background: Color,
+    pub highlights: HighlightMode,
+    pub dither: DitherMode,
+    /// Lift sparse regions relative to dense ones before gamma correction.
+    pub filament_boost: Option<FilamentBoost>,
+    /// Shade the density as a lit relief before the background is added.
+    pub lighting: Option<Lighting>,
+    /// Tone curves applied to the finished image before it is quantized.
+    pub curves: Option<ChannelCurves>,
+    /// Tonemap with portable implementations of the transcendental
+    /// functions, so the same histogram gives the same image everywhere.
+    pub deterministic_math: bool,
+}
+
+#[allow(deprecated)]
+impl RenderConfig {
+    pub fn tone_mapping(&self) -> ToneMapping {
+        ToneMapping {
+            gamma: self.gamma,
+            vibrancy: self.vibrancy,
+            preserve_color: self.preserve_color,
+            filament_boost: self.filament_boost,
+            lighting: self.lighting,
+            background: self.background,
+            highlights: self.highlights,
+            curves: self.curves,
+            deterministic_math: self.deterministic_math,
         }
-    
-        &self.functions.iter().last().unwrap()
     }
 
-    fn screen_transform(&self, cfg: RenderConfig) -> Affine2<f32> {
-        let w_scale = (cfg.width - 1) as f32 / self.bounds.width();
-        let h_scale =  (cfg.height - 1) as f32 / self.bounds.height();
+    pub fn output_encoding(&self) -> OutputEncoding {
+        OutputEncoding { grayscale: self.grayscale, dither: self.dither, depth: BitDepth::Eight }
+    }
+}
+
+#[allow(deprecated)]
+impl Default for RenderConfig {
+    /// The default `ToneMapping` encoded by the default `OutputEncoding`.
+    fn default() -> Self {
+        let (tone, encoding) = (ToneMapping::default(), OutputEncoding::default());
+        RenderConfig {
+            grayscale: encoding.grayscale,
+            gamma: tone.gamma,
+            preserve_color: tone.preserve_color,
+            vibrancy: tone.vibrancy,
+            background: tone.background,
+            highlights: tone.highlights,
+            dither: encoding.dither,
+            filament_boost: tone.filament_boost,
+            lighting: tone.lighting,
+            curves: tone.curves,
+            deterministic_math: tone.deterministic_math,
+        }
+    }
+}
+
+/// The look of a render: how a histogram's densities become display
+/// values between 0 and 1.
+///
+/// The background is part of the look rather than of the encoding, as
+/// highlights and curves act on the image composited over it.
+#[derive(Clone, Copy)]
+pub struct ToneMapping {
+    pub gamma: f64,
+    pub vibrancy: f64,
+    pub preserve_color: bool,
+    /// Lift sparse regions relative to dense ones before gamma correction.
+    pub filament_boost: Option<FilamentBoost>,
+    /// Shade the density as a lit relief before the background is added.
+    pub lighting: Option<Lighting>,
+    /// Color shown where nothing was plotted.
+    pub background: Color,
+    pub highlights: HighlightMode,
+    /// Tone curves applied to the finished image.
+    pub curves: Option<ChannelCurves>,
+    /// Use portable implementations of the transcendental functions, so
+    /// the same histogram gives the same image everywhere.
+    pub deterministic_math: bool,
+}
+
+impl Default for ToneMapping {
+    /// The look of the `standard` preset over a black background, with
+    /// every optional stage off.
+    fn default() -> Self {
+        let standard = RenderPreset::STANDARD;
+        ToneMapping {
+            gamma: standard.gamma,
+            vibrancy: standard.vibrancy,
+            preserve_color: false,
+            filament_boost: None,
+            lighting: None,
+            background: Color::rgb(0, 0, 0),
+            highlights: standard.highlights,
+            curves: None,
+            deterministic_math: false,
+        }
+    }
+}
+
+/// Number format of an encoded image's channels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BitDepth {
+    /// 8-bit integers, dithered.
+    Eight,
+    /// 32-bit floats holding the tone mapped values unquantized, for
+    /// formats such as OpenEXR.
+    Float,
+}
+
+/// How a tone mapped image is stored.
+#[derive(Clone, Copy)]
+pub struct OutputEncoding {
+    /// Keep only the gray level of each pixel.
+    pub grayscale: bool,
+    /// Dithering added before 8-bit channels are quantized.
+    pub dither: DitherMode,
+    pub depth: BitDepth,
+}
+
+impl Default for OutputEncoding {
+    /// Undithered 8-bit color.
+    fn default() -> Self {
+        OutputEncoding { grayscale: false, dither: DitherMode::None, depth: BitDepth::Eight }
+    }
+}
+
+impl OutputEncoding {
+    /// Encode an image tone mapped by `Buffer::tone_map`.
+    pub fn encode(&self, image: &Buffer<f64>) -> Result<DynamicImage, BufferError> {
+        match self.depth {
+            BitDepth::Eight => image.scale_convert::<u8>(self.dither).to_image(self.grayscale),
+            BitDepth::Float => Ok(DynamicImage::ImageRgb32F(image.to_rgb32f(self.grayscale)?)),
+        }
+    }
+}
+
+impl Flame {
+    /// Whether two flames have the same functions, palette, bounds, mask
+    /// and color model. Any metadata of the descriptors they were read from
+    /// is not considered.
+    pub fn eq_structural(&self, other: &Flame) -> bool {
+        self.functions == other.functions
+            && self.palette == other.palette
+            && self.bounds == other.bounds
+            && self.mask == other.mask
+            && self.color_model == other.color_model
+    }
+
+    pub fn run(&self, cfg: RunConfig) -> Result<Buffer<u32>, BufferError> {
+        let mut session = RenderSession::new(self.clone(), cfg)?;
+        session.run();
+        Ok(session.into_buffer())
+    }
+
+    #[deprecated(note = "use `render_encoded`")]
+    #[allow(deprecated)]
+    pub fn render(&self, run_cfg: RunConfig, cfg: RenderConfig) -> Result<DynamicImage, BufferError> {
+        self.render_encoded(run_cfg, &cfg.tone_mapping(), &cfg.output_encoding())
+    }
+
+    /// Run the chaos game, tone map the histogram and encode it.
+    pub fn render_encoded(
+        &self,
+        run_cfg: RunConfig,
+        tone: &ToneMapping,
+        encoding: &OutputEncoding,
+    ) -> Result<DynamicImage, BufferError> {
+        encoding.encode(&self.run(run_cfg)?.tone_map(tone))
+    }
+
+    fn screen_transform<T: RealField + Copy>(&self, cfg: RunConfig) -> Affine2<T> {
+        let c = |v: f32| T::from_subset(&(v as f64));
+        let [x_min, x_max, y_min, y_max] = self.bounds.to_array().map(c);
+        let w_scale = c((cfg.width - 1) as f32) / (x_max - x_min);
+        let h_scale = c((cfg.height - 1) as f32) / (y_max - y_min);
         Transform::from_matrix_unchecked(Matrix3::new(
-            w_scale, 0., -self.bounds.x_min * w_scale,
-            0., -h_scale, self.bounds.y_max * h_scale,
-            0., 0., 1.
+            w_scale, T::zero(), -x_min * w_scale,
+            T::zero(), -h_scale, y_max * h_scale,
+            T::zero(), T::zero(), T::one(),
         ))
     }
 }
 
-#[derive(Copy, Clone)]
+/// How far the weights of a flame's functions may sum from one before the
+/// sum is reported when read, allowing for rounding in descriptors written
+/// by hand. Functions are chosen by their share of the total whatever it is.
+pub(crate) const WEIGHT_TOLERANCE: f32 = 1e-3;
+
+/// Chooses functions at random by weight, from sums of the weights made
+/// once in double precision.
+///
+/// Each function is chosen with probability exactly its weight's share of
+/// the total, so rounding in the sums favors none of them.
+#[derive(Debug, Clone)]
+pub(crate) struct FunctionSelector {
+    cumulative: Vec<f64>,
+    /// For each sum, the fewest random bits of a draw from `[0, total)`
+    /// which reach it, so that draws are compared as the bits they are made
+    /// from.
+    thresholds: Vec<u64>,
+}
+
+/// Random bits in a draw of a double from a range, which are the top bits
+/// of a `u64`.
+const DRAW_BITS: u32 = 52;
+
+impl FunctionSelector {
+    /// `None` if there are no functions to choose from, or their weights
+    /// do not add up to a positive, finite total.
+    pub(crate) fn new(functions: &[Function]) -> Option<Self> {
+        let cumulative: Vec<f64> = functions.iter()
+            .scan(0.0, |sum, f| {
+                *sum += f.weight as f64;
+                Some(*sum)
+            })
+            .collect();
+        let total = *cumulative.last()?;
+        if !(total.is_finite() && total > 0.0) {
+            return None;
+        }
+        // Draws only grow with their bits, so the bits reaching each sum
+        // are found by bisection, making each candidate into a draw just as
+        // `Uniform` would.
+        let draw = Uniform::new(0.0, total);
+        let at = |bits: u64| draw.sample(&mut StepRng::new(bits << (64 - DRAW_BITS), 0));
+        let thresholds = cumulative.iter()
+            .map(|&x| {
+                let (mut low, mut high) = (0, 1 << DRAW_BITS);
+                while low < high {
+                    let mid = low + (high - low) / 2;
+                    if at(mid) >= x {
+                        high = mid;
+                    } else {
+                        low = mid + 1;
+                    }
+                }
+                low
+            })
+            .collect();
+        Some(FunctionSelector { cumulative, thresholds })
+    }
+
+    /// Index of a function chosen at random by weight, the first whose sum
+    /// is above a draw from `[0, total)`.
+    #[inline]
+    pub(crate) fn sample(&self, rng: &mut impl Rng) -> usize {
+        // The draw is below the total, which is the last sum, so some sum
+        // is always above it. Counting the sums it reaches, rather than
+        // searching them, leaves the chaos game no chain of comparisons to
+        // wait on.
+        let bits = rng.next_u64() >> (64 - DRAW_BITS);
+        self.thresholds.iter().filter(|&&t| t <= bits).count()
+    }
+
+    /// The chance of choosing each function.
+    pub(crate) fn probabilities(&self) -> Vec<f64> {
+        let total = *self.cumulative.last().expect("selectors have functions");
+        let mut reached = 0.0;
+        self.cumulative.iter()
+            .map(|&x| {
+                let chance = (x - reached) / total;
+                reached = x;
+                chance
+            })
+            .collect()
+    }
+}
+
+impl Flame {
+    /// A function chosen at random by weight, as in each iteration of the
+    /// chaos game. `None` if there are no functions, or their weights do not
+    /// add up to a positive, finite total.
+    pub fn choose_function(&self, rng: &mut impl Rng) -> Option<&Function> {
+        let selector = FunctionSelector::new(&self.functions)?;
+        Some(&self.functions[selector.sample(rng)])
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
 pub struct Function {
     pub weight: f32,
     pub color: u8,
     pub var: Variation,
     pub trans: Affine2<f32>,
+    /// How much of the variation is applied along x and y, from none at 0,
+    /// leaving the affine transform's output on that axis, to all at 1.
+    /// Blending the axes differently shears the variation in one direction.
+    pub axis_blend: [f32; 2],
 }
 
 impl Function {
+    /// The variation applied fully on both axes.
+    pub const FULL_BLEND: [f32; 2] = [1.0, 1.0];
+
     pub fn eval(&self, arg: Point2<f32>) -> Point2<f32> {
-        self.var.eval(self.trans * arg)
+        let p = self.trans * arg;
+        self.blend(p, self.var.eval(p))
+    }
+
+    /// Evaluate the function with the transcendental functions of `M`, in
+    /// the precision of `T`.
+    fn eval_with<M: math::Math, T: Float + RealField>(&self, arg: Point2<T>) -> Point2<T> {
+        let trans: Affine2<T> = Transform::from_matrix_unchecked(self.trans.matrix().map(|v| T::from_subset(&(v as f64))));
+        let p = trans * arg;
+        self.blend(p, self.var.eval_with::<M, T>(p))
+    }
+
+    /// Move each axis of the variation's input `p` towards its output `v`
+    /// by that axis's blend. Fully blended axes are `v` exactly.
+    fn blend<T: Float + RealField>(&self, p: Point2<T>, v: Point2<T>) -> Point2<T> {
+        if self.axis_blend == Function::FULL_BLEND {
+            return v;
+        }
+        let axis = |i: usize| {
+            let b = self.axis_blend[i];
+            if b == 1.0 { v[i] } else { p[i] + (v[i] - p[i]) * T::from_subset(&(b as f64)) }
+        };
+        Point2::new(axis(0), axis(1))
+    }
+}
+
+impl Buffer<u32> {
+    #[deprecated(note = "use `render_8bit`")]
+    #[allow(deprecated)]
+    pub fn render(&self, cfg: RenderConfig) -> Buffer<u8> {
+        self.render_8bit(&cfg.tone_mapping(), cfg.dither)
+    }
+
+    #[deprecated(note = "use `render_8bit_with_stats`")]
+    #[allow(deprecated)]
+    pub fn render_with_stats(&self, cfg: RenderConfig) -> (Buffer<u8>, ClipStats) {
+        self.render_8bit_with_stats(&cfg.tone_mapping(), cfg.dither)
+    }
+
+    /// Tonemap an accumulated histogram into an 8-bit buffer, dithered
+    /// with `dither`.
+    pub fn render_8bit(&self, tone: &ToneMapping, dither: DitherMode) -> Buffer<u8> {
+        self.render_8bit_with_stats(tone, dither).0
+    }
+
+    /// `render_8bit`, also reporting how much of the image was too bright
+    /// to display before highlights were handled.
+    pub fn render_8bit_with_stats(&self, tone: &ToneMapping, dither: DitherMode) -> (Buffer<u8>, ClipStats) {
+        let (image, stats) = self.tone_map_with_stats(tone);
+        (image.scale_convert(dither), stats)
+    }
+
+    /// Tone map an accumulated histogram into display values, which
+    /// `OutputEncoding::encode` can then encode any number of ways.
+    pub fn tone_map(&self, tone: &ToneMapping) -> Buffer<f64> {
+        self.tone_map_with_stats(tone).0
+    }
+
+    /// `tone_map`, also reporting how much of the image was too bright to
+    /// display before highlights were handled.
+    pub fn tone_map_with_stats(&self, tone: &ToneMapping) -> (Buffer<f64>, ClipStats) {
+        if tone.deterministic_math {
+            self.tonemap::<math::PortableMath>(tone)
+        } else {
+            self.tonemap::<math::PlatformMath>(tone)
+        }
+    }
+
+    #[deprecated(note = "use `render_bands_8bit`")]
+    #[allow(deprecated)]
+    pub fn render_bands(&self, bands: &[Buffer<u32>], cfg: RenderConfig) -> Vec<Buffer<u8>> {
+        self.render_bands_8bit(bands, &cfg.tone_mapping(), cfg.dither)
+    }
+
+    /// Tonemap the histograms of palette bands which add up to this one,
+    /// as kept by `RunConfig::split_bands`, into 8-bit layers.
+    ///
+    /// Each band's share of a pixel's channels is given that share of the
+    /// pixel's tonemapped value, so that the layers add up to this
+    /// histogram's image over black. Layers are not composited over the
+    /// background or given highlights or curves, which would break the sum.
+    pub fn render_bands_8bit(&self, bands: &[Buffer<u32>], tone: &ToneMapping, dither: DitherMode) -> Vec<Buffer<u8>> {
+        let toned = if tone.deterministic_math {
+            self.tone::<math::PortableMath>(tone)
+        } else {
+            self.tone::<math::PlatformMath>(tone)
+        };
+        let share = |t: f64, part: u32, whole: u32| if whole > 0 { t * part as f64 / whole as f64 } else { 0.0 };
+        bands.iter().map(|band| {
+            let buckets = toned.buckets().iter().zip(band.buckets()).zip(self.buckets())
+                .map(|((t, b), c)| Bucket {
+                    alpha: share(t.alpha, b.alpha, c.alpha),
+                    red: share(t.red, b.red, c.red),
+                    green: share(t.green, b.green, c.green),
+                    blue: share(t.blue, b.blue, c.blue),
+                })
+                .collect();
+            Buffer::from_buckets(self.width(), self.height(), buckets)
+                .expect("bands have the histogram's dimensions")
+                .scale_convert(dither)
+        }).collect()
+    }
+
+    /// Density and gamma, normalized, before the background is added.
+    fn tone<M: math::Math>(&self, cfg: &ToneMapping) -> Buffer<f64> {
+        let mut buffer: Buffer<f64> = self.clone().convert();
+        buffer.log_density_with::<M>();
+        buffer.normalize(cfg.preserve_color);
+        if let Some(boost) = cfg.filament_boost {
+            buffer.filament_boost_with::<M>(boost);
+        }
+        buffer.gamma_with::<M>(cfg.gamma, cfg.vibrancy);
+        buffer.normalize(cfg.preserve_color);
+        buffer
+    }
+
+    fn tonemap<M: math::Math>(&self, cfg: &ToneMapping) -> (Buffer<f64>, ClipStats) {
+        #[cfg(test)]
+        tests::TONE_MAPS.with(|n| n.set(n.get() + 1));
+        let mut buffer = self.tone::<M>(cfg);
+        if let Some(light) = cfg.lighting {
+            buffer.emboss_light_with::<M>(light);
+        }
+        let bg = cfg.background;
+        buffer.composite(&Bucket {
+            alpha: bg.to_gray() as f64 / 255.,
+            red: bg.red as f64 / 255.,
+            green: bg.green as f64 / 255.,
+            blue: bg.blue as f64 / 255.,
+        });
+        let stats = buffer.highlights_with::<M>(cfg.highlights);
+        if let Some(curves) = &cfg.curves {
+            buffer.curves_with::<M>(curves);
+        }
+        (buffer, stats)
+    }
+}
+
+impl Buffer<u8> {
+    pub fn to_image(&self, grayscale: bool) -> Result<DynamicImage, BufferError> {
+        Ok(if grayscale {
+            DynamicImage::ImageLuma8(self.to_gray8()?)
+        } else {
+            DynamicImage::ImageRgb8(self.to_rgb8()?)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bench::baseline_config;
+    use crate::presets;
+    use std::cell::Cell;
+
+    thread_local! {
+        /// Number of histograms this thread has tone mapped.
+        pub(super) static TONE_MAPS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    #[test]
+    fn band_layers_add_up_to_the_image_over_black() {
+        let cfg = RunConfig { width: 48, height: 48, iters: 100_000, seed: Some(4), split_bands: Some(3), ..baseline_config(2) };
+        let mut session = RenderSession::new(presets::gasket(), cfg).unwrap();
+        session.run();
+        let histogram = session.buffer();
+        let tone = ToneMapping::default();
+        let layers = histogram.render_bands_8bit(&session.bands().unwrap(), &tone, DitherMode::None);
+        assert_eq!(layers.len(), 3);
+        let image = histogram.render_8bit(&tone, DitherMode::None);
+        for (i, pixel) in image.buckets().iter().enumerate() {
+            let sum = |channel: fn(&Bucket<u8>) -> u8| layers.iter().map(|l| channel(&l.buckets()[i]) as i32).sum::<i32>();
+            // Each layer rounds its share down or up.
+            for (found, expected) in [(sum(|b| b.red), pixel.red), (sum(|b| b.green), pixel.green), (sum(|b| b.blue), pixel.blue)] {
+                assert!((found - expected as i32).abs() <= 2, "pixel {}: {} is not {}", i, found, expected);
+            }
+        }
+    }
+
+    /// A histogram of pseudo-random counts, a third of them empty, which
+    /// does not change when the chaos game does.
+    fn synthetic_histogram() -> Buffer<u32> {
+        let mut state = 12345u64;
+        let mut next = || {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (state >> 33) as u32
+        };
+        let buckets = (0 .. 64 * 48).map(|_| {
+            let alpha = if next() % 3 == 0 { 0 } else { next() % 2000 };
+            Bucket { alpha, red: alpha * (next() % 256), green: alpha * (next() % 256), blue: alpha * (next() % 256) }
+        }).collect();
+        Buffer::from_buckets(64, 48, buckets).unwrap()
+    }
+
+    /// Render settings exercising each stage of tone mapping and encoding.
+    fn varied_settings() -> [(ToneMapping, OutputEncoding); 3] {
+        let base = ToneMapping { vibrancy: 1.0, ..ToneMapping::default() };
+        [
+            (base, OutputEncoding::default()),
+            (
+                ToneMapping {
+                    gamma: 3.0,
+                    preserve_color: true,
+                    vibrancy: 0.5,
+                    background: Color::rgb(20, 30, 40),
+                    highlights: HighlightMode::PreserveHue,
+                    deterministic_math: true,
+                    ..base
+                },
+                OutputEncoding { grayscale: true, dither: DitherMode::Ordered8x8, ..OutputEncoding::default() },
+            ),
+            (
+                ToneMapping {
+                    vibrancy: 0.8,
+                    background: Color::rgb(255, 255, 255),
+                    highlights: HighlightMode::DesaturateToWhite { knee: 0.5 },
+                    ..base
+                },
+                OutputEncoding { dither: DitherMode::BlueNoise, ..OutputEncoding::default() },
+            ),
+        ]
+    }
+
+    fn image_fingerprint(image: &Buffer<u8>) -> u64 {
+        image.buckets().iter()
+            .flat_map(|b| [b.alpha, b.red, b.green, b.blue])
+            .fold(0xcbf2_9ce4_8422_2325, |h, v| (h ^ v as u64).wrapping_mul(0x100_0000_01b3))
+    }
+
+    #[test]
+    fn tone_mapping_then_encoding_is_the_combined_render() {
+        // Recorded from `render` before tone mapping and encoding were split.
+        let recorded = [0x54f1_4541_ba33_fd02, 0x956d_3420_9731_6f4a, 0x85e3_2d3e_d37a_ac8d];
+        let histogram = synthetic_histogram();
+        for ((tone, encoding), recorded) in varied_settings().into_iter().zip(recorded) {
+            let rendered = histogram.render_8bit(&tone, encoding.dither);
+            assert_eq!(image_fingerprint(&rendered), recorded);
+            let encoded = encoding.encode(&histogram.tone_map(&tone)).unwrap();
+            assert_eq!(encoded.as_bytes(), rendered.to_image(encoding.grayscale).unwrap().as_bytes());
+        }
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn render_config_renders_as_its_parts() {
+        let histogram = synthetic_histogram();
+        for (tone, encoding) in varied_settings() {
+            let cfg = RenderConfig {
+                grayscale: encoding.grayscale,
+                gamma: tone.gamma,
+                preserve_color: tone.preserve_color,
+                vibrancy: tone.vibrancy,
+                background: tone.background,
+                highlights: tone.highlights,
+                dither: encoding.dither,
+                deterministic_math: tone.deterministic_math,
+                ..RenderConfig::default()
+            };
+            let rendered = histogram.render_8bit(&tone, encoding.dither);
+            assert_eq!(histogram.render(cfg).as_flat_slice(), rendered.as_flat_slice());
+            assert_eq!(histogram.render_with_stats(cfg).0.as_flat_slice(), rendered.as_flat_slice());
+        }
+    }
+
+    #[test]
+    fn encodings_share_one_tone_map() {
+        let histogram = synthetic_histogram();
+        let (tone, encoding) = (ToneMapping::default(), OutputEncoding::default());
+        let before = TONE_MAPS.with(Cell::get);
+        let toned = histogram.tone_map(&tone);
+        let eight = encoding.encode(&toned).unwrap();
+        let float = OutputEncoding { depth: BitDepth::Float, ..encoding }.encode(&toned).unwrap();
+        assert_eq!(TONE_MAPS.with(Cell::get) - before, 1);
+
+        assert_eq!((eight.width(), eight.height()), (float.width(), float.height()));
+        let (eight, float) = (eight.into_rgb8(), float.into_rgb32f());
+        for (e, f) in eight.pixels().zip(float.pixels()) {
+            assert!(e.0.iter().zip(f.0).all(|(&e, f)| (e as f32 - f * 255.0).abs() <= 1.0), "{:?} {:?}", e, f);
+        }
+        // Rendering in one step tone maps each time.
+        histogram.render_8bit(&tone, encoding.dither);
+        assert_eq!(TONE_MAPS.with(Cell::get) - before, 2);
+    }
+
+    #[test]
+    fn an_empty_flame_shows_the_background_exactly() {
+        let flame = Flame { bounds: Bounds::new(100.0, 101.0, 100.0, 101.0), ..presets::gasket() };
+        let run = RunConfig { width: 16, height: 12, iters: 10_000, seed: Some(1), ..baseline_config(1) };
+        let background: Color = "#202030".parse().unwrap();
+
+        let tone = ToneMapping { background, ..ToneMapping::default() };
+        let image = flame.render_encoded(run, &tone, &OutputEncoding::default()).unwrap().into_rgb8();
+        for (x, y) in [(0, 0), (15, 0), (0, 11), (15, 11)] {
+            assert_eq!(image.get_pixel(x, y).0, [0x20, 0x20, 0x30]);
+        }
+
+        let grayscale = OutputEncoding { grayscale: true, ..OutputEncoding::default() };
+        let gray = flame.render_encoded(run, &tone, &grayscale).unwrap().into_luma8();
+        assert!(gray.pixels().all(|p| p.0 == [background.to_gray()]));
+    }
+
+    #[test]
+    fn every_background_byte_survives_tone_mapping() {
+        let empty: Buffer<u32> = Buffer::new(4, 4);
+        for dither in [DitherMode::None, DitherMode::Ordered8x8, DitherMode::BlueNoise] {
+            for v in 0 ..= 255 {
+                let background = Color::rgb(v, 255 - v, v / 2);
+                let tone = ToneMapping { background, ..ToneMapping::default() };
+                let image = empty.render_8bit(&tone, dither).to_rgb8().unwrap();
+                assert!(image.pixels().all(|p| p.0 == [v, 255 - v, v / 2]), "{:?} over {:?}", background, dither);
+            }
+        }
+    }
+
+    #[test]
+    fn the_flame_is_composited_over_the_background() {
+        let run = RunConfig { width: 24, height: 24, iters: 50_000, seed: Some(3), ..baseline_config(1) };
+        let histogram = presets::gasket().run(run).unwrap();
+        let black = histogram.render_8bit(&ToneMapping::default(), DitherMode::None);
+        let white = histogram.render_8bit(&ToneMapping { background: Color::rgb(255, 255, 255), ..ToneMapping::default() }, DitherMode::None);
+        for ((h, b), w) in histogram.buckets().iter().zip(black.buckets()).zip(white.buckets()) {
+            if h.alpha == 0 {
+                assert_eq!([b.red, b.green, b.blue, w.red, w.green, w.blue], [0, 0, 0, 255, 255, 255]);
+            } else {
+                // Hit pixels are no darker over white than over black.
+                assert!(w.red >= b.red && w.green >= b.green && w.blue >= b.blue);
+            }
+        }
+    }
+
+    #[test]
+    fn bounds_convert_between_center_scale_and_edges() {
+        let bounds = Bounds::from_center_scale([1.0, -0.5], 0.5, 2.0);
+        assert_eq!(bounds.to_array(), [-1.0, 3.0, -1.5, 0.5]);
+        assert_eq!((bounds.center(), bounds.scale(), bounds.aspect()), ([1.0, -0.5], 0.5, 2.0));
+
+        let edges = Bounds::new(-2.0, 1.0, 0.0, 4.0);
+        let again = Bounds::from_center_scale(edges.center(), edges.scale(), edges.aspect());
+        assert_eq!(again, edges);
+    }
+
+    /// Functions of the gasket's first, with the given weights.
+    fn weighted(weights: &[f32]) -> Vec<Function> {
+        let base = presets::gasket().functions[0];
+        weights.iter().map(|&weight| Function { weight, ..base }).collect()
+    }
+
+    /// Pearson's statistic for `counts` against the chances of `selector`.
+    fn chi_squared(selector: &FunctionSelector, counts: &[u64], draws: u64) -> f64 {
+        selector.probabilities().iter().zip(counts)
+            .map(|(p, &n)| {
+                let expected = p * draws as f64;
+                (n as f64 - expected).powi(2) / expected
+            })
+            .sum()
+    }
+
+    #[test]
+    fn functions_are_chosen_by_their_share_of_the_weight() {
+        // Forty single precision weights which do not sum to one exactly,
+        // as in a descriptor, so that the last function would take up any
+        // shortfall.
+        let weights: Vec<f32> = (0 .. 40).map(|i| (1 + i % 7) as f32 / 157.0).collect();
+        let functions = weighted(&weights);
+        let selector = FunctionSelector::new(&functions).unwrap();
+        let mut rng = StdRng::seed_from_u64(11);
+        let draws = 4_000_000;
+        let mut counts = vec![0u64; functions.len()];
+        for _ in 0 .. draws {
+            counts[selector.sample(&mut rng)] += 1;
+        }
+
+        // 39 degrees of freedom, whose statistic exceeds 80 with
+        // probability about 1e-4.
+        let statistic = chi_squared(&selector, &counts, draws);
+        assert!(statistic < 80.0, "chi-squared statistic {}", statistic);
+        let total: f64 = weights.iter().map(|&w| w as f64).sum();
+        for (i, (&n, &w)) in counts.iter().zip(&weights).enumerate() {
+            let p = w as f64 / total;
+            let sigma = (draws as f64 * p * (1.0 - p)).sqrt();
+            let deviation = (n as f64 - p * draws as f64).abs() / sigma;
+            assert!(deviation < 5.0, "function {} is {} standard deviations off", i, deviation);
+        }
+    }
+
+    #[test]
+    fn functions_are_chosen_as_by_a_draw_from_the_total() {
+        // Sums which are awkward in binary, and a weight close to the last
+        // but one's sum, so that some draws land within rounding of a sum.
+        let weights = [0.1, 0.2, 1e-7, 0.3, 0.4];
+        let functions = weighted(&weights);
+        let selector = FunctionSelector::new(&functions).unwrap();
+        let total: f64 = weights.iter().map(|&w| w as f64).sum();
+        let draw = Uniform::new(0.0, total);
+        let sums: Vec<f64> = weights.iter().scan(0.0, |sum, &w| { *sum += w as f64; Some(*sum) }).collect();
+
+        let mut rng = StdRng::seed_from_u64(3);
+        for _ in 0 .. 100_000 {
+            let r = draw.sample(&mut rng.clone());
+            assert_eq!(selector.sample(&mut rng), sums.partition_point(|&x| x <= r), "draw {}", r);
+        }
+        // Draws from the few bits either side of each sum.
+        for &x in &sums[.. sums.len() - 1] {
+            let bits = (x / total * (1u64 << DRAW_BITS) as f64) as u64;
+            for bits in bits - 3 ..= bits + 3 {
+                let mut rng = StepRng::new(bits << (64 - DRAW_BITS), 0);
+                let r = draw.sample(&mut rng.clone());
+                assert_eq!(selector.sample(&mut rng), sums.partition_point(|&x| x <= r), "draw {}", r);
+            }
+        }
+    }
+
+    #[test]
+    fn weights_far_from_one_are_taken_relative_to_their_total() {
+        for weights in [[0.1, 0.2, 0.4], [1.0, 2.0, 4.0]] {
+            let selector = FunctionSelector::new(&weighted(&weights)).unwrap();
+            let chances = selector.probabilities();
+            for (chance, expected) in chances.iter().zip([1.0 / 7.0, 2.0 / 7.0, 4.0 / 7.0]) {
+                assert!((chance - expected).abs() < 1e-6, "{:?}", chances);
+            }
+        }
+    }
+
+    #[test]
+    fn weightless_functions_are_never_chosen() {
+        let selector = FunctionSelector::new(&weighted(&[0.0, 0.5, 0.0, 0.5, 0.0])).unwrap();
+        assert_eq!(selector.probabilities(), [0.0, 0.5, 0.0, 0.5, 0.0]);
+        let mut rng = StdRng::seed_from_u64(5);
+        assert!((0 .. 10_000).map(|_| selector.sample(&mut rng)).all(|i| i == 1 || i == 3));
+    }
+
+    #[test]
+    fn nothing_is_chosen_without_functions_or_weight() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let empty = Flame { functions: Vec::new(), ..presets::gasket() };
+        assert!(empty.choose_function(&mut rng).is_none());
+        assert!(FunctionSelector::new(&[]).is_none());
+        for weights in [[0.0, 0.0], [f32::INFINITY, 1.0], [f32::NAN, 1.0]] {
+            assert!(FunctionSelector::new(&weighted(&weights)).is_none(), "{:?}", weights);
+        }
+
+        let flame = presets::gasket();
+        let chosen = flame.choose_function(&mut rng).unwrap();
+        assert!(flame.functions.contains(chosen));
+        // An empty flame still renders, as nothing at all.
+        let buffer = empty.run(RunConfig { width: 8, height: 8, iters: 1000, ..baseline_config(1) }).unwrap();
+        assert!(buffer.buckets().iter().all(|b| b.alpha == 0));
+    }
+
+    #[test]
+    fn plot_modes_parse_with_their_defaults() {
+        assert_eq!("points".parse(), Ok(PlotMode::Points));
+        assert_eq!("Strokes".parse(), Ok(PlotMode::Strokes { length: 8, attenuation: 0.1 }));
+        assert_eq!("strokes:3".parse(), Ok(PlotMode::Strokes { length: 3, attenuation: 0.1 }));
+        assert_eq!("strokes:16:0.5".parse(), Ok(PlotMode::Strokes { length: 16, attenuation: 0.5 }));
+        for bad in ["lines", "points:2", "strokes:1", "strokes:300", "strokes:8:1.5", "strokes:8:x", "strokes:8:0.1:2"] {
+            assert!(bad.parse::<PlotMode>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn pixel_counts_are_capped_before_rendering() {
+        let cfg = RunConfig { width: 1 << 20, height: 1 << 20, ..baseline_config(1) };
+        let too_large = BufferError::TooLarge { width: 1 << 20, height: 1 << 20, max_pixels: DEFAULT_MAX_PIXELS };
+        assert_eq!(cfg.check_size().err(), Some(too_large.clone()));
+        // Refused before any histogram is allocated.
+        assert_eq!(presets::gasket().render_encoded(cfg, &ToneMapping::default(), &OutputEncoding::default()).err(), Some(too_large.clone()));
+        assert_eq!(too_large.to_string(), "image of 1048576x1048576 pixels is too large (at most 2147483648 pixels allowed)");
+
+        let capped = RunConfig { width: 64, height: 32, max_pixels: 64 * 32, ..baseline_config(1) };
+        assert!(capped.check_size().is_ok());
+        assert!(RunConfig { height: 33, ..capped }.check_size().is_err());
+        assert!(RunConfig { width: usize::MAX, height: 2, ..capped }.check_size().is_err());
+        assert!(baseline_config(1).check_size().is_ok());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn images_narrower_or_shorter_than_two_pixels_are_refused() {
+        for (width, height) in [(0, 8), (8, 0), (1, 8), (8, 1), (0, 0)] {
+            let cfg = RunConfig { width, height, ..baseline_config(1) };
+            assert_eq!(cfg.check_size(), Err(BufferError::TooSmall { width, height }));
+            assert!(presets::gasket().run(cfg).is_err());
+            assert!(presets::gasket().render_encoded(cfg, &ToneMapping::default(), &OutputEncoding::default()).is_err());
+        }
+        assert!(RunConfig { width: 2, height: 2, ..baseline_config(1) }.check_size().is_ok());
+    }
+
+    #[test]
+    fn pathological_tone_mapping_gives_an_image() {
+        // Gammas and vibrancies which make channels infinite or NaN part way
+        // through; those are saturated or zeroed when they are quantized.
+        let histogram = presets::gasket().run(RunConfig { width: 24, height: 16, iters: 20_000, ..baseline_config(1) }).unwrap();
+        for (gamma, vibrancy) in [(0.0, 1.0), (f64::NAN, 1.0), (f64::INFINITY, 0.5), (-2.2, 1.0), (2.2, f64::NAN), (2.2, -1.0)] {
+            let (image, _) = histogram.render_8bit_with_stats(&ToneMapping { gamma, vibrancy, ..ToneMapping::default() }, DitherMode::None);
+            assert_eq!(image.to_rgb8().unwrap().dimensions(), (24, 16), "gamma {}, vibrancy {}", gamma, vibrancy);
+        }
+    }
+
+    #[test]
+    fn empty_renders_show_the_background() {
+        let background = Color::rgb(10, 20, 30);
+        for (width, height) in [(0, 0), (5, 0), (3, 2)] {
+            let empty: Buffer<u32> = Buffer::new(width, height);
+            let image = empty.render_8bit(&ToneMapping { background, ..ToneMapping::default() }, DitherMode::None).to_rgb8().unwrap();
+            assert_eq!(image.dimensions(), (width as u32, height as u32));
+            assert!(image.pixels().all(|p| p.0 == [10, 20, 30]));
+        }
+        // A render whose points all fall outside the bounds.
+        let flame = Flame { bounds: Bounds::new(5.0, 6.0, 5.0, 6.0), ..presets::fern() };
+        let run = RunConfig { width: 8, height: 8, iters: 5_000, ..baseline_config(2) };
+        let image = flame.render_encoded(run, &ToneMapping::default(), &OutputEncoding::default()).unwrap().into_rgb8();
+        assert!(image.pixels().all(|p| p.0 == [0, 0, 0]));
+    }
+
+    #[test]
+    fn grayscale_renders_of_monochrome_runs_are_unchanged() {
+        let run = RunConfig { width: 24, height: 24, iters: 50_000, seed: Some(3), ..baseline_config(2) };
+        let tone = ToneMapping::default();
+        let color = presets::gasket().run(run).unwrap().render_8bit(&tone, DitherMode::None).to_gray8().unwrap();
+        let mono = presets::gasket().run(RunConfig { monochrome: true, ..run }).unwrap().render_8bit(&tone, DitherMode::None).to_gray8().unwrap();
+        assert_eq!(mono, color);
+    }
+
+    #[test]
+    fn identity_curves_render_as_no_curves() {
+        let histogram = presets::swirl().run(RunConfig { width: 24, height: 24, iters: 20_000, ..baseline_config(1) }).unwrap();
+        let plain = histogram.render_8bit(&ToneMapping::default(), DitherMode::None);
+        let curved = histogram.render_8bit(&ToneMapping { curves: Some(ChannelCurves::default()), ..ToneMapping::default() }, DitherMode::None);
+        assert_eq!(curved.as_flat_slice(), plain.as_flat_slice());
+    }
+
+    /// Orbits along the x axis between 0 and 1, crowding towards 0.5.
+    fn line_attractor() -> Flame {
+        let line = |weight: f32, e: f32| Function {
+            weight,
+            trans: Transform::from_matrix_unchecked(Matrix3::new(0.5, 0.0, e, 0.0, 0.5, 0.0, 0.0, 0.0, 1.0)),
+            ..presets::gasket().functions[0]
+        };
+        Flame { functions: vec![line(0.8, 0.25), line(0.1, 0.0), line(0.1, 0.5)], ..presets::gasket() }
+    }
+
+    #[test]
+    fn f64_orbits_fill_more_buckets_of_a_deep_zoom() {
+        // A window five million times narrower than the line, whose pixels
+        // are far narrower than the spacing of single precision values
+        // around 0.5.
+        let flame = Flame { bounds: Bounds::new(0.499_999_9, 0.500_000_1, -1.0, 1.0), ..line_attractor() };
+        let run = RunConfig { width: 64, height: 3, iters: 400_000, seed: Some(2), ..baseline_config(1) };
+        assert_eq!(Precision::Auto.resolve(&flame.bounds, run.width, run.height), Precision::F64);
+
+        let filled = |precision| flame.run(RunConfig { precision, ..run }).unwrap().buckets().iter().filter(|b| b.alpha > 0).count();
+        let (single, double) = (filled(Precision::F32), filled(Precision::F64));
+        assert!(double > 4 * single, "f32 filled {} buckets and f64 {}", single, double);
+    }
+
+    #[test]
+    fn auto_precision_keeps_f32_for_ordinary_windows() {
+        let gasket = presets::gasket();
+        assert_eq!(Precision::Auto.resolve(&gasket.bounds, 1920, 1080), Precision::F32);
+        assert_eq!(Precision::F64.resolve(&gasket.bounds, 16, 16), Precision::F64);
+        assert_eq!(Precision::F32.resolve(&Bounds::new(0.499_999, 0.500_001, -1.0, 1.0), 64, 3), Precision::F32);
+        for name in Precision::NAMES {
+            assert_eq!(name.parse::<Precision>().unwrap().to_string(), name);
+        }
+    }
+
+    /// Points on and around the swirl's attractor, including the origin
+    /// and values far from it.
+    const SAMPLES: [[f32; 2]; 6] = [[0.0, 0.0], [0.3, -0.7], [-1.2, 0.4], [2.5, 2.5], [-0.01, 30.0], [7.0, -0.125]];
+
+    fn blended(axis_blend: [f32; 2]) -> Function {
+        Function { axis_blend, ..presets::swirl().functions[0] }
+    }
+
+    fn bits(p: Point2<f32>) -> [u32; 2] {
+        [p[0].to_bits(), p[1].to_bits()]
+    }
+
+    fn channels(histogram: &Buffer<u32>) -> Vec<u32> {
+        histogram.buckets().iter().flat_map(|b| [b.alpha, b.red, b.green, b.blue]).collect()
+    }
+
+    #[test]
+    fn full_blends_are_the_variation_and_no_blend_is_the_affine_transform() {
+        let (full, none) = (blended(Function::FULL_BLEND), blended([0.0, 0.0]));
+        for [x, y] in SAMPLES {
+            let p = Point2::new(x, y);
+            assert_eq!(bits(full.eval(p)), bits(full.var.eval(full.trans * p)), "at {:?}", p);
+            assert_eq!(bits(none.eval(p)), bits(none.trans * p), "at {:?}", p);
+        }
+
+        // Whole flames too: unblended functions render as if they had no
+        // variation.
+        let run = RunConfig { width: 32, height: 32, iters: 50_000, seed: Some(7), ..baseline_config(1) };
+        let swirl = presets::swirl();
+        let unblended = |var: Option<Variation>| Flame {
+            functions: swirl.functions.iter()
+                .map(|&f| Function { axis_blend: [0.0, 0.0], var: var.unwrap_or(f.var), ..f })
+                .collect(),
+            ..swirl.clone()
+        };
+        assert_eq!(channels(&unblended(None).run(run).unwrap()), channels(&unblended(Some(Variation::Id)).run(run).unwrap()));
+    }
+
+    #[test]
+    fn partial_blends_interpolate_each_axis_linearly() {
+        let (none, full) = (blended([0.0, 0.0]), blended(Function::FULL_BLEND));
+        for blend in [[0.25, 0.7], [0.5, 0.5], [1.0, 0.1], [0.0, 0.9]] {
+            let f = blended(blend);
+            for [x, y] in SAMPLES {
+                let p = Point2::new(x, y);
+                let (from, to, got) = (none.eval(p), full.eval(p), f.eval(p));
+                for i in 0..2 {
+                    let expected = from[i] + (to[i] - from[i]) * blend[i];
+                    let tolerance = 1e-6 * from[i].abs().max(to[i].abs()).max(1.0);
+                    assert!((got[i] - expected).abs() <= tolerance, "axis {} of {:?} at {:?}: {} != {}", i, blend, p, got[i], expected);
+                }
+                // Axes blended fully or not at all are exact.
+                for i in 0..2 {
+                    if blend[i] == 1.0 {
+                        assert_eq!(got[i].to_bits(), to[i].to_bits());
+                    } else if blend[i] == 0.0 {
+                        assert_eq!(got[i].to_bits(), from[i].to_bits());
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,443 @@
+use super::*;
+
+/// Which neighbors of a pixel count as touching it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Connectivity {
+    /// The pixels above, below, left and right.
+    Four,
+    /// The diagonal pixels too.
+    #[default]
+    Eight,
+}
+
+impl Connectivity {
+    /// Names of the connectivities, as accepted by `from_str`.
+    pub const NAMES: [&'static str; 2] = ["4", "8"];
+
+    /// The connectivity of the pixels outside a region of this one, so that
+    /// regions and the gaps between them never cross each other.
+    pub fn dual(self) -> Connectivity {
+        match self {
+            Connectivity::Four => Connectivity::Eight,
+            Connectivity::Eight => Connectivity::Four,
+        }
+    }
+
+    fn offsets(self) -> &'static [(isize, isize)] {
+        match self {
+            Connectivity::Four => &[(1, 0), (-1, 0), (0, 1), (0, -1)],
+            Connectivity::Eight => &[(1, 0), (-1, 0), (0, 1), (0, -1), (1, 1), (1, -1), (-1, 1), (-1, -1)],
+        }
+    }
+}
+
+impl std::str::FromStr for Connectivity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "4" => Ok(Connectivity::Four),
+            "8" => Ok(Connectivity::Eight),
+            _ => Err(format!("unknown connectivity '{}' (expected 4 or 8)", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for Connectivity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Connectivity::Four => write!(f, "4"),
+            Connectivity::Eight => write!(f, "8"),
+        }
+    }
+}
+
+/// An image of pixels which are each set or clear, row by row.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bitmap {
+    width: usize,
+    height: usize,
+    bits: Vec<bool>,
+}
+
+/// The connected regions of the set pixels of a bitmap.
+#[derive(Debug, Clone)]
+pub struct Components {
+    /// Region of each pixel, numbered from one, or zero for clear pixels.
+    pub labels: Vec<u32>,
+    /// Number of pixels in each region, the first for region one.
+    pub sizes: Vec<usize>,
+    /// Whether each region reaches the edge of the image.
+    pub touches_edge: Vec<bool>,
+}
+
+impl Bitmap {
+    /// A bitmap of `width` by `height` pixels, or `None` if `bits` is not
+    /// that long.
+    pub fn from_bits(width: usize, height: usize, bits: Vec<bool>) -> Option<Self> {
+        (width.checked_mul(height) == Some(bits.len())).then_some(Bitmap { width, height, bits })
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> bool {
+        self.bits[x + y * self.width]
+    }
+
+    pub fn bits(&self) -> &[bool] {
+        &self.bits
+    }
+
+    /// The same bitmap with every pixel flipped.
+    pub fn inverted(&self) -> Bitmap {
+        Bitmap { bits: self.bits.iter().map(|b| !b).collect(), ..*self }
+    }
+
+    /// Number the connected regions of set pixels, in the order their first
+    /// pixels appear row by row.
+    pub fn components(&self, connectivity: Connectivity) -> Components {
+        let mut labels = vec![0; self.bits.len()];
+        let (mut sizes, mut touches_edge) = (Vec::new(), Vec::new());
+        let mut stack = Vec::new();
+        for start in 0 .. self.bits.len() {
+            if !self.bits[start] || labels[start] != 0 {
+                continue;
+            }
+            let label = sizes.len() as u32 + 1;
+            let (mut size, mut edge) = (0, false);
+            labels[start] = label;
+            stack.push(start);
+            // Each pixel is labeled as it is pushed, so none is pushed twice.
+            while let Some(i) = stack.pop() {
+                let (x, y) = (i % self.width, i / self.width);
+                size += 1;
+                edge |= x == 0 || y == 0 || x + 1 == self.width || y + 1 == self.height;
+                for &(dx, dy) in connectivity.offsets() {
+                    let (Some(nx), Some(ny)) = (x.checked_add_signed(dx), y.checked_add_signed(dy)) else { continue };
+                    if nx >= self.width || ny >= self.height {
+                        continue;
+                    }
+                    let j = nx + ny * self.width;
+                    if self.bits[j] && labels[j] == 0 {
+                        labels[j] = label;
+                        stack.push(j);
+                    }
+                }
+            }
+            sizes.push(size);
+            touches_edge.push(edge);
+        }
+        Components { labels, sizes, touches_edge }
+    }
+
+    /// Clear every region of set pixels smaller than `min_size`, returning
+    /// how many were cleared.
+    pub fn remove_specks(&mut self, min_size: usize, connectivity: Connectivity) -> usize {
+        let components = self.components(connectivity);
+        let small: Vec<bool> = components.sizes.iter().map(|&s| s < min_size).collect();
+        for (bit, &label) in self.bits.iter_mut().zip(&components.labels) {
+            if label != 0 && small[label as usize - 1] {
+                *bit = false;
+            }
+        }
+        small.iter().filter(|&&s| s).count()
+    }
+
+    /// Set every hole smaller than `min_size`, returning how many were
+    /// filled. Holes are regions of clear pixels which do not reach the
+    /// edge of the image, connected by the dual of `connectivity`, which is
+    /// that of the set pixels around them.
+    pub fn fill_holes(&mut self, min_size: usize, connectivity: Connectivity) -> usize {
+        let components = self.inverted().components(connectivity.dual());
+        let small: Vec<bool> = components.sizes.iter().zip(&components.touches_edge)
+            .map(|(&s, &edge)| s < min_size && !edge)
+            .collect();
+        for (bit, &label) in self.bits.iter_mut().zip(&components.labels) {
+            if label != 0 && small[label as usize - 1] {
+                *bit = true;
+            }
+        }
+        small.iter().filter(|&&s| s).count()
+    }
+}
+
+/// How to reduce an image to the silhouette of the flame's attractor, for
+/// stencils and cutting: pixels dense enough are the foreground and all
+/// others the background, with small specks and holes cleaned up.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Silhouette {
+    /// Tonemapped density, from 0 to 1, above which pixels are foreground.
+    pub threshold: f64,
+    /// Regions of foreground and holes in it with fewer pixels than this
+    /// are removed. Zero and one keep everything.
+    pub min_region: usize,
+    /// Connectivity of the foreground. Holes have the dual connectivity.
+    pub connectivity: Connectivity,
+    pub foreground: Color,
+    pub background: Color,
+}
+
+impl Default for Silhouette {
+    fn default() -> Self {
+        Silhouette {
+            threshold: 0.02,
+            min_region: 0,
+            connectivity: Connectivity::default(),
+            foreground: Color::rgb(0, 0, 0),
+            background: Color::rgb(255, 255, 255),
+        }
+    }
+}
+
+impl Silhouette {
+    /// Threshold tonemapped densities given row by row, then remove specks
+    /// and fill holes smaller than `min_region`.
+    pub fn apply(&self, width: usize, height: usize, density: impl Iterator<Item = f64>) -> Bitmap {
+        let bits = density.map(|d| d > self.threshold).collect();
+        let mut bitmap = Bitmap::from_bits(width, height, bits).expect("one density per pixel");
+        if self.min_region > 1 {
+            bitmap.remove_specks(self.min_region, self.connectivity);
+            bitmap.fill_holes(self.min_region, self.connectivity);
+        }
+        bitmap
+    }
+
+    /// Apply a setting written `key=value`.
+    fn set(&mut self, setting: &str) -> Result<(), String> {
+        let (key, value) = setting.split_once('=')
+            .ok_or_else(|| format!("expected KEY=VALUE, got '{}'", setting))?;
+        let value = value.trim();
+        let invalid = |what: &str| format!("invalid {} '{}' in '{}'", what, value, setting);
+        match key.trim().to_ascii_lowercase().as_str() {
+            "threshold" => {
+                self.threshold = value.parse().map_err(|_| invalid("number"))?;
+                if !(0.0 .. 1.0).contains(&self.threshold) {
+                    return Err(format!("threshold must be in [0, 1) in '{}'", setting));
+                }
+            }
+            "min-region" => self.min_region = value.parse().map_err(|_| invalid("pixel count"))?,
+            "connectivity" => self.connectivity = value.parse()?,
+            "foreground" => self.foreground = value.parse().map_err(|_| invalid("color"))?,
+            "background" => self.background = value.parse().map_err(|_| invalid("color"))?,
+            k => {
+                return Err(format!(
+                    "unknown silhouette setting '{}' (expected threshold, min-region, connectivity, foreground or background)",
+                    k,
+                ))
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::str::FromStr for Silhouette {
+    type Err = String;
+
+    /// Parses comma separated `key=value` settings, such as
+    /// `threshold=0.02,min-region=8`. Settings not given keep their
+    /// defaults: a threshold of 0.02, no cleanup, 8-connectivity, and black
+    /// on white.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut silhouette = Silhouette::default();
+        for setting in s.split(',').filter(|s| !s.trim().is_empty()) {
+            silhouette.set(setting)?;
+        }
+        Ok(silhouette)
+    }
+}
+
+impl std::fmt::Display for Silhouette {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "threshold={},min-region={},connectivity={},foreground={},background={}",
+            self.threshold, self.min_region, self.connectivity, self.foreground, self.background,
+        )
+    }
+}
+
+impl Buffer<u32> {
+    /// The silhouette of a histogram, thresholding its density after tone
+    /// mapping but before the background, lighting and curves are applied.
+    pub fn silhouette(&self, tone: &ToneMapping, silhouette: &Silhouette) -> Bitmap {
+        let toned = if tone.deterministic_math {
+            self.tone::<math::PortableMath>(tone)
+        } else {
+            self.tone::<math::PlatformMath>(tone)
+        };
+        silhouette.apply(self.width(), self.height(), toned.row_major_buckets().map(|b| b.alpha))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+
+    /// A bitmap drawn with `#` for set pixels and `.` for clear ones.
+    fn grid(rows: &[&str]) -> Bitmap {
+        let bits = rows.iter().flat_map(|row| row.chars().map(|c| c == '#')).collect();
+        Bitmap::from_bits(rows[0].len(), rows.len(), bits).unwrap()
+    }
+
+    #[test]
+    fn diagonal_neighbors_touch_only_under_eight_connectivity() {
+        let diagonal = grid(&[
+            "#..",
+            ".#.",
+            "..#",
+        ]);
+        let four = diagonal.components(Connectivity::Four);
+        assert_eq!(four.sizes, [1, 1, 1]);
+        assert_eq!(four.labels, [1, 0, 0, 0, 2, 0, 0, 0, 3]);
+        // The middle pixel is the only one away from the edges.
+        assert_eq!(four.touches_edge, [true, false, true]);
+
+        let eight = diagonal.components(Connectivity::Eight);
+        assert_eq!((eight.sizes, eight.touches_edge), (vec![3], vec![true]));
+        assert_eq!(eight.labels, [1, 0, 0, 0, 1, 0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn specks_are_removed_by_size() {
+        let specks = || grid(&[
+            "##..#",
+            "##...",
+            ".....",
+            "...#.",
+            "..#..",
+        ]);
+        let mut eight = specks();
+        assert_eq!(eight.remove_specks(2, Connectivity::Eight), 1);
+        assert_eq!(eight, grid(&[
+            "##...",
+            "##...",
+            ".....",
+            "...#.",
+            "..#..",
+        ]));
+        // Apart, the diagonal pair are two specks.
+        let mut four = specks();
+        assert_eq!(four.remove_specks(2, Connectivity::Four), 3);
+        assert_eq!(four, grid(&["##...", "##...", ".....", ".....", "....."]));
+    }
+
+    #[test]
+    fn holes_reaching_the_edge_are_not_filled() {
+        let mut holes = grid(&[
+            "######",
+            "#..#..",
+            "#..#.#",
+            "######",
+        ]);
+        assert_eq!(holes.fill_holes(10, Connectivity::Eight), 1);
+        assert_eq!(holes, grid(&[
+            "######",
+            "####..",
+            "####.#",
+            "######",
+        ]));
+    }
+
+    #[test]
+    fn holes_are_connected_by_the_dual_connectivity() {
+        let holes = || grid(&[
+            "#####",
+            "#.#.#",
+            "##.##",
+            "#####",
+        ]);
+        let filled = grid(&["#####"; 4]);
+        // Around an 8-connected foreground, the three clear pixels are three
+        // holes of one pixel.
+        let mut eight = holes();
+        assert_eq!(eight.fill_holes(2, Connectivity::Eight), 3);
+        assert_eq!(eight, filled);
+        // Around a 4-connected one, they are a single hole of three.
+        let mut four = holes();
+        assert_eq!(four.fill_holes(3, Connectivity::Four), 0);
+        assert_eq!(four, holes());
+        assert_eq!(four.fill_holes(4, Connectivity::Four), 1);
+        assert_eq!(four, filled);
+
+        assert_eq!(Connectivity::Four.dual(), Connectivity::Eight);
+        assert_eq!(Connectivity::Eight.dual(), Connectivity::Four);
+    }
+
+    #[test]
+    fn regions_of_zero_or_one_pixel_are_always_kept() {
+        let drawn = grid(&[
+            "#...#",
+            ".###.",
+            ".#.#.",
+            ".###.",
+            "#....",
+        ]);
+        for min_size in [0, 1] {
+            let mut bitmap = drawn.clone();
+            assert_eq!(bitmap.remove_specks(min_size, Connectivity::Four), 0);
+            assert_eq!(bitmap.fill_holes(min_size, Connectivity::Four), 0);
+            assert_eq!(bitmap, drawn);
+
+            let silhouette = Silhouette { threshold: 0.5, min_region: min_size, ..Silhouette::default() };
+            let density = drawn.bits().iter().map(|&b| if b { 1.0 } else { 0.0 });
+            assert_eq!(silhouette.apply(5, 5, density), drawn);
+        }
+    }
+
+    #[test]
+    fn silhouettes_parse_their_settings_over_the_defaults() {
+        let parsed: Silhouette = "threshold=0.1, min-region=8,connectivity=4,foreground=#ff0000".parse().unwrap();
+        assert_eq!(parsed, Silhouette {
+            threshold: 0.1,
+            min_region: 8,
+            connectivity: Connectivity::Four,
+            foreground: Color::rgb(255, 0, 0),
+            ..Silhouette::default()
+        });
+        assert_eq!(parsed.to_string().parse::<Silhouette>(), Ok(parsed));
+        assert_eq!("".parse::<Silhouette>(), Ok(Silhouette::default()));
+        for bad in ["threshold=1", "threshold=-0.1", "min-region=-1", "connectivity=6", "size=3", "threshold"] {
+            assert!(bad.parse::<Silhouette>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn cleaned_silhouettes_of_dense_blobs_have_no_holes_or_specks() {
+        // A disk with pinholes where nothing was plotted, on a background
+        // with stray points.
+        let (size, radius) = (64, 20.0);
+        let mut rng = StdRng::seed_from_u64(4);
+        let histogram: Vec<u32> = (0 .. size * size).flat_map(|i| {
+            let (x, y) = ((i % size) as f64 - 32.0, (i / size) as f64 - 32.0);
+            let inside = (x * x + y * y).sqrt() < radius;
+            let count = match inside {
+                true if rng.gen_bool(0.1) => 0,
+                true => rng.gen_range(100 .. 1000),
+                false if rng.gen_bool(0.02) => rng.gen_range(100 .. 1000),
+                false => 0,
+            };
+            [count, count * 255, count * 255, count * 255]
+        }).collect();
+        let histogram = Buffer::from_flat_vec(size, size, histogram).unwrap();
+        let tone = ToneMapping { vibrancy: 1.0, ..ToneMapping::default() };
+        let holes = |bitmap: &Bitmap| {
+            bitmap.inverted().components(Connectivity::Four).touches_edge.iter().filter(|&&edge| !edge).count()
+        };
+
+        let raw = histogram.silhouette(&tone, &Silhouette::default());
+        assert!(holes(&raw) > 10, "{} holes", holes(&raw));
+        let cleaned = histogram.silhouette(&tone, &Silhouette { min_region: 8, ..Silhouette::default() });
+        assert_eq!(holes(&cleaned), 0);
+        let regions = cleaned.components(Connectivity::Eight);
+        assert_eq!(regions.sizes.len(), 1);
+        assert!(cleaned.get(32, 32) && !cleaned.get(0, 0));
+    }
+}
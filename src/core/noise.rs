@@ -0,0 +1,175 @@
+use image::{Rgb, RgbImage};
+
+/// Running estimate of how noisy each bucket's hit count is.
+///
+/// The estimate is updated once per chunk of iterations rather than per
+/// hit: the number of new hits each bucket received during the chunk is fed
+/// to Welford's algorithm, and the spread of those increments gives the
+/// standard error of the total. It is most accurate when chunks are of
+/// equal size.
+#[derive(Debug, Clone)]
+pub struct HitVariance {
+    width: usize,
+    height: usize,
+    chunks: u64,
+    previous: Vec<u32>,
+    mean: Vec<f64>,
+    m2: Vec<f64>,
+}
+
+impl HitVariance {
+    pub fn new(width: usize, height: usize) -> Self {
+        HitVariance {
+            width, height,
+            chunks: 0,
+            previous: vec![0; width * height],
+            mean: vec![0.0; width * height],
+            m2: vec![0.0; width * height],
+        }
+    }
+
+    /// Record the end of a chunk, given the total hit count of each bucket so far.
+    pub fn update(&mut self, totals: &[u32]) {
+        self.chunks += 1;
+        let k = self.chunks as f64;
+        for (i, &total) in totals.iter().enumerate() {
+            let inc = (total - self.previous[i]) as f64;
+            let delta = inc - self.mean[i];
+            self.mean[i] += delta / k;
+            self.m2[i] += delta * (inc - self.mean[i]);
+            self.previous[i] = total;
+        }
+    }
+
+    pub fn chunks(&self) -> u64 {
+        self.chunks
+    }
+
+    /// Estimated standard error of each bucket's hit count, relative to the
+    /// count itself, in row-major order.
+    ///
+    /// Buckets which were never hit, and every bucket before two chunks have
+    /// been recorded, have an infinite error.
+    pub fn relative_std_error(&self) -> Vec<f64> {
+        let k = self.chunks as f64;
+        self.mean.iter().zip(&self.m2).map(|(&mean, &m2)| {
+            if self.chunks < 2 || mean <= 0.0 {
+                f64::INFINITY
+            } else {
+                (k * m2 / (k - 1.0)).sqrt() / (k * mean)
+            }
+        }).collect()
+    }
+
+    /// False-color visualization of the relative error, running from blue
+    /// (no error) through yellow to red (100% or more). Empty buckets are black.
+    pub fn to_noise_map(&self) -> RgbImage {
+        let errors = self.relative_std_error();
+        RgbImage::from_fn(self.width as u32, self.height as u32, |x, y| {
+            let e = errors[x as usize + y as usize * self.width];
+            if !e.is_finite() {
+                return Rgb([0, 0, 0]);
+            }
+            let e = e.min(1.0);
+            let (lo, hi, t) = if e < 0.5 {
+                ([0., 64., 255.], [255., 255., 0.], e * 2.0)
+            } else {
+                ([255., 255., 0.], [255., 0., 0.], e * 2.0 - 1.0)
+            };
+            Rgb([0, 1, 2].map(|c| (lo[c] * (1.0 - t) + hi[c] * t) as u8))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bench::baseline_config;
+    use crate::core::*;
+    use crate::presets;
+    use nalgebra::{Matrix3, Transform};
+
+    #[test]
+    fn steady_buckets_have_no_error() {
+        let mut variance = HitVariance::new(2, 1);
+        assert!(variance.relative_std_error().iter().all(|e| e.is_infinite()));
+        for k in 1..=4 {
+            variance.update(&[10 * k, 0]);
+        }
+        assert_eq!(variance.chunks(), 4);
+        let errors = variance.relative_std_error();
+        assert_eq!(errors[0], 0.0);
+        assert!(errors[1].is_infinite());
+    }
+
+    #[test]
+    fn relative_error_follows_the_spread_of_increments() {
+        let mut variance = HitVariance::new(1, 1);
+        let mut total = 0;
+        for inc in [8, 12, 8, 12] {
+            total += inc;
+            variance.update(&[total]);
+        }
+        // Increments with mean 10 and sample variance 16 / 3: the total of
+        // 40 has standard error sqrt(4 * 16 / 3).
+        let expected = (4.0 * 16.0 / 3.0f64).sqrt() / 40.0;
+        assert!((variance.relative_std_error()[0] - expected).abs() < 1e-12);
+    }
+
+    /// Two functions, each sending every point to its own spot, one chosen
+    /// nine times as often as the other.
+    fn two_spots() -> Flame {
+        let spot = |weight: f32, x: f32| Function {
+            weight,
+            var: Variation::Id,
+            trans: Transform::from_matrix_unchecked(Matrix3::new(
+                0.0, 0.0, x,
+                0.0, 0.0, 0.0,
+                0.0, 0.0, 1.0,
+            )),
+            color: 0,
+            axis_blend: Function::FULL_BLEND,
+        };
+        Flame {
+            functions: vec![spot(0.9, -0.5), spot(0.1, 0.5)],
+            bounds: Bounds::new(-1.0, 1.0, -1.0, 1.0),
+            ..presets::gasket()
+        }
+    }
+
+    #[test]
+    fn sparse_regions_have_a_higher_estimated_error() {
+        let cfg = RunConfig {
+            width: 16, height: 16, iters: 160_000, seed: Some(3), track_variance: true,
+            ..baseline_config(1)
+        };
+        let mut session = RenderSession::new(two_spots(), cfg).unwrap();
+        session.run();
+        let variance = session.variance().expect("variance is tracked").clone();
+        assert!(variance.chunks() >= 2);
+
+        let buffer = session.into_buffer();
+        let hits: Vec<u32> = buffer.row_major_buckets().map(|b| b.alpha).collect();
+        let errors = variance.relative_std_error();
+        let dense = (0..hits.len()).max_by_key(|&i| hits[i]).unwrap();
+        let sparse = (0..hits.len()).filter(|&i| hits[i] > 0 && i != dense).max_by_key(|&i| hits[i]).unwrap();
+        assert!(dense % 16 < 8 && sparse % 16 >= 8, "dense at {}, sparse at {}", dense, sparse);
+        assert!(hits[dense] > 5 * hits[sparse]);
+        assert!(errors[sparse] > 2.0 * errors[dense], "sparse {} dense {}", errors[sparse], errors[dense]);
+        assert!(errors.iter().zip(&hits).all(|(e, &h)| (h == 0) == e.is_infinite()));
+
+        let map = variance.to_noise_map();
+        let pixel = |i: usize| *map.get_pixel((i % 16) as u32, (i / 16) as u32);
+        assert_eq!(pixel(0), Rgb([0, 0, 0]));
+        assert!(pixel(sparse)[0] > pixel(dense)[0], "noisier buckets are redder");
+    }
+
+    #[test]
+    fn tracking_variance_is_counted_in_the_memory_estimate() {
+        let cfg = RunConfig { width: 100, height: 100, ..baseline_config(1) };
+        let flame = presets::gasket();
+        let without = estimate_memory(cfg, &flame);
+        let with = estimate_memory(RunConfig { track_variance: true, ..cfg }, &flame);
+        assert_eq!(with - without, 100 * 100 * 20);
+    }
+}
@@ -0,0 +1,405 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use super::*;
+
+/// A source of elapsed time, so that pacing can be driven by a fake clock.
+pub trait Clock {
+    /// Time since some fixed starting point.
+    fn now(&self) -> Duration;
+}
+
+/// The system's monotonic clock.
+pub struct MonotonicClock(Instant);
+
+impl MonotonicClock {
+    pub fn new() -> Self {
+        MonotonicClock(Instant::now())
+    }
+}
+
+impl Default for MonotonicClock {
+    fn default() -> Self {
+        MonotonicClock::new()
+    }
+}
+
+impl Clock for MonotonicClock {
+    fn now(&self) -> Duration {
+        self.0.elapsed()
+    }
+}
+
+/// Flag shared between a renderer and its display, set when the display
+/// is ready for a new frame and cleared when one is produced.
+#[derive(Clone, Default)]
+pub struct FrameSignal(Arc<AtomicBool>);
+
+impl FrameSignal {
+    pub fn new() -> Self {
+        FrameSignal::default()
+    }
+
+    /// Ask for a frame. Requests made before the last one was served are merged.
+    pub fn request(&self) {
+        self.0.store(true, Ordering::Release);
+    }
+
+    /// Whether a frame was requested, clearing the request.
+    pub fn take(&self) -> bool {
+        self.0.swap(false, Ordering::AcqRel)
+    }
+}
+
+/// Chooses how many iterations to run between frames so that each chunk
+/// takes about as long as the target interval.
+pub struct FramePacer<C: Clock = MonotonicClock> {
+    clock: C,
+    interval: Duration,
+    iters: u64,
+    min_iters: u64,
+    max_iters: u64,
+    chunk_start: Option<Duration>,
+}
+
+impl FramePacer<MonotonicClock> {
+    /// A pacer aiming for `frames_per_second` chunks a second.
+    pub fn new(frames_per_second: f64) -> Self {
+        FramePacer::with_clock(frames_per_second, MonotonicClock::new())
+    }
+}
+
+impl<C: Clock> FramePacer<C> {
+    pub fn with_clock(frames_per_second: f64, clock: C) -> Self {
+        FramePacer {
+            clock,
+            interval: Duration::from_secs_f64(1.0 / frames_per_second.max(1e-3)),
+            iters: 100_000,
+            min_iters: 1_000,
+            max_iters: 1 << 32,
+            chunk_start: None,
+        }
+    }
+
+    /// Limit the iterations per chunk, which starts at the lower bound if it
+    /// is above the current value.
+    pub fn with_limits(mut self, min_iters: u64, max_iters: u64) -> Self {
+        self.min_iters = min_iters.max(1);
+        self.max_iters = max_iters.max(self.min_iters);
+        self.iters = self.iters.clamp(self.min_iters, self.max_iters);
+        self
+    }
+
+    /// Number of iterations the next chunk should run.
+    pub fn iters_per_chunk(&self) -> u64 {
+        self.iters
+    }
+
+    /// Mark the start of a chunk, returning its number of iterations.
+    pub fn begin_chunk(&mut self) -> u64 {
+        self.chunk_start = Some(self.clock.now());
+        self.iters
+    }
+
+    /// Mark the end of a chunk which ran `iters` iterations, adjusting the
+    /// size of the next one towards the target interval.
+    pub fn end_chunk(&mut self, iters: u64) {
+        let Some(start) = self.chunk_start.take() else { return };
+        let elapsed = self.clock.now().saturating_sub(start).as_secs_f64();
+        if iters == 0 || elapsed <= 0.0 {
+            return;
+        }
+        let ideal = iters as f64 * self.interval.as_secs_f64() / elapsed;
+        // Move halfway towards the ideal on a log scale, to damp the effect
+        // of a single slow or fast chunk.
+        let next = (self.iters as f64 * ideal).sqrt();
+        self.iters = (next as u64).clamp(self.min_iters, self.max_iters);
+    }
+}
+
+/// Longest pause a throttle asks for at once, and most idle time it
+/// credits towards later chunks, so that it stays responsive after the
+/// thread has waited a long time for work.
+const MAX_PAUSE: Duration = Duration::from_millis(250);
+
+/// Spaces chunks of work with pauses so that a thread is busy about a
+/// target fraction of the time, measured against the clock so that pauses
+/// which overrun are made up for.
+pub struct CpuThrottle<C: Clock = MonotonicClock> {
+    clock: C,
+    duty: f64,
+    /// Pause owed, in seconds, negative if the thread has been idle more
+    /// than the target requires.
+    balance: f64,
+    /// End of the last chunk.
+    last: Option<Duration>,
+}
+
+impl CpuThrottle<MonotonicClock> {
+    /// A throttle keeping a thread busy `duty` of the time, between 0 and 1.
+    pub fn new(duty: f64) -> Self {
+        CpuThrottle::with_clock(duty, MonotonicClock::new())
+    }
+}
+
+impl<C: Clock> CpuThrottle<C> {
+    pub fn with_clock(duty: f64, clock: C) -> Self {
+        CpuThrottle { clock, duty: duty.clamp(1e-3, 1.0), balance: 0.0, last: None }
+    }
+
+    pub fn duty(&self) -> f64 {
+        self.duty
+    }
+
+    /// Mark the end of a chunk which kept the thread busy for `busy`,
+    /// returning how long to pause before the next one. The rest of the
+    /// time since the previous chunk ended, including the pause taken after
+    /// it, counts as idle.
+    pub fn end_chunk(&mut self, busy: Duration) -> Duration {
+        let now = self.clock.now();
+        let elapsed = self.last.map_or(busy, |last| now.saturating_sub(last)).as_secs_f64();
+        self.last = Some(now);
+        self.balance = (self.balance + busy.as_secs_f64() / self.duty - elapsed).max(-MAX_PAUSE.as_secs_f64());
+        Duration::from_secs_f64(self.balance.clamp(0.0, MAX_PAUSE.as_secs_f64()))
+    }
+}
+
+/// Processor time used by the calling thread, where the platform reports
+/// it.
+pub fn thread_cpu_time() -> Option<Duration> {
+    #[cfg(target_os = "linux")]
+    {
+        let mut time = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+        // Safe, as the pointer is to a live timespec.
+        if unsafe { libc::clock_gettime(libc::CLOCK_THREAD_CPUTIME_ID, &mut time) } == 0 {
+            return Some(Duration::new(time.tv_sec as u64, time.tv_nsec as u32));
+        }
+    }
+    None
+}
+
+/// Lower the priority of the calling thread, so that it yields to
+/// interactive programs. Platforms without thread priorities are left as
+/// they are.
+pub fn lower_thread_priority() {
+    #[cfg(target_os = "linux")]
+    // Priorities belong to threads on Linux, so this leaves the rest of the
+    // process alone.
+    unsafe {
+        libc::setpriority(libc::PRIO_PROCESS, 0, 19);
+    }
+    #[cfg(windows)]
+    unsafe {
+        use winapi::um::processthreadsapi::{GetCurrentThread, SetThreadPriority};
+        use winapi::um::winbase::THREAD_PRIORITY_IDLE;
+        SetThreadPriority(GetCurrentThread(), THREAD_PRIORITY_IDLE as i32);
+    }
+}
+
+impl RenderSession {
+    /// Run one chunk sized by `pacer`, then tonemap a preview only if the
+    /// display has asked for one through `signal`.
+    pub fn advance_paced<C: Clock>(
+        &mut self,
+        pacer: &mut FramePacer<C>,
+        signal: &FrameSignal,
+        tone: &ToneMapping,
+        dither: DitherMode,
+        overlay_stats: bool,
+    ) -> (AdvanceResult, Option<Buffer<u8>>) {
+        let before = self.iters();
+        let chunk = pacer.begin_chunk();
+        let result = self.advance(chunk);
+        let frame = signal.take().then(|| self.preview_8bit(tone, dither, overlay_stats));
+        // The time spent tonemapping counts towards the chunk, so that
+        // frames arrive at the target rate.
+        pacer.end_chunk(self.iters() - before);
+        (result, frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bench::baseline_config;
+    use crate::presets;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    /// A clock which only moves when the test moves it.
+    #[derive(Clone, Default)]
+    struct FakeClock(Rc<Cell<Duration>>);
+
+    impl FakeClock {
+        fn advance(&self, by: Duration) {
+            self.0.set(self.0.get() + by);
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> Duration {
+            self.0.get()
+        }
+    }
+
+    /// Run a chunk on a machine taking `per_iter` for each iteration.
+    fn run_chunk(pacer: &mut FramePacer<FakeClock>, clock: &FakeClock, per_iter: Duration) -> u64 {
+        let iters = pacer.begin_chunk();
+        clock.advance(per_iter * iters as u32);
+        pacer.end_chunk(iters);
+        iters
+    }
+
+    #[test]
+    fn chunks_converge_on_the_target_interval() {
+        let clock = FakeClock::default();
+        let mut pacer = FramePacer::with_clock(2.0, clock.clone());
+        assert_eq!(pacer.iters_per_chunk(), 100_000);
+        // At a microsecond an iteration, half a second is 500k iterations.
+        for _ in 0 .. 20 {
+            run_chunk(&mut pacer, &clock, Duration::from_micros(1));
+        }
+        let iters = pacer.iters_per_chunk();
+        assert!(iters.abs_diff(500_000) < 500, "{} iterations per chunk", iters);
+
+        // The machine slows down, and the chunks shrink to match.
+        for _ in 0 .. 20 {
+            run_chunk(&mut pacer, &clock, Duration::from_micros(10));
+        }
+        let iters = pacer.iters_per_chunk();
+        assert!(iters.abs_diff(50_000) < 50, "{} iterations per chunk", iters);
+    }
+
+    #[test]
+    fn a_single_slow_chunk_is_damped() {
+        let clock = FakeClock::default();
+        let mut pacer = FramePacer::with_clock(10.0, clock.clone()).with_limits(1_000, 1_000_000);
+        for _ in 0 .. 30 {
+            run_chunk(&mut pacer, &clock, Duration::from_micros(1));
+        }
+        assert!(pacer.iters_per_chunk().abs_diff(100_000) < 100);
+        // One chunk four times slower than the rest halves the next, rather
+        // than quartering it.
+        run_chunk(&mut pacer, &clock, Duration::from_micros(4));
+        assert!(pacer.iters_per_chunk().abs_diff(50_000) < 100, "{}", pacer.iters_per_chunk());
+    }
+
+    #[test]
+    fn chunk_sizes_stay_within_their_limits() {
+        let clock = FakeClock::default();
+        let mut pacer = FramePacer::with_clock(1.0, clock.clone()).with_limits(200_000, 300_000);
+        assert_eq!(pacer.iters_per_chunk(), 200_000);
+        for _ in 0 .. 10 {
+            run_chunk(&mut pacer, &clock, Duration::from_nanos(1));
+        }
+        assert_eq!(pacer.iters_per_chunk(), 300_000);
+        for _ in 0 .. 10 {
+            run_chunk(&mut pacer, &clock, Duration::from_millis(1));
+        }
+        assert_eq!(pacer.iters_per_chunk(), 200_000);
+    }
+
+    #[test]
+    fn unmeasurable_chunks_leave_the_size_alone() {
+        let clock = FakeClock::default();
+        let mut pacer = FramePacer::with_clock(2.0, clock.clone());
+        // No time passed, nothing ran, or the chunk was never begun.
+        pacer.begin_chunk();
+        pacer.end_chunk(100_000);
+        pacer.begin_chunk();
+        clock.advance(Duration::from_secs(1));
+        pacer.end_chunk(0);
+        clock.advance(Duration::from_secs(1));
+        pacer.end_chunk(100_000);
+        assert_eq!(pacer.iters_per_chunk(), 100_000);
+    }
+
+    /// Run `chunks` chunks, each busy for `busy`, sleeping what the throttle
+    /// asks for stretched by `overrun`, and return the fraction of the time
+    /// from the first chunk's start that was busy.
+    fn duty_cycle(throttle: &mut CpuThrottle<FakeClock>, clock: &FakeClock, chunks: u32, busy: Duration, overrun: f64) -> f64 {
+        let start = clock.now();
+        for _ in 0 .. chunks {
+            clock.advance(busy);
+            let pause = throttle.end_chunk(busy);
+            clock.advance(pause.mul_f64(overrun));
+        }
+        (busy * chunks).as_secs_f64() / (clock.now() - start).as_secs_f64()
+    }
+
+    #[test]
+    fn the_duty_cycle_converges_on_the_target() {
+        for duty in [0.25, 0.5, 0.8] {
+            for overrun in [1.0, 1.3] {
+                let clock = FakeClock::default();
+                let mut throttle = CpuThrottle::with_clock(duty, clock.clone());
+                // Settle, then measure over a long window.
+                duty_cycle(&mut throttle, &clock, 20, Duration::from_millis(10), overrun);
+                let found = duty_cycle(&mut throttle, &clock, 200, Duration::from_millis(10), overrun);
+                assert!((found - duty).abs() < 0.01, "{} at {} overrun is {}", duty, overrun, found);
+            }
+        }
+    }
+
+    #[test]
+    fn idle_time_is_credited_only_up_to_the_longest_pause() {
+        let clock = FakeClock::default();
+        let mut throttle = CpuThrottle::with_clock(0.5, clock.clone());
+        throttle.end_chunk(Duration::ZERO);
+        // After a long wait for work, the chunks run without pausing only
+        // until the credit of one longest pause is spent: 10ms at a time.
+        clock.advance(Duration::from_secs(10));
+        let busy = Duration::from_millis(10);
+        let unpaused = (0 .. 100).take_while(|_| {
+            clock.advance(busy);
+            throttle.end_chunk(busy).is_zero()
+        }).count();
+        let credit = MAX_PAUSE.as_millis() as usize / 10;
+        assert!((credit ..= credit + 1).contains(&unpaused), "{} chunks ran without pausing", unpaused);
+        let found = duty_cycle(&mut throttle, &clock, 100, busy, 1.0);
+        assert!((found - 0.5).abs() < 0.01, "{}", found);
+    }
+
+    #[test]
+    fn full_duty_never_pauses() {
+        let clock = FakeClock::default();
+        let mut throttle = CpuThrottle::with_clock(1.5, clock.clone());
+        assert_eq!(throttle.duty(), 1.0);
+        for _ in 0 .. 10 {
+            clock.advance(Duration::from_millis(5));
+            assert_eq!(throttle.end_chunk(Duration::from_millis(5)), Duration::ZERO);
+        }
+    }
+
+    #[test]
+    fn frame_requests_are_merged_until_served() {
+        let signal = FrameSignal::new();
+        assert!(!signal.take());
+        signal.request();
+        signal.clone().request();
+        assert!(signal.take());
+        assert!(!signal.take());
+    }
+
+    #[test]
+    fn previews_are_tonemapped_only_when_requested() {
+        let tone = ToneMapping::default();
+        let run = RunConfig { width: 16, height: 16, iters: 10_000, seed: Some(2), ..baseline_config(1) };
+        let mut session = RenderSession::new(presets::gasket(), run).unwrap();
+        let clock = FakeClock::default();
+        let mut pacer = FramePacer::with_clock(2.0, clock).with_limits(1_000, 1_000);
+        let signal = FrameSignal::new();
+
+        let (result, frame) = session.advance_paced(&mut pacer, &signal, &tone, DitherMode::None, false);
+        assert!(frame.is_none() && !result.done);
+        assert_eq!(session.iters(), 1_000);
+
+        signal.request();
+        let (_, frame) = session.advance_paced(&mut pacer, &signal, &tone, DitherMode::None, false);
+        let frame = frame.expect("a frame was requested");
+        assert_eq!((frame.width(), frame.height()), (16, 16));
+        let (_, frame) = session.advance_paced(&mut pacer, &signal, &tone, DitherMode::None, false);
+        assert!(frame.is_none(), "one request, one frame");
+    }
+}
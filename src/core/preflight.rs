@@ -0,0 +1,170 @@
+use super::*;
+
+/// Everything that can be learned about a render without running it.
+#[derive(Debug, Clone)]
+pub struct PreflightReport {
+    /// Problems with the flame or the configuration, most severe first.
+    pub findings: Vec<Finding>,
+    /// How strongly the flame's functions contract, if it has any functions
+    /// and finite parameters to measure.
+    pub contractivity: Option<ContractivityReport>,
+    /// Rough peak memory use of the render in bytes.
+    pub memory_bytes: u64,
+    /// Values adjusted while the flame and configuration were read.
+    pub diagnostics: Diagnostics,
+    /// Precision the orbits will be computed in, with `Precision::Auto`
+    /// resolved for the flame's bounds.
+    pub precision: Precision,
+    /// Iterations each orbit will run before plotting, with
+    /// `FuseMode::Auto` resolved for the flame.
+    pub fuse: FuseMode,
+}
+
+impl PreflightReport {
+    /// Include diagnostics collected before the check, which count towards
+    /// `worst` and `fails` as findings do.
+    pub fn with_diagnostics(mut self, diagnostics: Diagnostics) -> Self {
+        self.diagnostics.extend(diagnostics);
+        self
+    }
+
+    /// The most severe finding or diagnostic, if there are any.
+    pub fn worst(&self) -> Option<Severity> {
+        self.findings.iter().map(|f| f.severity).max().max(self.diagnostics.worst())
+    }
+
+    /// Whether any finding or diagnostic is at least as severe as `threshold`.
+    pub fn fails(&self, threshold: Severity) -> bool {
+        self.worst().is_some_and(|s| s >= threshold)
+    }
+}
+
+/// Check a flame and the configuration it is to be rendered with.
+///
+/// This runs `Flame::validate` along with checks of the configuration, so
+/// a render should not be started if any finding is an error.
+pub fn preflight(flame: &Flame, run_cfg: RunConfig, tone: &ToneMapping) -> PreflightReport {
+    let mut findings = flame.validate();
+    let mut finding = |severity, message: &str| findings.push(Finding { severity, message: message.to_string() });
+
+    if let Err(e) = run_cfg.check_size() {
+        finding(Severity::Error, &e.to_string());
+    }
+    if !(tone.gamma.is_finite() && tone.gamma > 0.0) {
+        finding(Severity::Error, "gamma must be positive");
+    }
+    if !(0.0 ..= 1.0).contains(&tone.vibrancy) {
+        finding(Severity::Warning, "vibrancy is outside [0, 1]");
+    }
+    if run_cfg.temporal_color.is_some_and(|t| !(0.0 ..= 1.0).contains(&t.blend)) {
+        finding(Severity::Warning, "temporal color blend is outside [0, 1]");
+    }
+    if run_cfg.iters == 0 {
+        finding(Severity::Warning, "no iterations will be run, so the image will be empty");
+    }
+    if run_cfg.attribution && flame.functions.len() > MAX_ATTRIBUTED_FUNCTIONS {
+        finding(Severity::Error, &format!(
+            "attribution maps need at most {} functions, but the flame has {}",
+            MAX_ATTRIBUTED_FUNCTIONS,
+            flame.functions.len(),
+        ));
+    }
+    if run_cfg.projection != Projection::None && matches!(run_cfg.plot_mode, PlotMode::Strokes { .. }) {
+        finding(Severity::Error, "strokes cannot be drawn in a projected image");
+    }
+    if run_cfg.projection == Projection::Equirect && run_cfg.width != 2 * run_cfg.height {
+        finding(Severity::Warning, "equirectangular images are usually twice as wide as they are high");
+    }
+    findings.sort_by_key(|f| std::cmp::Reverse(f.severity));
+
+    let contractivity = findings.iter().all(|f| f.severity < Severity::Error)
+        .then(|| flame.contractivity_report());
+
+    PreflightReport {
+        findings,
+        contractivity,
+        memory_bytes: estimate_memory(run_cfg, flame),
+        diagnostics: Diagnostics::new(),
+        precision: run_cfg.precision.resolve(&flame.bounds, run_cfg.width, run_cfg.height),
+        fuse: run_cfg.fuse.resolve(flame),
+    }
+}
+
+/// Peak memory of a render: a histogram per thread and another for each
+/// palette band, the noise estimate if one is kept, the mask's weights if
+/// there is one, the hits of each function if they are attributed, and the
+/// combined histograms while they are tonemapped.
+pub(crate) fn estimate_memory(cfg: RunConfig, flame: &Flame) -> u64 {
+    let pixels = (cfg.width as u64).saturating_mul(cfg.height as u64);
+    let padded = cfg.layout.len(cfg.width, cfg.height) as u64;
+    let per_thread = 1 + cfg.split_bands.map_or(0, |n| n.max(1) as u64);
+    let histograms = padded.saturating_mul(16 * per_thread * cfg.threads.max(1) as u64);
+    // Each band's combined histogram and 8-bit layer.
+    let bands = pixels.saturating_mul(cfg.split_bands.map_or(0, |n| n.max(1) as u64) * (16 + 4));
+    // The previous totals, mean and second moment of each bucket.
+    let variance = if cfg.track_variance { pixels.saturating_mul(20) } else { 0 };
+    let mask = if flame.mask.is_some() { pixels.saturating_mul(4) } else { 0 };
+    // One count per function per pixel on each thread, and their sum.
+    let attributed = cfg.attribution && flame.functions.len() <= MAX_ATTRIBUTED_FUNCTIONS;
+    let attribution = if attributed {
+        pixels.saturating_mul(4 * flame.functions.len() as u64 * (cfg.threads.max(1) as u64 + 1))
+    } else {
+        0
+    };
+    // Combined histogram, its floating point copy and the 8-bit result.
+    let tonemap = pixels.saturating_mul(16 + 32 + 4);
+    histograms.saturating_add(variance).saturating_add(mask).saturating_add(tonemap).saturating_add(bands)
+        .saturating_add(attribution)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bench::baseline_config;
+    use crate::presets;
+
+    #[test]
+    fn a_valid_render_has_no_findings() {
+        let cfg = baseline_config(4);
+        let report = preflight(&presets::gasket(), cfg, &ToneMapping::default());
+        assert!(report.findings.is_empty(), "{:?}", report.findings);
+        assert_eq!(report.worst(), None);
+        assert!(!report.fails(Severity::Warning));
+        assert!(report.contractivity.unwrap().is_likely_convergent());
+        assert_eq!(report.memory_bytes, estimate_memory(cfg, &presets::gasket()));
+    }
+
+    #[test]
+    fn warnings_do_not_fail_an_error_threshold() {
+        let cfg = RunConfig { iters: 0, ..baseline_config(1) };
+        let report = preflight(&presets::gasket(), cfg, &ToneMapping { vibrancy: 1.5, ..ToneMapping::default() });
+        let messages: Vec<_> = report.findings.iter().map(|f| (f.severity, f.message.as_str())).collect();
+        assert_eq!(messages, [
+            (Severity::Warning, "vibrancy is outside [0, 1]"),
+            (Severity::Warning, "no iterations will be run, so the image will be empty"),
+        ]);
+        assert_eq!(report.worst(), Some(Severity::Warning));
+        assert!(report.fails(Severity::Warning) && !report.fails(Severity::Error));
+        assert!(report.contractivity.is_some());
+    }
+
+    #[test]
+    fn errors_come_first_and_skip_the_contractivity_report() {
+        let cfg = RunConfig { width: 1, ..baseline_config(1) };
+        let report = preflight(&presets::gasket(), cfg, &ToneMapping { vibrancy: -1.0, gamma: f64::NAN, ..ToneMapping::default() });
+        let severities: Vec<_> = report.findings.iter().map(|f| f.severity).collect();
+        assert_eq!(severities, [Severity::Error, Severity::Error, Severity::Warning]);
+        assert!(report.findings.iter().any(|f| f.message == "gamma must be positive"));
+        assert!(report.fails(Severity::Error));
+        assert!(report.contractivity.is_none());
+    }
+
+    #[test]
+    fn each_band_adds_a_histogram_per_thread_and_a_layer() {
+        let cfg = RunConfig { width: 100, height: 50, ..baseline_config(3) };
+        let plain = estimate_memory(cfg, &presets::gasket());
+        let split = estimate_memory(RunConfig { split_bands: Some(4), ..cfg }, &presets::gasket());
+        let pixels = 100 * 50;
+        assert_eq!(split - plain, 4 * (pixels * 16 * 3 + pixels * (16 + 4)));
+    }
+}
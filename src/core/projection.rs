@@ -0,0 +1,267 @@
+use std::f32::consts::{FRAC_PI_2, PI};
+
+use nalgebra::Point2;
+
+use super::math::Math;
+use super::*;
+
+/// How the plane a flame is drawn on is mapped into the image, for display
+/// on a dome or in VR.
+///
+/// The plane is wrapped around a sphere by the inverse stereographic
+/// projection, touching it at the center of the flame's bounds, so that
+/// the circle inscribed in the bounds lies a given angle from that point:
+/// half the field of view for a fisheye, and a right angle for an
+/// equirectangular image. The sphere is then drawn in the image as the
+/// projection says. Every point of the plane lands somewhere on the
+/// sphere, so the bounds only place and scale the plane.
+///
+/// There is no stereographic fisheye: it would take the sphere back to the
+/// plane it came from, whatever its field of view, so it is `None`.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Projection {
+    /// The plane as it is, within the bounds.
+    #[default]
+    None,
+    /// An angular fisheye, as used for dome masters: the angle from the
+    /// center grows evenly out to the circle inscribed in the image, which
+    /// spans `fov_deg` degrees, less than 360.
+    Fisheye { fov_deg: f32 },
+    /// Longitude across the image, all the way round, and latitude up it,
+    /// with the center of the bounds in the middle.
+    Equirect,
+}
+
+impl Projection {
+    /// Names of the projections, as accepted by `from_str`.
+    pub const NAMES: [&'static str; 3] = ["none", "fisheye", "equirect"];
+
+    /// Field of view of a fisheye given without one, in degrees.
+    pub const DEFAULT_FOV: f32 = 180.0;
+}
+
+impl std::str::FromStr for Projection {
+    type Err = String;
+
+    /// Parses `none`, `equirect`, or `fisheye` optionally followed by its
+    /// field of view in degrees, as in `fisheye:180`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(':');
+        let err = || format!("invalid projection '{}' (expected none, fisheye[:FOV] or equirect)", s);
+        match parts.next().map(str::to_ascii_lowercase).as_deref() {
+            Some("none") if parts.next().is_none() => Ok(Projection::None),
+            Some("equirect") if parts.next().is_none() => Ok(Projection::Equirect),
+            Some("fisheye") => {
+                let fov_deg = parts.next().map_or(Ok(Projection::DEFAULT_FOV), str::parse).map_err(|_| err())?;
+                if parts.next().is_some() || !(fov_deg > 0.0 && fov_deg < 360.0) {
+                    return Err(err());
+                }
+                Ok(Projection::Fisheye { fov_deg })
+            }
+            _ => Err(err()),
+        }
+    }
+}
+
+impl std::fmt::Display for Projection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Projection::None => write!(f, "none"),
+            Projection::Fisheye { fov_deg } => write!(f, "fisheye:{}", fov_deg),
+            Projection::Equirect => write!(f, "equirect"),
+        }
+    }
+}
+
+/// A projection fitted to the bounds and size of an image, taking pixels
+/// of the flat image to pixels of the projected one.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ScreenProjection {
+    projection: Projection,
+    /// Center of the image in pixels, where the center of the bounds is.
+    center: [f32; 2],
+    /// Distance across the plane of a pixel of the flat image along each
+    /// axis, in units of the radius of the circle inscribed in the bounds.
+    scale: [f32; 2],
+    /// Tangent of half the angle the inscribed circle lies from the center.
+    tan_half: f32,
+    /// Angle of the edge of a fisheye from its center.
+    half_fov: f32,
+    /// Radius in pixels of the circle inscribed in the image.
+    radius: f32,
+}
+
+impl ScreenProjection {
+    /// `None` if `projection` leaves the plane as it is.
+    pub(crate) fn new(projection: Projection, bounds: &Bounds, width: usize, height: usize) -> Option<Self> {
+        let half_fov = match projection {
+            Projection::None => return None,
+            Projection::Fisheye { fov_deg } => fov_deg.to_radians() / 2.0,
+            Projection::Equirect => FRAC_PI_2,
+        };
+        let [x_min, x_max, y_min, y_max] = bounds.to_array();
+        let inscribed = (x_max - x_min).min(y_max - y_min) / 2.0;
+        let (w, h) = ((width.max(2) - 1) as f32, (height.max(2) - 1) as f32);
+        Some(ScreenProjection {
+            projection,
+            center: [w / 2.0, h / 2.0],
+            scale: [(x_max - x_min) / (w * inscribed), (y_max - y_min) / (h * inscribed)],
+            tan_half: (half_fov / 2.0).tan(),
+            half_fov,
+            radius: w.min(h) / 2.0,
+        })
+    }
+
+    /// The pixel of the projected image a pixel `p` of the flat one lands
+    /// on, if it lands on the image, and the probability of plotting it
+    /// there.
+    ///
+    /// The probability is how much the projection enlarges the area around
+    /// `p`, relative to how much it enlarges it at the center, so that
+    /// points spread evenly over the plane are spread evenly over the
+    /// image too. Parts of an equirectangular image near the poles are
+    /// enlarged without limit, and stay fainter.
+    pub(crate) fn project<M: Math>(&self, p: Point2<f32>) -> Option<(Point2<f32>, f32)> {
+        let [cx, cy] = self.center;
+        let (qx, qy) = ((p[0] - cx) * self.scale[0], (p[1] - cy) * self.scale[1]);
+        let rho = qx.hypot(qy);
+        if !rho.is_finite() {
+            return None;
+        }
+        let (ux, uy) = if rho > 0.0 { (qx / rho, qy / rho) } else { (0.0, 0.0) };
+
+        // The stereographic projection meets the sphere at an angle theta
+        // from the center, with tan(theta / 2) proportional to rho.
+        let tan_half = self.tan_half * rho;
+        let half = M::atan(tan_half);
+        let cos_half = 1.0 / tan_half.hypot(1.0);
+        let sin_half = tan_half * cos_half;
+        // Areas of the plane are shrunk onto the sphere by cos^4(theta / 2)
+        // relative to the center.
+        let onto_sphere = cos_half.powi(4);
+
+        let (projected, weight) = match self.projection {
+            Projection::None => (p, 1.0),
+            Projection::Fisheye { .. } => {
+                let r = self.radius * 2.0 * half / self.half_fov;
+                // An angular fisheye enlarges areas of the sphere by
+                // theta / sin(theta).
+                let enlarged = if sin_half > 0.0 { half / (sin_half * cos_half) } else { 1.0 };
+                (Point2::new(cx + r * ux, cy + r * uy), onto_sphere * enlarged)
+            }
+            Projection::Equirect => {
+                let sin_theta = 2.0 * sin_half * cos_half;
+                let cos_theta = cos_half * cos_half - sin_half * sin_half;
+                let (vx, vy, vz) = (sin_theta * ux, sin_theta * uy, cos_theta);
+                let cos_lat = vx.hypot(vz);
+                let (lon, lat) = (M::atan2(vx, vz), M::atan2(-vy, cos_lat));
+                // Latitudes are enlarged by 1 / cos(latitude).
+                let weight = if cos_lat > 0.0 { (onto_sphere / cos_lat).min(1.0) } else { 1.0 };
+                (Point2::new(cx * (1.0 + lon / PI), cy * (1.0 - lat / FRAC_PI_2)), weight)
+            }
+        };
+        let [w, h] = [2.0 * cx, 2.0 * cy];
+        let on_image = (0.0 ..= w).contains(&projected[0]) && (0.0 ..= h).contains(&projected[1]);
+        on_image.then_some((projected, weight))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::math::PlatformMath;
+
+    /// Points to a side in each pixel of the flat image.
+    const PER_PIXEL: usize = 16;
+    /// Pixels to a side in each block of the projected image.
+    const BLOCK: usize = 8;
+
+    /// Density of an image projected from points spread evenly over the
+    /// flat image, summed over blocks of pixels. Unweighted points are all
+    /// plotted, and weighted ones in proportion to their weight.
+    fn density(projection: Projection, width: usize, height: usize, weighted: bool) -> Vec<Vec<f64>> {
+        let (per_pixel, block) = (PER_PIXEL, BLOCK);
+        let bounds = Bounds::new(-(width as f32) / height as f32, width as f32 / height as f32, -1.0, 1.0);
+        let screen = ScreenProjection::new(projection, &bounds, width, height).unwrap();
+        let mut blocks = vec![vec![0.0; width / block]; height / block];
+        let step = 1.0 / per_pixel as f32;
+        for i in 0 .. (width - 1) * per_pixel {
+            for j in 0 .. (height - 1) * per_pixel {
+                let p = Point2::new((i as f32 + 0.5) * step, (j as f32 + 0.5) * step);
+                if let Some((q, weight)) = screen.project::<PlatformMath>(p) {
+                    let (x, y) = (q[0] as usize / block, q[1] as usize / block);
+                    if let Some(b) = blocks.get_mut(y).and_then(|row| row.get_mut(x)) {
+                        *b += if weighted { weight as f64 } else { 1.0 };
+                    }
+                }
+            }
+        }
+        blocks
+    }
+
+    /// The ratio of the densest block to the faintest of those whose
+    /// corners, in pixels, all satisfy `within`.
+    fn spread(blocks: &[Vec<f64>], within: impl Fn(f32, f32) -> bool) -> f64 {
+        let block = BLOCK as f32;
+        let (mut min, mut max) = (f64::INFINITY, 0.0f64);
+        for (y, row) in blocks.iter().enumerate() {
+            for (x, &b) in row.iter().enumerate() {
+                let corners = [(0.0, 0.0), (1.0, 0.0), (0.0, 1.0), (1.0, 1.0)]
+                    .map(|(dx, dy)| ((x as f32 + dx) * block, (y as f32 + dy) * block));
+                if corners.iter().all(|&(cx, cy)| within(cx, cy)) {
+                    (min, max) = (min.min(b), max.max(b));
+                }
+            }
+        }
+        assert!(min > 0.0, "no block was checked, or one was empty");
+        max / min
+    }
+
+    #[test]
+    fn even_density_stays_even_in_a_fisheye() {
+        let size = 64;
+        let radius = (size - 1) as f32 / 2.0;
+        // Blocks well inside the circle, clear of its ragged edge.
+        let inside = |x: f32, y: f32| (x - radius).hypot(y - radius) < 0.9 * radius;
+        for fov_deg in [90.0, 180.0, 270.0] {
+            let projection = Projection::Fisheye { fov_deg };
+            let even = spread(&density(projection, size, size, true), inside);
+            assert!(even < 1.05, "the densest block of a {} degree fisheye is {} times the faintest", fov_deg, even);
+        }
+        // Without the weights, the center is much denser than the edge.
+        let uneven = spread(&density(Projection::Fisheye { fov_deg: 180.0 }, size, size, false), inside);
+        assert!(uneven > 1.5, "unweighted, the densest block is only {} times the faintest", uneven);
+    }
+
+    #[test]
+    fn even_density_stays_even_in_an_equirectangular_image() {
+        let (width, height) = (128, 64);
+        let (cx, cy) = ((width - 1) as f32 / 2.0, (height - 1) as f32 / 2.0);
+        // Within 75 degrees of longitude and 45 of latitude of the center,
+        // short of the poles, which stay fainter, and of the far side of
+        // the sphere, which lies beyond the flat image.
+        let inside = |x: f32, y: f32| {
+            let (lon, lat) = ((x / cx - 1.0) * 180.0, (1.0 - y / cy) * 90.0);
+            lon.abs() <= 75.0 && lat.abs() <= 45.0
+        };
+        let even = spread(&density(Projection::Equirect, width, height, true), inside);
+        assert!(even < 1.05, "the densest block is {} times the faintest", even);
+        let uneven = spread(&density(Projection::Equirect, width, height, false), inside);
+        assert!(uneven > 1.5, "unweighted, the densest block is only {} times the faintest", uneven);
+    }
+
+    #[test]
+    fn projections_parse_with_their_defaults() {
+        assert_eq!("none".parse(), Ok(Projection::None));
+        assert_eq!("Equirect".parse(), Ok(Projection::Equirect));
+        assert_eq!("fisheye".parse(), Ok(Projection::Fisheye { fov_deg: Projection::DEFAULT_FOV }));
+        assert_eq!(Projection::DEFAULT_FOV, 180.0);
+        assert_eq!("fisheye:220".parse(), Ok(Projection::Fisheye { fov_deg: 220.0 }));
+        for bad in ["fisheye:0", "fisheye:360", "fisheye:-90", "fisheye:wide", "fisheye:180:1", "none:1", "equirect:2", "stereographic", ""] {
+            assert!(bad.parse::<Projection>().is_err(), "{:?} was accepted", bad);
+        }
+        for projection in [Projection::None, Projection::Fisheye { fov_deg: 137.5 }, Projection::Equirect] {
+            assert_eq!(projection.to_string().parse(), Ok(projection));
+        }
+    }
+}
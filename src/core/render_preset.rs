@@ -0,0 +1,188 @@
+use super::*;
+
+/// A named bundle of settings trading render time for quality.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RenderPreset {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub iters: usize,
+    /// Factor the image dimensions are multiplied by.
+    pub scale: f64,
+    pub gamma: f64,
+    pub vibrancy: f64,
+    pub highlights: HighlightMode,
+}
+
+impl RenderPreset {
+    /// Small and noisy, for checking composition.
+    pub const DRAFT: RenderPreset = RenderPreset {
+        name: "draft",
+        description: "quick, noisy preview at half size",
+        iters: 1_000_000,
+        scale: 0.5,
+        gamma: 2.2,
+        vibrancy: 0.0,
+        highlights: HighlightMode::Clip,
+    };
+
+    /// The settings used when no preset is given.
+    pub const STANDARD: RenderPreset = RenderPreset {
+        name: "standard",
+        description: "the defaults",
+        iters: 5_000_000,
+        scale: 1.0,
+        gamma: 2.2,
+        vibrancy: 0.0,
+        highlights: HighlightMode::Clip,
+    };
+
+    pub const FINAL: RenderPreset = RenderPreset {
+        name: "final",
+        description: "clean image at twice the size",
+        iters: 100_000_000,
+        scale: 2.0,
+        gamma: 2.2,
+        vibrancy: 0.5,
+        highlights: HighlightMode::PreserveHue,
+    };
+
+    pub const PRINT: RenderPreset = RenderPreset {
+        name: "print",
+        description: "large, very clean image for printing",
+        iters: 1_000_000_000,
+        scale: 4.0,
+        gamma: 2.2,
+        vibrancy: 0.5,
+        highlights: HighlightMode::DesaturateToWhite { knee: 0.5 },
+    };
+
+    /// Every bundled preset, from cheapest to most expensive.
+    pub const ALL: [RenderPreset; 4] = [
+        RenderPreset::DRAFT,
+        RenderPreset::STANDARD,
+        RenderPreset::FINAL,
+        RenderPreset::PRINT,
+    ];
+
+    /// Names of the bundled presets.
+    pub fn names() -> impl Iterator<Item = &'static str> {
+        RenderPreset::ALL.iter().map(|p| p.name)
+    }
+
+    /// Overwrite the settings the preset covers, scaling the image
+    /// dimensions already in `run`.
+    pub fn apply(&self, run: &mut RunConfig, tone: &mut ToneMapping) {
+        let scale = |n: usize| ((n as f64 * self.scale).round() as usize).max(1);
+        run.width = scale(run.width);
+        run.height = scale(run.height);
+        run.iters = self.iters;
+        tone.gamma = self.gamma;
+        tone.vibrancy = self.vibrancy;
+        tone.highlights = self.highlights;
+    }
+}
+
+impl std::str::FromStr for RenderPreset {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.to_ascii_lowercase();
+        if let Some(preset) = RenderPreset::ALL.iter().find(|p| p.name == s) {
+            return Ok(*preset);
+        }
+        let closest = RenderPreset::ALL.iter()
+            .map(|p| (edit_distance(&s, p.name), p.name))
+            .min()
+            .filter(|&(d, _)| d <= 2);
+        Err(match closest {
+            Some((_, name)) => format!("unknown preset '{}' (did you mean '{}'?)", s, name),
+            None => format!("unknown preset '{}' (expected draft, standard, final or print)", s),
+        })
+    }
+}
+
+/// Number of single character insertions, deletions and substitutions
+/// needed to turn `a` into `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0 ..= b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diag = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let next = (diag + (ca != cb) as usize).min(row[j] + 1).min(row[j + 1] + 1);
+            diag = row[j + 1];
+            row[j + 1] = next;
+        }
+    }
+    row[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bench::baseline_config;
+
+    /// The configurations a preset makes from a 300x200 image.
+    fn applied(preset: RenderPreset) -> (RunConfig, ToneMapping) {
+        let (mut run, mut tone) = (RunConfig { width: 300, height: 200, ..baseline_config(1) }, ToneMapping::default());
+        preset.apply(&mut run, &mut tone);
+        (run, tone)
+    }
+
+    #[test]
+    fn presets_set_what_they_cover() {
+        let (run, tone) = applied(RenderPreset::DRAFT);
+        assert_eq!((run.width, run.height, run.iters), (150, 100, 1_000_000));
+        assert_eq!((tone.gamma, tone.vibrancy, tone.highlights), (2.2, 0.0, HighlightMode::Clip));
+        // Settings outside the preset are left alone.
+        assert_eq!((run.threads, run.seed), (1, baseline_config(1).seed));
+        assert_eq!(tone.background, Color::rgb(0, 0, 0));
+
+        let (run, tone) = applied(RenderPreset::PRINT);
+        assert_eq!((run.width, run.height), (1200, 800));
+        assert_eq!(tone.highlights, HighlightMode::DesaturateToWhite { knee: 0.5 });
+
+        // Tiny images still have a pixel.
+        let (mut run, mut tone) = (RunConfig { width: 1, height: 1, ..baseline_config(1) }, ToneMapping::default());
+        RenderPreset::DRAFT.apply(&mut run, &mut tone);
+        assert_eq!((run.width, run.height), (1, 1));
+    }
+
+    #[test]
+    fn each_preset_costs_more_than_the_last() {
+        let cost = |p: RenderPreset| {
+            let (run, _) = applied(p);
+            run.iters as f64 * (run.width * run.height) as f64
+        };
+        for pair in RenderPreset::ALL.windows(2) {
+            assert!(cost(pair[0]) < cost(pair[1]), "{} is not cheaper than {}", pair[0].name, pair[1].name);
+        }
+        assert!(cost(RenderPreset::DRAFT) * 100. < cost(RenderPreset::FINAL));
+    }
+
+    #[test]
+    fn presets_are_found_by_name_with_suggestions() {
+        for preset in RenderPreset::ALL {
+            assert_eq!(preset.name.parse(), Ok(preset));
+            assert_eq!(preset.name.to_uppercase().parse(), Ok(preset));
+        }
+        assert_eq!(RenderPreset::names().collect::<Vec<_>>(), ["draft", "standard", "final", "print"]);
+        assert_eq!("drfat".parse::<RenderPreset>(), Err("unknown preset 'drfat' (did you mean 'draft'?)".to_string()));
+        assert_eq!("Prnt".parse::<RenderPreset>(), Err("unknown preset 'prnt' (did you mean 'print'?)".to_string()));
+        assert_eq!(
+            "ultra".parse::<RenderPreset>(),
+            Err("unknown preset 'ultra' (expected draft, standard, final or print)".to_string()),
+        );
+    }
+
+    #[test]
+    fn edit_distance_counts_single_character_edits() {
+        assert_eq!(edit_distance("", "final"), 5);
+        assert_eq!(edit_distance("final", "final"), 0);
+        assert_eq!(edit_distance("fnal", "final"), 1);
+        assert_eq!(edit_distance("finale", "final"), 1);
+        assert_eq!(edit_distance("fianl", "final"), 2);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+    }
+}
@@ -0,0 +1,2102 @@
+use core_affinity::CoreId;
+use nalgebra::{Affine2, Point2, RealField};
+use num_traits::Float;
+use rand::distributions::Standard;
+use rand::prelude::*;
+use rand::rngs::StdRng;
+use std::ops::Range;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use super::*;
+use super::math::{Math, PlatformMath, PortableMath};
+
+/// Largest dimension of the proxy image compared by stop conditions.
+const PROXY_SIZE: usize = 128;
+
+/// Number of chunks a run is split into when tracking variance without a
+/// stop condition.
+const VARIANCE_CHUNKS: u64 = 16;
+
+/// Number of iterations between the pauses of a thread held to a CPU
+/// limit, which takes a few milliseconds.
+const THROTTLE_CHUNK: u64 = 1 << 16;
+
+/// Number of iterations in each chunk of a run whose histogram does not
+/// depend on its number of threads.
+pub const STABLE_CHUNK_ITERS: u64 = 1_000_000;
+
+/// Index of the band of palette positions `color` falls in, when the
+/// palette is split into `bands` equal bands.
+pub fn color_band(color: u8, bands: usize) -> usize {
+    (color as usize * bands / 255).min(bands.max(1) - 1)
+}
+
+/// The state of a single chaos game orbit, which persists between calls to
+/// `RenderSession::advance`.
+struct Orbit {
+    rng: StdRng,
+    /// The current point, in double precision whatever the orbit's precision.
+    point: Point2<f64>,
+    color: u8,
+    /// Position along the palette of the flam3 color model, which `color`
+    /// is rounded from.
+    position: f32,
+    /// Iterations left before points are plotted again.
+    skip: u64,
+    /// Iterations run from each random start before plotting.
+    fuse: u64,
+    iters: u64,
+    quota: u64,
+    /// Value of `iters` when the current chunk began, and the iteration of
+    /// the whole run it began at, which place the orbit's points in time.
+    chunk_began: u64,
+    chunk_start: u64,
+    /// Number of iterations over which points are colored by time.
+    timeline: u64,
+    buffer: Buffer<u32>,
+    /// Histogram of each band of palette positions, if they are kept.
+    bands: Option<Vec<Buffer<u32>>>,
+    /// Hits of each function in each bucket, if they are being counted.
+    attribution: Option<Attribution>,
+    /// Index of the function applied in the current iteration.
+    function: usize,
+    /// Core the orbit's threads are pinned to, if any.
+    core: Option<CoreId>,
+    /// Time spent iterating.
+    busy: Duration,
+    mode: PlotMode,
+    /// Screen position of the previous point of the current stroke.
+    last: Option<Point2<f32>>,
+    /// Number of points in the current stroke.
+    stroke: u8,
+    temporal: Option<TemporalColor>,
+    monochrome: bool,
+    deterministic_math: bool,
+    precision: Precision,
+    /// Number of iterations after which the orbit starts again from a new
+    /// random point, or zero if it runs from a single start.
+    restart_every: u64,
+    /// Index of the thread running the orbit.
+    thread: usize,
+    /// Counts for each function, if they are being kept.
+    function_stats: Option<Vec<FunctionStats>>,
+    /// Pauses keeping the orbit's thread to the CPU limit, if there is one.
+    throttle: Option<CpuThrottle>,
+    /// Broken invariants found so far, if the run is paranoid.
+    incidents: Option<IncidentLog>,
+    /// The function applied in the current iteration and the point it was
+    /// applied to, kept by paranoid runs to describe incidents.
+    current: (usize, Point2<f64>),
+    /// Where the orbit publishes its progress, if the run is watched.
+    heartbeat: Option<Arc<Heartbeat>>,
+    token: CancelToken,
+}
+
+/// What an orbit's points are plotted with, which is the same for every
+/// iteration of an `advance`.
+#[derive(Clone, Copy)]
+struct Plot<'a, T: RealField> {
+    flame: &'a Flame,
+    trans: &'a Affine2<T>,
+    projection: Option<&'a ScreenProjection>,
+    mask: Option<&'a [f32]>,
+    /// Whether every color of the palette is opaque.
+    opaque: bool,
+}
+
+impl Orbit {
+    fn new(thread: usize, mut rng: StdRng, quota: u64, core: Option<CoreId>, functions: usize, cfg: RunConfig) -> Self {
+        let (point, color) = first_point(&mut rng, cfg.precision);
+        Orbit {
+            point,
+            color,
+            position: color as f32 / 255.,
+            rng,
+            skip: cfg.fuse.iters(),
+            fuse: cfg.fuse.iters(),
+            iters: 0,
+            quota,
+            chunk_began: 0,
+            chunk_start: 0,
+            timeline: if cfg.stable_chunk_iters.is_some() { cfg.iters as u64 } else { quota },
+            buffer: Buffer::new_with_layout(cfg.width, cfg.height, cfg.layout),
+            bands: cfg.split_bands.map(|n| vec![Buffer::new_with_layout(cfg.width, cfg.height, cfg.layout); n.max(1)]),
+            attribution: (cfg.attribution && functions <= MAX_ATTRIBUTED_FUNCTIONS)
+                .then(|| Attribution::new(cfg.width, cfg.height, functions)),
+            function: 0,
+            core,
+            busy: Duration::ZERO,
+            mode: cfg.plot_mode,
+            last: None,
+            stroke: 0,
+            temporal: cfg.temporal_color,
+            monochrome: cfg.monochrome,
+            deterministic_math: cfg.deterministic_math,
+            precision: cfg.precision,
+            restart_every: if cfg.restarts_per_thread > 1 {
+                quota.div_ceil(cfg.restarts_per_thread as u64)
+            } else {
+                0
+            },
+            thread,
+            function_stats: cfg.per_function_stats.then(|| vec![FunctionStats::default(); functions]),
+            // Threads share the limit on the processors the machine has, so
+            // that running more threads than cores does not raise it.
+            throttle: cfg.cpu_limit.map(|limit| {
+                let cores = thread::available_parallelism().map_or(1, |n| n.get());
+                CpuThrottle::new(limit * cores as f64 / cfg.threads.max(1) as f64)
+            }),
+            incidents: cfg.paranoid.then(|| IncidentLog::new(INCIDENT_CAPACITY)),
+            current: (0, Point2::origin()),
+            heartbeat: None,
+            token: CancelToken::new(),
+        }
+    }
+
+    fn remaining(&self) -> u64 {
+        self.quota - self.iters
+    }
+
+    /// Start the orbit afresh from `rng` for the `len` iterations of the
+    /// run beginning at iteration `start`.
+    fn begin_chunk(&mut self, mut rng: StdRng, start: u64, len: u64) {
+        (self.point, self.color) = first_point(&mut rng, self.precision);
+        self.position = self.color as f32 / 255.;
+        self.rng = rng;
+        self.skip = self.fuse;
+        self.break_stroke();
+        self.quota += len;
+        self.chunk_began = self.iters;
+        self.chunk_start = start;
+    }
+
+    /// Run at most `n` more iterations, returning the number of points plotted.
+    ///
+    /// Points are kept with probability given by `mask`, the weight of each
+    /// pixel, if there is one.
+    fn advance(&mut self, flame: &Flame, screen: &ScreenTransform, mask: Option<&[f32]>, n: u64) -> u64 {
+        let Some(mut throttle) = self.throttle.take() else {
+            return self.advance_chunk(flame, screen, mask, n);
+        };
+        let mut plotted = 0;
+        let mut left = n.min(self.remaining());
+        while left > 0 {
+            let chunk = left.min(THROTTLE_CHUNK);
+            // Threads which share a processor are only busy for the time
+            // they are given, which the wall clock overstates.
+            let (cpu, wall) = (thread_cpu_time(), Instant::now());
+            plotted += self.advance_chunk(flame, screen, mask, chunk);
+            let busy = match (cpu, thread_cpu_time()) {
+                (Some(before), Some(after)) => after.saturating_sub(before),
+                _ => wall.elapsed(),
+            };
+            thread::sleep(throttle.end_chunk(busy));
+            left -= chunk;
+            if self.token.is_cancelled() {
+                break;
+            }
+        }
+        self.throttle = Some(throttle);
+        plotted
+    }
+
+    /// `advance`, without pausing for the CPU limit.
+    fn advance_chunk(&mut self, flame: &Flame, screen: &ScreenTransform, mask: Option<&[f32]>, n: u64) -> u64 {
+        if self.incidents.is_some() {
+            self.advance_with::<true>(flame, screen, mask, n)
+        } else {
+            self.advance_with::<false>(flame, screen, mask, n)
+        }
+    }
+
+    /// `advance`, checking the invariants of every iteration if `PARANOID`.
+    fn advance_with<const PARANOID: bool>(&mut self, flame: &Flame, screen: &ScreenTransform, mask: Option<&[f32]>, n: u64) -> u64 {
+        match (self.precision, self.deterministic_math) {
+            (Precision::F64, false) => self.run::<PlatformMath, f64, PARANOID>(flame, &screen.double, screen.projection.as_ref(), mask, n),
+            (Precision::F64, true) => self.run::<PortableMath, f64, PARANOID>(flame, &screen.double, screen.projection.as_ref(), mask, n),
+            (_, false) => self.run::<PlatformMath, f32, PARANOID>(flame, &screen.single, screen.projection.as_ref(), mask, n),
+            (_, true) => self.run::<PortableMath, f32, PARANOID>(flame, &screen.single, screen.projection.as_ref(), mask, n),
+        }
+    }
+
+    /// `advance`, computing the orbit with the functions of `M` in the
+    /// precision of `T`.
+    ///
+    /// Iterations run in blocks which end at each heartbeat and restart, so
+    /// that cancellation, heartbeats and restarts are checked once a block
+    /// rather than every iteration.
+    fn run<M, T, const PARANOID: bool>(
+        &mut self,
+        flame: &Flame,
+        trans: &Affine2<T>,
+        projection: Option<&ScreenProjection>,
+        mask: Option<&[f32]>,
+        n: u64,
+    ) -> u64
+    where
+        M: Math,
+        T: Float + RealField,
+        Standard: Distribution<T>,
+    {
+        let start = Instant::now();
+        let mut plotted = 0;
+        let Some(selector) = FunctionSelector::new(&flame.functions) else {
+            // With nothing to choose from, every iteration plots nothing.
+            self.iters += n.min(self.remaining());
+            return 0;
+        };
+        let mut point: Point2<T> = self.point.map(|v| T::from_subset(&v));
+        let plot = Plot { flame, trans, projection, mask, opaque: flame.palette.is_opaque() };
+        // Points are only thinned by masks, projections and transparent
+        // colors, so runs with none of them plot every point they keep.
+        let weighted = mask.is_some() || projection.is_some() || !plot.opaque;
+        let iterate = match (self.function_stats.is_some(), weighted) {
+            (false, false) => Orbit::iterate::<M, T, PARANOID, false, false>,
+            (false, true) => Orbit::iterate::<M, T, PARANOID, false, true>,
+            (true, false) => Orbit::iterate::<M, T, PARANOID, true, false>,
+            (true, true) => Orbit::iterate::<M, T, PARANOID, true, true>,
+        };
+
+        let mut left = n.min(self.remaining());
+        while left > 0 && !self.token.is_cancelled() {
+            if self.restart_every > 0 && self.iters > 0 && self.iters.is_multiple_of(self.restart_every) {
+                point = self.restart();
+            }
+            let mut block = left.min(HEARTBEAT_ITERS - self.iters % HEARTBEAT_ITERS);
+            if self.restart_every > 0 {
+                block = block.min(self.restart_every - self.iters % self.restart_every);
+            }
+            // The first function of the block is chosen here, so that a
+            // heartbeat can name it.
+            let function = selector.sample(&mut self.rng);
+            if let Some(heartbeat) = self.heartbeat.as_ref().filter(|_| self.iters.is_multiple_of(HEARTBEAT_ITERS)) {
+                heartbeat.beat(self.iters, function);
+            }
+            plotted += iterate(self, &plot, &selector, function, &mut point, block);
+            left -= block;
+        }
+
+        self.point = point.map(|v| v.to_f64().unwrap());
+        self.busy += start.elapsed();
+        plotted
+    }
+
+    /// Run `n` iterations, the first applying `first`, returning the number
+    /// of points plotted. Functions are counted if `STATS`, and points are
+    /// thinned by their weight if `WEIGHTED`.
+    fn iterate<M, T, const PARANOID: bool, const STATS: bool, const WEIGHTED: bool>(
+        &mut self,
+        plot: &Plot<T>,
+        selector: &FunctionSelector,
+        first: usize,
+        point: &mut Point2<T>,
+        n: u64,
+    ) -> u64
+    where
+        M: Math,
+        T: Float + RealField,
+        Standard: Distribution<T>,
+    {
+        let mut p = *point;
+        let mut plotted = self.step::<M, T, PARANOID, STATS, WEIGHTED>(plot, first, &mut p);
+        for _ in 1 .. n {
+            let function = selector.sample(&mut self.rng);
+            plotted += self.step::<M, T, PARANOID, STATS, WEIGHTED>(plot, function, &mut p);
+        }
+        *point = p;
+        plotted
+    }
+
+    /// Apply `function` to `point` and plot the result, returning the
+    /// number of points plotted.
+    #[inline(always)]
+    fn step<M, T, const PARANOID: bool, const STATS: bool, const WEIGHTED: bool>(
+        &mut self,
+        plot: &Plot<T>,
+        function: usize,
+        point: &mut Point2<T>,
+    ) -> u64
+    where
+        M: Math,
+        T: Float + RealField,
+        Standard: Distribution<T>,
+    {
+        let Plot { flame, trans, projection, mask, opaque } = *plot;
+        let f = &flame.functions[function];
+        self.function = function;
+        if PARANOID {
+            self.current = (function, point.map(|v| v.to_f64().unwrap()));
+        }
+        if STATS {
+            if let Some(stats) = &mut self.function_stats {
+                stats[function].selected += 1;
+            }
+        }
+
+        *point = f.eval_with::<M, T>(*point);
+        let finite = Float::is_finite(point[0]) && Float::is_finite(point[1]);
+        match flame.color_model {
+            ColorModel::Native => self.color = ((self.color as u16 + f.color as u16) / 2) as u8,
+            ColorModel::Flam3 if finite => {
+                self.position = (self.position + f.color as f32 / 255.) / 2.;
+                self.color = (self.position * 255.).round() as u8;
+            }
+            ColorModel::Flam3 => {}
+        }
+        self.iters += 1;
+
+        if !finite {
+            // The orbit escaped to infinity or hit a singularity, so start
+            // it again from a random point.
+            if PARANOID {
+                self.incident(IncidentKind::NonFinite);
+            }
+            *point = self.restart();
+            return 0;
+        } else if self.skip > 0 {
+            self.skip -= 1;
+            return 0;
+        } else if projection.is_none() && !flame.bounds.contains(point) {
+            // Segments leaving or entering the bounds are not drawn.
+            self.break_stroke();
+            return 0;
+        }
+
+        let mut screen_point = (*trans * *point).map(|v| v.to_f32().unwrap());
+        // Projections take the whole plane onto the sphere, so points
+        // outside the bounds land in the image too, and some inside do not.
+        let mut projected = 1.0;
+        if let Some(projection) = projection {
+            let Some((p, w)) = projection.project::<M>(screen_point) else {
+                self.break_stroke();
+                return 0;
+            };
+            (screen_point, projected) = (p, w);
+        }
+        if STATS {
+            if let Some(stats) = &mut self.function_stats {
+                stats[function].record_in_bounds(screen_point.into());
+            }
+        }
+        let (width, height) = (self.buffer.width(), self.buffer.height());
+        let on_screen = |p: Point2<f32>| p[0] >= 0.0 && p[1] >= 0.0 && (p[0] as usize) < width && (p[1] as usize) < height;
+        if PARANOID && !on_screen(screen_point) {
+            self.incident(IncidentKind::OffScreen { screen: screen_point.into() });
+            return 0;
+        }
+        // Monochrome runs do not plot colors, so need not sample them
+        // unless the palette's opacity varies.
+        let ColorA { color, alpha } = if self.monochrome && opaque {
+            ColorA::opaque(Color::rgb(0, 0, 0))
+        } else {
+            self.plot_color::<PARANOID>(&flame.palette)
+        };
+        let weight = |p: Point2<f32>| mask.map_or(1.0, |m| m[p[0] as usize + p[1] as usize * width]);
+        let opacity = alpha as f32 / 255.;
+        match self.mode {
+            PlotMode::Points => {
+                // Points of weight 1, and so all points of unweighted runs,
+                // draw no random numbers, leaving the render unchanged.
+                if WEIGHTED {
+                    let w = weight(screen_point) * projected * opacity;
+                    if w < 1.0 && !(w > 0.0 && self.rng.gen::<f32>() < w) {
+                        return 0;
+                    }
+                }
+                self.hit::<PARANOID>(screen_point, color);
+                1
+            }
+            PlotMode::Strokes { length, attenuation } => {
+                let weight = |p| weight(p) * opacity;
+                self.stroke_to::<PARANOID>(screen_point, color, length, attenuation, weight)
+            }
+        }
+    }
+
+    /// A random point to continue the orbit from, after which it warms up
+    /// again before plotting.
+    fn restart<T>(&mut self) -> Point2<T>
+    where
+        T: Float + RealField,
+        Standard: Distribution<T>,
+    {
+        self.skip = self.fuse;
+        self.break_stroke();
+        Point2::new(self.rng.gen(), self.rng.gen())
+    }
+
+    /// Color and opacity of the current point.
+    fn plot_color<const PARANOID: bool>(&mut self, palette: &Palette) -> ColorA {
+        let color = palette.sample(self.color);
+        match self.temporal {
+            Some(temporal) => {
+                let progress = (self.chunk_start + self.iters - self.chunk_began) as f32 / self.timeline as f32;
+                if PARANOID && !(0.0 ..= 1.0).contains(&progress) {
+                    self.incident(IncidentKind::ColorOutOfRange { position: progress });
+                }
+                color.lerp(palette.sample_at(progress), temporal.blend, ColorSpace::Srgb)
+            }
+            None => color,
+        }
+    }
+
+    /// Extend the current stroke to the screen position `p`, returning the
+    /// number of pixels hit.
+    ///
+    /// Each pixel a segment passes through is hit with probability equal to
+    /// its weight times `attenuation` and `weight` there, the mask's weight
+    /// times the color's opacity, so that the histogram's expected value is
+    /// the fractional coverage while its buckets stay integers.
+    fn stroke_to<const PARANOID: bool>(
+        &mut self,
+        p: Point2<f32>,
+        color: Color,
+        length: u8,
+        attenuation: f32,
+        mask: impl Fn(Point2<f32>) -> f32,
+    ) -> u64 {
+        let mut hits = 0;
+        if let Some(last) = self.last {
+            for (pixel, weight) in segment_pixels(last, p) {
+                if self.rng.gen::<f32>() < weight * attenuation * mask(pixel) {
+                    self.hit::<PARANOID>(pixel, color);
+                    hits += 1;
+                }
+            }
+        }
+
+        self.stroke += 1;
+        if self.stroke >= length {
+            self.break_stroke();
+        } else {
+            self.last = Some(p);
+        }
+        hits
+    }
+
+    /// Add a hit of `color` to the bucket at `pixel`. Paranoid runs record
+    /// hits which would overflow the bucket, and leave it full instead.
+    #[inline(always)]
+    fn hit<const PARANOID: bool>(&mut self, pixel: Point2<f32>, color: Color) {
+        let monochrome = self.monochrome;
+        if self.attribution.is_some() || self.bands.is_some() {
+            self.hit_layers(pixel, color);
+        }
+        let bucket = self.buffer.at_mut(pixel);
+        if !PARANOID {
+            bucket.alpha += 1;
+            if !monochrome {
+                bucket.red += color.red as u32;
+                bucket.green += color.green as u32;
+                bucket.blue += color.blue as u32;
+            }
+            return;
+        }
+
+        let mut overflowed = false;
+        let mut add = |channel: &mut u32, x: u8| {
+            overflowed |= channel.checked_add(x as u32).is_none();
+            *channel = channel.saturating_add(x as u32);
+        };
+        add(&mut bucket.alpha, 1);
+        if !monochrome {
+            add(&mut bucket.red, color.red);
+            add(&mut bucket.green, color.green);
+            add(&mut bucket.blue, color.blue);
+        }
+        if overflowed {
+            self.incident(IncidentKind::BucketOverflow { pixel: [pixel[0] as usize, pixel[1] as usize] });
+        }
+    }
+
+    /// Add a hit of `color` at `pixel` to the attribution maps and color
+    /// bands, which are only kept by some runs.
+    #[inline(never)]
+    fn hit_layers(&mut self, pixel: Point2<f32>, color: Color) {
+        if let Some(attribution) = &mut self.attribution {
+            attribution.hit(pixel, self.function);
+        }
+        if let Some(bands) = &mut self.bands {
+            // A band's bucket never holds more than the full histogram's,
+            // so overflows are found there.
+            let n = bands.len();
+            let bucket = bands[color_band(self.color, n)].at_mut(pixel);
+            bucket.alpha = bucket.alpha.saturating_add(1);
+            if !self.monochrome {
+                bucket.red = bucket.red.saturating_add(color.red as u32);
+                bucket.green = bucket.green.saturating_add(color.green as u32);
+                bucket.blue = bucket.blue.saturating_add(color.blue as u32);
+            }
+        }
+    }
+
+    /// Record a broken invariant in the current iteration.
+    fn incident(&mut self, kind: IncidentKind) {
+        let (function, point) = self.current;
+        let incident = Incident { kind, iteration: self.iters, thread: self.thread, point: point.into(), function };
+        if let Some(incidents) = &mut self.incidents {
+            incidents.record(incident);
+        }
+    }
+
+    fn break_stroke(&mut self) {
+        self.last = None;
+        self.stroke = 0;
+    }
+}
+
+/// A random starting point and color for an orbit.
+fn first_point(rng: &mut StdRng, precision: Precision) -> (Point2<f64>, u8) {
+    let point = match precision {
+        Precision::F64 => Point2::new(rng.gen(), rng.gen()),
+        _ => Point2::new(rng.gen::<f32>(), rng.gen::<f32>()).cast(),
+    };
+    (point, rng.gen())
+}
+
+/// The chunks of a run split so that its histogram does not depend on the
+/// number of threads, which are handed out in order.
+#[derive(Clone)]
+struct StableChunks {
+    seed: u64,
+    size: u64,
+    total: u64,
+    /// Index of the first chunk which has not been run.
+    next: u64,
+}
+
+impl StableChunks {
+    fn count(&self) -> u64 {
+        self.total.div_ceil(self.size)
+    }
+
+    /// First iteration and number of iterations of chunk `i`.
+    fn span(&self, i: u64) -> (u64, u64) {
+        let start = i * self.size;
+        (start, self.size.min(self.total - start))
+    }
+
+    /// The random stream of chunk `i`, which depends only on the seed and `i`.
+    fn rng(&self, i: u64) -> StdRng {
+        let mut seed = [0; 32];
+        seed[.. 8].copy_from_slice(&self.seed.to_le_bytes());
+        seed[8 .. 16].copy_from_slice(&i.to_le_bytes());
+        StdRng::from_seed(seed)
+    }
+
+    fn remaining(&self) -> u64 {
+        self.total - self.total.min(self.next.saturating_mul(self.size))
+    }
+
+    /// Take the next chunks, as many as cover `iters` iterations and at
+    /// least one, as long as any are left.
+    fn claim(&mut self, iters: u64) -> Range<u64> {
+        let first = self.next;
+        let mut claimed = 0;
+        while self.next < self.count() && (claimed < iters || self.next == first) {
+            claimed += self.span(self.next).1;
+            self.next += 1;
+        }
+        first .. self.next
+    }
+}
+
+/// The work an `advance` gives each orbit, returning the number of points
+/// it plotted.
+type Work = Arc<dyn Fn(&mut Orbit) -> u64 + Send + Sync>;
+
+/// An orbit handed back by a worker, with what its work returned.
+type Finished = (usize, Orbit, thread::Result<u64>);
+
+/// Threads which run a session's orbits, one each, parked between calls to
+/// `advance`. Each is pinned and given its priority once, when it starts.
+struct WorkerPool {
+    jobs: Vec<Sender<(Orbit, Work)>>,
+    finished: Receiver<Finished>,
+    handles: Vec<JoinHandle<()>>,
+}
+
+impl WorkerPool {
+    fn new(orbits: &[Orbit], nice: bool) -> Self {
+        let (done, finished) = mpsc::channel();
+        let (jobs, handles) = orbits.iter().enumerate()
+            .map(|(i, orbit)| {
+                let (job, received) = mpsc::channel::<(Orbit, Work)>();
+                let (core, done) = (orbit.core, done.clone());
+                let handle = thread::spawn(move || {
+                    if let Some(core) = core {
+                        core_affinity::set_for_current(core);
+                    }
+                    if nice {
+                        lower_thread_priority();
+                    }
+                    for (mut orbit, work) in received {
+                        // A panic is passed on to the session's thread with the
+                        // orbit, rather than leaving it waiting.
+                        let plotted = panic::catch_unwind(AssertUnwindSafe(|| work(&mut orbit)));
+                        if done.send((i, orbit, plotted)).is_err() {
+                            break;
+                        }
+                    }
+                });
+                (job, handle)
+            })
+            .unzip();
+        WorkerPool { jobs, finished, handles }
+    }
+}
+
+impl Drop for WorkerPool {
+    fn drop(&mut self) {
+        // Workers stop once there are no more jobs to wait for.
+        self.jobs.clear();
+        for handle in self.handles.drain(..) {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Run `work` on every orbit, on the session's workers unless there is only
+/// one orbit, returning the total number of points plotted. The calling
+/// thread watches the workers with `monitor`, if there is one.
+fn run_orbits(
+    orbits: &mut Vec<Orbit>,
+    pool: &mut Option<WorkerPool>,
+    nice: bool,
+    monitor: Option<&mut Monitor>,
+    work: Work,
+) -> u64 {
+    // Nice runs always iterate on threads of their own, so that the
+    // caller's priority is left alone, as do watched runs, so that the
+    // caller is free to watch.
+    if orbits.len() == 1 && !nice && monitor.is_none() {
+        return work(&mut orbits[0]);
+    }
+    let pool = pool.get_or_insert_with(|| WorkerPool::new(orbits, nice));
+    let mut returned: Vec<Option<(Orbit, thread::Result<u64>)>> = orbits.iter().map(|_| None).collect();
+    for (job, orbit) in pool.jobs.iter().zip(orbits.drain(..)) {
+        job.send((orbit, Arc::clone(&work))).expect("workers run as long as their session");
+    }
+
+    let mut outstanding = returned.len();
+    let mut monitor = monitor.map(|m| {
+        let next_check = Instant::now() + m.cfg.interval;
+        (m, next_check)
+    });
+    while outstanding > 0 {
+        let next = match &monitor {
+            Some((_, next_check)) => pool.finished.recv_timeout(next_check.saturating_duration_since(Instant::now())),
+            None => pool.finished.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match next {
+            Ok((i, orbit, plotted)) => {
+                returned[i] = Some((orbit, plotted));
+                outstanding -= 1;
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => unreachable!("workers run as long as their session"),
+        }
+        if let Some((monitor, next_check)) = &mut monitor {
+            if Instant::now() >= *next_check {
+                monitor.check(|i| returned[i].is_none());
+                *next_check += monitor.cfg.interval;
+            }
+        }
+    }
+
+    let mut plotted = 0;
+    let mut panicked = None;
+    for (orbit, result) in returned.into_iter().flatten() {
+        orbits.push(orbit);
+        match result {
+            Ok(n) => plotted += n,
+            Err(payload) => panicked = panicked.or(Some(payload)),
+        }
+    }
+    if let Some(payload) = panicked {
+        panic::resume_unwind(payload);
+    }
+    plotted
+}
+
+/// The transform from a flame's coordinates to pixels, in both of the
+/// precisions orbits can run in.
+struct ScreenTransform {
+    single: Affine2<f32>,
+    double: Affine2<f64>,
+    /// Applied after either transform, if the image is projected.
+    projection: Option<ScreenProjection>,
+}
+
+/// The pixels a line segment passes through, found by stepping along its
+/// major axis one pixel at a time, each with an equal share of its length.
+fn segment_pixels(a: Point2<f32>, b: Point2<f32>) -> impl Iterator<Item = (Point2<f32>, f32)> {
+    let d = b - a;
+    let steps = d.x.abs().max(d.y.abs()).ceil().max(1.) as usize;
+    let weight = d.norm() / steps as f32;
+    // Sampling the middle of each step keeps every pixel on the segment and
+    // plots the shared end of two segments only once.
+    (0 .. steps).map(move |i| (a + d * ((i as f32 + 0.5) / steps as f32), weight))
+}
+
+/// What every orbit of a session reads as it runs, shared with the
+/// session's workers.
+struct Scene {
+    flame: Flame,
+    screen: ScreenTransform,
+    /// Weight of each pixel under the flame's mask, if it has one.
+    mask: Option<Vec<f32>>,
+}
+
+/// The outcome of a call to `RenderSession::advance`.
+#[derive(Debug, Clone, Copy)]
+pub struct AdvanceResult {
+    /// Number of points plotted during the call.
+    pub plotted: u64,
+    /// Whether the session has run all of its iterations.
+    pub done: bool,
+}
+
+/// Progress statistics for a session.
+#[derive(Debug, Clone, Copy)]
+pub struct SessionStats {
+    pub iters: u64,
+    pub elapsed: Duration,
+    /// Fraction of iterations which plotted a point.
+    pub hit_rate: f64,
+    /// Relative change in the image at the most recent convergence check.
+    pub rel_change: Option<f64>,
+    /// Estimated error at the most recent quality check.
+    pub error: Option<ErrorEstimate>,
+}
+
+/// Fraction of iterations plotting a point below which a render is
+/// reported as empty.
+pub const EMPTY_FRAME_THRESHOLD: f64 = 0.001;
+
+/// How a finished render went.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RenderOutcome {
+    Rendered { hit_fraction: f64 },
+    /// So few iterations plotted a point that the image is all but blank,
+    /// most likely because the bounds miss the flame's attractor.
+    EmptyFrame { hit_fraction: f64 },
+}
+
+impl RenderOutcome {
+    pub fn hit_fraction(self) -> f64 {
+        match self {
+            RenderOutcome::Rendered { hit_fraction } | RenderOutcome::EmptyFrame { hit_fraction } => hit_fraction,
+        }
+    }
+}
+
+impl SessionStats {
+    /// Judge the render empty if fewer than `empty_below` of its iterations
+    /// plotted a point.
+    pub fn outcome(&self, empty_below: f64) -> RenderOutcome {
+        if self.hit_rate < empty_below {
+            RenderOutcome::EmptyFrame { hit_fraction: self.hit_rate }
+        } else {
+            RenderOutcome::Rendered { hit_fraction: self.hit_rate }
+        }
+    }
+}
+
+/// A render which may have been run a second time with fitted bounds.
+pub struct RetriedRender {
+    /// The session whose histogram should be used.
+    pub session: RenderSession,
+    /// Outcome of the first run.
+    pub first: RenderOutcome,
+    /// Outcome of the session kept, which differs from `first` only if it
+    /// was run again.
+    pub outcome: RenderOutcome,
+    /// Bounds the flame was run again with, if the first run was empty.
+    pub fitted: Option<Bounds>,
+}
+
+/// How the Monte Carlo error of a histogram was estimated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorMethod {
+    /// From the spread of each bucket's hits between chunks, when the
+    /// session tracks variance.
+    Variance,
+    /// From the spread of each bucket's hits between threads.
+    Jackknife,
+    /// Assuming each bucket's hits are Poisson distributed, when there is
+    /// only one thread and variance is not tracked.
+    Poisson,
+}
+
+impl std::fmt::Display for ErrorMethod {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ErrorMethod::Variance => write!(f, "variance"),
+            ErrorMethod::Jackknife => write!(f, "jackknife"),
+            ErrorMethod::Poisson => write!(f, "poisson"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ErrorEstimate {
+    /// Mean relative standard error over the buckets which have been hit.
+    pub mean_rel_err: f64,
+    pub method: ErrorMethod,
+}
+
+impl std::fmt::Display for SessionStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "iters {}", self.iters)?;
+        writeln!(f, "time {:.1}s", self.elapsed.as_secs_f64())?;
+        write!(f, "hits {:.1}%", self.hit_rate * 100.0)?;
+        if let Some(change) = self.rel_change {
+            write!(f, "\nchange {:.2e}", change)?;
+        }
+        if let Some(error) = self.error {
+            write!(f, "\nerror {:.2}%", error.mean_rel_err * 100.0)?;
+        }
+        Ok(())
+    }
+}
+
+/// A render which can be run a chunk at a time.
+///
+/// Each thread's orbit, random number generator and warm-up count are kept
+/// between calls to `advance`, so a seeded session produces the same buffer
+/// however its iterations are split up. The session's threads are started
+/// by the first call to `advance` and parked between calls, until the
+/// session is dropped; when `threads` is one the work happens on the
+/// caller's thread instead.
+///
+/// With `stable_chunk_iters` set, threads instead take fixed chunks of the
+/// run in turn, and `advance` runs whole chunks.
+pub struct RenderSession {
+    scene: Arc<Scene>,
+    cfg: RunConfig,
+    orbits: Vec<Orbit>,
+    /// Threads running the orbits, once any have been started.
+    pool: Option<WorkerPool>,
+    /// Chunks left to run, if the run is split into them.
+    stable: Option<StableChunks>,
+    variance: Option<HitVariance>,
+    started: Instant,
+    plotted: u64,
+    rel_change: Option<f64>,
+    error: Option<ErrorEstimate>,
+    /// Cancels the run, shared with every orbit.
+    token: CancelToken,
+    /// Progress each orbit has published, if the run is watched.
+    heartbeats: Vec<Arc<Heartbeat>>,
+    /// Why the watchdog cancelled the run, if it did.
+    stall: Option<StallReport>,
+}
+
+impl RenderSession {
+    /// A session which has run none of its iterations, or an error if the
+    /// image's size is not allowed by `RunConfig::check_size`.
+    pub fn new(flame: Flame, cfg: RunConfig) -> Result<Self, BufferError> {
+        cfg.check_size()?;
+        let cfg = RunConfig {
+            precision: cfg.precision.resolve(&flame.bounds, cfg.width, cfg.height),
+            fuse: cfg.fuse.resolve(&flame),
+            ..cfg
+        };
+        let threads = cfg.threads.max(1);
+        let functions = flame.functions.len();
+        let screen = ScreenTransform {
+            single: flame.screen_transform(cfg),
+            double: flame.screen_transform(cfg),
+            projection: ScreenProjection::new(cfg.projection, &flame.bounds, cfg.width, cfg.height),
+        };
+
+        let starts = (0 .. threads).map(|i| {
+            let rng = match cfg.seed {
+                Some(seed) => StdRng::seed_from_u64(seed.wrapping_add(i as u64)),
+                None => StdRng::from_entropy(),
+            };
+            // Orbits of a run split into chunks are given iterations a
+            // chunk at a time.
+            let quota = match cfg.stable_chunk_iters {
+                Some(_) => 0,
+                None => (cfg.iters / threads + usize::from(i < cfg.iters % threads)) as u64,
+            };
+            (rng, quota)
+        });
+        let stable = cfg.stable_chunk_iters.map(|size| StableChunks {
+            seed: cfg.seed.unwrap_or_else(rand::random),
+            size: size.max(1),
+            total: cfg.iters as u64,
+            next: 0,
+        });
+
+        let cores = if cfg.pin_threads && threads > 1 {
+            core_affinity::get_core_ids().filter(|cores| !cores.is_empty())
+        } else {
+            None
+        };
+        let mut orbits: Vec<Orbit> = match cores {
+            // Each histogram is allocated and zeroed by a thread already on
+            // its core, so that its pages are placed in that core's memory.
+            Some(cores) => thread::scope(|s| {
+                let handles: Vec<_> = starts.enumerate()
+                    .map(|(i, (rng, quota))| {
+                        let core = cores[i % cores.len()];
+                        s.spawn(move || {
+                            core_affinity::set_for_current(core);
+                            Orbit::new(i, rng, quota, Some(core), functions, cfg)
+                        })
+                    })
+                    .collect();
+                handles.into_iter().map(|h| h.join().unwrap()).collect()
+            }),
+            None => starts.enumerate().map(|(i, (rng, quota))| Orbit::new(i, rng, quota, None, functions, cfg)).collect(),
+        };
+
+        let token = CancelToken::new();
+        let heartbeats: Vec<_> = match cfg.watchdog {
+            Some(_) => orbits.iter().map(|_| Arc::new(Heartbeat::default())).collect(),
+            None => Vec::new(),
+        };
+        for (i, orbit) in orbits.iter_mut().enumerate() {
+            orbit.token = token.clone();
+            orbit.heartbeat = heartbeats.get(i).cloned();
+        }
+
+        let mask = flame.mask.as_ref()
+            .map(|m| m.to_mask(&screen.single, cfg.width, cfg.height).rasterize(cfg.width, cfg.height));
+        let variance = cfg.track_variance.then(|| HitVariance::new(cfg.width, cfg.height));
+
+        Ok(RenderSession {
+            scene: Arc::new(Scene { flame, screen, mask }),
+            cfg, orbits, stable, variance,
+            pool: None,
+            started: Instant::now(),
+            plotted: 0,
+            rel_change: None,
+            error: None,
+            token,
+            heartbeats,
+            stall: None,
+        })
+    }
+
+    /// Run up to `max_iters` more iterations, split evenly between threads.
+    /// Runs split into chunks run whole chunks, as many as cover `max_iters`.
+    pub fn advance(&mut self, max_iters: u64) -> AdvanceResult {
+        if self.is_done() {
+            return AdvanceResult { plotted: 0, done: true };
+        }
+
+        let mut monitor = self.cfg.watchdog.map(|cfg| Monitor::new(cfg, &self.scene.flame, &self.heartbeats, &self.token));
+        let scene = Arc::clone(&self.scene);
+        let work: Work = match &mut self.stable {
+            Some(chunks) => {
+                let claimed = chunks.claim(max_iters);
+                let next = AtomicU64::new(claimed.start);
+                let (chunks, token) = (chunks.clone(), self.token.clone());
+                Arc::new(move |orbit| {
+                    let mut plotted = 0;
+                    loop {
+                        let i = next.fetch_add(1, Ordering::Relaxed);
+                        if i >= claimed.end || token.is_cancelled() {
+                            return plotted;
+                        }
+                        let (start, len) = chunks.span(i);
+                        orbit.begin_chunk(chunks.rng(i), start, len);
+                        plotted += orbit.advance(&scene.flame, &scene.screen, scene.mask.as_deref(), len);
+                    }
+                })
+            }
+            None => {
+                let active = self.orbits.iter().filter(|o| o.remaining() > 0).count() as u64;
+                let share = max_iters.div_ceil(active);
+                Arc::new(move |orbit| orbit.advance(&scene.flame, &scene.screen, scene.mask.as_deref(), share))
+            }
+        };
+        let plotted = run_orbits(&mut self.orbits, &mut self.pool, self.cfg.nice, monitor.as_mut(), work);
+
+        if let Some(report) = monitor.and_then(|m| m.report) {
+            self.stall = Some(report);
+        }
+        self.plotted += plotted;
+
+        if let Some(variance) = &mut self.variance {
+            let mut totals = vec![0; self.cfg.width * self.cfg.height];
+            for orbit in &self.orbits {
+                for (t, b) in totals.iter_mut().zip(orbit.buffer.row_major_buckets()) {
+                    *t += b.alpha;
+                }
+            }
+            variance.update(&totals);
+        }
+
+        AdvanceResult { plotted, done: self.is_done() }
+    }
+
+    /// Run the remaining iterations, or until the configured stop condition
+    /// is met, returning the total number of iterations run.
+    pub fn run(&mut self) -> u64 {
+        if let Some(quality) = self.cfg.quality {
+            while !self.advance(quality.check_interval_iters).done {
+                let error = self.estimate_error();
+                self.error = error;
+                if error.is_some_and(|e| e.mean_rel_err <= quality.mean_rel_err) {
+                    break;
+                }
+            }
+            self.error = self.estimate_error();
+            return self.iters();
+        }
+
+        let Some(stop) = self.cfg.stop_when else {
+            let chunk = if self.variance.is_some() {
+                self.remaining().div_ceil(VARIANCE_CHUNKS)
+            } else {
+                self.remaining()
+            };
+            while !self.advance(chunk).done {}
+            return self.iters();
+        };
+
+        let mut previous = proxy(&self.buffer());
+        while !self.advance(stop.check_interval_iters).done {
+            let current = proxy(&self.buffer());
+            let change = relative_change(&previous, &current);
+            self.rel_change = Some(change);
+            if self.iters() >= stop.min_iters && change < stop.rel_change_below {
+                break;
+            }
+            previous = current;
+        }
+
+        self.iters()
+    }
+
+    /// Estimate the Monte Carlo error of the histogram so far, or `None` if
+    /// nothing has been plotted or too few chunks have been run to tell.
+    pub fn estimate_error(&self) -> Option<ErrorEstimate> {
+        let (errors, method) = if let Some(variance) = self.variance.as_ref().filter(|v| v.chunks() >= 2) {
+            (variance.relative_std_error(), ErrorMethod::Variance)
+        } else if self.orbits.len() >= 2 {
+            (self.jackknife_error(), ErrorMethod::Jackknife)
+        } else {
+            let errors = self.orbits[0].buffer.row_major_buckets()
+                .map(|b| if b.alpha > 0 { 1.0 / (b.alpha as f64).sqrt() } else { f64::INFINITY })
+                .collect();
+            (errors, ErrorMethod::Poisson)
+        };
+
+        let hit: Vec<f64> = errors.into_iter().filter(|e| e.is_finite()).collect();
+        if hit.is_empty() {
+            return None;
+        }
+        let mean_rel_err = hit.iter().sum::<f64>() / hit.len() as f64;
+        Some(ErrorEstimate { mean_rel_err, method })
+    }
+
+    /// Relative standard error of each bucket's total, treating the threads'
+    /// histograms as independent samples.
+    fn jackknife_error(&self) -> Vec<f64> {
+        let n = self.orbits.len() as f64;
+        let mut sum = vec![0.0; self.cfg.width * self.cfg.height];
+        let mut sum_sq = vec![0.0; self.cfg.width * self.cfg.height];
+        for orbit in &self.orbits {
+            for ((s, q), b) in sum.iter_mut().zip(&mut sum_sq).zip(orbit.buffer.row_major_buckets()) {
+                let x = b.alpha as f64;
+                *s += x;
+                *q += x * x;
+            }
+        }
+        sum.iter().zip(&sum_sq).map(|(&total, &sq)| {
+            if total <= 0.0 {
+                return f64::INFINITY;
+            }
+            let mean = total / n;
+            let var = ((sq - n * mean * mean) / (n - 1.0)).max(0.0);
+            (n * var).sqrt() / total
+        }).collect()
+    }
+
+    /// Number of iterations run so far, across all threads.
+    pub fn iters(&self) -> u64 {
+        self.orbits.iter().map(|o| o.iters).sum()
+    }
+
+    /// Time each thread has spent iterating, which shows how evenly the
+    /// work was shared out.
+    pub fn thread_times(&self) -> Vec<Duration> {
+        self.orbits.iter().map(|o| o.busy).collect()
+    }
+
+    fn remaining(&self) -> u64 {
+        let unclaimed = self.stable.as_ref().map_or(0, StableChunks::remaining);
+        self.orbits.iter().map(|o| o.remaining()).sum::<u64>() + unclaimed
+    }
+
+    /// Whether the session has run all of its iterations, or was cancelled.
+    pub fn is_done(&self) -> bool {
+        self.remaining() == 0 || self.token.is_cancelled()
+    }
+
+    /// A token cancelling the session, from any thread. Its threads stop
+    /// soon after, leaving the histogram of the iterations run so far.
+    pub fn cancel_token(&self) -> CancelToken {
+        self.token.clone()
+    }
+
+    /// Why the session's watchdog cancelled it, if it did.
+    pub fn stall_report(&self) -> Option<&StallReport> {
+        self.stall.as_ref()
+    }
+
+    /// Run a session to the end. If fewer than `empty_below` of its
+    /// iterations plot a point and `retry` is set, run it once more from
+    /// scratch with bounds fitted to the attractor, at the aspect ratio of
+    /// the image.
+    pub fn run_retrying(flame: Flame, cfg: RunConfig, empty_below: f64, retry: bool) -> Result<RetriedRender, BufferError> {
+        let mut session = RenderSession::new(flame.clone(), cfg)?;
+        session.run();
+        let first = session.stats().outcome(empty_below);
+
+        let fitted = match first {
+            RenderOutcome::EmptyFrame { .. } if retry && session.stall.is_none() => flame.fit_bounds(cfg.width as f32 / cfg.height as f32),
+            _ => None,
+        };
+        let Some(bounds) = fitted else {
+            return Ok(RetriedRender { session, first, outcome: first, fitted: None });
+        };
+
+        let mut session = RenderSession::new(Flame { bounds, ..flame }, cfg)?;
+        session.run();
+        let outcome = session.stats().outcome(empty_below);
+        Ok(RetriedRender { session, first, outcome, fitted })
+    }
+
+    /// Fraction of the total iterations which have been run, between 0 and 1.
+    pub fn progress(&self) -> f32 {
+        let total = self.iters() + self.remaining();
+        if total == 0 {
+            return 1.0;
+        }
+        self.iters() as f32 / total as f32
+    }
+
+    pub fn config(&self) -> RunConfig {
+        self.cfg
+    }
+
+    /// The flame being rendered.
+    pub fn flame(&self) -> &Flame {
+        &self.scene.flame
+    }
+
+    pub fn stats(&self) -> SessionStats {
+        let iters = self.iters();
+        SessionStats {
+            iters,
+            elapsed: self.started.elapsed(),
+            hit_rate: if iters > 0 { self.plotted as f64 / iters as f64 } else { 0.0 },
+            rel_change: self.rel_change,
+            error: self.error,
+        }
+    }
+
+    /// Hits of each function in each bucket over every thread so far, if
+    /// the session is counting them.
+    pub fn attribution(&self) -> Option<Attribution> {
+        let mut orbits = self.orbits.iter().filter_map(|o| o.attribution.as_ref());
+        let mut total = orbits.next()?.clone();
+        for attribution in orbits {
+            total.merge(attribution);
+        }
+        Some(total)
+    }
+
+    /// Counts for each function over every thread so far, if the session
+    /// is keeping them.
+    pub fn function_stats(&self) -> Option<Vec<FunctionStats>> {
+        let mut orbits = self.orbits.iter().filter_map(|o| o.function_stats.as_ref());
+        let mut total = orbits.next()?.clone();
+        for stats in orbits {
+            for (t, s) in total.iter_mut().zip(stats) {
+                t.merge(s);
+            }
+        }
+        Some(total)
+    }
+
+    /// Every thread's broken invariants so far, if the session is paranoid.
+    pub fn incidents(&self) -> Option<IncidentLog> {
+        self.cfg.paranoid.then(|| IncidentLog::combine(self.orbits.iter().filter_map(|o| o.incidents.as_ref())))
+    }
+
+    /// Noise estimate for each bucket, if the session is tracking variance.
+    pub fn variance(&self) -> Option<&HitVariance> {
+        self.variance.as_ref()
+    }
+
+    /// Accumulated histogram of every thread so far.
+    pub fn buffer(&self) -> Buffer<u32> {
+        Buffer::combine(self.orbits.iter().map(|o| o.buffer.clone())).to_row_major()
+    }
+
+    /// Accumulated histogram of each band of palette positions over every
+    /// thread so far, if the session is keeping them.
+    pub fn bands(&self) -> Option<Vec<Buffer<u32>>> {
+        let n = self.cfg.split_bands?.max(1);
+        Some((0 .. n).map(|band| {
+            let buffers = self.orbits.iter().filter_map(|o| o.bands.as_ref().map(|b| b[band].clone()));
+            Buffer::combine(buffers).to_row_major()
+        }).collect())
+    }
+
+    #[deprecated(note = "use `snapshot_8bit`")]
+    #[allow(deprecated)]
+    pub fn snapshot_image(&self, cfg: RenderConfig) -> Buffer<u8> {
+        self.snapshot_8bit(&cfg.tone_mapping(), cfg.dither)
+    }
+
+    #[deprecated(note = "use `preview_8bit`")]
+    #[allow(deprecated)]
+    pub fn preview_image(&self, cfg: RenderConfig, overlay_stats: bool) -> Buffer<u8> {
+        self.preview_8bit(&cfg.tone_mapping(), cfg.dither, overlay_stats)
+    }
+
+    /// Tonemap the current histogram without ending the session.
+    pub fn snapshot_8bit(&self, tone: &ToneMapping, dither: DitherMode) -> Buffer<u8> {
+        self.buffer().render_8bit(tone, dither)
+    }
+
+    /// Tonemap the current histogram for display while the session is
+    /// running, optionally with its statistics drawn in the top left corner.
+    pub fn preview_8bit(&self, tone: &ToneMapping, dither: DitherMode, overlay_stats: bool) -> Buffer<u8> {
+        let mut image = self.snapshot_8bit(tone, dither);
+        if overlay_stats {
+            let text = self.stats().to_string();
+            image.draw_label(2, 2, &text, Color::rgb(255, 255, 255), Color::rgb(0, 0, 0));
+        }
+        image
+    }
+
+    pub fn into_buffer(self) -> Buffer<u32> {
+        Buffer::combine(self.orbits.into_iter().map(|o| o.buffer)).to_row_major()
+    }
+
+    /// The histogram so far as an `Accumulator` of the session's flame,
+    /// which later pieces can only be added to if they are of the same
+    /// flame and size.
+    pub fn accumulator(&self) -> Result<Accumulator, AccumulateError> {
+        let flame = self.flame();
+        flame.resume(self.buffer(), flame.content_hash()?, self.iters())
+    }
+
+    pub fn into_accumulator(self) -> Result<Accumulator, AccumulateError> {
+        let flame = self.flame().clone();
+        let iters = self.iters();
+        flame.resume(self.into_buffer(), flame.content_hash()?, iters)
+    }
+}
+
+/// Log density of a downsampled copy of the histogram, normalized to a
+/// maximum of one.
+fn proxy(buffer: &Buffer<u32>) -> Vec<f64> {
+    let factor = buffer.width().max(buffer.height()).div_ceil(PROXY_SIZE);
+    let cells: Vec<f64> = buffer.downsample_sum(factor).buckets().iter().map(|b| b.alpha as f64).collect();
+
+    let max = cells.iter().map(|c| c.ln_1p()).fold(0.0, f64::max);
+    cells.iter().map(|c| if max > 0.0 { c.ln_1p() / max } else { 0.0 }).collect()
+}
+
+fn relative_change(previous: &[f64], current: &[f64]) -> f64 {
+    let diff: f64 = previous.iter().zip(current).map(|(p, c)| (p - c).abs()).sum();
+    let total: f64 = current.iter().sum();
+    if total > 0.0 { diff / total } else { f64::INFINITY }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bench::baseline_config;
+    use crate::presets;
+
+    fn config(threads: usize, iters: usize) -> RunConfig {
+        RunConfig { width: 32, height: 32, iters, seed: Some(7), ..baseline_config(threads) }
+    }
+
+    fn buckets(buffer: &Buffer<u32>) -> Vec<[u32; 4]> {
+        buffer.buckets().iter().map(|b| [b.alpha, b.red, b.green, b.blue]).collect()
+    }
+
+    fn chunked(cfg: RunConfig, chunk: u64) -> Buffer<u32> {
+        let mut session = RenderSession::new(presets::gasket(), cfg).unwrap();
+        while !session.advance(chunk).done {}
+        session.into_buffer()
+    }
+
+    #[test]
+    fn chunked_sessions_match_an_uninterrupted_run() {
+        for threads in [1, 3] {
+            let cfg = config(threads, 60_000);
+            let whole = buckets(&presets::gasket().run(cfg).unwrap());
+            assert!(whole.iter().any(|b| b[0] > 0));
+            for chunk in [1_000, 7_919, 25_000] {
+                assert_eq!(buckets(&chunked(cfg, chunk)), whole, "{} threads, chunks of {}", threads, chunk);
+            }
+        }
+    }
+
+    #[test]
+    fn chunked_stable_sessions_match_an_uninterrupted_run() {
+        let cfg = RunConfig { stable_chunk_iters: Some(5_000), ..config(2, 40_000) };
+        let whole = buckets(&presets::gasket().run(cfg).unwrap());
+        assert_eq!(buckets(&chunked(cfg, 1)), whole);
+        assert_eq!(buckets(&chunked(RunConfig { threads: 5, ..cfg }, 12_000)), whole);
+    }
+
+    #[test]
+    fn progress_rises_to_one() {
+        let mut session = RenderSession::new(presets::gasket(), config(2, 10_000)).unwrap();
+        let mut last = session.progress();
+        assert_eq!(last, 0.0);
+        loop {
+            let result = session.advance(1_500);
+            let progress = session.progress();
+            assert!(progress >= last, "progress fell from {} to {}", last, progress);
+            last = progress;
+            if result.done {
+                break;
+            }
+        }
+        assert_eq!(last, 1.0);
+        assert_eq!(session.iters(), 10_000);
+        assert_eq!(session.advance(1_000).plotted, 0);
+    }
+
+    #[test]
+    fn workers_start_once_and_stay_parked_between_advances() {
+        let mut session = RenderSession::new(presets::gasket(), config(3, 30_000)).unwrap();
+        assert!(session.pool.is_none());
+
+        session.advance(3_000);
+        let threads: Vec<_> = session.pool.as_ref().unwrap().handles.iter().map(|h| h.thread().id()).collect();
+        assert_eq!(threads.len(), 3);
+        for _ in 0 .. 4 {
+            session.advance(3_000);
+            let pool = session.pool.as_ref().unwrap();
+            assert_eq!(pool.handles.iter().map(|h| h.thread().id()).collect::<Vec<_>>(), threads);
+            assert!(pool.handles.iter().all(|h| !h.is_finished()));
+        }
+        assert_eq!(session.orbits.len(), 3);
+
+        // Dropping the pool ends its threads, which it waits for.
+        let pool = session.pool.take().unwrap();
+        drop(pool);
+        session.advance(3_000);
+        assert!(session.pool.is_some());
+    }
+
+    #[test]
+    fn single_threaded_sessions_run_on_the_calling_thread() {
+        let mut session = RenderSession::new(presets::gasket(), config(1, 10_000)).unwrap();
+        while !session.advance(2_000).done {}
+        assert!(session.pool.is_none());
+    }
+
+    #[test]
+    fn a_panicking_worker_passes_its_panic_on() {
+        let mut orbits: Vec<Orbit> = (0 .. 2)
+            .map(|i| Orbit::new(i, StdRng::seed_from_u64(i as u64), 10, None, 1, config(2, 20)))
+            .collect();
+        let mut pool = None;
+        let work: Work = Arc::new(|orbit| if orbit.thread == 1 { panic!("orbit 1 failed") } else { 0 });
+        let result = panic::catch_unwind(AssertUnwindSafe(|| run_orbits(&mut orbits, &mut pool, false, None, work)));
+        assert_eq!(*result.unwrap_err().downcast::<&str>().unwrap(), "orbit 1 failed");
+        // The orbits are returned, and the workers can be used again.
+        assert_eq!(orbits.len(), 2);
+        assert_eq!(run_orbits(&mut orbits, &mut pool, false, None, Arc::new(|_| 5)), 10);
+    }
+
+    fn stopping(rel_change_below: f64, min_iters: u64, iters: usize) -> RunConfig {
+        let stop = StopCondition { rel_change_below, check_interval_iters: 20_000, min_iters };
+        RunConfig { width: 16, height: 16, stop_when: Some(stop), ..config(1, iters) }
+    }
+
+    #[test]
+    fn converged_runs_stop_early() {
+        let mut session = RenderSession::new(presets::gasket(), stopping(1e-2, 40_000, 50_000_000)).unwrap();
+        let iters = session.run();
+        assert!((40_000 .. 5_000_000).contains(&iters), "{} iterations", iters);
+        assert_eq!(iters % 20_000, 0);
+        assert_eq!(session.iters(), iters);
+        assert!(session.stats().rel_change.is_some_and(|c| c < 1e-2));
+        // The histogram holds the iterations run.
+        assert!(session.buffer().buckets().iter().map(|b| b.alpha as u64).sum::<u64>() <= iters);
+    }
+
+    #[test]
+    fn runs_which_never_converge_enough_run_their_budget() {
+        let mut session = RenderSession::new(presets::gasket(), stopping(0.0, 0, 200_000)).unwrap();
+        assert_eq!(session.run(), 200_000);
+        assert!(session.stats().rel_change.is_some_and(|c| c > 0.0));
+    }
+
+    #[test]
+    fn runs_do_not_stop_before_their_minimum() {
+        let mut session = RenderSession::new(presets::gasket(), stopping(f64::INFINITY, 100_000, 1_000_000)).unwrap();
+        assert_eq!(session.run(), 100_000);
+    }
+
+    fn quality(mean_rel_err: f64, iters: usize) -> RunConfig {
+        RunConfig {
+            quality: Some(QualityTarget { mean_rel_err, check_interval_iters: 10_000 }),
+            ..config(1, iters)
+        }
+    }
+
+    #[test]
+    fn estimated_error_falls_with_iterations() {
+        for (threads, method) in [(1, ErrorMethod::Poisson), (3, ErrorMethod::Jackknife)] {
+            let mut session = RenderSession::new(presets::gasket(), config(threads, 400_000)).unwrap();
+            assert!(session.estimate_error().is_none());
+            let mut errors = Vec::new();
+            for _ in 0 .. 4 {
+                session.advance(100_000);
+                let estimate = session.estimate_error().unwrap();
+                assert_eq!(estimate.method, method);
+                errors.push(estimate.mean_rel_err);
+            }
+            assert!(errors.windows(2).all(|w| w[1] < w[0]), "{:?}", errors);
+            // Four times the iterations about halve the error.
+            let ratio = errors[0] / errors[3];
+            assert!((1.5 .. 2.7).contains(&ratio), "{} threads: {:?}", threads, errors);
+        }
+
+        let cfg = RunConfig { track_variance: true, ..config(1, 100_000) };
+        let mut session = RenderSession::new(presets::gasket(), cfg).unwrap();
+        session.run();
+        assert_eq!(session.estimate_error().unwrap().method, ErrorMethod::Variance);
+    }
+
+    #[test]
+    fn quality_targets_stop_the_run_once_reached() {
+        let mut session = RenderSession::new(presets::gasket(), quality(0.1, 10_000_000)).unwrap();
+        session.run();
+        let error = session.stats().error.unwrap();
+        assert!(error.mean_rel_err <= 0.1, "{}", error.mean_rel_err);
+        // Stopped at the first check meeting the target, not long after.
+        assert!(session.iters() < 10_000_000 && session.iters().is_multiple_of(10_000));
+        let mut before = RenderSession::new(presets::gasket(), config(1, session.iters() as usize - 10_000)).unwrap();
+        before.run();
+        assert!(before.estimate_error().unwrap().mean_rel_err > 0.1);
+    }
+
+    #[test]
+    fn unreachable_quality_targets_run_the_whole_budget() {
+        let mut session = RenderSession::new(presets::gasket(), quality(1e-6, 50_000)).unwrap();
+        session.run();
+        assert_eq!(session.iters(), 50_000);
+        assert!(session.stats().error.unwrap().mean_rel_err > 1e-6);
+    }
+
+    /// The gasket with a Log function which sends every point to the
+    /// origin, where the logarithm is infinite.
+    fn gasket_with_log_at_origin(weight: f32) -> Flame {
+        let mut flame = presets::gasket();
+        let collapse = nalgebra::Transform::from_matrix_unchecked(nalgebra::Matrix3::new(
+            0.0, 0.0, 0.0,
+            0.0, 0.0, 0.0,
+            0.0, 0.0, 1.0,
+        ));
+        flame.functions.push(Function { weight, var: Variation::Log, trans: collapse, color: 0, axis_blend: Function::FULL_BLEND });
+        flame
+    }
+
+    #[test]
+    fn orbits_restart_after_leaving_the_finite_plane() {
+        let cfg = RunConfig { paranoid: true, ..config(1, 50_000) };
+        let mut session = RenderSession::new(gasket_with_log_at_origin(0.1), cfg).unwrap();
+        session.run();
+        assert_eq!(session.iters(), 50_000);
+
+        let incidents = session.incidents().expect("paranoid runs keep incidents");
+        assert!(incidents.total() > 0);
+        assert!(incidents.iter().all(|i| i.kind == IncidentKind::NonFinite && i.function == 3));
+        // The orbit carried on from a fresh point rather than stopping.
+        assert!(hits(&session.into_buffer()) > 0);
+    }
+
+    #[test]
+    fn spherical_at_the_origin_is_recorded_as_an_incident() {
+        let mut flame = presets::gasket();
+        let collapse = nalgebra::Transform::from_matrix_unchecked(nalgebra::Matrix3::new(
+            0.0, 0.0, 0.0,
+            0.0, 0.0, 0.0,
+            0.0, 0.0, 1.0,
+        ));
+        flame.functions.push(Function { weight: 0.5, var: Variation::Spherical, trans: collapse, color: 0, axis_blend: Function::FULL_BLEND });
+        let cfg = RunConfig { paranoid: true, ..config(1, 20_000) };
+        let mut session = RenderSession::new(flame, cfg).unwrap();
+        session.run();
+
+        let incidents = session.incidents().unwrap();
+        // About half of the iterations apply the spherical function, far
+        // more than the log keeps.
+        assert!(incidents.total() > 5_000, "{} incidents", incidents.total());
+        assert_eq!(incidents.iter().count(), INCIDENT_CAPACITY);
+        assert_eq!(incidents.dropped(), incidents.total() - INCIDENT_CAPACITY as u64);
+        for incident in incidents.iter() {
+            assert_eq!((incident.kind, incident.function, incident.thread), (IncidentKind::NonFinite, 3, 0));
+            assert!(incident.point.iter().all(|c| c.is_finite()));
+        }
+        // The most recent are kept, in order.
+        let iterations: Vec<u64> = incidents.iter().map(|i| i.iteration).collect();
+        assert!(iterations.windows(2).all(|w| w[0] < w[1]));
+        assert!(*iterations.last().unwrap() <= session.iters() && iterations[0] > 10_000);
+    }
+
+    #[test]
+    fn overflowing_buckets_are_recorded_and_saturate() {
+        // Every point lands on the pixel of (0.5, 0.5).
+        let mut flame = presets::gasket();
+        for f in &mut flame.functions {
+            f.trans = nalgebra::Transform::from_matrix_unchecked(nalgebra::Matrix3::new(
+                0.0, 0.0, 0.5,
+                0.0, 0.0, 0.5,
+                0.0, 0.0, 1.0,
+            ));
+        }
+        let cfg = RunConfig { paranoid: true, ..config(1, 1_000) };
+        let mut session = RenderSession::new(flame, cfg).unwrap();
+        for c in session.orbits[0].buffer.as_flat_slice_mut() {
+            *c = u32::MAX - 50;
+        }
+        session.run();
+
+        let incidents = session.incidents().unwrap();
+        assert!(incidents.total() > 0);
+        let pixel = incidents.iter().next().map(|i| i.kind);
+        let Some(IncidentKind::BucketOverflow { pixel: [x, y] }) = pixel else {
+            panic!("expected an overflow, got {:?}", pixel);
+        };
+        for incident in incidents.iter() {
+            assert_eq!(incident.kind, IncidentKind::BucketOverflow { pixel: [x, y] });
+            assert!(incident.function < 3 && incident.thread == 0);
+        }
+        // The bucket was left full rather than wrapping around, and no
+        // other was plotted.
+        let buffer = session.into_buffer();
+        for (i, bucket) in buffer.buckets().iter().enumerate() {
+            if i == x + y * buffer.width() {
+                assert!(bucket.alpha > u32::MAX - 50);
+                assert_eq!([bucket.red, bucket.green, bucket.blue], [u32::MAX; 3]);
+            } else {
+                assert_eq!(bucket.alpha, u32::MAX - 50);
+            }
+        }
+    }
+
+    #[test]
+    fn orbits_which_always_leave_the_finite_plane_still_finish() {
+        let mut flame = gasket_with_log_at_origin(1.0);
+        flame.functions.drain(.. 3);
+        let mut session = RenderSession::new(flame, config(2, 20_000)).unwrap();
+        session.run();
+        assert_eq!(session.iters(), 20_000);
+        assert_eq!(hits(&session.into_buffer()), 0);
+    }
+
+    /// The gasket with bounds nowhere near its attractor.
+    fn misbounded_gasket() -> Flame {
+        Flame { bounds: Bounds::new(10.0, 11.0, 10.0, 11.0), ..presets::gasket() }
+    }
+
+    #[test]
+    fn empty_frames_are_reported_without_retrying_unless_asked() {
+        let retried = RenderSession::run_retrying(misbounded_gasket(), config(2, 20_000), EMPTY_FRAME_THRESHOLD, false).unwrap();
+        assert_eq!(retried.first, RenderOutcome::EmptyFrame { hit_fraction: 0.0 });
+        assert_eq!(retried.outcome, retried.first);
+        assert!(retried.fitted.is_none());
+        assert_eq!(hits(&retried.session.into_buffer()), 0);
+    }
+
+    #[test]
+    fn empty_frames_are_rendered_again_with_fitted_bounds() {
+        let retried = RenderSession::run_retrying(misbounded_gasket(), config(2, 20_000), EMPTY_FRAME_THRESHOLD, true).unwrap();
+        assert_eq!(retried.first, RenderOutcome::EmptyFrame { hit_fraction: 0.0 });
+        let RenderOutcome::Rendered { hit_fraction } = retried.outcome else { panic!("{:?}", retried.outcome) };
+        assert!(hit_fraction > 0.9, "{}", hit_fraction);
+        // The fitted bounds are close to those drawn up for the gasket by hand.
+        let fitted = retried.fitted.unwrap().to_array();
+        let by_hand = presets::gasket().bounds.to_array();
+        assert!(fitted.iter().zip(by_hand).all(|(a, b)| (a - b).abs() < 0.1), "{:?}", fitted);
+        assert_eq!(retried.session.flame().bounds, retried.fitted.unwrap());
+        assert!(hits(&retried.session.into_buffer()) > 0);
+    }
+
+    #[test]
+    fn framed_renders_are_not_retried() {
+        let retried = RenderSession::run_retrying(presets::gasket(), config(2, 20_000), EMPTY_FRAME_THRESHOLD, true).unwrap();
+        assert!(matches!(retried.first, RenderOutcome::Rendered { hit_fraction } if hit_fraction > 0.9));
+        assert!(retried.fitted.is_none());
+        assert_eq!(retried.session.flame().bounds, presets::gasket().bounds);
+    }
+
+    /// The gasket with every function taking `SLEEP` to apply, so that a
+    /// thread runs fewer than `HEARTBEAT_ITERS` iterations in a quarter of
+    /// a second.
+    fn sleeping_gasket() -> Flame {
+        let mut flame = presets::gasket();
+        for f in &mut flame.functions {
+            f.var = Variation::Sleep;
+        }
+        flame
+    }
+
+    fn hits(buffer: &Buffer<u32>) -> u64 {
+        buffer.buckets().iter().map(|b| b.alpha as u64).sum()
+    }
+
+    #[test]
+    fn stalled_threads_are_reported_without_cancelling() {
+        static WARNINGS: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+        let watchdog = Watchdog {
+            interval: Duration::from_millis(20),
+            stall_timeout: None,
+            on_stall: Some(|w| {
+                assert_eq!((w.thread, w.variation.as_str()), (0, "Sleep"));
+                WARNINGS.fetch_add(1, Ordering::Relaxed);
+            }),
+        };
+        let cfg = RunConfig { watchdog: Some(watchdog), ..config(1, 400) };
+        let mut session = RenderSession::new(sleeping_gasket(), cfg).unwrap();
+        session.run();
+        assert!(WARNINGS.load(Ordering::Relaxed) > 0);
+        assert!(session.stall_report().is_none());
+        assert_eq!(session.iters(), 400);
+    }
+
+    #[test]
+    fn stalled_threads_are_cancelled_after_the_timeout() {
+        static WARNINGS: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+        let timeout = Duration::from_millis(100);
+        let on_stall: fn(&StallWarning) = |_| {
+            WARNINGS.fetch_add(1, Ordering::Relaxed);
+        };
+        let iters = 1_000_000;
+        let cfg = RunConfig { watchdog: Some(Watchdog::new(Some(timeout), Some(on_stall))), ..config(1, iters) };
+        let started = Instant::now();
+        let mut session = RenderSession::new(sleeping_gasket(), cfg).unwrap();
+        session.run();
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert!(WARNINGS.load(Ordering::Relaxed) > 0);
+
+        let report = session.stall_report().expect("the run was cancelled").clone();
+        assert_eq!(report.timeout, timeout);
+        let warning = &report.stalled[0];
+        assert_eq!((warning.thread, warning.iters, warning.variation.as_str()), (0, 0, "Sleep"));
+        assert!(warning.stalled_for >= timeout);
+        assert!(session.is_done());
+
+        // The histogram holds the iterations run before the cancellation.
+        let run = session.iters();
+        // The thread stopped at its first heartbeat after the cancellation.
+        assert_eq!(run, HEARTBEAT_ITERS);
+        let histogram = session.into_buffer();
+        assert!(hits(&histogram) > 0 && hits(&histogram) <= run);
+    }
+
+    #[test]
+    fn pinned_threads_render_the_same_image() {
+        for threads in [1, 2, 5] {
+            let cfg = config(threads, 40_000);
+            let unpinned = buckets(&presets::gasket().run(cfg).unwrap());
+            let pinned = buckets(&presets::gasket().run(RunConfig { pin_threads: true, ..cfg }).unwrap());
+            assert_eq!(pinned, unpinned, "{} threads", threads);
+        }
+    }
+
+    #[test]
+    fn throttled_and_nice_threads_render_the_same_image() {
+        for threads in [1, 3] {
+            let cfg = config(threads, 40_000);
+            let free = buckets(&presets::gasket().run(cfg).unwrap());
+            let throttled = buckets(&presets::gasket().run(RunConfig { cpu_limit: Some(0.5), nice: true, ..cfg }).unwrap());
+            assert_eq!(throttled, free, "{} threads", threads);
+        }
+    }
+
+    #[test]
+    fn thread_times_are_reported_for_every_thread() {
+        let mut session = RenderSession::new(presets::gasket(), RunConfig { pin_threads: true, ..config(3, 30_000) }).unwrap();
+        assert_eq!(session.thread_times(), vec![Duration::ZERO; 3]);
+        while !session.advance(10_000).done {}
+        let times = session.thread_times();
+        assert_eq!(times.len(), 3);
+        assert!(times.iter().all(|t| *t > Duration::ZERO), "{:?}", times);
+    }
+
+    /// FNV-1a hash of every bucket, to compare a histogram with one
+    /// recorded earlier.
+    fn fingerprint(buffer: &Buffer<u32>) -> u64 {
+        buckets(buffer).iter().flatten().fold(0xcbf2_9ce4_8422_2325, |h, &v| (h ^ v as u64).wrapping_mul(0x100_0000_01b3))
+    }
+
+    #[test]
+    fn points_are_plotted_as_recorded() {
+        // Recorded from seeded runs; other plot modes must not change these.
+        for (threads, recorded) in [(1, 0xef6e_6a1a_cf0f_dde8), (3, 0x9f56_3133_b536_69d8)] {
+            let cfg = RunConfig { plot_mode: PlotMode::Points, ..config(threads, 50_000) };
+            assert_eq!(fingerprint(&presets::gasket().run(cfg).unwrap()), recorded, "{} threads", threads);
+        }
+    }
+
+    #[test]
+    fn attribution_leaves_the_histogram_as_recorded() {
+        // The fingerprints of `points_are_plotted_as_recorded`.
+        for (threads, recorded) in [(1, 0xef6e_6a1a_cf0f_dde8), (3, 0x9f56_3133_b536_69d8)] {
+            for attribution in [false, true] {
+                let mut session = RenderSession::new(presets::gasket(), RunConfig { attribution, ..config(threads, 50_000) }).unwrap();
+                session.run();
+                assert_eq!(session.attribution().is_some(), attribution);
+                assert_eq!(fingerprint(&session.buffer()), recorded, "{} threads, attribution {}", threads, attribution);
+            }
+        }
+    }
+
+    /// A palette whose quarters are red, green, blue and black, so that the
+    /// channel sums of a histogram count the hits in each quarter.
+    fn quarters() -> Palette {
+        let quarter = [Color::rgb(255, 0, 0), Color::rgb(0, 255, 0), Color::rgb(0, 0, 255), Color::rgb(0, 0, 0)];
+        Palette::new(std::array::from_fn(|i| quarter[i / 64]))
+    }
+
+    /// The fraction of a histogram's hits in each quarter of `quarters`.
+    fn quarter_shares(buffer: &Buffer<u32>) -> [f64; 4] {
+        let total = |channel: fn(&Bucket<u32>) -> u32| buffer.buckets().iter().map(|b| channel(b) as f64).sum::<f64>();
+        let hits = total(|b| b.alpha);
+        let [red, green, blue] = [total(|b| b.red), total(|b| b.green), total(|b| b.blue)].map(|sum| sum / 255. / hits);
+        [red, green, blue, 1.0 - red - green - blue]
+    }
+
+    #[test]
+    fn color_models_plot_the_same_points() {
+        let cfg = RunConfig { plot_mode: PlotMode::Points, ..config(1, 50_000) };
+        let native = presets::gasket().run(cfg).unwrap();
+        let flam3 = Flame { color_model: ColorModel::Flam3, ..presets::gasket() }.run(cfg).unwrap();
+        // The native model is the one recorded before flam3's was added.
+        assert_eq!(fingerprint(&native), 0xef6e_6a1a_cf0f_dde8);
+        let alpha = |buffer: &Buffer<u32>| buffer.buckets().iter().map(|b| b.alpha).collect::<Vec<_>>();
+        assert_eq!(alpha(&flam3), alpha(&native));
+        assert_ne!(buckets(&flam3), buckets(&native));
+    }
+
+    #[test]
+    fn opaque_palettes_plot_as_recorded() {
+        // The gasket's palette written out with an explicit opaque alpha.
+        let gasket = presets::gasket();
+        let keys = gasket.palette.keys_with_alpha().iter()
+            .map(|key| format!("{}ff", key.color).parse().unwrap())
+            .collect();
+        let palette = Palette::from_keys_with_alpha(keys).unwrap();
+        assert!(palette == gasket.palette && palette.is_opaque());
+        // The fingerprints of `points_are_plotted_as_recorded`, from before
+        // palettes had an alpha.
+        let flame = Flame { palette, ..gasket };
+        for (threads, recorded) in [(1, 0xef6e_6a1a_cf0f_dde8), (3, 0x9f56_3133_b536_69d8)] {
+            let cfg = RunConfig { plot_mode: PlotMode::Points, ..config(threads, 50_000) };
+            assert_eq!(fingerprint(&flame.run(cfg).unwrap()), recorded, "{} threads", threads);
+        }
+    }
+
+    /// `quarters` with the blue quarter given `alpha`.
+    fn translucent_blue(alpha: u8) -> Flame {
+        let quarters = quarters();
+        let palette = Palette::new_with_alpha(std::array::from_fn(|i| {
+            let color = quarters.sample(i as u8).color;
+            ColorA { color, alpha: if i / 64 == 2 { alpha } else { 255 } }
+        }));
+        Flame { palette, ..presets::gasket() }
+    }
+
+    #[test]
+    fn transparent_colors_plot_nothing() {
+        let cfg = RunConfig { plot_mode: PlotMode::Points, ..config(1, 50_000) };
+        let opaque = translucent_blue(255).run(cfg).unwrap();
+        assert!(quarter_shares(&opaque)[2] > 0.1);
+        // Points of other colors are drawn just as they were, having drawn
+        // no random numbers for the transparent ones.
+        let transparent = translucent_blue(0).run(cfg).unwrap();
+        for (t, o) in buckets(&transparent).into_iter().zip(buckets(&opaque)) {
+            assert_eq!(t, [o[0] - o[3] / 255, o[1], o[2], 0]);
+        }
+
+        // Half transparent points are plotted half as often as the others.
+        let ratio = |buffer: &Buffer<u32>| {
+            let [red, green, blue, black] = quarter_shares(buffer);
+            blue / (red + green + black)
+        };
+        let half = ratio(&translucent_blue(128).run(cfg).unwrap()) / ratio(&opaque);
+        assert!((half - 128.0 / 255.0).abs() < 0.05, "half transparent points were plotted {} times as often", half);
+    }
+
+    #[test]
+    fn a_lone_color_is_reached_only_by_flam3() {
+        // Halving the distance to 255 and rounding down stops at 254.
+        let mut palette = [Color::rgb(0, 0, 255); 256];
+        palette[254] = Color::rgb(0, 255, 0);
+        palette[255] = Color::rgb(255, 0, 0);
+        let mut flame = Flame { palette: Palette::new(palette), ..presets::gasket() };
+        flame.functions.truncate(1);
+        flame.functions[0].color = 255;
+        let cfg = config(2, 20_000);
+
+        let native = flame.run(cfg).unwrap();
+        assert!(native.buckets().iter().all(|b| b.red == 0 && b.green == 255 * b.alpha), "native");
+        let flam3 = Flame { color_model: ColorModel::Flam3, ..flame }.run(cfg).unwrap();
+        assert!(flam3.buckets().iter().all(|b| b.green == 0 && b.red == 255 * b.alpha), "flam3");
+        assert!(hits(&native) > 0 && hits(&native) == hits(&flam3));
+    }
+
+    #[test]
+    fn two_colors_spread_evenly_along_the_palette() {
+        // Blending halfway with 0 or 1 at random gives a uniform position,
+        // whose binary digits are the functions chosen.
+        let mut flame = Flame { palette: quarters(), ..presets::gasket() };
+        flame.functions.truncate(2);
+        flame.functions.iter_mut().for_each(|f| f.weight = 0.5);
+        (flame.functions[0].color, flame.functions[1].color) = (0, 255);
+        for model in [ColorModel::Native, ColorModel::Flam3] {
+            let shares = quarter_shares(&Flame { color_model: model, ..flame.clone() }.run(config(2, 200_000)).unwrap());
+            assert!(shares.iter().all(|s| (s - 0.25).abs() < 0.02), "{}: {:?}", model, shares);
+        }
+    }
+
+    #[test]
+    fn bands_add_up_to_the_histogram() {
+        for threads in [1, 3] {
+            let cfg = config(threads, 40_000);
+            let plain = presets::gasket().run(cfg).unwrap();
+            let mut session = RenderSession::new(presets::gasket(), RunConfig { split_bands: Some(4), ..cfg }).unwrap();
+            session.run();
+            // Keeping bands leaves the histogram as it was.
+            assert_eq!(buckets(&session.buffer()), buckets(&plain));
+            let bands = session.bands().unwrap();
+            assert_eq!(bands.len(), 4);
+            assert!(bands.iter().all(|band| hits(band) > 0));
+            assert_eq!(buckets(&Buffer::combine(bands)), buckets(&plain), "{} threads", threads);
+        }
+        assert!(RenderSession::new(presets::gasket(), config(1, 1_000)).unwrap().bands().is_none());
+    }
+
+    #[test]
+    fn bands_are_equal_quarters_of_the_palette() {
+        let bands: Vec<usize> = [0, 63, 64, 127, 128, 191, 192, 255].iter().map(|&c| color_band(c, 4)).collect();
+        assert_eq!(bands, [0, 0, 1, 1, 2, 2, 3, 3]);
+        assert!((0 ..= 255).all(|c| color_band(c, 1) == 0 && color_band(c, 256) == c as usize));
+
+        // An orbit which settles on one color plots into that color's band alone.
+        for (color, band) in [(0, 0), (100, 1), (255, 3)] {
+            let mut flame = presets::gasket();
+            flame.functions.truncate(1);
+            flame.functions[0].color = color;
+            let mut session = RenderSession::new(flame, RunConfig { split_bands: Some(4), ..config(2, 20_000) }).unwrap();
+            session.run();
+            let hits: Vec<u64> = session.bands().unwrap().iter().map(hits).collect();
+            assert!(hits[band] > 0 && hits.iter().sum::<u64>() == hits[band], "color {}: {:?}", color, hits);
+        }
+    }
+
+    #[test]
+    fn segment_pixels_share_out_the_length() {
+        let segments = [
+            ((0.5, 0.5), (10.5, 0.5)),
+            ((3.0, 9.0), (3.0, 2.0)),
+            ((0.0, 0.0), (7.0, 7.0)),
+            ((1.2, 30.7), (25.9, 4.1)),
+            ((5.0, 5.0), (5.3, 5.1)),
+            ((2.0, 2.0), (2.0, 2.0)),
+        ];
+        for ((ax, ay), (bx, by)) in segments {
+            let (a, b) = (Point2::new(ax, ay), Point2::new(bx, by));
+            let pixels: Vec<_> = segment_pixels(a, b).collect();
+            let total: f32 = pixels.iter().map(|&(_, w)| w).sum();
+            assert!((total - (b - a).norm()).abs() < 1e-4, "{:?} to {:?} has weight {}", a, b, total);
+
+            // Each pixel lies on the segment, and consecutive pixels touch.
+            for &(p, _) in &pixels {
+                let along = (p - a).dot(&(b - a)) / (b - a).norm_squared().max(f32::EPSILON);
+                assert!((-1e-4 ..= 1.0001).contains(&along), "{:?} off {:?} to {:?}", p, a, b);
+            }
+            for w in pixels.windows(2) {
+                let step = w[1].0 - w[0].0;
+                assert!(step.x.abs() <= 1.0001 && step.y.abs() <= 1.0001, "{:?} to {:?}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn strokes_attenuate_their_segments() {
+        let strokes = |attenuation| RunConfig { plot_mode: PlotMode::Strokes { length: 8, attenuation }, ..config(2, 50_000) };
+        assert_eq!(hits(&presets::gasket().run(strokes(0.0)).unwrap()), 0);
+        let (faint, bright) = (hits(&presets::gasket().run(strokes(0.05)).unwrap()), hits(&presets::gasket().run(strokes(0.5)).unwrap()));
+        assert!(faint > 0 && bright > 5 * faint, "{} and {}", faint, bright);
+    }
+
+    #[test]
+    fn temporal_color_with_no_blend_changes_nothing() {
+        for threads in [1, 3] {
+            let plain = config(threads, 50_000);
+            let temporal = RunConfig { temporal_color: Some(TemporalColor { blend: 0.0 }), ..plain };
+            assert_eq!(buckets(&presets::gasket().run(temporal).unwrap()), buckets(&presets::gasket().run(plain).unwrap()));
+        }
+    }
+
+    #[test]
+    fn temporal_color_follows_progress() {
+        let mut flame = presets::gasket();
+        flame.palette = Palette::from_keys(vec![Color::rgb(255, 0, 0), Color::rgb(0, 0, 255)]).unwrap();
+        let cfg = RunConfig { temporal_color: Some(TemporalColor { blend: 1.0 }), ..config(2, 200_000) };
+        let mut session = RenderSession::new(flame, cfg).unwrap();
+        session.advance(100_000);
+        let first = session.buffer();
+        while !session.advance(50_000).done {}
+        let whole = session.buffer();
+
+        // Share of the color in each half which is red rather than blue.
+        let redness = |b: &[Bucket<u32>]| {
+            let (red, blue) = b.iter().fold((0u64, 0u64), |(r, bl), b| (r + b.red as u64, bl + b.blue as u64));
+            red as f64 / (red + blue) as f64
+        };
+        let second: Vec<_> = whole.buckets().iter().zip(first.buckets())
+            .map(|(w, f)| Bucket { alpha: w.alpha - f.alpha, red: w.red - f.red, green: w.green - f.green, blue: w.blue - f.blue })
+            .collect();
+        let (early, late) = (redness(first.buckets()), redness(&second));
+        // Progress runs from 0 to 1/2 and then on to 1, so on average the
+        // halves are three quarters and one quarter red.
+        assert!((early - 0.75).abs() < 0.03, "first half is {} red", early);
+        assert!((late - 0.25).abs() < 0.03, "second half is {} red", late);
+    }
+
+    #[test]
+    fn white_masks_render_as_no_mask() {
+        for threads in [1, 3] {
+            let cfg = config(threads, 50_000);
+            let plain = buckets(&presets::gasket().run(cfg).unwrap());
+            let mut masked = presets::gasket();
+            masked.mask = Some(MaskShape::Image(image::GrayImage::from_pixel(5, 5, image::Luma([255]))));
+            assert_eq!(buckets(&masked.run(cfg).unwrap()), plain, "{} threads", threads);
+            masked.mask = Some(MaskShape::Rect { min: Point2::new(-10.0, -10.0), max: Point2::new(10.0, 10.0) });
+            assert_eq!(buckets(&masked.run(cfg).unwrap()), plain, "{} threads", threads);
+        }
+    }
+
+    #[test]
+    fn masks_keep_points_in_proportion_to_their_weight() {
+        let cfg = config(2, 100_000);
+        let mut flame = presets::gasket();
+        let plain = hits(&flame.run(cfg).unwrap());
+        flame.mask = Some(MaskShape::Image(image::GrayImage::from_pixel(5, 5, image::Luma([0]))));
+        assert_eq!(hits(&flame.run(cfg).unwrap()), 0);
+        flame.mask = Some(MaskShape::Image(image::GrayImage::from_pixel(5, 5, image::Luma([64]))));
+        let quarter = hits(&flame.run(cfg).unwrap()) as f64 / plain as f64;
+        assert!((quarter - 64.0 / 255.0).abs() < 0.01, "{} of the points kept", quarter);
+
+        // Only the left half of the image is plotted.
+        let [x_min, x_max, y_min, y_max] = flame.bounds.to_array();
+        let middle = (x_min + x_max) / 2.0;
+        flame.mask = Some(MaskShape::Rect { min: Point2::new(x_min, y_min), max: Point2::new(middle, y_max) });
+        let histogram = flame.run(cfg).unwrap();
+        let right: u64 = histogram.rows().flat_map(|row| &row[16 ..]).map(|b| b.alpha as u64).sum();
+        assert_eq!(right, 0);
+        assert!(hits(&histogram) > 0);
+    }
+
+    #[test]
+    fn monochrome_runs_count_the_same_hits_without_colors() {
+        let strokes = PlotMode::Strokes { length: 8, attenuation: 0.5 };
+        for (threads, plot_mode) in [(1, PlotMode::Points), (3, PlotMode::Points), (2, strokes)] {
+            let color = RunConfig { plot_mode, ..config(threads, 50_000) };
+            let mono = presets::gasket().run(RunConfig { monochrome: true, ..color }).unwrap();
+            let color = presets::gasket().run(color).unwrap();
+            for (m, c) in mono.buckets().iter().zip(color.buckets()) {
+                assert_eq!([m.alpha, m.red, m.green, m.blue], [c.alpha, 0, 0, 0]);
+            }
+        }
+    }
+
+    #[test]
+    fn portable_math_renders_are_as_recorded() {
+        // Recorded under deterministic math, which must give these on every
+        // platform. The swirl exercises the transcendental functions.
+        let cases = [
+            (presets::swirl(), 1, 0x7939_499c_61c0_f25e),
+            (presets::swirl(), 3, 0x0429_adc7_ea5e_bcca),
+            (presets::starter(), 2, 0x9d36_7056_6ac9_99a5),
+        ];
+        for (flame, threads, recorded) in cases {
+            let cfg = RunConfig { deterministic_math: true, ..config(threads, 50_000) };
+            assert_eq!(fingerprint(&flame.run(cfg).unwrap()), recorded, "{} threads", threads);
+        }
+    }
+
+    /// A flame with two attracting points, `(±0.95, 0)`, neither of which
+    /// an orbit ever leaves for the other: after its first step an orbit's
+    /// x only follows `sin(2 x)`, which keeps its sign, and which side it
+    /// takes depends on where it starts.
+    fn two_components() -> Flame {
+        let function = |weight: f32| Function {
+            weight,
+            var: Variation::Sinusoidal,
+            trans: Transform::from_matrix_unchecked(Matrix3::new(2.0, -2.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1.0)),
+            ..presets::gasket().functions[0]
+        };
+        Flame {
+            functions: vec![function(0.5), function(0.5)],
+            bounds: Bounds::new(-1.2, 1.2, -0.5, 0.5),
+            ..presets::gasket()
+        }
+    }
+
+    /// Hits left and right of the middle of the image.
+    fn sides(buffer: &Buffer<u32>) -> (u64, u64) {
+        let mut sides = (0, 0);
+        for (i, b) in buffer.row_major_buckets().enumerate() {
+            if i % buffer.width() < buffer.width() / 2 {
+                sides.0 += b.alpha as u64;
+            } else {
+                sides.1 += b.alpha as u64;
+            }
+        }
+        sides
+    }
+
+    #[test]
+    fn restarts_reach_every_component() {
+        let cfg = RunConfig { width: 32, height: 8, iters: 20_000, ..baseline_config(1) };
+        for seed in 0 .. 8 {
+            let single = two_components().run(RunConfig { seed: Some(seed), ..cfg }).unwrap();
+            let (left, right) = sides(&single);
+            assert!(left == 0 || right == 0, "seed {}: one orbit reached both sides ({}, {})", seed, left, right);
+
+            let restarted = two_components().run(RunConfig { seed: Some(seed), restarts_per_thread: 16, ..cfg }).unwrap();
+            let (left, right) = sides(&restarted);
+            assert!(left > 1_000 && right > 1_000, "seed {}: 16 starts reached ({}, {})", seed, left, right);
+        }
+    }
+
+    #[test]
+    fn a_single_start_is_the_default() {
+        let cfg = config(2, 30_000);
+        let default = buckets(&presets::swirl().run(cfg).unwrap());
+        assert_eq!(buckets(&presets::swirl().run(RunConfig { restarts_per_thread: 1, ..cfg }).unwrap()), default);
+        assert_eq!(buckets(&presets::swirl().run(RunConfig { restarts_per_thread: 0, ..cfg }).unwrap()), default);
+        assert_ne!(buckets(&presets::swirl().run(RunConfig { restarts_per_thread: 4, ..cfg }).unwrap()), default);
+    }
+}
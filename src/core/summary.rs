@@ -0,0 +1,300 @@
+//! A short record of a flame's properties, for comparing and searching
+//! many flames without rendering them.
+//!
+//! Symmetry is estimated from a fixed-seed run of the chaos game. The
+//! center is taken to be the centroid of the cells of a grid the points
+//! reach, which is the center of any symmetry the attractor's shape has
+//! whatever its density. Around it, the logarithm of the number of points
+//! in each sector of a set of rings is compared with the same after a
+//! rotation or reflection, less the mean of each ring so that only how
+//! the density varies around the rings counts, and the flame is taken to
+//! have the symmetry if the two correlate by at least `SYMMETRY_CORRELATION`.
+
+use std::collections::BTreeSet;
+
+use nalgebra::Point2;
+use serde::{Deserialize, Serialize};
+
+use super::*;
+
+/// Number of points sampled to estimate a flame's symmetry.
+const SYMMETRY_SAMPLES: usize = 1 << 17;
+/// Cells across the grid the center is found on, the fraction of points
+/// on each side left out of its extent, and the fewest points a cell must
+/// hold to count.
+const CENTER_GRID: usize = 64;
+const CENTER_OUTLIERS: f32 = 0.01;
+const CENTER_MIN_POINTS: u32 = 2;
+/// Rings and sectors points are counted in, out to the distance from the
+/// center within which all but `SYMMETRY_OUTLIERS` of them fall.
+const SYMMETRY_RINGS: usize = 12;
+const SYMMETRY_SECTORS: usize = 72;
+const SYMMETRY_OUTLIERS: f32 = 0.02;
+/// Least correlation between the counts and those of a rotated or
+/// reflected copy for the flame to count as symmetric under it.
+pub const SYMMETRY_CORRELATION: f32 = 0.7;
+/// Highest order of rotational symmetry looked for.
+pub const MAX_ROTATION: u32 = 12;
+/// Number of evenly spaced axes of reflection tried.
+const MIRROR_AXES: usize = 36;
+/// Least mean chroma, saturation times value, a palette needs to have a
+/// dominant hue rather than being gray.
+const MIN_CHROMA: f32 = 0.05;
+/// Width in degrees of the bins of the hue histogram.
+const HUE_BIN: f32 = 10.0;
+
+/// A flame's structure, palette and shape, in a few numbers.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FlameSummary {
+    pub functions: usize,
+    /// Names of the variations used, as in a descriptor.
+    pub variations: BTreeSet<String>,
+    /// Shannon entropy in bits of the choice of function in each step, as
+    /// made by weight: 0 when one function is always chosen, and
+    /// `log2(functions)` when all are equally likely.
+    pub weight_entropy: f32,
+    pub palette: PaletteSummary,
+    pub symmetry: Symmetry,
+    /// Ratio of the bounds' width to their height.
+    pub aspect: f32,
+}
+
+/// The colors of a palette's 256 entries. Means weight each by its
+/// opacity.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct PaletteSummary {
+    /// HSV hue in degrees, 0 red, 120 green and 240 blue, around which the
+    /// most saturated color is found. `None` for gray palettes.
+    pub dominant_hue: Option<f32>,
+    /// Mean, least and greatest Oklab lightness, from 0 black to 1 white.
+    pub lightness: f32,
+    pub lightness_min: f32,
+    pub lightness_max: f32,
+}
+
+/// Symmetries of the attractor about its center.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Symmetry {
+    /// Greatest order of rotational symmetry, at most `MAX_ROTATION`: 1
+    /// for none, 2 when unchanged by a half turn, and so on. Attractors
+    /// with no structure around their center, such as discs, have none.
+    pub rotation: u32,
+    /// Whether the attractor is its own mirror image about some axis.
+    pub mirror: bool,
+}
+
+impl Flame {
+    /// Summarize the flame. The symmetry takes a short run of the chaos
+    /// game, and is always the same for the same flame.
+    pub fn summary(&self) -> FlameSummary {
+        let variations = self.functions.iter().map(|f| format!("{:?}", f.var.discriminant())).collect();
+        let chances = FunctionSelector::new(&self.functions).map_or_else(Vec::new, |s| s.probabilities());
+        let weight_entropy = chances.iter().filter(|&&p| p > 0.0).map(|p| -p * p.log2()).sum::<f64>().max(0.0) as f32;
+
+        FlameSummary {
+            functions: self.functions.len(),
+            variations,
+            weight_entropy,
+            palette: self.palette.summary(),
+            symmetry: symmetry(&self.attractor_samples(SYMMETRY_SAMPLES)),
+            aspect: self.bounds.aspect(),
+        }
+    }
+}
+
+impl Palette {
+    /// The dominant hue and the lightness of the palette's entries.
+    pub fn summary(&self) -> PaletteSummary {
+        let entries: Vec<_> = (0 ..= 255).map(|i| self.sample(i)).collect();
+        let weights: Vec<f32> = entries.iter().map(|c| c.alpha as f32 / 255.0).collect();
+        let total = weights.iter().sum::<f32>().max(f32::EPSILON);
+
+        let lightness: Vec<f32> = entries.iter().map(|c| c.color.to_oklab().l).collect();
+        let mean = lightness.iter().zip(&weights).map(|(l, w)| l * w).sum::<f32>() / total;
+        let min = lightness.iter().copied().fold(f32::INFINITY, f32::min);
+        let max = lightness.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+
+        // Each entry votes for its hue with its chroma, and the dominant hue
+        // is the mean of the votes around the bin with the most, with its
+        // neighbors.
+        let hsv: Vec<[f64; 3]> = entries.iter()
+            .map(|c| rgb_to_hsv([c.color.red, c.color.green, c.color.blue].map(|x| x as f64 / 255.0)))
+            .collect();
+        let chroma: Vec<f32> = hsv.iter().zip(&weights).map(|([_, s, v], w)| (s * v) as f32 * w).collect();
+        let bins = (360.0 / HUE_BIN) as usize;
+        let mut histogram = vec![0.0; bins];
+        for ([h, ..], c) in hsv.iter().zip(&chroma) {
+            histogram[((*h as f32 * bins as f32) as usize).min(bins - 1)] += c;
+        }
+        let around = |i: usize| histogram[(i + bins - 1) % bins] + histogram[i] + histogram[(i + 1) % bins];
+        let peak = (0 .. bins).max_by(|&a, &b| around(a).total_cmp(&around(b))).unwrap_or(0);
+        let peak_hue = (peak as f32 + 0.5) * HUE_BIN;
+        let [x, y] = hsv.iter().zip(&chroma)
+            .map(|([h, ..], c)| (*h as f32 * 360.0, c))
+            .filter(|(h, _)| hue_distance(*h, peak_hue) <= 1.5 * HUE_BIN)
+            .fold([0.0f32; 2], |[x, y], (h, c)| [x + c * h.to_radians().cos(), y + c * h.to_radians().sin()]);
+        let dominant_hue = (chroma.iter().sum::<f32>() / total >= MIN_CHROMA)
+            .then(|| y.atan2(x).to_degrees().rem_euclid(360.0));
+
+        PaletteSummary { dominant_hue, lightness: mean, lightness_min: min, lightness_max: max }
+    }
+}
+
+/// Distance in degrees between two hues, the short way around.
+fn hue_distance(a: f32, b: f32) -> f32 {
+    let d = (a - b).rem_euclid(360.0);
+    d.min(360.0 - d)
+}
+
+fn symmetry(samples: &[Point2<f32>]) -> Symmetry {
+    let none = Symmetry { rotation: 1, mirror: false };
+    let Some(center) = symmetry_center(samples) else { return none };
+    let mut radii: Vec<f32> = samples.iter().map(|p| (p - center).norm()).collect();
+    radii.sort_by(f32::total_cmp);
+    let radius = radii[((radii.len() as f32 * (1.0 - SYMMETRY_OUTLIERS)) as usize).min(radii.len() - 1)];
+    if radius.is_nan() || radius <= f32::EPSILON {
+        return none;
+    }
+
+    // Each point within the radius as its ring and angle.
+    let polar: Vec<(usize, f32)> = samples.iter()
+        .map(|p| p - center)
+        .filter(|d| d.norm() < radius)
+        .map(|d| (((d.norm() / radius * SYMMETRY_RINGS as f32) as usize).min(SYMMETRY_RINGS - 1), d[1].atan2(d[0])))
+        .collect();
+    let tau = std::f32::consts::TAU;
+    let counts = |transform: &dyn Fn(f32) -> f32| {
+        let mut counts = vec![0.0f32; SYMMETRY_RINGS * SYMMETRY_SECTORS];
+        for &(ring, angle) in &polar {
+            let sector = ((transform(angle) / tau).rem_euclid(1.0) * SYMMETRY_SECTORS as f32) as usize;
+            counts[ring * SYMMETRY_SECTORS + sector.min(SYMMETRY_SECTORS - 1)] += 1.0;
+        }
+        for ring in counts.chunks_mut(SYMMETRY_SECTORS) {
+            ring.iter_mut().for_each(|c| *c = c.ln_1p());
+            let mean = ring.iter().sum::<f32>() / SYMMETRY_SECTORS as f32;
+            ring.iter_mut().for_each(|c| *c -= mean);
+        }
+        counts
+    };
+    let original = counts(&|a| a);
+    let variance: f32 = original.iter().map(|c| c * c).sum();
+    if variance.is_nan() || variance <= f32::EPSILON {
+        return none;
+    }
+    let is_symmetric = |transform: &dyn Fn(f32) -> f32| {
+        let moved = counts(transform);
+        original.iter().zip(&moved).map(|(a, b)| a * b).sum::<f32>() / variance >= SYMMETRY_CORRELATION
+    };
+
+    // An order only counts if every order dividing it does too, which
+    // every attractor symmetric under it would be.
+    let rotations: Vec<bool> = (1 ..= MAX_ROTATION).map(|n| n == 1 || is_symmetric(&|a| a + tau / n as f32)).collect();
+    let rotation = (1 ..= MAX_ROTATION).rev()
+        .find(|&n| (1 ..= n).filter(|d| n % d == 0).all(|d| rotations[d as usize - 1]))
+        .unwrap_or(1);
+    // Reflecting in the axis at angle `a` takes angle `t` to `2a - t`.
+    let mirror = (0 .. MIRROR_AXES).any(|i| is_symmetric(&|a| tau * i as f32 / MIRROR_AXES as f32 - a));
+    Symmetry { rotation, mirror }
+}
+
+/// The centroid of the cells of a grid over the points which hold a few of
+/// them, or `None` if the points do not spread out.
+fn symmetry_center(samples: &[Point2<f32>]) -> Option<Point2<f32>> {
+    if samples.is_empty() {
+        return None;
+    }
+    let range = |axis: usize| {
+        let mut values: Vec<f32> = samples.iter().map(|p| p[axis]).collect();
+        values.sort_by(f32::total_cmp);
+        let trim = (values.len() as f32 * CENTER_OUTLIERS) as usize;
+        (values[trim], values[values.len() - 1 - trim] - values[trim])
+    };
+    let ((x_min, width), (y_min, height)) = (range(0), range(1));
+    if !(width > f32::EPSILON && height > f32::EPSILON) {
+        return None;
+    }
+
+    let mut counts = vec![0u32; CENTER_GRID * CENTER_GRID];
+    for p in samples {
+        let x = ((p[0] - x_min) / width * CENTER_GRID as f32).floor();
+        let y = ((p[1] - y_min) / height * CENTER_GRID as f32).floor();
+        if (0.0 .. CENTER_GRID as f32).contains(&x) && (0.0 .. CENTER_GRID as f32).contains(&y) {
+            counts[y as usize * CENTER_GRID + x as usize] += 1;
+        }
+    }
+    let cells: Vec<usize> = (0 .. counts.len()).filter(|&i| counts[i] >= CENTER_MIN_POINTS).collect();
+    if cells.is_empty() {
+        return None;
+    }
+    let mean = |coord: fn(usize) -> usize| {
+        cells.iter().map(|&i| coord(i) as f32 + 0.5).sum::<f32>() / cells.len() as f32 / CENTER_GRID as f32
+    };
+    Some(Point2::new(x_min + mean(|i| i % CENTER_GRID) * width, y_min + mean(|i| i / CENTER_GRID) * height))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::presets;
+
+    fn palette(keys: &[(u8, u8, u8)]) -> Palette {
+        Palette::from_keys(keys.iter().map(|&(r, g, b)| Color::rgb(r, g, b)).collect()).unwrap()
+    }
+
+    #[test]
+    fn structure_is_counted() {
+        let swirl = presets::swirl().summary();
+        assert_eq!(swirl.functions, 3);
+        let names: Vec<&str> = swirl.variations.iter().map(String::as_str).collect();
+        assert_eq!(names, ["Id", "Spherical", "Swirl"]);
+        assert_eq!(presets::fern().summary().variations.len(), 1);
+
+        // Equal weights give log2 of the number of functions, and uneven
+        // ones less.
+        assert!((presets::gasket().summary().weight_entropy - 3f32.log2()).abs() < 1e-4);
+        assert!((presets::starter().summary().weight_entropy - 1.0).abs() < 1e-4);
+        assert!(presets::fern().summary().weight_entropy < 1.0);
+        let mut single = presets::gasket();
+        single.functions.truncate(1);
+        assert_eq!(single.summary().weight_entropy, 0.0);
+
+        let wide = Flame { bounds: Bounds::new(-2.0, 2.0, -1.0, 1.0), ..presets::gasket() };
+        assert!((wide.summary().aspect - 2.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn palettes_have_a_dominant_hue_unless_gray() {
+        let hue = |keys: &[(u8, u8, u8)]| palette(keys).summary().dominant_hue;
+        assert!(hue(&[(10, 20, 200), (40, 60, 255), (200, 200, 255)]).is_some_and(|h| (220.0 .. 250.0).contains(&h)));
+        assert!(hue(&[(200, 20, 10), (255, 80, 40)]).is_some_and(|h| h < 30.0));
+        // Reds either side of 0 average to red, not to cyan.
+        assert!(hue(&[(255, 0, 40), (255, 40, 0)]).is_some_and(|h| !(30.0 .. 330.0).contains(&h)));
+        assert_eq!(hue(&[(0, 0, 0), (128, 128, 128), (255, 255, 255)]), None);
+
+        let gray = palette(&[(0, 0, 0), (255, 255, 255)]).summary();
+        assert!(gray.lightness_min < 0.01 && gray.lightness_max > 0.99);
+        assert!(gray.lightness > gray.lightness_min && gray.lightness < gray.lightness_max);
+    }
+
+    #[test]
+    fn symmetry_is_found_in_symmetric_attractors() {
+        assert_eq!(presets::gasket().summary().symmetry, Symmetry { rotation: 3, mirror: true });
+        assert_eq!(presets::fern().summary().symmetry, Symmetry { rotation: 1, mirror: false });
+        // A square is unchanged by quarter turns, and so by half turns.
+        let quarter = |x, y| Function {
+            trans: Affine2::from_matrix_unchecked(Matrix3::new(0.5, 0.0, x, 0.0, 0.5, y, 0.0, 0.0, 1.0)),
+            ..presets::gasket().functions[0]
+        };
+        let square = Flame {
+            functions: vec![quarter(0.0, 0.0), quarter(0.5, 0.0), quarter(0.0, 0.5), quarter(0.5, 0.5)],
+            ..presets::gasket()
+        };
+        let symmetry = square.summary().symmetry;
+        assert!(symmetry.rotation.is_multiple_of(4) && symmetry.mirror, "{:?}", symmetry);
+    }
+
+    #[test]
+    fn summaries_are_repeatable() {
+        assert_eq!(presets::swirl().summary(), presets::swirl().summary());
+    }
+}
@@ -1,10 +1,19 @@
-use serde::Deserialize;
-use nalgebra::Point2;
+use serde::{Deserialize, Serialize};
+use serde::de::{self, Deserializer, MapAccess, Visitor};
+use nalgebra::{Point2, Scalar};
+use num_traits::Float;
+use std::collections::HashMap;
+
+use super::math::{Math, PlatformMath};
 
 use std::f32::consts::PI;
 const PII: f32 = 1.0 / PI;
 
-#[derive(Clone, Copy, Deserialize)]
+/// A variation, written in descriptors as its name or, for variations with
+/// parameters, as a map from its name to the parameters. Parameters may be
+/// given positionally, as `{"Blob": [1.0, 0.5]}`, or by name, as
+/// `{"Blob": {"waves": 6}}`. Any which are left out take their defaults.
+#[derive(Clone, Copy, PartialEq, Serialize)]
 pub enum Variation {
     Id,
     Sinusoidal,
@@ -27,15 +36,214 @@ pub enum Variation {
     Tangent,
     Blob(f32, f32, f32), // theta
     PDJ(f32, f32, f32, f32),
+    Waves2(f32, f32, f32, f32),
+    // Complex functions, treating (x, y) as x + iy
+    Exp,
+    Log,
+    Sin,
+    Cos,
+    Tan,
+    Sinh,
+    Cosh,
+    /// The identity, taking `SLEEP` to apply, for testing threads which
+    /// stall.
+    #[cfg(test)]
+    Sleep,
 }
 
+/// How long `Variation::Sleep` takes to apply.
+#[cfg(test)]
+pub(crate) const SLEEP: std::time::Duration = std::time::Duration::from_millis(1);
+
 use self::Variation::*;
 
+/// The kinds of variation, without their parameters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub enum VariationDiscriminant {
+    Id, Sinusoidal, Spherical, Swirl, Horseshoe, Polar, Handkerchief, Heart,
+    Disc, Spiral, Hyperbolic, Diamond, Ex, Bent, Fisheye, Eyefish,
+    Exponential, Cylinder, Tangent, Blob, PDJ, Waves2,
+    Exp, Log, Sin, Cos, Tan, Sinh, Cosh,
+    #[cfg(test)]
+    Sleep,
+}
+
+impl VariationDiscriminant {
+    /// Every kind of variation, in declaration order.
+    pub const ALL: [VariationDiscriminant; 29] = {
+        use self::VariationDiscriminant as D;
+        [
+            D::Id, D::Sinusoidal, D::Spherical, D::Swirl, D::Horseshoe, D::Polar, D::Handkerchief, D::Heart,
+            D::Disc, D::Spiral, D::Hyperbolic, D::Diamond, D::Ex, D::Bent, D::Fisheye, D::Eyefish,
+            D::Exponential, D::Cylinder, D::Tangent, D::Blob, D::PDJ, D::Waves2,
+            D::Exp, D::Log, D::Sin, D::Cos, D::Tan, D::Sinh, D::Cosh,
+        ]
+    };
+
+    /// Names and default values of the variation's parameters, in order.
+    pub fn parameters(self) -> &'static [(&'static str, f32)] {
+        match self {
+            VariationDiscriminant::Blob => &[("high", 1.0), ("low", 0.5), ("waves", 4.0)],
+            VariationDiscriminant::PDJ => &[("a", 1.0), ("b", 1.0), ("c", 1.0), ("d", 1.0)],
+            VariationDiscriminant::Waves2 => {
+                &[("scale_x", 0.25), ("scale_y", 0.25), ("freq_x", 4.0), ("freq_y", 4.0)]
+            }
+            _ => &[],
+        }
+    }
+
+    pub fn default_parameters(self) -> Vec<f32> {
+        self.parameters().iter().map(|&(_, x)| x).collect()
+    }
+
+    /// The variation of this kind with the given parameters, which must be
+    /// as many as `parameters()`.
+    pub fn with_params(self, p: &[f32]) -> Variation {
+        use self::VariationDiscriminant as D;
+        match self {
+            D::Id => Id, D::Sinusoidal => Sinusoidal, D::Spherical => Spherical,
+            D::Swirl => Swirl, D::Horseshoe => Horseshoe, D::Polar => Polar,
+            D::Handkerchief => Handkerchief, D::Heart => Heart, D::Disc => Disc,
+            D::Spiral => Spiral, D::Hyperbolic => Hyperbolic, D::Diamond => Diamond,
+            D::Ex => Ex, D::Bent => Bent, D::Fisheye => Fisheye, D::Eyefish => Eyefish,
+            D::Exponential => Exponential, D::Cylinder => Cylinder, D::Tangent => Tangent,
+            D::Blob => Blob(p[0], p[1], p[2]),
+            D::PDJ => PDJ(p[0], p[1], p[2], p[3]),
+            D::Waves2 => Waves2(p[0], p[1], p[2], p[3]),
+            D::Exp => Exp, D::Log => Log, D::Sin => Sin, D::Cos => Cos,
+            D::Tan => Tan, D::Sinh => Sinh, D::Cosh => Cosh,
+            #[cfg(test)]
+            D::Sleep => Sleep,
+        }
+    }
+
+    /// The variation of this kind with default parameters.
+    pub fn with_defaults(self) -> Variation {
+        self.with_params(&self.default_parameters())
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ParamsSource {
+    Positional(Vec<f32>),
+    Named(HashMap<String, f32>),
+}
+
+impl ParamsSource {
+    fn resolve<E: de::Error>(self, kind: VariationDiscriminant) -> Result<Vec<f32>, E> {
+        let spec = kind.parameters();
+        let mut params = kind.default_parameters();
+        match self {
+            ParamsSource::Positional(given) => {
+                if given.len() > spec.len() {
+                    return Err(E::custom(format!(
+                        "{:?} takes at most {} parameters, got {}", kind, spec.len(), given.len()
+                    )));
+                }
+                params[.. given.len()].copy_from_slice(&given);
+            }
+            ParamsSource::Named(given) => {
+                for (name, value) in given {
+                    let Some(i) = spec.iter().position(|&(n, _)| n == name) else {
+                        let names: Vec<_> = spec.iter().map(|&(n, _)| n).collect();
+                        return Err(E::custom(format!(
+                            "unknown parameter '{}' for {:?} (expected one of: {})", name, kind, names.join(", ")
+                        )));
+                    };
+                    params[i] = value;
+                }
+            }
+        }
+        Ok(params)
+    }
+}
+
+struct VariationVisitor;
+
+impl<'de> Visitor<'de> for VariationVisitor {
+    type Value = Variation;
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "a variation name, or a map from a variation name to its parameters")
+    }
+
+    fn visit_str<E: de::Error>(self, name: &str) -> Result<Variation, E> {
+        let kind = VariationDiscriminant::deserialize(de::value::StrDeserializer::<E>::new(name))?;
+        Ok(kind.with_defaults())
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Variation, A::Error> {
+        let Some(kind) = map.next_key::<VariationDiscriminant>()? else {
+            return Err(de::Error::custom("expected a variation name"));
+        };
+        let params = map.next_value::<ParamsSource>()?.resolve(kind)?;
+        if map.next_key::<de::IgnoredAny>()?.is_some() {
+            return Err(de::Error::custom("expected a single variation"));
+        }
+        Ok(kind.with_params(&params))
+    }
+}
+
+impl<'de> Deserialize<'de> for Variation {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(VariationVisitor)
+    }
+}
+
 impl Variation {
+    /// The variation's numeric parameters, in declaration order.
+    pub fn params(&self) -> Vec<f32> {
+        match *self {
+            Blob(a, b, c) => vec![a, b, c],
+            PDJ(a, b, c, d) | Waves2(a, b, c, d) => vec![a, b, c, d],
+            _ => Vec::new(),
+        }
+    }
+
+    /// The same variation with its parameters replaced by `p`, which must
+    /// be as long as `params()`.
+    pub fn with_params(self, p: &[f32]) -> Variation {
+        self.discriminant().with_params(p)
+    }
+
+    pub fn discriminant(&self) -> VariationDiscriminant {
+        use self::VariationDiscriminant as D;
+        match self {
+            Id => D::Id, Sinusoidal => D::Sinusoidal, Spherical => D::Spherical,
+            Swirl => D::Swirl, Horseshoe => D::Horseshoe, Polar => D::Polar,
+            Handkerchief => D::Handkerchief, Heart => D::Heart, Disc => D::Disc,
+            Spiral => D::Spiral, Hyperbolic => D::Hyperbolic, Diamond => D::Diamond,
+            Ex => D::Ex, Bent => D::Bent, Fisheye => D::Fisheye, Eyefish => D::Eyefish,
+            Exponential => D::Exponential, Cylinder => D::Cylinder, Tangent => D::Tangent,
+            Blob(..) => D::Blob, PDJ(..) => D::PDJ, Waves2(..) => D::Waves2,
+            Exp => D::Exp, Log => D::Log, Sin => D::Sin, Cos => D::Cos,
+            Tan => D::Tan, Sinh => D::Sinh, Cosh => D::Cosh,
+            #[cfg(test)]
+            Sleep => D::Sleep,
+        }
+    }
+
+    /// Whether two variations are the same function, ignoring parameters.
+    pub fn same_kind(&self, other: &Variation) -> bool {
+        std::mem::discriminant(self) == std::mem::discriminant(other)
+    }
+
     pub fn eval(self, arg: Point2<f32>) -> Point2<f32> {
+        self.eval_with::<PlatformMath, f32>(arg)
+    }
+
+    /// Evaluate the variation with the transcendental functions of `M`, in
+    /// the precision of `T`.
+    pub(crate) fn eval_with<M: Math, T: Float + Scalar>(self, arg: Point2<T>) -> Point2<T> {
         let (x, y) = (arg[0], arg[1]);
+        let (sin, cos) = (M::sin::<T>, M::cos::<T>);
+        // Constants and parameters, which are single precision.
+        let c = |v: f32| T::from(v).unwrap();
+        let (pi, pii) = (c(PI), c(PII));
+        let (zero, half, one, two) = (c(0.0), c(0.5), c(1.0), c(2.0));
 
-        let mut r_: Option<f32> = None;
+        let mut r_: Option<T> = None;
         let mut r = || {
             match r_ {
                 Some(r__) => r__,
@@ -47,21 +255,21 @@ impl Variation {
             }
         };
 
-        let mut theta_: Option<f32> = None;
+        let mut theta_: Option<T> = None;
         let mut theta = || {
             match theta_ {
                 Some(theta__) => theta__,
                 None => {
-                    let theta__ = if y == 0.0 {
-                        if x == 0.0 {
-                            0.0
-                        } else if x > 0.0 {
-                            0.5 * PI
+                    let theta__ = if y == zero {
+                        if x == zero {
+                            zero
+                        } else if x > zero {
+                            half * pi
                         } else {
-                            1.5 * PI
+                            c(1.5) * pi
                         }
                     } else {
-                        (x / y).atan()
+                        M::atan(x / y)
                     };
                     theta_ = Some(theta__);
                     theta__
@@ -71,42 +279,213 @@ impl Variation {
 
         let (xo, yo) = match self {
             Id => (x, y),
-            Sinusoidal => (x.sin(), y.sin()),
+            Sinusoidal => (sin(x), sin(y)),
             Spherical => (x / r(), y / r()),
-            Swirl => (x * r().sin() - y * r().cos(), x * r().cos() + y * r().sin()),
-            Horseshoe => ((x - y) * (x + y) / r(), 2.0 * x * y / r()),
-            Polar => (theta() * PII, r() - 1.0),
-            Handkerchief => ((theta() + r()).sin(), (theta() - r()).cos()),
-            Heart => (r() * (theta() * r()).sin(), -r() * (theta() * r()).cos()),
-            Disc => (theta() * PII * (PI * r()).sin(), theta() * PII),
-            Spiral => ((theta().cos() + r().sin()) / r(), (theta().sin() - r().cos()) / r()),
-            Hyperbolic => (theta().sin() / r(), r() * theta().cos()),
-            Diamond => (theta().sin() * r().cos(), theta().cos() * r().sin()),
+            Swirl => (x * sin(r()) - y * cos(r()), x * cos(r()) + y * sin(r())),
+            Horseshoe => ((x - y) * (x + y) / r(), two * x * y / r()),
+            Polar => (theta() * pii, r() - one),
+            Handkerchief => (sin(theta() + r()), cos(theta() - r())),
+            Heart => (r() * sin(theta() * r()), -r() * cos(theta() * r())),
+            Disc => (theta() * pii * sin(pi * r()), theta() * pii),
+            Spiral => ((cos(theta()) + sin(r())) / r(), (sin(theta()) - cos(r())) / r()),
+            Hyperbolic => (sin(theta()) / r(), r() * cos(theta())),
+            Diamond => (sin(theta()) * cos(r()), cos(theta()) * sin(r())),
             Ex => {
-                let p0 = (theta() + r()).sin().powi(3);
-                let p1 = (theta() - r()).cos().powi(3);
+                let p0 = sin(theta() + r()).powi(3);
+                let p1 = cos(theta() - r()).powi(3);
                 (r() * (p0 + p1), r() * (p0 - p1))
             }
             Bent => {
-                let a = if x >= 0.0 { x } else { 2.0 * x };
-                let b = if y >= 0.0 { y } else { 0.5 * y };
+                let a = if x >= zero { x } else { two * x };
+                let b = if y >= zero { y } else { half * y };
                 (a, b)
             }
-            Fisheye => (2.0 * y / (r() + 1.0), 2.0 * x / (r() + 1.0)),
-            Eyefish => (2.0 * x / (r() + 1.0), 2.0 * y / (r() + 1.0)),
+            Fisheye => (two * y / (r() + one), two * x / (r() + one)),
+            Eyefish => (two * x / (r() + one), two * y / (r() + one)),
             Exponential => (
-                (x - 1.0).exp() * (PI * y).cos(),
-                (x - 1.0).exp() * (PI * y).sin(),
+                M::exp(x - one) * cos(pi * y),
+                M::exp(x - one) * sin(pi * y),
             ),
-            Cylinder => (x.sin(), y),
-            Tangent => (x.sin() / y.cos(), y.tan()),
+            Cylinder => (sin(x), y),
+            Tangent => (sin(x) / cos(y), M::tan(y)),
             Blob(h, l, w) => {
-                let a = r() * (l + (h - l) / 2.0 * (1.0 + (theta() * w).sin()));
-                (a * theta().cos(), a * theta().sin())
+                let (h, l, w) = (c(h), c(l), c(w));
+                let a = r() * (l + (h - l) / two * (one + sin(theta() * w)));
+                (a * cos(theta()), a * sin(theta()))
+            }
+            PDJ(a, b, c_, d) => {
+                let (a, b, c_, d) = (c(a), c(b), c(c_), c(d));
+                (sin(a * y) - cos(b * x), sin(c_ * x) - cos(d * y))
+            }
+            Waves2(scale_x, scale_y, freq_x, freq_y) => (
+                x + c(scale_x) * sin(y * c(freq_x)),
+                y + c(scale_y) * sin(x * c(freq_y)),
+            ),
+            Exp => (M::exp(x) * cos(y), M::exp(x) * sin(y)),
+            // Principal branch, with the cut along the negative real axis.
+            Log => (half * M::ln(x * x + y * y), M::atan2(y, x)),
+            Sin => (sin(x) * M::cosh(y), cos(x) * M::sinh(y)),
+            Cos => (cos(x) * M::cosh(y), -sin(x) * M::sinh(y)),
+            Tan => {
+                let d = cos(two * x) + M::cosh(two * y);
+                (sin(two * x) / d, M::sinh(two * y) / d)
+            }
+            Sinh => (M::sinh(x) * cos(y), M::cosh(x) * sin(y)),
+            Cosh => (M::cosh(x) * cos(y), M::sinh(x) * sin(y)),
+            #[cfg(test)]
+            Sleep => {
+                std::thread::sleep(SLEEP);
+                (x, y)
             }
-            PDJ(a, b, c, d) => ((a * y).sin() - (b * x).cos(), (c * x).sin() - (d * y).cos()),
         };
 
         Point2::new(xo, yo)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(var: Variation, x: f64, y: f64) -> (f64, f64) {
+        let p = var.eval_with::<PlatformMath, f64>(Point2::new(x, y));
+        (p[0], p[1])
+    }
+
+    fn assert_close(got: (f64, f64), want: (f64, f64), what: &str) {
+        assert!(
+            (got.0 - want.0).abs() < 1e-12 && (got.1 - want.1).abs() < 1e-12,
+            "{}: got {:?}, expected {:?}", what, got, want
+        );
+    }
+
+    #[test]
+    fn complex_variations_match_their_reference_values() {
+        let (s, c) = (1f64.sin(), 1f64.cos());
+        let (sh, ch) = (1f64.sinh(), 1f64.cosh());
+        let e = std::f64::consts::E;
+        // z = 1 + i, worked out by hand from the complex identities.
+        assert_close(eval(Exp, 1.0, 1.0), (e * c, e * s), "exp");
+        assert_close(eval(Log, 1.0, 1.0), (0.5 * 2f64.ln(), std::f64::consts::FRAC_PI_4), "log");
+        assert_close(eval(Sin, 1.0, 1.0), (s * ch, c * sh), "sin");
+        assert_close(eval(Cos, 1.0, 1.0), (c * ch, -s * sh), "cos");
+        assert_close(eval(Sinh, 1.0, 1.0), (sh * c, ch * s), "sinh");
+        assert_close(eval(Cosh, 1.0, 1.0), (ch * c, sh * s), "cosh");
+        let d = 2f64.cos() + 2f64.cosh();
+        assert_close(eval(Tan, 1.0, 1.0), (2f64.sin() / d, 2f64.sinh() / d), "tan");
+        assert_close(
+            eval(Waves2(0.5, 0.25, 2.0, 3.0), 1.0, 1.0),
+            (1.0 + 0.5 * 2f64.sin(), 1.0 + 0.25 * 3f64.sin()),
+            "waves2",
+        );
+
+        // On the real axis they are the familiar real functions.
+        for x in [-1.5, -0.3, 0.7, 2.0] {
+            assert_close(eval(Exp, x, 0.0), (x.exp(), 0.0), "exp on the real axis");
+            assert_close(eval(Sin, x, 0.0), (x.sin(), 0.0), "sin on the real axis");
+            assert_close(eval(Cos, x, 0.0), (x.cos(), 0.0), "cos on the real axis");
+            assert_close(eval(Tan, x, 0.0), (x.tan(), 0.0), "tan on the real axis");
+        }
+        assert_close(eval(Log, 2.0, 0.0), (2f64.ln(), 0.0), "log on the real axis");
+    }
+
+    #[test]
+    fn log_takes_the_principal_branch() {
+        let pi = std::f64::consts::PI;
+        assert_close(eval(Log, -1.0, 0.0), (0.0, pi), "above the cut");
+        assert_close(eval(Log, -1.0, -0.0), (0.0, -pi), "below the cut");
+        // Exp undoes Log away from the origin.
+        for (x, y) in [(0.3, -2.0), (-1.2, 0.4), (5.0, 5.0)] {
+            let (u, v) = eval(Log, x, y);
+            assert_close(eval(Exp, u, v), (x, y), "exp of log");
+        }
+    }
+
+    #[test]
+    fn singularities_give_non_finite_points() {
+        assert_eq!(eval(Log, 0.0, 0.0).0, f64::NEG_INFINITY);
+        // tan has poles at odd multiples of pi / 2 on the real axis.
+        let (u, _) = eval(Tan, std::f64::consts::FRAC_PI_2, 0.0);
+        assert!(u.abs() > 1e15, "tan(pi / 2) = {}", u);
+        let p = Tan.eval(Point2::new(std::f32::consts::FRAC_PI_2, 0.0));
+        assert!(!p[0].is_finite() || p[0].abs() > 1e6, "tan(pi / 2) = {}", p[0]);
+        assert!(!Exp.eval(Point2::new(1000.0, 0.0))[0].is_finite());
+    }
+
+    #[test]
+    fn complex_variations_round_trip_through_descriptors() {
+        for var in [Exp, Log, Sin, Cos, Tan, Sinh, Cosh, Waves2(0.5, 0.1, 3.0, 7.0)] {
+            let json = serde_json::to_string(&var).unwrap();
+            let back: Variation = serde_json::from_str(&json).unwrap();
+            assert!(back == var, "{} came back as {:?}", json, back.params());
+        }
+        let log: Variation = serde_json::from_str(r#""Log""#).unwrap();
+        assert!(log == Log);
+    }
+
+    #[test]
+    fn waves2_parameters_take_their_defaults() {
+        let defaults: Variation = serde_json::from_str(r#""Waves2""#).unwrap();
+        assert_eq!(defaults.params(), vec![0.25, 0.25, 4.0, 4.0]);
+        let named: Variation = serde_json::from_str(r#"{"Waves2": {"scale_x": 0.5, "freq_y": 2}}"#).unwrap();
+        assert_eq!(named.params(), vec![0.5, 0.25, 4.0, 2.0]);
+        let positional: Variation = serde_json::from_str(r#"{"Waves2": [0.5, 0.75]}"#).unwrap();
+        assert_eq!(positional.params(), vec![0.5, 0.75, 4.0, 4.0]);
+
+        assert!(serde_json::from_str::<Variation>(r#"{"Waves2": [1, 2, 3, 4, 5]}"#).is_err());
+        let Err(err) = serde_json::from_str::<Variation>(r#"{"Waves2": {"amplitude": 1}}"#) else {
+            panic!("an unknown parameter was accepted");
+        };
+        assert!(err.to_string().contains("scale_x, scale_y, freq_x, freq_y"), "{}", err);
+    }
+
+    fn parse(json: &str) -> Result<Variation, String> {
+        serde_json::from_str(json).map_err(|e| e.to_string())
+    }
+
+    fn parse_error(json: &str) -> String {
+        parse(json).err().unwrap_or_else(|| panic!("{} was accepted", json))
+    }
+
+    #[test]
+    fn full_specifications_parse_as_before() {
+        assert!(parse(r#"{"Blob": [2.0, 0.25, 6.0]}"#).unwrap() == Blob(2.0, 0.25, 6.0));
+        assert!(parse(r#"{"PDJ": [1.5, -0.5, 2, 3]}"#).unwrap() == PDJ(1.5, -0.5, 2.0, 3.0));
+        assert!(parse(r#""Swirl""#).unwrap() == Swirl);
+        // Writing a variation gives the full positional form.
+        assert_eq!(serde_json::to_string(&Blob(2.0, 0.25, 6.0)).unwrap(), r#"{"Blob":[2.0,0.25,6.0]}"#);
+        assert_eq!(serde_json::to_string(&Swirl).unwrap(), r#""Swirl""#);
+    }
+
+    #[test]
+    fn missing_parameters_take_their_defaults() {
+        assert_eq!(parse(r#"{"Blob": {"waves": 6}}"#).unwrap().params(), vec![1.0, 0.5, 6.0]);
+        assert_eq!(parse(r#"{"Blob": [2]}"#).unwrap().params(), vec![2.0, 0.5, 4.0]);
+        assert_eq!(parse(r#"{"Blob": []}"#).unwrap().params(), vec![1.0, 0.5, 4.0]);
+        assert_eq!(parse(r#""Blob""#).unwrap().params(), vec![1.0, 0.5, 4.0]);
+        assert_eq!(parse(r#"{"PDJ": {"c": -1}}"#).unwrap().params(), vec![1.0, 1.0, -1.0, 1.0]);
+    }
+
+    #[test]
+    fn defaults_match_each_variation() {
+        for kind in VariationDiscriminant::ALL {
+            let var = kind.with_defaults();
+            assert_eq!(var.discriminant(), kind);
+            assert_eq!(var.params(), kind.default_parameters());
+            assert_eq!(kind.parameters().len(), kind.default_parameters().len());
+        }
+    }
+
+    #[test]
+    fn malformed_parameters_are_errors() {
+        let long = parse_error(r#"{"Blob": [1, 2, 3, 4]}"#);
+        assert!(long.contains("Blob takes at most 3 parameters, got 4"), "{}", long);
+        assert!(parse_error(r#"{"Swirl": [1]}"#).contains("at most 0"));
+        let unknown = parse_error(r#"{"Blob": {"amplitude": 1}}"#);
+        assert!(unknown.contains("expected one of: high, low, waves"), "{}", unknown);
+        assert!(parse_error(r#"{"Blob": [1], "PDJ": [1]}"#).contains("expected a single variation"));
+        assert!(parse_error(r#"{}"#).contains("expected a variation name"));
+        assert!(parse(r#""Blobby""#).is_err());
+        assert!(parse(r#"{"Blob": {"waves": "six"}}"#).is_err());
+    }
+}
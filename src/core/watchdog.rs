@@ -0,0 +1,194 @@
+//! Noticing threads which stop making progress, such as those caught in
+//! denormal arithmetic or escaping and restarting without end in a
+//! pathological variation, so that one slow thread does not hold up a
+//! render silently or forever.
+//!
+//! Each thread publishes its iteration count every `HEARTBEAT_ITERS`
+//! iterations, with the function it applies in that iteration. While waiting for
+//! the threads, the calling thread reads these every `Watchdog::interval`,
+//! warning of each thread whose count has not moved, and cancels the run
+//! once one has not moved for `Watchdog::stall_timeout`. Threads check for
+//! a cancellation between heartbeats, so a thread stops within
+//! `HEARTBEAT_ITERS` iterations of one, and a thread caught in a single
+//! iteration which never ends still cannot be stopped.
+
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use super::*;
+
+/// Number of iterations between a thread's heartbeats.
+pub const HEARTBEAT_ITERS: u64 = 256;
+
+/// Default time between the watchdog's checks of the threads.
+pub const WATCHDOG_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Shortest time between the watchdog's checks, however short the timeout.
+const MIN_WATCHDOG_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How often the threads of a run are checked, how long one may go
+/// without progress before the run is cancelled, and who is told of
+/// threads which have stalled.
+#[derive(Debug, Clone, Copy)]
+pub struct Watchdog {
+    pub interval: Duration,
+    /// Cancel the run once a thread has made no progress for this long,
+    /// or only warn of it if `None`.
+    pub stall_timeout: Option<Duration>,
+    /// Called on the thread waiting for the run with each warning, as it
+    /// is found.
+    pub on_stall: Option<fn(&StallWarning)>,
+}
+
+impl Watchdog {
+    /// A watchdog checking every `WATCHDOG_INTERVAL`, or more often if the
+    /// timeout is short.
+    pub fn new(stall_timeout: Option<Duration>, on_stall: Option<fn(&StallWarning)>) -> Self {
+        let interval = stall_timeout.map_or(WATCHDOG_INTERVAL, |t| WATCHDOG_INTERVAL.min(t / 2));
+        Watchdog { interval: interval.max(MIN_WATCHDOG_INTERVAL), stall_timeout, on_stall }
+    }
+}
+
+/// A flag asking the threads of a run to stop, shared by every copy.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        CancelToken::default()
+    }
+
+    /// Ask the run to stop. Its threads stop at their next heartbeat, and
+    /// it runs no more.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Release);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Acquire)
+    }
+}
+
+/// What a thread last published of its progress.
+#[derive(Debug, Default)]
+pub(crate) struct Heartbeat {
+    iters: AtomicU64,
+    function: AtomicUsize,
+}
+
+impl Heartbeat {
+    /// Record that the thread has run `iters` iterations and is applying
+    /// `function` in the next.
+    pub(crate) fn beat(&self, iters: u64, function: usize) {
+        self.function.store(function, Ordering::Relaxed);
+        self.iters.store(iters, Ordering::Relaxed);
+    }
+}
+
+/// A thread which made no progress between two of the watchdog's checks.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StallWarning {
+    pub thread: usize,
+    /// Iterations the thread had run at its last heartbeat.
+    pub iters: u64,
+    /// Index of the function the thread applied at its last heartbeat,
+    /// and the name of its variation.
+    pub function: usize,
+    pub variation: String,
+    /// Time since the thread's count was last seen to move.
+    pub stalled_for: Duration,
+}
+
+impl std::fmt::Display for StallWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "thread {} has made no progress for {:.1}s, after {} iterations, last applying function {} ({})",
+            self.thread,
+            self.stalled_for.as_secs_f64(),
+            self.iters,
+            self.function,
+            self.variation
+        )
+    }
+}
+
+/// Why a run was cancelled by its watchdog: every thread which had made no
+/// progress when it was.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StallReport {
+    pub timeout: Duration,
+    pub stalled: Vec<StallWarning>,
+}
+
+impl std::fmt::Display for StallReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "render cancelled after a thread made no progress for {:.1}s", self.timeout.as_secs_f64())?;
+        for warning in &self.stalled {
+            write!(f, "\n  {}", warning)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for StallReport {}
+
+/// The watchdog's view of one call running the threads of a session.
+pub(crate) struct Monitor<'a> {
+    pub(crate) cfg: Watchdog,
+    flame: &'a Flame,
+    heartbeats: &'a [Arc<Heartbeat>],
+    token: &'a CancelToken,
+    /// Each thread's count when it was last seen to move, and when.
+    progress: Vec<(u64, Instant)>,
+    pub(crate) report: Option<StallReport>,
+}
+
+impl<'a> Monitor<'a> {
+    pub(crate) fn new(
+        cfg: Watchdog,
+        flame: &'a Flame,
+        heartbeats: &'a [Arc<Heartbeat>],
+        token: &'a CancelToken,
+    ) -> Self {
+        let now = Instant::now();
+        let progress = heartbeats.iter().map(|h| (h.iters.load(Ordering::Relaxed), now)).collect();
+        Monitor { cfg, flame, heartbeats, token, progress, report: None }
+    }
+
+    /// Check the threads which are still running, warning of those which
+    /// have stalled and cancelling the run if one has for too long. Runs
+    /// already cancelled are left to stop.
+    pub(crate) fn check(&mut self, running: impl Fn(usize) -> bool) {
+        if self.token.is_cancelled() {
+            return;
+        }
+        let now = Instant::now();
+        let mut stalled = Vec::new();
+        for (thread, heartbeat) in self.heartbeats.iter().enumerate() {
+            let iters = heartbeat.iters.load(Ordering::Relaxed);
+            let (last, since) = &mut self.progress[thread];
+            if iters != *last || !running(thread) {
+                (*last, *since) = (iters, now);
+                continue;
+            }
+            let function = heartbeat.function.load(Ordering::Relaxed);
+            let variation = self.flame.functions.get(function)
+                .map_or_else(String::new, |f| format!("{:?}", f.var.discriminant()));
+            let warning = StallWarning { thread, iters, function, variation, stalled_for: now - *since };
+            if let Some(on_stall) = self.cfg.on_stall {
+                on_stall(&warning);
+            }
+            stalled.push(warning);
+        }
+
+        let Some(timeout) = self.cfg.stall_timeout else {
+            return;
+        };
+        if stalled.iter().any(|w| w.stalled_for >= timeout) {
+            self.token.cancel();
+            self.report = Some(StallReport { timeout, stalled });
+        }
+    }
+}
@@ -0,0 +1,285 @@
+use super::accumulator::AccumError;
+use super::animation::AnimationError;
+use super::batch::JournalError;
+use super::core::{BufferError, ColorParseError, ContentHashError, PaletteError, StallReport};
+use super::file::DescriptorError;
+use super::frames::FrameError;
+use super::meta::ThumbnailError;
+use super::output::SinkError;
+use super::repl::ReplError;
+
+/// Any error produced by the library, with a stable numeric code.
+///
+/// Errors which merely wrap others, such as a descriptor's JSON error, are
+/// flattened when converted so that they are classified by their cause.
+#[derive(Debug)]
+pub enum FlameError {
+    Io(std::io::Error),
+    Json(serde_json::Error),
+    Image(image::ImageError),
+    Palette(PaletteError),
+    Color(ColorParseError),
+    Animation(AnimationError),
+    Output(SinkError),
+    Frames(Vec<FrameError>),
+    /// A check requested by the user did not pass.
+    Validation(String),
+    /// The image is too large or its buffer is malformed.
+    Buffer(BufferError),
+    /// An accumulator file could not be read.
+    Accumulator(AccumError),
+    /// A batch's journal could not be read.
+    Journal(JournalError),
+    /// A render was cancelled by its watchdog after a thread stopped
+    /// making progress.
+    Stalled(StallReport),
+}
+
+/// Broad classes of error, which the command line tool reports through its
+/// exit status.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    Parse,
+    Io,
+    Validation,
+    Render,
+}
+
+impl ErrorKind {
+    pub fn exit_status(self) -> u8 {
+        match self {
+            ErrorKind::Parse => 2,
+            ErrorKind::Io => 3,
+            ErrorKind::Validation => 4,
+            ErrorKind::Render => 5,
+        }
+    }
+}
+
+impl FlameError {
+    /// A number identifying the variant, which will not change between releases.
+    pub fn code(&self) -> u16 {
+        match self {
+            FlameError::Io(_) => 1,
+            FlameError::Json(_) => 2,
+            FlameError::Image(_) => 3,
+            FlameError::Palette(_) => 4,
+            FlameError::Color(_) => 5,
+            FlameError::Animation(_) => 6,
+            FlameError::Output(_) => 7,
+            FlameError::Frames(_) => 8,
+            FlameError::Validation(_) => 9,
+            FlameError::Buffer(_) => 10,
+            FlameError::Accumulator(_) => 11,
+            FlameError::Journal(_) => 12,
+            FlameError::Stalled(_) => 13,
+        }
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            FlameError::Animation(AnimationError::Lerp(_)) => ErrorKind::Validation,
+            FlameError::Json(_) | FlameError::Color(_) | FlameError::Animation(_) => ErrorKind::Parse,
+            FlameError::Io(_) | FlameError::Image(_) | FlameError::Frames(_) => ErrorKind::Io,
+            FlameError::Output(SinkError::UnknownFormat(_)) => ErrorKind::Validation,
+            FlameError::Output(SinkError::Buffer(e)) | FlameError::Buffer(e) => e.kind(),
+            FlameError::Output(_) => ErrorKind::Io,
+            FlameError::Palette(_) | FlameError::Validation(_) => ErrorKind::Validation,
+            FlameError::Accumulator(AccumError::ChannelMismatch { .. } | AccumError::FlameMismatch { .. }) => {
+                ErrorKind::Validation
+            }
+            FlameError::Accumulator(_) => ErrorKind::Parse,
+            FlameError::Journal(_) => ErrorKind::Parse,
+            FlameError::Stalled(_) => ErrorKind::Render,
+        }
+    }
+
+    pub fn exit_status(&self) -> u8 {
+        self.kind().exit_status()
+    }
+}
+
+impl std::fmt::Display for FlameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FlameError::Io(e) => write!(f, "{}", e),
+            FlameError::Json(e) => write!(f, "invalid descriptor: {}", e),
+            FlameError::Image(e) => write!(f, "{}", e),
+            FlameError::Palette(e) => write!(f, "invalid palette: {}", e),
+            FlameError::Color(e) => write!(f, "{}", e),
+            FlameError::Animation(e) => write!(f, "{}", e),
+            FlameError::Output(e) => write!(f, "{}", e),
+            FlameError::Frames(errors) => {
+                write!(f, "failed to write {} frame(s)", errors.len())?;
+                for e in errors {
+                    write!(f, "\n  {}", e)?;
+                }
+                Ok(())
+            }
+            FlameError::Validation(msg) => write!(f, "{}", msg),
+            FlameError::Buffer(e) => write!(f, "{}", e),
+            FlameError::Accumulator(e) => write!(f, "{}", e),
+            FlameError::Journal(e) => write!(f, "{}", e),
+            FlameError::Stalled(report) => write!(f, "{}", report),
+        }
+    }
+}
+
+impl std::error::Error for FlameError {}
+
+impl BufferError {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            // Dimensions beyond the limit are a mistake in the input.
+            BufferError::TooSmall { .. } | BufferError::TooLarge { .. } => ErrorKind::Validation,
+            BufferError::SizeMismatch { .. } | BufferError::FlatLength { .. } => ErrorKind::Render,
+        }
+    }
+}
+
+impl From<std::io::Error> for FlameError {
+    fn from(e: std::io::Error) -> Self { FlameError::Io(e) }
+}
+
+impl From<serde_json::Error> for FlameError {
+    fn from(e: serde_json::Error) -> Self { FlameError::Json(e) }
+}
+
+impl From<image::ImageError> for FlameError {
+    fn from(e: image::ImageError) -> Self { FlameError::Image(e) }
+}
+
+impl From<PaletteError> for FlameError {
+    fn from(e: PaletteError) -> Self { FlameError::Palette(e) }
+}
+
+impl From<ContentHashError> for FlameError {
+    fn from(e: ContentHashError) -> Self { FlameError::Validation(e.to_string()) }
+}
+
+impl From<ColorParseError> for FlameError {
+    fn from(e: ColorParseError) -> Self { FlameError::Color(e) }
+}
+
+impl From<SinkError> for FlameError {
+    fn from(e: SinkError) -> Self { FlameError::Output(e) }
+}
+
+impl From<BufferError> for FlameError {
+    fn from(e: BufferError) -> Self { FlameError::Buffer(e) }
+}
+
+impl From<AccumError> for FlameError {
+    fn from(e: AccumError) -> Self {
+        match e {
+            AccumError::Io(e) => FlameError::Io(e),
+            AccumError::Buffer(e) => FlameError::Buffer(e),
+            e => FlameError::Accumulator(e),
+        }
+    }
+}
+
+impl From<JournalError> for FlameError {
+    fn from(e: JournalError) -> Self {
+        match e {
+            JournalError::Io(e) => FlameError::Io(e),
+            e @ JournalError::Mismatch { .. } => FlameError::Validation(e.to_string()),
+            e => FlameError::Journal(e),
+        }
+    }
+}
+
+impl From<ThumbnailError> for FlameError {
+    fn from(e: ThumbnailError) -> Self {
+        match e {
+            ThumbnailError::Image(e) => FlameError::Image(e),
+            ThumbnailError::Buffer(e) => FlameError::Buffer(e),
+            ThumbnailError::Descriptor(e) => (*e).into(),
+            e => FlameError::Validation(e.to_string()),
+        }
+    }
+}
+
+impl From<Vec<FrameError>> for FlameError {
+    fn from(e: Vec<FrameError>) -> Self { FlameError::Frames(e) }
+}
+
+impl From<StallReport> for FlameError {
+    fn from(e: StallReport) -> Self { FlameError::Stalled(e) }
+}
+
+impl From<DescriptorError> for FlameError {
+    fn from(e: DescriptorError) -> Self {
+        match e {
+            DescriptorError::Io(e) => FlameError::Io(e),
+            DescriptorError::Json(e) => FlameError::Json(e),
+            DescriptorError::Image(e) | DescriptorError::MaskImage(e) => FlameError::Image(e),
+            DescriptorError::Palette(e) => FlameError::Palette(e),
+            e @ (DescriptorError::MissingPart(_)
+            | DescriptorError::NonFinite { .. }
+            | DescriptorError::LimitExceeded { .. }
+            | DescriptorError::Template(_)) => FlameError::Validation(e.to_string()),
+        }
+    }
+}
+
+impl From<AnimationError> for FlameError {
+    fn from(e: AnimationError) -> Self {
+        match e {
+            AnimationError::Descriptor(e) => FlameError::from(e),
+            AnimationError::Template(e) => FlameError::Validation(e.to_string()),
+            e => FlameError::Animation(e),
+        }
+    }
+}
+
+impl From<ReplError> for FlameError {
+    fn from(e: ReplError) -> Self {
+        match e {
+            ReplError::Animation(e) => FlameError::from(e),
+            ReplError::Descriptor(e) => FlameError::from(e),
+            ReplError::Image(e) => FlameError::Image(e),
+            ReplError::Buffer(e) => FlameError::Buffer(e),
+            ReplError::Io(e) => FlameError::Io(e),
+            e @ (ReplError::Command(_) | ReplError::NothingToUndo) => FlameError::Validation(e.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::StallWarning;
+    use std::time::Duration;
+
+    #[test]
+    fn wrapped_errors_are_classified_by_their_cause() {
+        let missing = std::io::Error::new(std::io::ErrorKind::NotFound, "gone");
+        let e = FlameError::from(DescriptorError::Io(missing));
+        assert_eq!((e.code(), e.exit_status()), (1, 3));
+
+        let json = serde_json::from_str::<serde_json::Value>("{").unwrap_err();
+        let e = FlameError::from(DescriptorError::Json(json));
+        assert_eq!((e.code(), e.exit_status()), (2, 2));
+
+        let e = FlameError::from(DescriptorError::MissingPart("palette"));
+        assert_eq!((e.code(), e.exit_status()), (9, 4));
+    }
+
+    #[test]
+    fn render_failures_exit_with_five() {
+        let report = StallReport { timeout: Duration::from_secs(1), stalled: Vec::<StallWarning>::new() };
+        let e = FlameError::from(report);
+        assert_eq!((e.code(), e.kind(), e.exit_status()), (13, ErrorKind::Render, 5));
+
+        let e = FlameError::from(BufferError::SizeMismatch { width: 2, height: 2, buckets: 1 });
+        assert_eq!((e.kind(), e.exit_status()), (ErrorKind::Render, 5));
+    }
+
+    #[test]
+    fn exit_statuses_follow_the_documented_classes() {
+        let kinds = [ErrorKind::Parse, ErrorKind::Io, ErrorKind::Validation, ErrorKind::Render];
+        let statuses: Vec<u8> = kinds.iter().map(|k| k.exit_status()).collect();
+        assert_eq!(statuses, vec![2, 3, 4, 5]);
+    }
+}
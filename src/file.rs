@@ -1,103 +1,1933 @@
 use std::fs::File;
-use nalgebra::{Matrix3, Transform};
-use serde::Deserialize;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use image::DynamicImage;
+use nalgebra::{Affine2, Matrix3, Point2, Transform, Vector2};
+use serde::{Deserialize, Serialize};
 
+use super::animation::{AnimationError, Expr, Vars};
 use super::core::*;
+use super::meta::{Meta, Thumbnail, ThumbnailError};
+use super::template::{self, ParamMap, TemplateError};
 
-#[derive(Deserialize)]
+/// Seed used when clustering palettes referenced by descriptors, so that
+/// the same descriptor always produces the same palette.
+const DESCRIPTOR_PALETTE_SEED: u64 = 0;
+
+/// Bounds on the work and memory reading a descriptor may take, for
+/// descriptors from untrusted sources.
+///
+/// The document's size is checked before it is parsed, and the number of
+/// functions, palette colors and mask vertices while it is scanned before
+/// anything is built from it, so that oversized descriptors are refused
+/// without allocating for their contents.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseLimits {
+    pub max_document_bytes: u64,
+    pub max_functions: usize,
+    /// Control colors of a palette written in the descriptor.
+    pub max_palette_stops: usize,
+    /// Vertices of a polygon mask.
+    pub max_mask_vertices: usize,
+    /// Total size of the files the descriptor refers to, such as palette
+    /// images, which is also the most memory decoding an image may take.
+    pub max_referenced_bytes: u64,
+}
+
+impl Default for ParseLimits {
+    /// Limits far beyond any descriptor made by hand or by this crate.
+    fn default() -> Self {
+        ParseLimits {
+            max_document_bytes: 16 << 20,
+            max_functions: 1024,
+            max_palette_stops: 4096,
+            max_mask_vertices: 65536,
+            max_referenced_bytes: 256 << 20,
+        }
+    }
+}
+
+impl ParseLimits {
+    pub const UNLIMITED: ParseLimits = ParseLimits {
+        max_document_bytes: u64::MAX,
+        max_functions: usize::MAX,
+        max_palette_stops: usize::MAX,
+        max_mask_vertices: usize::MAX,
+        max_referenced_bytes: u64::MAX,
+    };
+}
+
+/// One of the bounds of `ParseLimits`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    DocumentBytes,
+    Functions,
+    PaletteStops,
+    MaskVertices,
+    ReferencedBytes,
+}
+
+impl std::fmt::Display for Limit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Limit::DocumentBytes => write!(f, "bytes"),
+            Limit::Functions => write!(f, "functions"),
+            Limit::PaletteStops => write!(f, "palette colors"),
+            Limit::MaskVertices => write!(f, "mask vertices"),
+            Limit::ReferencedBytes => write!(f, "bytes of referenced files"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum DescriptorError {
+    Io(std::io::Error),
+    Json(serde_json::Error),
+    Image(image::ImageError),
+    MaskImage(image::ImageError),
+    Palette(PaletteError),
+    /// A flame assembled from parts was not given this part.
+    MissingPart(&'static str),
+    /// The number at this path is infinite or NaN, which JSON cannot hold.
+    NonFinite { path: String, value: f64 },
+    /// The descriptor has more of something than its `ParseLimits` allow.
+    LimitExceeded { limit: Limit, max: u64 },
+    Template(TemplateError),
+}
+
+impl std::fmt::Display for DescriptorError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DescriptorError::Io(e) => write!(f, "could not read descriptor: {}", e),
+            DescriptorError::Json(e) => write!(f, "invalid descriptor: {}", e),
+            DescriptorError::Image(e) => write!(f, "could not load palette image: {}", e),
+            DescriptorError::MaskImage(e) => write!(f, "could not load mask image: {}", e),
+            DescriptorError::Palette(e) => write!(f, "invalid palette: {}", e),
+            DescriptorError::MissingPart(part) => {
+                write!(f, "no {} given, either in a descriptor or on their own", part)
+            }
+            DescriptorError::NonFinite { path, value } => {
+                write!(f, "'{}' is {}, but descriptors can only hold finite numbers", path, value)
+            }
+            DescriptorError::LimitExceeded { limit, max } => {
+                write!(f, "descriptor has more than the {} {} allowed", max, limit)
+            }
+            DescriptorError::Template(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for DescriptorError {}
+
+impl From<std::io::Error> for DescriptorError {
+    fn from(e: std::io::Error) -> Self { DescriptorError::Io(e) }
+}
+
+impl From<serde_json::Error> for DescriptorError {
+    fn from(e: serde_json::Error) -> Self { DescriptorError::Json(e) }
+}
+
+impl From<image::ImageError> for DescriptorError {
+    fn from(e: image::ImageError) -> Self { DescriptorError::Image(e) }
+}
+
+impl From<PaletteError> for DescriptorError {
+    fn from(e: PaletteError) -> Self { DescriptorError::Palette(e) }
+}
+
+impl From<ContentHashError> for DescriptorError {
+    fn from(e: ContentHashError) -> Self {
+        DescriptorError::NonFinite { path: e.path, value: e.value as f64 }
+    }
+}
+
+impl From<TemplateError> for DescriptorError {
+    fn from(e: TemplateError) -> Self { DescriptorError::Template(e) }
+}
+
+#[derive(Deserialize, Serialize)]
 pub struct FlameSource {
-    bounds: [f32; 4],
+    bounds: BoundsSource,
     functions: Vec<FunctionSource>,
     palette: PaletteSource,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mask: Option<MaskSource>,
+    /// `native` or `flam3`, native if left out.
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "option_string")]
+    color_model: Option<ColorModel>,
+    /// How the descriptor is meant to be rendered.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    render: Option<RenderSettings>,
+    /// Provenance of the descriptor, kept exactly as read.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    meta: Option<serde_json::Value>,
+    /// A final transform, under either of the names other programs give
+    /// it. Final transforms are not supported, so these are only read to
+    /// warn that they are ignored, and are never written.
+    #[serde(default, skip_serializing)]
+    last: Option<serde_json::Value>,
+    #[serde(default, rename = "final", skip_serializing)]
+    final_transform: Option<serde_json::Value>,
+    /// Directory that relative paths in the descriptor are resolved against.
+    #[serde(skip)]
+    base: PathBuf,
+    /// Limits on reading the files the descriptor refers to.
+    #[serde(skip)]
+    limits: ParseLimits,
+    /// Whether the descriptor was read from a template, whose parameters
+    /// it no longer holds.
+    #[serde(skip)]
+    template: bool,
 }
 
 impl FlameSource {
+    /// Parse a descriptor without any limits. The template of an instance
+    /// is resolved relative to the working directory.
     pub fn from_file(f: File) -> serde_json::Result<FlameSource> {
-        serde_json::from_reader(f)
+        let bytes = read_limited(f, u64::MAX, Limit::DocumentBytes).map_err(serde::de::Error::custom)?;
+        FlameSource::from_slice(&bytes, Path::new(""), ParseLimits::UNLIMITED, &ParamMap::new(), Vec::new())
+            .map_err(|e| match e {
+                DescriptorError::Json(e) => e,
+                e => serde::de::Error::custom(e),
+            })
+    }
+
+    /// Read a descriptor, resolving any paths it contains relative to its
+    /// location, within the default `ParseLimits`.
+    pub fn from_path(path: impl AsRef<Path>) -> Result<FlameSource, DescriptorError> {
+        FlameSource::from_path_with_limits(path, ParseLimits::default())
+    }
+
+    /// Read a descriptor as `from_path` does, binding `params` if it is a
+    /// template.
+    pub fn from_path_with_params(path: impl AsRef<Path>, params: &ParamMap) -> Result<FlameSource, DescriptorError> {
+        FlameSource::read_path(path.as_ref(), ParseLimits::default(), params)
+    }
+
+    /// Read a descriptor as `from_path` does, within `limits`.
+    pub fn from_path_with_limits(path: impl AsRef<Path>, limits: ParseLimits) -> Result<FlameSource, DescriptorError> {
+        FlameSource::read_path(path.as_ref(), limits, &ParamMap::new())
+    }
+
+    fn read_path(path: &Path, limits: ParseLimits, params: &ParamMap) -> Result<FlameSource, DescriptorError> {
+        if File::open(path)?.metadata()?.len() > limits.max_document_bytes {
+            return Err(DescriptorError::LimitExceeded { limit: Limit::DocumentBytes, max: limits.max_document_bytes });
+        }
+        let bytes = read_document(path, limits)?;
+        let chain = path.canonicalize().into_iter().collect();
+        FlameSource::from_slice(&bytes, path.parent().unwrap_or(Path::new("")), limits, params, chain)
+    }
+
+    /// Read a descriptor from `reader` within `limits`, resolving paths it
+    /// contains relative to `base`.
+    pub fn from_reader_with_limits(
+        reader: impl Read,
+        base: impl AsRef<Path>,
+        limits: ParseLimits,
+    ) -> Result<FlameSource, DescriptorError> {
+        let bytes = read_limited(reader, limits.max_document_bytes, Limit::DocumentBytes)?;
+        scan::check(&bytes, limits)?;
+        FlameSource::from_slice(&bytes, base.as_ref(), limits, &ParamMap::new(), Vec::new())
+    }
+
+    /// Parse a descriptor which has passed its scan, instantiating it with
+    /// `params` if it is a template. `chain` holds the descriptor's own
+    /// file, if it has one, so that naming itself as a template is refused.
+    fn from_slice(
+        bytes: &[u8],
+        base: &Path,
+        limits: ParseLimits,
+        params: &ParamMap,
+        mut chain: Vec<PathBuf>,
+    ) -> Result<FlameSource, DescriptorError> {
+        let doc: serde_json::Value = serde_json::from_slice(bytes)?;
+        let is_template = template::is_template(&doc);
+        // Plain descriptors are parsed from their text, so that errors in
+        // them give lines and columns.
+        let mut source: FlameSource = if is_template || !params.is_empty() {
+            let doc = resolve_instance(doc, base, limits, &mut chain)?;
+            serde_json::from_value(template::instantiate(doc, params)?)?
+        } else {
+            serde_json::from_slice(bytes)?
+        };
+        source.base = base.to_path_buf();
+        source.limits = limits;
+        source.template = is_template;
+        Ok(source)
+    }
+
+    /// Interpret a parsed descriptor, resolving paths it contains relative to `base`.
+    pub fn from_value(value: serde_json::Value, base: impl AsRef<Path>) -> serde_json::Result<FlameSource> {
+        let is_template = template::is_template(&value);
+        let value = if is_template {
+            resolve_instance(value, base.as_ref(), ParseLimits::default(), &mut Vec::new())
+                .and_then(|doc| Ok(template::instantiate(doc, &ParamMap::new())?))
+                .map_err(serde::de::Error::custom)?
+        } else {
+            value
+        };
+        let mut source: FlameSource = serde_json::from_value(value)?;
+        source.base = base.as_ref().to_path_buf();
+        source.template = is_template;
+        Ok(source)
+    }
+
+    /// Read the template at `path` with every parameter it declares, taking
+    /// the bindings of an instance as the defaults of its template, for
+    /// instantiating many times.
+    pub fn template_from_path(path: impl AsRef<Path>) -> Result<serde_json::Value, DescriptorError> {
+        let path = path.as_ref();
+        let limits = ParseLimits::default();
+        let doc = serde_json::from_slice(&read_document(path, limits)?)?;
+        let mut chain = path.canonicalize().into_iter().collect();
+        resolve_instance(doc, path.parent().unwrap_or(Path::new("")), limits, &mut chain)
+    }
+
+    /// Whether the descriptor was instantiated from a template, in which case
+    /// writing it back would replace the template with this instance.
+    pub fn is_template(&self) -> bool {
+        self.template
+    }
+
+    /// Whether what the descriptor draws may depend on files besides its
+    /// own: a template, or an image or gradient it refers to.
+    pub fn refers_to_files(&self) -> bool {
+        self.template
+            || matches!(self.palette, PaletteSource::Image { .. } | PaletteSource::Gradient { .. })
+            || matches!(self.mask, Some(MaskSource::Image(_)))
+    }
+
+    /// Describe an existing flame. Palettes are written as their control
+    /// colors. Image masks are left out, as there is no file to refer to.
+    pub fn from_flame(flame: &Flame) -> FlameSource {
+        FlameSource {
+            bounds: BoundsSource::MinMax(flame.bounds.to_array()),
+            functions: flame.functions.iter().map(FunctionSource::from_function).collect(),
+            palette: PaletteSource::Keys(flame.palette.keys_with_alpha().into_iter().map(ColorSource::from_color).collect()),
+            mask: flame.mask.as_ref().and_then(MaskSource::from_shape),
+            color_model: (flame.color_model != ColorModel::Native).then_some(flame.color_model),
+            render: None,
+            meta: None,
+            last: None,
+            final_transform: None,
+            base: PathBuf::new(),
+            limits: ParseLimits::default(),
+            template: false,
+        }
+    }
+
+    /// The descriptor's palette, with any image it refers to read and any
+    /// adjustments applied.
+    pub fn palette(&self) -> Result<Palette, DescriptorError> {
+        self.palette.to_palette(&mut ReferencedFiles::new(&self.base, self.limits))
+    }
+
+    /// Replace the descriptor's palette with the control colors of `palette`.
+    pub fn set_palette(&mut self, palette: &Palette) {
+        self.palette = PaletteSource::Keys(palette.keys_with_alpha().into_iter().map(ColorSource::from_color).collect());
+    }
+
+    /// The descriptor's metadata, if it has any in a form this version understands.
+    pub fn meta(&self) -> Option<Meta> {
+        self.meta.clone().and_then(|m| serde_json::from_value(m).ok())
+    }
+
+    pub fn set_meta(&mut self, meta: Meta) {
+        self.meta = serde_json::to_value(meta).ok();
+    }
+
+    pub fn with_meta(mut self, meta: Meta) -> Self {
+        self.set_meta(meta);
+        self
+    }
+
+    /// Hash of what the descriptor renders to, as 16 hex digits: the first
+    /// eight bytes, read little-endian, of the SHA-256 hash of the flame's
+    /// `canonical_encoding` followed by that of its render settings. Neither
+    /// metadata nor how the descriptor is written makes any difference.
+    /// Files the descriptor refers to are read, as their contents are part
+    /// of the flame.
+    pub fn content_hash(&self) -> Result<String, DescriptorError> {
+        let mut bytes = self.build_flame(&mut Diagnostics::new())?.canonical_encoding()?;
+        bytes.extend(self.render.clone().unwrap_or_default().canonical_encoding());
+        let hash = sha256(&bytes);
+        Ok(format!("{:016x}", u64::from_le_bytes(hash[.. 8].try_into().expect("a SHA-256 hash is longer than 8 bytes"))))
+    }
+
+    /// The thumbnail in the descriptor's metadata, if it has one which can
+    /// be read.
+    pub fn thumbnail(&self) -> Option<DynamicImage> {
+        self.thumbnail_source()?.to_image().ok()
+    }
+
+    /// Whether the descriptor lacks a thumbnail, or has one made before it
+    /// was last changed.
+    pub fn thumbnail_is_stale(&self) -> bool {
+        self.thumbnail_source().is_none_or(|t| self.content_hash().is_ok_and(|hash| t.hash != hash))
+    }
+
+    /// Store a thumbnail of `image`, the descriptor's render, in its
+    /// metadata, leaving everything else in it untouched.
+    pub fn set_thumbnail(&mut self, image: &DynamicImage) -> Result<(), ThumbnailError> {
+        let hash = self.content_hash().map_err(|e| ThumbnailError::Descriptor(Box::new(e)))?;
+        let thumbnail = Thumbnail::from_image(image, hash)?;
+        let meta = self.meta.get_or_insert_with(|| serde_json::Value::Object(Default::default()));
+        if let serde_json::Value::Object(fields) = meta {
+            fields.insert("thumbnail".to_string(), serde_json::to_value(thumbnail).expect("thumbnails convert to JSON"));
+        }
+        Ok(())
+    }
+
+    /// `set_thumbnail` with a rendered buffer.
+    pub fn set_thumbnail_from_buffer(&mut self, buf: &Buffer<u8>) -> Result<(), ThumbnailError> {
+        self.set_thumbnail(&DynamicImage::ImageRgb8(buf.to_rgb8()?))
+    }
+
+    fn thumbnail_source(&self) -> Option<Thumbnail> {
+        serde_json::from_value(self.meta.as_ref()?.get("thumbnail")?.clone()).ok()
+    }
+
+    /// The render settings the descriptor asks for, if it has any.
+    pub fn render_settings(&self) -> Option<&RenderSettings> {
+        self.render.as_ref()
+    }
+
+    pub fn set_render_settings(&mut self, settings: Option<RenderSettings>) {
+        self.render = settings;
+    }
+
+    pub fn with_render_settings(mut self, settings: RenderSettings) -> Self {
+        self.set_render_settings(Some(settings));
+        self
+    }
+
+    /// Append a description of a change to the metadata's history, leaving
+    /// everything else in it untouched. Descriptors without metadata are
+    /// given a `history` list alone.
+    pub fn record_history(&mut self, change: impl Into<String>) {
+        let meta = self.meta.get_or_insert_with(|| serde_json::Value::Object(Default::default()));
+        if let serde_json::Value::Object(fields) = meta {
+            let history = fields.entry("history").or_insert_with(|| serde_json::Value::Array(Vec::new()));
+            if let serde_json::Value::Array(entries) = history {
+                entries.push(serde_json::Value::String(change.into()));
+            }
+        }
+    }
+
+    /// Write the descriptor as JSON. Nothing is written if it holds a
+    /// number which is not finite, as it could not be read back.
+    pub fn to_writer(&self, w: impl std::io::Write) -> Result<(), DescriptorError> {
+        self.check_finite()?;
+        serde_json::to_writer_pretty(w, self)?;
+        Ok(())
+    }
+
+    /// Check that every number in the descriptor is finite, naming the
+    /// first which is not by its path, as used by `animate --mod`.
+    pub fn check_finite(&self) -> Result<(), DescriptorError> {
+        let check = |path: &dyn Fn() -> String, x: f32| {
+            if x.is_finite() {
+                Ok(())
+            } else {
+                Err(DescriptorError::NonFinite { path: path(), value: x as f64 })
+            }
+        };
+
+        match &self.bounds {
+            BoundsSource::MinMax(b) => {
+                for (i, &x) in b.iter().enumerate() {
+                    check(&|| format!("bounds[{}]", i), x)?;
+                }
+            }
+            BoundsSource::Center { center, scale, aspect } => {
+                for (i, &x) in center.iter().enumerate() {
+                    check(&|| format!("bounds.center[{}]", i), x)?;
+                }
+                check(&|| "bounds.scale".to_string(), *scale)?;
+                if let Some(aspect) = aspect {
+                    check(&|| "bounds.aspect".to_string(), *aspect)?;
+                }
+            }
+        }
+
+        for (i, f) in self.functions.iter().enumerate() {
+            check(&|| format!("functions[{}][0]", i), f.0)?;
+            for (j, &p) in f.1.params().iter().enumerate() {
+                check(&|| format!("functions[{}][1].{:?}[{}]", i, f.1.discriminant(), j), p)?;
+            }
+            match &f.2 {
+                AffineSource::Coefficients(c) => {
+                    for (j, &x) in c.iter().enumerate() {
+                        check(&|| format!("functions[{}][2][{}]", i, j), x)?;
+                    }
+                }
+                AffineSource::Decomposed { rotate, scale, translate, skew } => {
+                    check(&|| format!("functions[{}][2].rotate", i), rotate.0)?;
+                    for (j, &x) in scale.iter().enumerate() {
+                        check(&|| format!("functions[{}][2].scale[{}]", i, j), x)?;
+                    }
+                    for (j, &x) in translate.iter().enumerate() {
+                        check(&|| format!("functions[{}][2].translate[{}]", i, j), x)?;
+                    }
+                    check(&|| format!("functions[{}][2].skew", i), *skew)?;
+                }
+            }
+            check(&|| format!("functions[{}][3]", i), f.3)?;
+            for (j, &x) in f.4.iter().enumerate() {
+                check(&|| format!("functions[{}][4][{}]", i, j), x)?;
+            }
+        }
+
+        match &self.mask {
+            Some(MaskSource::Circle { center, radius }) => {
+                for (i, &x) in center.iter().enumerate() {
+                    check(&|| format!("mask.circle.center[{}]", i), x)?;
+                }
+                check(&|| "mask.circle.radius".to_string(), *radius)?;
+            }
+            Some(MaskSource::Rect { min, max }) => {
+                for (i, &x) in min.iter().enumerate() {
+                    check(&|| format!("mask.rect.min[{}]", i), x)?;
+                }
+                for (i, &x) in max.iter().enumerate() {
+                    check(&|| format!("mask.rect.max[{}]", i), x)?;
+                }
+            }
+            Some(MaskSource::Polygon(vertices)) => {
+                for (i, v) in vertices.iter().enumerate() {
+                    for (j, &x) in v.iter().enumerate() {
+                        check(&|| format!("mask.polygon[{}][{}]", i, j), x)?;
+                    }
+                }
+            }
+            Some(MaskSource::Image(_)) | None => {}
+        }
+
+        if let Some(render) = &self.render {
+            for (name, x) in [("gamma", render.gamma), ("vibrancy", render.vibrancy)] {
+                match x {
+                    Some(x) if !x.is_finite() => {
+                        return Err(DescriptorError::NonFinite { path: format!("render.{}", name), value: x });
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(())
     }
 
-    pub fn to_flame(self) -> Flame {
-        let funcs = self.functions.iter()
-            .map(FunctionSource::to_function)
-            .collect();
+    pub fn to_flame(self) -> Result<Flame, DescriptorError> {
+        self.to_flame_with_report(&mut Diagnostics::new())
+    }
 
-        Flame {
-            bounds: Bounds::new(
-                self.bounds[0],
-                self.bounds[1],
-                self.bounds[2],
-                self.bounds[3],
-            ),
-            functions: funcs,
-            palette: self.palette.to_palette(),
+    /// Build the flame, recording any values which had to be adjusted to
+    /// fit it in `diagnostics`.
+    pub fn to_flame_with_report(self, diagnostics: &mut Diagnostics) -> Result<Flame, DescriptorError> {
+        self.build_flame(diagnostics)
+    }
+
+    fn build_flame(&self, diagnostics: &mut Diagnostics) -> Result<Flame, DescriptorError> {
+        for (key, value) in [("last", &self.last), ("final", &self.final_transform)] {
+            if let Some(value) = value {
+                diagnostics.push(Diagnostic {
+                    severity: Severity::Warning,
+                    path: key.to_string(),
+                    message: "final transforms are not supported and this one is ignored".to_string(),
+                    value_before: Some(value.to_string()),
+                    value_after: None,
+                });
+            }
         }
+        let mut files = ReferencedFiles::new(&self.base, self.limits);
+        Ok(Flame {
+            bounds: self.bounds.to_bounds(),
+            functions: to_functions(&self.functions, diagnostics),
+            palette: self.palette.to_palette(&mut files)?,
+            mask: self.mask.as_ref().map(|m| m.to_shape(&mut files)).transpose()?,
+            color_model: self.color_model.unwrap_or_default(),
+        })
     }
 }
 
-#[derive(Deserialize)]
-struct FunctionSource(f32, Variation, [f32; 6], f32);
+/// Settings a descriptor suggests it be rendered with, each of which may be
+/// left out. They are not part of `Flame` and the renderer never sees them;
+/// the command line uses them in place of its defaults.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct RenderSettings {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iters: Option<u64>,
+    /// Width and height in pixels.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dims: Option<[usize; 2]>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gamma: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vibrancy: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preserve_color: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub grayscale: Option<bool>,
+    /// Written as on the command line, e.g. `"#102030"` or `"white"`.
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "option_string")]
+    pub background: Option<Color>,
+    /// Written as on the command line, e.g. `"desaturate:0.8"`.
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "option_string")]
+    pub highlights: Option<HighlightMode>,
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "option_string")]
+    pub dither: Option<DitherMode>,
+}
+
+impl RenderSettings {
+    /// A binary encoding of the settings, which two share exactly when they
+    /// are equal. Each field in turn is a byte, 0 if it is left out or 1 if
+    /// its value follows, all numbers little-endian: iterations as a u64,
+    /// dimensions as two u64, gamma and vibrancy as the bits of an f64 with
+    /// `-0.0` written as `0.0`, flags as one byte, the background as its red,
+    /// green and blue bytes, and the highlight mode and dithering as written
+    /// on the command line, as a u32 length and UTF-8 bytes.
+    pub fn canonical_encoding(&self) -> Vec<u8> {
+        fn field<T>(bytes: &mut Vec<u8>, value: &Option<T>, encode: impl FnOnce(&mut Vec<u8>, &T)) {
+            bytes.push(u8::from(value.is_some()));
+            if let Some(value) = value {
+                encode(bytes, value);
+            }
+        }
+        let float = |bytes: &mut Vec<u8>, &v: &f64| bytes.extend((v + 0.0).to_bits().to_le_bytes());
+        let flag = |bytes: &mut Vec<u8>, &v: &bool| bytes.push(u8::from(v));
+        let name = |bytes: &mut Vec<u8>, v: String| {
+            bytes.extend((v.len() as u32).to_le_bytes());
+            bytes.extend(v.as_bytes());
+        };
+
+        let mut bytes = Vec::new();
+        field(&mut bytes, &self.iters, |b, &v| b.extend(v.to_le_bytes()));
+        field(&mut bytes, &self.dims, |b, &[w, h]| {
+            b.extend((w as u64).to_le_bytes());
+            b.extend((h as u64).to_le_bytes());
+        });
+        field(&mut bytes, &self.gamma, float);
+        field(&mut bytes, &self.vibrancy, float);
+        field(&mut bytes, &self.preserve_color, flag);
+        field(&mut bytes, &self.grayscale, flag);
+        field(&mut bytes, &self.background, |b, c| b.extend([c.red, c.green, c.blue]));
+        field(&mut bytes, &self.highlights, |b, h| name(b, h.to_string()));
+        field(&mut bytes, &self.dither, |b, d| name(b, d.to_string()));
+        bytes
+    }
+}
+
+/// A value written as the string it is parsed from.
+mod string {
+    use std::fmt::Display;
+    use std::str::FromStr;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<T: Display, S: Serializer>(value: &T, s: S) -> Result<S::Ok, S::Error> {
+        s.collect_str(value)
+    }
+
+    pub fn deserialize<'de, T, D>(d: D) -> Result<T, D::Error>
+    where
+        T: FromStr,
+        T::Err: Display,
+        D: Deserializer<'de>,
+    {
+        String::deserialize(d)?.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Values written as the strings they are parsed from.
+mod option_string {
+    use std::fmt::Display;
+    use std::str::FromStr;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<T: Display, S: Serializer>(value: &Option<T>, s: S) -> Result<S::Ok, S::Error> {
+        match value {
+            Some(v) => s.collect_str(v),
+            None => s.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, T, D>(d: D) -> Result<Option<T>, D::Error>
+    where
+        T: FromStr,
+        T::Err: Display,
+        D: Deserializer<'de>,
+    {
+        Option::<String>::deserialize(d)?
+            .map(|s| s.parse().map_err(serde::de::Error::custom))
+            .transpose()
+    }
+}
+
+/// Lists of values written as the strings they are parsed from.
+mod vec_string {
+    use std::fmt::Display;
+    use std::str::FromStr;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<T: Display, S: Serializer>(values: &[T], s: S) -> Result<S::Ok, S::Error> {
+        s.collect_seq(values.iter().map(ToString::to_string))
+    }
+
+    pub fn deserialize<'de, T, D>(d: D) -> Result<Vec<T>, D::Error>
+    where
+        T: FromStr,
+        T::Err: Display,
+        D: Deserializer<'de>,
+    {
+        Vec::<String>::deserialize(d)?
+            .iter()
+            .map(|s| s.parse().map_err(serde::de::Error::custom))
+            .collect()
+    }
+}
+
+/// The sections of a flame, which may be read from separate descriptor
+/// fragments and combined, later parts replacing earlier ones.
+#[derive(Clone, Default)]
+pub struct FlameParts {
+    pub functions: Option<Vec<Function>>,
+    pub palette: Option<Palette>,
+    pub bounds: Option<Bounds>,
+    pub mask: Option<MaskShape>,
+    pub color_model: Option<ColorModel>,
+}
+
+impl FlameParts {
+    pub fn new() -> Self {
+        FlameParts::default()
+    }
+
+    /// Every part of a flame.
+    pub fn from_flame(flame: Flame) -> Self {
+        FlameParts {
+            functions: Some(flame.functions),
+            palette: Some(flame.palette),
+            bounds: Some(flame.bounds),
+            mask: flame.mask,
+            color_model: Some(flame.color_model),
+        }
+    }
+
+    /// Read a fragment holding a list of functions, written as in the
+    /// `functions` section of a descriptor. A whole descriptor may also be
+    /// given, in which case only its functions are used.
+    pub fn functions_from_path(path: impl AsRef<Path>) -> Result<Vec<Function>, DescriptorError> {
+        FlameParts::functions_from_path_with_report(path, &mut Diagnostics::new())
+    }
+
+    /// Read a fragment holding a list of functions, recording any values
+    /// which had to be adjusted in `diagnostics`.
+    pub fn functions_from_path_with_report(
+        path: impl AsRef<Path>,
+        diagnostics: &mut Diagnostics,
+    ) -> Result<Vec<Function>, DescriptorError> {
+        let sources: Vec<FunctionSource> = read_fragment(path.as_ref(), "functions")?;
+        Ok(to_functions(&sources, diagnostics))
+    }
+
+    /// Read a fragment holding a palette, resolving any image it refers to
+    /// relative to the fragment's location, or a GIMP gradient if the path
+    /// ends in `.ggr`.
+    pub fn palette_from_path(path: impl AsRef<Path>) -> Result<Palette, DescriptorError> {
+        let path = path.as_ref();
+        if path.extension().is_some_and(|e| e.eq_ignore_ascii_case("ggr")) {
+            return Ok(Palette::from_ggr(&std::fs::read_to_string(path)?)?);
+        }
+        let source: PaletteSource = read_fragment(path, "palette")?;
+        source.to_palette(&mut ReferencedFiles::new(path.parent().unwrap_or(Path::new("")), ParseLimits::default()))
+    }
+
+    /// Read a fragment holding bounds.
+    pub fn bounds_from_path(path: impl AsRef<Path>) -> Result<Bounds, DescriptorError> {
+        let source: BoundsSource = read_fragment(path.as_ref(), "bounds")?;
+        Ok(source.to_bounds())
+    }
+
+    /// Replace the parts which `other` has.
+    pub fn merge(self, other: FlameParts) -> Self {
+        FlameParts {
+            functions: other.functions.or(self.functions),
+            palette: other.palette.or(self.palette),
+            bounds: other.bounds.or(self.bounds),
+            mask: other.mask.or(self.mask),
+            color_model: other.color_model.or(self.color_model),
+        }
+    }
+
+    /// Put the parts together, failing with the first one missing.
+    pub fn assemble(self) -> Result<Flame, DescriptorError> {
+        Ok(Flame {
+            functions: self.functions.ok_or(DescriptorError::MissingPart("functions"))?,
+            palette: self.palette.ok_or(DescriptorError::MissingPart("palette"))?,
+            bounds: self.bounds.ok_or(DescriptorError::MissingPart("bounds"))?,
+            mask: self.mask,
+            color_model: self.color_model.unwrap_or_default(),
+        })
+    }
+}
+
+/// Parse a fragment, which is either the section itself or a descriptor
+/// containing it under `key`.
+fn read_fragment<T: serde::de::DeserializeOwned>(path: &Path, key: &str) -> Result<T, DescriptorError> {
+    let max = ParseLimits::default().max_document_bytes;
+    let mut value: serde_json::Value = serde_json::from_slice(&read_limited(File::open(path)?, max, Limit::DocumentBytes)?)?;
+    if let Some(section) = value.get_mut(key) {
+        value = section.take();
+    }
+    Ok(serde_json::from_value(value)?)
+}
+
+/// Bounds are written either as `[x_min, x_max, y_min, y_max]` or as
+/// `{"center": [x, y], "scale": s, "aspect": a}`, describing a window of
+/// width `2 / s` and height `2 / (s * a)`. The aspect ratio defaults to one.
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+enum BoundsSource {
+    MinMax([f32; 4]),
+    Center {
+        center: [f32; 2],
+        scale: f32,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        aspect: Option<f32>,
+    },
+}
+
+impl BoundsSource {
+    fn to_bounds(&self) -> Bounds {
+        match *self {
+            BoundsSource::MinMax([x_min, x_max, y_min, y_max]) => Bounds::new(x_min, x_max, y_min, y_max),
+            BoundsSource::Center { center, scale, aspect } => {
+                Bounds::from_center_scale(center, scale, aspect.unwrap_or(1.0))
+            }
+        }
+    }
+}
+
+/// Masks are written as one of `{"circle": {"center": [x, y], "radius": r}}`,
+/// `{"rect": {"min": [x, y], "max": [x, y]}}`, `{"polygon": [[x, y], ...]}`
+/// or `{"image": "stencil.png"}`, in the flame's coordinates.
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum MaskSource {
+    Circle { center: [f32; 2], radius: f32 },
+    Rect { min: [f32; 2], max: [f32; 2] },
+    Polygon(Vec<[f32; 2]>),
+    /// Path to a grayscale stencil, relative to the descriptor.
+    Image(PathBuf),
+}
+
+/// Read all of `reader`, failing once it has given more than `max` bytes.
+fn read_limited(reader: impl Read, max: u64, limit: Limit) -> Result<Vec<u8>, DescriptorError> {
+    let mut bytes = Vec::new();
+    reader.take(max.saturating_add(1)).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 > max {
+        return Err(DescriptorError::LimitExceeded { limit, max });
+    }
+    Ok(bytes)
+}
+
+/// Read the descriptor at `path` and scan it against `limits`.
+fn read_document(path: &Path, limits: ParseLimits) -> Result<Vec<u8>, DescriptorError> {
+    let bytes = read_limited(File::open(path)?, limits.max_document_bytes, Limit::DocumentBytes)?;
+    scan::check(&bytes, limits)?;
+    Ok(bytes)
+}
+
+/// Apply an instance to its template, and that to its own if it is an
+/// instance too, reading each relative to the one naming it. `chain` holds
+/// the files read so far, so that a file naming itself is refused.
+///
+/// Paths in a template other than those of templates are resolved relative
+/// to the first instance, like those it holds itself.
+fn resolve_instance(
+    doc: serde_json::Value,
+    base: &Path,
+    limits: ParseLimits,
+    chain: &mut Vec<PathBuf>,
+) -> Result<serde_json::Value, DescriptorError> {
+    let Some(path) = template::template_path(&doc)? else {
+        return Ok(doc);
+    };
+    let path = base.join(path);
+    let canonical = path.canonicalize()?;
+    if chain.contains(&canonical) {
+        return Err(TemplateError::Cycle(path).into());
+    }
+    chain.push(canonical);
+    let parent = serde_json::from_slice(&read_document(&path, limits)?)?;
+    let parent = resolve_instance(parent, path.parent().unwrap_or(Path::new("")), limits, chain)?;
+    Ok(template::apply_instance(doc, parent)?)
+}
+
+/// Reads the files a descriptor refers to, relative to its directory,
+/// keeping their total size within its limits.
+struct ReferencedFiles<'a> {
+    base: &'a Path,
+    limits: ParseLimits,
+    read: u64,
+}
+
+impl<'a> ReferencedFiles<'a> {
+    fn new(base: &'a Path, limits: ParseLimits) -> Self {
+        ReferencedFiles { base, limits, read: 0 }
+    }
+
+    fn read(&mut self, path: &Path) -> Result<Vec<u8>, DescriptorError> {
+        let max = self.limits.max_referenced_bytes;
+        let remaining = max.saturating_sub(self.read);
+        let bytes = read_limited(File::open(self.base.join(path))?, remaining, Limit::ReferencedBytes)
+            .map_err(|e| match e {
+                DescriptorError::LimitExceeded { limit, .. } => DescriptorError::LimitExceeded { limit, max },
+                e => e,
+            })?;
+        self.read += bytes.len() as u64;
+        Ok(bytes)
+    }
+
+    fn read_to_string(&mut self, path: &Path) -> Result<String, DescriptorError> {
+        String::from_utf8(self.read(path)?)
+            .map_err(|e| DescriptorError::Io(std::io::Error::new(std::io::ErrorKind::InvalidData, e)))
+    }
+
+    /// Decode an image, in the format its extension names if it has one,
+    /// using no more memory than the files may take.
+    fn image(&mut self, path: &Path) -> Result<Result<DynamicImage, image::ImageError>, DescriptorError> {
+        let bytes = self.read(path)?;
+        let mut reader = image::io::Reader::new(std::io::Cursor::new(bytes));
+        match image::ImageFormat::from_path(path) {
+            Ok(format) => reader.set_format(format),
+            Err(_) => reader = reader.with_guessed_format()?,
+        }
+        let mut limits = image::io::Limits::default();
+        limits.max_alloc = Some(self.limits.max_referenced_bytes);
+        reader.limits(limits);
+        Ok(reader.decode())
+    }
+}
+
+impl MaskSource {
+    fn to_shape(&self, files: &mut ReferencedFiles) -> Result<MaskShape, DescriptorError> {
+        Ok(match self {
+            MaskSource::Circle { center, radius } => {
+                MaskShape::Circle { center: Point2::from(*center), radius: *radius }
+            }
+            MaskSource::Rect { min, max } => MaskShape::Rect { min: Point2::from(*min), max: Point2::from(*max) },
+            MaskSource::Polygon(vertices) => MaskShape::Polygon(vertices.iter().map(|&v| Point2::from(v)).collect()),
+            MaskSource::Image(path) => {
+                MaskShape::Image(files.image(path)?.map_err(DescriptorError::MaskImage)?.to_luma8())
+            }
+        })
+    }
+
+    fn from_shape(shape: &MaskShape) -> Option<MaskSource> {
+        Some(match shape {
+            MaskShape::Circle { center, radius } => MaskSource::Circle { center: [center.x, center.y], radius: *radius },
+            MaskShape::Rect { min, max } => MaskSource::Rect { min: [min.x, min.y], max: [max.x, max.y] },
+            MaskShape::Polygon(vertices) => MaskSource::Polygon(vertices.iter().map(|v| [v.x, v.y]).collect()),
+            MaskShape::Image(_) => return None,
+        })
+    }
+}
+
+/// A function is written `[weight, variation, affine, color]`, followed by
+/// its `[x, y]` axis blend if that is not `[1, 1]`.
+#[derive(Deserialize, Serialize)]
+struct FunctionSource(
+    f32,
+    Variation,
+    AffineSource,
+    f32,
+    #[serde(default = "full_blend", skip_serializing_if = "is_full_blend")] [f32; 2],
+);
+
+fn full_blend() -> [f32; 2] {
+    Function::FULL_BLEND
+}
+
+fn is_full_blend(blend: &[f32; 2]) -> bool {
+    *blend == Function::FULL_BLEND
+}
+
+/// Convert a list of functions, recording colors which were clamped into
+/// [0, 1] and weights which do not sum to one.
+fn to_functions(sources: &[FunctionSource], diagnostics: &mut Diagnostics) -> Vec<Function> {
+    let total: f32 = sources.iter().map(|s| s.0).sum();
+    if !sources.is_empty() && total.is_finite() && (total - 1.0).abs() > WEIGHT_TOLERANCE {
+        diagnostics.push(Diagnostic {
+            severity: Severity::Warning,
+            path: "functions".to_string(),
+            message: "weights do not sum to one, so each function is chosen by its share of the total".to_string(),
+            value_before: Some(total.to_string()),
+            value_after: None,
+        });
+    }
+    sources.iter().enumerate().map(|(i, source)| {
+        let function = source.to_function();
+        if !(0.0 ..= 1.0).contains(&source.3) {
+            let message = "color is outside [0, 1] and was clamped";
+            diagnostics.adjusted(format!("functions[{}][3]", i), message, source.3, function.color as f32 / 255.);
+        }
+        for (j, (&before, &after)) in source.4.iter().zip(&function.axis_blend).enumerate() {
+            if before != after {
+                let message = "axis blend is outside [0, 1] and was clamped";
+                diagnostics.adjusted(format!("functions[{}][4][{}]", i, j), message, before, after);
+            }
+        }
+        function
+    }).collect()
+}
 
 impl FunctionSource {
     fn to_function(&self) -> Function {
-        let t = Transform::from_matrix_unchecked(Matrix3::new(
-            self.2[0], self.2[1], self.2[4], 
-            self.2[2], self.2[3], self.2[5], 
-            0.0,       0.0,       1.0,
-        ));
-
         Function {
             weight: self.0,
             var: self.1,
-            trans: t,
+            trans: self.2.to_affine(),
             color: (self.3 * 255.) as u8,
+            axis_blend: self.4.map(|b| b.clamp(0.0, 1.0)),
+        }
+    }
+
+    fn from_function(f: &Function) -> FunctionSource {
+        let m = f.trans.matrix();
+        FunctionSource(
+            f.weight,
+            f.var,
+            AffineSource::Coefficients([m[(0, 0)], m[(0, 1)], m[(1, 0)], m[(1, 1)], m[(0, 2)], m[(1, 2)]]),
+            f.color as f32 / 255.,
+            f.axis_blend,
+        )
+    }
+}
+
+/// Affine transforms are written either as `[a, b, c, d, e, f]`, mapping
+/// `(x, y)` to `(a x + b y + e, c x + d y + f)`, or decomposed as
+/// `{"rotate": "36deg", "scale": [0.5, 0.5], "translate": [0, 1], "skew": 0}`,
+/// where every field is optional.
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+enum AffineSource {
+    Coefficients(#[serde(serialize_with = "serialize_coefficients")] [f32; 6]),
+    Decomposed {
+        #[serde(default)]
+        rotate: Angle,
+        #[serde(default = "unit_scale")]
+        scale: [f32; 2],
+        #[serde(default)]
+        translate: [f32; 2],
+        #[serde(default)]
+        skew: f32,
+    },
+}
+
+/// Write coefficients with negative zeros made positive, so that saving a
+/// descriptor again does not change it.
+fn serialize_coefficients<S: serde::Serializer>(c: &[f32; 6], s: S) -> Result<S::Ok, S::Error> {
+    c.map(|x| x + 0.0).serialize(s)
+}
+
+fn unit_scale() -> [f32; 2] {
+    [1.0, 1.0]
+}
+
+impl AffineSource {
+    fn to_affine(&self) -> Affine2<f32> {
+        match *self {
+            AffineSource::Coefficients(c) => Transform::from_matrix_unchecked(Matrix3::new(
+                c[0], c[1], c[4],
+                c[2], c[3], c[5],
+                0.0,  0.0,  1.0,
+            )),
+            AffineSource::Decomposed { rotate, scale, translate, skew } => AffineDecomposition {
+                translation: Vector2::from(translate),
+                rotation: rotate.0,
+                scale: Vector2::from(scale),
+                skew,
+            }.compose(),
         }
     }
 }
 
+/// An angle in radians, written as a number of radians or as an expression
+/// followed by `deg` or `rad`, such as `"360/5 deg"`.
+#[derive(Deserialize, Serialize, Clone, Copy, Default)]
+#[serde(try_from = "AngleSource", into = "f32")]
+struct Angle(f32);
+
 #[derive(Deserialize)]
-struct PaletteSource(Vec<ColorSource>);
+#[serde(untagged)]
+enum AngleSource {
+    Radians(f32),
+    Text(String),
+}
 
-impl PaletteSource {
-    fn to_palette(&self) -> Palette {
-        if self.0.len() > 256 { panic!("too many colors in palette description"); }
+impl TryFrom<AngleSource> for Angle {
+    type Error = String;
 
-        let spacing = 256 / (self.0.len() - 1);
-        let leftover = 256 % (self.0.len() - 1);
+    fn try_from(source: AngleSource) -> Result<Angle, String> {
+        let text = match source {
+            AngleSource::Radians(x) => return Ok(Angle(x)),
+            AngleSource::Text(text) => text,
+        };
+        let trimmed = text.trim();
+        let (expr, to_radians) = if let Some(e) = trimmed.strip_suffix("deg") {
+            (e, std::f64::consts::PI / 180.0)
+        } else {
+            (trimmed.strip_suffix("rad").unwrap_or(trimmed), 1.0)
+        };
+        let expr: Expr = expr.parse().map_err(|e: AnimationError| format!("invalid angle '{}': {}", text, e))?;
+        Ok(Angle((expr.eval(Vars { t: 0.0, n: 0.0 }) * to_radians) as f32))
+    }
+}
 
-        let mut p_colors = [Color::rgb(0, 0, 0); 256];
+impl From<Angle> for f32 {
+    fn from(angle: Angle) -> f32 {
+        angle.0
+    }
+}
 
-        let mut colors = self.0.iter().map(ColorSource::to_color);
-        let mut start_color = colors.next().unwrap();
-        let mut offset = 0;
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+enum PaletteSource {
+    Keys(Vec<ColorSource>),
+    Image {
+        from_image: PathBuf,
+        colors: usize,
+        #[serde(default, skip_serializing_if = "Vec::is_empty", with = "vec_string")]
+        adjust: Vec<PaletteOp>,
+    },
+    /// A GIMP gradient file.
+    Gradient {
+        from_gradient: PathBuf,
+        #[serde(default, skip_serializing_if = "Vec::is_empty", with = "vec_string")]
+        adjust: Vec<PaletteOp>,
+    },
+    /// Control colors with adjustments, written as on the command line,
+    /// e.g. `"saturation=0.8"`.
+    Adjusted {
+        keys: Vec<ColorSource>,
+        #[serde(with = "vec_string")]
+        adjust: Vec<PaletteOp>,
+    },
+}
 
-        for (i, end_color) in colors.enumerate() {
-            let span = if i < leftover { spacing + 1 } else { spacing };
-            for j in 0 .. span {
-                let t = j as f32 / (span - 1) as f32;
-                let c = Color::rgb(
-                    lerp(start_color.red, end_color.red, t),
-                    lerp(start_color.green, end_color.green, t),
-                    lerp(start_color.blue, end_color.blue, t)
-                );
-                p_colors[offset + j] = c;
+impl PaletteSource {
+    fn to_palette(&self, files: &mut ReferencedFiles) -> Result<Palette, DescriptorError> {
+        match self {
+            PaletteSource::Keys(keys) => {
+                Ok(Palette::from_keys_with_alpha(keys.iter().map(ColorSource::to_color).collect())?)
+            }
+            PaletteSource::Image { from_image, colors, adjust } => {
+                let img = files.image(from_image)??;
+                Ok(Palette::from_image_kmeans(&img, *colors, DESCRIPTOR_PALETTE_SEED)?.adjusted(adjust))
+            }
+            PaletteSource::Gradient { from_gradient, adjust } => {
+                let src = files.read_to_string(from_gradient)?;
+                Ok(Palette::from_ggr(&src)?.adjusted(adjust))
+            }
+            PaletteSource::Adjusted { keys, adjust } => {
+                Ok(Palette::from_keys_with_alpha(keys.iter().map(ColorSource::to_color).collect())?.adjusted(adjust))
             }
-            offset += span;
-            start_color = end_color;
         }
+    }
+}
 
-        Palette::new(p_colors)
+/// A palette color, written `[r, g, b]`, `[r, g, b, a]`, or as a string such
+/// as `"#ff8000"` or `"#ff800080"`. Colors without an alpha are opaque.
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+enum ColorSource {
+    Rgb(u8, u8, u8),
+    Rgba(u8, u8, u8, u8),
+    Text(#[serde(with = "string")] ColorA),
+}
+
+impl ColorSource {
+    fn to_color(&self) -> ColorA {
+        match *self {
+            ColorSource::Rgb(r, g, b) => ColorA::opaque(Color::rgb(r, g, b)),
+            ColorSource::Rgba(r, g, b, alpha) => ColorA { color: Color::rgb(r, g, b), alpha },
+            ColorSource::Text(c) => c,
+        }
+    }
+
+    /// Opaque colors are written as triples, as they always have been.
+    fn from_color(c: ColorA) -> ColorSource {
+        let Color { red, green, blue } = c.color;
+        if c.is_opaque() {
+            ColorSource::Rgb(red, green, blue)
+        } else {
+            ColorSource::Rgba(red, green, blue, c.alpha)
+        }
     }
 }
 
-fn lerp(a: u8, b: u8, t: f32) -> u8 {
-    (a as f32 * (1. - t) + b as f32 * t) as u8
+/// A pass over a descriptor counting its functions, palette colors and mask
+/// vertices without keeping any of them, so that its limits are checked
+/// before anything is built from it.
+mod scan {
+    use std::cell::Cell;
+    use std::fmt;
+    use serde::de::{self, DeserializeSeed, Deserializer, IgnoredAny, MapAccess, SeqAccess, Visitor};
+
+    use super::{DescriptorError, Limit, ParseLimits};
+
+    /// Check the counts in `bytes` against `limits`. Malformed documents
+    /// pass, and are left for the parser to report.
+    pub fn check(bytes: &[u8], limits: ParseLimits) -> Result<(), DescriptorError> {
+        let exceeded = Cell::new(None);
+        let scan = Scan { part: Part::Document, limits, exceeded: &exceeded };
+        let _ = scan.deserialize(&mut serde_json::Deserializer::from_slice(bytes));
+        match exceeded.get() {
+            Some((limit, max)) => Err(DescriptorError::LimitExceeded { limit, max }),
+            None => Ok(()),
+        }
+    }
+
+    #[derive(Clone, Copy)]
+    enum Part {
+        Document,
+        Functions,
+        /// A list of colors, or an object which may hold one as `keys`.
+        Palette,
+        Mask,
+        Polygon,
+    }
+
+    #[derive(Clone, Copy)]
+    struct Scan<'a> {
+        part: Part,
+        limits: ParseLimits,
+        /// The limit exceeded, which is set before the scan is stopped.
+        exceeded: &'a Cell<Option<(Limit, u64)>>,
+    }
+
+    impl<'de> DeserializeSeed<'de> for Scan<'_> {
+        type Value = ();
+
+        fn deserialize<D: Deserializer<'de>>(self, d: D) -> Result<(), D::Error> {
+            d.deserialize_any(self)
+        }
+    }
+
+    impl<'de> Visitor<'de> for Scan<'_> {
+        type Value = ();
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "a descriptor")
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+            let limit = match self.part {
+                Part::Functions => Some((Limit::Functions, self.limits.max_functions)),
+                Part::Palette => Some((Limit::PaletteStops, self.limits.max_palette_stops)),
+                Part::Polygon => Some((Limit::MaskVertices, self.limits.max_mask_vertices)),
+                Part::Document | Part::Mask => None,
+            };
+            let mut count = 0;
+            while seq.next_element::<IgnoredAny>()?.is_some() {
+                count += 1;
+                if let Some((limit, max)) = limit.filter(|&(_, max)| count > max) {
+                    self.exceeded.set(Some((limit, max as u64)));
+                    return Err(de::Error::custom(format!("more than {} {}", max, limit)));
+                }
+            }
+            Ok(())
+        }
+
+        fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+            while let Some(key) = map.next_key::<String>()? {
+                let part = match (self.part, key.as_str()) {
+                    (Part::Document, "functions") => Some(Part::Functions),
+                    (Part::Document, "palette") => Some(Part::Palette),
+                    (Part::Document, "mask") => Some(Part::Mask),
+                    (Part::Palette, "keys") => Some(Part::Palette),
+                    (Part::Mask, "polygon") => Some(Part::Polygon),
+                    _ => None,
+                };
+                match part {
+                    Some(part) => map.next_value_seed(Scan { part, ..self })?,
+                    None => map.next_value::<IgnoredAny>().map(|_| ())?,
+                }
+            }
+            Ok(())
+        }
+
+        // Values of any other type hold nothing to count.
+        fn visit_bool<E: de::Error>(self, _: bool) -> Result<(), E> {
+            Ok(())
+        }
+
+        fn visit_i64<E: de::Error>(self, _: i64) -> Result<(), E> {
+            Ok(())
+        }
+
+        fn visit_u64<E: de::Error>(self, _: u64) -> Result<(), E> {
+            Ok(())
+        }
+
+        fn visit_f64<E: de::Error>(self, _: f64) -> Result<(), E> {
+            Ok(())
+        }
+
+        fn visit_str<E: de::Error>(self, _: &str) -> Result<(), E> {
+            Ok(())
+        }
+
+        fn visit_unit<E: de::Error>(self) -> Result<(), E> {
+            Ok(())
+        }
+    }
 }
 
-#[derive(Deserialize)]
-struct ColorSource(u8, u8, u8);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::meta::{GenerationMethod, MAX_THUMBNAIL_BYTES};
+    use crate::presets;
+    use base64::engine::general_purpose::STANDARD as BASE64;
+    use base64::Engine;
 
-impl ColorSource {
-    fn to_color(&self) -> Color {
-        Color::rgb(self.0, self.1, self.2)
+    fn source() -> FlameSource {
+        FlameSource::from_flame(&presets::swirl())
+    }
+
+    /// The descriptor written out and read back in.
+    fn reread(source: &FlameSource) -> FlameSource {
+        let mut bytes = Vec::new();
+        source.to_writer(&mut bytes).unwrap();
+        FlameSource::from_value(serde_json::from_slice(&bytes).unwrap(), ".").unwrap()
+    }
+
+    fn thumbnail() -> DynamicImage {
+        DynamicImage::ImageRgb8(image::RgbImage::from_pixel(4, 4, image::Rgb([9, 8, 7])))
+    }
+
+    #[test]
+    fn metadata_and_writing_leave_the_hash_alone() {
+        let hash = source().content_hash().unwrap();
+        let mut meta = Meta::new(GenerationMethod::Breed);
+        meta.command = "flame breed a.json b.json out".to_string();
+        let described = source().with_meta(meta);
+        assert_eq!(described.content_hash().unwrap(), hash);
+        assert_eq!(reread(&described).content_hash().unwrap(), hash);
+        // Render settings left out are the same as settings with nothing set.
+        assert_eq!(source().with_render_settings(RenderSettings::default()).content_hash().unwrap(), hash);
+    }
+
+    #[test]
+    fn the_flame_and_its_render_settings_change_the_hash() {
+        let hash = source().content_hash().unwrap();
+        let mut flame = presets::swirl();
+        flame.functions[0].weight = 0.45;
+        assert_ne!(FlameSource::from_flame(&flame).content_hash().unwrap(), hash);
+
+        let settings = [
+            RenderSettings { gamma: Some(2.2), ..RenderSettings::default() },
+            RenderSettings { gamma: Some(2.3), ..RenderSettings::default() },
+            RenderSettings { vibrancy: Some(2.2), ..RenderSettings::default() },
+            RenderSettings { dims: Some([64, 32]), ..RenderSettings::default() },
+            RenderSettings { dims: Some([32, 64]), ..RenderSettings::default() },
+            RenderSettings { grayscale: Some(false), ..RenderSettings::default() },
+            RenderSettings { background: Some(Color::rgb(1, 2, 3)), ..RenderSettings::default() },
+            RenderSettings { highlights: Some(HighlightMode::DesaturateToWhite { knee: 0.5 }), ..RenderSettings::default() },
+        ];
+        let mut seen = std::collections::HashSet::from([hash]);
+        for s in settings {
+            let hash = source().with_render_settings(s.clone()).content_hash().unwrap();
+            assert!(seen.insert(hash), "{:?} did not change the hash", s);
+        }
+    }
+
+    #[test]
+    fn negative_zero_settings_are_zero() {
+        let zero = RenderSettings { vibrancy: Some(0.0), ..RenderSettings::default() };
+        let negative = RenderSettings { vibrancy: Some(-0.0), ..RenderSettings::default() };
+        assert_eq!(zero.canonical_encoding(), negative.canonical_encoding());
+    }
+
+    #[test]
+    fn thumbnails_go_stale_when_what_they_show_changes() {
+        let mut source = source();
+        assert!(source.thumbnail_is_stale());
+        source.set_thumbnail(&thumbnail()).unwrap();
+        assert!(!source.thumbnail_is_stale());
+        // Metadata changes do not make it stale, and it survives a round trip.
+        source.record_history("renamed");
+        let mut source = reread(&source);
+        assert!(!source.thumbnail_is_stale());
+
+        source.set_render_settings(Some(RenderSettings { gamma: Some(4.0), ..RenderSettings::default() }));
+        assert!(source.thumbnail_is_stale());
+
+        let mut other = FlameSource::from_flame(&presets::gasket());
+        other.meta = source.meta.clone();
+        assert!(other.thumbnail_is_stale());
+
+        // A thumbnail whose hash was edited no longer matches either.
+        let mut source = self::source();
+        source.set_thumbnail(&thumbnail()).unwrap();
+        source.meta.as_mut().unwrap()["thumbnail"]["hash"] = "0000000000000000".into();
+        assert!(source.thumbnail_is_stale());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn thumbnails_round_trip_through_descriptors() {
+        let image = DynamicImage::ImageRgb8(image::RgbImage::from_fn(200, 100, |x, y| image::Rgb([x as u8, y as u8, 40])));
+        let mut source = source();
+        assert!(source.thumbnail().is_none());
+        source.set_thumbnail(&image).unwrap();
+        let read = reread(&source).thumbnail().unwrap().into_rgb8();
+        assert_eq!(read.dimensions(), (128, 64));
+        assert_eq!(read, image.thumbnail(128, 128).into_rgb8());
+
+        // A thumbnail too large to decode is ignored, and the descriptor
+        // still renders as it did.
+        let mut doc = serde_json::to_value(source).unwrap();
+        doc["meta"]["thumbnail"]["png"] = BASE64.encode(vec![0; MAX_THUMBNAIL_BYTES + 1]).into();
+        let oversized = FlameSource::from_value(doc, ".").unwrap();
+        assert!(oversized.thumbnail().is_none());
+        assert!(oversized.to_flame().unwrap().eq_structural(&self::source().to_flame().unwrap()));
+    }
+
+    #[test]
+    fn palettes_are_extracted_from_images_beside_the_descriptor() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("photos")).unwrap();
+        let img = image::RgbImage::from_fn(40, 10, |x, _| image::Rgb(if x < 25 { [20, 30, 90] } else { [250, 240, 210] }));
+        img.save(dir.path().join("photos/board.png")).unwrap();
+        let mut doc = serde_json::to_value(source()).unwrap();
+        doc["palette"] = serde_json::json!({"from_image": "board.png", "colors": 2});
+        std::fs::write(dir.path().join("photos/flame.json"), doc.to_string()).unwrap();
+
+        let flame = FlameSource::from_path(dir.path().join("photos/flame.json")).unwrap().to_flame().unwrap();
+        let expected = Palette::from_image_kmeans(&DynamicImage::ImageRgb8(img), 2, DESCRIPTOR_PALETTE_SEED).unwrap();
+        assert!(flame.palette == expected);
+        assert_eq!(flame.palette.keys()[0], Color::rgb(20, 30, 90));
+
+        // Relative to the descriptor, not the working directory.
+        assert!(FlameSource::from_value(doc, dir.path()).unwrap().to_flame().is_err());
+    }
+
+    #[test]
+    fn palette_adjustments_are_applied_at_load() {
+        let mut doc = serde_json::to_value(source()).unwrap();
+        let keys = doc["palette"].clone();
+        doc["palette"] = serde_json::json!({"keys": keys, "adjust": ["saturation=0", "brightness=0.5"]});
+        let adjusted = FlameSource::from_value(doc, ".").unwrap();
+        let plain = source().to_flame().unwrap().palette;
+        let ops = [PaletteOp::Saturation(0.0), PaletteOp::Brightness(0.5)];
+        assert!(adjusted.palette().unwrap() == plain.adjusted(&ops));
+        assert!(adjusted.to_flame().unwrap().palette == plain.adjusted(&ops));
+
+        // Adjustments are checked as they are read.
+        let mut doc = serde_json::to_value(source()).unwrap();
+        doc["palette"] = serde_json::json!({"keys": keys, "adjust": ["saturation=-1"]});
+        assert!(FlameSource::from_value(doc, ".").is_err());
+    }
+
+    /// The swirl's descriptor with its bounds written as `bounds`.
+    fn with_bounds(bounds: serde_json::Value) -> serde_json::Value {
+        let mut doc = serde_json::to_value(source()).unwrap();
+        doc["bounds"] = bounds;
+        doc
+    }
+
+    /// The limit `result` failed on, if it failed on one.
+    fn exceeded(result: Result<FlameSource, DescriptorError>) -> Option<(Limit, u64)> {
+        match result {
+            Err(DescriptorError::LimitExceeded { limit, max }) => Some((limit, max)),
+            _ => None,
+        }
+    }
+
+    fn read_within(doc: &serde_json::Value, limits: ParseLimits) -> Result<FlameSource, DescriptorError> {
+        FlameSource::from_reader_with_limits(doc.to_string().as_bytes(), ".", limits)
+    }
+
+    #[test]
+    fn documents_are_limited_in_size() {
+        let doc = serde_json::to_value(source()).unwrap();
+        let len = doc.to_string().len() as u64;
+        let limits = |max_document_bytes| ParseLimits { max_document_bytes, ..ParseLimits::default() };
+        assert!(read_within(&doc, limits(len)).is_ok());
+        assert_eq!(exceeded(read_within(&doc, limits(len - 1))), Some((Limit::DocumentBytes, len - 1)));
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("flame.json");
+        std::fs::write(&path, doc.to_string()).unwrap();
+        assert!(FlameSource::from_path_with_limits(&path, limits(len)).is_ok());
+        assert_eq!(exceeded(FlameSource::from_path_with_limits(&path, limits(len - 1))), Some((Limit::DocumentBytes, len - 1)));
+    }
+
+    #[test]
+    fn functions_colors_and_vertices_are_counted() {
+        let mut doc = serde_json::to_value(source()).unwrap();
+        doc["mask"] = serde_json::json!({"polygon": [[0, 0], [1, 0], [1, 1], [0, 1], [0.5, 0.5]]});
+        let (functions, stops) = (source().functions.len(), presets::swirl().palette.keys().len());
+        let exact = ParseLimits { max_functions: functions, max_palette_stops: stops, max_mask_vertices: 5, ..ParseLimits::default() };
+        assert!(read_within(&doc, exact).is_ok());
+
+        let fewer = [
+            (ParseLimits { max_functions: functions - 1, ..exact }, Limit::Functions, functions - 1),
+            (ParseLimits { max_palette_stops: stops - 1, ..exact }, Limit::PaletteStops, stops - 1),
+            (ParseLimits { max_mask_vertices: 4, ..exact }, Limit::MaskVertices, 4),
+        ];
+        for (limits, limit, max) in fewer {
+            assert_eq!(exceeded(read_within(&doc, limits)), Some((limit, max as u64)));
+        }
+
+        // Colors are counted where adjustments are made to them too.
+        let keys = doc["palette"].clone();
+        doc["palette"] = serde_json::json!({"keys": keys, "adjust": ["saturation=0"]});
+        assert!(read_within(&doc, exact).is_ok());
+        let limits = ParseLimits { max_palette_stops: stops - 1, ..exact };
+        assert_eq!(exceeded(read_within(&doc, limits)), Some((Limit::PaletteStops, stops as u64 - 1)));
+    }
+
+    #[test]
+    fn huge_lists_are_refused_before_they_are_parsed() {
+        // Elements which are not functions at all, so that only the scan
+        // can have refused them.
+        let mut doc = serde_json::to_value(source()).unwrap();
+        doc["functions"] = serde_json::Value::Array(vec![serde_json::json!({}); 1_000_000]);
+        let started = std::time::Instant::now();
+        assert_eq!(exceeded(read_within(&doc, ParseLimits::default())), Some((Limit::Functions, 1024)));
+        assert!(started.elapsed() < std::time::Duration::from_secs(5));
+        assert!(matches!(read_within(&doc, ParseLimits::UNLIMITED), Err(DescriptorError::Json(_))));
+    }
+
+    #[test]
+    fn referenced_files_share_one_budget() {
+        let dir = tempfile::tempdir().unwrap();
+        let board = image::RgbImage::from_fn(8, 8, |x, _| image::Rgb(if x < 4 { [20, 30, 90] } else { [250, 240, 210] }));
+        board.save(dir.path().join("board.png")).unwrap();
+        image::GrayImage::from_pixel(8, 8, image::Luma([255])).save(dir.path().join("stencil.png")).unwrap();
+        let size = |name| std::fs::metadata(dir.path().join(name)).unwrap().len();
+        let total = size("board.png") + size("stencil.png");
+
+        let mut doc = serde_json::to_value(source()).unwrap();
+        doc["palette"] = serde_json::json!({"from_image": "board.png", "colors": 2});
+        doc["mask"] = serde_json::json!({"image": "stencil.png"});
+        let build = |max_referenced_bytes| {
+            let limits = ParseLimits { max_referenced_bytes, ..ParseLimits::default() };
+            FlameSource::from_reader_with_limits(doc.to_string().as_bytes(), dir.path(), limits)?.to_flame()
+        };
+        assert!(build(total).is_ok());
+        // Either file alone is well within a byte less.
+        let error = build(total - 1).err().unwrap();
+        assert!(matches!(error, DescriptorError::LimitExceeded { limit: Limit::ReferencedBytes, max } if max == total - 1));
+        let error = error.to_string();
+        assert_eq!(error, format!("descriptor has more than the {} bytes of referenced files allowed", total - 1));
+    }
+
+    #[test]
+    fn center_and_scale_bounds_match_their_edges() {
+        let edges = with_bounds(serde_json::json!([-1.0, 3.0, -1.5, 0.5]));
+        let centered = with_bounds(serde_json::json!({"center": [1.0, -0.5], "scale": 0.5, "aspect": 2.0}));
+        let edges = FlameSource::from_value(edges, ".").unwrap().to_flame().unwrap();
+        let centered = FlameSource::from_value(centered, ".").unwrap().to_flame().unwrap();
+        assert_eq!(centered.bounds, edges.bounds);
+        assert!(centered.functions == edges.functions);
+
+        // The aspect ratio defaults to a square window.
+        let square = with_bounds(serde_json::json!({"center": [0, 0], "scale": 2}));
+        let square = FlameSource::from_value(square, ".").unwrap().to_flame().unwrap();
+        assert_eq!(square.bounds, Bounds::new(-0.5, 0.5, -0.5, 0.5));
+    }
+
+    #[test]
+    fn bounds_are_written_back_in_the_form_they_were_read() {
+        for bounds in [
+            serde_json::json!([-1.0, 3.0, -1.5, 0.5]),
+            serde_json::json!({"center": [1.0, -0.5], "scale": 0.5, "aspect": 2.0}),
+            serde_json::json!({"center": [1.0, -0.5], "scale": 0.5}),
+        ] {
+            let source = FlameSource::from_value(with_bounds(bounds.clone()), ".").unwrap();
+            let written = serde_json::to_value(reread(&source)).unwrap();
+            assert_eq!(written["bounds"], bounds);
+            // Reading it back does not change the hash.
+            assert_eq!(reread(&source).content_hash().unwrap(), source.content_hash().unwrap());
+        }
+    }
+
+    /// The affine of the first function of the swirl written as `affine`.
+    fn with_affine(affine: serde_json::Value) -> Result<Flame, DescriptorError> {
+        let mut doc = serde_json::to_value(source()).unwrap();
+        doc["functions"][0][2] = affine;
+        FlameSource::from_value(doc, ".")?.to_flame()
+    }
+
+    #[test]
+    fn decomposed_affines_match_their_coefficients() {
+        let (s, c) = (0.5 * 0.6f32.sin(), 0.5 * 0.6f32.cos());
+        let coefficients = with_affine(serde_json::json!([c, -s, s, c, 0.0, 1.0])).unwrap();
+        for rotate in [serde_json::json!(0.6), serde_json::json!("0.6rad"), serde_json::json!("0.6 * 180 / pi deg")] {
+            let decomposed = with_affine(serde_json::json!({"rotate": rotate, "scale": [0.5, 0.5], "translate": [0, 1]})).unwrap();
+            let error = (decomposed.functions[0].trans.matrix() - coefficients.functions[0].trans.matrix()).abs().max();
+            assert!(error < 1e-6, "{} is off by {}", rotate, error);
+        }
+
+        let fifth = with_affine(serde_json::json!({"rotate": "360/5 deg"})).unwrap();
+        let d = AffineDecomposition::decompose(&fifth.functions[0].trans);
+        assert!((d.rotation - 72f32.to_radians()).abs() < 1e-6);
+        assert_eq!((d.scale, d.translation), (AffineDecomposition::identity().scale, nalgebra::Vector2::zeros()));
+
+        let identity = with_affine(serde_json::json!({})).unwrap();
+        assert_eq!(*identity.functions[0].trans.matrix(), Matrix3::identity());
+        assert!(with_affine(serde_json::json!({"rotate": "36 furlongs"})).is_err());
+    }
+
+    /// A metadata block with fields this version does not know about.
+    fn foreign_meta() -> serde_json::Value {
+        serde_json::json!({
+            "version": "0.9.0",
+            "method": "randgen",
+            "seed": 42,
+            "parameters": {"functions": 3, "variations": ["Swirl", "Bent"]},
+            "created": 1_700_000_000u64,
+            "command": "flame randgen --seed 42",
+            "history": ["created"],
+            "generator_host": {"name": "studio", "threads": 16},
+        })
+    }
+
+    #[test]
+    fn metadata_is_kept_verbatim() {
+        let mut doc = serde_json::to_value(source()).unwrap();
+        doc["meta"] = foreign_meta();
+        let read = FlameSource::from_value(doc, ".").unwrap();
+        let written = serde_json::to_value(reread(&read)).unwrap();
+        assert_eq!(written["meta"], foreign_meta());
+
+        let meta = read.meta().unwrap();
+        assert_eq!((meta.method, meta.seed), (GenerationMethod::Randgen, Some(42)));
+        assert_eq!(meta.extra["generator_host"]["threads"], 16);
+        // Converting to the typed form and back loses nothing either.
+        let retyped = source().with_meta(meta);
+        assert_eq!(serde_json::to_value(reread(&retyped)).unwrap()["meta"], foreign_meta());
+    }
+
+    #[test]
+    fn recording_history_appends_to_it() {
+        let mut doc = serde_json::to_value(source()).unwrap();
+        doc["meta"] = foreign_meta();
+        let mut described = FlameSource::from_value(doc, ".").unwrap();
+        described.record_history("palette adjusted");
+        described.record_history("bounds fitted");
+        let mut expected = foreign_meta();
+        expected["history"] = serde_json::json!(["created", "palette adjusted", "bounds fitted"]);
+        assert_eq!(serde_json::to_value(reread(&described)).unwrap()["meta"], expected);
+
+        let mut bare = source();
+        bare.record_history("made by hand");
+        assert_eq!(serde_json::to_value(&bare).unwrap()["meta"], serde_json::json!({"history": ["made by hand"]}));
+    }
+
+    #[test]
+    fn metadata_does_not_change_the_flame() {
+        let mut doc = serde_json::to_value(source()).unwrap();
+        doc["meta"] = foreign_meta();
+        let described = FlameSource::from_value(doc, ".").unwrap().to_flame().unwrap();
+        let bare = source().to_flame().unwrap();
+        assert!(described.eq_structural(&bare));
+
+        let mut changed = bare.clone();
+        changed.functions[0].weight += 0.1;
+        assert!(!changed.eq_structural(&bare));
+    }
+
+    /// A directory holding the sections of `flame` as separate fragments,
+    /// named for the section they hold, and the whole descriptor.
+    fn fragments(flame: &Flame) -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        let mut bytes = Vec::new();
+        FlameSource::from_flame(flame).to_writer(&mut bytes).unwrap();
+        std::fs::write(dir.path().join("whole.json"), &bytes).unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        for key in ["functions", "palette", "bounds"] {
+            std::fs::write(dir.path().join(format!("{}.json", key)), value[key].to_string()).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn fragments_alone_assemble_a_flame() {
+        let dir = fragments(&presets::swirl());
+        let path = |name: &str| dir.path().join(name);
+        let parts = FlameParts {
+            functions: Some(FlameParts::functions_from_path(path("functions.json")).unwrap()),
+            palette: Some(FlameParts::palette_from_path(path("palette.json")).unwrap()),
+            bounds: Some(FlameParts::bounds_from_path(path("bounds.json")).unwrap()),
+            ..FlameParts::new()
+        };
+        assert!(parts.assemble().unwrap().eq_structural(&presets::swirl()));
+
+        // A whole descriptor serves as a fragment of any of its sections.
+        assert!(FlameParts::functions_from_path(path("whole.json")).unwrap() == presets::swirl().functions);
+        assert!(FlameParts::palette_from_path(path("whole.json")).unwrap() == presets::swirl().palette);
+        assert_eq!(FlameParts::bounds_from_path(path("whole.json")).unwrap(), presets::swirl().bounds);
+    }
+
+    #[test]
+    fn fragments_override_their_section_of_a_base() {
+        let dir = fragments(&presets::swirl());
+        let fern = presets::fern();
+        let functions = FlameParts::functions_from_path(dir.path().join("functions.json")).unwrap();
+        let flame = FlameParts::from_flame(fern.clone())
+            .merge(FlameParts { functions: Some(functions), ..FlameParts::new() })
+            .assemble()
+            .unwrap();
+        assert!(flame.functions == presets::swirl().functions);
+        assert!(flame.palette == fern.palette);
+        assert_eq!(flame.bounds, fern.bounds);
+
+        // Parts left out of the override are kept, not cleared.
+        let flame = FlameParts::from_flame(fern.clone()).merge(FlameParts::new()).assemble().unwrap();
+        assert!(flame.eq_structural(&fern));
+    }
+
+    #[test]
+    fn missing_parts_are_named() {
+        let whole = || FlameParts::from_flame(presets::gasket());
+        let cases = [
+            ("functions", FlameParts { functions: None, ..whole() }),
+            ("palette", FlameParts { palette: None, ..whole() }),
+            ("bounds", FlameParts { bounds: None, ..whole() }),
+            ("functions", FlameParts::new()),
+        ];
+        for (part, parts) in cases {
+            let Err(e) = parts.assemble() else { panic!("assembled without {}", part) };
+            assert!(matches!(e, DescriptorError::MissingPart(p) if p == part), "{}", e);
+            assert_eq!(e.to_string(), format!("no {} given, either in a descriptor or on their own", part));
+        }
+    }
+
+    #[test]
+    fn malformed_fragments_are_reported() {
+        let dir = fragments(&presets::gasket());
+        let path = |name: &str| dir.path().join(name);
+        // Each fragment holds the wrong section.
+        assert!(matches!(FlameParts::functions_from_path(path("bounds.json")), Err(DescriptorError::Json(_))));
+        assert!(matches!(FlameParts::bounds_from_path(path("palette.json")), Err(DescriptorError::Json(_))));
+        assert!(matches!(FlameParts::palette_from_path(path("functions.json")), Err(DescriptorError::Json(_))));
+        assert!(matches!(FlameParts::bounds_from_path(path("missing.json")), Err(DescriptorError::Io(_))));
+
+        std::fs::write(path("broken.ggr"), "GIMP Gradient\n2\n").unwrap();
+        assert!(matches!(
+            FlameParts::palette_from_path(path("broken.ggr")),
+            Err(DescriptorError::Palette(PaletteError::InvalidGradient { .. }))
+        ));
+        std::fs::write(path("gradient.ggr"), presets::gasket().palette.to_ggr("gasket")).unwrap();
+        assert!(FlameParts::palette_from_path(path("gradient.ggr")).is_ok());
+    }
+
+    /// The error writing `flame` gives, checking nothing was written.
+    fn write_error(flame: &Flame) -> String {
+        let mut bytes = Vec::new();
+        let Err(e) = FlameSource::from_flame(flame).to_writer(&mut bytes) else { panic!("wrote a non-finite flame") };
+        assert!(bytes.is_empty());
+        assert!(matches!(e, DescriptorError::NonFinite { .. }));
+        e.to_string()
+    }
+
+    #[test]
+    fn non_finite_values_are_not_written() {
+        let mut nan_weight = presets::gasket();
+        nan_weight.functions[1].weight = f32::NAN;
+        assert_eq!(write_error(&nan_weight), "'functions[1][0]' is NaN, but descriptors can only hold finite numbers");
+
+        let mut inf_param = presets::gasket();
+        inf_param.functions[0].var = Variation::Blob(1.0, f32::INFINITY, 0.5);
+        assert_eq!(write_error(&inf_param), "'functions[0][1].Blob[1]' is inf, but descriptors can only hold finite numbers");
+
+        let mut inf_affine = presets::gasket();
+        inf_affine.functions[2].trans = Affine2::from_matrix_unchecked(Matrix3::new(
+            0.5, 0.0, f32::NEG_INFINITY,
+            0.0, 0.5, 0.0,
+            0.0, 0.0, 1.0,
+        ));
+        assert_eq!(write_error(&inf_affine), "'functions[2][2][4]' is -inf, but descriptors can only hold finite numbers");
+
+        let mut nan_bounds = presets::gasket();
+        nan_bounds.bounds = Bounds::new(0.0, f32::NAN, 0.0, 1.0);
+        assert!(write_error(&nan_bounds).starts_with("'bounds[1]' is NaN"));
+    }
+
+    #[test]
+    fn validation_names_the_non_finite_parameter() {
+        let mut flame = presets::gasket();
+        flame.functions[0].var = Variation::Blob(f32::NAN, 1.0, 1.0);
+        flame.functions[2].weight = f32::INFINITY;
+        let errors: Vec<_> = flame.validate().into_iter().filter(|f| f.severity == Severity::Error).map(|f| f.message).collect();
+        assert_eq!(errors, [
+            "function 0 has parameters which are not finite ('functions[0][1].Blob[0]' is NaN)",
+            "function 2 has parameters which are not finite ('functions[2][0]' is inf)",
+        ]);
+    }
+
+    #[test]
+    fn patches_to_non_finite_values_name_their_path() {
+        let mut value = serde_json::to_value(source()).unwrap();
+        let original = value.clone();
+        for (path, x, message) in [
+            ("functions[0][0]", f64::NAN, "'functions[0][0]' is NaN"),
+            ("functions[1][2][3]", f64::INFINITY, "'functions[1][2][3]' is inf"),
+            ("bounds[0]", f64::NEG_INFINITY, "'bounds[0]' is -inf"),
+        ] {
+            let path: crate::animation::ValuePath = path.parse().unwrap();
+            let e = path.set(&mut value, x).unwrap_err();
+            assert_eq!(e.to_string(), format!("{}, but descriptors can only hold finite numbers", message));
+        }
+        assert_eq!(value, original);
+    }
+
+    #[test]
+    fn negative_zeros_are_written_as_zeros() {
+        let mut flame = presets::gasket();
+        flame.functions[0].trans = Affine2::from_matrix_unchecked(Matrix3::new(
+            -0.0, 0.5, -0.0,
+            0.5, -0.0, 0.25,
+            0.0, 0.0, 1.0,
+        ));
+        let write = |source: &FlameSource| {
+            let mut bytes = Vec::new();
+            source.to_writer(&mut bytes).unwrap();
+            String::from_utf8(bytes).unwrap()
+        };
+        let written = write(&FlameSource::from_flame(&flame));
+        let value: serde_json::Value = serde_json::from_str(&written).unwrap();
+        let coefficients: Vec<f64> = value["functions"][0][2].as_array().unwrap().iter().map(|x| x.as_f64().unwrap()).collect();
+        assert_eq!(coefficients, [0.0, 0.5, 0.5, 0.0, 0.0, 0.25]);
+        assert!(coefficients.iter().all(|x| x.is_sign_positive()), "{:?}", coefficients);
+        // Writing what was read gives the same text.
+        let read = FlameSource::from_value(serde_json::from_str(&written).unwrap(), ".").unwrap();
+        assert_eq!(write(&read), written);
+        assert!(read.to_flame().unwrap().eq_structural(&flame));
+    }
+
+    #[test]
+    fn final_transforms_are_reported_as_ignored() {
+        for key in ["last", "final"] {
+            let mut doc = serde_json::to_value(source()).unwrap();
+            doc[key] = serde_json::json!("Id");
+            let read = FlameSource::from_value(doc, ".").unwrap();
+            let mut diagnostics = Diagnostics::new();
+            let flame = read.to_flame_with_report(&mut diagnostics).unwrap();
+            assert!(flame.eq_structural(&presets::swirl()));
+            let reported: Vec<_> = diagnostics.iter().collect();
+            assert_eq!(reported.len(), 1, "{:?}", reported);
+            assert_eq!((reported[0].severity, reported[0].path.as_str()), (Severity::Warning, key));
+            assert_eq!(reported[0].value_before.as_deref(), Some("\"Id\""));
+        }
+        // Neither is written back.
+        let mut doc = serde_json::to_value(source()).unwrap();
+        doc["last"] = serde_json::json!("Id");
+        doc["final"] = serde_json::json!("Swirl");
+        let read = FlameSource::from_value(doc, ".").unwrap();
+        let written = serde_json::to_value(reread(&read)).unwrap();
+        assert!(written.get("last").is_none() && written.get("final").is_none());
+        let mut diagnostics = Diagnostics::new();
+        read.to_flame_with_report(&mut diagnostics).unwrap();
+        assert_eq!(diagnostics.len(), 2);
+        // Descriptors without either say nothing.
+        let mut diagnostics = Diagnostics::new();
+        source().to_flame_with_report(&mut diagnostics).unwrap();
+        assert!(diagnostics.is_empty());
+    }
+
+    /// The diagnostics of reading the swirl with the first function's
+    /// fields from `index` on replaced by `fields`.
+    fn function_diagnostics(index: usize, fields: &[serde_json::Value]) -> Vec<Diagnostic> {
+        let mut doc = serde_json::to_value(source()).unwrap();
+        let function = doc["functions"][1].as_array_mut().unwrap();
+        function.truncate(index);
+        function.extend(fields.iter().cloned());
+        let mut diagnostics = Diagnostics::new();
+        FlameSource::from_value(doc, ".").unwrap().to_flame_with_report(&mut diagnostics).unwrap();
+        diagnostics.iter().cloned().collect()
+    }
+
+    #[test]
+    fn clamped_colors_are_reported_at_their_path() {
+        let reported = function_diagnostics(3, &[serde_json::json!(1.5)]);
+        assert_eq!(reported, [Diagnostic {
+            severity: Severity::Warning,
+            path: "functions[1][3]".to_string(),
+            message: "color is outside [0, 1] and was clamped".to_string(),
+            value_before: Some("1.5".to_string()),
+            value_after: Some("1".to_string()),
+        }]);
+        assert!(function_diagnostics(3, &[serde_json::json!(0.75)]).is_empty());
+    }
+
+    #[test]
+    fn clamped_axis_blends_are_reported_at_their_path() {
+        let reported = function_diagnostics(4, &[serde_json::json!([0.5, -2.0])]);
+        assert_eq!(reported, [Diagnostic {
+            severity: Severity::Warning,
+            path: "functions[1][4][1]".to_string(),
+            message: "axis blend is outside [0, 1] and was clamped".to_string(),
+            value_before: Some("-2".to_string()),
+            value_after: Some("0".to_string()),
+        }]);
+        let both = function_diagnostics(4, &[serde_json::json!([3.0, 2.0])]);
+        let paths: Vec<_> = both.iter().map(|d| d.path.as_str()).collect();
+        assert_eq!(paths, ["functions[1][4][0]", "functions[1][4][1]"]);
+        assert!(function_diagnostics(4, &[serde_json::json!([0.0, 1.0])]).is_empty());
+    }
+
+    #[test]
+    fn weights_not_summing_to_one_are_reported_once() {
+        let mut doc = serde_json::to_value(source()).unwrap();
+        for function in doc["functions"].as_array_mut().unwrap() {
+            function[0] = serde_json::json!(1.0);
+        }
+        let count = doc["functions"].as_array().unwrap().len();
+        let mut diagnostics = Diagnostics::new();
+        FlameSource::from_value(doc, ".").unwrap().to_flame_with_report(&mut diagnostics).unwrap();
+        let reported: Vec<_> = diagnostics.iter().collect();
+        assert_eq!(reported.len(), 1, "{:?}", reported);
+        assert_eq!((reported[0].severity, reported[0].path.as_str()), (Severity::Warning, "functions"));
+        assert_eq!(reported[0].value_before, Some(count.to_string()));
+        assert_eq!(reported[0].value_after, None);
+    }
+
+    #[test]
+    fn render_settings_survive_writing_and_reading() {
+        let settings = RenderSettings {
+            iters: Some(3_000_000),
+            dims: Some([640, 360]),
+            gamma: Some(1.8),
+            vibrancy: Some(0.4),
+            preserve_color: Some(true),
+            grayscale: Some(false),
+            background: Some(Color::rgb(16, 32, 48)),
+            highlights: Some(HighlightMode::DesaturateToWhite { knee: 0.7 }),
+            dither: Some(DitherMode::BlueNoise),
+        };
+        let described = source().with_render_settings(settings.clone()).with_meta(Meta::new(GenerationMethod::Manual));
+        let read = reread(&described);
+        assert_eq!(read.render_settings(), Some(&settings));
+        // Written as on the command line.
+        let written = serde_json::to_value(&read).unwrap();
+        assert_eq!(written["render"]["background"], "#102030");
+        assert_eq!(written["render"]["dims"], serde_json::json!([640, 360]));
+        // Changing the palette or the metadata keeps the block.
+        let mut edited = read;
+        edited.set_palette(&presets::fern().palette);
+        edited.record_history("recolored");
+        assert_eq!(reread(&edited).render_settings(), Some(&settings));
+
+        // Settings left out are not written, and a block is not invented.
+        let partial = RenderSettings { gamma: Some(2.0), ..RenderSettings::default() };
+        let written = serde_json::to_value(source().with_render_settings(partial.clone())).unwrap();
+        assert_eq!(written["render"], serde_json::json!({"gamma": 2.0}));
+        assert_eq!(reread(&source().with_render_settings(partial.clone())).render_settings(), Some(&partial));
+        assert!(serde_json::to_value(source()).unwrap().get("render").is_none());
+        assert_eq!(reread(&source()).render_settings(), None);
+    }
+}
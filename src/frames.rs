@@ -0,0 +1,260 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, SendError, Sender, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use image::codecs::png::{CompressionType, FilterType, PngEncoder};
+use image::{ColorType, ImageEncoder, ImageError};
+
+use super::core::Buffer;
+use super::naming::{CollisionPolicy, UniquePathAllocator};
+
+/// A single frame waiting to be encoded.
+type Frame = (usize, Buffer<u8>);
+
+#[derive(Clone, Copy)]
+pub struct FrameConfig {
+    /// PNG compression level used for every frame.
+    pub compression: CompressionType,
+    /// Number of background encoder threads.
+    pub workers: usize,
+    /// Maximum number of frames queued before `write` blocks.
+    pub queue: usize,
+    /// Write single channel (alpha only) images instead of RGB.
+    pub grayscale: bool,
+    /// Number of digits the frame index is zero-padded to in file names.
+    pub digits: usize,
+    /// What to do when a frame's file already exists. Frames overwrite by
+    /// default, so that re-running a sequence replaces its frames.
+    pub on_collision: CollisionPolicy,
+}
+
+impl Default for FrameConfig {
+    fn default() -> Self {
+        FrameConfig {
+            compression: CompressionType::Fast,
+            workers: 2,
+            queue: 4,
+            grayscale: false,
+            digits: 5,
+            on_collision: CollisionPolicy::Overwrite,
+        }
+    }
+}
+
+/// An error encountered while writing one frame of a sequence.
+#[derive(Debug)]
+pub struct FrameError {
+    pub index: usize,
+    pub path: PathBuf,
+    pub error: ImageError,
+}
+
+impl std::fmt::Display for FrameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "failed to write frame {} to '{}': {}", self.index, self.path.display(), self.error)
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+/// Writes a sequence of frames as numbered PNG files on background threads.
+///
+/// Frames are named `<prefix><index>.png` inside the output directory, so
+/// file names follow the frame index no matter what order the encoders
+/// finish in. Each frame's file is claimed with a `UniquePathAllocator`,
+/// under the configured collision policy, which replaces the frames of an
+/// earlier run unless told to keep them. A failure to write one frame is reported through `errors` or
+/// `finish` and does not stop the rest of the sequence.
+pub struct FrameWriter {
+    sender: Option<SyncSender<Frame>>,
+    workers: Vec<JoinHandle<()>>,
+    errors: Receiver<FrameError>,
+}
+
+impl FrameWriter {
+    pub fn new(dir: impl AsRef<Path>, prefix: &str, cfg: FrameConfig) -> FrameWriter {
+        let (sender, receiver) = mpsc::sync_channel::<Frame>(cfg.queue);
+        let (err_sender, errors) = mpsc::channel();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        let workers = (0 .. cfg.workers.max(1)).map(|_| {
+            let receiver = Arc::clone(&receiver);
+            let err_sender = err_sender.clone();
+            let dir = dir.as_ref().to_path_buf();
+            let prefix = prefix.to_string();
+            thread::spawn(move || encode_frames(receiver, err_sender, dir, prefix, cfg))
+        }).collect();
+
+        FrameWriter { sender: Some(sender), workers, errors }
+    }
+
+    /// Queue a frame for writing, blocking if the queue is full.
+    pub fn write(&self, index: usize, frame: Buffer<u8>) -> Result<(), SendError<Frame>> {
+        self.sender.as_ref().unwrap().send((index, frame))
+    }
+
+    /// Errors reported by frames which have finished writing since the last call.
+    pub fn errors(&self) -> Vec<FrameError> {
+        self.errors.try_iter().collect()
+    }
+
+    /// Wait for all queued frames to be written and return any remaining errors.
+    pub fn finish(mut self) -> Vec<FrameError> {
+        drop(self.sender.take());
+        for worker in self.workers.drain(..) {
+            worker.join().unwrap();
+        }
+        self.errors.try_iter().collect()
+    }
+}
+
+impl Drop for FrameWriter {
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn encode_frames(
+    receiver: Arc<Mutex<Receiver<Frame>>>,
+    errors: Sender<FrameError>,
+    dir: PathBuf,
+    prefix: String,
+    cfg: FrameConfig,
+) {
+    let allocator = UniquePathAllocator::new(&dir, cfg.on_collision);
+    // The raw pixel data is kept between frames so that each frame only
+    // allocates when the dimensions grow.
+    let mut raw = Vec::new();
+
+    loop {
+        let next = receiver.lock().unwrap().recv();
+        let Ok((index, frame)) = next else { break };
+
+        let stem = format!("{}{:0width$}", prefix, index, width = cfg.digits);
+        let written = allocator.allocate(&stem, "png")
+            .map_err(|e| (dir.join(format!("{}.png", stem)), ImageError::IoError(e)))
+            .and_then(|(file, path)| encode_frame(file, &frame, &mut raw, cfg).map_err(|e| (path, e)));
+        if let Err((path, error)) = written {
+            let _ = errors.send(FrameError { index, path, error });
+        }
+    }
+}
+
+fn encode_frame(file: File, frame: &Buffer<u8>, raw: &mut Vec<u8>, cfg: FrameConfig) -> Result<(), ImageError> {
+    let color_type = if cfg.grayscale {
+        frame.write_gray8(raw);
+        ColorType::L8
+    } else {
+        frame.write_rgb8(raw);
+        ColorType::Rgb8
+    };
+
+    let file = BufWriter::new(file);
+    let encoder = PngEncoder::new_with_quality(file, cfg.compression, FilterType::Adaptive);
+    encoder.write_image(raw, frame.width() as u32, frame.height() as u32, color_type)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::Bucket;
+
+    /// A frame of one gray level, so that each frame's file can be told apart.
+    fn frame(level: u8) -> Buffer<u8> {
+        let bucket = Bucket { alpha: level, red: level, green: level, blue: level };
+        Buffer::from_buckets(3, 2, vec![bucket; 6]).unwrap()
+    }
+
+    #[test]
+    fn frames_written_out_of_order_land_in_their_own_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let cfg = FrameConfig { workers: 3, queue: 1, digits: 3, ..FrameConfig::default() };
+        let writer = FrameWriter::new(dir.path(), "f", cfg);
+        for index in [4, 0, 3, 1, 2] {
+            writer.write(index, frame(index as u8 * 50)).unwrap();
+        }
+        assert!(writer.finish().is_empty());
+
+        for index in 0 .. 5 {
+            let image = image::open(dir.path().join(format!("f{:03}.png", index))).unwrap().into_rgb8();
+            assert_eq!(image.dimensions(), (3, 2));
+            assert!(image.pixels().all(|p| p.0 == [index as u8 * 50; 3]));
+        }
+    }
+
+    #[test]
+    fn a_failed_frame_is_reported_while_the_others_are_written() {
+        let dir = tempfile::tempdir().unwrap();
+        // A directory in the way of frame 1's file.
+        std::fs::create_dir(dir.path().join("f00001.png")).unwrap();
+        let cfg = FrameConfig { on_collision: CollisionPolicy::Error, ..FrameConfig::default() };
+        let writer = FrameWriter::new(dir.path(), "f", cfg);
+        for index in 0 .. 3 {
+            writer.write(index, frame(100)).unwrap();
+        }
+        let errors = writer.finish();
+
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].index, 1);
+        assert_eq!(errors[0].path, dir.path().join("f00001.png"));
+        for index in [0, 2] {
+            assert!(image::open(dir.path().join(format!("f{:05}.png", index))).is_ok());
+        }
+    }
+
+    #[test]
+    fn a_second_run_replaces_the_frames_of_the_first() {
+        let dir = tempfile::tempdir().unwrap();
+        for level in [50, 150] {
+            let writer = FrameWriter::new(dir.path(), "f", FrameConfig::default());
+            for index in 0 .. 3 {
+                writer.write(index, frame(level)).unwrap();
+            }
+            assert!(writer.finish().is_empty());
+        }
+
+        let mut names: Vec<_> = std::fs::read_dir(dir.path()).unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["f00000.png", "f00001.png", "f00002.png"]);
+        for name in names {
+            let image = image::open(dir.path().join(name)).unwrap().into_rgb8();
+            assert!(image.pixels().all(|p| p.0 == [150; 3]));
+        }
+    }
+
+    #[test]
+    fn existing_files_are_kept_under_the_suffix_policy() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("f00000.png"), b"keep").unwrap();
+        let cfg = FrameConfig { on_collision: CollisionPolicy::Suffix, ..FrameConfig::default() };
+        let writer = FrameWriter::new(dir.path(), "f", cfg);
+        writer.write(0, frame(100)).unwrap();
+        assert!(writer.finish().is_empty());
+
+        assert_eq!(std::fs::read(dir.path().join("f00000.png")).unwrap(), b"keep");
+        let image = image::open(dir.path().join("f00000-2.png")).unwrap().into_rgb8();
+        assert!(image.pixels().all(|p| p.0 == [100; 3]));
+    }
+
+    #[test]
+    fn grayscale_frames_keep_the_alpha_channel() {
+        let dir = tempfile::tempdir().unwrap();
+        let cfg = FrameConfig { grayscale: true, ..FrameConfig::default() };
+        let writer = FrameWriter::new(dir.path(), "g", cfg);
+        let bucket = Bucket { alpha: 7, red: 200, green: 200, blue: 200 };
+        writer.write(0, Buffer::from_buckets(1, 1, vec![bucket]).unwrap()).unwrap();
+        assert!(writer.finish().is_empty());
+
+        let image = image::open(dir.path().join("g00000.png")).unwrap();
+        assert_eq!(image.color(), image::ColorType::L8);
+        assert_eq!(image.into_luma8().get_pixel(0, 0).0, [7]);
+    }
+}
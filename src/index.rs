@@ -0,0 +1,407 @@
+//! A cache of the summaries of the descriptors in a directory, kept beside
+//! them in `INDEX_FILE` so that searching the directory again only reads
+//! the files which have changed.
+//!
+//! The index holds the size and modification time of each file as it was
+//! read, with the content hash of its flame and its metadata, and the
+//! summary of the flame with each hash. A file whose size or time differs
+//! is read again, but only flames with new hashes are summarized, so
+//! touching, copying or editing the metadata of a descriptor costs little.
+//! Files which may draw on others, such as the instances of templates,
+//! are read every time, since the files they draw on may have changed.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use super::core::*;
+use super::file::FlameSource;
+use super::naming::write_atomically;
+use super::query::Record;
+
+/// Name of a directory's index, which is hidden and never itself searched.
+pub const INDEX_FILE: &str = ".flame-index.json";
+
+/// Version of the index, to be changed whenever summaries are, so that
+/// indexes written before are rebuilt.
+pub const INDEX_VERSION: u32 = 1;
+
+/// The summaries of a directory's descriptors, as last read.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SummaryIndex {
+    version: u32,
+    /// `CONTENT_HASH_VERSION` of the hashes in the index.
+    hash_version: u8,
+    /// What was read from each file, by name.
+    files: BTreeMap<String, FileEntry>,
+    /// Summaries by the content hash of the flame summarized.
+    summaries: BTreeMap<String, FlameSummary>,
+    #[serde(skip)]
+    changed: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct FileEntry {
+    len: u64,
+    /// Time the file was last modified, as seconds and nanoseconds since
+    /// the Unix epoch, if the file system keeps it.
+    modified: Option<(u64, u32)>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    refers_to_files: bool,
+    /// The hash of the file's flame, or why it could not be read as one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    hash: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(default, skip_serializing_if = "Map::is_empty")]
+    meta: Map<String, Value>,
+}
+
+/// The descriptors of a directory, as found by `SummaryIndex::refresh`.
+#[derive(Debug, Clone, Default)]
+pub struct Listing {
+    /// A record of each descriptor, in order of path.
+    pub records: Vec<Record>,
+    /// Files which could not be read as descriptors, and why.
+    pub skipped: Vec<(PathBuf, String)>,
+    /// Number of files read, and of flames summarized, rather than taken
+    /// from the index.
+    pub read: usize,
+    pub summarized: usize,
+}
+
+impl Default for SummaryIndex {
+    fn default() -> Self {
+        SummaryIndex {
+            version: INDEX_VERSION,
+            hash_version: CONTENT_HASH_VERSION,
+            files: BTreeMap::new(),
+            summaries: BTreeMap::new(),
+            changed: false,
+        }
+    }
+}
+
+impl SummaryIndex {
+    /// An empty index, with which every file is read.
+    pub fn new() -> Self {
+        SummaryIndex::default()
+    }
+
+    /// Read the index of `dir`. One which is missing, cannot be read or was
+    /// written by another version gives an empty index, to be rebuilt.
+    pub fn load(dir: &Path) -> Self {
+        std::fs::read(dir.join(INDEX_FILE)).ok()
+            .and_then(|bytes| serde_json::from_slice::<SummaryIndex>(&bytes).ok())
+            .filter(|index| index.version == INDEX_VERSION && index.hash_version == CONTENT_HASH_VERSION)
+            .unwrap_or_default()
+    }
+
+    /// Write the index to `dir`, replacing any there.
+    pub fn save(&self, dir: &Path) -> io::Result<()> {
+        let bytes = serde_json::to_vec(self).map_err(io::Error::other)?;
+        write_atomically(&dir.join(INDEX_FILE), &bytes)
+    }
+
+    /// Whether `refresh` has changed the index since it was loaded.
+    pub fn is_changed(&self) -> bool {
+        self.changed
+    }
+
+    /// Bring the index up to date with the JSON files in `dir`, reading
+    /// those which have changed, and return a record of each descriptor.
+    /// Files which are gone are dropped from the index, with summaries no
+    /// file has any more.
+    pub fn refresh(&mut self, dir: &Path) -> io::Result<Listing> {
+        let mut names = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            let Some(name) = path.file_name().map(|n| n.to_string_lossy().into_owned()) else { continue };
+            if !name.starts_with('.') && path.extension().is_some_and(|e| e.eq_ignore_ascii_case("json")) {
+                names.push(name);
+            }
+        }
+        names.sort();
+
+        let mut listing = Listing::default();
+        let mut files = BTreeMap::new();
+        let mut pending: BTreeMap<String, Flame> = BTreeMap::new();
+        for name in names {
+            let path = dir.join(&name);
+            let stat = match std::fs::metadata(&path) {
+                Ok(stat) => stat,
+                Err(e) => {
+                    listing.skipped.push((path, e.to_string()));
+                    continue;
+                }
+            };
+            let len = stat.len();
+            let modified = stat.modified().ok()
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map(|d| (d.as_secs(), d.subsec_nanos()));
+            let fresh = self.files.remove(&name).filter(|entry| {
+                entry.len == len
+                    && entry.modified.is_some()
+                    && entry.modified == modified
+                    && !entry.refers_to_files
+                    && entry.hash.as_ref().is_none_or(|hash| self.summaries.contains_key(hash))
+            });
+            let entry = match fresh {
+                Some(entry) => entry,
+                None => {
+                    listing.read += 1;
+                    self.changed = true;
+                    let (entry, flame) = read_entry(&path, len, modified);
+                    if let (Some(hash), Some(flame)) = (&entry.hash, flame) {
+                        if !self.summaries.contains_key(hash) {
+                            pending.insert(hash.clone(), flame);
+                        }
+                    }
+                    entry
+                }
+            };
+            files.insert(name, entry);
+        }
+
+        listing.summarized = pending.len();
+        self.summaries.extend(summarize(pending));
+        // Files not seen again are gone.
+        self.changed |= !self.files.is_empty();
+        self.files = files;
+        let hashes: std::collections::BTreeSet<&String> = self.files.values().filter_map(|e| e.hash.as_ref()).collect();
+        let before = self.summaries.len();
+        self.summaries.retain(|hash, _| hashes.contains(hash));
+        self.changed |= self.summaries.len() != before;
+
+        for (name, entry) in &self.files {
+            let path = dir.join(name);
+            match (&entry.hash, &entry.error) {
+                (Some(hash), _) => listing.records.push(Record {
+                    path,
+                    hash: hash.clone(),
+                    summary: self.summaries[hash].clone(),
+                    meta: entry.meta.clone(),
+                }),
+                (None, error) => listing.skipped.push((path, error.clone().unwrap_or_default())),
+            }
+        }
+        Ok(listing)
+    }
+}
+
+/// Read the descriptor at `path`, returning its entry and, if it could be
+/// read, its flame.
+fn read_entry(path: &Path, len: u64, modified: Option<(u64, u32)>) -> (FileEntry, Option<Flame>) {
+    let mut entry = FileEntry { len, modified, refers_to_files: false, hash: None, error: None, meta: Map::new() };
+    let source = match FlameSource::from_path(path) {
+        Ok(source) => source,
+        Err(e) => {
+            entry.error = Some(e.to_string());
+            return (entry, None);
+        }
+    };
+    entry.refers_to_files = source.refers_to_files();
+    if let Some(Value::Object(mut meta)) = source.meta().and_then(|m| serde_json::to_value(m).ok()) {
+        meta.remove("thumbnail");
+        entry.meta = meta;
+    }
+    match source.to_flame().map_err(|e| e.to_string())
+        .and_then(|flame| flame.content_hash().map(|hash| (flame, hash)).map_err(|e| e.to_string()))
+    {
+        Ok((flame, hash)) => {
+            entry.hash = Some(format!("{:016x}", hash));
+            (entry, Some(flame))
+        }
+        Err(e) => {
+            entry.error = Some(e);
+            (entry, None)
+        }
+    }
+}
+
+/// Summarize each flame, spread over the machine's cores.
+fn summarize(flames: BTreeMap<String, Flame>) -> Vec<(String, FlameSummary)> {
+    let flames: Vec<_> = flames.into_iter().collect();
+    let cores = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let chunk = flames.len().div_ceil(cores).max(1);
+    std::thread::scope(|s| {
+        let workers: Vec<_> = flames.chunks(chunk)
+            .map(|flames| s.spawn(move || {
+                flames.iter().map(|(hash, flame)| (hash.clone(), flame.summary())).collect::<Vec<_>>()
+            }))
+            .collect();
+        workers.into_iter().flat_map(|w| w.join().expect("summarizing does not panic")).collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::meta::{GenerationMethod, Meta};
+    use crate::presets;
+    use std::fs::File;
+    use std::time::{Duration, SystemTime};
+
+    fn write(dir: &Path, name: &str, source: &FlameSource) {
+        source.to_writer(File::create(dir.join(name)).unwrap()).unwrap();
+    }
+
+    /// Mark `name` as modified at `secs` past the epoch, so that changes
+    /// within the file system's resolution are still seen.
+    fn touch(dir: &Path, name: &str, secs: u64) {
+        let file = File::options().write(true).open(dir.join(name)).unwrap();
+        file.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(secs)).unwrap();
+    }
+
+    fn names(listing: &Listing) -> Vec<String> {
+        listing.records.iter().map(|r| r.path.file_name().unwrap().to_string_lossy().into_owned()).collect()
+    }
+
+    /// A directory holding the gasket and the fern, a file which is not a
+    /// descriptor, and files which are not searched.
+    fn library() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path(), "gasket.json", &FlameSource::from_flame(&presets::gasket()));
+        write(dir.path(), "fern.json", &FlameSource::from_flame(&presets::fern()));
+        std::fs::write(dir.path().join("broken.json"), "{\"bounds\":").unwrap();
+        std::fs::write(dir.path().join("notes.txt"), "not a descriptor").unwrap();
+        write(dir.path(), ".hidden.json", &FlameSource::from_flame(&presets::swirl()));
+        dir
+    }
+
+    #[test]
+    fn unchanged_files_are_taken_from_the_index() {
+        let dir = library();
+        let mut index = SummaryIndex::load(dir.path());
+        let first = index.refresh(dir.path()).unwrap();
+        assert_eq!(names(&first), ["fern.json", "gasket.json"]);
+        assert_eq!((first.read, first.summarized), (3, 2));
+        assert_eq!(first.skipped.len(), 1);
+        assert!(first.skipped[0].0.ends_with("broken.json"));
+        assert!(index.is_changed());
+        assert_eq!(first.records[1].summary, presets::gasket().summary());
+        assert_eq!(first.records[1].hash, format!("{:016x}", presets::gasket().content_hash().unwrap()));
+        index.save(dir.path()).unwrap();
+
+        let mut index = SummaryIndex::load(dir.path());
+        let second = index.refresh(dir.path()).unwrap();
+        assert_eq!((second.read, second.summarized), (0, 0));
+        assert!(!index.is_changed());
+        assert_eq!(second.records, first.records);
+        assert_eq!(second.skipped, first.skipped);
+    }
+
+    #[test]
+    fn changed_files_are_read_again() {
+        let dir = library();
+        let mut index = SummaryIndex::new();
+        index.refresh(dir.path()).unwrap();
+
+        // A new flame is summarized.
+        write(dir.path(), "fern.json", &FlameSource::from_flame(&presets::swirl()));
+        touch(dir.path(), "fern.json", 1_000);
+        let listing = index.refresh(dir.path()).unwrap();
+        assert_eq!((listing.read, listing.summarized), (1, 1));
+        assert_eq!(listing.records[0].summary, presets::swirl().summary());
+
+        // A file changed to the same length is still read, by its time.
+        let text = std::fs::read_to_string(dir.path().join("gasket.json")).unwrap();
+        let edited = text.replacen("0.5", "0.4", 1);
+        assert_eq!(edited.len(), text.len());
+        std::fs::write(dir.path().join("gasket.json"), edited).unwrap();
+        touch(dir.path(), "gasket.json", 2_000);
+        let listing = index.refresh(dir.path()).unwrap();
+        assert_eq!((listing.read, listing.summarized), (1, 1));
+        assert_ne!(listing.records[1].summary, presets::gasket().summary());
+    }
+
+    #[test]
+    fn copies_and_new_metadata_are_not_summarized_again() {
+        let dir = library();
+        let mut index = SummaryIndex::new();
+        index.refresh(dir.path()).unwrap();
+
+        let mut meta = Meta::new(GenerationMethod::Breed);
+        meta.seed = Some(42);
+        write(dir.path(), "gasket.json", &FlameSource::from_flame(&presets::gasket()).with_meta(meta));
+        std::fs::copy(dir.path().join("fern.json"), dir.path().join("fern-copy.json")).unwrap();
+        let listing = index.refresh(dir.path()).unwrap();
+        assert_eq!((listing.read, listing.summarized), (2, 0));
+        assert_eq!(names(&listing), ["fern-copy.json", "fern.json", "gasket.json"]);
+        assert_eq!(listing.records[0].hash, listing.records[1].hash);
+        assert_eq!(listing.records[2].meta["method"], "breed");
+        assert_eq!(listing.records[2].meta["seed"], 42);
+    }
+
+    #[test]
+    fn removed_files_are_dropped_with_their_summaries() {
+        let dir = library();
+        let mut index = SummaryIndex::new();
+        index.refresh(dir.path()).unwrap();
+        index.save(dir.path()).unwrap();
+
+        std::fs::remove_file(dir.path().join("fern.json")).unwrap();
+        let mut index = SummaryIndex::load(dir.path());
+        let listing = index.refresh(dir.path()).unwrap();
+        assert_eq!(names(&listing), ["gasket.json"]);
+        assert_eq!(listing.read, 0);
+        assert!(index.is_changed());
+        assert_eq!(index.summaries.len(), 1);
+        assert!(!index.files.contains_key("fern.json"));
+    }
+
+    #[test]
+    fn unreadable_or_outdated_indexes_are_rebuilt() {
+        let dir = library();
+        let mut index = SummaryIndex::new();
+        index.refresh(dir.path()).unwrap();
+        index.save(dir.path()).unwrap();
+        let saved = std::fs::read_to_string(dir.path().join(INDEX_FILE)).unwrap();
+
+        let older = saved.replacen(&format!("\"version\":{}", INDEX_VERSION), "\"version\":0", 1);
+        let other_hashes = saved.replacen(&format!("\"hash_version\":{}", CONTENT_HASH_VERSION), "\"hash_version\":0", 1);
+        for contents in [older, other_hashes, "{\"files\":".to_string(), String::new()] {
+            assert_ne!(contents, saved);
+            std::fs::write(dir.path().join(INDEX_FILE), contents).unwrap();
+            let listing = SummaryIndex::load(dir.path()).refresh(dir.path()).unwrap();
+            assert_eq!((listing.read, listing.summarized), (3, 2));
+        }
+
+        // An index whose summary of a file is missing reads the file again.
+        let mut index = SummaryIndex::new();
+        index.refresh(dir.path()).unwrap();
+        index.summaries.clear();
+        assert_eq!(index.refresh(dir.path()).unwrap().summarized, 2);
+    }
+
+    #[test]
+    fn templates_and_their_instances_are_always_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let family = serde_json::json!({
+            "params": {"w": 0.5},
+            "bounds": [-1, 1, -1, 1],
+            "functions": [["$w", "Id", [0.5, 0, 0, 0.5, 0, 0], 0], ["1 - $w", "Swirl", [0.5, 0, 0, 0.5, 0.5, 0], 1]],
+            "palette": [[0, 0, 255], [255, 255, 255]],
+        });
+        std::fs::write(dir.path().join("family.json"), family.to_string()).unwrap();
+        std::fs::write(dir.path().join("member.json"), r#"{"template": "family.json", "params": {"w": 0.25}}"#).unwrap();
+        let mut index = SummaryIndex::new();
+        let first = index.refresh(dir.path()).unwrap();
+        assert_eq!(names(&first), ["family.json", "member.json"]);
+        assert_ne!(first.records[0].hash, first.records[1].hash);
+
+        let again = index.refresh(dir.path()).unwrap();
+        assert_eq!((again.read, again.summarized), (2, 0));
+
+        // The instance changes with its template, though it is untouched.
+        let family = family.to_string().replace("\"Swirl\"", "\"Spherical\"");
+        std::fs::write(dir.path().join("family.json"), family).unwrap();
+        let changed = index.refresh(dir.path()).unwrap();
+        assert_eq!(changed.summarized, 2);
+        assert!(changed.records[1].summary.variations.contains("Spherical"));
+    }
+}
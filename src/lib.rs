@@ -1,2 +1,21 @@
+pub mod accumulator;
+pub mod animation;
+pub mod batch;
+pub mod bench;
+pub mod cli_types;
 pub mod core;
-pub mod file;
\ No newline at end of file
+pub mod error;
+pub mod file;
+pub mod frames;
+pub mod index;
+pub mod meta;
+pub mod naming;
+pub mod output;
+pub mod presets;
+pub mod query;
+pub mod random;
+pub mod repl;
+#[cfg(feature = "self-test")]
+pub mod selftest;
+pub mod streaming;
+pub mod template;
\ No newline at end of file
@@ -1,89 +1,2422 @@
-use clap::Parser;
-use clap_num::si_number;
+// The preflight report is one large json! literal.
+#![recursion_limit = "256"]
+
+use clap::builder::{PossibleValue, StringValueParser, TypedValueParser};
+use clap::{Args, CommandFactory, Parser, Subcommand};
+use clap_complete::Shell;
+use image::DynamicImage;
+use rand::rngs::StdRng;
+use rand::distributions::Distribution;
+use rand::SeedableRng;
 use std::fs::File;
-use std::path::PathBuf;
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::process::ExitCode;
 
+use flame::accumulator::Provenance;
+use flame::animation::*;
+use flame::batch::*;
+use flame::bench::*;
+use flame::cli_types::*;
 use flame::core::*;
+use flame::error::FlameError;
 use flame::file::*;
+use flame::frames::*;
+use flame::index::SummaryIndex;
+use flame::meta::*;
+use flame::naming::*;
+use flame::output::*;
+use flame::query::{Predicate, QueryFormat, Record};
+use flame::random::*;
+use flame::template::{apply_instance, param_map, Binding};
+
+/// Threads rendering on when --threads is not given, except in 'repl'.
+const DEFAULT_THREADS: usize = 10;
 
 #[derive(Parser)]
 #[command(author, version, about)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+#[command(after_help = "Exit status is 2 for unparseable input, 3 for I/O errors, \
+    4 for invalid input or failed checks and 5 for rendering failures.")]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    render: RenderArgs,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Inspect the palette of a flame descriptor.
+    #[command(subcommand)]
+    Palette(PaletteCommand),
+    /// Inspect saved accumulator files.
+    #[command(subcommand)]
+    Accum(AccumCommand),
+    /// Manage the preview images embedded in descriptors.
+    #[command(subcommand)]
+    Thumbnail(ThumbnailCommand),
+    /// List the descriptors in a directory whose summaries meet every
+    /// --where condition. Summaries are kept in an index in the directory,
+    /// so that only changed descriptors are read again.
+    Query(QueryArgs),
+    /// Render a sequence of frames, modulating descriptor values over time.
+    Animate(Box<AnimateArgs>),
+    /// Generate and render offspring mixing the functions, palettes and
+    /// bounds of two flames.
+    Breed(Box<BreedArgs>),
+    /// Write a new descriptor to start from.
+    New(NewArgs),
+    /// Edit a flame interactively, keeping the accumulated histogram between
+    /// edits where possible. Type 'help' at the prompt for the commands.
+    Repl(Box<ReplArgs>),
+    /// Write pictures and data showing how a flame moves points around.
+    Analyze(AnalyzeArgs),
+    /// Estimate the iterations a render needs to reach --target-quality at
+    /// the size given by --dims, from a short calibration run.
+    Estimate(Box<EstimateArgs>),
+    /// Measure how many iterations a second this machine runs on a built in
+    /// flame, optionally comparing the options which affect speed.
+    Bench(BenchArgs),
+    /// List the render presets accepted by --preset and what they set.
+    Presets,
+    /// Check that this build computes what it should, by comparing a few
+    /// small computations against reference values.
+    ///
+    /// Exits with status 4 if any check fails.
+    #[cfg(feature = "self-test")]
+    SelfTest,
+    /// Print a script completing commands and option values for a shell.
+    Completions {
+        /// Shell to complete for.
+        shell: Shell,
+    },
+}
+
+#[derive(Args)]
+struct ReplArgs {
     /// Path to flame descriptor file.
     input: PathBuf,
-    /// Path to output image (file extension must be JPEG or PNG).
+    /// Width in characters of previews shown by 'show'.
+    #[arg(long, default_value_t = 64)]
+    preview_width: u32,
+    #[command(flatten)]
+    opts: RenderOptions,
+}
+
+#[derive(Args)]
+#[command(group(clap::ArgGroup::new("outputs").required(true).multiple(true)))]
+struct AnalyzeArgs {
+    /// Path to flame descriptor file.
+    input: PathBuf,
+    /// Write an image of the average displacement of points under the
+    /// functions, one pixel per grid point, with hue showing the direction
+    /// and saturation the length.
+    ///
+    /// Red points right, and the hues turn counterclockwise from it through
+    /// yellow, green and blue. White areas are not moved.
+    #[arg(long, value_name = "PATH", group = "outputs")]
+    vector_field: Option<PathBuf>,
+    /// Write the displacement at each grid point, as JSON if the path ends
+    /// in .json and as CSV otherwise.
+    #[arg(long, value_name = "PATH", group = "outputs")]
+    vectors: Option<PathBuf>,
+    /// Number of points across and down the bounds to sample.
+    #[arg(long, number_of_values = 2, default_values_t = [128, 128])]
+    #[arg(value_names = ["WIDTH", "HEIGHT"])]
+    grid: Vec<usize>,
+}
+
+#[derive(Args)]
+struct EstimateArgs {
+    /// Path to flame descriptor file.
+    input: PathBuf,
+    /// How long to run the calibration for. Longer runs measure sparse
+    /// regions more reliably.
+    #[arg(long, value_name = "DURATION", default_value = "3s")]
+    calibration: HumanDuration,
+    #[command(flatten)]
+    opts: RenderOptions,
+}
+
+#[derive(Args)]
+struct BenchArgs {
+    /// Built in flame to run.
+    #[arg(long, default_value = "fern", value_parser = hinted::<String>(flame::presets::NAMES), hide_possible_values = true)]
+    flame: String,
+    /// Time spent measuring each configuration, after warming it up.
+    #[arg(long, value_name = "DURATION", default_value = "10s")]
+    duration: HumanDuration,
+    /// Number of timed runs the duration is split into. Their spread shows
+    /// how far the figures can be trusted.
+    #[arg(long, default_value_t = 3, value_parser = clap::value_parser!(u32).range(1..))]
+    repeats: u32,
+    /// Number of parallel threads [default: the number of cores].
+    #[arg(short, long)]
+    threads: Option<usize>,
+    /// Also measure each option affecting speed on its own, and how much
+    /// faster or slower it is than the baseline.
+    #[arg(long)]
+    matrix: bool,
+    /// Instead measure the baseline on one thread, on each power of two
+    /// below --threads and on --threads itself, and how much faster each
+    /// runs than one thread.
+    #[arg(long, conflicts_with = "matrix")]
+    scaling: bool,
+    /// Print the results as JSON.
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Subcommand)]
+enum PaletteCommand {
+    /// Check that a palette is distinguishable under color vision deficiencies
+    /// and reproducible in print.
+    ///
+    /// Exits with status 4 if any of the thresholds are violated.
+    Audit(AuditArgs),
+    /// Write a palette as a gradient for use in other programs.
+    Export(ExportArgs),
+    /// Adjust the colors of a descriptor's palette, writing a copy of the
+    /// descriptor with the new colors.
+    Adjust(AdjustArgs),
+}
+
+#[derive(Subcommand)]
+enum AccumCommand {
+    /// Print the header of an accumulator file.
+    Info {
+        /// Path to the accumulator file.
+        input: PathBuf,
+    },
+    /// Tone map an accumulator file into an image, such as the histogram
+    /// saved when a render's image could not be written.
+    Tonemap(Box<AccumTonemapArgs>),
+}
+
+#[derive(Args)]
+struct AccumTonemapArgs {
+    /// Path to the accumulator file.
+    input: PathBuf,
+    /// Path to write the image to. The format is given by the extension.
     output: PathBuf,
-    /// Number of iterations of the chaos game to run (accepts SI postfixes).
-    /// 
+    /// Descriptor whose render settings to start from, as when rendering
+    /// it. The size of the image is that of the accumulator.
+    #[arg(long, value_name = "PATH")]
+    descriptor: Option<PathBuf>,
+    #[command(flatten)]
+    opts: RenderOptions,
+}
+
+#[derive(Subcommand)]
+enum ThumbnailCommand {
+    /// Render a thumbnail for each descriptor in a directory which has
+    /// none, or whose thumbnail was made before it was last changed.
+    ///
+    /// --iters defaults to 500k, and thumbnails are the size of the
+    /// descriptor's render shrunk to fit within 128 pixels.
+    Update(Box<ThumbnailUpdateArgs>),
+}
+
+#[derive(Args)]
+struct ThumbnailUpdateArgs {
+    /// Directory of flame descriptors.
+    dir: PathBuf,
+    /// Render thumbnails for every descriptor, even those which are up to
+    /// date.
+    #[arg(long)]
+    force: bool,
+    #[command(flatten)]
+    opts: RenderOptions,
+}
+
+#[derive(Args)]
+struct QueryArgs {
+    /// Directory of flame descriptors.
+    dir: PathBuf,
+    /// A condition on the descriptors listed, written FIELD OP VALUE, such
+    /// as 'variations contains PDJ', 'functions.count > 5' or
+    /// 'palette.dominant_hue between 180 260'. Fields are name,
+    /// functions.count, variations, weights.entropy, palette.dominant_hue,
+    /// palette.lightness, palette.lightness_min, palette.lightness_max,
+    /// symmetry.rotation, symmetry.mirror, bounds.aspect and meta.KEY, and
+    /// operators =, !=, <, <=, >, >=, between and contains. May be given
+    /// many times.
+    #[arg(long = "where", value_name = "CONDITION")]
+    conditions: Vec<Predicate>,
+    /// How to list the matches: table, json or paths.
+    #[arg(long, default_value = "table", value_parser = hinted::<QueryFormat>(QueryFormat::NAMES), hide_possible_values = true)]
+    format: QueryFormat,
+    /// Read every descriptor, neither reading nor writing the index.
+    #[arg(long)]
+    no_index: bool,
+}
+
+#[derive(Args)]
+struct ExportArgs {
+    /// Path to flame descriptor file.
+    input: PathBuf,
+    /// Gradient format: ugr (Apophysis), ggr (GIMP) or css.
+    #[arg(short, long, value_parser = hinted::<GradientFormat>(GradientFormat::NAMES), hide_possible_values = true)]
+    format: GradientFormat,
+    /// Path to write the gradient to, instead of standard output.
+    #[arg(short, long, value_name = "PATH")]
+    output: Option<PathBuf>,
+    /// Name stored in the gradient, by default the descriptor's file name.
+    #[arg(long)]
+    name: Option<String>,
+}
+
+#[derive(Args)]
+struct AdjustArgs {
+    /// Path to flame descriptor file.
+    input: PathBuf,
+    /// Adjustment to apply, in order given: brightness=K, saturation=K,
+    /// hue-rotate=DEGREES, contrast=K or posterize=LEVELS.
+    #[arg(long = "op", value_name = "OP=VALUE", required = true,
+        value_parser = hinted::<PaletteOp>(PaletteOp::NAMES), hide_possible_values = true)]
+    ops: Vec<PaletteOp>,
+    /// Path to write the descriptor to, instead of standard output.
+    #[arg(short, long, value_name = "PATH")]
+    output: Option<PathBuf>,
+}
+
+#[derive(Args)]
+struct AuditArgs {
+    /// Path to flame descriptor file.
+    input: PathBuf,
+    /// Minimum Oklab distance between adjacent palette colors.
+    #[arg(long, default_value_t = 0.05)]
+    min_distance: f32,
+    /// Minimum Oklab distance between adjacent palette colors under simulated
+    /// protanopia, deuteranopia and tritanopia.
+    #[arg(long, default_value_t = 0.02)]
+    min_cvd_distance: f32,
+    /// Maximum fraction of the palette outside the approximate print gamut.
+    #[arg(long, default_value_t = 0.25)]
+    max_out_of_gamut: f32,
+}
+
+#[derive(Args)]
+struct RenderArgs {
+    /// Path to flame descriptor file.
+    ///
+    /// May be left out when --functions, --palette-file and --bounds give
+    /// the whole flame, in which case the only path is the output.
+    #[arg(required_unless_present = "dry_run")]
+    input: Option<PathBuf>,
+    /// Path to output image (file extension must be JPEG, PNG, PGM, PPM, PBM
+    /// or EXR, unless --output-format is given).
+    output: Option<PathBuf>,
+    /// Take the functions from this file, which holds a list of functions as
+    /// in a descriptor, or a whole descriptor.
+    #[arg(long, value_name = "PATH")]
+    functions: Option<PathBuf>,
+    /// Take the palette from this file, which holds a palette as in a
+    /// descriptor, or a whole descriptor, or is a GIMP gradient ending in
+    /// .ggr.
+    #[arg(long, value_name = "PATH")]
+    palette_file: Option<PathBuf>,
+    /// Take the bounds from these values.
+    #[arg(long, number_of_values = 4, allow_negative_numbers = true)]
+    #[arg(value_names = ["X_MIN", "X_MAX", "Y_MIN", "Y_MAX"])]
+    bounds: Option<Vec<f32>>,
+    /// Value of a parameter the descriptor declares as a template, replacing
+    /// its default, e.g. 'spin=0.45'. May be given many times.
+    #[arg(long = "param", value_name = "NAME=VALUE")]
+    params: Vec<Binding>,
+    /// Check the descriptor and configuration and print a JSON report of
+    /// the checks, without rendering.
+    #[arg(long)]
+    dry_run: bool,
+    /// Least severe finding of the checks which stops the render, or fails
+    /// a dry run: warning or error.
+    #[arg(long, default_value = "error", value_parser = hinted::<Severity>(Severity::NAMES), hide_possible_values = true)]
+    fail_on: Severity,
+    /// Treat warnings as errors, including warnings that a value in the
+    /// descriptor was adjusted. The same as --fail-on warning.
+    #[arg(long)]
+    deny_warnings: bool,
+    /// Format of the output image, instead of the one its extension names:
+    /// png, jpeg, the plain text pgm or ppm, the one-bit pbm, or exr.
+    #[arg(long, value_name = "FORMAT", value_parser = hinted::<OutputFormat>(OutputFormat::NAMES), hide_possible_values = true)]
+    output_format: Option<OutputFormat>,
+    /// Quality of JPEG output, from 1 to 100.
+    #[arg(long, default_value_t = 90, value_parser = clap::value_parser!(u8).range(1..=100))]
+    jpeg_quality: u8,
+    /// Chroma subsampling of JPEG output: 444 keeps color at full resolution,
+    /// 420 halves it for smaller files at the cost of smearing fine colored
+    /// detail.
+    #[arg(long, default_value = "444", value_parser = hinted::<ChromaSubsampling>(ChromaSubsampling::NAMES), hide_possible_values = true)]
+    chroma_subsampling: ChromaSubsampling,
+    /// Encode JPEG output progressively, so it can be shown at low detail
+    /// before it has fully loaded.
+    #[arg(long)]
+    progressive: bool,
+    /// Fraction of iterations which must land in the bounds for the render
+    /// not to be reported as empty.
+    #[arg(long, value_name = "FRACTION", default_value_t = EMPTY_FRAME_THRESHOLD)]
+    empty_threshold: f64,
+    /// When the render is empty, fit bounds to the flame and render it once
+    /// more with them.
+    #[arg(long)]
+    auto_retry_bounds: bool,
+    /// Write the fitted bounds to this file as JSON, when they are used.
+    #[arg(long, value_name = "PATH", requires = "auto_retry_bounds")]
+    fitted_bounds: Option<PathBuf>,
+    /// Also write an image for each of this many equal bands of palette
+    /// position, named as the output with .band0, .band1 and so on before
+    /// its extension.
+    ///
+    /// Each holds only the points colored from its band, tonemapped along
+    /// with the whole image so that the layers add up to it over black.
+    /// Each band takes as much memory as the image's histogram.
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u16).range(2..=256))]
+    split_bands: Option<u16>,
+    /// Cancel the render once a thread has made no progress for this long,
+    /// such as '30s', saving the histogram so far.
+    ///
+    /// When rendering on more than one thread, threads making no progress
+    /// are reported every few seconds whether or not this is given.
+    #[arg(long, value_name = "DURATION")]
+    stall_timeout: Option<HumanDuration>,
+    /// Also write an image coloring each pixel by the function which
+    /// plotted most of its points, with brightness from its density.
+    ///
+    /// Functions are given evenly spaced hues in order, starting from red.
+    /// The flame may have at most 16 functions, and each thread keeps four
+    /// bytes per function per pixel.
+    #[arg(long, value_name = "PATH")]
+    attribution_map: Option<PathBuf>,
+    /// Write the attractor's silhouette in two colors instead of the image,
+    /// for stencils and cutting, e.g. threshold=0.02,min-region=8.
+    ///
+    /// Pixels whose tonemapped density is above the threshold are the
+    /// foreground. Regions of foreground, and holes in it, with fewer than
+    /// min-region pixels are removed. Other settings are connectivity (4 or
+    /// 8), foreground and background; the default is black on white. PNG
+    /// output has a two-color palette, and .pbm output one bit a pixel.
+    #[arg(long, value_name = "SETTINGS")]
+    silhouette: Option<Silhouette>,
+    /// Also write the image to this path, in the format its extension
+    /// names, e.g. an unquantized .exr master alongside a PNG. May be given
+    /// more than once; the histogram is only tone mapped once for all.
+    #[arg(long, value_name = "PATH")]
+    also_save: Vec<PathBuf>,
+    #[command(flatten)]
+    opts: RenderOptions,
+}
+
+#[derive(Args)]
+struct AnimateArgs {
+    /// Path to flame descriptor file.
+    input: PathBuf,
+    /// Directory to write numbered PNG frames to.
+    output: PathBuf,
+    /// Number of frames to render.
+    #[arg(short, long, default_value_t = 60)]
+    frames: usize,
+    /// Assignment of an expression to a descriptor value, applied to every
+    /// frame, e.g. 'functions[0][2][4] = 0.5*sin(t*tau)'.
+    ///
+    /// Paths index into the descriptor JSON. Expressions may use the frame
+    /// phase t (from 0 up to 1) and the frame index n.
+    ///
+    /// When the input is a template, its parameters can be modulated as
+    /// 'params.NAME'.
+    #[arg(short, long = "mod", value_name = "PATH=EXPR")]
+    mods: Vec<Modulation>,
+    /// Value of a parameter the input declares as a template, replacing its
+    /// default, e.g. 'spin=0.45'. May be given many times.
+    #[arg(long = "param", value_name = "NAME=VALUE", conflicts_with = "to")]
+    params: Vec<Binding>,
+    /// Morph from the input flame into this one instead of modulating it.
+    ///
+    /// The flames must have the same number of functions, with the same
+    /// variation in each position. Their palettes may differ.
+    #[arg(long, value_name = "PATH", conflicts_with = "mods")]
+    to: Option<PathBuf>,
+    /// Color space to interpolate palettes in when morphing: srgb, linear or oklab.
+    #[arg(long, default_value = "oklab", requires = "to")]
+    #[arg(value_parser = hinted::<ColorSpace>(ColorSpace::NAMES), hide_possible_values = true)]
+    color_space: ColorSpace,
+    /// Number of positions at which palettes are sampled and mixed when
+    /// morphing (at most 256).
+    #[arg(long, default_value_t = 256, requires = "to", conflicts_with = "strict_palette")]
+    palette_samples: usize,
+    /// Mix corresponding palette colors when morphing, requiring the palettes
+    /// to have the same number of colors.
+    #[arg(long, requires = "to")]
+    strict_palette: bool,
+    /// What to do when a frame's file already exists: overwrite, which
+    /// replaces it so that re-running an animation updates its frames;
+    /// suffix, which adds -2, -3 and so on to the new frame's name; or error.
+    #[arg(long, default_value = "overwrite", value_parser = hinted::<CollisionPolicy>(CollisionPolicy::NAMES), hide_possible_values = true)]
+    on_collision: CollisionPolicy,
+    #[command(flatten)]
+    opts: RenderOptions,
+}
+
+#[derive(Args)]
+struct NewArgs {
+    /// Path to write the descriptor to.
+    output: PathBuf,
+    /// Preset to start from: starter, fern, gasket or swirl.
+    #[arg(short, long, default_value = "starter")]
+    #[arg(value_parser = hinted::<String>(flame::presets::NAMES), hide_possible_values = true)]
+    example: String,
+    /// Replace the preset's palette with a random one following a hue
+    /// harmony: analogous[:SPREAD], complementary, triadic or mono[:MIN-MAX].
+    #[arg(long, value_name = "HARMONY")]
+    #[arg(value_parser = hinted::<Harmony>(Harmony::NAMES), hide_possible_values = true)]
+    palette_harmony: Option<Harmony>,
+    /// Base hue in degrees of the random palette, instead of a random one.
+    #[arg(long, value_name = "DEGREES", requires = "palette_harmony")]
+    palette_hue: Option<f32>,
+    /// Seed for the random palette.
+    #[arg(long, requires = "palette_harmony")]
+    seed: Option<u64>,
+    /// Overwrite the file if it already exists.
+    #[arg(long)]
+    force: bool,
+}
+
+#[derive(Args)]
+struct BreedArgs {
+    /// Path to the first parent's descriptor.
+    first: PathBuf,
+    /// Path to the second parent's descriptor.
+    second: PathBuf,
+    /// Directory to write each child's descriptor and image to.
+    output: PathBuf,
+    /// Number of children to generate.
+    #[arg(short = 'n', long, default_value_t = 8)]
+    count: usize,
+    /// Probability that matching functions are interpolated rather than
+    /// inherited whole from one parent.
+    #[arg(long, default_value_t = 0.5)]
+    lerp_probability: f64,
+    /// Probability that each numeric parameter of a child is mutated.
+    #[arg(long, default_value_t = 0.05)]
+    mutation_rate: f64,
+    /// Standard deviation of the noise added by a mutation.
+    #[arg(long, default_value_t = 0.05)]
+    mutation_scale: f32,
+    /// Number of children to render at once.
+    #[arg(long, default_value_t = 1)]
+    parallel_flames: usize,
+    /// What to do when a child's files already exist: suffix, which adds
+    /// -2, -3 and so on to the new child's name, or error.
+    #[arg(long, default_value = "suffix", value_parser = hinted::<CollisionPolicy>(CollisionPolicy::NAMES), hide_possible_values = true)]
+    on_collision: CollisionPolicy,
+    /// Continue the batch recorded in the output directory's journal,
+    /// skipping children it finished and redoing those it was writing.
+    ///
+    /// The parents and crossover options must match the interrupted run,
+    /// whose seed is reused unless --seed is given. Without this option, a
+    /// new journal replaces any already there.
+    #[arg(long)]
+    resume: bool,
+    #[command(flatten)]
+    opts: RenderOptions,
+}
+
+#[derive(Args)]
+struct RenderOptions {
+    /// Bundle of quality settings to start from: draft, standard, final or
+    /// print. Run 'flame presets' to see what each sets.
+    ///
+    /// Options given explicitly take precedence over the preset, and the
+    /// preset over any settings in the descriptor's render block. The
+    /// preset's size multiplier does not apply to explicit --dims.
+    #[arg(long, value_parser = hinted::<RenderPreset>(RenderPreset::names()), hide_possible_values = true)]
+    preset: Option<RenderPreset>,
+    /// Number of iterations of the chaos game to run (accepts SI postfixes)
+    /// [default: 5M].
+    ///
     /// Higher values reduce noise but take longer to run.
-    #[arg(short, long, default_value = "5M", value_parser = si_number::<usize>)]
-    iters: usize,
-    /// Number of parallel threads.
-    #[arg(short, long, default_value_t = 10)]
-    threads: usize,
-    /// Dimensions (in pixels) of the output image.
-    #[arg(short, long, number_of_values = 2, default_values_t = [500, 500])]
+    #[arg(short, long)]
+    iters: Option<SiCount>,
+    /// Number of parallel threads [default: 10, or one less than the number
+    /// of cores for 'repl'].
+    #[arg(short, long)]
+    threads: Option<usize>,
+    /// Pin each thread to its own core.
+    ///
+    /// This can speed up renders on machines with more than one processor
+    /// socket, by keeping each thread's memory local to it. It has no effect
+    /// where the platform does not support it.
+    #[arg(long)]
+    pin_threads: bool,
+    /// How orbits are drawn: points, or strokes[:LENGTH[:ATTENUATION]].
+    ///
+    /// Strokes join up to LENGTH consecutive points of each orbit (8 by
+    /// default) with faint lines, weighted by ATTENUATION (0.1 by default),
+    /// to show the flow of the system. This is meant for understanding a
+    /// flame rather than for final renders.
+    #[arg(long, default_value = "points", value_parser = hinted::<PlotMode>(PlotMode::NAMES), hide_possible_values = true)]
+    plot_mode: PlotMode,
+    /// How the flame is mapped into the image, for display on a dome or in
+    /// VR: none, fisheye[:FOV] or equirect.
+    ///
+    /// The flame's plane is wrapped onto a sphere, with the circle inscribed
+    /// in its bounds at the edge of the fisheye's field of view (FOV
+    /// degrees, 180 by default), or a quarter turn from the middle of an
+    /// equirectangular image. Points are thinned where the projection
+    /// squeezes them together, so that densities are not distorted.
+    #[arg(long, default_value = "none", value_parser = hinted::<Projection>(Projection::NAMES), hide_possible_values = true)]
+    projection: Projection,
+    /// Dimensions (in pixels) of the output image [default: 500 500].
+    #[arg(short, long, number_of_values = 2)]
     #[arg(value_names = ["WIDTH", "HEIGHT"])]
-    dims: Vec<usize>,
+    dims: Option<Vec<usize>>,
+    /// Largest number of pixels allowed in the output image (accepts SI postfixes).
+    #[arg(long, default_value_t = SiCount(DEFAULT_MAX_PIXELS as u64))]
+    max_pixels: SiCount,
+    /// Blend each point's color towards the palette color at how far
+    /// through the render it was plotted, by this weight between 0 and 1.
+    ///
+    /// Early iterations take colors from the start of the palette and later
+    /// ones from the end, giving a layered, long exposure look. Progress is
+    /// counted per thread.
+    #[arg(long, value_name = "BLEND")]
+    temporal_color: Option<f32>,
     /// Output a grayscale image, ignoring any specified color information.
     #[arg(short='G', long)]
     grayscale: bool,
-    /// Gamma correction factor.
-    #[arg(short, long, default_value_t = 2.2)]
-    gamma: f64,
+    /// Gamma correction factor [default: 2.2].
+    #[arg(short, long)]
+    gamma: Option<f64>,
     /// Preserve the true ratios of the color channels.
-    /// 
-    /// When enabled, instead of scaling each color channel independently to 
+    ///
+    /// When enabled, instead of scaling each color channel independently to
     /// fit the 8-bit range, they will be scaled by a common factor.
     #[arg(short, long)]
     preserve_color: bool,
-    /// Gamma color vibrancy (between 0 and 1).
-    /// 
+    /// Gamma color vibrancy (between 0 and 1) [default: 0].
+    ///
     /// When this value is zero, gamma correction is applied independently to each color channel,
     /// which can lead to washed out colors. When it is one, gamma correction only affects luminance.
     /// Values between 0 and 1 interpolate geometrically between these extremes.
-    #[arg(short, long, default_value_t = 0.0)]
-    vibrancy: f64,
+    #[arg(short, long)]
+    vibrancy: Option<f64>,
+    /// Background color, as #rrggbb, #rgb or a color name [default: black].
+    #[arg(short, long)]
+    background: Option<Color>,
+    /// How colors too bright to display are handled: clip, preserve-hue or
+    /// desaturate[:KNEE] [default: clip].
+    ///
+    /// Clipping turns bright colors white one channel at a time, which can
+    /// shift their hue. preserve-hue darkens them instead, and desaturate
+    /// keeps the hue until they are well over range, then fades to white.
+    #[arg(long, value_parser = hinted::<HighlightMode>(HighlightMode::NAMES), hide_possible_values = true)]
+    highlights: Option<HighlightMode>,
+    /// Dithering applied when converting to 8-bit color: none, ordered or
+    /// blue-noise [default: none].
+    ///
+    /// Dithering hides the contours that rounding to 256 levels leaves in
+    /// slow gradients. It depends only on pixel position, so seeded renders
+    /// stay reproducible.
+    #[arg(long, value_parser = hinted::<DitherMode>(DitherMode::NAMES), hide_possible_values = true)]
+    dither: Option<DitherMode>,
+    /// Brighten sparse regions relative to their surroundings by up to
+    /// 1 + STRENGTH times, so thin filaments stay visible next to dense
+    /// cores. SCALE is the size in pixels of the neighborhood (16 by default).
+    #[arg(long, value_name = "STRENGTH[:SCALE]")]
+    filament_boost: Option<FilamentBoost>,
+    /// Shade the image as a relief lit from AZIMUTH degrees counterclockwise
+    /// from the right, ELEVATION degrees above the image, blended in by
+    /// STRENGTH from 0 to 1, e.g. `--light 135,45,0.4`.
+    ///
+    /// Dense ridges catch the light on one side and are shaded on the other,
+    /// while flat regions are left as they were.
+    #[arg(long, value_name = "AZIMUTH,ELEVATION,STRENGTH")]
+    light: Option<Lighting>,
+    /// Tone curve applied to a channel of the finished image, as
+    /// CHANNEL:KEY=VALUE,... with keys lift, gamma and gain. May be repeated.
+    ///
+    /// Channels are master, red, green and blue, or r, g, b and m. The master
+    /// curve is applied to every channel before its own, e.g.
+    /// `--curve r:lift=0.02,gain=1.1 --curve master:gamma=0.95`.
+    #[arg(long, value_name = "CHANNEL:KEY=VALUE,...", value_parser = parse_curve)]
+    curve: Vec<String>,
+    /// Seed for the random number generator.
+    ///
+    /// Renders with the same seed and number of threads are identical on
+    /// the same platform, or on any platform with --deterministic-math.
+    #[arg(short, long)]
+    seed: Option<u64>,
+    /// Make seeded renders identical whatever the number of threads.
+    ///
+    /// The iterations are split into chunks of a million, each started
+    /// afresh with its own random stream, which threads take in turn. Each
+    /// chunk discards a few warm-up iterations, which makes no visible
+    /// difference. --restarts has no effect.
+    #[arg(long)]
+    seed_stable: bool,
+    /// Use portable implementations of sin, exp and other functions, so
+    /// that seeded renders are identical across platforms.
+    ///
+    /// The standard library's versions can round differently on different
+    /// operating systems and processors. The portable ones are slower.
+    #[arg(long)]
+    deterministic_math: bool,
+    /// Check every iteration for numerical problems, such as orbits going
+    /// to infinity or buckets overflowing, and list them after the render.
+    ///
+    /// This is much slower, and is meant for developing variations and
+    /// descriptors. The most recent 256 problems of each thread are listed.
+    #[arg(long)]
+    paranoid: bool,
+    /// List how often each function was applied, how often its points
+    /// landed in the bounds, and the center and spread in pixels of where
+    /// they were plotted.
+    #[arg(long)]
+    per_function_stats: bool,
+    /// Render at the lowest thread priority, so that the render only uses
+    /// processor time nothing else wants.
+    #[arg(long)]
+    nice: bool,
+    /// Percentage of the machine's processor time the render may use,
+    /// pausing in between to keep it cool and quiet. Seeded renders come
+    /// out the same as without a limit, only slower.
+    #[arg(long, value_name = "PERCENT", value_parser = clap::value_parser!(u8).range(1..=100))]
+    cpu_limit: Option<u8>,
+    /// Precision of orbit coordinates: auto, f32 or f64.
+    ///
+    /// Single precision cannot place points finely enough for deep zooms,
+    /// which come out blocky. auto uses double precision when the bounds
+    /// are small enough to need it; it is somewhat slower.
+    #[arg(long, default_value = "auto", value_parser = hinted::<Precision>(Precision::NAMES), hide_possible_values = true)]
+    precision: Precision,
+    /// Iterations each orbit runs from a random start before it plots, or
+    /// auto.
+    ///
+    /// Weakly contracting flames need longer to settle onto their
+    /// attractor, and show stray specks with too short a fuse. auto chooses
+    /// from 10 to 500 iterations from how strongly the flame contracts.
+    #[arg(long, default_value_t = FuseMode::default(), value_name = "ITERS")]
+    fuse: FuseMode,
+    /// Number of random starting points each thread divides its iterations
+    /// between.
+    ///
+    /// More starts help flames whose orbits rarely move between parts of
+    /// the attractor, and make short previews less streaky.
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    restarts: u32,
+    /// Memory layout of the histograms while plotting: row-major or morton.
+    ///
+    /// morton keeps pixels which are close on screen close in memory, which
+    /// can reduce cache misses for large images. The image is the same
+    /// either way.
+    #[arg(long, default_value = "row-major", value_parser = hinted::<Layout>(Layout::NAMES), hide_possible_values = true)]
+    layout: Layout,
+    /// Stop early once the relative change in the image between checks
+    /// falls below this value.
+    #[arg(long, value_name = "CHANGE")]
+    stop_rel_change: Option<f64>,
+    /// Number of iterations between convergence checks (accepts SI postfixes).
+    #[arg(long, default_value = "1M", requires = "stop_rel_change", value_parser = si_count_from(1))]
+    stop_interval: SiCount,
+    /// Minimum number of iterations before stopping early (accepts SI postfixes).
+    #[arg(long, default_value = "1M", requires = "stop_rel_change")]
+    stop_min_iters: SiCount,
+    /// Run until the estimated mean relative error of the hit pixels falls
+    /// to this value (e.g. 0.01 for 1%), instead of for a fixed number of
+    /// iterations.
+    #[arg(long, value_name = "ERROR", conflicts_with = "iters")]
+    target_quality: Option<f64>,
+    /// Maximum number of iterations when using --target-quality (accepts SI postfixes).
+    #[arg(long, default_value = "100M", requires = "target_quality")]
+    max_iters: SiCount,
+    /// Number of iterations between error estimates when using
+    /// --target-quality (accepts SI postfixes).
+    #[arg(long, default_value = "1M", requires = "target_quality", value_parser = si_count_from(1))]
+    quality_interval: SiCount,
+    /// Also write a false-color map of the estimated relative noise in each
+    /// pixel, from blue (none) through yellow to red (100% or more).
+    #[arg(long, value_name = "PATH")]
+    noise_map: Option<PathBuf>,
+    /// Replace the descriptor's palette with the dominant colors of an image.
+    #[arg(long, value_name = "IMAGE")]
+    palette_from_image: Option<PathBuf>,
+    /// Number of colors to extract when using --palette-from-image.
+    #[arg(long, default_value_t = 6, requires = "palette_from_image")]
+    palette_size: usize,
+    /// Only plot points where this grayscale stencil is bright, replacing
+    /// any mask in the descriptor.
+    ///
+    /// The stencil is stretched over the whole image. Points are kept with
+    /// a probability equal to its brightness where they land, so gray areas
+    /// are drawn with proportionally less density.
+    #[arg(long, value_name = "IMAGE")]
+    mask: Option<PathBuf>,
+}
+
+/// Parses values with `FromStr`, while offering `names` to shell completion.
+/// Values other than the names, such as `strokes:8`, are still accepted.
+#[derive(Clone)]
+struct Hinted<T> {
+    names: Vec<&'static str>,
+    value: PhantomData<fn() -> T>,
+}
+
+fn hinted<T>(names: impl IntoIterator<Item = &'static str>) -> Hinted<T> {
+    Hinted { names: names.into_iter().collect(), value: PhantomData }
+}
+
+impl<T> TypedValueParser for Hinted<T>
+where
+    T: FromStr + Clone + Send + Sync + 'static,
+    T::Err: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    type Value = T;
+
+    fn parse_ref(&self, cmd: &clap::Command, arg: Option<&clap::Arg>, value: &std::ffi::OsStr) -> Result<T, clap::Error> {
+        StringValueParser::new().try_map(|s| s.parse::<T>()).parse_ref(cmd, arg, value)
+    }
+
+    fn possible_values(&self) -> Option<Box<dyn Iterator<Item = PossibleValue> + '_>> {
+        Some(Box::new(self.names.iter().map(|&n| PossibleValue::new(n))))
+    }
+}
+
+/// Parses an `SiCount` of at least `min`, as `value_parser!(u64).range(min..)`
+/// does a plain number.
+fn si_count_from(min: u64) -> impl Fn(&str) -> Result<SiCount, String> + Clone + Send + Sync + 'static {
+    move |s| match s.parse::<SiCount>() {
+        Ok(count) if count.0 < min => Err(format!("{} is not in {}..", count, min)),
+        parsed => parsed.map_err(|e| e.to_string()),
+    }
+}
+
+/// Checks a `--curve` argument, which is kept as written so that repeated
+/// curves for the same channel can be merged in order.
+fn parse_curve(s: &str) -> Result<String, String> {
+    ChannelCurves::default().set(s)?;
+    Ok(s.to_string())
+}
+
+/// Where a setting in the render configuration came from, from lowest to
+/// highest precedence.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ConfigSource {
+    Default,
+    /// The `render` block of the descriptor.
+    Descriptor,
+    Preset,
+    Option,
+}
+
+impl std::fmt::Display for ConfigSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ConfigSource::Default => "default",
+            ConfigSource::Descriptor => "descriptor",
+            ConfigSource::Preset => "preset",
+            ConfigSource::Option => "option",
+        })
+    }
+}
+
+/// The source of each setting which a descriptor can give.
+type ConfigSources = std::collections::BTreeMap<&'static str, ConfigSource>;
+
+impl RenderOptions {
+    /// The configuration of the preset, with any options given explicitly
+    /// taking its place.
+    fn to_configs(&self) -> (RunConfig, ToneMapping, OutputEncoding) {
+        let (run_cfg, tone, encoding, _) = self.to_configs_for(None);
+        (run_cfg, tone, encoding)
+    }
+
+    /// The configuration to render a descriptor with. Its render settings
+    /// replace the defaults, and are themselves replaced by an explicit
+    /// preset and then by options given explicitly. Where each setting came
+    /// from is returned alongside.
+    fn to_configs_for(&self, settings: Option<&RenderSettings>) -> (RunConfig, ToneMapping, OutputEncoding, ConfigSources) {
+        let settings = settings.cloned().unwrap_or_default();
+        let mut sources = ConfigSources::new();
+        let from = |explicit: bool, descriptor: bool| {
+            if explicit {
+                ConfigSource::Option
+            } else if descriptor {
+                ConfigSource::Descriptor
+            } else {
+                ConfigSource::Default
+            }
+        };
+
+        let grayscale = self.grayscale || settings.grayscale == Some(true);
+        sources.insert("grayscale", from(self.grayscale, settings.grayscale.is_some()));
+        let preserve_color = self.preserve_color || settings.preserve_color == Some(true);
+        sources.insert("preserve_color", from(self.preserve_color, settings.preserve_color.is_some()));
+        sources.insert("background", from(self.background.is_some(), settings.background.is_some()));
+        sources.insert("dither", from(self.dither.is_some(), settings.dither.is_some()));
+
+        let mut run_cfg = RunConfig {
+            width: 500,
+            height: 500,
+            iters: 0,
+            threads: self.threads.unwrap_or(DEFAULT_THREADS),
+            seed: self.seed,
+            stop_when: self.stop_rel_change.map(|rel_change_below| StopCondition {
+                rel_change_below,
+                check_interval_iters: self.stop_interval.0,
+                min_iters: self.stop_min_iters.0,
+            }),
+            track_variance: self.noise_map.is_some(),
+            quality: self.target_quality.map(|mean_rel_err| QualityTarget {
+                mean_rel_err,
+                check_interval_iters: self.quality_interval.0,
+            }),
+            pin_threads: self.pin_threads,
+            plot_mode: self.plot_mode,
+            max_pixels: self.max_pixels.0 as usize,
+            temporal_color: self.temporal_color.map(|blend| TemporalColor { blend }),
+            monochrome: grayscale,
+            deterministic_math: self.deterministic_math,
+            precision: self.precision,
+            restarts_per_thread: self.restarts,
+            layout: self.layout,
+            paranoid: self.paranoid,
+            nice: self.nice,
+            cpu_limit: self.cpu_limit.map(|percent| percent as f64 / 100.0),
+            per_function_stats: self.per_function_stats,
+            stable_chunk_iters: self.seed_stable.then_some(STABLE_CHUNK_ITERS),
+            split_bands: None,
+            attribution: false,
+            projection: self.projection,
+            fuse: self.fuse,
+            watchdog: None,
+        };
+        let mut tone = ToneMapping {
+            gamma: 0.0,
+            vibrancy: 0.0,
+            preserve_color,
+            filament_boost: self.filament_boost,
+            lighting: self.light,
+            background: self.background.or(settings.background).unwrap_or(Color::rgb(0, 0, 0)),
+            highlights: HighlightMode::default(),
+            curves: None,
+            deterministic_math: self.deterministic_math,
+        };
+        let encoding = OutputEncoding {
+            grayscale,
+            dither: self.dither.or(settings.dither).unwrap_or_default(),
+            depth: BitDepth::Eight,
+        };
+
+        // The descriptor's size is scaled by an explicit preset, like the
+        // default size is.
+        if let Some([width, height]) = settings.dims {
+            run_cfg.width = width;
+            run_cfg.height = height;
+        }
+        self.preset.unwrap_or(RenderPreset::STANDARD).apply(&mut run_cfg, &mut tone);
+        let scaled = self.preset.is_some_and(|p| p.scale != 1.0);
+        let dims_source = match (&self.dims, settings.dims) {
+            (Some(_), _) => ConfigSource::Option,
+            (None, _) if scaled => ConfigSource::Preset,
+            (None, Some(_)) => ConfigSource::Descriptor,
+            (None, None) => ConfigSource::Default,
+        };
+        sources.insert("width", dims_source);
+        sources.insert("height", dims_source);
+
+        // Anything else the preset covers is only taken from the descriptor
+        // when no preset was asked for.
+        let preset = |descriptor: bool| match self.preset {
+            Some(_) => ConfigSource::Preset,
+            None if descriptor => ConfigSource::Descriptor,
+            None => ConfigSource::Default,
+        };
+        sources.insert("iters", preset(settings.iters.is_some()));
+        sources.insert("gamma", preset(settings.gamma.is_some()));
+        sources.insert("vibrancy", preset(settings.vibrancy.is_some()));
+        sources.insert("highlights", preset(settings.highlights.is_some()));
+        if self.preset.is_none() {
+            if let Some(iters) = settings.iters {
+                run_cfg.iters = iters as usize;
+            }
+            if let Some(gamma) = settings.gamma {
+                tone.gamma = gamma;
+            }
+            if let Some(vibrancy) = settings.vibrancy {
+                tone.vibrancy = vibrancy;
+            }
+            if let Some(highlights) = settings.highlights {
+                tone.highlights = highlights;
+            }
+        }
+
+        if let Some(dims) = &self.dims {
+            run_cfg.width = dims[0];
+            run_cfg.height = dims[1];
+        }
+        if let Some(iters) = self.iters {
+            run_cfg.iters = iters.0 as usize;
+            sources.insert("iters", ConfigSource::Option);
+        }
+        if self.target_quality.is_some() {
+            run_cfg.iters = self.max_iters.0 as usize;
+            sources.insert("iters", ConfigSource::Option);
+        }
+        if let Some(gamma) = self.gamma {
+            tone.gamma = gamma;
+            sources.insert("gamma", ConfigSource::Option);
+        }
+        if let Some(vibrancy) = self.vibrancy {
+            tone.vibrancy = vibrancy;
+            sources.insert("vibrancy", ConfigSource::Option);
+        }
+        if let Some(highlights) = self.highlights {
+            tone.highlights = highlights;
+            sources.insert("highlights", ConfigSource::Option);
+        }
+        if !self.curve.is_empty() {
+            let mut curves = ChannelCurves::default();
+            for spec in &self.curve {
+                // Each was checked by `parse_curve` as it was read.
+                curves.set(spec).expect("curve was validated when parsed");
+            }
+            tone.curves = Some(curves);
+        }
+        (run_cfg, tone, encoding, sources)
+    }
+
+    fn override_flame(&self, flame: &mut Flame) -> Result<(), FlameError> {
+        if let Some(path) = &self.palette_from_image {
+            let img = image::open(path)?;
+            flame.palette = Palette::from_image_kmeans(&img, self.palette_size, self.seed.unwrap_or(0))?;
+        }
+        if let Some(path) = &self.mask {
+            flame.mask = Some(MaskShape::Image(image::open(path)?.to_luma8()));
+        }
+        Ok(())
+    }
 }
 
-impl Cli {
-    fn to_config(&self) -> RenderConfig {
-        RenderConfig {
-            width: self.dims[0],
-            height: self.dims[1],
-            iters: self.iters,
-            threads: self.threads,
-            grayscale: self.grayscale,
-            gamma: self.gamma,
-            preserve_color: self.preserve_color,
-            vibrancy: self.vibrancy
+fn main() -> ExitCode {
+    match run(Cli::parse()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::from(e.exit_status())
         }
     }
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let cli = Cli::parse();
-    let input_file = File::open(&cli.input)?;
-    let cfg = cli.to_config();
+fn run(cli: Cli) -> Result<(), FlameError> {
+    match cli.command {
+        Some(Command::Palette(PaletteCommand::Audit(args))) => audit(args),
+        Some(Command::Palette(PaletteCommand::Export(args))) => export(args),
+        Some(Command::Palette(PaletteCommand::Adjust(args))) => adjust(args),
+        Some(Command::Accum(AccumCommand::Info { input })) => accum_info(input),
+        Some(Command::Accum(AccumCommand::Tonemap(args))) => accum_tonemap(*args),
+        Some(Command::Thumbnail(ThumbnailCommand::Update(args))) => thumbnail_update(*args),
+        Some(Command::Query(args)) => query(args),
+        Some(Command::Animate(args)) => animate(*args),
+        Some(Command::Breed(args)) => breed(*args),
+        Some(Command::New(args)) => new(args),
+        Some(Command::Repl(args)) => repl(*args),
+        Some(Command::Analyze(args)) => analyze(args),
+        Some(Command::Estimate(args)) => estimate(*args),
+        Some(Command::Bench(args)) => bench(args),
+        Some(Command::Presets) => presets(),
+        #[cfg(feature = "self-test")]
+        Some(Command::SelfTest) => self_test(),
+        Some(Command::Completions { shell }) => {
+            clap_complete::generate(shell, &mut Cli::command(), "flame", &mut std::io::stdout());
+            Ok(())
+        }
+        None => render(cli.render),
+    }
+}
+
+fn render(args: RenderArgs) -> Result<(), FlameError> {
+    // A single path is the output when rendering, unless the flame can only
+    // come from a descriptor.
+    let whole = args.functions.is_some() && args.palette_file.is_some() && args.bounds.is_some();
+    let (input, output) = match (args.input, args.output) {
+        (Some(output), None) if whole && !args.dry_run => (None, Some(output)),
+        paths => paths,
+    };
+    if output.is_none() && !args.dry_run {
+        return Err(FlameError::Validation("no output path given".to_string()));
+    }
+
+    let params = param_map(&args.params);
+    let source = input.as_ref().map(|path| FlameSource::from_path_with_params(path, &params)).transpose()?;
+    if source.is_none() && !params.is_empty() {
+        return Err(FlameError::Validation("--param binds parameters of a descriptor, but none was given".to_string()));
+    }
+    let (run_cfg, tone, encoding, sources) = args.opts.to_configs_for(source.as_ref().and_then(FlameSource::render_settings));
+    let run_cfg = RunConfig {
+        split_bands: args.split_bands.map(usize::from),
+        attribution: args.attribution_map.is_some(),
+        // Watching takes the caller's thread, so a render on one thread is
+        // only watched if it may be cancelled.
+        watchdog: (args.stall_timeout.is_some() || run_cfg.threads > 1)
+            .then(|| Watchdog::new(args.stall_timeout.map(|t| t.0), Some(|w| eprintln!("warning: {}", w)))),
+        ..run_cfg
+    };
 
-    let flame: Flame = FlameSource::from_file(input_file)?.to_flame();
+    let mut diagnostics = Diagnostics::new();
+    let base = match source {
+        Some(source) => FlameParts::from_flame(source.to_flame_with_report(&mut diagnostics)?),
+        None => FlameParts::new(),
+    };
+    let overrides = FlameParts {
+        functions: args.functions.as_ref()
+            .map(|path| FlameParts::functions_from_path_with_report(path, &mut diagnostics))
+            .transpose()?,
+        palette: args.palette_file.as_ref().map(FlameParts::palette_from_path).transpose()?,
+        bounds: args.bounds.as_ref().map(|b| Bounds::new(b[0], b[1], b[2], b[3])),
+        mask: None,
+        color_model: None,
+    };
+    let mut flame = base.merge(overrides).assemble()?;
+    args.opts.override_flame(&mut flame)?;
+    let report = preflight(&flame, run_cfg, &tone).with_diagnostics(diagnostics);
+    let fail_on = if args.deny_warnings { Severity::Warning } else { args.fail_on };
+
+    if args.dry_run {
+        println!("{}", serde_json::to_string_pretty(&preflight_json(&report, run_cfg, &tone, &encoding, &sources))?);
+        return check(&report, fail_on, false);
+    }
+    check(&report, fail_on, true)?;
+
+    let output = output.unwrap();
+    // The JPEG options apply to every JPEG written, including extra copies.
+    let jpeg_options = |sink: FileSink| match sink.format() {
+        OutputFormat::Jpeg { .. } => sink.with_format(OutputFormat::Jpeg {
+            quality: args.jpeg_quality,
+            subsampling: args.chroma_subsampling,
+            progressive: args.progressive,
+        }),
+        _ => sink,
+    };
+    let mut sink = jpeg_options(match args.output_format {
+        Some(format) => FileSink::new_with_format(&output, format),
+        None => FileSink::new(&output)?,
+    });
+    let mut also = args.also_save.iter()
+        .map(|path| FileSink::new(path).map(jpeg_options))
+        .collect::<Result<Vec<_>, _>>()?;
+    for sink in std::iter::once(&sink).chain(&also) {
+        sink.check_writable()?;
+    }
 
     println!("Rendering flame...");
 
     let before_run = std::time::Instant::now();
 
-    let buffer = flame.render(cfg);
+    let retried = RenderSession::run_retrying(flame, run_cfg, args.empty_threshold, args.auto_retry_bounds)?;
+    let session = retried.session;
+    let iters = session.iters();
+    let precision = session.config().precision;
+    let fuse = session.config().fuse;
+    let error = session.stats().error;
+    let thread_times = session.thread_times();
+    let incidents = session.incidents();
+    let function_stats = session.function_stats().map(|stats| function_stats_table(session.flame(), &stats));
+    let noise_map = session.variance().map(|variance| variance.to_noise_map());
+    let attribution_map = session.attribution().map(|attribution| attribution.to_map());
+    let provenance = Provenance {
+        iterations: iters,
+        flame_hash: session.flame().content_hash()?,
+        seed: run_cfg.seed,
+        shard: None,
+    };
+    let stall = session.stall_report().cloned();
+    let bands = session.bands();
+    let histogram = session.into_buffer();
+    if let Some(report) = stall {
+        rescue_histogram(&output, &histogram, &provenance, input.as_deref(), "The render was cancelled");
+        return Err(report.into());
+    }
+    let (toned, clipped) = histogram.tone_map_with_stats(&tone);
+    let layers = bands.map(|bands| histogram.render_bands_8bit(&bands, &tone, encoding.dither));
 
     let dur = before_run.elapsed();
 
-    buffer.into_rgb8().save(&cli.output)?;
+    // Nothing is written until the render is done, so if any of it cannot
+    // be, the histogram is saved instead of being lost.
+    let saved = (|| -> Result<(), FlameError> {
+        if let (Some(path), Some(map)) = (&args.opts.noise_map, noise_map) {
+            map.save(path)?;
+        }
+        if let (Some(path), Some(map)) = (&args.attribution_map, attribution_map) {
+            map.save(path)?;
+        }
+        match args.silhouette {
+            Some(silhouette) => {
+                let bitmap = histogram.silhouette(&tone, &silhouette);
+                for sink in std::iter::once(&mut sink).chain(&mut also) {
+                    write_bitmap(&bitmap, silhouette.foreground, silhouette.background, sink)?;
+                }
+            }
+            None => {
+                for sink in std::iter::once(&mut sink).chain(&mut also) {
+                    write_image(&encode_image(&toned, encoding, sink.format())?, sink)?;
+                }
+            }
+        }
+        for (i, layer) in layers.iter().flatten().enumerate() {
+            let ext = output.extension().map_or(String::new(), |e| format!(".{}", e.to_string_lossy()));
+            let path = output.with_extension(format!("band{}{}", i, ext));
+            let layer = layer.to_image(encoding.grayscale)?;
+            write_image(&DynamicImage::ImageRgb8(layer.into_rgb8()), &mut FileSink::new_with_format(path, sink.format()))?;
+        }
+        Ok(())
+    })();
+    if let Err(e) = saved {
+        rescue_histogram(&output, &histogram, &provenance, input.as_deref(), "The output could not be written");
+        return Err(e);
+    }
 
     println!(
-        "Completed! Rendered in {}.{:03} seconds. Output written to '{}'",
+        "Completed! Rendered {} iterations in {}.{:03} seconds. Output written to '{}'",
+        SiCount(iters),
         dur.as_secs(),
         dur.subsec_millis(),
-        cli.output.display()
+        output.display()
     );
+    if precision == Precision::F64 {
+        println!("Orbits were computed in double precision.");
+    }
+    if run_cfg.fuse == FuseMode::Auto {
+        println!("Orbits ran {} iterations before plotting.", fuse);
+    }
+    match run_cfg.projection {
+        Projection::None => {}
+        Projection::Fisheye { fov_deg } => println!("Projected as a {}° fisheye.", fov_deg),
+        Projection::Equirect => println!("Projected as an equirectangular panorama."),
+    }
+    if let (Some(size), Some(count)) = (run_cfg.stable_chunk_iters, run_cfg.stable_chunks()) {
+        println!("Iterations were split into {} chunks of {}.", count, SiCount(size));
+    }
+    if let Some(error) = error {
+        println!(
+            "Estimated mean relative error {:.2}% ({}).",
+            error.mean_rel_err * 100.0,
+            error.method
+        );
+    }
+    if clipped.any_channel > 0.0 {
+        println!(
+            "{:.2}% of pixels were too bright in some channel and {:.2}% in all.",
+            clipped.any_channel * 100.0,
+            clipped.all_channels * 100.0,
+        );
+    }
+    if thread_times.len() > 1 {
+        let secs: Vec<f64> = thread_times.iter().map(|t| t.as_secs_f64()).collect();
+        let min = secs.iter().copied().fold(f64::INFINITY, f64::min);
+        let max = secs.iter().copied().fold(0.0, f64::max);
+        println!("Threads busy for {:.3} to {:.3} seconds each.", min, max);
+    }
+    if let RenderOutcome::EmptyFrame { hit_fraction } = retried.first {
+        eprintln!(
+            "warning: only {:.4}% of iterations landed in the bounds, so the image is nearly empty. \
+             The bounds probably miss the flame's attractor.",
+            hit_fraction * 100.0,
+        );
+        match retried.fitted {
+            Some(bounds) => {
+                let [x_min, x_max, y_min, y_max] = bounds.to_array();
+                println!(
+                    "Rendered again with fitted bounds {} {} {} {}, which {:.2}% of iterations landed in.",
+                    x_min, x_max, y_min, y_max,
+                    retried.outcome.hit_fraction() * 100.0,
+                );
+                if let Some(path) = &args.fitted_bounds {
+                    let json = serde_json::json!({ "bounds": bounds.to_array() });
+                    std::fs::write(path, serde_json::to_string_pretty(&json)?)?;
+                    println!("Fitted bounds written to '{}'.", path.display());
+                }
+            }
+            None if args.auto_retry_bounds => eprintln!("warning: no bounds could be fitted, as the flame's orbit never stays finite."),
+            None => eprintln!("Use --auto-retry-bounds to fit bounds to the flame and render it again."),
+        }
+    }
+    if let Some(table) = function_stats {
+        print!("{}", table);
+    }
+    if let Some(incidents) = incidents {
+        println!("{} incidents found by the paranoid checks.", incidents.total());
+        for incident in incidents.iter() {
+            println!("  {}", incident);
+        }
+        if incidents.dropped() > 0 {
+            println!("  ({} earlier incidents not kept)", incidents.dropped());
+        }
+    }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+/// A table of how each function of `flame` fared in a render.
+fn function_stats_table(flame: &Flame, stats: &[FunctionStats]) -> String {
+    let selected: u64 = stats.iter().map(|s| s.selected).sum();
+    let mut table = format!(
+        "{:>3}  {:<14} {:>6}  {:>8}  {:>9}  {:>17}  {:>15}\n",
+        "#", "variation", "weight", "applied", "in bounds", "center (px)", "spread (px)",
+    );
+    for (i, (f, s)) in flame.functions.iter().zip(stats).enumerate() {
+        let percent = |n: u64, of: u64| if of > 0 { format!("{:.2}%", n as f64 / of as f64 * 100.0) } else { "-".to_string() };
+        let pair = |p: Option<[f64; 2]>| p.map_or("-".to_string(), |[x, y]| format!("{:.1}, {:.1}", x, y));
+        table.push_str(&format!(
+            "{:>3}  {:<14} {:>6.3}  {:>8}  {:>9}  {:>17}  {:>15}\n",
+            i, format!("{:?}", f.var.discriminant()), f.weight,
+            percent(s.selected, selected), percent(s.in_bounds, s.selected),
+            pair(s.centroid()), pair(s.spread()),
+        ));
+    }
+    table
+}
+
+/// Fail if any of the findings or diagnostics of a preflight check are at
+/// least as severe as `threshold`, optionally printing all of them first.
+///
+/// The error only counts them, as they have been printed or reported.
+fn check(report: &PreflightReport, threshold: Severity, print: bool) -> Result<(), FlameError> {
+    if print {
+        for diagnostic in &report.diagnostics {
+            eprintln!("{}", diagnostic);
+        }
+        for finding in &report.findings {
+            eprintln!("{}", finding);
+        }
+    }
+    let failing = report.diagnostics.iter().filter(|d| d.severity >= threshold).count()
+        + report.findings.iter().filter(|f| f.severity >= threshold).count();
+    match failing {
+        0 => Ok(()),
+        1 => Err(FlameError::Validation(format!("1 finding at or above {}", threshold))),
+        n => Err(FlameError::Validation(format!("{} findings at or above {}", n, threshold))),
+    }
+}
+
+/// A preflight report with the configuration it was made for, as printed by
+/// a dry run.
+fn preflight_json(
+    report: &PreflightReport,
+    run_cfg: RunConfig,
+    tone: &ToneMapping,
+    encoding: &OutputEncoding,
+    sources: &ConfigSources,
+) -> serde_json::Value {
+    serde_json::json!({
+        "dry_run": true,
+        "findings": report.findings.iter().map(|f| serde_json::json!({
+            "severity": f.severity.to_string(),
+            "message": f.message,
+        })).collect::<Vec<_>>(),
+        "diagnostics": report.diagnostics.iter().map(|d| serde_json::json!({
+            "severity": d.severity.to_string(),
+            "path": d.path,
+            "message": d.message,
+            "value_before": d.value_before,
+            "value_after": d.value_after,
+        })).collect::<Vec<_>>(),
+        "contractivity": report.contractivity.as_ref().map(|c| serde_json::json!({
+            "mean_log_contraction": c.mean_log_contraction,
+            "likely_convergent": c.is_likely_convergent(),
+            "functions": c.functions.iter().map(|f| serde_json::json!({
+                "singular_values": f.singular_values,
+                // Infinite when no sample point could be measured.
+                "variation_lipschitz": f.variation_lipschitz.is_finite().then_some(f.variation_lipschitz),
+                "log_contraction": f.log_contraction.is_finite().then_some(f.log_contraction),
+            })).collect::<Vec<_>>(),
+        })),
+        "memory_bytes": report.memory_bytes,
+        "config": {
+            "width": run_cfg.width,
+            "height": run_cfg.height,
+            "iters": run_cfg.iters,
+            "threads": run_cfg.threads,
+            "seed": run_cfg.seed,
+            "stop_rel_change": run_cfg.stop_when.map(|s| s.rel_change_below),
+            "target_quality": run_cfg.quality.map(|q| q.mean_rel_err),
+            "track_variance": run_cfg.track_variance,
+            "pin_threads": run_cfg.pin_threads,
+            "temporal_color": run_cfg.temporal_color.map(|t| t.blend),
+            "deterministic_math": run_cfg.deterministic_math,
+            "precision": report.precision.to_string(),
+            "fuse": report.fuse.iters(),
+            "restarts_per_thread": run_cfg.restarts_per_thread,
+            "layout": run_cfg.layout.to_string(),
+            "paranoid": run_cfg.paranoid,
+            "nice": run_cfg.nice,
+            "cpu_limit": run_cfg.cpu_limit,
+            "per_function_stats": run_cfg.per_function_stats,
+            "stable_chunk_iters": run_cfg.stable_chunk_iters,
+            "stable_chunks": run_cfg.stable_chunks(),
+            "attribution": run_cfg.attribution,
+            "split_bands": run_cfg.split_bands,
+            "projection": run_cfg.projection.to_string(),
+            "grayscale": encoding.grayscale,
+            "gamma": tone.gamma,
+            "preserve_color": tone.preserve_color,
+            "vibrancy": tone.vibrancy,
+            "dither": encoding.dither.to_string(),
+            "filament_boost": tone.filament_boost.map(|b| serde_json::json!({ "strength": b.strength, "scale": b.scale })),
+            "light": tone.lighting.map(|l| serde_json::json!({
+                "azimuth": l.azimuth,
+                "elevation": l.elevation,
+                "strength": l.strength,
+            })),
+            "curves": tone.curves.map(|c| serde_json::json!({
+                "master": c.master.to_string(),
+                "red": c.red.to_string(),
+                "green": c.green.to_string(),
+                "blue": c.blue.to_string(),
+            })),
+            "background": tone.background.to_string(),
+        },
+        "sources": sources.iter()
+            .map(|(key, source)| (key.to_string(), serde_json::Value::from(source.to_string())))
+            .collect::<serde_json::Map<_, _>>(),
+    })
+}
+
+fn animate(args: AnimateArgs) -> Result<(), FlameError> {
+    let (run_cfg, tone, encoding) = args.opts.to_configs();
+    run_cfg.check_size()?;
+
+    let mut diagnostics = Diagnostics::new();
+    let sequence: Box<dyn Iterator<Item = Result<Flame, AnimationError>>> = match &args.to {
+        Some(to) => {
+            let from = FlameSource::from_path(&args.input)?.to_flame()?;
+            let to = FlameSource::from_path(to)?.to_flame()?;
+            let opts = LerpOptions {
+                palette: if args.strict_palette {
+                    PaletteLerpMode::Strict
+                } else {
+                    PaletteLerpMode::Resample(diagnostics.clamped("--palette-samples", args.palette_samples, 2, 256))
+                },
+                space: args.color_space,
+            };
+            Box::new(MorphSequence::new(from, to, args.frames, opts)?)
+        }
+        None => {
+            let mut base = FlameSource::template_from_path(&args.input)?;
+            if !args.params.is_empty() {
+                let bindings = serde_json::json!({ "params": param_map(&args.params) });
+                base = apply_instance(bindings, base).map_err(DescriptorError::from)?;
+            }
+            let dir = args.input.parent().map(Path::to_path_buf).unwrap_or_default();
+            Box::new(ModulatedSequence::new(base, args.mods, args.frames)?.with_base_dir(dir))
+        }
+    };
+    for diagnostic in &diagnostics {
+        eprintln!("{}", diagnostic);
+    }
+
+    std::fs::create_dir_all(&args.output)?;
+    let writer = FrameWriter::new(&args.output, "frame_", FrameConfig {
+        grayscale: encoding.grayscale,
+        on_collision: args.on_collision,
+        ..FrameConfig::default()
+    });
+
+    let before_run = std::time::Instant::now();
+    let mut errors = Vec::new();
+
+    for (i, flame) in sequence.enumerate() {
+        let mut flame = flame?;
+        args.opts.override_flame(&mut flame)?;
+
+        println!("Rendering frame {} of {}...", i + 1, args.frames);
+        writer.write(i, flame.run(run_cfg)?.render_8bit(&tone, encoding.dither))
+            .map_err(|_| std::io::Error::other("frame writer stopped unexpectedly"))?;
+
+        errors.extend(writer.errors());
+    }
+
+    errors.extend(writer.finish());
+
+    let dur = before_run.elapsed();
+    println!(
+        "Completed! Rendered {} frames in {}.{:03} seconds. Output written to '{}'",
+        args.frames,
+        dur.as_secs(),
+        dur.subsec_millis(),
+        args.output.display()
+    );
+
+    if !errors.is_empty() {
+        return Err(FlameError::Frames(errors));
+    }
+
+    Ok(())
+}
+
+fn breed(args: BreedArgs) -> Result<(), FlameError> {
+    let (mut run_cfg, tone, encoding) = args.opts.to_configs();
+    let mut diagnostics = Diagnostics::new();
+    let opts = CrossoverOptions {
+        lerp_probability: diagnostics.clamped("--lerp-probability", args.lerp_probability, 0.0, 1.0),
+        mutation_rate: diagnostics.clamped("--mutation-rate", args.mutation_rate, 0.0, 1.0),
+        mutation_scale: args.mutation_scale,
+        ..CrossoverOptions::default()
+    };
+    for diagnostic in &diagnostics {
+        eprintln!("{}", diagnostic);
+    }
+
+    let first = FlameSource::from_path(&args.first)?.to_flame()?;
+    let second = FlameSource::from_path(&args.second)?.to_flame()?;
+
+    std::fs::create_dir_all(&args.output)?;
+    let journal = match args.resume {
+        true => Some(Journal::open(&args.output)?),
+        false => None,
+    };
+    // Draw a seed even when none is given, so that it can be recorded, and
+    // a resumed batch generates the same children.
+    let recorded_seed = journal.as_ref().and_then(|j| j.batch()["seed"].as_u64());
+    let seed = args.opts.seed.or(recorded_seed).unwrap_or_else(rand::random);
+    let mut rng = StdRng::seed_from_u64(seed);
+    // Renders are seeded too, so that children redone after an
+    // interruption come out as they would have.
+    run_cfg.seed = Some(seed);
+
+    let batch = serde_json::json!({
+        "command": "breed",
+        "parents": [args.first, args.second],
+        "seed": seed,
+        "count": args.count,
+        "lerp_probability": opts.lerp_probability,
+        "mutation_rate": opts.mutation_rate,
+        "mutation_scale": opts.mutation_scale,
+    });
+    let mut journal = match journal {
+        Some(journal) => {
+            journal.check_batch(&batch)?;
+            journal
+        }
+        None => Journal::create(&args.output, batch)?,
+    };
+
+    let mut meta = Meta::new(GenerationMethod::Breed);
+    meta.seed = Some(seed);
+    meta.parents = vec![ParentRef::from_path(&args.first)?, ParentRef::from_path(&args.second)?];
+    meta.parameters = serde_json::json!({
+        "lerp_probability": opts.lerp_probability,
+        "mutation_rate": opts.mutation_rate,
+        "mutation_scale": opts.mutation_scale,
+    });
+
+    let allocator = UniquePathAllocator::new(&args.output, args.on_collision);
+
+    // Each job's child number and name.
+    let mut names = Vec::new();
+    let mut jobs = Vec::new();
+    let mut skipped = 0;
+    for i in 0 .. args.count {
+        // Every child is generated, finished or not, so that the rest are
+        // drawn from the same point in the sequence as before.
+        let mut child = crossover(&first, &second, &mut rng, opts);
+        if journal.is_completed(i) {
+            skipped += 1;
+            continue;
+        }
+        args.opts.override_flame(&mut child)?;
+        if !is_valid(&child) {
+            eprintln!("Skipping child {}: parameters are not finite", i);
+            continue;
+        }
+
+        let stem = match journal.stem(i) {
+            Some(stem) => stem.to_string(),
+            None => {
+                let stem = allocator.reserve(&format!("child_{:03}", i), &["json", "png"])?.stem;
+                journal.claim(i, &stem)?;
+                stem
+            }
+        };
+        let mut descriptor = Vec::new();
+        FlameSource::from_flame(&child).with_meta(meta.clone()).to_writer(&mut descriptor)?;
+        write_atomically(&args.output.join(format!("{}.json", stem)), &descriptor)?;
+
+        let sink = FileSink::new(args.output.join(format!("{}.png", stem)))?.atomic();
+        jobs.push(RenderJob::new(child, run_cfg, tone, encoding, sink));
+        names.push((i, stem));
+    }
+    if skipped > 0 {
+        println!("Resuming: {} of {} children already finished", skipped, args.count);
+    }
+
+    let total = jobs.len();
+    let journal = Mutex::new(journal);
+    let results = render_all(jobs, args.parallel_flames, |id, event| {
+        let (item, stem) = &names[id];
+        match event {
+            JobEvent::Started => println!("Rendering {} ({} of {})...", stem, id + 1, total),
+            JobEvent::Finished(_) => {
+                let outputs = [PathBuf::from(format!("{}.json", stem)), PathBuf::from(format!("{}.png", stem))];
+                if let Err(e) = embed_thumbnail(&args.output.join(&outputs[0]), &args.output.join(&outputs[1])) {
+                    eprintln!("warning: could not add a thumbnail to {}: {}", stem, e);
+                }
+                if let Err(e) = journal.lock().unwrap().complete(*item, stem, &outputs) {
+                    eprintln!("warning: could not record {} as finished: {}", stem, e);
+                }
+            }
+            _ => {}
+        }
+    });
+
+    let mut errors = 0;
+    for r in &results {
+        if let Err(e) = &r.result {
+            eprintln!("error: {}: {}", names[r.id].1, e);
+            errors += 1;
+        }
+    }
+
+    println!("Completed! Wrote {} children to '{}'", total - errors, args.output.display());
+
+    if errors > 0 {
+        return Err(results.into_iter().find_map(|r| r.result.err()).unwrap());
+    }
+
+    Ok(())
+}
+
+fn analyze(args: AnalyzeArgs) -> Result<(), FlameError> {
+    if args.grid.contains(&0) {
+        return Err(FlameError::Validation("--grid must be at least one point in each direction".to_string()));
+    }
+    let flame: Flame = FlameSource::from_path(&args.input)?.to_flame()?;
+    let field = flame.vector_field(args.grid[0], args.grid[1]);
+
+    if let Some(path) = &args.vector_field {
+        field.to_buffer().to_rgb8()?.save(path)?;
+    }
+    if let Some(path) = &args.vectors {
+        if path.extension().is_some_and(|e| e.eq_ignore_ascii_case("json")) {
+            let json = serde_json::json!({
+                "width": field.width(),
+                "height": field.height(),
+                "vectors": field.iter().map(|(p, v)| serde_json::json!({
+                    "x": p[0],
+                    "y": p[1],
+                    "dx": v[0],
+                    "dy": v[1],
+                })).collect::<Vec<_>>(),
+            });
+            std::fs::write(path, serde_json::to_string_pretty(&json)?)?;
+        } else {
+            std::fs::write(path, field.to_csv())?;
+        }
+    }
+
+    Ok(())
+}
+
+fn estimate(args: EstimateArgs) -> Result<(), FlameError> {
+    let source = FlameSource::from_path(&args.input)?;
+    let (run_cfg, _, _, _) = args.opts.to_configs_for(source.render_settings());
+    let Some(target) = run_cfg.quality.map(|q| q.mean_rel_err) else {
+        return Err(FlameError::Validation("--target-quality is required".to_string()));
+    };
+    if !(target.is_finite() && target > 0.0) {
+        return Err(FlameError::Validation("--target-quality must be positive".to_string()));
+    }
+    run_cfg.check_size()?;
+    let mut flame = source.to_flame()?;
+    args.opts.override_flame(&mut flame)?;
+
+    let Some(estimate) = flame.estimate_iterations(run_cfg, target, args.calibration.0) else {
+        return Err(FlameError::Validation("nothing was plotted during calibration, so the bounds may miss the flame".to_string()));
+    };
+    // Rounded up to two significant figures, so it can be passed to -i.
+    let round = |n: u64| {
+        let unit = 10u64.pow(n.checked_ilog10().unwrap_or(0).saturating_sub(1));
+        SiCount(n.div_ceil(unit).saturating_mul(unit))
+    };
+    println!("iterations  {}", round(estimate.iters));
+    println!("time        {}", HumanDuration(std::time::Duration::from_secs(estimate.time.as_secs().max(1))));
+    println!("memory      {}", ByteSize(estimate.memory_bytes.div_ceil(1 << 20) << 20));
+    println!("hit rate    {:.1}%", estimate.hit_rate * 100.0);
+    println!("coverage    {:.1}%", estimate.coverage * 100.0);
+    println!();
+    println!("iterations needed by each tenth of the hit pixels, sparsest first:");
+    for (i, iters) in estimate.density_iters.iter().enumerate() {
+        println!("  {:>2}  {}", i + 1, round(*iters));
+    }
+    println!();
+    println!("calibrated on {} iterations at {}x{}, assuming that:", estimate.calibration_iters,
+        estimate.calibration_dims.0, estimate.calibration_dims.1);
+    println!("  - each pixel's error falls as 1/sqrt(N), its hits being Poisson distributed");
+    println!("  - the density is even within each pixel of the calibration histogram");
+    println!("  - pixels not hit during calibration can be ignored, which underestimates");
+    println!("    flames with large faint regions");
+    println!("  - the render runs as many iterations a second as the calibration did");
+    Ok(())
+}
+
+fn bench(args: BenchArgs) -> Result<(), FlameError> {
+    let flame = flame::presets::by_name(&args.flame).expect("flame names are validated when parsed");
+    let threads = args.threads
+        .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()))
+        .max(1);
+    let cfg = BenchConfig { repeats: args.repeats, ..BenchConfig::new(args.duration.0) };
+    if args.scaling {
+        return bench_scaling(&args, &flame, threads, cfg);
+    }
+    let variants = if args.matrix { &VARIANTS[..] } else { &VARIANTS[.. 1] };
+
+    if !args.json {
+        println!(
+            "{} at {}x{} on {} thread{}, {} repeats of {}",
+            args.flame, BENCH_SIZE, BENCH_SIZE, threads, if threads == 1 { "" } else { "s" },
+            cfg.repeats, HumanDuration(cfg.duration / cfg.repeats),
+        );
+        println!();
+        println!("{:<14} {:>10} {:>10} {:>7} {:>8}", "VARIANT", "ITERS/S", "PLOTS/S", "SPREAD", "SPEEDUP");
+    }
+    let mut results = Vec::new();
+    for variant in variants {
+        let m = bench_flame(&flame, variant, threads, cfg);
+        let speedup = results.first().map_or(1.0, |(_, base): &(_, Measurement)| {
+            m.mean_iters_per_sec() / base.mean_iters_per_sec()
+        });
+        if !args.json {
+            println!(
+                "{:<14} {:>9.2}M {:>9.2}M {:>6.1}% {:>7.2}x{}",
+                variant.name, m.mean_iters_per_sec() / 1e6, m.mean_plots_per_sec() / 1e6,
+                m.rel_spread() * 100.0, speedup, if m.steady { "" } else { "  (never settled)" },
+            );
+        }
+        results.push((variant, m));
+    }
+
+    if args.json {
+        let base = results[0].1.mean_iters_per_sec();
+        let json = serde_json::json!({
+            "flame": args.flame,
+            "width": BENCH_SIZE,
+            "height": BENCH_SIZE,
+            "threads": threads,
+            "seed": BENCH_SEED,
+            "duration_secs": cfg.duration.as_secs_f64(),
+            "repeats": cfg.repeats,
+            "variants": results.iter().map(|(variant, m)| serde_json::json!({
+                "name": variant.name,
+                "description": variant.description,
+                "iters_per_sec": m.mean_iters_per_sec(),
+                "plots_per_sec": m.mean_plots_per_sec(),
+                "rel_spread": m.rel_spread(),
+                "speedup": m.mean_iters_per_sec() / base,
+                "repeat_iters_per_sec": m.iters_per_sec,
+                "chunk_iters": m.chunk_iters,
+                "warmup_secs": m.warmup.as_secs_f64(),
+                "steady": m.steady,
+            })).collect::<Vec<_>>(),
+        });
+        println!("{}", serde_json::to_string_pretty(&json)?);
+    } else if args.matrix {
+        println!();
+        for variant in variants {
+            println!("{:<14} {}", variant.name, variant.description);
+        }
+    }
+    Ok(())
+}
+
+fn bench_scaling(args: &BenchArgs, flame: &Flame, max_threads: usize, cfg: BenchConfig) -> Result<(), FlameError> {
+    if !args.json {
+        println!(
+            "{} at {}x{} on 1 to {} threads, {} repeats of {}",
+            args.flame, BENCH_SIZE, BENCH_SIZE, max_threads, cfg.repeats, HumanDuration(cfg.duration / cfg.repeats),
+        );
+        println!();
+        println!("{:<8} {:>10} {:>7} {:>8} {:>11}", "THREADS", "ITERS/S", "SPREAD", "SPEEDUP", "EFFICIENCY");
+    }
+    let mut results = Vec::new();
+    for threads in scaling_threads(max_threads) {
+        let m = bench_flame(flame, &VARIANTS[0], threads, cfg);
+        let speedup = results.first().map_or(1.0, |(_, base): &(_, Measurement)| {
+            m.mean_iters_per_sec() / base.mean_iters_per_sec()
+        });
+        if !args.json {
+            println!(
+                "{:<8} {:>9.2}M {:>6.1}% {:>7.2}x {:>10.0}%{}",
+                threads, m.mean_iters_per_sec() / 1e6, m.rel_spread() * 100.0, speedup,
+                speedup / threads as f64 * 100.0, if m.steady { "" } else { "  (never settled)" },
+            );
+        }
+        results.push((threads, m));
+    }
+
+    if args.json {
+        let base = results[0].1.mean_iters_per_sec();
+        let json = serde_json::json!({
+            "flame": args.flame,
+            "width": BENCH_SIZE,
+            "height": BENCH_SIZE,
+            "seed": BENCH_SEED,
+            "duration_secs": cfg.duration.as_secs_f64(),
+            "repeats": cfg.repeats,
+            "scaling": results.iter().map(|(threads, m)| serde_json::json!({
+                "threads": threads,
+                "iters_per_sec": m.mean_iters_per_sec(),
+                "plots_per_sec": m.mean_plots_per_sec(),
+                "rel_spread": m.rel_spread(),
+                "speedup": m.mean_iters_per_sec() / base,
+                "efficiency": m.mean_iters_per_sec() / base / *threads as f64,
+                "repeat_iters_per_sec": m.iters_per_sec,
+                "chunk_iters": m.chunk_iters,
+                "warmup_secs": m.warmup.as_secs_f64(),
+                "steady": m.steady,
+            })).collect::<Vec<_>>(),
+        });
+        println!("{}", serde_json::to_string_pretty(&json)?);
+    }
+    Ok(())
+}
+
+fn presets() -> Result<(), FlameError> {
+    println!("{:<10} {:>6} {:>5} {:>5} {:>8}  {:<16} DESCRIPTION", "NAME", "ITERS", "SIZE", "GAMMA", "VIBRANCY", "HIGHLIGHTS");
+    for p in RenderPreset::ALL {
+        println!(
+            "{:<10} {:>6} {:>4}x {:>5} {:>8}  {:<16} {}",
+            p.name, SiCount(p.iters as u64).to_string(), p.scale, p.gamma, p.vibrancy, p.highlights.to_string(), p.description,
+        );
+    }
+    Ok(())
+}
+
+#[cfg(feature = "self-test")]
+fn self_test() -> Result<(), FlameError> {
+    let report = flame::selftest::run_all();
+    for group in &report.groups {
+        println!("{:<12} {}", group.name, if group.passed() { "ok" } else { "FAILED" });
+        for failure in &group.failures {
+            println!("  {}", failure);
+        }
+    }
+    let failed = report.groups.iter().filter(|g| !g.passed()).count();
+    match failed {
+        0 => Ok(()),
+        n => Err(FlameError::Validation(format!("{} of {} self-test groups failed", n, report.groups.len()))),
+    }
+}
+
+fn new(args: NewArgs) -> Result<(), FlameError> {
+    let Some(mut flame) = flame::presets::by_name(&args.example) else {
+        return Err(FlameError::Validation(format!(
+            "unknown example '{}' (expected one of {})",
+            args.example,
+            flame::presets::NAMES.join(", "),
+        )));
+    };
+
+    if let Some(harmony) = args.palette_harmony {
+        let mut distribution = HarmonyPaletteDistribution::new(harmony);
+        distribution.base_hue = args.palette_hue;
+        let mut rng = StdRng::seed_from_u64(args.seed.unwrap_or_else(rand::random));
+        flame.palette = distribution.sample(&mut rng);
+    }
+
+    let file = if args.force {
+        File::create(&args.output)?
+    } else {
+        File::options().write(true).create_new(true).open(&args.output)?
+    };
+    let source = FlameSource::from_flame(&flame).with_meta(Meta::new(GenerationMethod::Manual));
+    source.to_writer(file)?;
+
+    println!("Wrote '{}' to '{}'", args.example, args.output.display());
+
+    Ok(())
+}
+
+fn accum_info(input: PathBuf) -> Result<(), FlameError> {
+    let header = flame::accumulator::read_header(File::open(&input)?)?;
+    println!("{}", header);
+    Ok(())
+}
+
+fn accum_tonemap(args: AccumTonemapArgs) -> Result<(), FlameError> {
+    let source = args.descriptor.as_ref().map(FlameSource::from_path).transpose()?;
+    let (_, tone, encoding, _) = args.opts.to_configs_for(source.as_ref().and_then(FlameSource::render_settings));
+    let loaded = flame::accumulator::read(std::io::BufReader::new(File::open(&args.input)?))?;
+    let histogram = match source {
+        Some(source) => loaded.into_accumulator(&source.to_flame()?)?.into_buffer(),
+        None => loaded.into_buffer::<u32>()?,
+    };
+    let toned = histogram.tone_map(&tone);
+    let mut sink = FileSink::new(&args.output)?;
+    write_image(&encode_image(&toned, encoding, sink.format())?, &mut sink)?;
+    println!("Wrote '{}' to '{}'", args.input.display(), args.output.display());
+    Ok(())
+}
+
+/// Save the histogram of a render which could not be finished or written,
+/// saying why, and how to make the image from it once the problem is fixed.
+fn rescue_histogram(output: &Path, histogram: &Buffer<u32>, provenance: &Provenance, descriptor: Option<&Path>, why: &str) {
+    match flame::accumulator::rescue(output, histogram, provenance) {
+        Ok(path) => {
+            let descriptor = descriptor.map_or(String::new(), |d| format!(" --descriptor '{}'", d.display()));
+            eprintln!("{}, so the rendered histogram was saved to '{}'.", why, path.display());
+            eprintln!("Once the problem is fixed, make the image from it with the same tone mapping options:");
+            eprintln!("    flame accum tonemap '{}' '{}'{} [OPTIONS]", path.display(), output.display(), descriptor);
+        }
+        Err(e) => eprintln!("The rendered histogram could not be saved either: {}", e),
+    }
+}
+
+/// An image encoded from a tone mapped histogram at the depth of `format`.
+fn encode_image(toned: &Buffer<f64>, encoding: OutputEncoding, format: OutputFormat) -> Result<DynamicImage, BufferError> {
+    let image = encode_for(toned, encoding, format)?;
+    Ok(match format.depth() {
+        BitDepth::Eight => DynamicImage::ImageRgb8(image.into_rgb8()),
+        BitDepth::Float => image,
+    })
+}
+
+fn thumbnail_update(args: ThumbnailUpdateArgs) -> Result<(), FlameError> {
+    let mut paths = std::fs::read_dir(&args.dir)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    paths.retain(|p| p.extension().is_some_and(|e| e.eq_ignore_ascii_case("json")));
+    paths.sort();
+
+    let (mut updated, mut first_error) = (0, None);
+    for path in &paths {
+        match update_thumbnail(path, &args) {
+            Ok(true) => {
+                println!("Updated '{}'", path.display());
+                updated += 1;
+            }
+            Ok(false) => {}
+            // Other JSON files may share the directory.
+            Err(FlameError::Json(e)) => eprintln!("Skipping '{}': not a descriptor ({})", path.display(), e),
+            Err(e) => {
+                eprintln!("error: {}: {}", path.display(), e);
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+
+    println!("Completed! Updated {} thumbnail(s) in '{}'", updated, args.dir.display());
+    first_error.map_or(Ok(()), Err)
+}
+
+fn query(args: QueryArgs) -> Result<(), FlameError> {
+    let mut index = if args.no_index { SummaryIndex::new() } else { SummaryIndex::load(&args.dir) };
+    let listing = index.refresh(&args.dir)?;
+    if !args.no_index && index.is_changed() {
+        // The index only saves time, so searching goes on without it.
+        if let Err(e) = index.save(&args.dir) {
+            eprintln!("warning: could not write the index of '{}': {}", args.dir.display(), e);
+        }
+    }
+    for (path, why) in &listing.skipped {
+        eprintln!("Skipping '{}': {}", path.display(), why);
+    }
+
+    let matches: Vec<&Record> = listing.records.iter().filter(|r| r.matches(&args.conditions)).collect();
+    match args.format {
+        QueryFormat::Paths => {
+            for record in &matches {
+                println!("{}", record.path.display());
+            }
+        }
+        QueryFormat::Json => println!("{}", serde_json::to_string_pretty(&matches)?),
+        QueryFormat::Table => {
+            let names: Vec<String> = matches.iter().map(|r| r.path.display().to_string()).collect();
+            let width = names.iter().map(|n| n.chars().count()).max().unwrap_or(0).max(4);
+            println!("{:<width$}  {:>5}  {:>5}  {:>5}  {:>4}  {:>6}  VARIATIONS", "PATH", "FUNCS", "HUE", "LIGHT", "SYM", "ASPECT");
+            for (record, name) in matches.iter().zip(&names) {
+                let s = &record.summary;
+                // Symmetry groups are written C n for rotations alone and
+                // D n with reflections.
+                let symmetry = format!("{}{}", if s.symmetry.mirror { 'D' } else { 'C' }, s.symmetry.rotation);
+                let hue = s.palette.dominant_hue.map_or_else(|| "-".to_string(), |h| format!("{:.0}", h));
+                let variations: Vec<&str> = s.variations.iter().map(String::as_str).collect();
+                println!(
+                    "{:<width$}  {:>5}  {:>5}  {:>5.2}  {:>4}  {:>6.2}  {}",
+                    name, s.functions, hue, s.palette.lightness, symmetry, s.aspect, variations.join(",")
+                );
+            }
+            println!("{} of {} descriptor(s) match", matches.len(), listing.records.len());
+        }
+    }
+    Ok(())
+}
+
+/// Render and embed a thumbnail for the descriptor at `path` if it needs
+/// one, returning whether it did.
+fn update_thumbnail(path: &Path, args: &ThumbnailUpdateArgs) -> Result<bool, FlameError> {
+    let mut source = FlameSource::from_path(path)?;
+    if source.is_template() {
+        eprintln!("Skipping '{}', which is a template", path.display());
+        return Ok(false);
+    }
+    if !args.force && !source.thumbnail_is_stale() {
+        return Ok(false);
+    }
+    let (mut run_cfg, tone, encoding, _) = args.opts.to_configs_for(source.render_settings());
+    if args.opts.iters.is_none() && args.opts.target_quality.is_none() {
+        run_cfg.iters = THUMBNAIL_ITERS;
+    }
+    (run_cfg.width, run_cfg.height) = Thumbnail::dims_for(run_cfg.width, run_cfg.height);
+
+    let mut flame = FlameSource::from_path(path)?.to_flame()?;
+    args.opts.override_flame(&mut flame)?;
+    source.set_thumbnail_from_buffer(&flame.run(run_cfg)?.render_8bit(&tone, encoding.dither))?;
+    let mut descriptor = Vec::new();
+    source.to_writer(&mut descriptor)?;
+    write_atomically(path, &descriptor)?;
+    Ok(true)
+}
+
+/// Embed a thumbnail of the image at `image` in the descriptor at `path`.
+fn embed_thumbnail(path: &Path, image: &Path) -> Result<(), FlameError> {
+    let mut source = FlameSource::from_path(path)?;
+    if source.is_template() {
+        let message = format!("'{}' is a template, which cannot hold a thumbnail", path.display());
+        return Err(FlameError::Validation(message));
+    }
+    source.set_thumbnail(&image::open(image)?)?;
+    let mut descriptor = Vec::new();
+    source.to_writer(&mut descriptor)?;
+    write_atomically(path, &descriptor)?;
+    Ok(())
+}
+
+fn audit(args: AuditArgs) -> Result<(), FlameError> {
+    let flame: Flame = FlameSource::from_path(&args.input)?.to_flame()?;
+    let audit = flame.palette.audit();
+
+    let mut failed = false;
+    let mut check = |name: &str, value: f32, ok: bool| {
+        println!("{:<32} {:>8.4}  {}", name, value, if ok { "ok" } else { "FAIL" });
+        failed |= !ok;
+    };
+
+    check("min adjacent distance", audit.min_distance, audit.min_distance >= args.min_distance);
+    check("min distance (protanopia)", audit.protanopia, audit.protanopia >= args.min_cvd_distance);
+    check("min distance (deuteranopia)", audit.deuteranopia, audit.deuteranopia >= args.min_cvd_distance);
+    check("min distance (tritanopia)", audit.tritanopia, audit.tritanopia >= args.min_cvd_distance);
+    check("fraction out of print gamut", audit.out_of_gamut, audit.out_of_gamut <= args.max_out_of_gamut);
+
+    if failed {
+        return Err(FlameError::Validation("palette failed audit".to_string()));
+    }
+
+    Ok(())
+}
+
+fn export(args: ExportArgs) -> Result<(), FlameError> {
+    let flame: Flame = FlameSource::from_path(&args.input)?.to_flame()?;
+    let name = args.name.unwrap_or_else(|| {
+        args.input.file_stem().map_or("flame".into(), |s| s.to_string_lossy().into_owned())
+    });
+    let gradient = flame.palette.export(args.format, &name);
+
+    match &args.output {
+        Some(path) => std::fs::write(path, gradient)?,
+        None => print!("{}", gradient),
+    }
+
+    Ok(())
+}
+
+fn adjust(args: AdjustArgs) -> Result<(), FlameError> {
+    let mut source = FlameSource::from_path(&args.input)?;
+    let palette = source.palette()?.adjusted(&args.ops);
+    source.set_palette(&palette);
+    let ops: Vec<String> = args.ops.iter().map(ToString::to_string).collect();
+    source.record_history(format!("adjusted palette: {}", ops.join(", ")));
+
+    match &args.output {
+        Some(path) => source.to_writer(File::create(path)?)?,
+        None => {
+            source.to_writer(std::io::stdout().lock())?;
+            println!();
+        }
+    }
+
+    Ok(())
+}
+
+fn repl(args: ReplArgs) -> Result<(), FlameError> {
+    use std::io::{BufRead, Write};
+
+    let descriptor: serde_json::Value = serde_json::from_reader(File::open(&args.input)?)?;
+    let dir = args.input.parent().map(Path::to_path_buf).unwrap_or_default();
+    let (mut run_cfg, tone, encoding) = args.opts.to_configs();
+    if args.opts.threads.is_none() {
+        run_cfg.threads = flame::repl::default_threads();
+    }
+    let mut session = flame::repl::Session::new(descriptor, dir, run_cfg, tone, encoding)?;
+
+    let stdin = std::io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        print!("> ");
+        std::io::stdout().flush()?;
+        let Some(line) = lines.next() else { break };
+        match session.execute(&line?) {
+            Ok(flame::repl::Response::Text(text)) if text.is_empty() => {}
+            Ok(flame::repl::Response::Text(text)) => println!("{}", text),
+            Ok(flame::repl::Response::Preview(image)) => print_preview(&image, args.preview_width)?,
+            Ok(flame::repl::Response::Quit) => break,
+            Err(e) => eprintln!("error: {}", e),
+        }
+    }
+
+    Ok(())
+}
+
+/// Draw an image in the terminal with 24-bit color, two pixels to a
+/// character cell.
+fn print_preview(image: &Buffer<u8>, columns: u32) -> Result<(), FlameError> {
+    let rgb = image.to_rgb8()?;
+    let columns = columns.clamp(1, rgb.width().max(1));
+    let rows = (rgb.height() as u64 * columns as u64 / rgb.width().max(1) as u64).max(2) as u32;
+    let small = image::imageops::resize(&rgb, columns, rows, image::imageops::FilterType::Triangle);
+
+    let mut out = String::new();
+    for y in (0 .. small.height()).step_by(2) {
+        for x in 0 .. small.width() {
+            let top = small.get_pixel(x, y).0;
+            let bottom = small.get_pixel(x, (y + 1).min(small.height() - 1)).0;
+            out += &format!(
+                "\x1b[38;2;{};{};{}m\x1b[48;2;{};{};{}m\u{2580}",
+                top[0], top[1], top[2], bottom[0], bottom[1], bottom[2],
+            );
+        }
+        out += "\x1b[0m\n";
+    }
+    print!("{}", out);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The exit status of the command line `flame args...`.
+    fn status(args: &[&str]) -> u8 {
+        let cli = Cli::try_parse_from(std::iter::once("flame").chain(args.iter().copied())).unwrap();
+        run(cli).err().map_or(0, |e| e.exit_status())
+    }
+
+    #[test]
+    fn failures_exit_with_the_status_of_their_class() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name).to_str().unwrap().to_string();
+        std::fs::write(path("broken.json"), "{ \"functions\": [").unwrap();
+        assert_eq!(status(&["new", &path("gasket.json"), "--example", "gasket"]), 0);
+
+        // Parse errors.
+        assert_eq!(status(&[&path("broken.json"), &path("out.png")]), 2);
+        assert_eq!(status(&["palette", "audit", &path("broken.json")]), 2);
+        // I/O errors.
+        assert_eq!(status(&[&path("missing.json"), &path("out.png")]), 3);
+        assert_eq!(status(&["palette", "audit", &path("missing.json")]), 3);
+        // Validation failures.
+        assert_eq!(status(&["palette", "audit", &path("gasket.json"), "--min-distance", "10"]), 4);
+        assert_eq!(status(&[&path("gasket.json"), &path("out.unknown")]), 4);
+        assert!(!dir.path().join("out.png").exists());
+    }
+
+    #[test]
+    fn new_writes_each_example_without_overwriting() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in flame::presets::NAMES {
+            let path = dir.path().join(format!("{}.json", name));
+            let path = path.to_str().unwrap();
+            assert_eq!(status(&["new", path, "--example", name]), 0);
+            let flame = FlameSource::from_path(path).unwrap().to_flame().unwrap();
+            assert!(flame.eq_structural(&flame::presets::by_name(name).unwrap()));
+            assert_eq!(FlameSource::from_path(path).unwrap().meta().unwrap().method, GenerationMethod::Manual);
+        }
+
+        let path = dir.path().join("fern.json");
+        let path = path.to_str().unwrap();
+        std::fs::write(path, "kept").unwrap();
+        assert_eq!(status(&["new", path, "--example", "gasket"]), 3);
+        assert_eq!(std::fs::read_to_string(path).unwrap(), "kept");
+        assert_eq!(status(&["new", path, "--example", "gasket", "--force"]), 0);
+        assert!(FlameSource::from_path(path).is_ok());
+    }
+
+    #[test]
+    fn dry_runs_fail_on_findings_at_the_threshold() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("gasket.json");
+        let path = path.to_str().unwrap();
+        assert_eq!(status(&["new", path, "--example", "gasket"]), 0);
+
+        // A valid descriptor passes, and no image is written.
+        assert_eq!(status(&[path, "--dry-run"]), 0);
+        assert_eq!(status(&[path, "--dry-run", "--fail-on", "warning"]), 0);
+        // Warnings only fail when asked to.
+        assert_eq!(status(&[path, "--dry-run", "--vibrancy", "2"]), 0);
+        assert_eq!(status(&[path, "--dry-run", "--vibrancy", "2", "--fail-on", "warning"]), 4);
+        assert_eq!(status(&[path, "--dry-run", "--vibrancy", "2", "--deny-warnings"]), 4);
+        // Errors always fail.
+        assert_eq!(status(&[path, "--dry-run", "--gamma", "0"]), 4);
+        assert_eq!(dir.path().read_dir().unwrap().count(), 1);
+    }
+
+    #[test]
+    fn failed_checks_count_their_findings_once() {
+        let mut report = preflight(&flame::presets::gasket(), baseline_config(1), &ToneMapping {
+            vibrancy: 2.0,
+            gamma: 0.0,
+            ..ToneMapping::default()
+        });
+        assert!(check(&report, Severity::Error, false).is_err());
+        let message = |report: &PreflightReport, threshold| check(report, threshold, false).unwrap_err().to_string();
+        assert_eq!(message(&report, Severity::Error), "1 finding at or above error");
+        assert_eq!(message(&report, Severity::Warning), "2 findings at or above warning");
+
+        report.findings.retain(|f| f.severity < Severity::Error);
+        assert!(check(&report, Severity::Error, false).is_ok());
+        assert_eq!(message(&report, Severity::Warning), "1 finding at or above warning");
+    }
+
+    /// The render options of a command line.
+    fn options(args: &[&str]) -> RenderOptions {
+        #[derive(Parser)]
+        struct Options {
+            #[command(flatten)]
+            opts: RenderOptions,
+        }
+        Options::try_parse_from(std::iter::once("flame").chain(args.iter().copied())).unwrap().opts
+    }
+
+    #[test]
+    fn threads_default_to_ten_unless_given() {
+        assert_eq!(options(&[]).to_configs().0.threads, DEFAULT_THREADS);
+        assert_eq!(options(&["-t", "3"]).to_configs().0.threads, 3);
+        assert_eq!(options(&[]).threads, None);
+    }
+
+    #[test]
+    fn options_take_precedence_over_presets_and_presets_over_descriptors() {
+        let settings = RenderSettings {
+            iters: Some(123),
+            dims: Some([200, 100]),
+            gamma: Some(1.8),
+            vibrancy: Some(0.3),
+            ..RenderSettings::default()
+        };
+
+        // The descriptor's settings replace the defaults.
+        let (run, tone, _, sources) = options(&[]).to_configs_for(Some(&settings));
+        assert_eq!((run.iters, run.width, run.height, tone.gamma, tone.vibrancy), (123, 200, 100, 1.8, 0.3));
+        assert_eq!(sources["iters"], ConfigSource::Descriptor);
+        let (run, tone, _, sources) = options(&[]).to_configs_for(None);
+        assert_eq!((run.iters, run.width, tone.gamma), (5_000_000, 500, 2.2));
+        assert_eq!(sources["gamma"], ConfigSource::Default);
+
+        // A preset replaces them, scaling the descriptor's size.
+        let (run, tone, _, sources) = options(&["--preset", "final"]).to_configs_for(Some(&settings));
+        assert_eq!((run.iters, run.width, run.height, tone.gamma, tone.vibrancy), (100_000_000, 400, 200, 2.2, 0.5));
+        assert_eq!((sources["iters"], sources["width"]), (ConfigSource::Preset, ConfigSource::Preset));
+
+        // Explicit options replace both, and --dims is not scaled.
+        let (run, tone, _, sources) = options(&["--preset", "final", "--iters", "2M", "--dims", "64", "32", "--gamma", "3"])
+            .to_configs_for(Some(&settings));
+        assert_eq!((run.iters, run.width, run.height, tone.gamma, tone.vibrancy), (2_000_000, 64, 32, 3.0, 0.5));
+        assert_eq!((sources["iters"], sources["width"], sources["gamma"]), (ConfigSource::Option, ConfigSource::Option, ConfigSource::Option));
+        assert_eq!(sources["vibrancy"], ConfigSource::Preset);
+    }
+
+    #[test]
+    fn unknown_presets_are_refused_with_a_suggestion() {
+        let Err(e) = Cli::try_parse_from(["flame", "in.json", "out.png", "--preset", "finall"]) else {
+            panic!("unknown preset accepted");
+        };
+        assert!(e.to_string().contains("did you mean 'final'?"), "{}", e);
+        assert_eq!(status(&["presets"]), 0);
+    }
+
+    #[test]
+    fn check_intervals_must_be_at_least_one_iteration() {
+        let parse = |args: &[&str]| Cli::try_parse_from(["flame", "in.json", "out.png"].iter().chain(args));
+        for args in [["--stop-rel-change", "0.01", "--stop-interval"], ["--target-quality", "0.01", "--quality-interval"]] {
+            let Err(e) = parse(&[args[0], args[1], args[2], "0"]) else {
+                panic!("{} 0 accepted", args[2]);
+            };
+            assert!(e.to_string().contains("0 is not in 1.."), "{}", e);
+            assert!(parse(&[args[0], args[1], args[2], "1k"]).is_ok());
+        }
+        assert_eq!(options(&["--stop-rel-change", "0.01", "--stop-interval", "1"]).to_configs_for(None).0.stop_when.unwrap().check_interval_iters, 1);
+    }
+
+    #[test]
+    fn filament_boost_is_off_unless_asked_for() {
+        assert_eq!(options(&[]).to_configs_for(None).1.filament_boost, None);
+        let (_, tone, _, _) = options(&["--filament-boost", "0.5"]).to_configs_for(None);
+        assert_eq!(tone.filament_boost, Some(FilamentBoost { strength: 0.5, scale: 16 }));
+    }
+
+    #[test]
+    fn completions_offer_preset_and_mode_names() {
+        for shell in [Shell::Bash, Shell::Zsh, Shell::Fish, Shell::PowerShell] {
+            let mut script = Vec::new();
+            clap_complete::generate(shell, &mut Cli::command(), "flame", &mut script);
+            let script = String::from_utf8(script).unwrap();
+            let names = RenderPreset::names().chain(PlotMode::NAMES).chain(DitherMode::NAMES).chain(flame::presets::NAMES);
+            for name in names {
+                assert!(script.contains(name), "{} completion lacks {}", shell, name);
+            }
+        }
+        assert!(Cli::try_parse_from(["flame", "completions", "bash"]).is_ok());
+        assert!(Cli::try_parse_from(["flame", "completions", "tcsh"]).is_err());
+    }
+
+    #[test]
+    fn every_descriptor_setting_yields_to_presets_and_options() {
+        let settings = RenderSettings {
+            preserve_color: Some(true),
+            grayscale: Some(true),
+            background: Some(Color::rgb(1, 2, 3)),
+            highlights: Some(HighlightMode::PreserveHue),
+            dither: Some(DitherMode::Ordered8x8),
+            ..RenderSettings::default()
+        };
+        let (run, tone, encoding, sources) = options(&[]).to_configs_for(Some(&settings));
+        assert!(encoding.grayscale && run.monochrome && tone.preserve_color);
+        assert_eq!((tone.background, tone.highlights, encoding.dither), (Color::rgb(1, 2, 3), HighlightMode::PreserveHue, DitherMode::Ordered8x8));
+        for key in ["grayscale", "preserve_color", "background", "highlights", "dither"] {
+            assert_eq!(sources[key], ConfigSource::Descriptor, "{}", key);
+        }
+
+        // Presets set highlights, but not colors or dithering.
+        let (_, tone, _, sources) = options(&["--preset", "draft"]).to_configs_for(Some(&settings));
+        assert_eq!((tone.highlights, sources["highlights"]), (RenderPreset::DRAFT.highlights, ConfigSource::Preset));
+        assert_eq!((tone.background, sources["background"]), (Color::rgb(1, 2, 3), ConfigSource::Descriptor));
+
+        let (_, tone, encoding, sources) = options(&["--preset", "draft", "--background", "white", "--dither", "none", "--highlights", "clip"])
+            .to_configs_for(Some(&settings));
+        assert_eq!((tone.background, encoding.dither, tone.highlights), (Color::rgb(255, 255, 255), DitherMode::None, HighlightMode::Clip));
+        for key in ["background", "dither", "highlights"] {
+            assert_eq!(sources[key], ConfigSource::Option, "{}", key);
+        }
+    }
+
+    #[test]
+    fn descriptor_settings_apply_without_flags() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("gasket.json");
+        let settings = RenderSettings { iters: Some(20_000), dims: Some([40, 24]), grayscale: Some(true), ..RenderSettings::default() };
+        FlameSource::from_flame(&flame::presets::gasket()).with_render_settings(settings).to_writer(File::create(&input).unwrap()).unwrap();
+        let output = dir.path().join("out.png");
+        assert_eq!(status(&[input.to_str().unwrap(), output.to_str().unwrap()]), 0);
+        let image = image::open(&output).unwrap();
+        assert_eq!((image.width(), image.height()), (40, 24));
+        assert!(image.into_rgb8().pixels().all(|p| p[0] == p[1] && p[1] == p[2]));
+        // --dims replaces them.
+        assert_eq!(status(&[input.to_str().unwrap(), output.to_str().unwrap(), "--dims", "16", "16"]), 0);
+        assert_eq!(image::open(&output).unwrap().width(), 16);
+    }
+
+    #[test]
+    fn empty_renders_are_retried_and_their_bounds_written_when_asked() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("gasket.json");
+        let flame = Flame { bounds: Bounds::new(10.0, 11.0, 10.0, 11.0), ..flame::presets::gasket() };
+        let settings = RenderSettings { iters: Some(20_000), dims: Some([32, 32]), ..RenderSettings::default() };
+        FlameSource::from_flame(&flame).with_render_settings(settings).to_writer(File::create(&input).unwrap()).unwrap();
+        let (input, output) = (input.to_str().unwrap(), dir.path().join("out.png"));
+        let fitted = dir.path().join("bounds.json");
+        let lit = |path: &Path| image::open(path).unwrap().into_rgb8().pixels().any(|p| p.0 != [0; 3]);
+
+        // Without --auto-retry-bounds the empty image is only warned about.
+        assert_eq!(status(&[input, output.to_str().unwrap()]), 0);
+        assert!(!lit(&output));
+
+        assert_eq!(status(&[input, output.to_str().unwrap(), "--auto-retry-bounds", "--fitted-bounds", fitted.to_str().unwrap()]), 0);
+        assert!(lit(&output));
+        let json: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&fitted).unwrap()).unwrap();
+        let bounds: Vec<f64> = json["bounds"].as_array().unwrap().iter().map(|b| b.as_f64().unwrap()).collect();
+        let by_hand = flame::presets::gasket().bounds.to_array();
+        assert!(bounds.iter().zip(by_hand).all(|(a, b)| (a - b as f64).abs() < 0.1), "{:?}", bounds);
+    }
+
+    #[test]
+    fn extra_copies_are_encoded_with_the_same_jpeg_options() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("gasket.json");
+        let settings = RenderSettings { iters: Some(20_000), dims: Some([48, 32]), ..RenderSettings::default() };
+        FlameSource::from_flame(&flame::presets::gasket()).with_render_settings(settings).to_writer(File::create(&input).unwrap()).unwrap();
+        let path = |name: &str| dir.path().join(name).to_str().unwrap().to_string();
+        let args = [input.to_str().unwrap(), &path("out.jpg"), "--seed", "1", "--jpeg-quality", "20", "--progressive"];
+        assert_eq!(status(&[&args[..], &["--also-save", &path("copy.jpg"), "--also-save", &path("copy.png")]].concat()), 0);
+        assert_eq!(std::fs::read(path("copy.jpg")).unwrap(), std::fs::read(path("out.jpg")).unwrap());
+        assert!(image::open(path("copy.png")).is_ok());
+        // The options do change the file.
+        assert_eq!(status(&[input.to_str().unwrap(), &path("default.jpg"), "--seed", "1"]), 0);
+        assert_ne!(std::fs::read(path("default.jpg")).unwrap(), std::fs::read(path("out.jpg")).unwrap());
+    }
+
+    #[test]
+    fn interrupted_breeds_resume_to_the_uninterrupted_result() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name).to_str().unwrap().to_string();
+        assert_eq!(status(&["new", &path("fern.json"), "--example", "fern"]), 0);
+        assert_eq!(status(&["new", &path("swirl.json"), "--example", "swirl"]), 0);
+        let breed = |out: &str, resume: bool| {
+            let (fern, swirl) = (path("fern.json"), path("swirl.json"));
+            let mut args = vec!["breed", &fern, &swirl, out, "-n", "4", "--seed", "5", "--iters", "20k", "--dims", "24", "16"];
+            if resume {
+                args.push("--resume");
+            }
+            status(&args)
+        };
+        assert_eq!(breed(&path("reference"), false), 0);
+        assert_eq!(breed(&path("resumed"), false), 0);
+
+        // Interrupt the second batch while child 2 was being written: its
+        // files are claimed but empty, child 3 was never started, child 1's
+        // image has changed since, and the journal's last line is cut short.
+        let resumed = dir.path().join("resumed");
+        let journal = std::fs::read_to_string(resumed.join(flame::batch::JOURNAL_FILE)).unwrap();
+        let lost = |line: &str| line.contains("\"item\":3") || line.contains("\"item\":2") && line.contains("completed");
+        let kept: Vec<&str> = journal.lines().filter(|line| !lost(line)).collect();
+        std::fs::write(resumed.join(flame::batch::JOURNAL_FILE), kept.join("\n") + "\n{\"event\":\"claimed\",\"it").unwrap();
+        for name in ["child_002.json", "child_002.png"] {
+            std::fs::write(resumed.join(name), b"").unwrap();
+        }
+        for name in ["child_003.json", "child_003.png"] {
+            std::fs::remove_file(resumed.join(name)).unwrap();
+        }
+        std::fs::write(resumed.join("child_001.png"), b"not an image").unwrap();
+
+        assert_eq!(breed(&path("resumed"), true), 0);
+        let listing = |dir: &Path| {
+            let mut names: Vec<String> = std::fs::read_dir(dir).unwrap().map(|e| e.unwrap().file_name().into_string().unwrap()).collect();
+            names.sort();
+            names
+        };
+        assert_eq!(listing(&resumed), listing(&dir.path().join("reference")));
+        for i in 0 .. 4 {
+            let name = |ext: &str| format!("child_{:03}.{}", i, ext);
+            let image = |batch: &str| std::fs::read(dir.path().join(batch).join(name("png"))).unwrap();
+            assert_eq!(image("resumed"), image("reference"), "child {}", i);
+            let flame = |batch: &str| FlameSource::from_path(dir.path().join(batch).join(name("json"))).unwrap().to_flame().unwrap();
+            assert!(flame("resumed").eq_structural(&flame("reference")), "child {}", i);
+        }
+    }
+
+    #[cfg(feature = "self-test")]
+    #[test]
+    fn the_self_test_passes_on_this_build() {
+        assert_eq!(status(&["self-test"]), 0);
+    }
+}
@@ -0,0 +1,229 @@
+//! Records of how a descriptor came to be.
+//!
+//! Metadata is carried by descriptors but never by `Flame`, so it has no
+//! effect on rendering. Fields this version does not know about are kept
+//! and written back unchanged.
+
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use image::codecs::png::{CompressionType, FilterType, PngEncoder};
+use image::{DynamicImage, ImageEncoder};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use super::core::BufferError;
+use super::file::DescriptorError;
+
+/// Largest width or height of a thumbnail, and the most bytes its PNG may
+/// take, so that thumbnails never dominate the descriptors holding them.
+pub const THUMBNAIL_SIZE: u32 = 128;
+pub const MAX_THUMBNAIL_BYTES: usize = 64 * 1024;
+/// Iterations run for a thumbnail when rendering one from scratch, enough
+/// for an image of its size.
+pub const THUMBNAIL_ITERS: usize = 500_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GenerationMethod {
+    Manual,
+    Randgen,
+    Breed,
+    Migrated,
+}
+
+/// A file a descriptor was derived from.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ParentRef {
+    pub path: String,
+    /// FNV-1a hash of the file's contents, as 16 hex digits.
+    pub hash: String,
+}
+
+impl ParentRef {
+    pub fn from_path(path: impl AsRef<Path>) -> std::io::Result<ParentRef> {
+        let path = path.as_ref();
+        let bytes = std::fs::read(path)?;
+        Ok(ParentRef {
+            path: path.display().to_string(),
+            hash: format!("{:016x}", fnv1a(&bytes)),
+        })
+    }
+}
+
+pub(crate) fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |h, &b| (h ^ b as u64).wrapping_mul(0x100000001b3))
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Meta {
+    /// Version of this crate that created the descriptor.
+    pub version: String,
+    pub method: GenerationMethod,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    /// Settings of the generator, such as mutation rates.
+    #[serde(default, skip_serializing_if = "Value::is_null")]
+    pub parameters: Value,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub parents: Vec<ParentRef>,
+    /// Seconds since the Unix epoch.
+    pub created: u64,
+    /// The command line which created the descriptor.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub command: String,
+    /// Descriptions of changes made after creation, oldest first.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub history: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thumbnail: Option<Thumbnail>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+impl Meta {
+    /// Metadata for a descriptor created now by this process.
+    pub fn new(method: GenerationMethod) -> Meta {
+        Meta {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            method,
+            seed: None,
+            parameters: Value::Null,
+            parents: Vec::new(),
+            created: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()),
+            command: std::env::args().collect::<Vec<_>>().join(" "),
+            history: Vec::new(),
+            thumbnail: None,
+            extra: Map::new(),
+        }
+    }
+
+    /// Append a description of a change to the history.
+    pub fn record(&mut self, change: impl Into<String>) {
+        self.history.push(change.into());
+    }
+}
+
+/// A small preview of the image a descriptor renders to, so that file
+/// browsers can show it without rendering.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Thumbnail {
+    /// The image as a base64 encoded PNG.
+    pub png: String,
+    /// Hash of the descriptor the thumbnail was made from, leaving out its
+    /// metadata, as given by `FlameSource::content_hash`.
+    pub hash: String,
+}
+
+#[derive(Debug)]
+pub enum ThumbnailError {
+    Image(image::ImageError),
+    Buffer(BufferError),
+    /// The `png` field is not valid base64.
+    Encoding(base64::DecodeError),
+    /// The PNG is bigger than `MAX_THUMBNAIL_BYTES`.
+    TooLarge { bytes: usize },
+    /// The descriptor to hold the thumbnail cannot be hashed.
+    Descriptor(Box<DescriptorError>),
+}
+
+impl std::fmt::Display for ThumbnailError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ThumbnailError::Image(e) => write!(f, "invalid thumbnail image: {}", e),
+            ThumbnailError::Buffer(e) => write!(f, "{}", e),
+            ThumbnailError::Encoding(e) => write!(f, "thumbnail is not valid base64: {}", e),
+            ThumbnailError::TooLarge { bytes } => {
+                write!(f, "thumbnail is {} bytes, more than the {} allowed", bytes, MAX_THUMBNAIL_BYTES)
+            }
+            ThumbnailError::Descriptor(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for ThumbnailError {}
+
+impl From<image::ImageError> for ThumbnailError {
+    fn from(e: image::ImageError) -> Self { ThumbnailError::Image(e) }
+}
+
+impl From<BufferError> for ThumbnailError {
+    fn from(e: BufferError) -> Self { ThumbnailError::Buffer(e) }
+}
+
+impl Thumbnail {
+    /// Shrink `image` to fit within `THUMBNAIL_SIZE` on each side, keeping
+    /// its shape, and encode it for a descriptor with content hash `hash`.
+    pub fn from_image(image: &DynamicImage, hash: String) -> Result<Thumbnail, ThumbnailError> {
+        let small = image.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE).into_rgb8();
+        let mut png = Vec::new();
+        PngEncoder::new_with_quality(&mut png, CompressionType::Best, FilterType::Adaptive)
+            .write_image(small.as_raw(), small.width(), small.height(), image::ColorType::Rgb8)?;
+        if png.len() > MAX_THUMBNAIL_BYTES {
+            return Err(ThumbnailError::TooLarge { bytes: png.len() });
+        }
+        Ok(Thumbnail { png: BASE64.encode(png), hash })
+    }
+
+    /// Dimensions of the thumbnail of an image `width` by `height` pixels:
+    /// as large as fits within `THUMBNAIL_SIZE`, but no larger than the
+    /// image.
+    pub fn dims_for(width: usize, height: usize) -> (usize, usize) {
+        let scale = (THUMBNAIL_SIZE as f64 / width.max(height).max(1) as f64).min(1.0);
+        let fit = |n: usize| ((n as f64 * scale).round() as usize).max(2).min(n);
+        (fit(width), fit(height))
+    }
+
+    /// Decode the image. Thumbnails too large to have been written by
+    /// `from_image` are refused before they are decoded.
+    pub fn to_image(&self) -> Result<DynamicImage, ThumbnailError> {
+        let bytes = base64::decoded_len_estimate(self.png.len());
+        if bytes > MAX_THUMBNAIL_BYTES + 2 {
+            return Err(ThumbnailError::TooLarge { bytes });
+        }
+        let png = BASE64.decode(&self.png).map_err(ThumbnailError::Encoding)?;
+        if png.len() > MAX_THUMBNAIL_BYTES {
+            return Err(ThumbnailError::TooLarge { bytes: png.len() });
+        }
+        Ok(image::load_from_memory_with_format(&png, image::ImageFormat::Png)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn thumbnails_fit_the_size_and_never_enlarge() {
+        assert_eq!(Thumbnail::dims_for(512, 256), (128, 64));
+        assert_eq!(Thumbnail::dims_for(90, 300), (38, 128));
+        assert_eq!(Thumbnail::dims_for(64, 32), (64, 32));
+        // Slivers keep two pixels across where the image has them.
+        assert_eq!(Thumbnail::dims_for(1000, 10), (128, 2));
+        assert_eq!(Thumbnail::dims_for(1000, 1), (128, 1));
+        assert_eq!(Thumbnail::dims_for(1, 1), (1, 1));
+    }
+
+    #[test]
+    fn thumbnails_decode_to_the_shrunk_image() {
+        let image = DynamicImage::ImageRgb8(image::RgbImage::from_fn(300, 150, |x, y| image::Rgb([x as u8, y as u8, 99])));
+        let thumbnail = Thumbnail::from_image(&image, "0123".to_string()).unwrap();
+        let decoded = thumbnail.to_image().unwrap().into_rgb8();
+        assert_eq!(decoded.dimensions(), (128, 64));
+        assert_eq!(decoded, image.thumbnail(128, 128).into_rgb8());
+    }
+
+    #[test]
+    fn oversized_thumbnails_are_refused() {
+        for bytes in [MAX_THUMBNAIL_BYTES + 1, 10 * MAX_THUMBNAIL_BYTES] {
+            let thumbnail = Thumbnail { png: BASE64.encode(vec![0; bytes]), hash: String::new() };
+            let error = thumbnail.to_image().unwrap_err();
+            assert!(matches!(error, ThumbnailError::TooLarge { bytes: b } if b >= bytes), "{:?}", error);
+            assert!(error.to_string().ends_with(&format!("more than the {} allowed", MAX_THUMBNAIL_BYTES)), "{}", error);
+        }
+        let garbled = Thumbnail { png: "not base64!".to_string(), hash: String::new() };
+        assert!(matches!(garbled.to_image(), Err(ThumbnailError::Encoding(_))));
+    }
+}
@@ -0,0 +1,297 @@
+//! Turning names into file names, and claiming output paths without
+//! overwriting what is already there.
+//!
+//! Paths are claimed by creating their files, which the file system does
+//! atomically, so several processes writing to one directory never choose
+//! the same name. Numbered sequences, whose names are fixed by their index,
+//! may instead replace the files a previous run left.
+
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+
+use super::meta::fnv1a;
+
+/// Make a name safe to use as a file name: compatibility decomposed,
+/// lowercased, with accents on Latin letters dropped and every run of
+/// characters other than letters and digits made a single hyphen. Letters
+/// of other scripts are kept as they are.
+///
+/// The result is at most `max_len` bytes, and never empty: names with no
+/// letters or digits become a hash of the name, in hex.
+pub fn slugify(name: &str, max_len: usize) -> String {
+    let max_len = max_len.max(1);
+    let mut base_is_ascii = true;
+    let stripped = name.nfkd().filter(|&c| {
+        if is_combining_mark(c) {
+            !base_is_ascii
+        } else {
+            base_is_ascii = c.is_ascii();
+            true
+        }
+    });
+
+    let mut slug = String::new();
+    let mut gap = false;
+    for c in stripped.nfc().flat_map(char::to_lowercase) {
+        if !(c.is_alphanumeric() || is_combining_mark(c)) {
+            gap = true;
+            continue;
+        }
+        let hyphen = gap && !slug.is_empty();
+        if slug.len() + usize::from(hyphen) + c.len_utf8() > max_len {
+            break;
+        }
+        if hyphen {
+            slug.push('-');
+        }
+        slug.push(c);
+        gap = false;
+    }
+
+    if slug.is_empty() {
+        let mut hash = format!("{:016x}", fnv1a(name.as_bytes()));
+        hash.truncate(max_len.min(8));
+        return hash;
+    }
+    slug
+}
+
+/// What to do when the path asked for is taken.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CollisionPolicy {
+    /// Add `-2`, `-3` and so on to the name until it is free.
+    #[default]
+    Suffix,
+    /// Fail with an `AlreadyExists` error.
+    Error,
+    /// Replace the file, for names such as frame numbers which a re-run
+    /// is expected to reuse.
+    Overwrite,
+}
+
+impl CollisionPolicy {
+    /// Names of the policies, as accepted by `from_str`.
+    pub const NAMES: [&'static str; 3] = ["suffix", "error", "overwrite"];
+}
+
+impl std::str::FromStr for CollisionPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "suffix" => Ok(CollisionPolicy::Suffix),
+            "error" => Ok(CollisionPolicy::Error),
+            "overwrite" => Ok(CollisionPolicy::Overwrite),
+            _ => Err(format!("unknown collision policy '{}' (expected suffix, error or overwrite)", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for CollisionPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CollisionPolicy::Suffix => write!(f, "suffix"),
+            CollisionPolicy::Error => write!(f, "error"),
+            CollisionPolicy::Overwrite => write!(f, "overwrite"),
+        }
+    }
+}
+
+/// Files claimed together under one name, each created empty.
+#[derive(Debug)]
+pub struct Reservation {
+    /// The name the files were given, before their extensions.
+    pub stem: String,
+    /// The path and open file for each extension, in the order asked for.
+    pub files: Vec<(PathBuf, File)>,
+}
+
+/// Claims paths in a directory which nothing else has taken.
+#[derive(Debug, Clone)]
+pub struct UniquePathAllocator {
+    dir: PathBuf,
+    policy: CollisionPolicy,
+}
+
+impl UniquePathAllocator {
+    pub fn new(dir: impl Into<PathBuf>, policy: CollisionPolicy) -> Self {
+        UniquePathAllocator { dir: dir.into(), policy }
+    }
+
+    /// Create `stem.EXT` in the directory for each of `extensions`, so
+    /// that related outputs such as a descriptor and its image share a
+    /// name. If any of them exists, none are kept and the policy decides
+    /// what happens next; under `Overwrite` existing files are truncated.
+    pub fn reserve(&self, stem: &str, extensions: &[&str]) -> io::Result<Reservation> {
+        for n in 1 .. {
+            let candidate = if n == 1 { stem.to_string() } else { format!("{}-{}", stem, n) };
+            match self.try_reserve(&candidate, extensions) {
+                Ok(files) => return Ok(Reservation { stem: candidate, files }),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists && self.policy == CollisionPolicy::Suffix => {}
+                Err(e) => return Err(e),
+            }
+        }
+        unreachable!("ran out of suffixes")
+    }
+
+    /// Create a single file `stem.extension`, as `reserve` does.
+    pub fn allocate(&self, stem: &str, extension: &str) -> io::Result<(File, PathBuf)> {
+        let (path, file) = self.reserve(stem, &[extension])?.files.pop().expect("one file per extension");
+        Ok((file, path))
+    }
+
+    fn try_reserve(&self, stem: &str, extensions: &[&str]) -> io::Result<Vec<(PathBuf, File)>> {
+        let mut files = Vec::with_capacity(extensions.len());
+        let mut options = File::options();
+        match self.policy {
+            CollisionPolicy::Overwrite => options.write(true).create(true).truncate(true),
+            _ => options.write(true).create_new(true),
+        };
+        for ext in extensions {
+            let path = self.dir.join(format!("{}.{}", stem, ext));
+            match options.open(&path) {
+                Ok(file) => files.push((path, file)),
+                Err(e) => {
+                    let e = match e.kind() {
+                        io::ErrorKind::AlreadyExists => {
+                            io::Error::new(e.kind(), format!("'{}' already exists", path.display()))
+                        }
+                        _ => e,
+                    };
+                    // Give back what was claimed, so the name stays free for
+                    // whoever holds the rest of it.
+                    for (path, file) in files {
+                        drop(file);
+                        let _ = std::fs::remove_file(path);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        Ok(files)
+    }
+}
+
+/// Write `bytes` to `path` by writing them to a hidden file beside it and
+/// renaming that over it once they are on disk, so that `path` never holds
+/// part of its contents, even if the process is interrupted.
+pub fn write_atomically(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let temp = partial_path(path)?;
+    let mut file = File::create(&temp)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    std::fs::rename(&temp, path)
+}
+
+/// The hidden file beside `path` which its contents are written to before
+/// being renamed into place.
+pub(crate) fn partial_path(path: &Path) -> io::Result<PathBuf> {
+    let name = path.file_name().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, format!("'{}' is not a file path", path.display()))
+    })?;
+    Ok(path.with_file_name(format!(".{}.partial", name.to_string_lossy())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::sync::Barrier;
+
+    #[test]
+    fn slugs_are_lowercase_words_joined_by_hyphens() {
+        assert_eq!(slugify("--Hello,  World!--", 64), "hello-world");
+        assert_eq!(slugify("Crème Brûlée", 64), "creme-brulee");
+        // Compatibility forms are decomposed.
+        assert_eq!(slugify("ＡＢＣ ﬁre", 64), "abc-fire");
+    }
+
+    #[test]
+    fn path_separators_never_survive() {
+        for name in ["../../etc/passwd", "a/b\\c", "C:\\flames\\x", "./.", ".."] {
+            let slug = slugify(name, 64);
+            assert!(!slug.contains(['/', '\\', ':', '.']), "{:?} became {:?}", name, slug);
+            assert!(!slug.is_empty());
+        }
+        assert_eq!(slugify("a/b\\c", 64), "a-b-c");
+    }
+
+    #[test]
+    fn emoji_alone_become_a_hash() {
+        let slug = slugify("🔥🌀", 64);
+        assert_eq!(slug.len(), 8);
+        assert!(slug.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_eq!(slug, slugify("🔥🌀", 64));
+        assert_ne!(slug, slugify("🌀🔥", 64));
+        assert_eq!(slugify("fire 🔥 spiral", 64), "fire-spiral");
+    }
+
+    #[test]
+    fn right_to_left_text_is_kept() {
+        assert_eq!(slugify("שלום עולם", 64), "שלום-עולם");
+        assert_eq!(slugify("مرحبا، بالعالم", 64), "مرحبا-بالعالم");
+    }
+
+    #[test]
+    fn long_names_are_cut_to_the_byte_limit() {
+        let ascii = "a".repeat(500);
+        assert_eq!(slugify(&ascii, 64), "a".repeat(64));
+        // Two bytes a letter, cut between letters.
+        let hebrew = "ש".repeat(500);
+        let slug = slugify(&hebrew, 63);
+        assert_eq!(slug, "ש".repeat(31));
+        // No trailing hyphen where a cut falls after a word.
+        let words = "ab ".repeat(200);
+        let slug = slugify(&words, 5);
+        assert_eq!(slug, "ab-ab");
+        assert_eq!(slugify(&"!".repeat(500), 4).len(), 4);
+    }
+
+    #[test]
+    fn suffixes_are_added_or_refused_by_policy() {
+        let dir = tempfile::tempdir().unwrap();
+        let suffix = UniquePathAllocator::new(dir.path(), CollisionPolicy::Suffix);
+        let first = suffix.reserve("child", &["json", "png"]).unwrap();
+        assert_eq!(first.stem, "child");
+        // Only one of the pair exists, but the pair is moved on together.
+        std::fs::remove_file(dir.path().join("child.json")).unwrap();
+        let second = suffix.reserve("child", &["json", "png"]).unwrap();
+        assert_eq!(second.stem, "child-2");
+        assert!(!dir.path().join("child.json").exists());
+
+        let error = UniquePathAllocator::new(dir.path(), CollisionPolicy::Error);
+        let e = error.allocate("child", "png").unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::AlreadyExists);
+
+        std::fs::write(dir.path().join("child.png"), b"old").unwrap();
+        let overwrite = UniquePathAllocator::new(dir.path(), CollisionPolicy::Overwrite);
+        let (_, path) = overwrite.allocate("child", "png").unwrap();
+        assert_eq!(path, dir.path().join("child.png"));
+        assert!(std::fs::read(path).unwrap().is_empty());
+    }
+
+    #[test]
+    fn concurrent_allocations_never_collide() {
+        let dir = tempfile::tempdir().unwrap();
+        let allocator = UniquePathAllocator::new(dir.path(), CollisionPolicy::Suffix);
+        let (threads, each) = (8, 25);
+        let barrier = Barrier::new(threads);
+        let stems: Vec<String> = std::thread::scope(|s| {
+            let handles: Vec<_> = (0 .. threads).map(|_| s.spawn(|| {
+                barrier.wait();
+                (0 .. each)
+                    .map(|_| allocator.reserve("frame", &["json", "png"]).unwrap().stem)
+                    .collect::<Vec<_>>()
+            })).collect();
+            handles.into_iter().flat_map(|h| h.join().unwrap()).collect()
+        });
+
+        let unique: HashSet<&String> = stems.iter().collect();
+        assert_eq!(unique.len(), threads * each);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 2 * threads * each);
+    }
+}
@@ -0,0 +1,612 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Cursor, Write};
+use std::path::{Path, PathBuf};
+
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use image::{DynamicImage, ImageError, ImageOutputFormat, RgbImage};
+use jpeg_encoder::{ColorType, Encoder, EncodingError, SamplingFactor};
+
+use super::core::*;
+use super::naming::write_atomically;
+use super::streaming::write_chunk;
+
+/// Resolution the color of JPEG output is stored at, relative to its
+/// brightness.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChromaSubsampling {
+    /// Color at full resolution (4:4:4), which keeps thin colored filaments
+    /// from bleeding into their surroundings.
+    #[default]
+    Full,
+    /// Color at half resolution in each direction (4:2:0), for smaller files.
+    Half,
+}
+
+impl ChromaSubsampling {
+    /// Names of the modes, as accepted by `from_str`.
+    pub const NAMES: [&'static str; 2] = ["444", "420"];
+}
+
+impl std::str::FromStr for ChromaSubsampling {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "444" | "4:4:4" => Ok(ChromaSubsampling::Full),
+            "420" | "4:2:0" => Ok(ChromaSubsampling::Half),
+            _ => Err(format!("unknown chroma subsampling '{}' (expected 444 or 420)", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for ChromaSubsampling {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChromaSubsampling::Full => write!(f, "444"),
+            ChromaSubsampling::Half => write!(f, "420"),
+        }
+    }
+}
+
+/// Image encodings that can be delivered to a sink.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Png,
+    Jpeg {
+        /// Between 1 and 100.
+        quality: u8,
+        subsampling: ChromaSubsampling,
+        /// Encode in several passes of increasing detail.
+        progressive: bool,
+    },
+    /// Plain text portable graymap (P2), for reviewing images in diffs.
+    Pgm,
+    /// Plain text portable pixmap (P3).
+    Ppm,
+    /// Binary portable bitmap (P4), one bit to a pixel, for cutting and
+    /// tracing tools. Pixels darker than mid gray are set.
+    Pbm,
+    /// OpenEXR with 32-bit float channels, which keeps the tone mapped
+    /// values unquantized.
+    Exr,
+}
+
+impl OutputFormat {
+    /// JPEG at quality 90 with full resolution color.
+    pub const JPEG: OutputFormat = OutputFormat::Jpeg {
+        quality: 90,
+        subsampling: ChromaSubsampling::Full,
+        progressive: false,
+    };
+
+    /// Names of the formats, as accepted by `from_str`.
+    pub const NAMES: [&'static str; 6] = ["png", "jpeg", "pgm", "ppm", "pbm", "exr"];
+
+    /// Guess the format from a file extension.
+    pub fn from_path(path: &Path) -> Option<OutputFormat> {
+        path.extension()?.to_str()?.parse().ok()
+    }
+
+    /// Number format images should be encoded with for this format.
+    pub fn depth(&self) -> BitDepth {
+        match self {
+            OutputFormat::Exr => BitDepth::Float,
+            _ => BitDepth::Eight,
+        }
+    }
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = String;
+
+    /// Parses a format name or file extension. JPEG is at quality 90 with
+    /// full resolution color.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "png" => Ok(OutputFormat::Png),
+            "jpg" | "jpeg" => Ok(OutputFormat::JPEG),
+            "pgm" => Ok(OutputFormat::Pgm),
+            "ppm" => Ok(OutputFormat::Ppm),
+            "pbm" => Ok(OutputFormat::Pbm),
+            "exr" => Ok(OutputFormat::Exr),
+            _ => Err(format!("unknown output format '{}' (expected png, jpeg, pgm, ppm, pbm or exr)", s)),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SinkError {
+    Io(std::io::Error),
+    /// A file could not be created or written.
+    File { path: PathBuf, error: std::io::Error },
+    Image(ImageError),
+    UnknownFormat(PathBuf),
+    Buffer(BufferError),
+    Jpeg(EncodingError),
+    /// JPEG images can be at most 65535 pixels wide and high.
+    TooLargeForJpeg { width: u32, height: u32 },
+    /// An image being streamed has no pixels.
+    EmptyImage,
+    /// A row given to a streaming encoder had the wrong number of bytes.
+    RowLength { row: usize, expected: usize, actual: usize },
+    /// A streaming encoder was given fewer rows than the image's height.
+    TooFewRows { expected: usize, actual: usize },
+    /// A streaming encoder was given more rows than the image's height.
+    TooManyRows { expected: usize },
+}
+
+impl std::fmt::Display for SinkError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SinkError::Io(e) => write!(f, "could not write image: {}", e),
+            SinkError::File { path, error } => write!(f, "could not write '{}': {}", path.display(), error),
+            SinkError::Image(e) => write!(f, "could not encode image: {}", e),
+            SinkError::UnknownFormat(p) => {
+                write!(f, "cannot infer image format of '{}' (expected .png, .jpg, .jpeg, .pgm, .ppm, .pbm or .exr)", p.display())
+            }
+            SinkError::Buffer(e) => write!(f, "could not make image: {}", e),
+            SinkError::Jpeg(e) => write!(f, "could not encode image: {}", e),
+            SinkError::TooLargeForJpeg { width, height } => {
+                write!(f, "{}x{} image is too large for JPEG, which allows at most 65535x65535", width, height)
+            }
+            SinkError::EmptyImage => write!(f, "cannot write an image with no pixels"),
+            SinkError::RowLength { row, expected, actual } => {
+                write!(f, "row {} has {} bytes, expected {}", row, actual, expected)
+            }
+            SinkError::TooFewRows { expected, actual } => write!(f, "image ended after {} of {} rows", actual, expected),
+            SinkError::TooManyRows { expected } => write!(f, "image has more than its {} rows", expected),
+        }
+    }
+}
+
+impl std::error::Error for SinkError {}
+
+impl From<std::io::Error> for SinkError {
+    fn from(e: std::io::Error) -> Self { SinkError::Io(e) }
+}
+
+impl From<ImageError> for SinkError {
+    fn from(e: ImageError) -> Self { SinkError::Image(e) }
+}
+
+impl From<BufferError> for SinkError {
+    fn from(e: BufferError) -> Self { SinkError::Buffer(e) }
+}
+
+impl From<EncodingError> for SinkError {
+    fn from(e: EncodingError) -> Self { SinkError::Jpeg(e) }
+}
+
+/// A destination for encoded images.
+pub trait ImageSink {
+    fn write(&mut self, format: OutputFormat, bytes: &[u8]) -> Result<(), SinkError>;
+
+    /// The format images delivered to this sink should be encoded in.
+    fn format(&self) -> OutputFormat {
+        OutputFormat::Png
+    }
+}
+
+/// Writes images to a file, in the format given by its extension.
+pub struct FileSink {
+    path: PathBuf,
+    format: OutputFormat,
+    atomic: bool,
+}
+
+impl FileSink {
+    pub fn new(path: impl Into<PathBuf>) -> Result<FileSink, SinkError> {
+        let path = path.into();
+        match OutputFormat::from_path(&path) {
+            Some(format) => Ok(FileSink { path, format, atomic: false }),
+            None => Err(SinkError::UnknownFormat(path)),
+        }
+    }
+
+    /// A sink writing `format` whatever the path's extension.
+    pub fn new_with_format(path: impl Into<PathBuf>, format: OutputFormat) -> FileSink {
+        FileSink { path: path.into(), format, atomic: false }
+    }
+
+    /// Write the image beside the path and rename it into place once it is
+    /// complete, so that the path never holds a partial image.
+    pub fn atomic(mut self) -> Self {
+        self.atomic = true;
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Encode in `format` instead of the one given by the extension.
+    pub fn with_format(mut self, format: OutputFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Check that the path can be written, so that a long render fails
+    /// before it starts rather than once it is done. An existing file is
+    /// opened without being changed, and otherwise an empty file is created
+    /// in its place and removed again.
+    pub fn check_writable(&self) -> Result<(), SinkError> {
+        let checked = match OpenOptions::new().append(true).open(&self.path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                File::create_new(&self.path).and_then(|_| std::fs::remove_file(&self.path))
+            }
+            opened => opened.map(drop),
+        };
+        checked.map_err(|error| self.error(error))
+    }
+
+    fn error(&self, error: io::Error) -> SinkError {
+        SinkError::File { path: self.path.clone(), error }
+    }
+}
+
+impl ImageSink for FileSink {
+    fn write(&mut self, _format: OutputFormat, bytes: &[u8]) -> Result<(), SinkError> {
+        if self.atomic {
+            return write_atomically(&self.path, bytes).map_err(|e| self.error(e));
+        }
+        let written = File::create(&self.path).and_then(|file| {
+            let mut file = BufWriter::new(file);
+            file.write_all(bytes)?;
+            file.flush()
+        });
+        written.map_err(|e| self.error(e))
+    }
+
+    fn format(&self) -> OutputFormat {
+        self.format
+    }
+}
+
+/// Writes images to any `io::Write`.
+pub struct WriterSink<W: Write> {
+    writer: W,
+    format: OutputFormat,
+}
+
+impl<W: Write> WriterSink<W> {
+    pub fn new(writer: W, format: OutputFormat) -> Self {
+        WriterSink { writer, format }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write> ImageSink for WriterSink<W> {
+    fn write(&mut self, _format: OutputFormat, bytes: &[u8]) -> Result<(), SinkError> {
+        self.writer.write_all(bytes)?;
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn format(&self) -> OutputFormat {
+        self.format
+    }
+}
+
+/// Keeps every image delivered to it in memory.
+pub struct VecSink {
+    pub format: OutputFormat,
+    pub images: Vec<Vec<u8>>,
+}
+
+impl VecSink {
+    pub fn new(format: OutputFormat) -> Self {
+        VecSink { format, images: Vec::new() }
+    }
+}
+
+impl ImageSink for VecSink {
+    fn write(&mut self, _format: OutputFormat, bytes: &[u8]) -> Result<(), SinkError> {
+        self.images.push(bytes.to_vec());
+        Ok(())
+    }
+
+    fn format(&self) -> OutputFormat {
+        self.format
+    }
+}
+
+/// Encode an image in memory in the sink's format and deliver it.
+///
+/// Float formats store the image's channels as they are, so only images
+/// with float channels, such as those from `encode_for`, keep more than 8
+/// bits in them.
+pub fn write_image(img: &DynamicImage, sink: &mut dyn ImageSink) -> Result<(), SinkError> {
+    let format = sink.format();
+    let mut bytes = Cursor::new(Vec::new());
+    match format {
+        OutputFormat::Png => img.write_to(&mut bytes, ImageOutputFormat::Png)?,
+        OutputFormat::Jpeg { quality, subsampling, progressive } => {
+            encode_jpeg(img, quality, subsampling, progressive, bytes.get_mut())?;
+        }
+        OutputFormat::Pgm => {
+            let gray = img.to_luma8();
+            let (width, height) = (gray.width() as usize, gray.height() as usize);
+            bytes.get_mut().extend(pnm_ascii("P2", width, height, 1, gray.as_raw()).into_bytes());
+        }
+        OutputFormat::Ppm => {
+            let rgb = img.to_rgb8();
+            let (width, height) = (rgb.width() as usize, rgb.height() as usize);
+            bytes.get_mut().extend(pnm_ascii("P3", width, height, 3, rgb.as_raw()).into_bytes());
+        }
+        OutputFormat::Pbm => {
+            let gray = img.to_luma8();
+            let bits = gray.as_raw().iter().map(|&v| v < 128).collect();
+            let bitmap = Bitmap::from_bits(gray.width() as usize, gray.height() as usize, bits)
+                .expect("one sample per pixel");
+            encode_pbm(&bitmap, bytes.get_mut());
+        }
+        OutputFormat::Exr => DynamicImage::ImageRgb32F(img.to_rgb32f()).write_to(&mut bytes, ImageOutputFormat::OpenExr)?,
+    }
+    sink.write(format, bytes.get_ref())
+}
+
+fn encode_jpeg(
+    img: &DynamicImage,
+    quality: u8,
+    subsampling: ChromaSubsampling,
+    progressive: bool,
+    out: &mut Vec<u8>,
+) -> Result<(), SinkError> {
+    let (width, height) = (img.width(), img.height());
+    let too_large = || SinkError::TooLargeForJpeg { width, height };
+    let (w, h) = (u16::try_from(width).map_err(|_| too_large())?, u16::try_from(height).map_err(|_| too_large())?);
+
+    let mut encoder = Encoder::new(out, quality.clamp(1, 100));
+    encoder.set_sampling_factor(match subsampling {
+        ChromaSubsampling::Full => SamplingFactor::R_4_4_4,
+        ChromaSubsampling::Half => SamplingFactor::R_4_2_0,
+    });
+    encoder.set_progressive(progressive);
+    match img {
+        DynamicImage::ImageLuma8(gray) => encoder.encode(gray.as_raw(), w, h, ColorType::Luma)?,
+        _ => encoder.encode(img.to_rgb8().as_raw(), w, h, ColorType::Rgb)?,
+    }
+    Ok(())
+}
+
+/// Encode a two-tone image in the sink's format and deliver it, with set
+/// pixels in `foreground` and the rest in `background`.
+///
+/// PNG is written with a palette of the two colors, at one bit a pixel.
+/// PBM has no colors, so set pixels are black whatever `foreground` is.
+/// Other formats are written as they would be for any image.
+pub fn write_bitmap(bitmap: &Bitmap, foreground: Color, background: Color, sink: &mut dyn ImageSink) -> Result<(), SinkError> {
+    if bitmap.width() == 0 || bitmap.height() == 0 {
+        return Err(SinkError::EmptyImage);
+    }
+    let format = sink.format();
+    let mut bytes = Vec::new();
+    match format {
+        OutputFormat::Png => encode_bitmap_png(bitmap, [background, foreground], &mut bytes)?,
+        OutputFormat::Pbm => encode_pbm(bitmap, &mut bytes),
+        _ => {
+            let pixels = bitmap.bits().iter()
+                .flat_map(|&set| {
+                    let c = if set { foreground } else { background };
+                    [c.red, c.green, c.blue]
+                })
+                .collect();
+            let image = RgbImage::from_raw(bitmap.width() as u32, bitmap.height() as u32, pixels)
+                .expect("three samples per pixel");
+            return write_image(&DynamicImage::ImageRgb8(image), sink);
+        }
+    }
+    sink.write(format, &bytes)
+}
+
+/// Rows of a bitmap packed eight pixels to a byte, the first in the most
+/// significant bit, each row starting on a new byte.
+fn pack_rows(bitmap: &Bitmap) -> impl Iterator<Item = Vec<u8>> + '_ {
+    bitmap.bits().chunks(bitmap.width()).map(|row| {
+        row.chunks(8)
+            .map(|bits| bits.iter().enumerate().fold(0, |byte, (i, &set)| byte | (u8::from(set) << (7 - i))))
+            .collect()
+    })
+}
+
+fn encode_pbm(bitmap: &Bitmap, out: &mut Vec<u8>) {
+    out.extend(format!("P4\n{} {}\n", bitmap.width(), bitmap.height()).into_bytes());
+    out.extend(pack_rows(bitmap).flatten());
+}
+
+/// Encode a bitmap as a PNG with a palette of `colors`, the first for
+/// clear pixels and the second for set ones.
+fn encode_bitmap_png(bitmap: &Bitmap, colors: [Color; 2], out: &mut Vec<u8>) -> std::io::Result<()> {
+    let mut header = Vec::with_capacity(13);
+    header.extend((bitmap.width() as u32).to_be_bytes());
+    header.extend((bitmap.height() as u32).to_be_bytes());
+    // 1-bit indexed color, deflate, filtered per row, not interlaced.
+    header.extend([1, 3, 0, 0, 0]);
+    let palette: Vec<u8> = colors.iter().flat_map(|c| [c.red, c.green, c.blue]).collect();
+
+    let mut data = ZlibEncoder::new(Vec::new(), Compression::default());
+    for row in pack_rows(bitmap) {
+        // No filter: runs of equal bytes compress well as they are.
+        data.write_all(&[0])?;
+        data.write_all(&row)?;
+    }
+
+    out.extend(b"\x89PNG\r\n\x1a\n");
+    write_chunk(out, b"IHDR", &header)?;
+    write_chunk(out, b"PLTE", &palette)?;
+    write_chunk(out, b"IDAT", &data.finish()?)?;
+    write_chunk(out, b"IEND", &[])
+}
+
+/// Render a flame and deliver the resulting image to a sink, encoded at
+/// the depth of the sink's format, so that float formats get the tone
+/// mapped values unquantized.
+pub fn render_to(
+    flame: &Flame,
+    run_cfg: RunConfig,
+    tone: &ToneMapping,
+    encoding: OutputEncoding,
+    sink: &mut dyn ImageSink,
+) -> Result<(), SinkError> {
+    let toned = flame.run(run_cfg)?.tone_map(tone);
+    write_image(&encode_for(&toned, encoding, sink.format())?, sink)
+}
+
+/// Encode a tone mapped image as `format` stores it: unquantized for float
+/// formats, whatever the depth of `encoding`, and in 8 bits otherwise.
+pub fn encode_for(toned: &Buffer<f64>, encoding: OutputEncoding, format: OutputFormat) -> Result<DynamicImage, BufferError> {
+    OutputEncoding { depth: format.depth(), ..encoding }.encode(toned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bench::baseline_config;
+    use crate::presets;
+
+    fn run_config() -> RunConfig {
+        RunConfig { width: 20, height: 16, iters: 40_000, seed: Some(11), ..baseline_config(1) }
+    }
+
+    /// A sink which refuses every image.
+    struct FailingSink;
+
+    impl ImageSink for FailingSink {
+        fn write(&mut self, _format: OutputFormat, _bytes: &[u8]) -> Result<(), SinkError> {
+            Err(SinkError::Io(io::Error::new(io::ErrorKind::StorageFull, "sink is full")))
+        }
+    }
+
+    #[test]
+    fn memory_and_file_sinks_receive_the_same_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let flame = presets::gasket();
+        for format in [OutputFormat::Png, OutputFormat::JPEG, OutputFormat::Ppm, OutputFormat::Pbm, OutputFormat::Exr] {
+            let mut memory = VecSink::new(format);
+            render_to(&flame, run_config(), &ToneMapping::default(), OutputEncoding::default(), &mut memory).unwrap();
+            let mut writer = WriterSink::new(Vec::new(), format);
+            render_to(&flame, run_config(), &ToneMapping::default(), OutputEncoding::default(), &mut writer).unwrap();
+            let path = dir.path().join("out.img");
+            render_to(&flame, run_config(), &ToneMapping::default(), OutputEncoding::default(), &mut FileSink::new_with_format(&path, format)).unwrap();
+
+            assert_eq!(memory.images.len(), 1);
+            assert_eq!(memory.images[0], std::fs::read(&path).unwrap(), "{:?}", format);
+            assert_eq!(memory.images[0], writer.into_inner(), "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn the_format_follows_the_file_extension() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.png");
+        render_to(&presets::gasket(), run_config(), &ToneMapping::default(), OutputEncoding::default(), &mut FileSink::new(&path).unwrap()).unwrap();
+        let image = image::open(&path).unwrap();
+        assert_eq!((image.width(), image.height()), (20, 16));
+        assert!(matches!(FileSink::new(dir.path().join("out.tiff")), Err(SinkError::UnknownFormat(_))));
+    }
+
+    #[test]
+    fn sink_errors_are_passed_on() {
+        let result = render_to(&presets::gasket(), run_config(), &ToneMapping::default(), OutputEncoding::default(), &mut FailingSink);
+        match result {
+            Err(SinkError::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::StorageFull),
+            other => panic!("expected the sink's error, got {:?}", other),
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("missing").join("out.png");
+        match render_to(&presets::gasket(), run_config(), &ToneMapping::default(), OutputEncoding::default(), &mut FileSink::new(&path).unwrap()) {
+            Err(SinkError::File { path: p, .. }) => assert_eq!(p, path),
+            other => panic!("expected a file error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn exr_output_keeps_the_tone_mapped_values_unquantized() {
+        let flame = presets::gasket();
+        let mut sink = VecSink::new(OutputFormat::Exr);
+        render_to(&flame, run_config(), &ToneMapping::default(), OutputEncoding::default(), &mut sink).unwrap();
+        let decoded = image::load_from_memory(&sink.images[0]).unwrap().into_rgb32f();
+
+        let toned = flame.run(run_config()).unwrap().tone_map(&ToneMapping::default());
+        let mut off_grid = 0;
+        for (pixel, bucket) in decoded.pixels().zip(toned.buckets()) {
+            let expected = [bucket.red, bucket.green, bucket.blue].map(|c| c as f32);
+            assert_eq!(pixel.0, expected);
+            off_grid += expected.iter().filter(|&&c| (c * 255.0).fract() != 0.0).count();
+        }
+        // Values between the 8-bit levels survive.
+        assert!(off_grid > 0);
+    }
+
+    /// Sum over the pixels of column `x` of their squared distance from
+    /// gray in the YCbCr chroma plane.
+    fn column_chroma_energy(image: &RgbImage, x: u32) -> f64 {
+        (0 .. image.height()).map(|y| {
+            let [r, g, b] = image.get_pixel(x, y).0.map(f64::from);
+            let cb = -0.168_736 * r - 0.331_264 * g + 0.5 * b;
+            let cr = 0.5 * r - 0.418_688 * g - 0.081_312 * b;
+            cb * cb + cr * cr
+        }).sum()
+    }
+
+    #[test]
+    fn full_chroma_keeps_the_color_of_thin_lines() {
+        // A one pixel red line on black.
+        let mut line = RgbImage::new(32, 32);
+        for y in 0 .. 32 {
+            line.put_pixel(13, y, image::Rgb([230, 20, 40]));
+        }
+        let energy = |subsampling| {
+            let mut sink = VecSink::new(OutputFormat::Jpeg { quality: 90, subsampling, progressive: false });
+            write_image(&DynamicImage::ImageRgb8(line.clone()), &mut sink).unwrap();
+            column_chroma_energy(&image::load_from_memory(&sink.images[0]).unwrap().into_rgb8(), 13)
+        };
+        let (full, half) = (energy(ChromaSubsampling::Full), energy(ChromaSubsampling::Half));
+        let original = column_chroma_energy(&line, 13);
+        assert!(full > 2.0 * half, "4:4:4 kept {} of the line's chroma energy and 4:2:0 {}", full, half);
+        assert!(full > 0.8 * original, "4:4:4 kept {} of {}", full, original);
+    }
+
+    #[test]
+    fn jpeg_defaults_to_full_chroma_at_high_quality() {
+        for format in ["jpg".parse().unwrap(), "JPEG".parse().unwrap(), OutputFormat::from_path(Path::new("a.jpg")).unwrap(), OutputFormat::JPEG] {
+            match format {
+                OutputFormat::Jpeg { quality, subsampling, progressive } => {
+                    assert!(quality >= 90);
+                    assert_eq!((subsampling, progressive), (ChromaSubsampling::Full, false));
+                }
+                other => panic!("expected JPEG, got {:?}", other),
+            }
+        }
+        assert_eq!(ChromaSubsampling::default(), ChromaSubsampling::Full);
+        assert_eq!("4:2:0".parse(), Ok(ChromaSubsampling::Half));
+        assert_eq!("444".parse(), Ok(ChromaSubsampling::Full));
+        assert!("422".parse::<ChromaSubsampling>().is_err());
+    }
+
+    #[test]
+    fn progressive_jpegs_decode_to_the_same_image() {
+        let image = flame_image();
+        let decode = |progressive| {
+            let mut sink = VecSink::new(OutputFormat::Jpeg { quality: 95, subsampling: ChromaSubsampling::Full, progressive });
+            write_image(&image, &mut sink).unwrap();
+            (sink.images[0].clone(), image::load_from_memory(&sink.images[0]).unwrap().into_rgb8())
+        };
+        let ((baseline_bytes, baseline), (progressive_bytes, progressive)) = (decode(false), decode(true));
+        assert_ne!(baseline_bytes, progressive_bytes);
+        let diff = baseline.as_raw().iter().zip(progressive.as_raw()).map(|(&a, &b)| a.abs_diff(b)).max().unwrap();
+        assert!(diff <= 2, "progressive decoding differs by {}", diff);
+    }
+
+    fn flame_image() -> DynamicImage {
+        let flame = presets::gasket();
+        let mut sink = VecSink::new(OutputFormat::Png);
+        render_to(&flame, run_config(), &ToneMapping::default(), OutputEncoding::default(), &mut sink).unwrap();
+        image::load_from_memory(&sink.images[0]).unwrap()
+    }
+}
@@ -0,0 +1,132 @@
+//! Built in flames, used as starting points for new descriptors.
+
+use nalgebra::{Affine2, Matrix3, Transform};
+
+use super::core::*;
+
+/// Names accepted by `by_name`.
+pub const NAMES: [&str; 4] = ["starter", "fern", "gasket", "swirl"];
+
+/// Look up a preset by name.
+pub fn by_name(name: &str) -> Option<Flame> {
+    match name {
+        "starter" => Some(starter()),
+        "fern" => Some(fern()),
+        "gasket" => Some(gasket()),
+        "swirl" => Some(swirl()),
+        _ => None,
+    }
+}
+
+/// An affine transform from the coefficients `[a, b, c, d, e, f]` used in
+/// descriptors, mapping `(x, y)` to `(a x + b y + e, c x + d y + f)`.
+fn affine(c: [f32; 6]) -> Affine2<f32> {
+    Transform::from_matrix_unchecked(Matrix3::new(
+        c[0], c[1], c[4],
+        c[2], c[3], c[5],
+        0.0,  0.0,  1.0,
+    ))
+}
+
+fn function(weight: f32, var: Variation, c: [f32; 6], color: u8) -> Function {
+    Function { weight, var, trans: affine(c), color, axis_blend: Function::FULL_BLEND }
+}
+
+fn palette(keys: &[(u8, u8, u8)]) -> Palette {
+    Palette::from_keys(keys.iter().map(|&(r, g, b)| Color::rgb(r, g, b)).collect())
+        .expect("preset palettes are valid")
+}
+
+/// A simple two function flame to edit.
+pub fn starter() -> Flame {
+    Flame {
+        functions: vec![
+            function(0.5, Variation::Sinusoidal, [0.6, -0.2, 0.2, 0.6, 0.3, 0.0], 0),
+            function(0.5, Variation::Spherical, [0.5, 0.3, -0.3, 0.5, -0.3, 0.2], 255),
+        ],
+        palette: palette(&[(30, 60, 200), (240, 240, 255), (250, 120, 30)]),
+        bounds: Bounds::new(-2.0, 2.0, -2.0, 2.0),
+        mask: None,
+        color_model: ColorModel::Native,
+    }
+}
+
+/// Barnsley's fern.
+pub fn fern() -> Flame {
+    Flame {
+        functions: vec![
+            function(0.01, Variation::Id, [0.0, 0.0, 0.0, 0.16, 0.0, 0.0], 0),
+            function(0.85, Variation::Id, [0.85, 0.04, -0.04, 0.85, 0.0, 1.6], 170),
+            function(0.07, Variation::Id, [0.2, -0.26, 0.23, 0.22, 0.0, 1.6], 85),
+            function(0.07, Variation::Id, [-0.15, 0.28, 0.26, 0.24, 0.0, 0.44], 255),
+        ],
+        palette: palette(&[(20, 70, 20), (60, 170, 40), (190, 240, 110)]),
+        bounds: Bounds::new(-5.5, 5.5, -0.5, 10.5),
+        mask: None,
+        color_model: ColorModel::Native,
+    }
+}
+
+/// The Sierpinski gasket.
+pub fn gasket() -> Flame {
+    Flame {
+        functions: vec![
+            function(1. / 3., Variation::Id, [0.5, 0.0, 0.0, 0.5, 0.0, 0.0], 0),
+            function(1. / 3., Variation::Id, [0.5, 0.0, 0.0, 0.5, 0.5, 0.0], 128),
+            function(1. / 3., Variation::Id, [0.5, 0.0, 0.0, 0.5, 0.25, 0.433], 255),
+        ],
+        palette: palette(&[(230, 50, 50), (50, 200, 80), (60, 90, 230)]),
+        bounds: Bounds::new(-0.05, 1.05, -0.1, 1.0),
+        mask: None,
+        color_model: ColorModel::Native,
+    }
+}
+
+/// A spiral built from the swirl variation.
+pub fn swirl() -> Flame {
+    Flame {
+        functions: vec![
+            function(0.5, Variation::Swirl, [0.7, -0.3, 0.3, 0.7, 0.2, 0.0], 0),
+            function(0.3, Variation::Id, [0.5, 0.0, 0.0, 0.5, -0.5, 0.4], 150),
+            function(0.2, Variation::Spherical, [0.6, 0.2, -0.2, 0.6, -0.3, -0.4], 255),
+        ],
+        palette: palette(&[(90, 20, 120), (240, 60, 140), (255, 210, 90)]),
+        bounds: Bounds::new(-1.5, 1.5, -1.5, 1.5),
+        mask: None,
+        color_model: ColorModel::Native,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bench::baseline_config;
+    use crate::file::FlameSource;
+
+    #[test]
+    fn every_preset_survives_its_descriptor() {
+        for name in NAMES {
+            let flame = by_name(name).unwrap();
+            let mut bytes = Vec::new();
+            FlameSource::from_flame(&flame).to_writer(&mut bytes).unwrap();
+            let value = serde_json::from_slice(&bytes).unwrap();
+            let read = FlameSource::from_value(value, ".").unwrap().to_flame().unwrap();
+            assert!(read.eq_structural(&flame), "{} changed when written", name);
+        }
+        assert!(by_name("dragon").is_none());
+    }
+
+    #[test]
+    fn every_preset_validates_and_renders() {
+        for name in NAMES {
+            let flame = by_name(name).unwrap();
+            let findings = flame.validate();
+            assert!(findings.is_empty(), "{}: {:?}", name, findings);
+
+            let run = RunConfig { width: 64, height: 64, iters: 50_000, seed: Some(1), ..baseline_config(1) };
+            let histogram = flame.run(run).unwrap();
+            let lit = histogram.buckets().iter().filter(|b| b.alpha > 0).count();
+            assert!(lit > 64 * 64 / 20, "{} lit only {} pixels", name, lit);
+        }
+    }
+}
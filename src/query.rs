@@ -0,0 +1,590 @@
+//! Searching many descriptors by the properties in their summaries.
+//!
+//! A search is a list of predicates which must all hold, each written as a
+//! field, an operator and its operands:
+//!
+//! ```text
+//! variations contains PDJ
+//! functions.count > 5
+//! palette.dominant_hue between 180 260
+//! meta.method = breed
+//! ```
+//!
+//! Fields are numbers, text, true or false, or sets of names, and each
+//! operator applies to some of these. Predicates are checked against the
+//! fields as they are parsed, except for those of the metadata, whose type
+//! is only known once a descriptor is read. A descriptor without a value
+//! for a field, such as one with a gray palette for its dominant hue, or
+//! one whose metadata has no such key, matches no predicate on it.
+
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use super::core::FlameSummary;
+
+/// The summary of one descriptor, and what is known of where it came from.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Record {
+    pub path: PathBuf,
+    /// The flame's content hash, as 16 hex digits.
+    pub hash: String,
+    pub summary: FlameSummary,
+    /// The descriptor's metadata, without its thumbnail.
+    pub meta: Map<String, Value>,
+}
+
+impl Record {
+    /// The value of `field`, if the record has one.
+    pub fn get(&self, field: &Field) -> Option<FieldValue> {
+        let s = &self.summary;
+        let number = |x: f32| Some(FieldValue::Number(x as f64));
+        match field {
+            Field::Name => Some(FieldValue::Text(self.path.file_name()?.to_string_lossy().into_owned())),
+            Field::Functions => Some(FieldValue::Number(s.functions as f64)),
+            Field::Variations => Some(FieldValue::Set(s.variations.clone())),
+            Field::WeightEntropy => number(s.weight_entropy),
+            Field::DominantHue => number(s.palette.dominant_hue?),
+            Field::Lightness => number(s.palette.lightness),
+            Field::LightnessMin => number(s.palette.lightness_min),
+            Field::LightnessMax => number(s.palette.lightness_max),
+            Field::Rotation => Some(FieldValue::Number(s.symmetry.rotation as f64)),
+            Field::Mirror => Some(FieldValue::Bool(s.symmetry.mirror)),
+            Field::Aspect => number(s.aspect),
+            Field::Meta(key) => FieldValue::from_json(self.meta.get(key)?),
+        }
+    }
+
+    /// Whether every one of `predicates` holds for the record.
+    pub fn matches(&self, predicates: &[Predicate]) -> bool {
+        predicates.iter().all(|p| p.matches(self))
+    }
+}
+
+/// A property of a record which can be searched.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Field {
+    /// The descriptor's file name.
+    Name,
+    Functions,
+    Variations,
+    WeightEntropy,
+    DominantHue,
+    Lightness,
+    LightnessMin,
+    LightnessMax,
+    Rotation,
+    Mirror,
+    Aspect,
+    /// A key of the descriptor's metadata, such as `method` or `seed`.
+    Meta(String),
+}
+
+impl Field {
+    /// Names of the fields, as accepted by `from_str`, but for those of
+    /// the metadata, written `meta.KEY`.
+    pub const NAMES: [&'static str; 11] = [
+        "name",
+        "functions.count",
+        "variations",
+        "weights.entropy",
+        "palette.dominant_hue",
+        "palette.lightness",
+        "palette.lightness_min",
+        "palette.lightness_max",
+        "symmetry.rotation",
+        "symmetry.mirror",
+        "bounds.aspect",
+    ];
+
+    /// The type of the field's values, unless it is one of the metadata.
+    pub fn kind(&self) -> Option<Kind> {
+        match self {
+            Field::Name => Some(Kind::Text),
+            Field::Variations => Some(Kind::Set),
+            Field::Mirror => Some(Kind::Bool),
+            Field::Meta(_) => None,
+            _ => Some(Kind::Number),
+        }
+    }
+
+    /// Whether the field is an angle in degrees, whose ranges may wrap
+    /// around through 0.
+    fn is_angle(&self) -> bool {
+        *self == Field::DominantHue
+    }
+}
+
+/// The fields other than those of the metadata, in the order of `NAMES`.
+const FIELDS: [Field; 11] = [
+    Field::Name,
+    Field::Functions,
+    Field::Variations,
+    Field::WeightEntropy,
+    Field::DominantHue,
+    Field::Lightness,
+    Field::LightnessMin,
+    Field::LightnessMax,
+    Field::Rotation,
+    Field::Mirror,
+    Field::Aspect,
+];
+
+impl FromStr for Field {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(key) = s.strip_prefix("meta.").filter(|k| !k.is_empty()) {
+            return Ok(Field::Meta(key.to_string()));
+        }
+        let name = match s.to_ascii_lowercase() {
+            name if name == "functions" => "functions.count".to_string(),
+            name => name,
+        };
+        FIELDS.iter().find(|f| f.to_string() == name).cloned()
+            .ok_or_else(|| format!("unknown field '{}' (expected one of {}, or meta.KEY)", s, Field::NAMES.join(", ")))
+    }
+}
+
+impl std::fmt::Display for Field {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Field::Name => write!(f, "name"),
+            Field::Functions => write!(f, "functions.count"),
+            Field::Variations => write!(f, "variations"),
+            Field::WeightEntropy => write!(f, "weights.entropy"),
+            Field::DominantHue => write!(f, "palette.dominant_hue"),
+            Field::Lightness => write!(f, "palette.lightness"),
+            Field::LightnessMin => write!(f, "palette.lightness_min"),
+            Field::LightnessMax => write!(f, "palette.lightness_max"),
+            Field::Rotation => write!(f, "symmetry.rotation"),
+            Field::Mirror => write!(f, "symmetry.mirror"),
+            Field::Aspect => write!(f, "bounds.aspect"),
+            Field::Meta(key) => write!(f, "meta.{}", key),
+        }
+    }
+}
+
+/// The types of values fields hold.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Number,
+    Text,
+    Bool,
+    Set,
+}
+
+impl std::fmt::Display for Kind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Kind::Number => write!(f, "a number"),
+            Kind::Text => write!(f, "text"),
+            Kind::Bool => write!(f, "true or false"),
+            Kind::Set => write!(f, "a set of names"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FieldValue {
+    Number(f64),
+    Text(String),
+    Bool(bool),
+    Set(BTreeSet<String>),
+}
+
+impl FieldValue {
+    pub fn kind(&self) -> Kind {
+        match self {
+            FieldValue::Number(_) => Kind::Number,
+            FieldValue::Text(_) => Kind::Text,
+            FieldValue::Bool(_) => Kind::Bool,
+            FieldValue::Set(_) => Kind::Set,
+        }
+    }
+
+    /// A value of the metadata. Arrays are sets of their items, written as
+    /// JSON but for strings, and objects and nulls have no value.
+    fn from_json(value: &Value) -> Option<FieldValue> {
+        match value {
+            Value::Number(x) => x.as_f64().map(FieldValue::Number),
+            Value::String(s) => Some(FieldValue::Text(s.clone())),
+            Value::Bool(b) => Some(FieldValue::Bool(*b)),
+            Value::Array(items) => Some(FieldValue::Set(items.iter()
+                .map(|item| item.as_str().map_or_else(|| item.to_string(), str::to_string))
+                .collect())),
+            Value::Object(_) | Value::Null => None,
+        }
+    }
+
+    /// Parse an operand: a number, `true` or `false`, or text, which may be
+    /// quoted to keep it from being read as either.
+    fn parse(s: &str) -> FieldValue {
+        let unquoted = ['"', '\''].into_iter()
+            .find_map(|q| s.strip_prefix(q).and_then(|s| s.strip_suffix(q)));
+        if let Some(text) = unquoted {
+            return FieldValue::Text(text.to_string());
+        }
+        match s {
+            "true" => FieldValue::Bool(true),
+            "false" => FieldValue::Bool(false),
+            _ => s.parse().ok().filter(|x: &f64| x.is_finite())
+                .map_or_else(|| FieldValue::Text(s.to_string()), FieldValue::Number),
+        }
+    }
+}
+
+impl std::fmt::Display for FieldValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FieldValue::Number(x) => write!(f, "{}", x),
+            FieldValue::Text(s) => write!(f, "{}", s),
+            FieldValue::Bool(b) => write!(f, "{}", b),
+            FieldValue::Set(names) => write!(f, "{}", names.iter().cloned().collect::<Vec<_>>().join(",")),
+        }
+    }
+}
+
+/// How a field is compared with a predicate's operands.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Op {
+    Eq(FieldValue),
+    Ne(FieldValue),
+    Lt(f64),
+    Le(f64),
+    Gt(f64),
+    Ge(f64),
+    /// Between two numbers inclusive. For angles, a range whose start is
+    /// past its end wraps around through 0, so that `between 330 30`
+    /// holds the reds.
+    Between(f64, f64),
+    /// A set holding a name, or text holding a substring, ignoring case.
+    Contains(String),
+}
+
+/// A condition on one field of a record, written `FIELD OP OPERANDS`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Predicate {
+    pub field: Field,
+    pub op: Op,
+}
+
+impl Predicate {
+    pub fn matches(&self, record: &Record) -> bool {
+        let Some(value) = record.get(&self.field) else { return false };
+        match (&self.op, &value) {
+            (Op::Eq(x), value) => equals(value, x) == Some(true),
+            (Op::Ne(x), value) => equals(value, x) == Some(false),
+            (Op::Lt(x), FieldValue::Number(v)) => v < x,
+            (Op::Le(x), FieldValue::Number(v)) => v <= x,
+            (Op::Gt(x), FieldValue::Number(v)) => v > x,
+            (Op::Ge(x), FieldValue::Number(v)) => v >= x,
+            (Op::Between(lo, hi), FieldValue::Number(v)) if self.field.is_angle() => {
+                // Measured around the circle from the start of the range.
+                hi - lo >= 360.0 || (v - lo).rem_euclid(360.0) <= (hi - lo).rem_euclid(360.0)
+            }
+            (Op::Between(lo, hi), FieldValue::Number(v)) => lo <= v && v <= hi,
+            (Op::Contains(x), FieldValue::Set(names)) => names.iter().any(|n| n.eq_ignore_ascii_case(x)),
+            (Op::Contains(x), FieldValue::Text(text)) => text.to_lowercase().contains(&x.to_lowercase()),
+            _ => false,
+        }
+    }
+}
+
+/// Whether `value` equals `x`, or `None` if they cannot be compared.
+fn equals(value: &FieldValue, x: &FieldValue) -> Option<bool> {
+    match (value, x) {
+        // Text in the metadata, such as a version, compares with numbers
+        // and flags as written.
+        (FieldValue::Text(a), FieldValue::Number(_) | FieldValue::Bool(_)) => Some(*a == x.to_string()),
+        _ if value.kind() == x.kind() => Some(value == x),
+        _ => None,
+    }
+}
+
+impl FromStr for Predicate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let end = s.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.')).unwrap_or(s.len());
+        if end == 0 {
+            return Err(format!("expected FIELD OP VALUE, got '{}'", s));
+        }
+        let field: Field = s[.. end].parse()?;
+        let rest = s[end ..].trim_start();
+        if rest.is_empty() {
+            return Err(format!("expected an operator and a value after '{}'", field));
+        }
+
+        let symbols = [("<=", "<="), (">=", ">="), ("!=", "!="), ("==", "="), ("=", "="), ("<", "<"), (">", ">")];
+        let (op, operands) = match symbols.into_iter().find(|(sym, _)| rest.starts_with(sym)) {
+            Some((sym, op)) => (op.to_string(), rest[sym.len() ..].trim()),
+            None => {
+                let (word, operands) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+                (word.to_ascii_lowercase(), operands.trim())
+            }
+        };
+        let number = |operand: &str| {
+            operand.parse().ok().filter(|x: &f64| x.is_finite())
+                .ok_or_else(|| format!("'{}' needs a number, got '{}'", op, operand))
+        };
+        let need_kind = |kinds: &[Kind]| match field.kind() {
+            Some(kind) if !kinds.contains(&kind) => {
+                Err(format!("'{}' cannot be used on {}, which is {}", op, field, kind))
+            }
+            _ => Ok(()),
+        };
+        if operands.is_empty() {
+            return Err(format!("'{}' in '{}' needs a value", op, s));
+        }
+
+        let op = match op.as_str() {
+            "=" | "!=" => {
+                let value = FieldValue::parse(operands);
+                match (field.kind(), value.kind()) {
+                    (Some(Kind::Set), _) => return Err(format!("{} is a set of names, so use 'contains'", field)),
+                    (Some(Kind::Text), _) | (None, _) => {}
+                    (Some(kind), found) if kind != found => {
+                        return Err(format!("{} is {}, but '{}' is {}", field, kind, operands, found));
+                    }
+                    _ => {}
+                }
+                // Text fields compare with the operand as written.
+                let value = match (field.kind(), value) {
+                    (Some(Kind::Text), FieldValue::Number(_) | FieldValue::Bool(_)) => FieldValue::Text(operands.to_string()),
+                    (_, value) => value,
+                };
+                if op == "=" { Op::Eq(value) } else { Op::Ne(value) }
+            }
+            "<" | "<=" | ">" | ">=" => {
+                need_kind(&[Kind::Number])?;
+                let x = number(operands)?;
+                match op.as_str() {
+                    "<" => Op::Lt(x),
+                    "<=" => Op::Le(x),
+                    ">" => Op::Gt(x),
+                    _ => Op::Ge(x),
+                }
+            }
+            "between" => {
+                need_kind(&[Kind::Number])?;
+                let bounds: Vec<&str> = operands.split_whitespace().filter(|w| !w.eq_ignore_ascii_case("and")).collect();
+                let [lo, hi] = bounds[..] else {
+                    return Err(format!("'between' needs two numbers, got '{}'", operands));
+                };
+                let (lo, hi) = (number(lo)?, number(hi)?);
+                if lo > hi && !field.is_angle() {
+                    return Err(format!("the range {} to {} is empty", lo, hi));
+                }
+                Op::Between(lo, hi)
+            }
+            "contains" => {
+                need_kind(&[Kind::Set, Kind::Text])?;
+                match FieldValue::parse(operands) {
+                    FieldValue::Text(x) => Op::Contains(x),
+                    _ => Op::Contains(operands.to_string()),
+                }
+            }
+            _ => return Err(format!("unknown operator '{}' (expected =, !=, <, <=, >, >=, between or contains)", op)),
+        };
+        Ok(Predicate { field, op })
+    }
+}
+
+/// How the records found by a search are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueryFormat {
+    /// A line of the main properties of each, under a header.
+    Table,
+    /// An array of the records, summaries and all.
+    Json,
+    /// The path of each, one to a line.
+    Paths,
+}
+
+impl QueryFormat {
+    /// Names of the formats, as accepted by `from_str`.
+    pub const NAMES: [&'static str; 3] = ["table", "json", "paths"];
+}
+
+impl FromStr for QueryFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "table" => Ok(QueryFormat::Table),
+            "json" => Ok(QueryFormat::Json),
+            "paths" => Ok(QueryFormat::Paths),
+            _ => Err(format!("unknown format '{}' (expected table, json or paths)", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{PaletteSummary, Symmetry};
+
+    fn parse(s: &str) -> Predicate {
+        s.parse().unwrap_or_else(|e| panic!("'{}': {}", s, e))
+    }
+
+    fn record(hue: Option<f32>, variations: &[&str], meta: Value) -> Record {
+        Record {
+            path: PathBuf::from("lib/spiral.json"),
+            hash: "0123456789abcdef".to_string(),
+            summary: FlameSummary {
+                functions: 6,
+                variations: variations.iter().map(|v| v.to_string()).collect(),
+                weight_entropy: 2.0,
+                palette: PaletteSummary { dominant_hue: hue, lightness: 0.5, lightness_min: 0.2, lightness_max: 0.9 },
+                symmetry: Symmetry { rotation: 3, mirror: true },
+                aspect: 1.5,
+            },
+            meta: match meta {
+                Value::Object(meta) => meta,
+                _ => Map::new(),
+            },
+        }
+    }
+
+    #[test]
+    fn predicates_are_parsed() {
+        assert_eq!(parse("variations contains PDJ"), Predicate { field: Field::Variations, op: Op::Contains("PDJ".into()) });
+        assert_eq!(parse("functions.count > 5"), Predicate { field: Field::Functions, op: Op::Gt(5.0) });
+        assert_eq!(parse("FUNCTIONS>=5"), Predicate { field: Field::Functions, op: Op::Ge(5.0) });
+        assert_eq!(parse("  bounds.aspect <= 1.5 "), Predicate { field: Field::Aspect, op: Op::Le(1.5) });
+        assert_eq!(parse("weights.entropy < 1e0").op, Op::Lt(1.0));
+        assert_eq!(parse("palette.dominant_hue between 180 260").op, Op::Between(180.0, 260.0));
+        assert_eq!(parse("palette.lightness BETWEEN 0.2 and 0.4").op, Op::Between(0.2, 0.4));
+        assert_eq!(parse("symmetry.mirror = true").op, Op::Eq(FieldValue::Bool(true)));
+        assert_eq!(parse("symmetry.rotation == 3").op, Op::Eq(FieldValue::Number(3.0)));
+        assert_eq!(parse("name != 'a b.json'").op, Op::Ne(FieldValue::Text("a b.json".into())));
+        // Text fields compare with numbers as written.
+        assert_eq!(parse("name = 1.50").op, Op::Eq(FieldValue::Text("1.50".into())));
+        assert_eq!(parse("meta.method = breed"), Predicate {
+            field: Field::Meta("method".into()),
+            op: Op::Eq(FieldValue::Text("breed".into())),
+        });
+        assert_eq!(parse("meta.seed = 42").op, Op::Eq(FieldValue::Number(42.0)));
+        assert_eq!(parse("meta.version = \"0.6\"").op, Op::Eq(FieldValue::Text("0.6".into())));
+        // Ranges of hues may wrap around through 0.
+        assert_eq!(parse("palette.dominant_hue between 330 30").op, Op::Between(330.0, 30.0));
+    }
+
+    #[test]
+    fn every_field_name_parses_and_prints_as_itself() {
+        for name in Field::NAMES {
+            assert_eq!(name.parse::<Field>().unwrap().to_string(), name);
+        }
+        assert_eq!("functions".parse::<Field>(), Ok(Field::Functions));
+        assert_eq!("meta.seed".parse::<Field>().unwrap().to_string(), "meta.seed");
+    }
+
+    #[test]
+    fn malformed_predicates_are_refused() {
+        for (s, error) in [
+            ("", "expected FIELD OP VALUE"),
+            ("> 5", "expected FIELD OP VALUE"),
+            ("colors > 5", "unknown field 'colors'"),
+            ("meta. = 1", "unknown field 'meta.'"),
+            ("functions.count", "expected an operator"),
+            ("functions.count >", "needs a value"),
+            ("functions.count > many", "needs a number"),
+            ("functions.count > inf", "needs a number"),
+            ("functions.count like 5", "unknown operator 'like'"),
+            ("functions.count = blue", "is a number, but 'blue' is text"),
+            ("symmetry.mirror = 1", "is true or false"),
+            ("variations = PDJ", "use 'contains'"),
+            ("variations > 2", "cannot be used on variations"),
+            ("name between 1 2", "cannot be used on name"),
+            ("functions.count contains 5", "cannot be used on functions.count"),
+            ("bounds.aspect between 1", "needs two numbers"),
+            ("bounds.aspect between 1 2 3", "needs two numbers"),
+            ("bounds.aspect between 2 1", "is empty"),
+        ] {
+            let err = s.parse::<Predicate>().unwrap_err();
+            assert!(err.contains(error), "'{}' gave '{}'", s, err);
+        }
+    }
+
+    #[test]
+    fn predicates_match_records() {
+        let record = record(Some(240.0), &["PDJ", "Swirl"], serde_json::json!({
+            "method": "breed",
+            "seed": 42,
+            "version": "0.6",
+            "tags": ["spiral", 3],
+            "parents": {"a": 1},
+        }));
+        let holds = |s: &str| parse(s).matches(&record);
+        for s in [
+            "variations contains pdj",
+            "functions.count > 5",
+            "functions.count = 6",
+            "functions.count != 7",
+            "palette.dominant_hue between 180 260",
+            "palette.dominant_hue between 240 240",
+            "weights.entropy >= 2",
+            "symmetry.rotation = 3",
+            "symmetry.mirror = true",
+            "bounds.aspect < 2",
+            "name = spiral.json",
+            "name contains SPIRAL",
+            "meta.method = breed",
+            "meta.seed >= 42",
+            "meta.version = 0.6",
+            "meta.tags contains spiral",
+            "meta.tags contains 3",
+        ] {
+            assert!(holds(s), "'{}' does not hold", s);
+        }
+        for s in [
+            "variations contains Blob",
+            "functions.count > 6",
+            "palette.dominant_hue between 0 180",
+            "palette.dominant_hue between 330 30",
+            "symmetry.mirror = false",
+            "name != spiral.json",
+            "meta.method = random",
+            // Fields a record has no value for, or one of another type,
+            // match nothing, whatever the operator.
+            "meta.missing = 1",
+            "meta.missing != 1",
+            "meta.parents = 1",
+            "meta.parents != 1",
+            "meta.method > 1",
+            "meta.seed contains 4",
+        ] {
+            assert!(!holds(s), "'{}' holds", s);
+        }
+        assert!(record.matches(&[parse("functions.count > 5"), parse("variations contains PDJ")]));
+        assert!(!record.matches(&[parse("functions.count > 5"), parse("variations contains Blob")]));
+        assert!(record.matches(&[]));
+    }
+
+    #[test]
+    fn hue_ranges_wrap_around() {
+        let reds = parse("palette.dominant_hue between 330 30");
+        for (hue, holds) in [(350.0, true), (0.0, true), (30.0, true), (29.5, true), (31.0, false), (180.0, false), (329.0, false)] {
+            assert_eq!(reds.matches(&record(Some(hue), &[], Value::Null)), holds, "hue {}", hue);
+        }
+        assert!(parse("palette.dominant_hue between 0 360").matches(&record(Some(359.0), &[], Value::Null)));
+        // Gray palettes have no dominant hue.
+        assert!(!parse("palette.dominant_hue between 0 360").matches(&record(None, &[], Value::Null)));
+        assert!(!parse("palette.dominant_hue != 10").matches(&record(None, &[], Value::Null)));
+    }
+
+    #[test]
+    fn formats_are_parsed() {
+        for (name, format) in QueryFormat::NAMES.into_iter().zip([QueryFormat::Table, QueryFormat::Json, QueryFormat::Paths]) {
+            assert_eq!(name.parse(), Ok(format));
+            assert_eq!(name.to_uppercase().parse(), Ok(format));
+        }
+        assert!("csv".parse::<QueryFormat>().is_err());
+    }
+}
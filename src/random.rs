@@ -0,0 +1,451 @@
+use nalgebra::Transform;
+use rand::distributions::Distribution;
+use rand::Rng;
+
+use super::core::*;
+
+/// How a child's palette is made from its parents' palettes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PaletteMix {
+    /// Take one parent's palette whole.
+    Pick,
+    /// Join the start of one parent's control colors to the end of the other's.
+    Splice,
+    /// Interpolate between the parents' control colors.
+    Lerp,
+    /// Choose one of the above at random for each child.
+    Any,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CrossoverOptions {
+    /// Probability that a function slot whose entries share a variation is
+    /// interpolated rather than taken whole from one parent.
+    pub lerp_probability: f64,
+    pub palette: PaletteMix,
+    /// Probability that each numeric parameter of the child is mutated.
+    pub mutation_rate: f64,
+    /// Standard deviation of the Gaussian noise added by a mutation.
+    pub mutation_scale: f32,
+}
+
+impl Default for CrossoverOptions {
+    fn default() -> Self {
+        CrossoverOptions {
+            lerp_probability: 0.5,
+            palette: PaletteMix::Any,
+            mutation_rate: 0.05,
+            mutation_scale: 0.05,
+        }
+    }
+}
+
+/// Produce a child flame mixing the functions, palettes and bounds of two parents.
+///
+/// Function slots present in both parents are either taken whole from one of
+/// them or, when their variations agree, interpolated. Slots present in only
+/// one parent are kept with even odds. The child's weights are renormalized
+/// and a fraction of its parameters are then perturbed with Gaussian noise.
+pub fn crossover(a: &Flame, b: &Flame, rng: &mut impl Rng, opts: CrossoverOptions) -> Flame {
+    let mut functions = Vec::new();
+    for i in 0 .. a.functions.len().max(b.functions.len()) {
+        match (a.functions.get(i), b.functions.get(i)) {
+            (Some(fa), Some(fb)) => {
+                if fa.var.same_kind(&fb.var) && rng.gen_bool(opts.lerp_probability) {
+                    functions.push(fa.lerp(fb, rng.gen()));
+                } else {
+                    functions.push(if rng.gen() { *fa } else { *fb });
+                }
+            }
+            (Some(f), None) | (None, Some(f)) => {
+                if rng.gen() {
+                    functions.push(*f);
+                }
+            }
+            (None, None) => unreachable!(),
+        }
+    }
+    if functions.is_empty() {
+        functions.push(a.functions[0]);
+    }
+
+    let t = rng.gen();
+    let (ba, bb) = (a.bounds.to_array(), b.bounds.to_array());
+    let bounds = [0, 1, 2, 3].map(|i| lerp(ba[i], bb[i], t));
+
+    let mut child = Flame {
+        functions,
+        palette: mix_palettes(&a.palette, &b.palette, rng, opts.palette),
+        bounds: Bounds::new(bounds[0], bounds[1], bounds[2], bounds[3]),
+        mask: if rng.gen() { a.mask.clone() } else { b.mask.clone() },
+        color_model: a.color_model,
+    };
+
+    mutate(&mut child, rng, opts);
+    normalize_weights(&mut child.functions);
+    child
+}
+
+/// Linear interpolation, written so that equal endpoints are returned exactly.
+fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a + (b - a) * t
+}
+
+fn mix_palettes(a: &Palette, b: &Palette, rng: &mut impl Rng, mix: PaletteMix) -> Palette {
+    let mix = match mix {
+        PaletteMix::Any => [PaletteMix::Pick, PaletteMix::Splice, PaletteMix::Lerp][rng.gen_range(0 .. 3)],
+        m => m,
+    };
+    let (ka, kb) = (a.keys_with_alpha(), b.keys_with_alpha());
+
+    let keys: Vec<ColorA> = match mix {
+        PaletteMix::Pick => if rng.gen() { ka.to_vec() } else { kb.to_vec() },
+        PaletteMix::Splice => {
+            let split = rng.gen_range(0.0 ..= 1.0f32);
+            let na = (ka.len() as f32 * split).round() as usize;
+            let nb = (kb.len() as f32 * split).round() as usize;
+            ka[.. na].iter().chain(&kb[nb ..]).copied().collect()
+        }
+        PaletteMix::Lerp | PaletteMix::Any => {
+            let t = rng.gen();
+            if ka.len() == kb.len() {
+                ka.iter().zip(&kb).map(|(&x, &y)| x.lerp(y, t, ColorSpace::Srgb)).collect()
+            } else {
+                let n = ka.len().max(kb.len());
+                (0 .. n).map(|i| {
+                    let pos = i as f32 / (n - 1).max(1) as f32;
+                    a.sample_at(pos).lerp(b.sample_at(pos), t, ColorSpace::Srgb)
+                }).collect()
+            }
+        }
+    };
+
+    Palette::from_keys_with_alpha(keys).unwrap_or_else(|_| a.clone())
+}
+
+/// A sample from the standard normal distribution, by the Box-Muller transform.
+fn gaussian(rng: &mut impl Rng) -> f32 {
+    let u1: f32 = 1.0 - rng.gen::<f32>();
+    let u2: f32 = rng.gen();
+    (-2.0 * u1.ln()).sqrt() * (std::f32::consts::TAU * u2).cos()
+}
+
+fn mutate(flame: &mut Flame, rng: &mut impl Rng, opts: CrossoverOptions) {
+    if opts.mutation_rate <= 0.0 {
+        return;
+    }
+    let mut jitter = |x: f32, scale: f32| {
+        if rng.gen_bool(opts.mutation_rate.min(1.0)) { x + gaussian(rng) * scale } else { x }
+    };
+
+    for f in &mut flame.functions {
+        f.weight = jitter(f.weight, opts.mutation_scale).abs();
+        f.color = jitter(f.color as f32, opts.mutation_scale * 255.).clamp(0., 255.) as u8;
+        let params: Vec<f32> = f.var.params().iter().map(|&p| jitter(p, opts.mutation_scale)).collect();
+        f.var = f.var.with_params(&params);
+        let mut m = *f.trans.matrix();
+        for i in 0 .. 2 {
+            for j in 0 .. 3 {
+                m[(i, j)] = jitter(m[(i, j)], opts.mutation_scale);
+            }
+        }
+        f.trans = Transform::from_matrix_unchecked(m);
+    }
+    // Blends are jittered four times as much as other parameters so that a
+    // mutated blend is visibly partial. Those at one can only be lowered, so
+    // half the mutations of a full blend leave it as it was.
+    for f in &mut flame.functions {
+        f.axis_blend = f.axis_blend.map(|b| jitter(b, opts.mutation_scale * 4.).clamp(0., 1.));
+    }
+}
+
+/// Rescale weights to sum to one, leaving them alone if they already do.
+fn normalize_weights(functions: &mut [Function]) {
+    let total: f32 = functions.iter().map(|f| f.weight).sum();
+    if total > 0.0 && (total - 1.0).abs() > 1e-6 {
+        for f in functions.iter_mut() {
+            f.weight /= total;
+        }
+    }
+}
+
+/// A color scheme relating the hues of a palette to a base hue.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Harmony {
+    /// Hues within `spread` degrees centered on the base.
+    Analogous { spread: f32 },
+    /// The base hue and the one opposite it.
+    Complementary,
+    /// Three hues evenly spaced around the wheel.
+    Triadic,
+    /// The base hue alone, at lightnesses within `lightness_range`.
+    Monochrome { lightness_range: [f32; 2] },
+}
+
+impl Harmony {
+    /// Names of the schemes, as accepted by `from_str`.
+    pub const NAMES: [&'static str; 4] = ["analogous", "complementary", "triadic", "mono"];
+}
+
+impl std::str::FromStr for Harmony {
+    type Err = String;
+
+    /// Parses `analogous[:SPREAD]`, `complementary`, `triadic` or
+    /// `mono[:MIN-MAX]`, as in `analogous:60` or `mono:0.3-0.8`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || format!(
+            "unknown palette harmony '{}' (expected analogous[:SPREAD], complementary, triadic or mono[:MIN-MAX])", s,
+        );
+        let (name, arg) = s.split_once(':').map_or((s, None), |(n, a)| (n, Some(a)));
+        match (name.to_ascii_lowercase().as_str(), arg) {
+            ("analogous", arg) => {
+                let spread: f32 = arg.map_or(Ok(60.0), str::parse).map_err(|_| err())?;
+                if (0.0 ..= 360.0).contains(&spread) { Ok(Harmony::Analogous { spread }) } else { Err(err()) }
+            }
+            ("complementary", None) => Ok(Harmony::Complementary),
+            ("triadic", None) => Ok(Harmony::Triadic),
+            ("mono", None) => Ok(Harmony::Monochrome { lightness_range: [0.25, 0.9] }),
+            ("mono", Some(range)) => {
+                let (min, max) = range.split_once('-').ok_or_else(err)?;
+                let range = [min.parse().map_err(|_| err())?, max.parse().map_err(|_| err())?];
+                if 0.0 <= range[0] && range[0] <= range[1] && range[1] <= 1.0 {
+                    Ok(Harmony::Monochrome { lightness_range: range })
+                } else {
+                    Err(err())
+                }
+            }
+            _ => Err(err()),
+        }
+    }
+}
+
+impl std::fmt::Display for Harmony {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Harmony::Analogous { spread } => write!(f, "analogous:{}", spread),
+            Harmony::Complementary => write!(f, "complementary"),
+            Harmony::Triadic => write!(f, "triadic"),
+            Harmony::Monochrome { lightness_range: [min, max] } => write!(f, "mono:{}-{}", min, max),
+        }
+    }
+}
+
+/// Random palettes whose colors follow a harmony, chosen in Oklch with
+/// moderate chroma and ordered from dark to light so the gradient reads
+/// smoothly.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HarmonyPaletteDistribution {
+    pub harmony: Harmony,
+    /// Base hue in degrees, or `None` to pick one uniformly.
+    pub base_hue: Option<f32>,
+    /// Number of control colors.
+    pub stops: usize,
+    pub lightness_range: [f32; 2],
+    pub chroma_range: [f32; 2],
+}
+
+impl HarmonyPaletteDistribution {
+    pub fn new(harmony: Harmony) -> Self {
+        HarmonyPaletteDistribution {
+            harmony,
+            base_hue: None,
+            stops: 5,
+            lightness_range: [0.25, 0.9],
+            chroma_range: [0.05, 0.18],
+        }
+    }
+
+    pub fn with_base_hue(mut self, hue: f32) -> Self {
+        self.base_hue = Some(hue);
+        self
+    }
+}
+
+/// Largest random offset in degrees from the hues of the complementary and
+/// triadic schemes, so their colors are not all alike.
+const HUE_JITTER: f32 = 15.0;
+
+impl Distribution<Palette> for HarmonyPaletteDistribution {
+    fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> Palette {
+        let base = self.base_hue.unwrap_or_else(|| rng.gen_range(0.0 .. 360.0));
+        let uniform = |rng: &mut R, [lo, hi]: [f32; 2]| if lo < hi { rng.gen_range(lo ..= hi) } else { lo };
+        let mut colors: Vec<Oklch> = (0 .. self.stops.max(1)).map(|_| {
+            let jitter = |rng: &mut R| rng.gen_range(-HUE_JITTER ..= HUE_JITTER);
+            let (hue, lightness) = match self.harmony {
+                Harmony::Analogous { spread } => {
+                    (base + uniform(rng, [-spread / 2.0, spread / 2.0]), uniform(rng, self.lightness_range))
+                }
+                Harmony::Complementary => {
+                    (base + 180.0 * rng.gen_range(0 .. 2) as f32 + jitter(rng), uniform(rng, self.lightness_range))
+                }
+                Harmony::Triadic => {
+                    (base + 120.0 * rng.gen_range(0 .. 3) as f32 + jitter(rng), uniform(rng, self.lightness_range))
+                }
+                Harmony::Monochrome { lightness_range } => (base, uniform(rng, lightness_range)),
+            };
+            Oklch { l: lightness, c: uniform(rng, self.chroma_range), h: hue.rem_euclid(360.0) }
+        }).collect();
+        colors.sort_by(|a, b| a.l.total_cmp(&b.l));
+
+        Palette::from_keys(colors.into_iter().map(Color::from_oklch).collect())
+            .expect("between one and 256 stops")
+    }
+}
+
+/// Whether a flame is fit to be saved: `Flame::validate` reports no errors.
+pub fn is_valid(flame: &Flame) -> bool {
+    flame.validate().iter().all(|f| f.severity < Severity::Error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::presets;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn exact(palette: PaletteMix) -> CrossoverOptions {
+        CrossoverOptions { lerp_probability: 0.5, palette, mutation_rate: 0.0, mutation_scale: 0.05 }
+    }
+
+    fn same(a: &Flame, b: &Flame) -> bool {
+        a.functions == b.functions && a.palette == b.palette && a.bounds == b.bounds
+            && a.color_model == b.color_model
+    }
+
+    #[test]
+    fn children_of_identical_parents_without_mutation_are_the_parent() {
+        for parent in [presets::fern(), presets::gasket(), presets::swirl()] {
+            for mix in [PaletteMix::Pick, PaletteMix::Splice, PaletteMix::Lerp, PaletteMix::Any] {
+                for seed in 0 .. 20 {
+                    let child = crossover(&parent, &parent, &mut StdRng::seed_from_u64(seed), exact(mix));
+                    assert!(same(&child, &parent), "{:?} with seed {}", mix, seed);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn children_are_determined_by_the_seed() {
+        let (a, b) = (presets::fern(), presets::gasket());
+        let opts = CrossoverOptions::default();
+        let child = |seed| crossover(&a, &b, &mut StdRng::seed_from_u64(seed), opts);
+        assert!(same(&child(3), &child(3)));
+        assert!((0 .. 10).any(|seed| !same(&child(seed), &child(3))));
+    }
+
+    #[test]
+    fn interpolated_functions_lie_between_their_parents() {
+        let a = presets::gasket();
+        let mut b = a.clone();
+        for f in &mut b.functions {
+            f.trans = Transform::from_matrix_unchecked(f.trans.matrix() * 2.0);
+            f.color = 200;
+        }
+        let opts = CrossoverOptions { lerp_probability: 1.0, ..exact(PaletteMix::Pick) };
+        for seed in 0 .. 10 {
+            let child = crossover(&a, &b, &mut StdRng::seed_from_u64(seed), opts);
+            assert_eq!(child.functions.len(), 3);
+            for ((c, fa), fb) in child.functions.iter().zip(&a.functions).zip(&b.functions) {
+                for (i, ((x, lo), hi)) in c.trans.matrix().iter().zip(fa.trans.matrix().iter()).zip(fb.trans.matrix().iter()).enumerate() {
+                    let (lo, hi) = (lo.min(*hi), lo.max(*hi));
+                    assert!(lo - 1e-6 <= *x && *x <= hi + 1e-6, "entry {} is {} outside [{}, {}]", i, x, lo, hi);
+                }
+            }
+            let bounds = child.bounds.to_array();
+            for (x, (lo, hi)) in bounds.iter().zip(a.bounds.to_array().iter().zip(b.bounds.to_array())) {
+                assert!(lo.min(hi) <= *x && *x <= lo.max(hi));
+            }
+        }
+    }
+
+    #[test]
+    fn slots_in_one_parent_only_are_sometimes_kept() {
+        let (a, b) = (presets::fern(), presets::starter());
+        let lengths: Vec<usize> = (0 .. 40)
+            .map(|seed| crossover(&a, &b, &mut StdRng::seed_from_u64(seed), exact(PaletteMix::Pick)).functions.len())
+            .collect();
+        assert!(lengths.iter().all(|&n| (2 ..= 4).contains(&n)));
+        assert!(lengths.contains(&2) && lengths.contains(&4));
+    }
+
+    #[test]
+    fn mutation_perturbs_parameters_and_keeps_weights_normalized() {
+        let parent = presets::fern();
+        let opts = CrossoverOptions { mutation_rate: 1.0, mutation_scale: 0.1, ..exact(PaletteMix::Pick) };
+        let child = crossover(&parent, &parent, &mut StdRng::seed_from_u64(1), opts);
+        assert!(child.functions.iter().zip(&parent.functions).all(|(c, p)| c.trans.matrix() != p.trans.matrix()));
+        let total: f32 = child.functions.iter().map(|f| f.weight).sum();
+        assert!((total - 1.0).abs() < 1e-5 && child.functions.iter().all(|f| f.weight >= 0.0));
+        assert!(child.functions.iter().all(|f| f.axis_blend.iter().all(|b| (0.0 ..= 1.0).contains(b))));
+    }
+
+    #[test]
+    fn children_of_valid_parents_are_usually_valid() {
+        let (a, b) = (presets::fern(), presets::swirl());
+        assert!(is_valid(&a) && is_valid(&b));
+        let valid = (0 .. 20)
+            .filter(|&seed| is_valid(&crossover(&a, &b, &mut StdRng::seed_from_u64(seed), exact(PaletteMix::Any))))
+            .count();
+        assert!(valid >= 10, "{} of 20 children are valid", valid);
+    }
+
+    /// Signed difference from `base` to `hue`, in degrees between -180 and 180.
+    fn hue_offset(hue: f32, base: f32) -> f32 {
+        (hue - base + 180.0).rem_euclid(360.0) - 180.0
+    }
+
+    #[test]
+    fn harmonies_parse_with_their_defaults_and_ranges() {
+        assert_eq!("analogous".parse(), Ok(Harmony::Analogous { spread: 60.0 }));
+        assert_eq!("Analogous:90".parse(), Ok(Harmony::Analogous { spread: 90.0 }));
+        assert_eq!("mono:0.3-0.8".parse(), Ok(Harmony::Monochrome { lightness_range: [0.3, 0.8] }));
+        for bad in ["analogous:400", "mono:0.8-0.3", "triadic:2", "tetradic"] {
+            assert!(bad.parse::<Harmony>().is_err(), "{}", bad);
+        }
+        for harmony in ["analogous:45", "complementary", "triadic", "mono:0.2-0.6"] {
+            assert_eq!(harmony.parse::<Harmony>().unwrap().to_string(), harmony);
+        }
+    }
+
+    #[test]
+    fn analogous_hues_stay_within_their_window() {
+        let (base, spread) = (210.0, 40.0);
+        let dist = HarmonyPaletteDistribution::new(Harmony::Analogous { spread }).with_base_hue(base);
+        let mut rng = StdRng::seed_from_u64(5);
+        let offsets: Vec<f32> = (0 .. 200)
+            .flat_map(|_| dist.sample(&mut rng).keys().to_vec())
+            .map(|color| hue_offset(color.to_oklch().h, base))
+            .collect();
+        // Rounding to 8 bits moves the hue of the dullest colors a little.
+        assert!(offsets.iter().all(|o| o.abs() <= spread / 2.0 + 4.0), "{:?}", offsets);
+        let mean = offsets.iter().sum::<f32>() / offsets.len() as f32;
+        assert!(mean.abs() < 2.0, "mean offset {}", mean);
+        let wide = offsets.iter().filter(|o| o.abs() > spread / 4.0).count();
+        assert!(wide > offsets.len() / 4, "{} of {} in the outer half", wide, offsets.len());
+    }
+
+    #[test]
+    fn stops_run_from_dark_to_light() {
+        for harmony in ["analogous", "complementary", "triadic", "mono"] {
+            let dist = HarmonyPaletteDistribution::new(harmony.parse().unwrap());
+            let mut rng = StdRng::seed_from_u64(8);
+            for _ in 0 .. 20 {
+                let palette = dist.sample(&mut rng);
+                let lightness: Vec<f32> = palette.keys().iter().map(|c| c.to_oklch().l).collect();
+                assert_eq!(lightness.len(), dist.stops);
+                assert!(lightness.windows(2).all(|w| w[0] <= w[1] + 0.01), "{}: {:?}", harmony, lightness);
+            }
+        }
+    }
+
+    #[test]
+    fn complementary_hues_cluster_on_opposite_sides() {
+        let dist = HarmonyPaletteDistribution::new(Harmony::Complementary).with_base_hue(30.0);
+        let mut rng = StdRng::seed_from_u64(13);
+        for color in (0 .. 50).flat_map(|_| dist.sample(&mut rng).keys().to_vec()) {
+            let offset = hue_offset(color.to_oklch().h, 30.0).abs();
+            assert!(offset <= HUE_JITTER + 4.0 || offset >= 180.0 - HUE_JITTER - 4.0, "{:?}", color);
+        }
+    }
+}
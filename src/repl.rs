@@ -0,0 +1,499 @@
+//! An interactive session which edits a descriptor while keeping the
+//! histogram it has accumulated so far.
+//!
+//! Commands are plain text, one per line:
+//!
+//! - `set PATH EXPR` assigns an expression to a number in the descriptor,
+//!   with paths as for `animate --mod`, e.g. `set functions[0][0] 0.9`.
+//! - `iters N` runs N more iterations, e.g. `iters 50M`.
+//! - `tonemap KEY=VALUE ...` changes how the histogram is turned into an
+//!   image: `gamma`, `vibrancy`, `preserve_color`, `grayscale`, `background`,
+//!   `dither` or `curve`, which adds to the tone curves as in
+//!   `curve=r:lift=0.02,gain=1.1`.
+//! - `bounds X_MIN X_MAX Y_MIN Y_MAX` moves the view, and `bounds auto` fits
+//!   it to the attractor at the image's aspect ratio.
+//! - `save PATH` writes the image, or the descriptor if PATH ends in `.json`.
+//! - `undo` reverts the last `set` or `bounds`.
+//! - `show` returns a preview of the image.
+//! - `stats` describes the accumulator.
+//! - `help` lists the commands and `quit` ends the session.
+//!
+//! Edits which change the flame restart the accumulator; tonemapping edits
+//! do not.
+
+use std::path::{Path, PathBuf};
+
+use serde_json::Value;
+
+use super::animation::{AnimationError, Expr, ValuePath, Vars};
+use super::cli_types::SiCount;
+use super::core::*;
+use super::file::{DescriptorError, FlameSource};
+
+const HELP: &str = "\
+set PATH EXPR            assign to a number in the descriptor
+iters N                  run N more iterations
+tonemap KEY=VALUE ...    set gamma, vibrancy, preserve_color, grayscale, background, dither or curve
+bounds X0 X1 Y0 Y1       move the view
+bounds auto              fit the view to the attractor
+save PATH                write the image, or the descriptor to a .json path
+undo                     revert the last set or bounds
+show                     preview the image
+stats                    describe the accumulator
+quit                     end the session";
+
+#[derive(Debug)]
+pub enum ReplError {
+    /// The command could not be understood.
+    Command(String),
+    /// There are no edits left to undo.
+    NothingToUndo,
+    Animation(AnimationError),
+    Descriptor(DescriptorError),
+    Image(image::ImageError),
+    Buffer(BufferError),
+    Io(std::io::Error),
+}
+
+impl std::fmt::Display for ReplError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReplError::Command(e) => write!(f, "{}", e),
+            ReplError::NothingToUndo => write!(f, "nothing to undo"),
+            ReplError::Animation(e) => write!(f, "{}", e),
+            ReplError::Descriptor(e) => write!(f, "{}", e),
+            ReplError::Image(e) => write!(f, "could not save image: {}", e),
+            ReplError::Buffer(e) => write!(f, "{}", e),
+            ReplError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for ReplError {}
+
+impl From<AnimationError> for ReplError {
+    fn from(e: AnimationError) -> Self { ReplError::Animation(e) }
+}
+
+impl From<DescriptorError> for ReplError {
+    fn from(e: DescriptorError) -> Self { ReplError::Descriptor(e) }
+}
+
+impl From<serde_json::Error> for ReplError {
+    fn from(e: serde_json::Error) -> Self { ReplError::Descriptor(DescriptorError::Json(e)) }
+}
+
+impl From<image::ImageError> for ReplError {
+    fn from(e: image::ImageError) -> Self { ReplError::Image(e) }
+}
+
+impl From<BufferError> for ReplError {
+    fn from(e: BufferError) -> Self { ReplError::Buffer(e) }
+}
+
+impl From<std::io::Error> for ReplError {
+    fn from(e: std::io::Error) -> Self { ReplError::Io(e) }
+}
+
+/// The result of a command.
+pub enum Response {
+    /// Text to show, which may be empty.
+    Text(String),
+    /// A tonemapped image of the histogram so far.
+    Preview(Buffer<u8>),
+    /// The session is over.
+    Quit,
+}
+
+/// Threads an interactive session iterates on unless told otherwise: one
+/// less than the number of cores, leaving one for the terminal and
+/// whatever else is running, and at least one.
+pub fn default_threads() -> usize {
+    std::thread::available_parallelism().map_or(1, |n| n.get()).saturating_sub(1).max(1)
+}
+
+/// A descriptor being edited and the histogram of its current flame.
+pub struct Session {
+    descriptor: Value,
+    base: PathBuf,
+    /// Descriptors before each `set` or `bounds`, most recent last.
+    history: Vec<Value>,
+    flame: Flame,
+    run_cfg: RunConfig,
+    tone: ToneMapping,
+    encoding: OutputEncoding,
+    accumulator: RenderSession,
+}
+
+impl Session {
+    /// Start editing a descriptor, resolving paths in it against `base`.
+    ///
+    /// The iteration count of `run_cfg` is ignored: iterations are run on
+    /// request by `iters`, split between `run_cfg.threads` threads as in a
+    /// whole render. The threads are started by the first `iters` and wait
+    /// for the next between commands. With `stable_chunk_iters` set, the
+    /// histogram after a given number of iterations is the same whatever
+    /// the thread count.
+    pub fn new(
+        descriptor: Value,
+        base: impl AsRef<Path>,
+        run_cfg: RunConfig,
+        tone: ToneMapping,
+        encoding: OutputEncoding,
+    ) -> Result<Session, ReplError> {
+        let base = base.as_ref().to_path_buf();
+        let flame = FlameSource::from_value(descriptor.clone(), &base)?.to_flame()?;
+        // Orbits are given an unbounded quota, so that `iters` can always run
+        // more, and colors are always kept as `tonemap` can turn grayscale off.
+        let run_cfg = RunConfig { iters: usize::MAX, monochrome: false, ..run_cfg };
+        let accumulator = RenderSession::new(flame.clone(), run_cfg)?;
+        Ok(Session { descriptor, base, history: Vec::new(), flame, run_cfg, tone, encoding, accumulator })
+    }
+
+    pub fn descriptor(&self) -> &Value {
+        &self.descriptor
+    }
+
+    pub fn flame(&self) -> &Flame {
+        &self.flame
+    }
+
+    pub fn tone_mapping(&self) -> ToneMapping {
+        self.tone
+    }
+
+    pub fn output_encoding(&self) -> OutputEncoding {
+        self.encoding
+    }
+
+    /// Number of iterations accumulated since the flame last changed.
+    pub fn iters(&self) -> u64 {
+        self.accumulator.iters()
+    }
+
+    /// Number of edits which can be undone.
+    pub fn undo_depth(&self) -> usize {
+        self.history.len()
+    }
+
+    /// Run a single command.
+    pub fn execute(&mut self, cmd: &str) -> Result<Response, ReplError> {
+        let cmd = cmd.trim();
+        let (name, rest) = cmd.split_once(char::is_whitespace).unwrap_or((cmd, ""));
+        let rest = rest.trim();
+        let usage = |u: &str| ReplError::Command(format!("usage: {}", u));
+
+        match name {
+            "" => Ok(Response::Text(String::new())),
+            "set" => {
+                let (path, expr) = rest.split_once(char::is_whitespace).ok_or_else(|| usage("set PATH EXPR"))?;
+                let path: ValuePath = path.parse()?;
+                let expr: Expr = expr.parse()?;
+                let mut descriptor = self.descriptor.clone();
+                path.set(&mut descriptor, expr.eval(Vars { t: 0.0, n: 0.0 }))?;
+                let reset = self.replace_descriptor(descriptor)?;
+                Ok(reset_notice(reset))
+            }
+            "iters" => {
+                let n: SiCount = rest.parse().map_err(|_| usage("iters N"))?;
+                self.accumulator.advance(n.0);
+                Ok(Response::Text(self.accumulator.stats().to_string()))
+            }
+            "tonemap" => {
+                if rest.is_empty() {
+                    return Err(usage("tonemap KEY=VALUE ..."));
+                }
+                let (mut tone, mut encoding) = (self.tone, self.encoding);
+                for setting in rest.split_whitespace() {
+                    set_tonemap(&mut tone, &mut encoding, setting)?;
+                }
+                (self.tone, self.encoding) = (tone, encoding);
+                Ok(Response::Text(String::new()))
+            }
+            "bounds" => {
+                let bounds = if rest == "auto" {
+                    let aspect = self.run_cfg.width as f32 / self.run_cfg.height as f32;
+                    let fitted = self.flame.fit_bounds(aspect)
+                        .ok_or_else(|| ReplError::Command("cannot fit bounds to an orbit which never stays finite".to_string()))?;
+                    fitted.to_array().map(f64::from)
+                } else {
+                    let values: Vec<f64> = rest.split_whitespace()
+                        .map(str::parse)
+                        .collect::<Result<_, _>>()
+                        .map_err(|_| usage("bounds X0 X1 Y0 Y1 | bounds auto"))?;
+                    let [x_min, x_max, y_min, y_max] = values[..] else {
+                        return Err(usage("bounds X0 X1 Y0 Y1 | bounds auto"));
+                    };
+                    if !values.iter().all(|x| x.is_finite()) || x_min >= x_max || y_min >= y_max {
+                        return Err(ReplError::Command("bounds must be finite with X0 < X1 and Y0 < Y1".to_string()));
+                    }
+                    [x_min, x_max, y_min, y_max]
+                };
+                let mut descriptor = self.descriptor.clone();
+                descriptor["bounds"] = Value::from(bounds.to_vec());
+                let reset = self.replace_descriptor(descriptor)?;
+                Ok(reset_notice(reset))
+            }
+            "save" => {
+                if rest.is_empty() {
+                    return Err(usage("save PATH"));
+                }
+                let path = Path::new(rest);
+                if path.extension().is_some_and(|e| e.eq_ignore_ascii_case("json")) {
+                    std::fs::write(path, serde_json::to_string_pretty(&self.descriptor)?)?;
+                } else {
+                    self.image().to_image(self.encoding.grayscale)?.save(path)?;
+                }
+                Ok(Response::Text(format!("saved '{}'", path.display())))
+            }
+            "undo" => {
+                let previous = self.history.pop().ok_or(ReplError::NothingToUndo)?;
+                let reset = self.set_descriptor(previous)?;
+                Ok(reset_notice(reset))
+            }
+            "show" => Ok(Response::Preview(self.image())),
+            "stats" => Ok(Response::Text(self.accumulator.stats().to_string())),
+            "help" => Ok(Response::Text(HELP.to_string())),
+            "quit" | "exit" => Ok(Response::Quit),
+            _ => Err(ReplError::Command(format!("unknown command '{}' (try 'help')", name))),
+        }
+    }
+
+    fn image(&self) -> Buffer<u8> {
+        self.accumulator.snapshot_8bit(&self.tone, self.encoding.dither)
+    }
+
+    /// Replace the descriptor, remembering the old one for `undo`. Returns
+    /// whether the accumulator was reset.
+    fn replace_descriptor(&mut self, descriptor: Value) -> Result<bool, ReplError> {
+        let previous = self.descriptor.clone();
+        let reset = self.set_descriptor(descriptor)?;
+        self.history.push(previous);
+        Ok(reset)
+    }
+
+    /// Replace the descriptor, restarting the accumulator only if the flame
+    /// it describes is different. Nothing changes if it is invalid.
+    fn set_descriptor(&mut self, descriptor: Value) -> Result<bool, ReplError> {
+        let flame = FlameSource::from_value(descriptor.clone(), &self.base)?.to_flame()?;
+        let reset = !flame.eq_structural(&self.flame);
+        if reset {
+            self.accumulator = RenderSession::new(flame.clone(), self.run_cfg)?;
+        }
+        self.descriptor = descriptor;
+        self.flame = flame;
+        Ok(reset)
+    }
+}
+
+fn reset_notice(reset: bool) -> Response {
+    Response::Text(if reset { "accumulator reset".to_string() } else { String::new() })
+}
+
+/// Apply a tonemapping setting written `key=value`.
+fn set_tonemap(tone: &mut ToneMapping, encoding: &mut OutputEncoding, setting: &str) -> Result<(), ReplError> {
+    let invalid = || ReplError::Command(format!("invalid tonemap setting '{}'", setting));
+    let (key, value) = setting.split_once('=').ok_or_else(invalid)?;
+    match key {
+        "gamma" => tone.gamma = value.parse().map_err(|_| invalid())?,
+        "vibrancy" => tone.vibrancy = value.parse().map_err(|_| invalid())?,
+        "preserve_color" => tone.preserve_color = value.parse().map_err(|_| invalid())?,
+        "grayscale" => encoding.grayscale = value.parse().map_err(|_| invalid())?,
+        "background" => tone.background = value.parse().map_err(|_| invalid())?,
+        "dither" => encoding.dither = value.parse().map_err(|_| invalid())?,
+        "curve" => {
+            let mut curves = tone.curves.unwrap_or_default();
+            curves.set(value).map_err(ReplError::Command)?;
+            tone.curves = Some(curves);
+        }
+        _ => {
+            return Err(ReplError::Command(format!(
+                "unknown tonemap setting '{}' (expected gamma, vibrancy, preserve_color, grayscale, background, dither or curve)",
+                key,
+            )));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bench::baseline_config;
+    use crate::presets;
+
+    fn session() -> Session {
+        session_with(RunConfig { width: 24, height: 16, ..baseline_config(2) })
+    }
+
+    fn session_with(run_cfg: RunConfig) -> Session {
+        let mut bytes = Vec::new();
+        FlameSource::from_flame(&presets::gasket()).to_writer(&mut bytes).unwrap();
+        let descriptor = serde_json::from_slice(&bytes).unwrap();
+        Session::new(descriptor, ".", run_cfg, ToneMapping::default(), OutputEncoding::default()).unwrap()
+    }
+
+    fn text(response: Response) -> String {
+        match response {
+            Response::Text(text) => text,
+            _ => panic!("expected text"),
+        }
+    }
+
+    fn error(session: &mut Session, cmd: &str) -> String {
+        match session.execute(cmd) {
+            Err(e) => e.to_string(),
+            Ok(_) => panic!("'{}' succeeded", cmd),
+        }
+    }
+
+    #[test]
+    fn edits_reset_the_accumulator_and_tonemapping_does_not() {
+        let mut session = session();
+        text(session.execute("iters 20k").unwrap());
+        assert_eq!(session.iters(), 20_000);
+
+        assert_eq!(text(session.execute("tonemap gamma=1.5 vibrancy=0.5").unwrap()), "");
+        assert_eq!((session.tone_mapping().gamma, session.tone_mapping().vibrancy), (1.5, 0.5));
+        assert_eq!(session.iters(), 20_000);
+
+        assert_eq!(text(session.execute("set functions[0][0] 0.5 + 0.4").unwrap()), "accumulator reset");
+        assert_eq!(session.iters(), 0);
+        assert_eq!(session.flame().functions[0].weight, 0.9);
+        assert_eq!(session.descriptor()["functions"][0][0], 0.9);
+
+        // Setting the value it already has leaves the histogram alone.
+        text(session.execute("iters 5k").unwrap());
+        assert_eq!(text(session.execute("set functions[0][0] 0.9").unwrap()), "");
+        assert_eq!(session.iters(), 5_000);
+        assert_eq!(session.undo_depth(), 2);
+    }
+
+    #[test]
+    fn stable_sessions_accumulate_the_same_histogram_on_any_number_of_threads() {
+        let histogram = |threads| {
+            let mut session = session_with(RunConfig {
+                width: 24,
+                height: 16,
+                stable_chunk_iters: Some(10_000),
+                ..baseline_config(threads)
+            });
+            let mut histograms = Vec::new();
+            // Runs ending within chunks, a restart and runs after it.
+            for cmd in ["iters 25k", "iters 15k", "set functions[1][0] 0.4", "iters 5k", "iters 32k"] {
+                session.execute(cmd).unwrap();
+                let buffer = session.accumulator.buffer();
+                histograms.push(buffer.buckets().iter().map(|b| [b.alpha, b.red, b.green, b.blue]).collect::<Vec<_>>());
+            }
+            histograms
+        };
+        let single = histogram(1);
+        assert!(single[1] != single[0] && single[2].iter().all(|b| b[0] == 0));
+        for threads in [2, 4] {
+            assert!(histogram(threads) == single, "{} threads", threads);
+        }
+    }
+
+    #[test]
+    fn undo_walks_back_through_the_edits() {
+        let mut session = session();
+        let original = session.descriptor().clone();
+        text(session.execute("set functions[1][0] 2").unwrap());
+        text(session.execute("set functions[1][0] 3").unwrap());
+        text(session.execute("iters 1k").unwrap());
+
+        assert_eq!(text(session.execute("undo").unwrap()), "accumulator reset");
+        assert_eq!(session.flame().functions[1].weight, 2.0);
+        assert_eq!(text(session.execute("undo").unwrap()), "accumulator reset");
+        assert_eq!(session.descriptor(), &original);
+        assert!(session.flame().eq_structural(&presets::gasket()));
+        assert_eq!(session.undo_depth(), 0);
+        assert_eq!(error(&mut session, "undo"), "nothing to undo");
+    }
+
+    #[test]
+    fn bounds_move_the_view_and_can_be_undone() {
+        let mut session = session();
+        let original = session.flame().bounds.to_array();
+        text(session.execute("iters 1k").unwrap());
+
+        assert_eq!(text(session.execute("bounds -0.5 1.5 -0.25 1.25").unwrap()), "accumulator reset");
+        assert_eq!(session.flame().bounds.to_array(), [-0.5, 1.5, -0.25, 1.25]);
+        assert_eq!(session.descriptor()["bounds"][0], -0.5);
+        assert_eq!(session.iters(), 0);
+        assert_eq!(text(session.execute("bounds -0.5 1.5 -0.25 1.25").unwrap()), "");
+
+        // The gasket is a triangle about (0.5, 0.43), framed at the 3:2 aspect
+        // ratio of the image.
+        text(session.execute("iters 1k").unwrap());
+        assert_eq!(text(session.execute("bounds auto").unwrap()), "accumulator reset");
+        let fitted = session.flame().bounds;
+        assert!((fitted.aspect() - 1.5).abs() < 1e-4, "{}", fitted.aspect());
+        let [cx, cy] = fitted.center();
+        assert!((cx - 0.5).abs() < 0.05 && (cy - 0.43).abs() < 0.05, "{:?}", fitted.to_array());
+        assert_eq!(session.iters(), 0);
+
+        assert_eq!(text(session.execute("undo").unwrap()), "accumulator reset");
+        assert_eq!(session.flame().bounds.to_array(), [-0.5, 1.5, -0.25, 1.25]);
+        text(session.execute("undo").unwrap());
+        text(session.execute("undo").unwrap());
+        assert_eq!(session.flame().bounds.to_array(), original);
+        assert_eq!(session.undo_depth(), 0);
+    }
+
+    #[test]
+    fn bad_commands_are_explained_and_change_nothing() {
+        let mut session = session();
+        text(session.execute("iters 1k").unwrap());
+        let descriptor = session.descriptor().clone();
+
+        assert_eq!(error(&mut session, "frobnicate"), "unknown command 'frobnicate' (try 'help')");
+        assert_eq!(error(&mut session, "set functions[0][0]"), "usage: set PATH EXPR");
+        assert_eq!(error(&mut session, "iters lots"), "usage: iters N");
+        assert_eq!(error(&mut session, "tonemap"), "usage: tonemap KEY=VALUE ...");
+        assert_eq!(error(&mut session, "save"), "usage: save PATH");
+        for cmd in ["bounds", "bounds 0 1 0", "bounds 0 1 0 one", "bounds auto 2"] {
+            assert_eq!(error(&mut session, cmd), "usage: bounds X0 X1 Y0 Y1 | bounds auto");
+        }
+        assert_eq!(error(&mut session, "bounds 1 0 0 1"), "bounds must be finite with X0 < X1 and Y0 < Y1");
+        assert_eq!(error(&mut session, "bounds 0 1 0 inf"), "bounds must be finite with X0 < X1 and Y0 < Y1");
+        assert_eq!(error(&mut session, "tonemap gamma"), "invalid tonemap setting 'gamma'");
+        assert_eq!(error(&mut session, "tonemap gamma=high"), "invalid tonemap setting 'gamma=high'");
+        assert!(error(&mut session, "tonemap hue=2").starts_with("unknown tonemap setting 'hue'"));
+        // The gamma before the bad setting is not applied either.
+        assert!(session.execute("tonemap gamma=1.0 hue=2").is_err());
+        assert_eq!(session.tone_mapping().gamma, 2.2);
+        // Edits making an invalid descriptor are refused.
+        assert!(session.execute("set functions[9][0] 1").is_err());
+        assert!(session.execute("set functions[0][1] 1").is_err());
+
+        assert_eq!(session.descriptor(), &descriptor);
+        assert_eq!((session.iters(), session.undo_depth()), (1_000, 0));
+    }
+
+    #[test]
+    fn saving_previews_and_quitting() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut session = session();
+        text(session.execute("iters 10k").unwrap());
+        text(session.execute("set functions[2][0] 0.5").unwrap());
+
+        let json = dir.path().join("edited.json");
+        text(session.execute(&format!("save {}", json.display())).unwrap());
+        let saved = FlameSource::from_path(&json).unwrap().to_flame().unwrap();
+        assert!(saved.eq_structural(session.flame()));
+
+        let png = dir.path().join("edited.png");
+        assert_eq!(text(session.execute(&format!("save {}", png.display())).unwrap()), format!("saved '{}'", png.display()));
+        assert_eq!(image::open(&png).unwrap().width(), 24);
+
+        match session.execute("show").unwrap() {
+            Response::Preview(image) => assert_eq!((image.width(), image.height()), (24, 16)),
+            _ => panic!("expected a preview"),
+        }
+        assert!(text(session.execute("help").unwrap()).contains("undo"));
+        assert_eq!(text(session.execute("   ").unwrap()), "");
+        assert!(matches!(session.execute("quit").unwrap(), Response::Quit));
+        assert!(matches!(session.execute("exit").unwrap(), Response::Quit));
+    }
+}
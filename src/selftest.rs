@@ -0,0 +1,227 @@
+//! Checks that this build computes what it should.
+//!
+//! Compiler flags, math libraries and platforms can each change results in
+//! ways that are hard to spot in a picture. These checks compare a few small
+//! computations against reference values which every correct build
+//! reproduces exactly, using the portable math of `deterministic_math`
+//! where results would otherwise depend on the platform.
+
+use nalgebra::Point2;
+
+use super::core::math::PortableMath;
+use super::core::*;
+use super::meta::fnv1a;
+
+/// Points each variation is evaluated at.
+const POINTS: [[f64; 2]; 4] = [[0.3, -0.7], [-1.2, 0.4], [0.05, 0.9], [0.8, 0.6]];
+
+/// Largest relative difference allowed between a variation computed with
+/// the platform's math in single precision and with portable math.
+const PLATFORM_TOLERANCE: f64 = 1e-4;
+
+/// Hashes of each variation's values at `POINTS`, with default parameters,
+/// in the order of `VariationDiscriminant::ALL`.
+const VARIATION_HASHES: [u64; 29] = [
+    0x443ece165142e08b, 0x6abafd5c0036dbd5, 0x659a35162ad1c0f5, 0x0a9ad93dfdf03d2f,
+    0x4b1dc05399967f53, 0x8a6d2dd1bb3555a8, 0x856cd86d31fe4072, 0xf34470956c49cf05,
+    0xc5f3439473a5503d, 0xa3382c28566c0e2e, 0x51b22112a756244d, 0xa9e2921a7f96fad0,
+    0xbf51120a119e88f5, 0x886019b87d6958ce, 0xd697e567be14a2e4, 0xb8d02ea8f99b563c,
+    0xcaca93bb540ca599, 0xff2a86a8354485eb, 0x5c0f8605c88e96c8, 0x02603134151318aa,
+    0x79fc6bceec6073c8, 0x7a1d95f91fc630a4, 0x8f635562f029582c, 0xa0920a7b80dd6768,
+    0xc04b42fd6dcf5b72, 0xcf16a026e9d67c67, 0x9f8e4eebee7cf318, 0x234a7d4303f92f22,
+    0x614c0216ad898584,
+];
+
+/// Hash of the image of the gasket preset run with `run_config(1)`.
+const RENDER_HASH: u64 = 0xa5091400d3b2b353;
+
+/// Palette positions sampled, and the colors expected there.
+const PALETTE_SAMPLES: [(u8, [u8; 3]); 5] = [
+    (0, [0x00, 0x00, 0x00]),
+    (64, [0x80, 0x40, 0x00]),
+    (128, [0xff, 0x80, 0x00]),
+    (200, [0x6e, 0x5b, 0x90]),
+    (255, [0x00, 0x40, 0xff]),
+];
+
+/// A tiny histogram in alpha, red, green, blue order, and its 8-bit image
+/// under `tone_config`.
+const TONEMAP_INPUT: [u32; 16] = [0, 0, 0, 0, 1, 1, 0, 0, 10, 0, 10, 5, 100, 50, 50, 100];
+const TONEMAP_OUTPUT: [u8; 16] = [0, 0, 0, 0, 0, 0, 0, 0, 186, 0, 255, 93, 255, 255, 174, 255];
+
+/// Results of one group of checks.
+#[derive(Debug, Clone)]
+pub struct SelfTestGroup {
+    pub name: &'static str,
+    /// What went wrong, one line each. Empty if the group passed.
+    pub failures: Vec<String>,
+}
+
+impl SelfTestGroup {
+    pub fn passed(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SelfTestReport {
+    pub groups: Vec<SelfTestGroup>,
+}
+
+impl SelfTestReport {
+    pub fn passed(&self) -> bool {
+        self.groups.iter().all(SelfTestGroup::passed)
+    }
+}
+
+/// Run every check. This takes well under a second, so embedders can run
+/// it at startup.
+pub fn run_all() -> SelfTestReport {
+    SelfTestReport {
+        groups: vec![
+            group("variations", check_variations),
+            group("render", check_render),
+            group("palette", check_palette),
+            group("tonemap", check_tonemap),
+            group("threading", check_threading),
+        ],
+    }
+}
+
+fn group(name: &'static str, check: fn(&mut Vec<String>)) -> SelfTestGroup {
+    let mut failures = Vec::new();
+    check(&mut failures);
+    SelfTestGroup { name, failures }
+}
+
+fn check_variations(failures: &mut Vec<String>) {
+    compare_variations(&VARIATION_HASHES, failures);
+}
+
+fn compare_variations(hashes: &[u64], failures: &mut Vec<String>) {
+    for (kind, &expected) in VariationDiscriminant::ALL.iter().zip(hashes) {
+        let var = kind.with_defaults();
+        let mut bytes = Vec::new();
+        for [x, y] in POINTS {
+            let portable = var.eval_with::<PortableMath, f64>(Point2::new(x, y));
+            bytes.extend(portable.iter().flat_map(|v| v.to_le_bytes()));
+
+            let platform = var.eval(Point2::new(x as f32, y as f32));
+            for (p, q) in platform.iter().zip(portable.iter()) {
+                if (*p as f64 - q).abs() > PLATFORM_TOLERANCE * q.abs().max(1.0) {
+                    failures.push(format!("{:?} at ({}, {}) is {} with platform math, expected {}", kind, x, y, p, q));
+                }
+            }
+        }
+        let hash = fnv1a(&bytes);
+        if hash != expected {
+            failures.push(format!("{:?} with portable math hashes to {:016x}, expected {:016x}", kind, hash, expected));
+        }
+    }
+}
+
+/// A small seeded render whose histogram does not depend on the platform
+/// or the number of threads.
+fn run_config(threads: usize) -> RunConfig {
+    RunConfig {
+        width: 48,
+        height: 48,
+        iters: 200_000,
+        threads,
+        seed: Some(1),
+        deterministic_math: true,
+        precision: Precision::F64,
+        stable_chunk_iters: Some(25_000),
+        ..RunConfig::default()
+    }
+}
+
+fn tone_config() -> ToneMapping {
+    ToneMapping { vibrancy: 1.0, deterministic_math: true, ..ToneMapping::default() }
+}
+
+fn check_render(failures: &mut Vec<String>) {
+    compare_render(RENDER_HASH, failures);
+}
+
+fn compare_render(expected: u64, failures: &mut Vec<String>) {
+    let flame = super::presets::by_name("gasket").expect("gasket is a preset");
+    let image = flame.run(run_config(1)).expect("the self-test image is large enough").render_8bit(&tone_config(), DitherMode::None);
+    let hash = fnv1a(image.as_flat_slice());
+    if hash != expected {
+        failures.push(format!("seeded render hashes to {:016x}, expected {:016x}", hash, expected));
+    }
+}
+
+fn check_palette(failures: &mut Vec<String>) {
+    compare_palette(&PALETTE_SAMPLES, failures);
+}
+
+fn compare_palette(samples: &[(u8, [u8; 3])], failures: &mut Vec<String>) {
+    let keys = vec![Color::rgb(0, 0, 0), Color::rgb(255, 128, 0), Color::rgb(0, 64, 255)];
+    let palette = Palette::from_keys(keys).expect("three keys make a palette");
+    for &(i, [r, g, b]) in samples {
+        let (color, expected) = (palette.sample(i).color, Color::rgb(r, g, b));
+        if color != expected {
+            failures.push(format!("palette position {} is {}, expected {}", i, color, expected));
+        }
+    }
+}
+
+fn check_tonemap(failures: &mut Vec<String>) {
+    compare_tonemap(&TONEMAP_OUTPUT, failures);
+}
+
+fn compare_tonemap(expected: &[u8], failures: &mut Vec<String>) {
+    let histogram = Buffer::from_flat_vec(4, 1, TONEMAP_INPUT.to_vec()).expect("four pixels");
+    let image = histogram.render_8bit(&tone_config(), DitherMode::None);
+    if image.as_flat_slice() != expected {
+        failures.push(format!("tone mapped to {:?}, expected {:?}", image.as_flat_slice(), expected));
+    }
+}
+
+fn check_threading(failures: &mut Vec<String>) {
+    let flame = super::presets::by_name("gasket").expect("gasket is a preset");
+    let one = flame.run(run_config(1)).expect("the self-test image is large enough");
+    let four = flame.run(run_config(4)).expect("the self-test image is large enough");
+    if one.as_flat_slice() != four.as_flat_slice() {
+        failures.push("seeded histograms differ between 1 and 4 threads".to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_check_passes() {
+        for group in run_all().groups {
+            assert!(group.passed(), "{}: {:?}", group.name, group.failures);
+        }
+    }
+
+    #[test]
+    fn corrupted_references_are_detected() {
+        let failures = |compare: &dyn Fn(&mut Vec<String>)| {
+            let mut failures = Vec::new();
+            compare(&mut failures);
+            failures
+        };
+
+        let mut hashes = VARIATION_HASHES;
+        hashes[3] ^= 1;
+        let found = failures(&|f| compare_variations(&hashes, f));
+        assert_eq!(found.len(), 1, "{:?}", found);
+        assert!(found[0].starts_with(&format!("{:?} ", VariationDiscriminant::ALL[3])), "{}", found[0]);
+
+        assert_eq!(failures(&|f| compare_render(RENDER_HASH ^ 1, f)).len(), 1);
+
+        let mut samples = PALETTE_SAMPLES;
+        samples[2].1[0] -= 1;
+        assert_eq!(failures(&|f| compare_palette(&samples, f)), ["palette position 128 is #ff8000, expected #fe8000"]);
+
+        let mut output = TONEMAP_OUTPUT;
+        output[8] += 1;
+        assert_eq!(failures(&|f| compare_tonemap(&output, f)).len(), 1);
+    }
+}
@@ -0,0 +1,355 @@
+//! Writing PNG images a row at a time.
+//!
+//! Rows are gathered into bands, which are filtered and compressed on
+//! several threads at once, each as an independent run of the deflate
+//! stream, and written out in order as they finish. Only the bands in
+//! flight are held in memory, so producers which generate an image from
+//! top to bottom never need all of it at once.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, IntoInnerError, Write};
+use std::path::Path;
+use std::sync::mpsc::{channel, sync_channel};
+use std::sync::Mutex;
+
+use flate2::{Compress, Compression, FlushCompress, Status};
+
+use super::naming::partial_path;
+use super::output::SinkError;
+
+/// One row of an image, three bytes of 8-bit red, green and blue per pixel.
+pub type RgbRow = Vec<u8>;
+
+/// Number of rows compressed together. Bands are compressed independently,
+/// so smaller bands compress slightly worse.
+const BAND_ROWS: usize = 64;
+
+/// Modulus of the Adler-32 checksum ending a zlib stream.
+const ADLER_MOD: u32 = 65521;
+
+/// A band of rows, filtered and compressed.
+struct Band {
+    index: usize,
+    deflated: Vec<u8>,
+    adler: u32,
+    len: usize,
+}
+
+/// Write rows of an image to a PNG file at `path`, compressing them on up
+/// to `threads` threads. `rows` must yield `height` rows of `width` pixels.
+///
+/// The image is written beside the path and renamed into place once it is
+/// complete, as by an atomic `FileSink`, so that the path never holds part
+/// of an image, and keeps what it held before if writing fails.
+pub fn save_image_streaming(
+    path: impl AsRef<Path>,
+    width: u32,
+    height: u32,
+    rows: impl Iterator<Item = RgbRow>,
+    threads: usize,
+) -> Result<(), SinkError> {
+    let path = path.as_ref();
+    let in_file = |error| SinkError::File { path: path.to_path_buf(), error };
+    let temp = partial_path(path).map_err(in_file)?;
+    let written = File::create(&temp).map_err(in_file).and_then(|file| {
+        let file = match write_png_streaming(BufWriter::new(file), width, height, rows, threads) {
+            Ok(file) => file,
+            Err(SinkError::Io(error)) => return Err(in_file(error)),
+            Err(e) => return Err(e),
+        };
+        file.into_inner()
+            .map_err(IntoInnerError::into_error)
+            .and_then(|file| file.sync_all())
+            .and_then(|()| std::fs::rename(&temp, path))
+            .map_err(in_file)
+    });
+    if written.is_err() {
+        let _ = std::fs::remove_file(&temp);
+    }
+    written
+}
+
+/// `save_image_streaming`, writing to any `io::Write`, which is returned
+/// once the image is complete.
+pub fn write_png_streaming<W: Write>(
+    mut out: W,
+    width: u32,
+    height: u32,
+    rows: impl Iterator<Item = RgbRow>,
+    threads: usize,
+) -> Result<W, SinkError> {
+    if width == 0 || height == 0 {
+        return Err(SinkError::EmptyImage);
+    }
+    let row_len = width as usize * 3;
+    let bands = (height as usize).div_ceil(BAND_ROWS);
+    let threads = threads.max(1);
+
+    out.write_all(b"\x89PNG\r\n\x1a\n")?;
+    let mut header = Vec::with_capacity(13);
+    header.extend(width.to_be_bytes());
+    header.extend(height.to_be_bytes());
+    // 8-bit truecolor, deflate, filtered per row, not interlaced.
+    header.extend([8, 2, 0, 0, 0]);
+    write_chunk(&mut out, b"IHDR", &header)?;
+
+    let (jobs, job_receiver) = sync_channel::<(usize, RgbRow, Vec<RgbRow>)>(threads);
+    let job_receiver = Mutex::new(job_receiver);
+    let (done, results) = channel::<Band>();
+
+    std::thread::scope(|s| -> Result<(), SinkError> {
+        for _ in 0 .. threads {
+            let done = done.clone();
+            let job_receiver = &job_receiver;
+            s.spawn(move || loop {
+                // The lock is released as soon as a band is received.
+                let next = job_receiver.lock().unwrap().recv();
+                let Ok((index, above, rows)) = next else { break };
+                let filtered = filter_rows(&above, &rows);
+                let deflated = deflate(&filtered, index + 1 == bands);
+                let band = Band { index, deflated, adler: adler32(&filtered), len: filtered.len() };
+                if done.send(band).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(done);
+
+        let mut idat = IdatWriter { bands, next: 0, adler: 1, finished: BTreeMap::new() };
+        let mut rows = rows.enumerate();
+        let mut above = vec![0; row_len];
+        for index in 0 .. bands {
+            let count = BAND_ROWS.min(height as usize - index * BAND_ROWS);
+            let mut band = Vec::with_capacity(count);
+            for (y, row) in rows.by_ref().take(count) {
+                if row.len() != row_len {
+                    return Err(SinkError::RowLength { row: y, expected: row_len, actual: row.len() });
+                }
+                band.push(row);
+            }
+            if band.len() < count {
+                return Err(SinkError::TooFewRows { expected: height as usize, actual: index * BAND_ROWS + band.len() });
+            }
+            let last = band.last().expect("bands are not empty").clone();
+            jobs.send((index, std::mem::replace(&mut above, last), band)).expect("workers outlive the jobs");
+
+            // Keep no more bands in flight than the threads can work on and
+            // have queued, so that memory does not grow with the image.
+            while index + 1 - idat.next >= 2 * threads {
+                idat.push(results.recv().expect("workers finish every band"), &mut out)?;
+            }
+        }
+        drop(jobs);
+        if rows.next().is_some() {
+            return Err(SinkError::TooManyRows { expected: height as usize });
+        }
+
+        for band in results {
+            idat.push(band, &mut out)?;
+        }
+        Ok(())
+    })?;
+
+    write_chunk(&mut out, b"IEND", &[])?;
+    Ok(out)
+}
+
+/// Writes compressed bands as the image's data in order, holding back those
+/// finished before the bands above them.
+struct IdatWriter {
+    bands: usize,
+    /// Index of the band to be written next.
+    next: usize,
+    /// Checksum of the uncompressed data of the bands written so far.
+    adler: u32,
+    finished: BTreeMap<usize, Band>,
+}
+
+impl IdatWriter {
+    fn push(&mut self, band: Band, out: &mut impl Write) -> std::io::Result<()> {
+        self.finished.insert(band.index, band);
+        while let Some(band) = self.finished.remove(&self.next) {
+            let mut data = Vec::with_capacity(band.deflated.len() + 6);
+            if band.index == 0 {
+                // zlib header: deflate with a 32 KiB window, default level.
+                data.extend([0x78, 0x9c]);
+            }
+            data.extend(&band.deflated);
+            self.adler = adler32_combine(self.adler, band.adler, band.len);
+            if band.index + 1 == self.bands {
+                data.extend(self.adler.to_be_bytes());
+            }
+            write_chunk(out, b"IDAT", &data)?;
+            self.next += 1;
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn write_chunk(out: &mut impl Write, kind: &[u8; 4], data: &[u8]) -> std::io::Result<()> {
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(kind);
+    crc.update(data);
+    out.write_all(&(data.len() as u32).to_be_bytes())?;
+    out.write_all(kind)?;
+    out.write_all(data)?;
+    out.write_all(&crc.finalize().to_be_bytes())
+}
+
+/// Filter each row with the Paeth predictor, given the row above the first,
+/// which is zero for the top of the image.
+fn filter_rows(above: &[u8], rows: &[RgbRow]) -> Vec<u8> {
+    let mut filtered = Vec::with_capacity(rows.len() * (above.len() + 1));
+    let mut prev = above;
+    for row in rows {
+        filtered.push(4);
+        for i in 0 .. row.len() {
+            let (a, b) = (if i >= 3 { row[i - 3] } else { 0 }, prev[i]);
+            let c = if i >= 3 { prev[i - 3] } else { 0 };
+            filtered.push(row[i].wrapping_sub(paeth(a, b, c)));
+        }
+        prev = row;
+    }
+    filtered
+}
+
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let (pa, pb, pc) = ((p - a as i16).abs(), (p - b as i16).abs(), (p - c as i16).abs());
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+/// Compress a band as raw deflate blocks, ending at a byte boundary so that
+/// the next band's blocks can follow, or ending the stream if it is the
+/// last band.
+fn deflate(data: &[u8], last: bool) -> Vec<u8> {
+    let mut compress = Compress::new(Compression::default(), false);
+    let flush = if last { FlushCompress::Finish } else { FlushCompress::Sync };
+    let mut out = Vec::with_capacity(data.len() / 2 + 64);
+    loop {
+        if out.len() == out.capacity() {
+            out.reserve(out.capacity());
+        }
+        let consumed = compress.total_in() as usize;
+        let status = compress.compress_vec(&data[consumed ..], &mut out, flush).expect("deflate does not fail on memory input");
+        let flushed = compress.total_in() as usize == data.len() && out.len() < out.capacity();
+        if status == Status::StreamEnd || (!last && flushed) {
+            return out;
+        }
+    }
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    // Sums of this many bytes cannot overflow before they are reduced.
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= ADLER_MOD;
+        b %= ADLER_MOD;
+    }
+    b << 16 | a
+}
+
+/// The Adler-32 checksum of two runs of bytes together, given the checksum
+/// of each and the length of the second.
+fn adler32_combine(first: u32, second: u32, second_len: usize) -> u32 {
+    let rem = (second_len % ADLER_MOD as usize) as u64;
+    let m = ADLER_MOD as u64;
+    let (a1, b1) = ((first & 0xffff) as u64, (first >> 16) as u64);
+    let (a2, b2) = ((second & 0xffff) as u64, (second >> 16) as u64);
+    let a = (a1 + a2 + m - 1) % m;
+    let b = (b1 + b2 + rem * a1 + m - rem) % m;
+    (b << 16 | a) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::{write_image, OutputFormat, VecSink};
+    use image::{DynamicImage, RgbImage};
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+
+    /// A gradient with noise, so that every filter and band has work to do.
+    /// Its height is not a multiple of the band height.
+    fn image() -> RgbImage {
+        let mut rng = StdRng::seed_from_u64(3);
+        RgbImage::from_fn(300, 3 * BAND_ROWS as u32 + 5, |x, y| {
+            image::Rgb([(x % 256) as u8, (y % 256) as u8, ((x + y) as u8).wrapping_add(rng.gen_range(0 .. 16))])
+        })
+    }
+
+    fn rows(image: &RgbImage) -> impl Iterator<Item = RgbRow> + '_ {
+        image.as_raw().chunks(image.width() as usize * 3).map(<[u8]>::to_vec)
+    }
+
+    fn stream(image: &RgbImage, threads: usize) -> Vec<u8> {
+        write_png_streaming(Vec::new(), image.width(), image.height(), rows(image), threads).unwrap()
+    }
+
+    #[test]
+    fn streamed_images_decode_to_the_buffered_pixels() {
+        let image = image();
+        let mut sink = VecSink::new(OutputFormat::Png);
+        write_image(&DynamicImage::ImageRgb8(image.clone()), &mut sink).unwrap();
+        let buffered = image::load_from_memory(&sink.images[0]).unwrap().into_rgb8();
+        assert_eq!(buffered, image);
+
+        let single = stream(&image, 1);
+        assert_eq!(image::load_from_memory(&single).unwrap().into_rgb8(), buffered);
+        // Bands are compressed the same way whichever thread takes them.
+        for threads in [2, 4, 7] {
+            assert!(stream(&image, threads) == single, "{} threads", threads);
+        }
+    }
+
+    #[test]
+    fn rows_must_fill_the_image_exactly() {
+        let image = image();
+        let (width, height) = (image.width(), image.height());
+        let write = |rows: Vec<RgbRow>| write_png_streaming(Vec::new(), width, height, rows.into_iter(), 3).map(drop);
+        let all: Vec<RgbRow> = rows(&image).collect();
+
+        let mut short = all.clone();
+        short[BAND_ROWS + 2].pop();
+        assert!(matches!(write(short), Err(SinkError::RowLength { row, expected: 900, actual: 899 }) if row == BAND_ROWS + 2));
+
+        // Cut in the last band, which is shorter than the others.
+        let cut = all[.. all.len() - 2].to_vec();
+        assert!(matches!(write(cut), Err(SinkError::TooFewRows { expected, actual }) if expected == all.len() && actual == all.len() - 2));
+
+        let mut long = all.clone();
+        long.push(vec![0; 900]);
+        assert!(matches!(write(long), Err(SinkError::TooManyRows { expected }) if expected == all.len()));
+
+        assert!(matches!(write_png_streaming(Vec::new(), 0, 4, std::iter::empty(), 1), Err(SinkError::EmptyImage)));
+    }
+
+    #[test]
+    fn files_are_replaced_only_by_complete_images() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("big.png");
+        std::fs::write(&path, b"previous").unwrap();
+        let image = image();
+        let files = || std::fs::read_dir(dir.path()).unwrap().count();
+
+        let failed = save_image_streaming(&path, image.width(), image.height(), rows(&image).take(100), 2);
+        assert!(matches!(failed, Err(SinkError::TooFewRows { actual: 100, .. })));
+        assert_eq!(std::fs::read(&path).unwrap(), b"previous");
+        assert_eq!(files(), 1);
+
+        save_image_streaming(&path, image.width(), image.height(), rows(&image), 2).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), stream(&image, 1));
+        assert_eq!(files(), 1);
+    }
+}
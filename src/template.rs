@@ -0,0 +1,496 @@
+//! Descriptors with named parameters, for families of flames which share a
+//! structure and differ in a few numbers.
+//!
+//! A template declares its parameters and their defaults in a `params`
+//! object, such as `{"spin": 0.3, "depth": 4}`, and refers to them anywhere
+//! a number is accepted by a string expression such as `"$spin"` or
+//! `"2*$spin + 1"`. Expressions are those of `animate --mod`, without the
+//! frame variables. Defaults may be expressions too, referring to other
+//! parameters, as long as none refers back to itself.
+//!
+//! An instance is a document naming the template it fills in, with the
+//! values it binds, and any other fields replacing the template's:
+//!
+//! ```json
+//! {"template": "family.json", "params": {"spin": 0.45}}
+//! ```
+//!
+//! An instance may name another instance as its template, whose bindings
+//! its own override.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use serde_json::{Map, Value};
+
+use super::animation::{AnimationError, Expr, Vars};
+
+/// Values bound to template parameters, by name.
+pub type ParamMap = BTreeMap<String, f64>;
+
+/// Key of the parameters of a template and the bindings of an instance.
+pub const PARAMS_KEY: &str = "params";
+
+/// Key naming the template an instance fills in.
+pub const TEMPLATE_KEY: &str = "template";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TemplateError {
+    /// A string at this path holds an expression which cannot be parsed.
+    Expr { path: String, error: String },
+    /// An expression refers to a parameter which is not declared.
+    Undefined { name: String, path: String },
+    /// Parameters whose defaults refer to each other in a cycle, the first
+    /// repeated at the end.
+    Recursive(Vec<String>),
+    /// A value is bound to a parameter which is not declared.
+    UnknownBinding(String),
+    /// A value is bound to a parameter which nothing refers to.
+    UnusedBinding(String),
+    /// A parameter's name or default is neither a number nor an expression.
+    InvalidParam(String),
+    /// An expression at this path evaluates to infinity or NaN.
+    NonFinite { path: String, value: f64 },
+    /// An instance's template or bindings are not as described above.
+    InvalidInstance(String),
+    /// An instance is, through its templates, a template of itself.
+    Cycle(PathBuf),
+}
+
+impl std::fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TemplateError::Expr { path, error } => write!(f, "invalid expression at '{}': {}", path, error),
+            TemplateError::Undefined { name, path } => {
+                write!(f, "'{}' refers to '${}', which is not declared in params", path, name)
+            }
+            TemplateError::Recursive(cycle) => {
+                write!(f, "parameters are defined in terms of themselves: {}", cycle.join(" -> "))
+            }
+            TemplateError::UnknownBinding(name) => {
+                write!(f, "a value is given for '{}', but the template has no such parameter", name)
+            }
+            TemplateError::UnusedBinding(name) => {
+                write!(f, "a value is given for '{}', but nothing in the template refers to it", name)
+            }
+            TemplateError::InvalidParam(name) => {
+                write!(f, "parameter '{}' must be named by letters, digits and '_', and default to a number or expression", name)
+            }
+            TemplateError::NonFinite { path, value } => {
+                write!(f, "'{}' is {}, but descriptors can only hold finite numbers", path, value)
+            }
+            TemplateError::InvalidInstance(msg) => write!(f, "invalid template instance: {}", msg),
+            TemplateError::Cycle(path) => write!(f, "'{}' is its own template", path.display()),
+        }
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+/// A value bound to a parameter, written `name=value`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Binding {
+    pub name: String,
+    pub value: f64,
+}
+
+impl FromStr for Binding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, value) = s.split_once('=').ok_or_else(|| format!("expected NAME=VALUE, got '{}'", s))?;
+        let value = value.trim().parse().ok().filter(|x: &f64| x.is_finite())
+            .ok_or_else(|| format!("invalid number '{}' in '{}'", value.trim(), s))?;
+        Ok(Binding { name: name.trim().trim_start_matches('$').to_string(), value })
+    }
+}
+
+/// The bindings given, later ones replacing earlier ones of the same name.
+pub fn param_map(bindings: &[Binding]) -> ParamMap {
+    bindings.iter().map(|b| (b.name.clone(), b.value)).collect()
+}
+
+/// Whether `doc` declares parameters or fills in a template.
+pub fn is_template(doc: &Value) -> bool {
+    doc.get(PARAMS_KEY).is_some() || doc.get(TEMPLATE_KEY).is_some()
+}
+
+/// The path of the template `doc` fills in, if it is an instance.
+pub fn template_path(doc: &Value) -> Result<Option<PathBuf>, TemplateError> {
+    match doc.get(TEMPLATE_KEY) {
+        None => Ok(None),
+        Some(Value::String(path)) => Ok(Some(PathBuf::from(path))),
+        Some(_) => Err(TemplateError::InvalidInstance(format!("'{}' must be a path", TEMPLATE_KEY))),
+    }
+}
+
+/// Fill in `template` with the bindings and fields of `instance`, giving a
+/// template whose defaults are the values the instance binds.
+pub fn apply_instance(mut instance: Value, mut template: Value) -> Result<Value, TemplateError> {
+    let Value::Object(fields) = &mut instance else {
+        return Err(TemplateError::InvalidInstance("an instance must be an object".to_string()));
+    };
+    fields.remove(TEMPLATE_KEY);
+    let bindings = match fields.remove(PARAMS_KEY) {
+        None => Map::new(),
+        Some(Value::Object(bindings)) => bindings,
+        Some(_) => return Err(TemplateError::InvalidInstance(format!("'{}' must be an object", PARAMS_KEY))),
+    };
+    let Value::Object(template_fields) = &mut template else {
+        return Err(TemplateError::InvalidInstance("a template must be an object".to_string()));
+    };
+    let params = template_fields.entry(PARAMS_KEY).or_insert_with(|| Value::Object(Map::new()));
+    let Value::Object(params) = params else {
+        return Err(TemplateError::InvalidInstance(format!("the template's '{}' must be an object", PARAMS_KEY)));
+    };
+    for (name, value) in bindings {
+        if !value.is_number() {
+            return Err(TemplateError::InvalidInstance(format!("the value of '{}' must be a number", name)));
+        }
+        match params.get_mut(&name) {
+            Some(default) => *default = value,
+            None => return Err(TemplateError::UnknownBinding(name)),
+        }
+    }
+    template_fields.extend(std::mem::take(fields));
+    Ok(template)
+}
+
+/// Replace every reference to a parameter in `doc` with its value, taking
+/// values from `bindings` before the defaults in `doc`'s `params`, which
+/// are removed. Documents without parameters are returned unchanged, as
+/// long as nothing is bound.
+///
+/// Every binding must be to a parameter the document refers to, directly
+/// or through the defaults of others. Metadata is left as it is.
+pub fn instantiate(mut doc: Value, bindings: &ParamMap) -> Result<Value, TemplateError> {
+    let defaults = match &mut doc {
+        Value::Object(fields) => match fields.remove(PARAMS_KEY) {
+            None => Map::new(),
+            Some(Value::Object(defaults)) => defaults,
+            Some(_) => return Err(TemplateError::InvalidParam(PARAMS_KEY.to_string())),
+        },
+        _ => Map::new(),
+    };
+
+    let mut exprs = BTreeMap::new();
+    for (name, default) in &defaults {
+        let valid_name = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        let expr = match default {
+            _ if !valid_name => None,
+            Value::Number(x) => x.as_f64().map(Expr::Num),
+            Value::String(s) => Some(parse(s, &format!("{}.{}", PARAMS_KEY, name))?),
+            _ => None,
+        };
+        exprs.insert(name.as_str(), expr.ok_or_else(|| TemplateError::InvalidParam(name.clone()))?);
+    }
+    for (name, &value) in bindings {
+        let expr = exprs.get_mut(name.as_str()).ok_or_else(|| TemplateError::UnknownBinding(name.clone()))?;
+        *expr = Expr::Num(value);
+    }
+
+    // Parameters are evaluated as they are first needed, so that those
+    // referred to nowhere need not be valid, and a cycle is found by
+    // coming back to a parameter still being evaluated.
+    let mut params = Params { exprs, values: ParamMap::new(), evaluating: Vec::new() };
+    let mut replaced = doc;
+    if let Value::Object(fields) = &mut replaced {
+        for (key, value) in fields.iter_mut().filter(|(k, _)| k.as_str() != "meta") {
+            substitute(value, key.clone(), &mut params)?;
+        }
+    }
+
+    if let Some(name) = bindings.keys().find(|name| !params.values.contains_key(name.as_str())) {
+        return Err(TemplateError::UnusedBinding(name.clone()));
+    }
+    Ok(replaced)
+}
+
+struct Params<'a> {
+    exprs: BTreeMap<&'a str, Expr>,
+    values: ParamMap,
+    /// Parameters whose defaults are being evaluated, innermost last.
+    evaluating: Vec<String>,
+}
+
+impl Params<'_> {
+    /// The value of the parameter `name`, referred to at `path`.
+    fn value(&mut self, name: &str, path: &str) -> Result<f64, TemplateError> {
+        if let Some(&value) = self.values.get(name) {
+            return Ok(value);
+        }
+        if let Some(start) = self.evaluating.iter().position(|n| n == name) {
+            let mut cycle = self.evaluating[start ..].to_vec();
+            cycle.push(name.to_string());
+            return Err(TemplateError::Recursive(cycle));
+        }
+        let expr = self.exprs.get(name)
+            .ok_or_else(|| TemplateError::Undefined { name: name.to_string(), path: path.to_string() })?
+            .clone();
+        self.evaluating.push(name.to_string());
+        let value = self.eval(&expr, &format!("{}.{}", PARAMS_KEY, name))?;
+        self.evaluating.pop();
+        self.values.insert(name.to_string(), value);
+        Ok(value)
+    }
+
+    fn eval(&mut self, expr: &Expr, path: &str) -> Result<f64, TemplateError> {
+        let mut values = ParamMap::new();
+        for name in expr.params() {
+            values.insert(name.to_string(), self.value(name, path)?);
+        }
+        let value = expr.eval_with(Vars { t: 0.0, n: 0.0 }, &values);
+        if !value.is_finite() {
+            return Err(TemplateError::NonFinite { path: path.to_string(), value });
+        }
+        Ok(value)
+    }
+}
+
+fn parse(s: &str, path: &str) -> Result<Expr, TemplateError> {
+    let expr = s.parse::<Expr>().map_err(|e| {
+        let error = match e {
+            AnimationError::Expr(error) => error,
+            e => e.to_string(),
+        };
+        TemplateError::Expr { path: path.to_string(), error }
+    })?;
+    if expr.uses_frame() {
+        let error = "t and n are only defined in modulations".to_string();
+        return Err(TemplateError::Expr { path: path.to_string(), error });
+    }
+    Ok(expr)
+}
+
+/// Replace strings referring to parameters within `value`, at `path`.
+fn substitute(value: &mut Value, path: String, params: &mut Params) -> Result<(), TemplateError> {
+    match value {
+        Value::String(s) if s.contains('$') => {
+            let x = params.eval(&parse(s, &path)?, &path)?;
+            // Whole numbers are written as integers, for fields which are.
+            *value = if x.fract() == 0.0 && x.abs() < (1u64 << 53) as f64 {
+                Value::from(x as i64)
+            } else {
+                serde_json::Number::from_f64(x).map(Value::Number).expect("values are finite")
+            };
+        }
+        Value::Array(items) => {
+            for (i, item) in items.iter_mut().enumerate() {
+                substitute(item, format!("{}[{}]", path, i), params)?;
+            }
+        }
+        Value::Object(fields) => {
+            for (key, item) in fields.iter_mut() {
+                substitute(item, format!("{}.{}", path, key), params)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::ColorModel;
+    use crate::file::{DescriptorError, FlameSource};
+    use serde_json::json;
+
+    fn bind(pairs: &[(&str, f64)]) -> ParamMap {
+        pairs.iter().map(|&(name, value)| (name.to_string(), value)).collect()
+    }
+
+    /// A template with a parameter in every place a number is accepted.
+    fn everywhere() -> Value {
+        json!({
+            "params": {"s": 0.5, "n": 4, "c": 0.25, "dim": 64},
+            "bounds": ["-$s", "$s", "-2*$s", "2*$s"],
+            "functions": [
+                ["$s", {"Blob": {"high": "$s", "waves": "$n"}}, ["$s", 0, 0, "$s", "$c", "-$c"], "$c", ["$s", "$c"]],
+                ["1 - $s", {"PDJ": ["$s", "$c", 1, "$n"]}, [1, "$c", 0, 1, 0, 0], "1 - $c"]
+            ],
+            "palette": [["$n", 20, 120], [240, "$dim", 140]],
+            "render": {"iters": "1000*$n", "dims": ["$dim", "$dim / 2"], "gamma": "$n / 2", "vibrancy": "$c"}
+        })
+    }
+
+    #[test]
+    fn parameters_are_substituted_in_every_numeric_position() {
+        let flame = instantiate(everywhere(), &bind(&[("s", 0.75)])).unwrap();
+        assert_eq!(flame, json!({
+            "bounds": [-0.75, 0.75, -1.5, 1.5],
+            "functions": [
+                [0.75, {"Blob": {"high": 0.75, "waves": 4}}, [0.75, 0, 0, 0.75, 0.25, -0.25], 0.25, [0.75, 0.25]],
+                [0.25, {"PDJ": [0.75, 0.25, 1, 4]}, [1, 0.25, 0, 1, 0, 0], 0.75]
+            ],
+            "palette": [[4, 20, 120], [240, 64, 140]],
+            "render": {"iters": 4000, "dims": [64, 32], "gamma": 2, "vibrancy": 0.25}
+        }));
+        let source = FlameSource::from_value(flame, ".").unwrap();
+        let settings = source.render_settings().unwrap();
+        assert_eq!((settings.iters, settings.dims, settings.gamma), (Some(4000), Some([64, 32]), Some(2.0)));
+        source.to_flame().unwrap();
+    }
+
+    #[test]
+    fn defaults_apply_where_nothing_is_bound() {
+        let read = FlameSource::from_value(everywhere(), ".").unwrap();
+        let written = instantiate(everywhere(), &ParamMap::new()).unwrap();
+        assert_eq!(read.content_hash().unwrap(), FlameSource::from_value(written, ".").unwrap().content_hash().unwrap());
+        assert!(read.is_template());
+    }
+
+    #[test]
+    fn expressions_combine_parameters_and_defaults() {
+        let doc = json!({
+            "params": {"spin": 0.25, "twice": "2*$spin", "more": "$twice + 1"},
+            "a": "2*$spin", "b": "$more", "c": "pow($spin, 2) + min($twice, 0.1)"
+        });
+        let out = instantiate(doc.clone(), &ParamMap::new()).unwrap();
+        assert_eq!(out, json!({"a": 0.5, "b": 1.5, "c": 0.1625}));
+        // Binding a parameter changes the defaults which refer to it, and
+        // binding one of those replaces its expression.
+        let out = instantiate(doc.clone(), &bind(&[("spin", 1.0)])).unwrap();
+        assert_eq!(out, json!({"a": 2, "b": 3, "c": 1.1}));
+        let out = instantiate(doc, &bind(&[("spin", 1.0), ("twice", 5.0)])).unwrap();
+        assert_eq!(out, json!({"a": 2, "b": 6, "c": 1.1}));
+    }
+
+    #[test]
+    fn plain_documents_and_metadata_are_left_alone() {
+        let doc = json!({"bounds": [0, 1, 0, 1], "meta": {"command": "flame --set $x=1"}});
+        assert_eq!(instantiate(doc.clone(), &ParamMap::new()).unwrap(), doc);
+        let with_params = json!({"params": {"x": 1}, "a": "$x", "meta": {"command": "$x"}});
+        assert_eq!(instantiate(with_params, &ParamMap::new()).unwrap(), json!({"a": 1, "meta": {"command": "$x"}}));
+        // Nothing can be bound in a document without parameters.
+        let err = instantiate(doc, &bind(&[("x", 1.0)])).unwrap_err();
+        assert_eq!(err, TemplateError::UnknownBinding("x".to_string()));
+    }
+
+    #[test]
+    fn undeclared_references_are_reported_with_their_path() {
+        let doc = json!({"params": {"s": 1}, "functions": [[1, "Id", [1, 0, 0, 1, "$s", "$t0"], 0]]});
+        let err = instantiate(doc, &ParamMap::new()).unwrap_err();
+        assert_eq!(err, TemplateError::Undefined { name: "t0".to_string(), path: "functions[0][2][5]".to_string() });
+
+        let doc = json!({"params": {"s": "$missing"}, "render": {"gamma": "$s"}});
+        let err = instantiate(doc, &ParamMap::new()).unwrap_err();
+        assert_eq!(err, TemplateError::Undefined { name: "missing".to_string(), path: "params.s".to_string() });
+    }
+
+    #[test]
+    fn bindings_must_be_declared_and_used() {
+        let doc = json!({"params": {"used": 1, "derived": "$used", "unused": 2}, "a": "$derived"});
+        let err = instantiate(doc.clone(), &bind(&[("other", 1.0)])).unwrap_err();
+        assert_eq!(err, TemplateError::UnknownBinding("other".to_string()));
+        let err = instantiate(doc.clone(), &bind(&[("unused", 3.0)])).unwrap_err();
+        assert_eq!(err, TemplateError::UnusedBinding("unused".to_string()));
+        // A parameter used only through another's default is used.
+        assert_eq!(instantiate(doc, &bind(&[("used", 3.0)])).unwrap(), json!({"a": 3}));
+    }
+
+    #[test]
+    fn recursive_defaults_are_refused() {
+        let doc = json!({"params": {"a": "$b + 1", "b": "2*$c", "c": "$a"}, "x": "$b"});
+        let err = instantiate(doc.clone(), &ParamMap::new()).unwrap_err();
+        assert_eq!(err, TemplateError::Recursive(vec!["b".into(), "c".into(), "a".into(), "b".into()]));
+        let doc = json!({"params": {"a": "$a"}, "x": 1, "y": "$a"});
+        assert_eq!(instantiate(doc.clone(), &ParamMap::new()).unwrap_err(), TemplateError::Recursive(vec!["a".into(), "a".into()]));
+        // Binding a value breaks the cycle.
+        assert_eq!(instantiate(doc, &bind(&[("a", 2.0)])).unwrap(), json!({"x": 1, "y": 2}));
+        // Cycles nothing refers to are never evaluated.
+        let doc = json!({"params": {"a": "$b", "b": "$a", "c": 1}, "x": "$c"});
+        assert_eq!(instantiate(doc, &ParamMap::new()).unwrap(), json!({"x": 1}));
+    }
+
+    #[test]
+    fn invalid_parameters_and_expressions_are_refused() {
+        for params in [json!({"2x": 1}), json!({"a-b": 1}), json!({"x": true}), json!({"x": [1]}), json!([1])] {
+            let err = instantiate(json!({"params": params}), &ParamMap::new()).unwrap_err();
+            assert!(matches!(err, TemplateError::InvalidParam(_)), "{:?}", err);
+        }
+        let err = instantiate(json!({"params": {"x": 1}, "a": ["$x +"]}), &ParamMap::new()).unwrap_err();
+        assert!(matches!(err, TemplateError::Expr { ref path, .. } if path == "a[0]"), "{:?}", err);
+        // Frame variables belong to modulations only.
+        let err = instantiate(json!({"params": {"x": 1}, "a": "$x * t"}), &ParamMap::new()).unwrap_err();
+        assert!(matches!(err, TemplateError::Expr { ref path, .. } if path == "a"), "{:?}", err);
+    }
+
+    #[test]
+    fn non_finite_values_are_refused() {
+        let err = instantiate(json!({"params": {"x": 0}, "a": {"b": "1 / $x"}}), &ParamMap::new()).unwrap_err();
+        assert_eq!(err, TemplateError::NonFinite { path: "a.b".to_string(), value: f64::INFINITY });
+        let err = instantiate(json!({"params": {"x": "sqrt(0 - 1)"}, "a": "$x"}), &ParamMap::new()).unwrap_err();
+        assert!(matches!(err, TemplateError::NonFinite { ref path, value } if path == "params.x" && value.is_nan()));
+    }
+
+    #[test]
+    fn bindings_are_parsed_from_the_command_line() {
+        assert_eq!("$spin=0.5".parse(), Ok(Binding { name: "spin".to_string(), value: 0.5 }));
+        assert_eq!(" depth = -2 ".parse(), Ok(Binding { name: "depth".to_string(), value: -2.0 }));
+        for s in ["spin", "spin=", "spin=x", "spin=inf", "spin=NaN"] {
+            assert!(s.parse::<Binding>().is_err(), "{}", s);
+        }
+        let map = param_map(&["a=1".parse().unwrap(), "b=2".parse().unwrap(), "a=3".parse().unwrap()]);
+        assert_eq!(map, bind(&[("a", 3.0), ("b", 2.0)]));
+    }
+
+    #[test]
+    fn instances_replace_defaults_and_fields() {
+        let template = json!({"params": {"spin": 0.3, "depth": 4}, "a": "$spin", "b": "$depth", "c": 1});
+        let instance = json!({"template": "family.json", "params": {"spin": 0.45}, "c": 2});
+        assert!(is_template(&instance) && is_template(&template) && !is_template(&json!({"c": 1})));
+        assert_eq!(template_path(&instance), Ok(Some(PathBuf::from("family.json"))));
+        assert_eq!(template_path(&template), Ok(None));
+        assert!(template_path(&json!({"template": 1})).is_err());
+
+        let applied = apply_instance(instance, template.clone()).unwrap();
+        assert_eq!(applied["params"], json!({"spin": 0.45, "depth": 4}));
+        assert_eq!(instantiate(applied, &ParamMap::new()).unwrap(), json!({"a": 0.45, "b": 4, "c": 2}));
+
+        let err = apply_instance(json!({"params": {"twist": 1}}), template.clone()).unwrap_err();
+        assert_eq!(err, TemplateError::UnknownBinding("twist".to_string()));
+        let err = apply_instance(json!({"params": {"spin": "$depth"}}), template.clone()).unwrap_err();
+        assert!(matches!(err, TemplateError::InvalidInstance(_)));
+        assert!(matches!(apply_instance(json!([]), template), Err(TemplateError::InvalidInstance(_))));
+    }
+
+    #[test]
+    fn templates_which_include_themselves_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let write = |name: &str, doc: Value| std::fs::write(dir.path().join(name), doc.to_string()).unwrap();
+        write("self.json", json!({"template": "self.json"}));
+        write("a.json", json!({"template": "b.json", "params": {"s": 1}}));
+        write("b.json", json!({"template": "a.json"}));
+
+        for (name, repeated) in [("self.json", "self.json"), ("a.json", "a.json")] {
+            match FlameSource::from_path(dir.path().join(name)) {
+                Err(DescriptorError::Template(TemplateError::Cycle(path))) => assert!(path.ends_with(repeated)),
+                Err(e) => panic!("{}: {}", name, e),
+                Ok(_) => panic!("{} was read", name),
+            }
+        }
+    }
+
+    #[test]
+    fn instances_chain_through_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let write = |name: &str, doc: Value| std::fs::write(dir.path().join(name), doc.to_string()).unwrap();
+        let mut base = everywhere();
+        base["params"]["s"] = json!(0.6);
+        write("family.json", base);
+        write("member.json", json!({"template": "family.json", "params": {"s": 0.75, "n": 5}}));
+        write("variant.json", json!({"template": "member.json", "params": {"n": 4}, "color_model": "flam3"}));
+
+        let variant = FlameSource::from_path(dir.path().join("variant.json")).unwrap();
+        let expected = FlameSource::from_value(instantiate(everywhere(), &bind(&[("s", 0.75)])).unwrap(), ".").unwrap();
+        let flame = variant.to_flame().unwrap();
+        assert_eq!(flame.color_model, ColorModel::Flam3);
+        assert_eq!(flame.functions[0].weight, 0.75);
+
+        let bound = FlameSource::from_path_with_params(dir.path().join("member.json"), &bind(&[("n", 4.0)])).unwrap();
+        assert_eq!(bound.content_hash().unwrap(), expected.content_hash().unwrap());
+    }
+}
@@ -0,0 +1,94 @@
+//! Searching a directory of generated descriptors, as `flame query` does.
+
+use std::fs::File;
+use std::path::Path;
+
+use flame::core::*;
+use flame::file::FlameSource;
+use flame::index::{SummaryIndex, INDEX_FILE};
+use flame::meta::{GenerationMethod, Meta};
+use flame::presets;
+use flame::query::Predicate;
+
+fn write(dir: &Path, name: &str, flame: &Flame, method: Option<GenerationMethod>) {
+    let mut source = FlameSource::from_flame(flame);
+    if let Some(method) = method {
+        source = source.with_meta(Meta::new(method));
+    }
+    source.to_writer(File::create(dir.join(name)).unwrap()).unwrap();
+}
+
+/// A copy of `base` with `n` functions of the variation `var`.
+fn with_functions(base: Flame, n: usize, var: Variation) -> Flame {
+    let function = Function { var, weight: 1.0 / n as f32, ..base.functions[0] };
+    Flame { functions: vec![function; n], ..base }
+}
+
+fn blue() -> Palette {
+    Palette::from_keys(vec![Color::rgb(10, 20, 120), Color::rgb(40, 80, 230), Color::rgb(150, 180, 255)]).unwrap()
+}
+
+/// The descriptors of a small library, with the PDJ flames that have more
+/// than five functions and a blue palette only in `pdj-blue.json`.
+fn library(dir: &Path) {
+    let pdj = VariationDiscriminant::PDJ.with_defaults();
+    write(dir, "gasket.json", &presets::gasket(), Some(GenerationMethod::Randgen));
+    write(dir, "fern.json", &presets::fern(), None);
+    write(dir, "swirl.json", &presets::swirl(), Some(GenerationMethod::Breed));
+    write(dir, "pdj-blue.json", &Flame { palette: blue(), ..with_functions(presets::swirl(), 6, pdj) }, None);
+    write(dir, "pdj-few.json", &Flame { palette: blue(), ..with_functions(presets::swirl(), 3, pdj) }, None);
+    write(dir, "pdj-green.json", &with_functions(presets::fern(), 7, pdj), Some(GenerationMethod::Breed));
+    std::fs::write(dir.join("broken.json"), "[]").unwrap();
+}
+
+/// Names of the descriptors in `dir` matching every one of `conditions`.
+fn query(dir: &Path, conditions: &[&str]) -> Vec<String> {
+    let predicates: Vec<Predicate> = conditions.iter().map(|c| c.parse().unwrap()).collect();
+    let mut index = SummaryIndex::load(dir);
+    let listing = index.refresh(dir).unwrap();
+    if index.is_changed() {
+        index.save(dir).unwrap();
+    }
+    assert_eq!(listing.skipped.len(), 1);
+    listing.records.iter()
+        .filter(|r| r.matches(&predicates))
+        .map(|r| r.path.file_name().unwrap().to_string_lossy().into_owned())
+        .collect()
+}
+
+#[test]
+fn queries_find_the_matching_descriptors() {
+    let dir = tempfile::tempdir().unwrap();
+    library(dir.path());
+    let dir = dir.path();
+
+    assert_eq!(query(dir, &[]), ["fern.json", "gasket.json", "pdj-blue.json", "pdj-few.json", "pdj-green.json", "swirl.json"]);
+    assert!(dir.join(INDEX_FILE).exists());
+
+    assert_eq!(query(dir, &["variations contains PDJ"]), ["pdj-blue.json", "pdj-few.json", "pdj-green.json"]);
+    assert_eq!(query(dir, &["variations contains PDJ", "functions.count > 5"]), ["pdj-blue.json", "pdj-green.json"]);
+    assert_eq!(
+        query(dir, &["variations contains PDJ", "functions.count > 5", "palette.dominant_hue between 180 260"]),
+        ["pdj-blue.json"],
+    );
+    assert_eq!(query(dir, &["palette.dominant_hue between 80 140"]), ["fern.json", "pdj-green.json"]);
+    assert_eq!(query(dir, &["meta.method = breed"]), ["pdj-green.json", "swirl.json"]);
+    assert_eq!(query(dir, &["meta.method != breed"]), ["gasket.json"]);
+    assert_eq!(query(dir, &["symmetry.rotation = 3", "symmetry.mirror = true"]), ["gasket.json"]);
+    assert_eq!(query(dir, &["name contains pdj", "functions.count <= 3"]), ["pdj-few.json"]);
+    assert!(query(dir, &["variations contains Blob"]).is_empty());
+}
+
+#[test]
+fn queries_see_changes_to_the_directory() {
+    let dir = tempfile::tempdir().unwrap();
+    library(dir.path());
+    let dir = dir.path();
+    let blue_pdj = ["variations contains PDJ", "palette.dominant_hue between 180 260"];
+    assert_eq!(query(dir, &blue_pdj), ["pdj-blue.json", "pdj-few.json"]);
+
+    std::fs::remove_file(dir.join("pdj-few.json")).unwrap();
+    let pdj = VariationDiscriminant::PDJ.with_defaults();
+    write(dir, "pdj-green.json", &Flame { palette: blue(), ..with_functions(presets::fern(), 7, pdj) }, None);
+    assert_eq!(query(dir, &blue_pdj), ["pdj-blue.json", "pdj-green.json"]);
+}